@@ -0,0 +1,187 @@
+// Deliberate misbehaviour for the user binary, used to exercise the maker's validation. Only
+// compiled with the `adversarial` feature and enabled with `--adversarial <scenario>`, which sets
+// `UserConfig::adversarial`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use bdk::bitcoin::{psbt, EcdsaSighashType, PrivateKey, PublicKey};
+use bdk::bitcoin::psbt::Psbt;
+use bdk::SignOptions;
+
+use crate::error::ProtocolError;
+
+// Each scenario documents which maker check is expected to catch it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scenario {
+    // The first contract key is sent uncompressed; parse_contract_keys only accepts compressed keys
+    UncompressedKey,
+    // The given key (the other user's) is sent as our multisig key, so the contract has duplicates
+    DuplicateKey(PublicKey),
+    // The refund PSBT is returned without our partial signature
+    UnsignedRefund,
+    // The refund PSBT is signed with SIGHASH_NONE, which would let anyone rewrite the outputs
+    SighashNoneRefund,
+    // The witness_utxo of our input claims more value than the previous output actually holds; the
+    // maker checks it against the full previous tx
+    InflatedUtxo,
+    // We never answer after receiving the contract data and PSBTs, until the maker hangs up
+    SilentAfterContract,
+    // The signed funding PSBT is sent twice
+    DoubleFundingSig,
+    // We drop the connection once we have the finalized refund and resume the session on a new one;
+    // the maker must pick the round up where we left it
+    ReconnectBeforeFunding,
+    // We keep our users2maker multisig key once paid; the maker must sweep through the hashlock path
+    WithheldContractKey,
+}
+
+// The maker check that catches a scenario, by the error the maker drops the user with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MakerCheck {
+    Keys,
+    ContractDesc,
+    Psbt,
+    Utxo,
+    Timeout,
+    // A message the maker doesn't expect at that step, refused for its size if larger than the one it expects
+    Unexpected,
+}
+
+impl MakerCheck {
+    pub fn matches(self, error: &ProtocolError) -> bool {
+        matches!(
+            (self, error),
+            (MakerCheck::Keys, ProtocolError::Keys(_))
+                | (MakerCheck::ContractDesc, ProtocolError::ContractDesc(_))
+                | (MakerCheck::Psbt, ProtocolError::Psbt(_))
+                | (MakerCheck::Utxo, ProtocolError::Utxo(_))
+                | (MakerCheck::Timeout, ProtocolError::Timeout(_))
+                | (MakerCheck::Unexpected, ProtocolError::Unexpected { .. } | ProtocolError::TooLong { .. })
+        )
+    }
+}
+
+impl Scenario {
+    // The maker check that catches the scenario, None if the maker gets through the round anyway
+    pub fn caught_by(self) -> Option<MakerCheck> {
+        match self {
+            Scenario::UncompressedKey => Some(MakerCheck::Keys),
+            Scenario::DuplicateKey(_) => Some(MakerCheck::ContractDesc),
+            Scenario::UnsignedRefund | Scenario::SighashNoneRefund => Some(MakerCheck::Psbt),
+            Scenario::InflatedUtxo => Some(MakerCheck::Utxo),
+            Scenario::SilentAfterContract => Some(MakerCheck::Timeout),
+            Scenario::DoubleFundingSig => Some(MakerCheck::Unexpected),
+            Scenario::ReconnectBeforeFunding | Scenario::WithheldContractKey => None,
+        }
+    }
+
+    // Whether the maker only catches the scenario once the funding tx is out, so the round ends with
+    // every user taking its refund (see `RoundStatus::PeerAborted`) rather than before anything is at stake
+    pub fn caught_after_funding(self) -> bool {
+        self == Scenario::DoubleFundingSig
+    }
+}
+
+// Extra sats claimed by `Scenario::InflatedUtxo`
+pub const INFLATION: u64 = 100_000;
+
+pub const SCENARIOS: [&str; 9] = [
+    "uncompressed-key",
+    "duplicate-key:<pubkey>",
+    "unsigned-refund",
+    "sighash-none-refund",
+    "inflated-utxo",
+    "silent-after-contract",
+    "double-funding-sig",
+    "reconnect-before-funding",
+    "withhold-contract-key",
+];
+
+impl FromStr for Scenario {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(key) = s.strip_prefix("duplicate-key:") {
+            let key = PublicKey::from_str(key).map_err(|e| format!("Invalid duplicate key: {e}"))?;
+            return Ok(Scenario::DuplicateKey(key));
+        }
+
+        match s {
+            "uncompressed-key" => Ok(Scenario::UncompressedKey),
+            "unsigned-refund" => Ok(Scenario::UnsignedRefund),
+            "sighash-none-refund" => Ok(Scenario::SighashNoneRefund),
+            "inflated-utxo" => Ok(Scenario::InflatedUtxo),
+            "silent-after-contract" => Ok(Scenario::SilentAfterContract),
+            "double-funding-sig" => Ok(Scenario::DoubleFundingSig),
+            "reconnect-before-funding" => Ok(Scenario::ReconnectBeforeFunding),
+            "withhold-contract-key" => Ok(Scenario::WithheldContractKey),
+            _ => Err(format!("Unknown scenario '{s}', expected one of: {}", SCENARIOS.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scenario::UncompressedKey => write!(f, "uncompressed-key"),
+            Scenario::DuplicateKey(key) => write!(f, "duplicate-key:{key}"),
+            Scenario::UnsignedRefund => write!(f, "unsigned-refund"),
+            Scenario::SighashNoneRefund => write!(f, "sighash-none-refund"),
+            Scenario::InflatedUtxo => write!(f, "inflated-utxo"),
+            Scenario::SilentAfterContract => write!(f, "silent-after-contract"),
+            Scenario::DoubleFundingSig => write!(f, "double-funding-sig"),
+            Scenario::ReconnectBeforeFunding => write!(f, "reconnect-before-funding"),
+            Scenario::WithheldContractKey => write!(f, "withhold-contract-key"),
+        }
+    }
+}
+
+// Reads `--adversarial <scenario>` from the command line
+pub fn from_args() -> Result<Option<Scenario>, String> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.iter().position(|arg| arg == "--adversarial") {
+        Some(i) => {
+            let name = args.get(i + 1).ok_or("Missing scenario after --adversarial")?;
+            Scenario::from_str(name).map(Some)
+        },
+        None => Ok(None),
+    }
+}
+
+// Replaces our contract keys according to the scenario
+pub fn contract_keys(scenario: Option<Scenario>, keys: [PublicKey; 3]) -> [PublicKey; 3] {
+    match scenario {
+        Some(Scenario::UncompressedKey) => {
+            let uncompressed = PublicKey { compressed: false, inner: keys[0].inner };
+            [uncompressed, keys[1], keys[2]]
+        },
+        Some(Scenario::DuplicateKey(other)) => [other, keys[1], keys[2]],
+        _ => keys,
+    }
+}
+
+// Inflates the value claimed by our input according to the scenario
+pub fn tamper_utxo(scenario: Option<Scenario>, psbt_in: &mut psbt::Input) {
+    if let (Some(Scenario::InflatedUtxo), Some(txout)) = (scenario, psbt_in.witness_utxo.as_mut()) {
+        txout.value += INFLATION;
+    }
+}
+
+// Without our private keys the wallet adds no signature to the refund PSBT
+pub fn refund_signing_keys(scenario: Option<Scenario>, prv_keys: Vec<PrivateKey>) -> Vec<PrivateKey> {
+    match scenario {
+        Some(Scenario::UnsignedRefund) => Vec::new(),
+        _ => prv_keys,
+    }
+}
+
+pub fn refund_sign_options(scenario: Option<Scenario>, refund: &mut Psbt, sign_ops: SignOptions) -> SignOptions {
+    match scenario {
+        Some(Scenario::SighashNoneRefund) => {
+            refund.inputs[0].sighash_type = Some(EcdsaSighashType::None.into());
+            SignOptions { allow_all_sighashes: true, ..sign_ops }
+        },
+        _ => sign_ops,
+    }
+}
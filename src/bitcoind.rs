@@ -0,0 +1,423 @@
+// Backend over the JSON-RPC of a bitcoind node, for makers that run their own. Only compiled with the
+// `bitcoind` feature and used with `--bitcoind <url>`. On regtest it also mines blocks on demand.
+
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use bdk::bitcoin::consensus::encode::{deserialize, serialize_hex};
+use bdk::bitcoin::hashes::hex::{FromHex, ToHex};
+use bdk::bitcoin::{Address, BlockHash, OutPoint, Script, Transaction, Txid};
+use bdk::blockchain::{GetHeight, Progress, WalletSync};
+use bdk::database::{AnyDatabase, BatchDatabase};
+use bdk::{BlockTime, FeeRate, SyncOptions, Wallet};
+use serde_json::{json, Value};
+
+use crate::chain::{record_wallet_txs, BroadcastError, ChainAccess, ChainError, FeeEstimator, RpcAuth};
+use crate::http::HttpClient;
+
+// A bitcoind node. Without `-txindex` it only finds txs in its mempool or in a block we know, which is
+// all the sessions and the wallet sync need.
+pub struct BitcoindChain {
+    http: HttpClient,
+    auth: RpcAuth,
+}
+
+// What bitcoind answers when it has no such tx, block or address
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+// What it answers when it refuses a tx: unparsable, invalid, against its policy or already mined
+const RPC_TX_REFUSED: [i64; 4] = [-22, -25, -26, -27];
+
+impl BitcoindChain {
+    // From the url of the JSON-RPC, like `http://127.0.0.1:18443`, or that of a wallet of the node
+    pub fn new(url: &str, auth: RpcAuth) -> Result<Self, ChainError> {
+        Ok(BitcoindChain { http: HttpClient::new("bitcoind", url)?, auth })
+    }
+
+    // The cookie file is read on each call, as the node writes a new one each time it starts
+    fn authorization(&self) -> Result<String, ChainError> {
+        let credentials = match &self.auth {
+            RpcAuth::Cookie(path) => fs::read_to_string(path)
+                .map_err(|e| ChainError(format!("Can't read the bitcoind cookie {}: {e}", path.display())))?
+                .trim()
+                .to_string(),
+            RpcAuth::UserPass { user, password } => format!("{user}:{password}"),
+        };
+
+        Ok(format!("Authorization: Basic {}", base64::encode(credentials)))
+    }
+
+    // The result of `method`, or `None` if the node doesn't know what we asked for
+    fn call_opt(&self, method: &str, params: Value) -> Result<Option<Value>, ChainError> {
+        match self.answer(method, params)? {
+            Ok(result) => Ok(Some(result)),
+            Err((RPC_INVALID_ADDRESS_OR_KEY, _)) => Ok(None),
+            Err((_, message)) => Err(ChainError(format!("bitcoind failed {method}: {message}"))),
+        }
+    }
+
+    // The result of `method`, or the code and message of the error the node answered
+    fn answer(&self, method: &str, params: Value) -> Result<Result<Value, (i64, String)>, ChainError> {
+        let request = json!({ "jsonrpc": "1.0", "id": "joinswap", "method": method, "params": params });
+        let headers = [self.authorization()?, "Content-Type: application/json".to_string()];
+        let (status, body) = self.http.request("POST", "", &headers, &request.to_string())?;
+        if status == 401 {
+            return Err(ChainError("bitcoind rejected our RPC credentials".to_string()));
+        }
+
+        // Failed calls come with a non-200 status, but still with the error in the body
+        let answer: Value = serde_json::from_str(&body)
+            .map_err(|_| ChainError(format!("bitcoind answered {method} with {status}: {}", body.trim())))?;
+        match &answer["error"] {
+            Value::Null => Ok(Ok(answer["result"].clone())),
+            error => {
+                let message = error["message"].as_str().unwrap_or("").to_string();
+                Ok(Err((error["code"].as_i64().unwrap_or_default(), message)))
+            },
+        }
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, ChainError> {
+        self.call_opt(method, params)?
+            .ok_or_else(|| ChainError(format!("bitcoind doesn't know what we asked with {method}")))
+    }
+
+    // Mines `blocks` paying to `address` and returns their hashes. Only for regtest, where the timelocks
+    // of the refund txs have to be made to expire.
+    pub fn generate_to_address(&self, blocks: u32, address: &Address) -> Result<Vec<BlockHash>, ChainError> {
+        let hashes = self.call("generatetoaddress", json!([blocks, address.to_string()]))?;
+        let hashes = hashes.as_array().into_iter().flatten().map(|hash| hash.as_str().and_then(|h| h.parse().ok()));
+
+        hashes.collect::<Option<_>>().ok_or_else(|| ChainError("bitcoind answered an invalid block hash".into()))
+    }
+
+    // The confirmed txs paying to `script_pubkeys` that are still unspent, with their blocks. The node
+    // looks them up in its utxo set, so it doesn't need a wallet or `-txindex`.
+    fn scan_utxos(&self, script_pubkeys: &[Script]) -> Result<Vec<(OutPoint, BlockTime)>, ChainError> {
+        let descriptors: Vec<_> = script_pubkeys.iter().map(|spk| format!("raw({})", spk.to_hex())).collect();
+        let scan = self.call("scantxoutset", json!(["start", descriptors]))?;
+
+        let mut blocks = HashMap::new();
+        let mut utxos = Vec::new();
+        for utxo in scan["unspents"].as_array().into_iter().flatten() {
+            let txid = utxo["txid"].as_str().and_then(|txid| Txid::from_str(txid).ok());
+            let (Some(txid), Some(vout), Some(height)) = (txid, utxo["vout"].as_u64(), utxo["height"].as_u64()) else {
+                return Err(ChainError("bitcoind answered scantxoutset with an invalid utxo".to_string()));
+            };
+            let block = match blocks.entry(height) {
+                Entry::Occupied(block) => block.into_mut(),
+                Entry::Vacant(entry) => {
+                    let hash = self.call("getblockhash", json!([height]))?;
+                    let header = self.call("getblockheader", json!([hash]))?;
+                    let timestamp = header["time"].as_u64().unwrap_or_default();
+                    entry.insert(BlockTime { height: height as u32, timestamp })
+                },
+            };
+            utxos.push((OutPoint { txid, vout: vout as u32 }, block.clone()));
+        }
+
+        Ok(utxos)
+    }
+
+    // A tx mined at `block`, found without `-txindex`
+    fn get_mined_tx(&self, txid: &Txid, block: &BlockTime) -> Result<Transaction, ChainError> {
+        let hash = self.call("getblockhash", json!([block.height]))?;
+        let hex = self.call("getrawtransaction", json!([txid.to_string(), false, hash]))?;
+
+        parse_tx(txid, &hex)
+    }
+}
+
+fn parse_tx(txid: &Txid, hex: &Value) -> Result<Transaction, ChainError> {
+    hex.as_str().and_then(|hex| Vec::<u8>::from_hex(hex).ok()).and_then(|bytes| deserialize(&bytes).ok())
+        .ok_or_else(|| ChainError(format!("bitcoind answered tx {txid} with an invalid tx")))
+}
+
+impl ChainAccess for BitcoindChain {
+    fn broadcast(&self, tx: &Transaction) -> Result<(), BroadcastError> {
+        match self.answer("sendrawtransaction", json!([serialize_hex(tx)]))? {
+            Ok(_) => Ok(()),
+            Err((code, message)) if RPC_TX_REFUSED.contains(&code) => {
+                Err(BroadcastError::Rejected(format!("bitcoind rejected tx {}: {message}", tx.txid())))
+            },
+            Err((_, message)) => Err(ChainError(format!("bitcoind failed sendrawtransaction: {message}")).into()),
+        }
+    }
+
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, ChainError> {
+        let hex = self.call_opt("getrawtransaction", json!([txid.to_string(), false]))?;
+
+        hex.map(|hex| parse_tx(txid, &hex)).transpose()
+    }
+
+    fn tip_height(&self) -> Result<u32, ChainError> {
+        let height = self.call("getblockcount", json!([]))?;
+
+        height.as_u64().map(|height| height as u32)
+            .ok_or_else(|| ChainError("bitcoind didn't tell the tip height".to_string()))
+    }
+
+    // Without `-txindex` the node only knows mined txs with an unspent output, so we look for that
+    // of `script_pubkey`
+    fn confirmations(&self, txid: &Txid, script_pubkey: &Script) -> Result<u32, ChainError> {
+        if let Some(tx) = self.call_opt("getrawtransaction", json!([txid.to_string(), true]))? {
+            return Ok(tx["confirmations"].as_u64().unwrap_or_default() as u32);
+        }
+        let utxos = self.scan_utxos(std::slice::from_ref(script_pubkey))?;
+        let Some((_, block)) = utxos.iter().find(|(outpoint, _)| outpoint.txid == *txid) else {
+            return Ok(0);
+        };
+
+        Ok((self.tip_height()? + 1).saturating_sub(block.height))
+    }
+
+    // The node has no utxo for an output spent in a block or in its mempool, and we only ask about
+    // outputs of confirmed txs
+    fn spent(&self, outpoint: &OutPoint, _script_pubkey: &Script) -> Result<bool, ChainError> {
+        let utxo = self.call("gettxout", json!([outpoint.txid.to_string(), outpoint.vout, true]))?;
+
+        Ok(utxo.is_null())
+    }
+
+    fn sync_wallet(&self, wallet: &Wallet<AnyDatabase>) -> Result<(), ChainError> {
+        wallet.sync(self, SyncOptions::default()).map_err(|e| ChainError(e.to_string()))
+    }
+
+    fn test_accept(&self, tx: &Transaction) -> Result<Option<String>, ChainError> {
+        let results = self.call("testmempoolaccept", json!([[serialize_hex(tx)]]))?;
+        let result = &results[0];
+        if result["allowed"].as_bool() == Some(true) {
+            return Ok(None);
+        }
+
+        Ok(Some(result["reject-reason"].as_str().unwrap_or("no reason given").to_string()))
+    }
+}
+
+// The node estimates in BTC/kvB and answers errors instead without enough data, and we never go below
+// the min relay feerate
+impl FeeEstimator for BitcoindChain {
+    fn fee_rate(&self, target: usize) -> Result<FeeRate, ChainError> {
+        let estimate = self.call("estimatesmartfee", json!([target]))?;
+        let sat_per_vb = estimate["feerate"].as_f64().map_or(1.0, |btc_per_kvb| (btc_per_kvb * 100_000.0) as f32);
+
+        Ok(FeeRate::from_sat_per_vb(if sat_per_vb >= 1.0 { sat_per_vb } else { 1.0 }))
+    }
+}
+
+impl GetHeight for BitcoindChain {
+    fn get_height(&self) -> Result<u32, bdk::Error> {
+        self.tip_height().map_err(|e| bdk::Error::Generic(e.to_string()))
+    }
+}
+
+impl WalletSync for BitcoindChain {
+    // Scans the utxo set of the node for the cached wallet scripts. Only confirmed coins are found, and
+    // those spent in the mempool are marked as spent.
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &RefCell<D>,
+        _progress_update: Box<dyn Progress>,
+    ) -> Result<(), bdk::Error> {
+        let chain_error = |e: ChainError| bdk::Error::Generic(e.to_string());
+        let script_pubkeys = database.borrow().iter_script_pubkeys(None)?;
+        let utxos = self.scan_utxos(&script_pubkeys).map_err(chain_error)?;
+
+        let mut txs = Vec::new();
+        let mut seen = HashSet::new();
+        for (outpoint, block) in &utxos {
+            if seen.insert(outpoint.txid) {
+                txs.push((self.get_mined_tx(&outpoint.txid, block).map_err(chain_error)?, Some(block.clone())));
+            }
+        }
+        let mut database = database.borrow_mut();
+        record_wallet_txs(&mut *database, &txs)?;
+
+        // The rest were spent in a block, and `gettxout` tells which ones are spent in the mempool
+        let unspent: HashSet<_> = utxos.iter().map(|(outpoint, _)| *outpoint).collect();
+        for mut utxo in database.iter_utxos()? {
+            let is_spent = !unspent.contains(&utxo.outpoint) || {
+                let params = json!([utxo.outpoint.txid.to_string(), utxo.outpoint.vout, true]);
+                self.call("gettxout", params).map_err(chain_error)?.is_null()
+            };
+            if utxo.is_spent != is_spent {
+                utxo.is_spent = is_spent;
+                database.set_utxo(&utxo)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BitcoindChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BitcoindChain({:?})", self.http)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::mpsc::Receiver;
+
+    use bdk::bitcoin::hashes::Hash;
+    use bdk::bitcoin::{Network, PackedLockTime, TxIn, TxOut};
+
+    use super::*;
+    use crate::http::stub_server;
+
+    fn result(result: Value) -> (u16, String) {
+        (200, json!({ "result": result, "error": null, "id": "joinswap" }).to_string())
+    }
+
+    // Failed calls come with a 500 status
+    fn error(code: i64, message: &str) -> (u16, String) {
+        (500, json!({ "result": null, "error": { "code": code, "message": message }, "id": "joinswap" }).to_string())
+    }
+
+    fn node(answers: Vec<(u16, String)>, auth: RpcAuth) -> (BitcoindChain, Receiver<String>) {
+        let (url, requests) = stub_server(answers);
+        (BitcoindChain::new(&url, auth).unwrap(), requests)
+    }
+
+    fn user_pass() -> RpcAuth {
+        RpcAuth::UserPass { user: "alice".to_string(), password: "secret".to_string() }
+    }
+
+    // The method and params of a call the node got
+    fn call_of(request: &str) -> (String, Value) {
+        let call: Value = serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        (call["method"].as_str().unwrap().to_string(), call["params"].clone())
+    }
+
+    fn tx() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn::default()],
+            output: vec![TxOut { value: 50_000, script_pubkey: Script::new_op_return(&[]) }],
+        }
+    }
+
+    #[test]
+    fn cookie_and_user_pass_auth() {
+        let (chain, requests) = node(vec![result(json!(800))], user_pass());
+        assert_eq!(chain.tip_height().unwrap(), 800);
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST / HTTP/1.0\r\n"), "{request}");
+        assert!(request.contains(&format!("Authorization: Basic {}\r\n", base64::encode("alice:secret"))));
+        assert_eq!(call_of(&request), ("getblockcount".to_string(), json!([])));
+
+        // The cookie is read again on each call, as the node writes a new one when it restarts
+        let cookie = env::temp_dir().join(format!("joinswap-bitcoind-cookie-{}", std::process::id()));
+        fs::write(&cookie, "__cookie__:first\n").unwrap();
+        let (chain, requests) = node(vec![result(json!(800)), result(json!(801))], RpcAuth::Cookie(cookie.clone()));
+        chain.tip_height().unwrap();
+        assert!(requests.recv().unwrap().contains(&base64::encode("__cookie__:first")));
+        fs::write(&cookie, "__cookie__:second").unwrap();
+        assert_eq!(chain.tip_height().unwrap(), 801);
+        assert!(requests.recv().unwrap().contains(&base64::encode("__cookie__:second")));
+
+        // Without the cookie nothing is sent
+        fs::remove_file(&cookie).unwrap();
+        assert!(chain.tip_height().unwrap_err().0.contains("Can't read the bitcoind cookie"));
+
+        let (chain, _) = node(vec![(401, String::new())], user_pass());
+        assert!(chain.tip_height().unwrap_err().0.contains("rejected our RPC credentials"));
+    }
+
+    // Refused txs are rejected, for a pricier one to be tried, but a node we can't use is a chain error
+    #[test]
+    fn broadcast_rejections() {
+        let answers = vec![
+            result(json!(tx().txid().to_string())),
+            error(-26, "min relay fee not met"),
+            error(-25, "bad-txns-inputs-missingorspent"),
+            error(-28, "Loading block index..."),
+            (503, "Service Unavailable".to_string()),
+        ];
+        let (chain, requests) = node(answers, user_pass());
+
+        chain.broadcast(&tx()).unwrap();
+        let params = json!([serialize_hex(&tx())]);
+        assert_eq!(call_of(&requests.recv().unwrap()), ("sendrawtransaction".to_string(), params));
+        let rejected =
+            |reason| matches!(chain.broadcast(&tx()), Err(BroadcastError::Rejected(e)) if e.contains(reason));
+        assert!(rejected("min relay fee not met"));
+        assert!(rejected("missingorspent"));
+        let failed = |reason| matches!(chain.broadcast(&tx()), Err(BroadcastError::Chain(e)) if e.0.contains(reason));
+        assert!(failed("Loading block index"));
+        assert!(failed("with 503"));
+    }
+
+    #[test]
+    fn test_accept_reasons() {
+        let answers = vec![
+            result(json!([{ "txid": tx().txid().to_string(), "allowed": true }])),
+            result(json!([{ "txid": tx().txid().to_string(), "allowed": false, "reject-reason": "non-BIP68-final" }])),
+            result(json!([{ "txid": tx().txid().to_string(), "allowed": false }])),
+            error(-22, "TX decode failed"),
+        ];
+        let (chain, requests) = node(answers, user_pass());
+
+        assert_eq!(chain.test_accept(&tx()).unwrap(), None);
+        let params = json!([[serialize_hex(&tx())]]);
+        assert_eq!(call_of(&requests.recv().unwrap()), ("testmempoolaccept".to_string(), params));
+        assert_eq!(chain.test_accept(&tx()).unwrap(), Some("non-BIP68-final".to_string()));
+        assert_eq!(chain.test_accept(&tx()).unwrap(), Some("no reason given".to_string()));
+        assert!(chain.test_accept(&tx()).unwrap_err().0.contains("TX decode failed"));
+    }
+
+    // From BTC/kvB, never below the min relay feerate, even when the node has no estimate
+    #[test]
+    fn fee_estimates() {
+        let answers = vec![
+            result(json!({ "feerate": 0.0002, "blocks": 6 })),
+            result(json!({ "errors": ["Insufficient data or no feerate found"], "blocks": 0 })),
+            result(json!({ "feerate": 0.000005, "blocks": 6 })),
+            error(-8, "Invalid conf_target, must be between 1 and 1008"),
+        ];
+        let (chain, requests) = node(answers, user_pass());
+
+        assert_eq!(chain.fee_rate(6).unwrap(), FeeRate::from_sat_per_vb(20.0));
+        assert_eq!(call_of(&requests.recv().unwrap()), ("estimatesmartfee".to_string(), json!([6])));
+        assert_eq!(chain.fee_rate(6).unwrap(), FeeRate::from_sat_per_vb(1.0));
+        assert_eq!(chain.fee_rate(6).unwrap(), FeeRate::from_sat_per_vb(1.0));
+        assert!(chain.fee_rate(2000).unwrap_err().0.contains("Invalid conf_target"));
+    }
+
+    #[test]
+    fn unknown_txs() {
+        let answers = vec![
+            error(RPC_INVALID_ADDRESS_OR_KEY, "No such mempool or blockchain transaction"),
+            result(json!(serialize_hex(&tx()))),
+            result(json!("not a tx")),
+        ];
+        let (chain, _) = node(answers, user_pass());
+
+        assert_eq!(chain.get_tx(&tx().txid()).unwrap(), None);
+        assert_eq!(chain.get_tx(&tx().txid()).unwrap(), Some(tx()));
+        assert!(chain.get_tx(&tx().txid()).unwrap_err().0.contains("invalid tx"));
+    }
+
+    // Blocks mined on regtest to expire the timelocks
+    #[test]
+    fn blocks_on_demand() {
+        let hashes = [BlockHash::from_inner([1; 32]), BlockHash::from_inner([2; 32])];
+        let answers = vec![
+            result(json!(hashes.map(|hash| hash.to_string()))),
+            result(json!(["not a block hash"])),
+        ];
+        let (chain, requests) = node(answers, user_pass());
+        let address = Address::from_str("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080").unwrap();
+        assert_eq!(address.network, Network::Regtest);
+
+        assert_eq!(chain.generate_to_address(2, &address).unwrap(), hashes);
+        let params = json!([2, address.to_string()]);
+        assert_eq!(call_of(&requests.recv().unwrap()), ("generatetoaddress".to_string(), params));
+        assert!(chain.generate_to_address(1, &address).unwrap_err().0.contains("invalid block hash"));
+    }
+}
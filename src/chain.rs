@@ -0,0 +1,415 @@
+// Access to the blockchain used by the sessions to broadcast and look up transactions, and to keep
+// the maker wallet up to date. The maker also asks it the feerates of the txs it builds.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use bdk::bitcoin::{OutPoint, PackedLockTime, Script, Transaction, TxIn, TxOut, Txid};
+use bdk::blockchain::{GetHeight, Progress, WalletSync};
+use bdk::database::{AnyDatabase, BatchDatabase};
+use bdk::{BlockTime, FeeRate, LocalUtxo, SyncOptions, TransactionDetails, Wallet};
+
+#[cfg(feature = "electrum")]
+use bdk::blockchain::{Blockchain, ElectrumBlockchain, GetTx};
+#[cfg(feature = "electrum")]
+use bdk::electrum_client::{Client, ElectrumApi};
+
+#[derive(Debug)]
+pub struct ChainError(pub String);
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Chain backend error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+// Why a broadcast failed
+#[derive(Debug)]
+pub enum BroadcastError {
+    // The node refused the tx, by consensus or by its mempool policy, for the reason given
+    Rejected(String),
+    // We couldn't ask it, so we don't know if it would take the tx
+    Chain(ChainError),
+}
+
+impl fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BroadcastError::Rejected(reason) => write!(f, "{reason}"),
+            BroadcastError::Chain(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BroadcastError {}
+
+impl From<ChainError> for BroadcastError {
+    fn from(e: ChainError) -> Self {
+        BroadcastError::Chain(e)
+    }
+}
+
+impl From<BroadcastError> for ChainError {
+    fn from(e: BroadcastError) -> Self {
+        match e {
+            BroadcastError::Rejected(reason) => ChainError(reason),
+            BroadcastError::Chain(e) => e,
+        }
+    }
+}
+
+pub trait ChainAccess: Send + Sync {
+    fn broadcast(&self, tx: &Transaction) -> Result<(), BroadcastError>;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, ChainError>;
+
+    // Height of the best block, the locktime of the txs we build (see `anti_fee_sniping_locktime`)
+    fn tip_height(&self) -> Result<u32, ChainError>;
+
+    // Blocks on top of the one `txid` is in, that one included, or 0 while unconfirmed. Some backends look
+    // txs up by the scripts they pay, so it takes one.
+    fn confirmations(&self, txid: &Txid, script_pubkey: &Script) -> Result<u32, ChainError>;
+
+    // Whether a tx in a block or in the mempool spends `outpoint`, the output paying `script_pubkey` of a
+    // confirmed tx
+    fn spent(&self, outpoint: &OutPoint, script_pubkey: &Script) -> Result<bool, ChainError>;
+
+    // Brings the txs and utxos of `wallet` up to date
+    fn sync_wallet(&self, wallet: &Wallet<AnyDatabase>) -> Result<(), ChainError>;
+
+    // Why the mempool of our node would reject `tx`, if it would, without broadcasting it. Backends
+    // that can't tell take every tx.
+    fn test_accept(&self, _tx: &Transaction) -> Result<Option<String>, ChainError> {
+        Ok(None)
+    }
+}
+
+// Runs a call to `chain` on the blocking thread pool, as backends wait on the network and the sessions
+// of other rounds would wait with them
+pub async fn chain_call<T: Send + 'static, E: Send + 'static>(
+    chain: &Arc<dyn ChainAccess>,
+    call: impl FnOnce(&dyn ChainAccess) -> Result<T, E> + Send + 'static,
+) -> Result<T, E> {
+    let chain = chain.clone();
+
+    tokio::task::spawn_blocking(move || call(chain.as_ref())).await.unwrap()
+}
+
+// Feerate for a tx to confirm within `target` blocks
+pub trait FeeEstimator: fmt::Debug + Send + Sync {
+    fn fee_rate(&self, target: usize) -> Result<FeeRate, ChainError>;
+}
+
+// Like `chain_call`, for the estimators that ask a backend
+pub async fn estimate_fee_rate(estimator: &Arc<dyn FeeEstimator>, target: usize) -> Result<FeeRate, ChainError> {
+    let estimator = estimator.clone();
+
+    tokio::task::spawn_blocking(move || estimator.fee_rate(target)).await.unwrap()
+}
+
+// The same feerate whatever the target, for the demo or a maker that sets its own
+#[derive(Debug, Clone, Copy)]
+pub struct FixedFeeRate(pub FeeRate);
+
+impl FeeEstimator for FixedFeeRate {
+    fn fee_rate(&self, _target: usize) -> Result<FeeRate, ChainError> {
+        Ok(self.0)
+    }
+}
+
+// Keeps broadcast transactions in memory. Stands in for a real backend in the demo, where nothing
+// is actually sent to the network.
+#[derive(Debug, Default)]
+pub struct MemoryChain {
+    txs: Mutex<HashMap<Txid, Transaction>>,
+    // Txs kept out of blocks, see `hold_in_mempool`
+    mempool: Mutex<HashSet<Txid>>,
+    // Height of the block each tx was mined in
+    heights: Mutex<HashMap<Txid, u32>>,
+    // Blocks mined on top of `MEMORY_CHAIN_HEIGHT`, see `mine`
+    mined: Mutex<u32>,
+}
+
+// Everything broadcast counts as mined in the tip block, unless held in the mempool. The tip starts
+// at this height.
+const MEMORY_CHAIN_HEIGHT: u32 = 100;
+
+impl MemoryChain {
+    // Makes up a tx paying each of `values` to `script_pubkey`, standing in for the coins a demo
+    // wallet already had
+    pub fn fund(&self, script_pubkey: &Script, values: &[u64]) -> Txid {
+        let tx = Transaction {
+            version: 1,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn { previous_output: OutPoint { vout: 0, ..Default::default() }, ..Default::default() }],
+            output: values.iter().map(|value| TxOut { value: *value, script_pubkey: script_pubkey.clone() }).collect(),
+        };
+        self.txs.lock().unwrap().insert(tx.txid(), tx.clone());
+        self.heights.lock().unwrap().insert(tx.txid(), self.tip());
+
+        tx.txid()
+    }
+
+    // Keeps `txid` unconfirmed, as if no block took it yet
+    pub fn hold_in_mempool(&self, txid: Txid) {
+        self.mempool.lock().unwrap().insert(txid);
+    }
+
+    // Mines `blocks` empty blocks on top of the tip, so the txs broadcast before get as many more
+    // confirmations
+    pub fn mine(&self, blocks: u32) {
+        *self.mined.lock().unwrap() += blocks;
+    }
+
+    fn tip(&self) -> u32 {
+        MEMORY_CHAIN_HEIGHT + *self.mined.lock().unwrap()
+    }
+
+    fn confirmations_of(&self, txid: &Txid) -> u32 {
+        if self.mempool.lock().unwrap().contains(txid) {
+            return 0;
+        }
+        let height = self.heights.lock().unwrap().get(txid).copied();
+
+        height.map_or(0, |height| self.tip() + 1 - height)
+    }
+}
+
+impl ChainAccess for MemoryChain {
+    // A tx spending what another one spent replaces it, as with BIP125. As with BIP68, an input with a
+    // relative timelock of n blocks must spend a tx with n confirmations, if we know that tx.
+    fn broadcast(&self, tx: &Transaction) -> Result<(), BroadcastError> {
+        for txin in tx.input.iter().filter(|txin| tx.version >= 2 && txin.sequence.is_height_locked()) {
+            let (parent, blocks) = (txin.previous_output.txid, txin.sequence.0 & 0xffff);
+            let known = self.txs.lock().unwrap().contains_key(&parent);
+            if known && self.confirmations_of(&parent) < blocks {
+                let reason = format!("non-BIP68-final: {parent} has fewer than {blocks} confirmations");
+                return Err(BroadcastError::Rejected(reason));
+            }
+        }
+        let tip = self.tip();
+        let mut txs = self.txs.lock().unwrap();
+        let spends = |other: &Transaction| other.input.iter().any(|txin| {
+            tx.input.iter().any(|spent| spent.previous_output == txin.previous_output)
+        });
+        txs.retain(|_, other| !spends(other));
+        txs.insert(tx.txid(), tx.clone());
+        self.heights.lock().unwrap().insert(tx.txid(), tip);
+
+        Ok(())
+    }
+
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, ChainError> {
+        Ok(self.txs.lock().unwrap().get(txid).cloned())
+    }
+
+    fn tip_height(&self) -> Result<u32, ChainError> {
+        Ok(self.tip())
+    }
+
+    fn confirmations(&self, txid: &Txid, _script_pubkey: &Script) -> Result<u32, ChainError> {
+        let known = self.txs.lock().unwrap().contains_key(txid);
+
+        Ok(if known { self.confirmations_of(txid) } else { 0 })
+    }
+
+    fn spent(&self, outpoint: &OutPoint, _script_pubkey: &Script) -> Result<bool, ChainError> {
+        let txs = self.txs.lock().unwrap();
+
+        Ok(txs.values().flat_map(|tx| &tx.input).any(|txin| txin.previous_output == *outpoint))
+    }
+
+    fn sync_wallet(&self, wallet: &Wallet<AnyDatabase>) -> Result<(), ChainError> {
+        wallet.sync(self, SyncOptions::default()).map_err(|e| ChainError(e.to_string()))
+    }
+}
+
+impl GetHeight for MemoryChain {
+    fn get_height(&self) -> Result<u32, bdk::Error> {
+        Ok(self.tip())
+    }
+}
+
+impl WalletSync for MemoryChain {
+    // Every tx is looked at on each sync, there are only those of this run
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &RefCell<D>,
+        _progress_update: Box<dyn Progress>,
+    ) -> Result<(), bdk::Error> {
+        let (mempool, heights) = (self.mempool.lock().unwrap(), self.heights.lock().unwrap());
+        let mined = |txid| {
+            let height = heights.get(&txid).copied().filter(|_| !mempool.contains(&txid))?;
+            Some(BlockTime { height, timestamp: 0 })
+        };
+        let txs: Vec<_> = self.txs.lock().unwrap().values().map(|tx| (tx.clone(), mined(tx.txid()))).collect();
+
+        record_wallet_txs(&mut *database.borrow_mut(), &txs)
+    }
+}
+
+// Stores the utxos and txs of the wallet of `database` out of `txs`, each with the block it was mined in
+// if any. Any tx spending a wallet utxo must be among them, as that's how we know it's spent.
+pub(crate) fn record_wallet_txs<D: BatchDatabase>(
+    database: &mut D,
+    txs: &[(Transaction, Option<BlockTime>)],
+) -> Result<(), bdk::Error> {
+    let spent: HashSet<_> = txs.iter().flat_map(|(tx, _)| &tx.input).map(|txin| txin.previous_output).collect();
+
+    // Our outputs first, as the inputs spending them tell what each tx sent
+    let mut ours = HashMap::new();
+    for (tx, _) in txs {
+        for (vout, txout) in tx.output.iter().enumerate() {
+            let Some((keychain, _)) = database.get_path_from_script_pubkey(&txout.script_pubkey)? else {
+                continue;
+            };
+            let outpoint = OutPoint { txid: tx.txid(), vout: vout as u32 };
+            let is_spent = spent.contains(&outpoint);
+            database.set_utxo(&LocalUtxo { outpoint, txout: txout.clone(), keychain, is_spent })?;
+            ours.insert(outpoint, txout.value);
+        }
+    }
+    for (tx, confirmation_time) in txs {
+        let txid = tx.txid();
+        let received = (0..tx.output.len() as u32)
+            .filter_map(|vout| ours.get(&OutPoint { txid, vout }))
+            .sum::<u64>();
+        let sent = tx.input.iter().filter_map(|txin| ours.get(&txin.previous_output)).sum::<u64>();
+        if received == 0 && sent == 0 {
+            continue;
+        }
+        database.set_tx(&TransactionDetails {
+            transaction: Some(tx.clone()),
+            txid,
+            received,
+            sent,
+            fee: None,
+            confirmation_time: confirmation_time.clone(),
+        })?;
+    }
+    Ok(())
+}
+
+// An Electrum server, like electrs in front of a regtest node
+#[cfg(feature = "electrum")]
+pub struct ElectrumChain(ElectrumBlockchain);
+
+#[cfg(feature = "electrum")]
+impl ElectrumChain {
+    pub fn connect(url: &str) -> Result<Self, ChainError> {
+        let client = Client::new(url).map_err(|e| ChainError(format!("Can't connect to {url}: {e}")))?;
+
+        Ok(ElectrumChain(ElectrumBlockchain::from(client)))
+    }
+}
+
+#[cfg(feature = "electrum")]
+impl ChainAccess for ElectrumChain {
+    fn broadcast(&self, tx: &Transaction) -> Result<(), BroadcastError> {
+        self.0.broadcast(tx).map_err(|e| match e {
+            // The server passes on the refusal of its node
+            bdk::Error::Electrum(bdk::electrum_client::Error::Protocol(reason)) => {
+                BroadcastError::Rejected(format!("Electrum server rejected tx {}: {reason}", tx.txid()))
+            },
+            e => BroadcastError::Chain(ChainError(e.to_string())),
+        })
+    }
+
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, ChainError> {
+        self.0.get_tx(txid).map_err(|e| ChainError(e.to_string()))
+    }
+
+    fn tip_height(&self) -> Result<u32, ChainError> {
+        self.0.get_height().map_err(|e| ChainError(e.to_string()))
+    }
+
+    // The server tells the height of each tx paying `script_pubkey`, 0 or less while unconfirmed
+    fn confirmations(&self, txid: &Txid, script_pubkey: &Script) -> Result<u32, ChainError> {
+        let history = ElectrumApi::script_get_history(&*self.0, script_pubkey).map_err(|e| ChainError(e.to_string()))?;
+        let Some(entry) = history.iter().find(|entry| entry.tx_hash == *txid && entry.height > 0) else {
+            return Ok(0);
+        };
+
+        Ok((self.tip_height()? + 1).saturating_sub(entry.height as u32))
+    }
+
+    // The history of `script_pubkey` also has the txs spending from it
+    fn spent(&self, outpoint: &OutPoint, script_pubkey: &Script) -> Result<bool, ChainError> {
+        let history = ElectrumApi::script_get_history(&*self.0, script_pubkey).map_err(|e| ChainError(e.to_string()))?;
+        for entry in history.iter().filter(|entry| entry.tx_hash != outpoint.txid) {
+            let spends = |tx: Transaction| tx.input.iter().any(|txin| txin.previous_output == *outpoint);
+            if self.get_tx(&entry.tx_hash)?.is_some_and(spends) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn sync_wallet(&self, wallet: &Wallet<AnyDatabase>) -> Result<(), ChainError> {
+        wallet.sync(&self.0, SyncOptions::default()).map_err(|e| ChainError(e.to_string()))
+    }
+}
+
+// The estimates of the server's node, in BTC/kvB. A node without enough data answers -1, and we never go
+// below the min relay feerate.
+#[cfg(feature = "electrum")]
+impl FeeEstimator for ElectrumChain {
+    fn fee_rate(&self, target: usize) -> Result<FeeRate, ChainError> {
+        let btc_per_kvb = ElectrumApi::estimate_fee(&*self.0, target).map_err(|e| ChainError(e.to_string()))?;
+        let sat_per_vb = (btc_per_kvb * 100_000.0) as f32;
+
+        Ok(FeeRate::from_sat_per_vb(if sat_per_vb >= 1.0 { sat_per_vb } else { 1.0 }))
+    }
+}
+
+#[cfg(feature = "electrum")]
+impl fmt::Debug for ElectrumChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ElectrumChain")
+    }
+}
+
+// A chain backend we connect to, which also estimates feerates
+pub struct ChainBackend {
+    pub chain: Arc<dyn ChainAccess>,
+    pub fee_estimator: Arc<dyn FeeEstimator>,
+}
+
+// How we log in to the JSON-RPC of bitcoind
+#[derive(Debug, Clone)]
+pub enum RpcAuth {
+    // The `.cookie` file the node writes in its data dir on each start
+    Cookie(PathBuf),
+    UserPass { user: String, password: String },
+}
+
+// Connects to the chain backend named as its feature, `electrum`, `esplora` or `bitcoind`, at `url`.
+// bitcoind needs `rpc_auth`.
+#[cfg_attr(not(feature = "bitcoind"), allow(unused_variables))]
+pub fn connect_backend(name: &str, url: &str, rpc_auth: Option<RpcAuth>) -> Result<ChainBackend, ChainError> {
+    match name {
+        #[cfg(feature = "electrum")]
+        "electrum" => {
+            let chain = Arc::new(ElectrumChain::connect(url)?);
+            Ok(ChainBackend { chain: chain.clone(), fee_estimator: chain })
+        },
+        #[cfg(feature = "esplora")]
+        "esplora" => {
+            let chain = Arc::new(crate::esplora::EsploraChain::new(url)?);
+            Ok(ChainBackend { chain: chain.clone(), fee_estimator: chain })
+        },
+        #[cfg(feature = "bitcoind")]
+        "bitcoind" => {
+            let rpc_auth =
+                rpc_auth.ok_or_else(|| ChainError("bitcoind needs a cookie file or a user and password".into()))?;
+            let chain = Arc::new(crate::bitcoind::BitcoindChain::new(url, rpc_auth)?);
+            Ok(ChainBackend { chain: chain.clone(), fee_estimator: chain })
+        },
+        _ => Err(ChainError(format!("The {name} backend needs the {name} feature"))),
+    }
+}
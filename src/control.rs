@@ -0,0 +1,344 @@
+// JSON-RPC interface for operators to script the maker. Requests and responses are JSON-RPC 2.0
+// objects, one per line, over a connection to the control address. There's no authentication, so
+// it must only be reachable by the operator (on localhost or a Unix socket).
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
+
+use crate::events::{AbortCode, ContractKind, Leg, Phase, ProtocolEvent};
+use crate::transport::{Acceptor, Connection, Framing};
+
+// Largest request line we read
+const MAX_REQUEST_SIZE: usize = 10_000;
+
+// Finished rounds still listed by `list_sessions`
+const MAX_FINISHED_SESSIONS: usize = 100;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStatus {
+    Active,
+    Completed,
+    Aborted,
+}
+
+// A round as seen from the control interface
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    // Rounds are numbered from 1 since the maker started
+    pub id: u64,
+    pub status: SessionStatus,
+    pub phase: Phase,
+    pub users2maker_address: Option<String>,
+    pub users2maker_amount: Option<u64>,
+    pub maker2user_addresses: Vec<String>,
+    // Sats sent to each second leg user, on-chain or through Lightning
+    pub payouts: Vec<u64>,
+    pub profit: Option<u64>,
+    pub abort_code: Option<AbortCode>,
+    pub abort_reason: Option<String>,
+}
+
+impl SessionInfo {
+    fn new(id: u64) -> Self {
+        SessionInfo {
+            id,
+            status: SessionStatus::Active,
+            phase: Phase::Connect,
+            users2maker_address: None,
+            users2maker_amount: None,
+            maker2user_addresses: Vec::new(),
+            payouts: Vec::new(),
+            profit: None,
+            abort_code: None,
+            abort_reason: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RegistryState {
+    // Users each round starts with
+    round_users: usize,
+    // Users connected and waiting for the next round to fill up
+    users_waiting: usize,
+    rounds_completed: u64,
+    rounds_aborted: u64,
+    profit: u64,
+    // Rounds run at once, by id
+    active: BTreeMap<u64, SessionInfo>,
+    // Most recent last
+    finished: VecDeque<SessionInfo>,
+}
+
+impl RegistryState {
+    fn session(&mut self, round: u64) -> Option<&mut SessionInfo> {
+        self.active.get_mut(&round)
+    }
+
+    fn finish(&mut self, round: u64, status: SessionStatus, profit: Option<u64>, abort: Option<(AbortCode, String)>) {
+        let Some(mut session) = self.active.remove(&round) else { return };
+        session.status = status;
+        session.profit = profit;
+        (session.abort_code, session.abort_reason) = abort.unzip();
+
+        if self.finished.len() == MAX_FINISHED_SESSIONS {
+            self.finished.pop_front();
+        }
+        self.finished.push_back(session);
+    }
+}
+
+// State of the maker shared with the control interface. The maker binary feeds it the protocol
+// events of each round, numbered from 1 in the order they start filling up, and the request
+// handlers read it.
+#[derive(Debug, Clone)]
+pub struct MakerRegistry(Arc<RwLock<RegistryState>>);
+
+impl MakerRegistry {
+    pub fn new(round_users: usize) -> Self {
+        MakerRegistry(Arc::new(RwLock::new(RegistryState { round_users, ..Default::default() })))
+    }
+
+    pub fn apply(&self, round: u64, event: &ProtocolEvent) {
+        let mut state = self.0.write().unwrap();
+
+        match event {
+            ProtocolEvent::PhaseEntered(Phase::Connect) => state.users_waiting = 0,
+            ProtocolEvent::WaitingForUsers { missing } => state.users_waiting = state.round_users - missing,
+            ProtocolEvent::PhaseEntered(phase) => {
+                if let Some(session) = state.session(round) {
+                    session.phase = *phase;
+                }
+            },
+            // The round starts once it has all its users
+            ProtocolEvent::PeerConnected { leg: Leg::First, .. } => {
+                state.users_waiting += 1;
+                if state.users_waiting == state.round_users {
+                    state.users_waiting = 0;
+                    state.active.insert(round, SessionInfo::new(round));
+                }
+            },
+            ProtocolEvent::ContractCreated { contract, address } => if let Some(session) = state.session(round) {
+                match contract {
+                    ContractKind::Users2Maker => session.users2maker_address = Some(address.to_string()),
+                    ContractKind::Maker2User(_) => session.maker2user_addresses.push(address.to_string()),
+                }
+            },
+            ProtocolEvent::ContractFunded { contract, amount } => if let Some(session) = state.session(round) {
+                match contract {
+                    ContractKind::Users2Maker => session.users2maker_amount = Some(*amount),
+                    ContractKind::Maker2User(_) => session.payouts.push(*amount),
+                }
+            },
+            ProtocolEvent::InvoicePaid { amount, .. } => if let Some(session) = state.session(round) {
+                session.payouts.push(*amount);
+            },
+            ProtocolEvent::Completed { profit } if state.active.contains_key(&round) => {
+                state.rounds_completed += 1;
+                state.profit += profit.unwrap_or_default();
+                state.finish(round, SessionStatus::Completed, *profit, None);
+            },
+            // Users we couldn't talk to are dropped without aborting a round
+            ProtocolEvent::Aborted { code, reason } if state.active.contains_key(&round) => {
+                state.rounds_aborted += 1;
+                state.finish(round, SessionStatus::Aborted, None, Some((*code, reason.clone())));
+            },
+            _ => {},
+        }
+    }
+
+    // Active rounds first, then the finished ones from the most recent
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let state = self.0.read().unwrap();
+
+        state.active.values().chain(state.finished.iter().rev()).cloned().collect()
+    }
+
+    pub fn profit(&self) -> u64 {
+        self.0.read().unwrap().profit
+    }
+
+    fn status(&self) -> Value {
+        let state = self.0.read().unwrap();
+
+        json!({
+            "users_waiting": state.users_waiting,
+            "active_sessions": state.active.keys().collect::<Vec<_>>(),
+            "rounds_completed": state.rounds_completed,
+            "rounds_aborted": state.rounds_aborted,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    method: String,
+}
+
+// Answers control requests until the process exits. `shutdown` cancels `drain`, after which the
+// maker finishes the rounds in progress (if any) and stops.
+pub async fn serve_control<A>(acceptor: A, registry: MakerRegistry, drain: CancellationToken)
+where
+    A: Acceptor,
+    A::Stream: 'static,
+{
+    loop {
+        // A failed accept only concerns that client
+        let Ok(stream) = acceptor.accept().await else { continue };
+        let registry = registry.clone();
+        let drain = drain.clone();
+
+        tokio::spawn(async move {
+            let mut conn = Connection::new(stream, Framing::Line);
+            while let Ok(line) = conn.reader.read_frame(MAX_REQUEST_SIZE).await {
+                let response = handle_request(&line, &registry, &drain);
+                if conn.writer.write_frame(&response.to_string()).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+fn handle_request(line: &str, registry: &MakerRegistry, drain: &CancellationToken) -> Value {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) if e.is_data() => return error_response(Value::Null, INVALID_REQUEST, "Invalid request"),
+        Err(_) => return error_response(Value::Null, PARSE_ERROR, "Parse error"),
+    };
+    if request.jsonrpc != "2.0" {
+        return error_response(request.id, INVALID_REQUEST, "Only JSON-RPC 2.0 is supported");
+    }
+
+    let result = match request.method.as_str() {
+        "get_status" => {
+            let mut status = registry.status();
+            status["draining"] = drain.is_cancelled().into();
+            status
+        },
+        "list_sessions" => serde_json::to_value(registry.sessions()).expect("Sessions always serialize"),
+        "get_profit" => json!({ "profit": registry.profit() }),
+        "shutdown" => {
+            drain.cancel();
+            json!({ "draining": true })
+        },
+        _ => return error_response(request.id, METHOD_NOT_FOUND, "Method not found"),
+    };
+
+    json!({ "jsonrpc": "2.0", "id": request.id, "result": result })
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk::bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use bdk::bitcoin::{Address, Network, Script};
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::transport::{memory_transport, Transport};
+
+    async fn call(conn: &mut Connection<DuplexStream>, request: &str) -> Value {
+        conn.writer.write_frame(request).await.unwrap();
+        serde_json::from_str(&conn.reader.read_frame(MAX_REQUEST_SIZE).await.unwrap()).unwrap()
+    }
+
+    async fn result(conn: &mut Connection<DuplexStream>, method: &str) -> Value {
+        let response = call(conn, &json!({ "jsonrpc": "2.0", "id": 7, "method": method }).to_string()).await;
+        assert_eq!(response["id"], 7);
+        response["result"].clone()
+    }
+
+    // The endpoints while the maker plays a round out, as its events reach the registry
+    #[tokio::test]
+    async fn endpoints_through_a_session() {
+        let key = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let (transport, acceptor) = memory_transport(key);
+        let (registry, drain) = (MakerRegistry::new(2), CancellationToken::new());
+        tokio::spawn(serve_control(acceptor, registry.clone(), drain.clone()));
+        let mut conn = Connection::new(transport.connect().await.unwrap(), Framing::Line);
+
+        registry.apply(1, &ProtocolEvent::PhaseEntered(Phase::Connect));
+        registry.apply(1, &ProtocolEvent::PeerConnected { leg: Leg::First, index: 0 });
+        registry.apply(1, &ProtocolEvent::WaitingForUsers { missing: 1 });
+        assert_eq!(result(&mut conn, "get_status").await["users_waiting"], 1);
+        assert_eq!(result(&mut conn, "list_sessions").await, json!([]));
+
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        for event in [
+            ProtocolEvent::PeerConnected { leg: Leg::First, index: 1 },
+            ProtocolEvent::PhaseEntered(Phase::ContractCreation),
+            ProtocolEvent::ContractCreated { contract: ContractKind::Users2Maker, address: address.clone() },
+            ProtocolEvent::ContractFunded { contract: ContractKind::Users2Maker, amount: 109_810 },
+            ProtocolEvent::PhaseEntered(Phase::SecondContractCreation),
+            ProtocolEvent::ContractFunded { contract: ContractKind::Maker2User(0), amount: 54_000 },
+        ] {
+            registry.apply(1, &event);
+        }
+        let status = result(&mut conn, "get_status").await;
+        assert_eq!(status, json!({
+            "users_waiting": 0,
+            "active_sessions": [1],
+            "rounds_completed": 0,
+            "rounds_aborted": 0,
+            "draining": false,
+        }));
+        let sessions = result(&mut conn, "list_sessions").await;
+        let [session] = &sessions.as_array().unwrap()[..] else {
+            panic!("Expected one session");
+        };
+        assert_eq!(session["id"], 1);
+        assert_eq!(session["status"], "active");
+        assert_eq!(session["phase"], serde_json::to_value(Phase::SecondContractCreation).unwrap());
+        assert_eq!(session["users2maker_address"], address.to_string());
+        assert_eq!(session["users2maker_amount"], 109_810);
+        assert_eq!(session["payouts"], json!([54_000]));
+        assert_eq!(result(&mut conn, "get_profit").await, json!({ "profit": 0 }));
+
+        registry.apply(1, &ProtocolEvent::Completed { profit: Some(1_810) });
+        assert_eq!(result(&mut conn, "get_profit").await, json!({ "profit": 1_810 }));
+        let sessions = result(&mut conn, "list_sessions").await;
+        assert_eq!((&sessions[0]["status"], &sessions[0]["profit"]), (&json!("completed"), &json!(1_810)));
+        assert_eq!(result(&mut conn, "get_status").await["rounds_completed"], 1);
+
+        assert!(!drain.is_cancelled());
+        assert_eq!(result(&mut conn, "shutdown").await, json!({ "draining": true }));
+        assert!(drain.is_cancelled());
+        assert_eq!(result(&mut conn, "get_status").await["draining"], true);
+    }
+
+    #[tokio::test]
+    async fn bad_requests() {
+        let key = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let (transport, acceptor) = memory_transport(key);
+        tokio::spawn(serve_control(acceptor, MakerRegistry::new(2), CancellationToken::new()));
+        let mut conn = Connection::new(transport.connect().await.unwrap(), Framing::Line);
+
+        let errors = [
+            ("{\"jsonrpc\":", PARSE_ERROR),
+            ("{\"jsonrpc\":\"2.0\"}", INVALID_REQUEST),
+            ("{\"jsonrpc\":\"1.0\",\"id\":1,\"method\":\"get_status\"}", INVALID_REQUEST),
+            ("{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"get_keys\"}", METHOD_NOT_FOUND),
+        ];
+        for (request, code) in errors {
+            assert_eq!(call(&mut conn, request).await["error"]["code"], code, "{request}");
+        }
+        // The connection is still served
+        assert_eq!(result(&mut conn, "get_profit").await, json!({ "profit": 0 }));
+    }
+}
@@ -0,0 +1,996 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bdk::bitcoin::{Network, OutPoint, PublicKey, Sequence, Txid};
+use bdk::bitcoin::psbt::PsbtSighashType;
+use bdk::miniscript::descriptor::DescriptorType;
+
+use crate::chain::{BroadcastError, ChainError};
+use crate::events::{AbortCode, Leg};
+use crate::lightning::PreimageError;
+use crate::{SigStatus, SigVerifyError, MAX_USER_UTXOS};
+
+// Something went wrong exchanging messages with a peer
+#[derive(Debug)]
+pub enum ProtocolError {
+    // The peer hung up
+    Eof,
+    // The peer sent a blank line where a message was expected
+    EmptyLine,
+    // The peer sent a line longer than we accept for that message
+    TooLong { max: usize },
+    // The peer sent whitespace around the message content
+    Padded,
+    // A message that doesn't follow the format of the named field
+    Malformed { field: &'static str, reason: String },
+    // A valid message, but not the one the protocol expects at this step
+    Unexpected { expected: &'static str, got: &'static str },
+    // The peer didn't send the message in time
+    Timeout(Duration),
+    // Includes messages that aren't valid UTF-8
+    Io(io::Error),
+    Keys(KeyParseError),
+    PrvKey(PrvKeyError),
+    // The keys the peer sent don't make a valid contract
+    ContractDesc(ContractDescError),
+    Psbt(PsbtReadError),
+    Utxo(UtxoError),
+    RefundAddr(RefundAddrError),
+    // The peer speaks another protocol version, or runs on another network
+    Incompatible(String),
+    // The peer dropped us, telling us why and its code for it
+    Rejected { code: AbortCode, reason: String },
+    // The user left the round before signing its funding input, telling us why
+    Declined(String),
+    // The maker offers us another round, as another user declined ours
+    Rematch(String),
+    // We don't take the terms the maker offered
+    TermsRejected(String),
+    // The Noise handshake failed, the peer doesn't hold the key we expect or doesn't speak Noise
+    Handshake(String),
+    // An encrypted message didn't authenticate, the connection can't be trusted anymore
+    Undecryptable,
+    // We refused to send the named secret over a connection that isn't encrypted
+    Unencrypted(&'static str),
+    // The peer didn't answer our last pings, nor sent anything else
+    ConnectionLost { missed: u32 },
+    // The peer sent the named message without signing it, after the hellos
+    Unsigned(&'static str),
+    // A signed message that doesn't verify, or signed with a key that isn't the peer's
+    BadSignature(String),
+    // A message of another session, replayed from it
+    WrongSession,
+    // A message of this session that repeats or skips some, by its number
+    OutOfOrder { expected: u64, got: u64 },
+    // The peer asked to resume a session we don't have, or no longer take back
+    UnknownSession,
+    // The peer asked to join the second leg of a round we aren't running
+    UnknownRound,
+    // An input added to the funding tx (see `AddInput`) that we don't take
+    AddedInput(AddInputError),
+}
+
+impl ProtocolError {
+    // Whether the connection is gone, so the peer may still come back on another one
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, ProtocolError::Eof | ProtocolError::Io(_) | ProtocolError::ConnectionLost { .. })
+    }
+
+    pub fn abort_code(&self) -> AbortCode {
+        match self {
+            error if error.is_disconnect() => AbortCode::PeerGone,
+            ProtocolError::Timeout(_) => AbortCode::PeerGone,
+            ProtocolError::Rejected { .. } => AbortCode::PeerRejected,
+            ProtocolError::Declined(_) | ProtocolError::Rematch(_) | ProtocolError::TermsRejected(_) => {
+                AbortCode::Declined
+            },
+            _ => AbortCode::PeerMisbehaved,
+        }
+    }
+
+    // Whether the peer sent us something invalid, in which case we tell it before dropping it
+    pub fn is_peer_fault(&self) -> bool {
+        matches!(
+            self,
+            ProtocolError::EmptyLine
+                | ProtocolError::TooLong { .. }
+                | ProtocolError::Padded
+                | ProtocolError::Malformed { .. }
+                | ProtocolError::Unexpected { .. }
+                | ProtocolError::Incompatible(_)
+                | ProtocolError::Keys(_)
+                | ProtocolError::PrvKey(_)
+                | ProtocolError::ContractDesc(_)
+                | ProtocolError::Psbt(_)
+                | ProtocolError::Utxo(_)
+                | ProtocolError::RefundAddr(_)
+                | ProtocolError::Unsigned(_)
+                | ProtocolError::BadSignature(_)
+                | ProtocolError::WrongSession
+                | ProtocolError::OutOfOrder { .. }
+                | ProtocolError::UnknownSession
+                | ProtocolError::UnknownRound
+                | ProtocolError::AddedInput(_)
+        )
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Eof => write!(f, "Peer disconnected"),
+            ProtocolError::EmptyLine => write!(f, "Peer sent an empty message"),
+            ProtocolError::TooLong { max } => write!(f, "Peer sent a message larger than {max} bytes"),
+            ProtocolError::Padded => write!(f, "Peer sent a message with surrounding whitespace"),
+            ProtocolError::Malformed { field, reason } => write!(f, "Invalid {field}: {reason}"),
+            ProtocolError::Unexpected { expected, got } => write!(f, "Expected {expected} but peer sent {got}"),
+            ProtocolError::Timeout(limit) => write!(f, "Peer didn't answer within {limit:?}"),
+            ProtocolError::Io(e) => write!(f, "Connection error: {e}"),
+            ProtocolError::Keys(e) => write!(f, "{e}"),
+            ProtocolError::PrvKey(e) => write!(f, "{e}"),
+            ProtocolError::ContractDesc(e) => write!(f, "{e}"),
+            ProtocolError::Psbt(e) => write!(f, "{e}"),
+            ProtocolError::Utxo(e) => write!(f, "{e}"),
+            ProtocolError::RefundAddr(e) => write!(f, "{e}"),
+            ProtocolError::Incompatible(reason) => write!(f, "Incompatible peer: {reason}"),
+            ProtocolError::Rejected { reason, .. } => write!(f, "Peer rejected us: {reason}"),
+            ProtocolError::Declined(reason) => write!(f, "User declined the round: {reason}"),
+            ProtocolError::Rematch(reason) => write!(f, "Another user declined the round: {reason}"),
+            ProtocolError::TermsRejected(reason) => write!(f, "Terms rejected: {reason}"),
+            ProtocolError::Handshake(reason) => write!(f, "Encryption handshake failed: {reason}"),
+            ProtocolError::Undecryptable => write!(f, "Peer sent a message that doesn't decrypt"),
+            ProtocolError::Unencrypted(name) => write!(f, "Refusing to send {name} over an unencrypted connection"),
+            ProtocolError::ConnectionLost { missed } => write!(f, "Connection lost, peer didn't answer {missed} pings"),
+            ProtocolError::Unsigned(name) => write!(f, "Peer sent an unsigned {name}"),
+            ProtocolError::BadSignature(reason) => write!(f, "Bad message signature: {reason}"),
+            ProtocolError::WrongSession => write!(f, "Peer sent a message from another session"),
+            ProtocolError::OutOfOrder { expected, got } => {
+                write!(f, "Peer sent message number {got} of the session, expected {expected}")
+            },
+            ProtocolError::UnknownSession => write!(f, "Peer asked to resume a session we don't have"),
+            ProtocolError::UnknownRound => write!(f, "Peer asked to join a round we aren't running"),
+            ProtocolError::AddedInput(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+impl From<KeyParseError> for ProtocolError {
+    fn from(e: KeyParseError) -> Self {
+        ProtocolError::Keys(e)
+    }
+}
+
+impl From<PrvKeyError> for ProtocolError {
+    fn from(e: PrvKeyError) -> Self {
+        ProtocolError::PrvKey(e)
+    }
+}
+
+impl From<ContractDescError> for ProtocolError {
+    fn from(e: ContractDescError) -> Self {
+        ProtocolError::ContractDesc(e)
+    }
+}
+
+impl From<PsbtReadError> for ProtocolError {
+    fn from(e: PsbtReadError) -> Self {
+        ProtocolError::Psbt(e)
+    }
+}
+
+impl From<UtxoError> for ProtocolError {
+    fn from(e: UtxoError) -> Self {
+        ProtocolError::Utxo(e)
+    }
+}
+
+impl From<RefundAddrError> for ProtocolError {
+    fn from(e: RefundAddrError) -> Self {
+        ProtocolError::RefundAddr(e)
+    }
+}
+
+impl From<AddInputError> for ProtocolError {
+    fn from(e: AddInputError) -> Self {
+        ProtocolError::AddedInput(e)
+    }
+}
+
+// A list of public keys that we can't use in the contracts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyParseError {
+    WrongCount { expected: usize, got: usize },
+    Invalid { index: usize, reason: String },
+    // The wsh descriptors only take compressed keys
+    Uncompressed { index: usize },
+}
+
+impl fmt::Display for KeyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyParseError::WrongCount { expected, got } => write!(f, "Expected {expected} pub keys, got {got}"),
+            KeyParseError::Invalid { index, reason } => write!(f, "Invalid pub key at position {index}: {reason}"),
+            KeyParseError::Uncompressed { index } => write!(f, "Uncompressed pub key at position {index}"),
+        }
+    }
+}
+
+impl std::error::Error for KeyParseError {}
+
+// Error returned by a whole protocol session
+#[derive(Debug)]
+pub enum JoinSwapError {
+    Io(io::Error),
+    // Error talking to our only peer (the maker, on the user side)
+    Protocol(ProtocolError),
+    // Error talking to one of the maker's peers
+    Peer { leg: Leg, index: usize, error: ProtocolError },
+    // The counterparty handed over a private key that isn't the one we expected
+    KeyMismatch(KeyMismatch),
+    ContractKeys(ContractKeyError),
+    // Every rule broken by the funding and refund PSBTs
+    PsbtChecks(Vec<PsbtCheckFailure>),
+    Build(BuildError),
+    Chain(ChainError),
+    Preimage(PreimageError),
+    // Our own utxo can't join a round
+    Utxo(UtxoError),
+    // The maker2user fundings would leave the maker below its minimum margin (or at a loss)
+    MarginTooLow { received: u64, spent: u64, min_margin: u64 },
+    // We couldn't save our recovery file, so we stopped before the step it was for
+    Recovery(io::Error),
+    // We couldn't save the session index of our contract keys (see `keys::KeyChain`), so we didn't use them
+    KeySession(io::Error),
+    // We couldn't save the hash of our round (see `rounds::UsedInputs`), so we didn't use it
+    UsedHashes(io::Error),
+    // We were told to stop midway, what we need to take our coins back was saved to `saved`
+    Shutdown { saved: Option<PathBuf> },
+    // Our round didn't fill up within the time we were willing to wait
+    NoRound { waited: Duration },
+    // We were asked to approve the contract before signing its refund and didn't
+    NotApproved,
+}
+
+impl fmt::Display for JoinSwapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinSwapError::Io(e) => write!(f, "Connection error: {e}"),
+            JoinSwapError::Protocol(e) => write!(f, "{e}"),
+            JoinSwapError::Peer { leg, index, error } => write!(f, "{leg:?} leg peer {index}: {error}"),
+            JoinSwapError::KeyMismatch(e) => write!(f, "{e}"),
+            JoinSwapError::ContractKeys(e) => write!(f, "{e}"),
+            JoinSwapError::PsbtChecks(failures) => {
+                let failures: Vec<_> = failures.iter().map(|failure| failure.to_string()).collect();
+                write!(f, "Invalid funding or refund PSBT: {}", failures.join("; "))
+            },
+            JoinSwapError::Build(e) => write!(f, "{e}"),
+            JoinSwapError::Chain(e) => write!(f, "{e}"),
+            JoinSwapError::Preimage(e) => write!(f, "{e}"),
+            JoinSwapError::Utxo(e) => write!(f, "Can't use our utxo: {e}"),
+            JoinSwapError::MarginTooLow { received, spent, min_margin } => write!(
+                f,
+                "Maker margin too low: receiving {received} sats and spending {spent} sats, \
+                but the minimum margin is {min_margin} sats",
+            ),
+            JoinSwapError::Recovery(e) => write!(f, "Can't save the recovery file: {e}"),
+            JoinSwapError::KeySession(e) => write!(f, "Can't save the session of our contract keys: {e}"),
+            JoinSwapError::UsedHashes(e) => write!(f, "Can't save the hash of our round: {e}"),
+            JoinSwapError::Shutdown { saved: Some(path) } => write!(f, "Shut down, state saved to {}", path.display()),
+            JoinSwapError::Shutdown { saved: None } => write!(f, "Shut down"),
+            JoinSwapError::NoRound { waited } => write!(f, "No round filled up within {waited:?}"),
+            JoinSwapError::NotApproved => write!(f, "The contract wasn't approved"),
+        }
+    }
+}
+
+impl JoinSwapError {
+    pub fn abort_code(&self) -> AbortCode {
+        match self {
+            JoinSwapError::Io(_) => AbortCode::PeerGone,
+            JoinSwapError::Protocol(error) | JoinSwapError::Peer { error, .. } => error.abort_code(),
+            JoinSwapError::ContractKeys(_) => AbortCode::PeerMisbehaved,
+            JoinSwapError::KeyMismatch(_) => AbortCode::KeyMismatch,
+            JoinSwapError::PsbtChecks(_) => AbortCode::InvalidPsbts,
+            JoinSwapError::MarginTooLow { .. } => AbortCode::MarginTooLow,
+            JoinSwapError::Shutdown { .. } => AbortCode::Shutdown,
+            JoinSwapError::NoRound { .. } => AbortCode::NoRound,
+            JoinSwapError::NotApproved => AbortCode::NotApproved,
+            JoinSwapError::Build(_)
+            | JoinSwapError::Chain(_)
+            | JoinSwapError::Preimage(_)
+            | JoinSwapError::Utxo(_)
+            | JoinSwapError::Recovery(_)
+            | JoinSwapError::KeySession(_)
+            | JoinSwapError::UsedHashes(_) => AbortCode::Internal,
+        }
+    }
+}
+
+impl std::error::Error for JoinSwapError {}
+
+impl From<io::Error> for JoinSwapError {
+    fn from(e: io::Error) -> Self {
+        JoinSwapError::Io(e)
+    }
+}
+
+impl From<ProtocolError> for JoinSwapError {
+    fn from(e: ProtocolError) -> Self {
+        JoinSwapError::Protocol(e)
+    }
+}
+
+impl From<KeyMismatch> for JoinSwapError {
+    fn from(e: KeyMismatch) -> Self {
+        JoinSwapError::KeyMismatch(e)
+    }
+}
+
+impl From<ContractKeyError> for JoinSwapError {
+    fn from(e: ContractKeyError) -> Self {
+        JoinSwapError::ContractKeys(e)
+    }
+}
+
+impl From<BuildError> for JoinSwapError {
+    fn from(e: BuildError) -> Self {
+        JoinSwapError::Build(e)
+    }
+}
+
+impl From<ChainError> for JoinSwapError {
+    fn from(e: ChainError) -> Self {
+        JoinSwapError::Chain(e)
+    }
+}
+
+impl From<BroadcastError> for JoinSwapError {
+    fn from(e: BroadcastError) -> Self {
+        JoinSwapError::Chain(e.into())
+    }
+}
+
+impl From<PreimageError> for JoinSwapError {
+    fn from(e: PreimageError) -> Self {
+        JoinSwapError::Preimage(e)
+    }
+}
+
+impl From<UtxoError> for JoinSwapError {
+    fn from(e: UtxoError) -> Self {
+        JoinSwapError::Utxo(e)
+    }
+}
+
+// A handed over WIF private key we can't accept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrvKeyError {
+    Invalid(String),
+    // WIF keys only tell mainnet apart from the test networks
+    WrongNetwork(Network),
+    // The contract keys are compressed, so an uncompressed key would sign for another script
+    Uncompressed,
+}
+
+impl fmt::Display for PrvKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrvKeyError::Invalid(e) => write!(f, "Invalid private key: {e}"),
+            PrvKeyError::WrongNetwork(network) => write!(f, "Private key is for {network}"),
+            PrvKeyError::Uncompressed => write!(f, "Private key is uncompressed"),
+        }
+    }
+}
+
+impl std::error::Error for PrvKeyError {}
+
+// Why a contract descriptor can't be built from the given keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContractDescError {
+    DuplicateKey(PublicKey),
+    Uncompressed(PublicKey),
+    // The descriptor doesn't parse or isn't sane
+    Invalid(String),
+    // Standard nodes wouldn't relay a tx spending the contract
+    ScriptTooLarge { size: usize, max: usize },
+    // Paths need from two participants (the maker and a user) to all of them
+    BadThreshold { path: &'static str, threshold: usize, participants: usize },
+    // The peer built another contract descriptor than ours, by their checksums
+    ChecksumMismatch { ours: String, theirs: String },
+}
+
+impl fmt::Display for ContractDescError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContractDescError::DuplicateKey(key) => write!(f, "Key {key} appears more than once in the contract"),
+            ContractDescError::Uncompressed(key) => write!(f, "Contract key {key} is uncompressed"),
+            ContractDescError::Invalid(e) => write!(f, "Invalid contract descriptor: {e}"),
+            ContractDescError::ScriptTooLarge { size, max } => {
+                write!(f, "Contract witness script of {size} bytes, the most is {max}")
+            },
+            ContractDescError::BadThreshold { path, threshold, participants } => {
+                write!(f, "The {path} path can't need {threshold} of the {participants} participants")
+            },
+            ContractDescError::ChecksumMismatch { ours, theirs } => {
+                write!(f, "Peer built the contract descriptor with checksum {theirs}, ours has {ours}")
+            },
+        }
+    }
+}
+
+impl std::error::Error for ContractDescError {}
+
+// Why nodes with the default policy wouldn't relay a tx (see `check_standardness`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StandardnessError {
+    // We weren't told the satisfaction weight of every input
+    UnknownInputWeights,
+    UnknownFee,
+    TooHeavy { weight: usize, max: usize },
+    DustOutput { output: usize, value: u64, dust: u64 },
+    BelowMinRelayFee { fee: u64, min: u64 },
+    WitnessScriptTooLarge { input: usize, size: usize },
+    // Items the witness pushes besides the script to satisfy it
+    TooManyWitnessItems { input: usize, items: usize },
+}
+
+impl fmt::Display for StandardnessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StandardnessError::UnknownInputWeights => write!(f, "Can't estimate how much the inputs weigh"),
+            StandardnessError::UnknownFee => write!(f, "Can't tell the fee it pays"),
+            StandardnessError::TooHeavy { weight, max } => {
+                write!(f, "It weighs {weight} WU, the most relayed is {max}")
+            },
+            StandardnessError::DustOutput { output, value, dust } => {
+                write!(f, "Output {output} of {value} sats is dust, it needs {dust}")
+            },
+            StandardnessError::BelowMinRelayFee { fee, min } => {
+                write!(f, "It pays {fee} sats, below the min relay fee of {min}")
+            },
+            StandardnessError::WitnessScriptTooLarge { input, size } => {
+                write!(f, "Input {input} spends a witness script of {size} bytes")
+            },
+            StandardnessError::TooManyWitnessItems { input, items } => {
+                write!(f, "Input {input} may need {items} witness items to be spent")
+            },
+        }
+    }
+}
+
+impl std::error::Error for StandardnessError {}
+
+// A PSBT message we can't accept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PsbtReadError {
+    Decode(String),
+    // The PSBT is for another tx than the one we agreed on
+    TxidMismatch { expected: Txid, actual: Txid },
+    // A signature we require is missing or not valid
+    BadSignature { input: usize, key: PublicKey, status: SigStatus },
+    Unverifiable(SigVerifyError),
+    // An input isn't finalized or doesn't satisfy the script it spends
+    InvalidInput { input: usize, reason: String },
+    // The peer changed more than its signatures in the PSBT we sent
+    Altered(PsbtPart),
+    SighashChanged { input: usize, sighash: Option<PsbtSighashType> },
+    // The finalized input weighs more than its descriptor allowed, so the funding tx pays less
+    // feerate than the fees were computed for
+    WitnessTooHeavy { input: usize, weight: usize, declared: usize },
+    // Our wallet refused to sign the PSBT, as with a sighash it doesn't take
+    Unsignable(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsbtPart {
+    Global,
+    Input(usize),
+    Output(usize),
+}
+
+impl fmt::Display for PsbtPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PsbtPart::Global => write!(f, "global map"),
+            PsbtPart::Input(index) => write!(f, "input {index}"),
+            PsbtPart::Output(index) => write!(f, "output {index}"),
+        }
+    }
+}
+
+impl fmt::Display for PsbtReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PsbtReadError::Decode(e) => write!(f, "Can't decode PSBT: {e}"),
+            PsbtReadError::TxidMismatch { expected, actual } => write!(
+                f,
+                "Counterparty sent a different transaction: expected {expected}, got {actual}",
+            ),
+            PsbtReadError::BadSignature { input, key, status } => match status {
+                SigStatus::Missing => write!(f, "Missing signature of {key} on input {input}"),
+                SigStatus::NonStandardSighash(sighash) => {
+                    write!(f, "Signature of {key} on input {input} uses {sighash} instead of SIGHASH_ALL")
+                },
+                _ => write!(f, "Invalid signature of {key} on input {input}"),
+            },
+            PsbtReadError::Unverifiable(e) => write!(f, "Can't verify the PSBT signatures: {e:?}"),
+            PsbtReadError::InvalidInput { input, reason } => write!(f, "Input {input} is not validly signed: {reason}"),
+            PsbtReadError::Altered(part) => write!(f, "The PSBT {part} was changed beyond adding signatures"),
+            PsbtReadError::SighashChanged { input, sighash: Some(sighash) } => {
+                write!(f, "Sighash type of input {input} was changed to {sighash}")
+            },
+            PsbtReadError::SighashChanged { input, sighash: None } => {
+                write!(f, "Sighash type of input {input} was removed")
+            },
+            PsbtReadError::WitnessTooHeavy { input, weight, declared } => {
+                write!(f, "Input {input} satisfaction weighs {weight} WU, above the declared {declared} WU")
+            },
+            PsbtReadError::Unsignable(e) => write!(f, "Can't sign the PSBT: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PsbtReadError {}
+
+// A handed over private key that doesn't belong to the contract
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMismatch {
+    // Position of the private key in the handed over set
+    pub index: usize,
+    pub derived: PublicKey,
+    pub expected: Vec<PublicKey>,
+    pub kind: MismatchKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchKind {
+    // The key isn't any of the expected ones
+    Unknown,
+    // The key was already handed over for another expected key
+    Duplicate,
+    WrongNetwork(Network),
+}
+
+impl fmt::Display for KeyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let expected: Vec<_> = self.expected.iter().map(|key| key.to_string()).collect();
+        let KeyMismatch { index, derived, .. } = self;
+
+        match self.kind {
+            MismatchKind::Unknown => write!(f, "Private key {index} (for {derived}) is not one of ")?,
+            MismatchKind::Duplicate => write!(f, "Private key {index} (for {derived}) was given twice, expected ")?,
+            MismatchKind::WrongNetwork(network) => {
+                write!(f, "Private key {index} (for {derived}) is for {network}, expected ")?
+            },
+        }
+        write!(f, "{}", expected.join(", "))
+    }
+}
+
+impl std::error::Error for KeyMismatch {}
+
+// Contract keys sent by the maker that we can't accept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContractKeyError {
+    DuplicateKey(PublicKey),
+    MultisigKeyMissing,
+    TimelockKeyMissing,
+    HashlockKeyMissing,
+    // One of our keys also appears in a path where it doesn't belong
+    MultipleRoles(PublicKey),
+    Uncompressed(PublicKey),
+    // The maker used this key in a previous session
+    ReusedKey(PublicKey),
+}
+
+impl fmt::Display for ContractKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContractKeyError::DuplicateKey(key) => write!(f, "Contract key {key} is used more than once"),
+            ContractKeyError::MultisigKeyMissing => write!(f, "Our multisig key is missing from the contract"),
+            ContractKeyError::TimelockKeyMissing => write!(f, "Our timelock key is missing from the contract"),
+            ContractKeyError::HashlockKeyMissing => write!(f, "Our hashlock key is missing from the contract"),
+            ContractKeyError::MultipleRoles(key) => write!(f, "Our key {key} is used in more than one path"),
+            ContractKeyError::Uncompressed(key) => write!(f, "Contract key {key} is uncompressed"),
+            ContractKeyError::ReusedKey(key) => write!(f, "Contract key {key} was used in a previous swap"),
+        }
+    }
+}
+
+impl std::error::Error for ContractKeyError {}
+
+// A rule broken by the funding or refund PSBT the maker sent us
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PsbtCheckFailure {
+    // No funding output, or more than one, pays to the users2maker contract
+    WrongContractSpk,
+    // The feerate over the estimated vsize is out of the range we accept
+    FundingFeeRate { fee: u64, vsize: usize },
+    // Some funding input doesn't tell its value, or the outputs add up to more than the inputs
+    UnknownFundingFee,
+    // The funding fee is too far from what it pays at the feerate the maker told us
+    FundingFeeMismatch { fee: u64, stated: u64 },
+    // A funding bump doesn't spend this input of the funding tx it replaces
+    ReplacedInputMissing(OutPoint),
+    // Nodes don't relay a replacement paying less than this
+    ReplacementFeeTooLow { fee: u64, min: u64 },
+    // We don't know how this funding input is spent, so we can't estimate the feerate
+    UnknownInputWeight { input: usize },
+    MyUtxoMissing(OutPoint),
+    MyUtxoDuplicated { outpoint: OutPoint, count: usize },
+    // We can't get the value spent by this funding input
+    MissingPrevTx { input: usize },
+    // The inputs minus the fee don't add up to the contract output
+    BalanceMismatch { inputs: u64, fee: u64, output: u64 },
+    // The refund must only spend the contract output
+    WrongRefundInputs { inputs: Vec<OutPoint> },
+    WrongRefundTimelock { version: i32, sequence: Option<Sequence> },
+    // Outputs paying to our refund address, there must be exactly one
+    RefundOutputCount { count: usize },
+    RefundFeeRate { fee: Option<u64>, vsize: usize },
+    WrongRefundAmount { expected: u64, actual: u64 },
+    // The funding tx must spend from one to `MAX_USER_UTXOS` utxos per user
+    WrongInputCount { users: usize, actual: usize },
+    DuplicateInput(OutPoint),
+    // Outputs beyond one per user pay to scripts we weren't told about
+    WrongRefundOutputCount { expected: usize, actual: usize },
+    // The maker must tell us one distinct refund address per user, ours included
+    WrongRefundAddrCount { expected: usize, actual: usize },
+    MyRefundAddrMissing,
+    // The refund output doesn't pay to any of the participants
+    UnknownRefundOutput { output: usize },
+    // The refund outputs and fee must add up to the contract value
+    RefundValueMismatch { funded: u64, paid: u64 },
+    // Funding outputs paying to our change address, there must be exactly one unless it was dust
+    ChangeOutputCount { count: usize },
+    // Zero if the change was dust and shouldn't have been paid
+    WrongChangeAmount { expected: u64, actual: u64 },
+    // Outputs beyond the contract and one change per user pay to scripts we weren't told about
+    TooManyFundingOutputs { users: usize, actual: usize },
+    // The payout of the round leaves the maker more of what we put in than the fee it advertised
+    FeeAboveOffer { put_in: u64, payout: u64, advertised: u64 },
+    // Locktimes must be block heights close to the tip our backend knows
+    FundingLocktime { lock_time: u32, tip: u32 },
+    RefundLocktime { lock_time: u32, tip: u32 },
+    // A pricier refund (see `REFUND_LADDER`) isn't the refund tx paying `multiple` times its fee shares
+    LadderRefundMismatch { multiple: u64 },
+    NonstandardFunding(StandardnessError),
+    // Our funding input asks for a sighash we don't sign with, SIGHASH_ALL unless we both agreed on
+    // `ANYONE_CAN_PAY`
+    MySighash { input: usize, sighash: PsbtSighashType },
+    // The refund paying `multiple` times its fee shares, 1 for the base one
+    NonstandardRefund { multiple: u64, error: StandardnessError },
+}
+
+impl fmt::Display for PsbtCheckFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PsbtCheckFailure::WrongContractSpk => write!(f, "Funding tx doesn't pay to the contract once"),
+            PsbtCheckFailure::FundingFeeRate { fee, vsize } => {
+                write!(f, "Funding fee of {fee} sats for {vsize} vB is out of the accepted feerates")
+            },
+            PsbtCheckFailure::UnknownFundingFee => write!(f, "Funding fee can't be computed"),
+            PsbtCheckFailure::FundingFeeMismatch { fee, stated } => {
+                write!(f, "Funding fee of {fee} sats, at the feerate the maker told us it would be {stated}")
+            },
+            PsbtCheckFailure::ReplacedInputMissing(outpoint) => {
+                write!(f, "Funding tx replacement doesn't spend {outpoint} of the replaced one")
+            },
+            PsbtCheckFailure::ReplacementFeeTooLow { fee, min } => {
+                write!(f, "Funding tx replacement pays {fee} sats, nodes relay it from {min}")
+            },
+            PsbtCheckFailure::UnknownInputWeight { input } => {
+                write!(f, "Can't estimate the satisfaction weight of funding input {input}")
+            },
+            PsbtCheckFailure::MyUtxoMissing(outpoint) => {
+                write!(f, "Our utxo {outpoint} is not spent by the funding tx")
+            },
+            PsbtCheckFailure::MyUtxoDuplicated { outpoint, count } => {
+                write!(f, "Our utxo {outpoint} is spent {count} times")
+            },
+            PsbtCheckFailure::MissingPrevTx { input } => write!(f, "Funding input {input} has no previous tx"),
+            PsbtCheckFailure::BalanceMismatch { inputs, fee, output } => write!(
+                f,
+                "Funding inputs of {inputs} sats minus {fee} sats fee don't match the {output} sats output",
+            ),
+            PsbtCheckFailure::WrongRefundInputs { inputs } => {
+                write!(f, "Refund tx spends {inputs:?} instead of the contract output")
+            },
+            PsbtCheckFailure::WrongRefundTimelock { version, sequence: Some(sequence) } => {
+                write!(f, "Refund tx has version {version} and sequence {}", sequence.0)
+            },
+            PsbtCheckFailure::WrongRefundTimelock { version, sequence: None } => {
+                write!(f, "Refund tx has version {version} and no inputs")
+            },
+            PsbtCheckFailure::RefundOutputCount { count } => {
+                write!(f, "Refund tx pays {count} times to our address instead of once")
+            },
+            PsbtCheckFailure::RefundFeeRate { fee: Some(fee), vsize } => {
+                write!(f, "Refund fee of {fee} sats for {vsize} vB is out of the accepted feerates")
+            },
+            PsbtCheckFailure::RefundFeeRate { fee: None, .. } => write!(f, "Refund fee can't be computed"),
+            PsbtCheckFailure::WrongRefundAmount { expected, actual } => {
+                write!(f, "Refund pays us {actual} sats instead of {expected}")
+            },
+            PsbtCheckFailure::WrongInputCount { users, actual } => {
+                write!(f, "Funding tx spends {actual} utxos, not one to {MAX_USER_UTXOS} for each of {users} users")
+            },
+            PsbtCheckFailure::DuplicateInput(outpoint) => write!(f, "Funding tx spends {outpoint} more than once"),
+            PsbtCheckFailure::WrongRefundOutputCount { expected, actual } => {
+                write!(f, "Refund tx has {actual} outputs instead of one per user ({expected})")
+            },
+            PsbtCheckFailure::WrongRefundAddrCount { expected, actual } => {
+                write!(f, "Got {actual} distinct refund addresses instead of one per user ({expected})")
+            },
+            PsbtCheckFailure::MyRefundAddrMissing => write!(f, "Our address is not among the refund addresses"),
+            PsbtCheckFailure::UnknownRefundOutput { output } => {
+                write!(f, "Refund output {output} doesn't pay to any user refund address")
+            },
+            PsbtCheckFailure::RefundValueMismatch { funded, paid } => {
+                write!(f, "Refund tx pays {paid} sats with fees but the contract holds {funded}")
+            },
+            PsbtCheckFailure::ChangeOutputCount { count } => {
+                write!(f, "Funding tx pays {count} times to our change address instead of once")
+            },
+            PsbtCheckFailure::WrongChangeAmount { expected, actual } => {
+                write!(f, "Funding tx pays us {actual} sats of change instead of {expected}")
+            },
+            PsbtCheckFailure::TooManyFundingOutputs { users, actual } => {
+                write!(f, "Funding tx has {actual} outputs, more than the contract and a change per user ({users})")
+            },
+            PsbtCheckFailure::FeeAboveOffer { put_in, payout, advertised } => write!(
+                f,
+                "We put in {put_in} sats and get paid {payout}, a maker fee of {} over the advertised {advertised}",
+                put_in - payout,
+            ),
+            PsbtCheckFailure::FundingLocktime { lock_time, tip } => {
+                write!(f, "Funding tx has locktime {lock_time}, too far from our tip at height {tip}")
+            },
+            PsbtCheckFailure::RefundLocktime { lock_time, tip } => {
+                write!(f, "Refund tx has locktime {lock_time}, too far from our tip at height {tip}")
+            },
+            PsbtCheckFailure::LadderRefundMismatch { multiple } => {
+                write!(f, "Refund tx at {multiple}x the fee isn't the refund tx paying that fee")
+            },
+            PsbtCheckFailure::NonstandardFunding(e) => write!(f, "Funding tx wouldn't be relayed: {e}"),
+            PsbtCheckFailure::MySighash { input, sighash } => {
+                write!(f, "Funding input {input} of ours asks for sighash {sighash}")
+            },
+            PsbtCheckFailure::NonstandardRefund { multiple, error } => {
+                write!(f, "Refund tx at {multiple}x the fee wouldn't be relayed: {error}")
+            },
+        }
+    }
+}
+
+// Why the funding and refund txs of the users2maker contract, or a spend of a contract, can't be built
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    Descriptor(String),
+    // Each user must bring some utxo
+    NoUtxos,
+    // Each user needs its own refund address
+    CountMismatch { users: usize, addresses: usize },
+    // The utxos of the user can't pay its share of the funding and refund fees
+    InsufficientValue { index: usize, value: u64, fee_share: u64 },
+    // The user wants to put in the contract more than its utxos are worth
+    ContributionTooLarge { index: usize, contribution: u64, value: u64 },
+    // The change of the user would be too small to relay, and it doesn't let us fold it
+    DustChange { index: usize, change: u64 },
+    // Rounds of equal amounts pay each user back what it doesn't put in, so every user needs change
+    MissingChange { index: usize },
+    // The denomination of a round of equal amounts is below what we accept
+    DenominationTooSmall { denomination: u64, min: u64 },
+    // What the users put in, minus our fee, pays less than we can relay
+    PayoutTooSmall { payout: u64 },
+    TxBuilder(String),
+    // Our wallet can't pay for its part of the round
+    Treasury(String),
+    // Nodes with the default policy wouldn't relay the funding or refund tx
+    Nonstandard { tx: &'static str, error: StandardnessError },
+    // The keys and preimages we hold don't satisfy the contract path we spend through, or the input
+    // sequence is below its timelock
+    Unsatisfied { path: usize },
+    // The key of the contract path we spend through that we have no private key for
+    MissingKey { path: usize, key: PublicKey },
+    // The finalized contract spend fails the script interpreter
+    InvalidSpend(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Descriptor(e) => write!(f, "Invalid contract descriptor: {e}"),
+            BuildError::NoUtxos => write!(f, "No utxos to fund the contract with"),
+            BuildError::CountMismatch { users, addresses } => {
+                write!(f, "Got the utxos of {users} users but {addresses} refund addresses")
+            },
+            BuildError::InsufficientValue { index, value, fee_share } => write!(
+                f,
+                "User {index} brings {value} sats, which can't pay its {fee_share} sats share of the fees",
+            ),
+            BuildError::ContributionTooLarge { index, contribution, value } => {
+                write!(f, "User {index} contributes {contribution} sats out of utxos worth {value}")
+            },
+            BuildError::DustChange { index, change } => {
+                write!(f, "User {index} would get {change} sats of change, too little to relay")
+            },
+            BuildError::MissingChange { index } => {
+                write!(f, "User {index} keeps no change, which rounds of equal amounts need")
+            },
+            BuildError::DenominationTooSmall { denomination, min } => {
+                write!(f, "Users can only put in {denomination} sats each, the least we take is {min}")
+            },
+            BuildError::PayoutTooSmall { payout } => {
+                write!(f, "Users would get paid {payout} sats, less than the dust limit")
+            },
+            BuildError::TxBuilder(e) => write!(f, "Can't build the funding and refund txs: {e}"),
+            BuildError::Treasury(e) => write!(f, "Can't spend from the maker wallet: {e}"),
+            BuildError::Nonstandard { tx, error } => write!(f, "The {tx} tx wouldn't be relayed: {error}"),
+            BuildError::Unsatisfied { path } => write!(f, "What we hold doesn't satisfy path {path} of the contract"),
+            BuildError::MissingKey { path, key } => {
+                write!(f, "We have no private key of {key}, in path {path} of the contract")
+            },
+            BuildError::InvalidSpend(e) => write!(f, "The contract spend is invalid: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// A user utxo whose data doesn't add up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UtxoError {
+    Malformed(String),
+    // Only native segwit v0 utxos are weighted and spent correctly by the funding tx
+    UnsupportedDescriptor(DescriptorType),
+    // Spending the utxo would add more weight to the funding tx than we allow for its type
+    HeavySatisfaction { weight: usize, max: usize },
+    MissingWitnessUtxo,
+    // Without the full previous tx we can't check the witness_utxo value
+    MissingPrevTx,
+    // The previous tx is not the one the outpoint spends from
+    TxidMismatch { expected: Txid, actual: Txid },
+    VoutMissing { vout: u32 },
+    ValueMismatch { claimed: u64, actual: u64 },
+    // The witness_utxo script is not the one of the previous output
+    ScriptMismatch,
+    // The previous output doesn't pay to the declared descriptor
+    DescriptorMismatch,
+    // The maker only takes utxos adding up to between min and max sats
+    ValueOutOfRange { value: u64, min: u64, max: u64 },
+    // Users bring from one to `MAX_USER_UTXOS` utxos
+    Count { count: usize, max: usize },
+    // The user brought the same utxo more than once
+    Duplicate(OutPoint),
+    // The user wants to put in the contract more than its utxos are worth
+    ContributionTooLarge { contribution: u64, value: u64 },
+    // Rounds of equal amounts need somewhere to pay back what the user doesn't put in
+    MissingChange,
+    // Not an address we can pay the change to: invalid, for another network, non-standard or the
+    // contract's
+    BadChangeAddress(String),
+    // Another user of the round brought the same utxo
+    AlreadyInRound(OutPoint),
+    // The utxo is spent by the funding tx of another round, running or finished
+    PreviouslyUsed(OutPoint),
+    // A tx we didn't make spends the utxo, in a block or in the mempool
+    Spent(OutPoint),
+    // The utxo could go away along with its parent tx, and a parent shared by several users would let
+    // whoever made it double spend the funding tx of the others
+    Unconfirmed(OutPoint),
+}
+
+impl fmt::Display for UtxoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UtxoError::Malformed(e) => write!(f, "{e}"),
+            UtxoError::UnsupportedDescriptor(desc_type) => {
+                write!(f, "Unsupported {desc_type:?} descriptor, only wpkh and wsh are accepted")
+            },
+            UtxoError::HeavySatisfaction { weight, max } => {
+                write!(f, "Utxo satisfaction weight of {weight} WU, the most is {max} WU")
+            },
+            UtxoError::MissingWitnessUtxo => write!(f, "Utxo without witness_utxo"),
+            UtxoError::MissingPrevTx => write!(f, "Utxo without its previous tx"),
+            UtxoError::TxidMismatch { expected, actual } => {
+                write!(f, "Previous tx {actual} doesn't match the outpoint txid {expected}")
+            },
+            UtxoError::VoutMissing { vout } => write!(f, "Previous tx has no output {vout}"),
+            UtxoError::ValueMismatch { claimed, actual } => {
+                write!(f, "Utxo claims {claimed} sats but the previous output holds {actual}")
+            },
+            UtxoError::ScriptMismatch => write!(f, "Utxo script doesn't match the previous output"),
+            UtxoError::DescriptorMismatch => write!(f, "The descriptor needs to match the utxo"),
+            UtxoError::ValueOutOfRange { value, min, max } => {
+                write!(f, "Utxos adding up to {value} sats, the accepted values are {min} to {max} sats")
+            },
+            UtxoError::Count { count, max } => write!(f, "Brought {count} utxos, the maker takes 1 to {max}"),
+            UtxoError::Duplicate(outpoint) => write!(f, "Utxo {outpoint} is brought more than once"),
+            UtxoError::ContributionTooLarge { contribution, value } => {
+                write!(f, "Contribution of {contribution} sats out of utxos worth {value}")
+            },
+            UtxoError::MissingChange => write!(f, "Rounds of equal amounts need a change address"),
+            UtxoError::BadChangeAddress(address) => write!(f, "Can't pay the change to {address}"),
+            UtxoError::AlreadyInRound(outpoint) => write!(f, "Utxo {outpoint} is already in the round"),
+            UtxoError::PreviouslyUsed(outpoint) => write!(f, "Utxo {outpoint} is spent by another round"),
+            UtxoError::Spent(outpoint) => write!(f, "Utxo {outpoint} is already spent"),
+            UtxoError::Unconfirmed(outpoint) => write!(f, "Utxo {outpoint} is unconfirmed"),
+        }
+    }
+}
+
+impl std::error::Error for UtxoError {}
+
+// A funding tx that isn't the one we had with some inputs added, or an added input the funding tx can't take
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddInputError {
+    // Outputs are committed by every signature of the funding tx, adding an input can't touch them
+    OutputsChanged,
+    // The version, locktime or inputs we had are not the same, or not first in the same order
+    InputsChanged,
+    NoAddedInput,
+    DuplicateInput(OutPoint),
+    // We can't tell the value of the added input from its previous tx
+    UnknownValue(OutPoint),
+    // The input we proposed is missing, or one of ours was added that we didn't propose
+    NotProposed(OutPoint),
+    // The refund txs don't just spend the contract output of the new funding tx
+    RefundsChanged,
+}
+
+impl fmt::Display for AddInputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddInputError::OutputsChanged => write!(f, "Adding an input changed the funding outputs"),
+            AddInputError::InputsChanged => write!(f, "Adding an input changed the rest of the funding tx"),
+            AddInputError::NoAddedInput => write!(f, "No input was added to the funding tx"),
+            AddInputError::DuplicateInput(outpoint) => write!(f, "Added input {outpoint} is already spent by it"),
+            AddInputError::UnknownValue(outpoint) => write!(f, "Can't tell the value of added input {outpoint}"),
+            AddInputError::NotProposed(outpoint) => write!(f, "Input {outpoint} isn't the one we proposed"),
+            AddInputError::RefundsChanged => write!(f, "Refund txs don't match the funding tx with the added input"),
+        }
+    }
+}
+
+impl std::error::Error for AddInputError {}
+
+// A refund address the maker won't build the refund tx with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefundAddrError {
+    Invalid(String),
+    WrongNetwork { address: String, network: Network },
+    NonStandard(String),
+    // Refunding to the contract would lock the coins in it again
+    ContractAddress,
+    // Already used by the other user
+    Duplicate(String),
+}
+
+impl fmt::Display for RefundAddrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefundAddrError::Invalid(e) => write!(f, "Invalid refund address: {e}"),
+            RefundAddrError::WrongNetwork { address, network } => {
+                write!(f, "Refund address {address} is not for {network}")
+            },
+            RefundAddrError::NonStandard(address) => write!(f, "Refund address {address} is not standard"),
+            RefundAddrError::ContractAddress => write!(f, "Refund address is the contract address"),
+            RefundAddrError::Duplicate(address) => {
+                write!(f, "Refund address {address} is already used by the other user")
+            },
+        }
+    }
+}
+
+impl std::error::Error for RefundAddrError {}
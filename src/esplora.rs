@@ -0,0 +1,288 @@
+// Esplora HTTP API backend, for those who don't run an Electrum server. Only compiled with the `esplora`
+// feature and used with `--esplora <url>`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use bdk::bitcoin::consensus::encode::{deserialize, serialize_hex};
+use bdk::bitcoin::hashes::hex::FromHex;
+use bdk::bitcoin::hashes::{sha256, Hash};
+use bdk::bitcoin::{OutPoint, Script, Transaction, Txid};
+use bdk::blockchain::{GetHeight, Progress, WalletSync};
+use bdk::database::{AnyDatabase, BatchDatabase};
+use bdk::{BlockTime, FeeRate, KeychainKind, SyncOptions, Wallet};
+
+use crate::chain::{record_wallet_txs, BroadcastError, ChainAccess, ChainError, FeeEstimator};
+use crate::http::HttpClient;
+
+// An Esplora HTTP API, like that of mempool.space or of electrs with `--http-addr`
+pub struct EsploraChain(HttpClient);
+
+// Unused addresses in a row after which we stop looking up the txs of a keychain, as BIP44 wallets do
+const STOP_GAP: u32 = 20;
+
+// Confirmed txs Esplora lists per page of the history of a script
+const ESPLORA_PAGE_TXS: usize = 25;
+
+impl EsploraChain {
+    // From the url of the API, like `https://mempool.space/signet/api`
+    pub fn new(url: &str) -> Result<Self, ChainError> {
+        HttpClient::new("Esplora", url).map(EsploraChain)
+    }
+
+    fn request(&self, method: &str, path: &str, body: &str) -> Result<(u16, String), ChainError> {
+        self.0.request(method, path, &["Content-Type: text/plain".to_string()], body)
+    }
+
+    // The body of a successful GET, or `None` if the API doesn't know what we asked for
+    fn get(&self, path: &str) -> Result<Option<String>, ChainError> {
+        match self.request("GET", path, "")? {
+            (200, body) => Ok(Some(body)),
+            (404, _) => Ok(None),
+            (status, body) => Err(ChainError(format!("Esplora answered {path} with {status}: {}", body.trim()))),
+        }
+    }
+
+    fn get_json(&self, path: &str) -> Result<Option<serde_json::Value>, ChainError> {
+        self.get(path)?
+            .map(|body| serde_json::from_str(&body))
+            .transpose()
+            .map_err(|e| ChainError(format!("Esplora answered {path} with invalid JSON: {e}")))
+    }
+
+    // Every tx paying to or spending from `script_pubkey`, mempool ones first, with the block of the
+    // confirmed ones
+    fn script_history(&self, script_pubkey: &Script) -> Result<Vec<(Txid, Option<BlockTime>)>, ChainError> {
+        let script_hash = sha256::Hash::hash(script_pubkey.as_bytes());
+        let mut path = format!("/scripthash/{script_hash:x}/txs");
+        let mut history = Vec::new();
+        loop {
+            let page = self.get_json(&path)?.unwrap_or_default();
+            let entries = page.as_array().map(Vec::as_slice).unwrap_or_default();
+            let mut confirmed = 0;
+            for entry in entries {
+                let txid = entry["txid"].as_str().and_then(|txid| Txid::from_str(txid).ok())
+                    .ok_or_else(|| ChainError(format!("Esplora answered {path} with a tx without txid")))?;
+                let status = &entry["status"];
+                let block = status["confirmed"].as_bool().unwrap_or_default().then(|| BlockTime {
+                    height: status["block_height"].as_u64().unwrap_or_default() as u32,
+                    timestamp: status["block_time"].as_u64().unwrap_or_default(),
+                });
+                confirmed += usize::from(block.is_some());
+                history.push((txid, block));
+            }
+            // The rest of the confirmed ones come in pages after the last one we got
+            match history.last() {
+                Some((last, Some(_))) if confirmed == ESPLORA_PAGE_TXS => {
+                    path = format!("/scripthash/{script_hash:x}/txs/chain/{last}");
+                },
+                _ => return Ok(history),
+            }
+        }
+    }
+}
+
+impl ChainAccess for EsploraChain {
+    fn broadcast(&self, tx: &Transaction) -> Result<(), BroadcastError> {
+        match self.request("POST", "/tx", &serialize_hex(tx))? {
+            (200, _) => Ok(()),
+            // What its node said of the tx
+            (400, body) => Err(BroadcastError::Rejected(format!("Esplora rejected tx {}: {}", tx.txid(), body.trim()))),
+            (status, body) => {
+                Err(ChainError(format!("Esplora answered tx {} with {status}: {}", tx.txid(), body.trim())).into())
+            },
+        }
+    }
+
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, ChainError> {
+        let Some(hex) = self.get(&format!("/tx/{txid}/hex"))? else {
+            return Ok(None);
+        };
+        let tx = Vec::<u8>::from_hex(hex.trim()).ok().and_then(|bytes| deserialize(&bytes).ok())
+            .ok_or_else(|| ChainError(format!("Esplora answered tx {txid} with an invalid tx")))?;
+
+        Ok(Some(tx))
+    }
+
+    fn tip_height(&self) -> Result<u32, ChainError> {
+        let height = self.get("/blocks/tip/height")?.and_then(|height| height.trim().parse().ok());
+
+        height.ok_or_else(|| ChainError("Esplora didn't tell the tip height".to_string()))
+    }
+
+    fn confirmations(&self, txid: &Txid, _script_pubkey: &Script) -> Result<u32, ChainError> {
+        let status = self.get_json(&format!("/tx/{txid}/status"))?.unwrap_or_default();
+        let confirmed = status["confirmed"].as_bool() == Some(true);
+        let Some(height) = status["block_height"].as_u64().filter(|_| confirmed) else {
+            return Ok(0);
+        };
+
+        Ok((self.tip_height()? + 1).saturating_sub(height as u32))
+    }
+
+    fn spent(&self, outpoint: &OutPoint, _script_pubkey: &Script) -> Result<bool, ChainError> {
+        let outspend = self.get_json(&format!("/tx/{}/outspend/{}", outpoint.txid, outpoint.vout))?;
+
+        Ok(outspend.is_some_and(|outspend| outspend["spent"].as_bool() == Some(true)))
+    }
+
+    fn sync_wallet(&self, wallet: &Wallet<AnyDatabase>) -> Result<(), ChainError> {
+        wallet.sync(self, SyncOptions::default()).map_err(|e| ChainError(e.to_string()))
+    }
+}
+
+// Esplora estimates the feerate in sat/vB for some targets only, so we take that of the target right
+// below ours, and never go below the min relay feerate
+impl FeeEstimator for EsploraChain {
+    fn fee_rate(&self, target: usize) -> Result<FeeRate, ChainError> {
+        let estimates = self.get_json("/fee-estimates")?.unwrap_or_default();
+        let mut estimates: Vec<(usize, f64)> = estimates.as_object().into_iter().flatten()
+            .filter_map(|(blocks, rate)| Some((blocks.parse().ok()?, rate.as_f64()?)))
+            .collect();
+        estimates.sort_by_key(|(blocks, _)| *blocks);
+
+        let estimate = estimates.iter().rev().find(|(blocks, _)| *blocks <= target).or(estimates.first());
+        let sat_per_vb = estimate.map_or(1.0, |(_, rate)| *rate as f32);
+
+        Ok(FeeRate::from_sat_per_vb(if sat_per_vb >= 1.0 { sat_per_vb } else { 1.0 }))
+    }
+}
+
+impl GetHeight for EsploraChain {
+    fn get_height(&self) -> Result<u32, bdk::Error> {
+        self.tip_height().map_err(|e| bdk::Error::Generic(e.to_string()))
+    }
+}
+
+impl WalletSync for EsploraChain {
+    // Looks up the history of each wallet address until `STOP_GAP` unused ones in a row
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &RefCell<D>,
+        _progress_update: Box<dyn Progress>,
+    ) -> Result<(), bdk::Error> {
+        let chain_error = |e: ChainError| bdk::Error::Generic(e.to_string());
+        let mut history = HashMap::new();
+        for keychain in [KeychainKind::External, KeychainKind::Internal] {
+            let mut unused = 0;
+            for index in 0.. {
+                let Some(script_pubkey) = database.borrow().get_script_pubkey_from_path(keychain, index)? else {
+                    break;
+                };
+                let txs = self.script_history(&script_pubkey).map_err(chain_error)?;
+                unused = if txs.is_empty() { unused + 1 } else { 0 };
+                history.extend(txs);
+                if unused == STOP_GAP {
+                    break;
+                }
+            }
+        }
+
+        let mut txs = Vec::new();
+        for (txid, block) in history {
+            let tx = self.get_tx(&txid).map_err(chain_error)?
+                .ok_or_else(|| bdk::Error::Generic(format!("Esplora doesn't know tx {txid} of our history")))?;
+            txs.push((tx, block));
+        }
+        record_wallet_txs(&mut *database.borrow_mut(), &txs)
+    }
+}
+
+impl fmt::Debug for EsploraChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EsploraChain({:?})", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use bdk::bitcoin::{PackedLockTime, TxIn, TxOut};
+    use serde_json::json;
+
+    use super::*;
+    use crate::http::stub_server;
+
+    fn api(answers: Vec<(u16, String)>) -> (EsploraChain, Receiver<String>) {
+        let (url, requests) = stub_server(answers);
+        (EsploraChain::new(&format!("{url}/api")).unwrap(), requests)
+    }
+
+    fn ok(body: impl ToString) -> (u16, String) {
+        (200, body.to_string())
+    }
+
+    fn tx() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn::default()],
+            output: vec![TxOut { value: 50_000, script_pubkey: Script::new_op_return(&[]) }],
+        }
+    }
+
+    // What its node says of a refused tx comes with a 400, anything else is a chain error
+    #[test]
+    fn broadcast_rejections() {
+        let refused = "sendrawtransaction RPC error: {\"code\":-26,\"message\":\"min relay fee not met\"}";
+        let answers = vec![ok(tx().txid()), (400, refused.to_string()), (503, "Service Unavailable".to_string())];
+        let (chain, requests) = api(answers);
+
+        chain.broadcast(&tx()).unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST /api/tx HTTP/1.0\r\n"), "{request}");
+        assert!(request.ends_with(&format!("\r\n\r\n{}", serialize_hex(&tx()))));
+        assert!(matches!(chain.broadcast(&tx()), Err(BroadcastError::Rejected(e)) if e.contains("min relay fee")));
+        assert!(matches!(chain.broadcast(&tx()), Err(BroadcastError::Chain(e)) if e.0.contains("with 503")));
+    }
+
+    // The maker2user tx may not be there yet
+    #[test]
+    fn txs_not_found_yet() {
+        let answers = vec![(404, "Transaction not found".to_string()), ok(serialize_hex(&tx())), ok("nonsense")];
+        let (chain, requests) = api(answers);
+
+        assert_eq!(chain.get_tx(&tx().txid()).unwrap(), None);
+        assert!(requests.recv().unwrap().starts_with(&format!("GET /api/tx/{}/hex HTTP/1.0\r\n", tx().txid())));
+        assert_eq!(chain.get_tx(&tx().txid()).unwrap(), Some(tx()));
+        assert!(chain.get_tx(&tx().txid()).unwrap_err().0.contains("invalid tx"));
+    }
+
+    #[test]
+    fn confirmations_and_spends() {
+        let answers = vec![
+            ok(json!({ "confirmed": true, "block_height": 800, "block_hash": "00", "block_time": 0 })),
+            ok(802),
+            ok(json!({ "confirmed": false })),
+            (404, "Transaction not found".to_string()),
+            ok(json!({ "spent": true, "txid": tx().txid().to_string(), "vin": 0 })),
+            ok(json!({ "spent": false })),
+        ];
+        let (chain, _) = api(answers);
+
+        assert_eq!(chain.confirmations(&tx().txid(), &Script::new()).unwrap(), 3);
+        assert_eq!(chain.confirmations(&tx().txid(), &Script::new()).unwrap(), 0);
+        assert_eq!(chain.confirmations(&tx().txid(), &Script::new()).unwrap(), 0);
+        let outpoint = OutPoint { txid: tx().txid(), vout: 0 };
+        assert!(chain.spent(&outpoint, &Script::new()).unwrap());
+        assert!(!chain.spent(&outpoint, &Script::new()).unwrap());
+    }
+
+    // That of the target right below ours, or the shortest one, never below the min relay feerate
+    #[test]
+    fn fee_estimates() {
+        let estimates = json!({ "1": 20.5, "3": 10.0, "6": 5.0, "144": 0.5 });
+        let answers = vec![ok(&estimates), ok(&estimates), ok(&estimates), ok(json!({})), (500, "oops".to_string())];
+        let (chain, requests) = api(answers);
+
+        assert_eq!(chain.fee_rate(4).unwrap(), FeeRate::from_sat_per_vb(10.0));
+        assert!(requests.recv().unwrap().starts_with("GET /api/fee-estimates HTTP/1.0\r\n"));
+        assert_eq!(chain.fee_rate(0).unwrap(), FeeRate::from_sat_per_vb(20.5));
+        assert_eq!(chain.fee_rate(1008).unwrap(), FeeRate::from_sat_per_vb(1.0));
+        assert_eq!(chain.fee_rate(6).unwrap(), FeeRate::from_sat_per_vb(1.0));
+        assert!(chain.fee_rate(6).unwrap_err().0.contains("with 500"));
+    }
+}
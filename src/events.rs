@@ -0,0 +1,159 @@
+// Progress events emitted while running the protocol, so front ends can follow a swap without
+// parsing stdout. The binaries' console output is just one subscriber of this channel.
+
+use bdk::bitcoin::{Address, Txid};
+use bdk::bitcoin::psbt::Psbt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::message::Offer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Connect,
+    ContractCreation,
+    // Connection of the new identities for the second leg
+    SecondConnect,
+    SecondContractCreation,
+    Handover,
+}
+
+// First leg peers are the users' original identities, second leg peers the new ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Leg {
+    First,
+    Second,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    UserData,
+    ContractData,
+    FundingAndRefund,
+    SignedRefund,
+    FinalizedRefund,
+    SignedFunding,
+    FinalizedFunding,
+    // A replacement of the funding tx at a higher feerate, with its refund
+    FundingBump,
+    // A utxo the user adds to the funding tx before signing it, and the funding tx with the added ones
+    AddInput,
+    InputAdded,
+    SecondUserData,
+    SecondContractData,
+    // Proof that the maker paid the user's invoice, instead of the maker2user contract data
+    LightningPayout,
+    HashlockKey,
+    PreimageAndKey,
+    ContractKey,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContractKind {
+    Users2Maker,
+    // Index of the second leg peer the contract pays to
+    Maker2User(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsbtRole {
+    Funding,
+    Refund,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxRole {
+    Funding,
+    Maker2UserFunding(usize),
+    // The maker taking the users2maker contract coins
+    Users2MakerSweep,
+    // A user taking its maker2user contract coins
+    Maker2UserSweep,
+    // A user taking back its users2maker contract coins, see `watchtower`
+    Refund,
+}
+
+// What ended a swap early, so front ends can act on it without parsing the reason. Peers tell each
+// other theirs in `Abort`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbortCode {
+    // The contract was funded, the user takes its coins back with the refund tx
+    Refunded,
+    // A peer sent something invalid
+    PeerMisbehaved,
+    // A peer dropped the connection or stopped answering
+    PeerGone,
+    // A peer dropped us, telling us why
+    PeerRejected,
+    // The maker turned the connection away, being over its limits
+    Refused,
+    // We or a user left the round before funding it
+    Declined,
+    // The maker's funding or refund PSBT broke our rules
+    InvalidPsbts,
+    // A handed over key isn't the one we expected
+    KeyMismatch,
+    MarginTooLow,
+    // Not enough users for a round in time, or users left the pool
+    NoRound,
+    Shutdown,
+    NotApproved,
+    // Our own side failed: the chain backend, building a tx, saving state
+    Internal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolEvent {
+    PhaseEntered(Phase),
+    PeerConnected { leg: Leg, index: usize },
+    // The terms of the maker, which users get before joining a round
+    OfferReceived(Offer),
+    // Users the next round still lacks, as the maker counts them
+    WaitingForUsers { missing: usize },
+    // A first leg connection dropped and the session goes on over a new one. The maker gets the
+    // index of the user, users None.
+    SessionResumed { index: Option<usize> },
+    // A user declined the round before signing the funding tx, and the others wait for another one
+    // with the data they sent. The maker emits it for each of them with its index, users None.
+    Rematched { index: Option<usize> },
+    MessageSent(MessageKind),
+    MessageReceived(MessageKind),
+    ContractCreated { contract: ContractKind, address: Address },
+    // The maker broadcast the funding of a contract holding `amount` sats, users don't emit it
+    ContractFunded { contract: ContractKind, amount: u64 },
+    // The PSBT as received, once it passed our checks and before we sign it
+    PsbtReceived { role: PsbtRole, fee: u64, psbt: Box<Psbt> },
+    Broadcast { role: TxRole, txid: Txid },
+    AwaitingConfirmation { txid: Txid, have: u32, need: u32 },
+    // The maker paid the invoice of a second leg peer
+    InvoicePaid { index: usize, amount: u64 },
+    HandoverComplete,
+    // The maker reports the sats it earned with the swap
+    Completed { profit: Option<u64> },
+    Aborted { code: AbortCode, reason: String },
+}
+
+// Sending half handed to the protocol logic. Events are dropped if nobody listens.
+#[derive(Debug, Clone, Default)]
+pub struct EventSink(Option<UnboundedSender<ProtocolEvent>>);
+
+impl EventSink {
+    pub fn channel() -> (EventSink, UnboundedReceiver<ProtocolEvent>) {
+        let (sender, receiver) = unbounded_channel();
+
+        (EventSink(Some(sender)), receiver)
+    }
+
+    pub fn none() -> EventSink {
+        EventSink(None)
+    }
+
+    pub fn emit(&self, event: ProtocolEvent) {
+        if let Some(sender) = &self.0 {
+            // The receiver may be gone, which only means nobody is interested anymore
+            let _ = sender.send(event);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+// A small blocking HTTP client for the chain backends that speak HTTP (Esplora, bitcoind's JSON-RPC).
+// Each request is a plain HTTP/1.0 one on a new connection, over TLS for https urls, as they make few.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv6Addr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ClientConfig, ClientConnection, ServerName, StreamOwned};
+
+use crate::chain::ChainError;
+use crate::tls::webpki_roots;
+
+// How long a request may take to connect, and then between reads
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+// Largest answer we read, above that of a transaction filling a whole block, in hex
+const MAX_RESPONSE_SIZE: u64 = 32 * 1024 * 1024;
+
+pub(crate) struct HttpClient {
+    // Backend named in our errors
+    backend: &'static str,
+    host: String,
+    port: u16,
+    // Path of the API on the host, like `/signet/api`
+    base: String,
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl HttpClient {
+    // From the url of the API, like `https://mempool.space/signet/api`
+    pub(crate) fn new(backend: &'static str, url: &str) -> Result<Self, ChainError> {
+        let invalid = |reason: &str| ChainError(format!("Invalid {backend} url {url}: {reason}"));
+        let (https, rest) = match url.split_once("://") {
+            Some(("https", rest)) => (true, rest),
+            Some(("http", rest)) => (false, rest),
+            _ => return Err(invalid("it must start with http:// or https://")),
+        };
+        let (authority, base) = rest.split_once('/').unwrap_or((rest, ""));
+        let (host, port) = parse_authority(authority, if https { 443 } else { 80 }).map_err(invalid)?;
+        let base = match base.trim_end_matches('/') {
+            "" => String::new(),
+            base => format!("/{base}"),
+        };
+        let tls = https.then(|| {
+            let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(webpki_roots())
+                .with_no_client_auth();
+            Arc::new(config)
+        });
+
+        Ok(HttpClient { backend, host, port, base, tls })
+    }
+
+    // The host as written in urls, with IPv6 addresses in brackets
+    fn url_host(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        }
+    }
+
+    // The status code and body of the answer to `method` on `path` of the API, sending `headers` (like
+    // `Authorization: ...`) with the request
+    pub(crate) fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[String],
+        body: &str,
+    ) -> Result<(u16, String), ChainError> {
+        let backend = self.backend;
+        let host = self.url_host();
+        let failed = |e: io::Error| ChainError(format!("{backend} request to {host}{path} failed: {e}"));
+        let addr = (self.host.as_str(), self.port).to_socket_addrs().map_err(failed)?.next()
+            .ok_or_else(|| failed(io::ErrorKind::NotFound.into()))?;
+        let mut tcp = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT).map_err(failed)?;
+        tcp.set_read_timeout(Some(HTTP_TIMEOUT)).map_err(failed)?;
+        tcp.set_write_timeout(Some(HTTP_TIMEOUT)).map_err(failed)?;
+
+        let headers: String = headers.iter().map(|header| format!("{header}\r\n")).collect();
+        // An empty `path` is the root of the API, like the JSON-RPC endpoint of bitcoind
+        let target = match format!("{}{path}", self.base) {
+            target if target.is_empty() => "/".to_string(),
+            target => target,
+        };
+        let request = format!(
+            "{method} {target} HTTP/1.0\r\nHost: {host}\r\n{headers}Content-Length: {}\r\n\r\n{body}",
+            body.len(),
+        );
+        let (response, closed) = match &self.tls {
+            Some(config) => {
+                let name = ServerName::try_from(self.host.as_str())
+                    .map_err(|e| failed(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+                let conn = ClientConnection::new(config.clone(), name)
+                    .map_err(|e| failed(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+                let mut stream = StreamOwned::new(conn, tcp);
+                stream.write_all(request.as_bytes()).map_err(failed)?;
+                let response = read_response(&mut stream).map_err(failed)?;
+                // The reads end at the TCP one, whether or not the server closed the TLS session first
+                let closed = stream.conn.process_new_packets().is_ok_and(|state| state.peer_has_closed());
+                (response, closed)
+            },
+            None => {
+                tcp.write_all(request.as_bytes()).map_err(failed)?;
+                (read_response(tcp).map_err(failed)?, true)
+            },
+        };
+
+        let answered = |reason: String| ChainError(format!("{backend} answered {host}{path} {reason}"));
+        let split = response.windows(4).position(|window| window == b"\r\n\r\n");
+        let (head, body) = match split {
+            Some(end) => (&response[..end], &response[end + 4..]),
+            None => (&response[..], &[][..]),
+        };
+        let head = String::from_utf8_lossy(head);
+        let status = head.split_whitespace().nth(1).and_then(|code| code.parse().ok())
+            .ok_or_else(|| answered("with no HTTP status".to_string()))?;
+
+        let length = head.lines().skip(1).filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Length"))
+            .map(|(_, value)| value.trim().parse::<usize>().map_err(|_| answered(format!("with a bad length {value}"))))
+            .transpose()?;
+        let body = match length {
+            Some(length) if body.len() < length => {
+                return Err(answered(format!("with {} of its {length} bytes", body.len())));
+            },
+            Some(length) => &body[..length],
+            // Some servers hang up without closing the TLS session first, which is fine once we got
+            // the whole answer, but with no length to check it may have been cut short
+            None if !closed => return Err(answered("without ending the TLS session".to_string())),
+            None => body,
+        };
+
+        Ok((status, String::from_utf8_lossy(body).into_owned()))
+    }
+}
+
+// The host and port of an url authority, like `example.com`, `example.com:3000` or `[::1]:3000`
+fn parse_authority(authority: &str, default_port: u16) -> Result<(String, u16), &'static str> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']').ok_or("missing ] after the IPv6 address")?;
+            host.parse::<Ipv6Addr>().map_err(|_| "bad IPv6 address")?;
+            match rest {
+                "" => (host, None),
+                rest => (host, Some(rest.strip_prefix(':').ok_or("bad port")?)),
+            }
+        },
+        None if authority.matches(':').count() > 1 => return Err("IPv6 addresses go in brackets, like [::1]:3000"),
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        return Err("missing host");
+    }
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| "bad port")?,
+        None => default_port,
+    };
+
+    Ok((host.to_string(), port))
+}
+
+// The whole answer on `stream`, up to MAX_RESPONSE_SIZE
+fn read_response(stream: impl Read) -> io::Result<Vec<u8>> {
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut response)?;
+    if response.len() as u64 > MAX_RESPONSE_SIZE {
+        let e = format!("The answer is over {MAX_RESPONSE_SIZE} bytes");
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+
+    Ok(response)
+}
+
+impl fmt::Debug for HttpClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}{}", self.url_host(), self.port, self.base)
+    }
+}
+
+// A server on localhost answering each request it gets with the next of `answers`, a status and a body,
+// for the tests of the backends. The url to reach it, and each request as read, body included.
+#[cfg(test)]
+pub(crate) fn stub_server(answers: Vec<(u16, String)>) -> (String, std::sync::mpsc::Receiver<String>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+    let (requests, received) = std::sync::mpsc::channel();
+
+    std::thread::spawn(move || {
+        for (status, body) in answers {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            let head_end = loop {
+                if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break end + 4;
+                }
+                let read = tcp.read(&mut buf).unwrap();
+                assert!(read > 0, "The request ended early");
+                request.extend_from_slice(&buf[..read]);
+            };
+            let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+            let length = head.lines().filter_map(|line| line.strip_prefix("Content-Length: "))
+                .map(|length| length.parse::<usize>().unwrap())
+                .next()
+                .unwrap_or_default();
+            while request.len() < head_end + length {
+                let read = tcp.read(&mut buf).unwrap();
+                assert!(read > 0, "The request ended early");
+                request.extend_from_slice(&buf[..read]);
+            }
+            let _ = requests.send(String::from_utf8(request).unwrap());
+
+            let answer = format!("HTTP/1.0 {status} Stub\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+            tcp.write_all(answer.as_bytes()).unwrap();
+        }
+    });
+
+    (url, received)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, ServerConnection};
+
+    use super::*;
+    use crate::tls::pem_blocks;
+
+    const CA: &str = include_str!("../testdata/ca.pem");
+    const CERT: &str = include_str!("../testdata/localhost.pem");
+    const KEY: &str = include_str!("../testdata/localhost.key");
+
+    // Reads one request on `stream` and sends back `answer`
+    fn answer(mut stream: impl Read + Write, answer: &[u8]) {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = stream.read(&mut buf).unwrap();
+            assert!(read > 0);
+            request.extend_from_slice(&buf[..read]);
+        }
+        stream.write_all(answer).unwrap();
+        stream.flush().unwrap();
+    }
+
+    // A client for a server that answers one request with `response`, closing the TLS session (when
+    // `https`) only if `close_notify`
+    fn client_for(response: &str, https: bool, close_notify: bool) -> HttpClient {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let response = response.as_bytes().to_vec();
+
+        thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            if !https {
+                return answer(tcp, &response);
+            }
+            let certs = pem_blocks(CERT, &["CERTIFICATE"]).unwrap().into_iter().map(Certificate).collect();
+            let key = PrivateKey(pem_blocks(KEY, &["PRIVATE KEY"]).unwrap().remove(0));
+            let config = ServerConfig::builder().with_safe_defaults().with_no_client_auth()
+                .with_single_cert(certs, key).unwrap();
+            let mut stream = StreamOwned::new(ServerConnection::new(Arc::new(config)).unwrap(), tcp);
+            answer(&mut stream, &response);
+            if close_notify {
+                stream.conn.send_close_notify();
+                stream.flush().unwrap();
+            }
+        });
+
+        let scheme = if https { "https" } else { "http" };
+        let mut client = HttpClient::new("test", &format!("{scheme}://localhost:{port}")).unwrap();
+        if https {
+            let mut roots = RootCertStore::empty();
+            roots.add(&Certificate(pem_blocks(CA, &["CERTIFICATE"]).unwrap().remove(0))).unwrap();
+            let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots)
+                .with_no_client_auth();
+            client.tls = Some(Arc::new(config));
+        }
+        client
+    }
+
+    fn get(client: HttpClient) -> Result<(u16, String), ChainError> {
+        client.request("GET", "/tip", &[], "")
+    }
+
+    #[test]
+    fn authorities() {
+        assert_eq!(parse_authority("example.com", 80), Ok(("example.com".to_string(), 80)));
+        assert_eq!(parse_authority("example.com:3000", 80), Ok(("example.com".to_string(), 3000)));
+        assert_eq!(parse_authority("127.0.0.1:3000", 80), Ok(("127.0.0.1".to_string(), 3000)));
+        assert_eq!(parse_authority("[::1]:3000", 80), Ok(("::1".to_string(), 3000)));
+        assert_eq!(parse_authority("[2001:db8::1]", 443), Ok(("2001:db8::1".to_string(), 443)));
+
+        for bad in ["::1:3000", "[::1", "[::1]3000", "[nothost]:3000", "example.com:port", ":3000", ""] {
+            assert!(parse_authority(bad, 80).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn ipv6_urls() {
+        let client = HttpClient::new("test", "http://[::1]:3000/api/").unwrap();
+        assert_eq!((client.host.as_str(), client.port, client.base.as_str()), ("::1", 3000, "/api"));
+        assert_eq!(format!("{client:?}"), "[::1]:3000/api");
+    }
+
+    #[test]
+    fn answers_with_length() {
+        let client = client_for("HTTP/1.0 200 OK\r\nContent-Length: 3\r\n\r\n800", false, false);
+        assert_eq!(get(client).unwrap(), (200, "800".to_string()));
+
+        // Anything after the announced length isn't part of the body
+        let client = client_for("HTTP/1.0 200 OK\r\ncontent-length: 3\r\n\r\n800junk", false, false);
+        assert_eq!(get(client).unwrap(), (200, "800".to_string()));
+
+        let client = client_for("HTTP/1.0 200 OK\r\n\r\n800", false, false);
+        assert_eq!(get(client).unwrap(), (200, "800".to_string()));
+    }
+
+    #[test]
+    fn short_bodies_fail() {
+        let client = client_for("HTTP/1.0 200 OK\r\nContent-Length: 10\r\n\r\n800", false, false);
+        assert!(get(client).unwrap_err().0.contains("with 3 of its 10 bytes"));
+
+        let client = client_for("HTTP/1.0 200 OK\r\nContent-Length: ten\r\n\r\n800", false, false);
+        assert!(get(client).is_err());
+    }
+
+    #[test]
+    fn oversize_answers_fail() {
+        let e = read_response(io::repeat(b'a')).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        assert_eq!(read_response(&[b'a'; 1000][..]).unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn tls_hang_ups() {
+        // With the whole announced body, hanging up without closing the session is fine
+        let client = client_for("HTTP/1.0 200 OK\r\nContent-Length: 3\r\n\r\n800", true, false);
+        assert_eq!(get(client).unwrap(), (200, "800".to_string()));
+
+        let client = client_for("HTTP/1.0 200 OK\r\n\r\n800", true, true);
+        assert_eq!(get(client).unwrap(), (200, "800".to_string()));
+
+        // Otherwise the answer may have been cut short
+        let client = client_for("HTTP/1.0 200 OK\r\n\r\n800", true, false);
+        assert!(get(client).unwrap_err().0.contains("without ending the TLS session"));
+
+        let client = client_for("HTTP/1.0 200 OK\r\nContent-Length: 10\r\n\r\n800", true, false);
+        assert!(get(client).unwrap_err().0.contains("with 3 of its 10 bytes"));
+    }
+}
@@ -0,0 +1,227 @@
+// Contract keys. Each session takes the next index of a counter kept next to the recovery files, and
+// its keys come from the wallet seed under that index, so the seed and the session saved in a recovery
+// file (see `recovery`) are enough to find every private key of its contracts again.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use bdk::bitcoin::{Network, PrivateKey, PublicKey};
+use bdk::bitcoin::secp256k1::Secp256k1;
+use bdk::bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{gen_key_pair, FALLBACK_PATH, MAX_ROUND_USERS};
+use crate::shutdown::save_state;
+
+// Branch of the wallet seed the contract keys come from, next to the receive and change ones
+pub const CONTRACT_KEYS_PATH: &str = "m/84h/1h/0h/2";
+
+// What a contract key is for, the last step of its derivation path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRole {
+    // Key of the users2maker contract path, see `MULTISIG_PATH` and the others
+    Users2Maker(usize),
+    // Key of the maker2user contract of the user at that index in the round. A user only has its own.
+    Maker2User { user: usize, path: usize },
+}
+
+impl KeyRole {
+    fn index(self) -> u32 {
+        match self {
+            KeyRole::Users2Maker(path) => path as u32,
+            // The fallback keys come after those of the other paths of every user, which kept their indexes
+            KeyRole::Maker2User { user, path: FALLBACK_PATH } => 3 + 3 * MAX_ROUND_USERS as u32 + user as u32,
+            KeyRole::Maker2User { user, path } => 3 + 3 * user as u32 + path as u32,
+        }
+    }
+}
+
+// Key of `role` in `session`, at `CONTRACT_KEYS_PATH/<session>/<role>` of the wallet seed `xprv`
+pub fn derive_contract_keys(xprv: &ExtendedPrivKey, session: u32, role: KeyRole) -> (PrivateKey, PublicKey) {
+    let secp = Secp256k1::new();
+    let path = DerivationPath::from_str(CONTRACT_KEYS_PATH).unwrap().extend([
+        ChildNumber::from_normal_idx(session).expect("Session indexes stay below 2^31"),
+        ChildNumber::from_normal_idx(role.index()).unwrap(),
+    ]);
+
+    let prv_key = xprv.derive_priv(&secp, &path).unwrap().to_priv();
+    (prv_key, prv_key.public_key(&secp))
+}
+
+// Where the contract keys of our sessions come from
+#[derive(Debug, Clone, Default)]
+pub enum ContractKeySource {
+    // Fresh random keys, lost with the process memory once the recovery file is gone
+    #[default]
+    Random,
+    Derived(KeyChain),
+}
+
+impl ContractKeySource {
+    // The keys of a new session. Its index is saved before we use any of them, so no two sessions of
+    // the seed share keys.
+    pub fn new_session(&self, network: Network) -> io::Result<SessionKeys> {
+        Ok(match self {
+            ContractKeySource::Random => SessionKeys { network, derived: None },
+            ContractKeySource::Derived(chain) => {
+                let session = chain.next_session()?;
+                SessionKeys { network, derived: Some((chain.xprv, session)) }
+            },
+        })
+    }
+}
+
+// The wallet seed and the counter of its sessions, saved to `<dir>/contract-sessions-<fingerprint>.json`.
+// The counter must outlive the process, or a restart would hand out the keys of earlier sessions again.
+#[derive(Debug, Clone)]
+pub struct KeyChain {
+    xprv: ExtendedPrivKey,
+    dir: PathBuf,
+    next: Arc<Mutex<u32>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionCounter {
+    next: u32,
+}
+
+impl KeyChain {
+    pub fn new(xprv: ExtendedPrivKey, dir: PathBuf) -> io::Result<Self> {
+        let name = counter_name(&xprv);
+        let next = match fs::read_to_string(dir.join(format!("{name}.json"))) {
+            Ok(json) => {
+                let counter: SessionCounter =
+                    serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                counter.next
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        Ok(KeyChain { xprv, dir, next: Arc::new(Mutex::new(next)) })
+    }
+
+    fn next_session(&self) -> io::Result<u32> {
+        let mut next = self.next.lock().unwrap();
+        let session = *next;
+        save_state(&self.dir, &counter_name(&self.xprv), &SessionCounter { next: session + 1 })?;
+        *next += 1;
+
+        Ok(session)
+    }
+}
+
+fn counter_name(xprv: &ExtendedPrivKey) -> String {
+    format!("contract-sessions-{}", xprv.fingerprint(&Secp256k1::new()))
+}
+
+// The keys of one session, see `ContractKeySource::new_session`
+#[derive(Debug, Clone)]
+pub struct SessionKeys {
+    network: Network,
+    derived: Option<(ExtendedPrivKey, u32)>,
+}
+
+impl SessionKeys {
+    // The session index the keys derive from, None for random keys
+    pub fn session(&self) -> Option<u32> {
+        self.derived.map(|(_, session)| session)
+    }
+
+    pub fn key(&self, role: KeyRole) -> (PrivateKey, PublicKey) {
+        let Some((xprv, session)) = &self.derived else {
+            return gen_key_pair(self.network);
+        };
+        let (mut prv_key, pub_key) = derive_contract_keys(xprv, *session, role);
+        prv_key.network = self.network;
+
+        (prv_key, pub_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::env;
+    use std::path::Path;
+
+    use super::*;
+
+    // A fresh state dir, as a process would find it on its first start
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("joinswap-keys-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn keychain(dir: &Path) -> ContractKeySource {
+        let xprv = ExtendedPrivKey::new_master(Network::Regtest, &[7; 32]).unwrap();
+        ContractKeySource::Derived(KeyChain::new(xprv, dir.to_path_buf()).unwrap())
+    }
+
+    const ROLES: [KeyRole; 4] = [
+        KeyRole::Users2Maker(0),
+        KeyRole::Users2Maker(2),
+        KeyRole::Maker2User { user: 0, path: 1 },
+        KeyRole::Maker2User { user: 3, path: 2 },
+    ];
+
+    // After a restart the seed and the session index give back the same keys, and new sessions
+    // don't take the index again
+    #[test]
+    fn keys_survive_a_restart() {
+        let dir = state_dir("restart");
+        let before = keychain(&dir).new_session(Network::Regtest).unwrap();
+        let public: Vec<_> = ROLES.iter().map(|role| before.key(*role).1).collect();
+
+        let source = keychain(&dir);
+        let ContractKeySource::Derived(chain) = &source else { unreachable!() };
+        let again: Vec<_> = ROLES.iter().map(|role| derive_contract_keys(&chain.xprv, 0, *role).1).collect();
+        assert_eq!((before.session(), again), (Some(0), public.clone()));
+
+        let after = source.new_session(Network::Regtest).unwrap();
+        assert_eq!(after.session(), Some(1));
+        assert!(ROLES.iter().all(|role| !public.contains(&after.key(*role).1)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Every role of a session has its own key
+    #[test]
+    fn roles_have_their_own_keys() {
+        let dir = state_dir("roles");
+        let session = keychain(&dir).new_session(Network::Regtest).unwrap();
+        let public: HashSet<_> = ROLES.iter().map(|role| session.key(*role).1).collect();
+
+        assert_eq!(public.len(), ROLES.len());
+        assert_eq!(session.key(ROLES[0]), session.key(ROLES[0]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // The fallback keys of a full round take no index of another role, nor the one of an earlier version
+    #[test]
+    fn fallback_keys_have_their_own_indexes() {
+        let paths = (0..MAX_ROUND_USERS).flat_map(|user| (0..=FALLBACK_PATH).map(move |path| (user, path)));
+        let roles: Vec<_> = (0..3).map(KeyRole::Users2Maker)
+            .chain(paths.map(|(user, path)| KeyRole::Maker2User { user, path }))
+            .collect();
+        let indexes: HashSet<_> = roles.iter().map(|role| role.index()).collect();
+
+        assert_eq!(indexes.len(), roles.len());
+        assert_eq!(KeyRole::Maker2User { user: 1, path: 2 }.index(), 8);
+    }
+
+    // A counter we can't read stops us, rather than starting over at 0
+    #[test]
+    fn unreadable_counter() {
+        let dir = state_dir("unreadable");
+        let xprv = ExtendedPrivKey::new_master(Network::Regtest, &[7; 32]).unwrap();
+        fs::write(dir.join(format!("{}.json", counter_name(&xprv))), "not json").unwrap();
+
+        assert_eq!(KeyChain::new(xprv, dir.clone()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,273 +1,3265 @@
-use std::collections::BTreeMap;
+#[cfg(feature = "adversarial")]
+pub mod adversarial;
+#[cfg(feature = "bitcoind")]
+pub mod bitcoind;
+pub mod chain;
+pub mod control;
+pub mod error;
+#[cfg(feature = "esplora")]
+pub mod esplora;
+pub mod events;
+#[cfg(any(feature = "esplora", feature = "bitcoind"))]
+mod http;
+pub mod keys;
+pub mod lightning;
+pub mod limits;
+pub mod message;
+pub mod noise;
+pub mod protocol;
+pub mod recovery;
+pub mod shutdown;
+pub mod signing;
+pub mod socks;
+pub mod tls;
+pub mod transport;
+pub mod watchtower;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::future::Future;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use bdk::bitcoin::{Address, Network, OutPoint, PrivateKey, PublicKey, Txid};
-use bdk::bitcoin::psbt::Psbt;
+use bdk::bitcoin::{Address, EcdsaSighashType, LockTime, Network, OutPoint, PackedLockTime, PrivateKey, psbt, PublicKey,
+                   SchnorrSighashType, Script, Sequence, Transaction, TxIn, TxOut, WScriptHash};
+use bdk::bitcoin::consensus::serialize;
+use bdk::bitcoin::psbt::{Psbt, PsbtSighashType};
 use bdk::descriptor::{Descriptor, Segwitv0};
-use bdk::{KeychainKind, LocalUtxo, SignOptions, Utxo, Wallet, WeightedUtxo};
-use bdk::bitcoin::hashes::sha256;
-use bdk::bitcoin::secp256k1::Secp256k1;
-use bdk::bitcoin::util::bip32::{DerivationPath, KeySource};
+use bdk::{FeeRate, KeychainKind, LocalUtxo, SignOptions, Utxo, Wallet, WeightedUtxo};
+use bdk::bitcoin::hashes::{Hash, hash160, sha256};
+use bdk::bitcoin::secp256k1::rand::seq::SliceRandom;
+use bdk::bitcoin::secp256k1::rand::{thread_rng, Rng};
+use bdk::bitcoin::secp256k1::{Message, Secp256k1, SecretKey, XOnlyPublicKey};
+use bdk::bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, KeySource};
+use bdk::bitcoin::util::sighash::{Prevouts, SighashCache};
+use bdk::bitcoin::util::taproot::TapLeafHash;
 use bdk::database::{BatchDatabase, BatchOperations, MemoryDatabase};
+use bdk::miniscript::interpreter::Interpreter;
+use bdk::miniscript::{ForEachKey, Miniscript, ScriptContext, Tap, Terminal};
+use bdk::miniscript::descriptor::TapTree;
+use bdk::miniscript::policy::{Liftable, Semantic};
+use bdk::signer::{SignerContext, SignerOrdering, SignerWrapper};
 
 use bdk::keys::{GeneratedKey, GeneratableKey, ExtendedKey, DerivableKey, DescriptorKey, PrivateKeyGenerateOptions};
 use bdk::keys::bip39::{Language, Mnemonic, WordCount};
 use bdk::keys::DescriptorKey::Secret;
-use bdk::psbt::PsbtUtils;
 use bdk::wallet::AddressIndex;
+use bdk::wallet::tx_builder::{TxBuilder, TxBuilderContext, TxOrdering};
+use bdk::wallet::coin_selection::{BranchAndBoundCoinSelection, CoinSelectionAlgorithm, CoinSelectionResult, Excess,
+                                  LargestFirstCoinSelection};
+
+use crate::error::{AddInputError, BuildError, ContractDescError, KeyMismatch, KeyParseError, MismatchKind, PrvKeyError,
+                   ProtocolError, PsbtCheckFailure, PsbtReadError, StandardnessError, UtxoError};
+use crate::message::send_signed_psbt;
+use crate::transport::FramedWriter;
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWrite;
+use tokio::time::timeout;
 
-pub fn check_prv_keys(prv_keys: &Vec<PrivateKey>, match_against: Vec<PublicKey>) {
+// Each private key must be for `network` and match a different key of `match_against`
+pub fn check_prv_keys(
+    prv_keys: &[PrivateKey],
+    match_against: Vec<PublicKey>,
+    network: Network,
+) -> Result<(), KeyMismatch> {
     let secp = Secp256k1::new();
+    let mut matched = vec![false; match_against.len()];
 
-    let pub_keys = prv_keys.iter()
-        .map(|key| key.public_key(&secp));
+    for (index, prv_key) in prv_keys.iter().enumerate() {
+        let derived = prv_key.public_key(&secp);
+        let mismatch = |kind| KeyMismatch { index, derived, expected: match_against.clone(), kind };
 
-    pub_keys.for_each(|key| {
-        assert_eq!(match_against.iter().filter(|actual_key| **actual_key == key).count(), 1)
-    });
+        // WIF keys only tell mainnet apart from the test networks
+        if (prv_key.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+            return Err(mismatch(MismatchKind::WrongNetwork(prv_key.network)));
+        }
+
+        let position = match_against.iter().position(|key| *key == derived)
+            .ok_or_else(|| mismatch(MismatchKind::Unknown))?;
+        if matched[position] {
+            return Err(mismatch(MismatchKind::Duplicate));
+        }
+        matched[position] = true;
+    }
+    Ok(())
+}
+
+// Parses a handed over WIF private key, which must be compressed and for `network`
+pub fn parse_prv_key(line: &str, network: Network) -> Result<PrivateKey, PrvKeyError> {
+    let key = PrivateKey::from_str(line).map_err(|e| PrvKeyError::Invalid(e.to_string()))?;
+
+    if (key.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+        return Err(PrvKeyError::WrongNetwork(key.network));
+    }
+    if !key.compressed {
+        return Err(PrvKeyError::Uncompressed);
+    }
+    Ok(key)
+}
+
+// Outcome of checking the partial signature of one key on a PSBT input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigStatus {
+    Valid,
+    Missing,
+    // Only SIGHASH_ALL is accepted, anything else lets others modify the signed tx. SIGHASH_ALL|ANYONECANPAY
+    // passes on inputs that ask for it, see `set_anyone_can_pay`.
+    NonStandardSighash(PsbtSighashType),
+    Invalid,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SigVerifyError {
+    NoSuchInput(usize),
+    MissingPrevout(usize),
+    MissingWitnessScript(usize),
+    WitnessScriptMismatch(usize),
+    // Only p2wpkh, p2wsh and p2tr inputs can be verified
+    UnsupportedScript(usize),
+}
+
+// Checks the `partial_sigs` of the given keys on a PSBT input against the BIP-143 sighash, without
+// building a wallet. The input needs its prevout (witness_utxo or non_witness_utxo) and, if p2wsh,
+// the witness_script. Only SIGHASH_ALL signatures are accepted, or SIGHASH_ALL|ANYONECANPAY where the
+// input's `sighash_type` asks for it. On p2tr inputs the `tap_script_sigs` of the keys are checked
+// instead, see `verify_tap_script_sigs`.
+pub fn verify_partial_sigs(
+    psbt: &Psbt,
+    index: usize,
+    keys: &[PublicKey],
+) -> Result<Vec<(PublicKey, SigStatus)>, SigVerifyError> {
+    let input = psbt.inputs.get(index).ok_or(SigVerifyError::NoSuchInput(index))?;
+    let txin = psbt.unsigned_tx.input.get(index).ok_or(SigVerifyError::NoSuchInput(index))?;
+
+    let prevout: TxOut = match (&input.witness_utxo, &input.non_witness_utxo) {
+        (Some(txout), _) => txout.clone(),
+        (None, Some(prev_tx)) => prev_tx.output
+            .get(txin.previous_output.vout as usize)
+            .cloned()
+            .ok_or(SigVerifyError::MissingPrevout(index))?,
+        (None, None) => return Err(SigVerifyError::MissingPrevout(index)),
+    };
+    if prevout.script_pubkey.is_v1_p2tr() {
+        return verify_tap_script_sigs(psbt, index, keys);
+    }
+
+    let script_code = if prevout.script_pubkey.is_v0_p2wsh() {
+        let witness_script = input.witness_script.as_ref()
+            .ok_or(SigVerifyError::MissingWitnessScript(index))?;
+
+        if Script::new_v0_p2wsh(&witness_script.wscript_hash()) != prevout.script_pubkey {
+            return Err(SigVerifyError::WitnessScriptMismatch(index));
+        }
+        witness_script.clone()
+    } else if prevout.script_pubkey.is_v0_p2wpkh() {
+        prevout.script_pubkey.p2wpkh_script_code().unwrap()
+    } else {
+        return Err(SigVerifyError::UnsupportedScript(index));
+    };
+
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    let secp = Secp256k1::verification_only();
+
+    let mut results = Vec::new();
+    for key in keys {
+        let sig = match input.partial_sigs.get(key) {
+            Some(sig) => sig,
+            None => {
+                results.push((*key, SigStatus::Missing));
+                continue;
+            },
+        };
+
+        let anyone_can_pay = sig.hash_ty == EcdsaSighashType::AllPlusAnyoneCanPay && is_anyone_can_pay(input);
+        if sig.hash_ty != EcdsaSighashType::All && !anyone_can_pay {
+            results.push((*key, SigStatus::NonStandardSighash(sig.hash_ty.into())));
+            continue;
+        }
+
+        let sighash = cache
+            .segwit_signature_hash(index, &script_code, prevout.value, sig.hash_ty)
+            .map_err(|_| SigVerifyError::NoSuchInput(index))?;
+        let msg = Message::from_slice(&sighash[..]).unwrap();
+
+        let status = match secp.verify_ecdsa(&msg, &sig.sig, &key.inner) {
+            Ok(()) => SigStatus::Valid,
+            Err(_) => SigStatus::Invalid,
+        };
+        results.push((*key, status));
+    }
+
+    Ok(results)
+}
+
+// A key signs a taproot input once for each leaf it's in, which must be one of the input's `tap_scripts`.
+// Taproot sighashes commit to every prevout, so every input needs its witness_utxo.
+fn verify_tap_script_sigs(
+    psbt: &Psbt,
+    index: usize,
+    keys: &[PublicKey],
+) -> Result<Vec<(PublicKey, SigStatus)>, SigVerifyError> {
+    let input = &psbt.inputs[index];
+    let prevouts = psbt.inputs.iter().enumerate()
+        .map(|(index, input)| input.witness_utxo.clone().ok_or(SigVerifyError::MissingPrevout(index)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let leaves: HashSet<_> = input.tap_scripts.values()
+        .map(|(script, version)| TapLeafHash::from_script(script, *version))
+        .collect();
+
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    let secp = Secp256k1::verification_only();
+
+    let mut results = Vec::new();
+    for key in keys {
+        let x_only = XOnlyPublicKey::from(key.inner);
+        let sigs: Vec<_> = input.tap_script_sigs.iter().filter(|((signer, _), _)| *signer == x_only).collect();
+
+        let mut status = if sigs.is_empty() { SigStatus::Missing } else { SigStatus::Valid };
+        for ((_, leaf_hash), sig) in sigs {
+            if !matches!(sig.hash_ty, SchnorrSighashType::Default | SchnorrSighashType::All) {
+                status = SigStatus::NonStandardSighash(sig.hash_ty.into());
+                break;
+            }
+
+            let sighash = cache
+                .taproot_script_spend_signature_hash(index, &Prevouts::All(&prevouts), *leaf_hash, sig.hash_ty)
+                .map_err(|_| SigVerifyError::NoSuchInput(index))?;
+            let msg = Message::from_slice(&sighash[..]).unwrap();
+
+            if !leaves.contains(leaf_hash) || secp.verify_schnorr(&sig.sig, &msg, &x_only).is_err() {
+                status = SigStatus::Invalid;
+                break;
+            }
+        }
+        results.push((*key, status));
+    }
+
+    Ok(results)
+}
+
+// Runs the script interpreter on an input finalized by its owner, checking its witness satisfies the
+// prevout. Every input needs its witness_utxo, as segwit v0 sighashes commit to the spent value.
+pub fn verify_finalized_input(psbt: &Psbt, index: usize) -> Result<(), String> {
+    let input = psbt.inputs.get(index).ok_or("No such input")?;
+    let txin = psbt.unsigned_tx.input.get(index).ok_or("No such input")?;
+    let witness = input.final_script_witness.as_ref().ok_or("Not finalized")?;
+    let script_sig = input.final_script_sig.clone().unwrap_or_default();
+
+    let prevouts = psbt.inputs.iter()
+        .map(|input| input.witness_utxo.clone().ok_or("Missing witness_utxo"))
+        .collect::<Result<Vec<TxOut>, _>>()?;
+
+    let interpreter = Interpreter::from_txdata(
+        &prevouts[index].script_pubkey,
+        &script_sig,
+        witness,
+        txin.sequence,
+        psbt.unsigned_tx.lock_time.into(),
+    ).map_err(|e| e.to_string())?;
+
+    let secp = Secp256k1::verification_only();
+    let prevouts = Prevouts::All(&prevouts);
+    if let Some(e) = interpreter.iter(&secp, &psbt.unsigned_tx, index, &prevouts).find_map(Result::err) {
+        return Err(e.to_string());
+    }
+    Ok(())
 }
 
-// The first pair of keys is from the user and maker, timelocked path key is from maker, and
-// hashlocked path key is from user
+// The first pair of keys is from the user and maker, timelocked path key is from maker, and both
+// hashlocked path keys are from user
+//
+// The hashlock path takes the preimage of the `payout_hash` of this contract alone, which the maker hands
+// over. Its fallback takes that of the round `hash` instead, which a hashlock claim of the users2maker
+// contract reveals, so a maker that claims the users2maker coins without handing over the payout preimage
+// still pays the user.
+//
+// `wsh(thresh(1,multi(2,<multisig keys>),snj:and_v(v:pk(<timelock key>),older(<timelock>)),
+// aj:and_v(v:pk(<hashlock key>),<payout hash>),aj:and_v(v:pk(<fallback key>),sha256(<hash>))))`
 pub fn maker2users_contract_desc(
     multisig_keys: &[PublicKey; 2],
     timelock_key: &PublicKey,
-    hashlock_key: &PublicKey,
+    [hashlock_key, fallback_key]: &[PublicKey; 2],
+    payout_hash: PayoutHash,
     hash: sha256::Hash,
-) -> String {
-format!("wsh(thresh(1,\
-    multi(2,{},{}),\
-    snj:and_v(v:pk({}),older(69)),\
-    aj:and_v(v:pk({}),sha256({hash}))\
-    ))", multisig_keys[0], multisig_keys[1], timelock_key, hashlock_key)
+    timelock: u16,
+) -> Result<Descriptor<PublicKey>, ContractDescError> {
+    check_contract_keys(&[multisig_keys[0], multisig_keys[1], *timelock_key, *hashlock_key, *fallback_key])?;
+    let multisig = Terminal::Multi(2, multisig_keys.to_vec());
+    let timelock = and_v(pk(*timelock_key)?, older(timelock)?)?;
+    let hashlock = and_v(pk(*hashlock_key)?, fragment(payout_hash.terminal())?)?;
+    let fallback = and_v(pk(*fallback_key)?, fragment(Terminal::Sha256(hash))?)?;
+
+    let contract = Miniscript::from_ast(Terminal::Thresh(1, vec![
+        fragment(multisig)?,
+        fragment(Terminal::Swap(fragment(Terminal::ZeroNotEqual(fragment(Terminal::NonZero(timelock))?))?))?,
+        fragment(Terminal::Alt(fragment(Terminal::NonZero(hashlock))?))?,
+        fragment(Terminal::Alt(fragment(Terminal::NonZero(fallback))?))?,
+    ])).map_err(|e| ContractDescError::Invalid(e.to_string()))?;
+    let desc = Descriptor::new_wsh(contract).map_err(|e| ContractDescError::Invalid(e.to_string()))?;
+
+    check_contract_desc(&desc)?;
+    Ok(desc)
 }
 
-// Each triplet of keys must be from the users A, B and the maker
-pub fn users2maker_contract_desc(keys: &[PublicKey; 9], hash: sha256::Hash) -> String {
-    format!("wsh(thresh(1,\
-    multi(3,{},{},{}),\
-    anj:and_v(v:multi(3,{},{},{}),older(48)),\
-    aj:and_v(v:multi(3,{},{},{}),sha256({hash}))\
-    ))", keys[0], keys[1], keys[2], keys[3], keys[4], keys[5], keys[6], keys[7], keys[8])
+// Same contract as `maker2users_contract_desc` with each path in a leaf of its own, so a spend with the
+// payout preimage doesn't show the round hash:
+// `tr(<unspendable key>,{multi_a(2,...),{<timelock path>,{<hashlock path>,<fallback>}}})`
+pub fn maker2users_contract_desc_tr(
+    multisig_keys: &[PublicKey; 2],
+    timelock_key: &PublicKey,
+    [hashlock_key, fallback_key]: &[PublicKey; 2],
+    payout_hash: PayoutHash,
+    hash: sha256::Hash,
+    timelock: u16,
+) -> Result<Descriptor<PublicKey>, ContractDescError> {
+    check_contract_keys(&[multisig_keys[0], multisig_keys[1], *timelock_key, *hashlock_key, *fallback_key])?;
+    let multisig = fragment(Terminal::MultiA(2, multisig_keys.to_vec()))?;
+    let timelock = and_v(pk(*timelock_key)?, older(timelock)?)?;
+    let hashlock = and_v(pk(*hashlock_key)?, fragment(payout_hash.terminal())?)?;
+    let fallback = and_v(pk(*fallback_key)?, fragment(Terminal::Sha256(hash))?)?;
+    let desc = taproot_contract([multisig, timelock, hashlock], Some(fallback))?;
+
+    check_contract_desc(&desc)?;
+    Ok(desc)
 }
 
-pub async fn read_contract_keys(reader: &mut BufReader<ReadHalf<TcpStream>>, n: u8) -> Vec<PublicKey> {
-    let line = read_message(reader).await;
-    let parts: Vec<&str> = line.trim().split(',').collect();
+// Participants whose keys each path of the users2maker contract needs, the maker always among them.
+// Below every participant, the path takes the keys of any that many users besides the maker's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathThresholds {
+    pub multisig: usize,
+    pub timelock: usize,
+    pub hashlock: usize,
+}
 
-    if parts.len() != n as usize {
-        panic!("Invalid input! Please ensure there are {n} pub keys separated only by commas");
+impl PathThresholds {
+    // Every path needs every participant
+    pub fn all(participants: usize) -> Self {
+        PathThresholds { multisig: participants, timelock: participants, hashlock: participants }
     }
 
-    parts.iter().map(|key| {
-        PublicKey::from_str(key).unwrap()
-    }).collect()
+    // Whether each path needs at least as many participants as in `lowest`
+    pub fn at_least(&self, lowest: &PathThresholds) -> bool {
+        self.multisig >= lowest.multisig && self.timelock >= lowest.timelock && self.hashlock >= lowest.hashlock
+    }
 }
 
-pub async fn send_message(m: String, writer: &mut WriteHalf<TcpStream>) {
-    let line = m+"\n";
-    writer.write_all(line.as_bytes()).await.unwrap();
-}
+// `<multisig>,<timelock>,<hashlock>`
+impl FromStr for PathThresholds {
+    type Err = String;
 
-pub async fn read_message(reader: &mut BufReader<ReadHalf<TcpStream>>) -> String {
-    let mut buf = String::new();
-    reader.read_line(&mut buf).await.unwrap();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let thresholds = s.split(',')
+            .map(|threshold| threshold.parse().map_err(|e| format!("Invalid threshold '{threshold}': {e}")))
+            .collect::<Result<Vec<usize>, _>>()?;
 
-    buf
+        match thresholds[..] {
+            [multisig, timelock, hashlock] => Ok(PathThresholds { multisig, timelock, hashlock }),
+            _ => Err(format!("Expected a threshold for each of the 3 paths, got {}", thresholds.len())),
+        }
+    }
 }
 
-pub async fn read_psbt(
-    reader: &mut BufReader<ReadHalf<TcpStream>>,
-    txid: Option<Txid>,
-) -> Psbt {
-    let line = read_message(reader).await;
-    let psbt: Psbt = serde_json::from_str(&line.trim()).unwrap();
-
-    if let Some(value) = txid {
-        assert_eq!(psbt.unsigned_tx.txid(), value);
+impl fmt::Display for PathThresholds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "multisig {}, timelock {}, hashlock {}", self.multisig, self.timelock, self.hashlock)
     }
-    psbt
 }
 
-pub async fn sign_and_send_psbt<D: BatchDatabase>(
-    psbt: &mut Psbt,
-    wallet: &Wallet<D>,
-    sign_ops: SignOptions,
-    writers: &mut Vec<WriteHalf<TcpStream>>,
-) {
-    wallet.sign(psbt, sign_ops).unwrap();
-    let serialized_psbt = serde_json::to_string(psbt).unwrap();
+// Each triplet of keys holds the multisig, timelock and hashlock path keys of a participant: one per
+// user of the round and the maker's last. Each path needs the keys of as many participants as its
+// threshold, the maker's always. The refund tx spends the timelock path after `refund_timelock` blocks.
+//
+// `wsh(thresh(1,<multisig path>,anj:and_v(v:<timelock path>,older(<refund timelock>)),
+// aj:and_v(v:<hashlock path>,sha256(<hash>))))`
+pub fn users2maker_contract_desc(
+    keys: &[[PublicKey; 3]],
+    hash: sha256::Hash,
+    thresholds: PathThresholds,
+    refund_timelock: u16,
+) -> Result<Descriptor<PublicKey>, ContractDescError> {
+    check_contract_keys(&keys.concat())?;
+    let participants = keys.len();
+    let [multisig, timelock, hashlock] = users2maker_paths(keys, thresholds, Terminal::Multi)?;
+    // Only a bare multi can be dissatisfied as is
+    let multisig = if thresholds.multisig == participants { multisig } else { fragment(Terminal::NonZero(multisig))? };
+    let timelock = and_v(timelock, older(refund_timelock)?)?;
+    let hashlock = and_v(hashlock, fragment(Terminal::Sha256(hash))?)?;
 
-    for mut writer in writers {
-        send_message(serialized_psbt.to_string(), &mut writer).await;
-    }
+    let contract = Miniscript::from_ast(Terminal::Thresh(1, vec![
+        multisig,
+        fragment(Terminal::Alt(fragment(Terminal::ZeroNotEqual(fragment(Terminal::NonZero(timelock))?))?))?,
+        fragment(Terminal::Alt(fragment(Terminal::NonZero(hashlock))?))?,
+    ])).map_err(|e| ContractDescError::Invalid(e.to_string()))?;
+    let desc = Descriptor::new_wsh(contract).map_err(|e| ContractDescError::Invalid(e.to_string()))?;
+
+    check_contract_desc(&desc)?;
+    Ok(desc)
 }
 
-pub fn build_funding_and_refund(
-    pub_desc: &Descriptor<PublicKey>,
-    from_utxos: Vec<WeightedUtxo>,
-    refund_to: Vec<Address>,
-) -> (Psbt, Psbt) {
-    assert_eq!(from_utxos.len(), refund_to.len());
-    assert!(pub_desc.sanity_check().is_ok());
+// Same contract as `users2maker_contract_desc` with each path in a leaf of its own, so a spend only
+// shows the path it takes. The multisig leaf, spent when the round goes well, has the shortest proof.
+pub fn users2maker_contract_desc_tr(
+    keys: &[[PublicKey; 3]],
+    hash: sha256::Hash,
+    thresholds: PathThresholds,
+    refund_timelock: u16,
+) -> Result<Descriptor<PublicKey>, ContractDescError> {
+    check_contract_keys(&keys.concat())?;
+    let [multisig, timelock, hashlock] = users2maker_paths(keys, thresholds, Terminal::MultiA)?;
+    let timelock = and_v(timelock, older(refund_timelock)?)?;
+    let hashlock = and_v(hashlock, fragment(Terminal::Sha256(hash))?)?;
+    let desc = taproot_contract([multisig, timelock, hashlock], None)?;
 
-    let initial_amounts = (0..from_utxos.len())
-        .into_iter()
-        .map(|i| from_utxos[i].utxo.txout().value);
+    check_contract_desc(&desc)?;
+    Ok(desc)
+}
 
-    let refund_recipients: Vec<(Address, u64)> = refund_to
-        .into_iter()
-        .zip(initial_amounts)
-        .collect();
+// The keys each path of the users2maker contract needs, with `multi` being `multi` in wsh and `multi_a`
+// in a tapscript leaf
+fn users2maker_paths<Ctx: ScriptContext>(
+    keys: &[[PublicKey; 3]],
+    thresholds: PathThresholds,
+    multi: fn(usize, Vec<PublicKey>) -> Terminal<PublicKey, Ctx>,
+) -> Result<[Fragment<Ctx>; 3], ContractDescError> {
+    let participants = keys.len();
+    let path = |index: usize, name: &'static str, threshold: usize| {
+        if !(2..=participants).contains(&threshold) {
+            return Err(ContractDescError::BadThreshold { path: name, threshold, participants });
+        }
+        let path_keys = |keys: &[[PublicKey; 3]]| keys.iter().map(|triplet| triplet[index]).collect();
+        if threshold == participants {
+            return fragment(multi(participants, path_keys(keys)));
+        }
+        let (users, maker) = keys.split_at(participants - 1);
+        and_v(pk(maker[0][index])?, fragment(multi(threshold - 1, path_keys(users)))?)
+    };
+    Ok([
+        path(0, "multisig", thresholds.multisig)?,
+        path(1, "timelock", thresholds.timelock)?,
+        path(2, "hashlock", thresholds.hashlock)?,
+    ])
+}
 
-    let pub_wallet = Wallet::new(
-        &pub_desc.to_string(),
-        None,
-        Network::Regtest,
-        MemoryDatabase::new(),
-    ).unwrap();
-    let funding_psbt = build_funding_tx(&pub_wallet, from_utxos);
+// A miniscript fragment of the contracts, type checked as it's built so a wrong wrapper can't slip in
+type Fragment<Ctx> = Arc<Miniscript<PublicKey, Ctx>>;
 
-    // Create local utxo with the funding tx and update the database (only one output assumed)
-    let outpoint = OutPoint { txid: funding_psbt.unsigned_tx.txid(), vout: 0 };
-    let local = LocalUtxo {
-        outpoint,
-        txout: funding_psbt.unsigned_tx.output[0].clone(),
-        keychain: KeychainKind::External,
-        is_spent: false
+fn fragment<Ctx: ScriptContext>(term: Terminal<PublicKey, Ctx>) -> Result<Fragment<Ctx>, ContractDescError> {
+    Miniscript::from_ast(term).map(Arc::new).map_err(|e| ContractDescError::Invalid(e.to_string()))
+}
+
+// `pk(key)`, short for `c:pk_k(key)`
+fn pk<Ctx: ScriptContext>(key: PublicKey) -> Result<Fragment<Ctx>, ContractDescError> {
+    fragment(Terminal::Check(fragment(Terminal::PkK(key))?))
+}
+
+fn older<Ctx: ScriptContext>(blocks: u16) -> Result<Fragment<Ctx>, ContractDescError> {
+    fragment(Terminal::Older(Sequence::from_height(blocks)))
+}
+
+// `and_v(v:first,then)`
+fn and_v<Ctx: ScriptContext>(first: Fragment<Ctx>, then: Fragment<Ctx>) -> Result<Fragment<Ctx>, ContractDescError> {
+    fragment(Terminal::AndV(fragment(Terminal::Verify(first))?, then))
+}
+
+// Our taproot contracts: the unspendable internal key and the multisig leaf next to the others, with the
+// `fallback` leaf of the hashlock path next to it if there's one
+fn taproot_contract(
+    paths: [Fragment<Tap>; 3],
+    fallback: Option<Fragment<Tap>>,
+) -> Result<Descriptor<PublicKey>, ContractDescError> {
+    let [multisig, timelock, hashlock] = paths;
+    let hashlock = match fallback {
+        Some(fallback) => TapTree::Tree(Arc::new(TapTree::Leaf(hashlock)), Arc::new(TapTree::Leaf(fallback))),
+        None => TapTree::Leaf(hashlock),
     };
-    let mut database = MemoryDatabase::new();
-    database.set_utxo(&local).unwrap();
+    let others = TapTree::Tree(Arc::new(TapTree::Leaf(timelock)), Arc::new(hashlock));
+    let tree = TapTree::Tree(Arc::new(TapTree::Leaf(multisig)), Arc::new(others));
+    let internal_key = PublicKey::from_str(UNSPENDABLE_KEY).unwrap();
 
-    let updated_wallet = Wallet::new(
-        &pub_desc.to_string(),
-        None,
-        Network::Regtest,
-        database,
-    ).unwrap();
+    Descriptor::new_tr(internal_key, Some(tree)).map_err(|e| ContractDescError::Invalid(e.to_string()))
+}
+
+// Hash function of the payout hashes. Preimages are 32 bytes either way, but a hash160 takes 12 bytes less
+// in each script that has it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKind {
+    #[default]
+    Sha256,
+    Hash160,
+}
 
-    let mut refund_psbt = build_refund_tx(&updated_wallet, refund_recipients, &funding_psbt);
+// A hash locking a maker2user contract, see `maker2users_contract_desc`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PayoutHash {
+    Sha256(sha256::Hash),
+    Hash160(hash160::Hash),
+}
 
-    // Witness utxo field doesn't include the whole tx data so we can spend from unsigned txs
-    refund_psbt.inputs[0].witness_utxo = Some(funding_psbt.unsigned_tx.output[0].clone());
+impl PayoutHash {
+    pub fn of(kind: HashKind, preimage: &[u8; 32]) -> Self {
+        match kind {
+            HashKind::Sha256 => PayoutHash::Sha256(sha256::Hash::hash(preimage)),
+            HashKind::Hash160 => PayoutHash::Hash160(hash160::Hash::hash(preimage)),
+        }
+    }
+
+    // A hash of `kind` in hex
+    pub fn parse(kind: HashKind, hex: &str) -> Result<Self, String> {
+        let len = match kind {
+            HashKind::Sha256 => 64,
+            HashKind::Hash160 => 40,
+        };
+        if hex.len() != len {
+            return Err(format!("Expected {len} hex characters, got {}", hex.len()));
+        }
+        match kind {
+            HashKind::Sha256 => sha256::Hash::from_str(hex).map(PayoutHash::Sha256),
+            HashKind::Hash160 => hash160::Hash::from_str(hex).map(PayoutHash::Hash160),
+        }.map_err(|e| e.to_string())
+    }
+
+    pub fn kind(&self) -> HashKind {
+        match self {
+            PayoutHash::Sha256(_) => HashKind::Sha256,
+            PayoutHash::Hash160(_) => HashKind::Hash160,
+        }
+    }
+
+    pub fn matches(&self, preimage: &[u8; 32]) -> bool {
+        *self == PayoutHash::of(self.kind(), preimage)
+    }
 
-    (funding_psbt, refund_psbt)
+    // The miniscript fragment that takes the preimage
+    fn terminal<Ctx: ScriptContext>(&self) -> Terminal<PublicKey, Ctx> {
+        match self {
+            PayoutHash::Sha256(hash) => Terminal::Sha256(*hash),
+            PayoutHash::Hash160(hash) => Terminal::Hash160(*hash),
+        }
+    }
 }
 
-fn build_refund_tx(
-    wallet: &Wallet<MemoryDatabase>,
-    recipients: Vec<(Address, u64)>,
-    funding_psbt: &Psbt,
-) -> Psbt {
-    assert_eq!(recipients.len(), funding_psbt.unsigned_tx.input.len());
-    let out_count = recipients.len() as u64;
+impl fmt::Display for PayoutHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayoutHash::Sha256(hash) => write!(f, "{hash}"),
+            PayoutHash::Hash160(hash) => write!(f, "{hash}"),
+        }
+    }
+}
 
-    let funding_fee = funding_psbt.fee_amount().unwrap();
-    let refund_fee = 1000;
+// Internal key of the taproot contracts: the point of BIP-341 no one knows the private key of, so every
+// spend goes through a leaf
+pub const UNSPENDABLE_KEY: &str = "0250929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
 
-    let mut outputs = Vec::new();
-    for (address, initial_value) in recipients {
-        let final_value =
-            initial_value - (&funding_fee / &out_count) - (&refund_fee / &out_count);
+// Paths of the contracts, in the order their descriptors list them
+pub const MULTISIG_PATH: usize = 0;
+pub const TIMELOCK_PATH: usize = 1;
+pub const HASHLOCK_PATH: usize = 2;
+// The path of a maker2user contract with the round hash, see `maker2users_contract_desc`
+pub const FALLBACK_PATH: usize = 3;
 
-        outputs.push((address.script_pubkey(), final_value));
+// Contract data lists the users2maker contract keys path by path, each path with the users in the
+// order the maker shuffled them and the maker last
+pub fn contract_keys_by_path(keys: &[[PublicKey; 3]]) -> Vec<PublicKey> {
+    (0..3).flat_map(|index| keys.iter().map(move |triplet| triplet[index])).collect()
+}
+
+// The triplet of each participant from keys listed as in `contract_keys_by_path`
+pub fn contract_keys_by_participant(keys: &[PublicKey]) -> Vec<[PublicKey; 3]> {
+    let participants = keys.len() / 3;
+
+    (0..participants).map(|index| {
+        [keys[index], keys[participants + index], keys[2 * participants + index]]
+    }).collect()
+}
+
+// Largest witness script relayed by standard nodes
+pub const MAX_WITNESS_SCRIPT_SIZE: usize = 3600;
+
+// A key repeated across the contract paths would let one party sign for several of them. Tapscript only
+// takes the x coordinate of keys, so two keys that share it are the same key there.
+fn check_contract_keys(keys: &[PublicKey]) -> Result<(), ContractDescError> {
+    for (index, key) in keys.iter().enumerate() {
+        if !key.compressed {
+            return Err(ContractDescError::Uncompressed(*key));
+        }
+        let x_only = XOnlyPublicKey::from(key.inner);
+        if keys[..index].iter().any(|other| XOnlyPublicKey::from(other.inner) == x_only) {
+            return Err(ContractDescError::DuplicateKey(*key));
+        }
     }
+    Ok(())
+}
 
-    // We have to spend from the relative timelocked path
-    let mut path = BTreeMap::new();
-    let wallet_policy = wallet.policies(KeychainKind::External).unwrap().unwrap();
-    path.insert(wallet_policy.id, vec![1]);
+fn check_contract_desc(desc: &Descriptor<PublicKey>) -> Result<(), ContractDescError> {
+    desc.sanity_check().map_err(|e| ContractDescError::Invalid(e.to_string()))?;
 
-    let outpoint = OutPoint { txid: funding_psbt.unsigned_tx.txid(), vout: 0 };
-    let mut tx_builder = wallet.build_tx();
-    tx_builder
-        .manually_selected_only()
-        .add_utxo(outpoint).unwrap()
-        .fee_absolute(refund_fee)
-        .set_recipients(outputs)
-        .policy_path(path, KeychainKind::External);
+    // A taproot spend only shows the leaf it takes
+    let size = match desc {
+        Descriptor::Tr(tr) => tr.iter_scripts().map(|(_, ms)| ms.script_size()).max().unwrap_or_default(),
+        _ => desc.explicit_script().map_err(|e| ContractDescError::Invalid(e.to_string()))?.len(),
+    };
+    if size > MAX_WITNESS_SCRIPT_SIZE {
+        return Err(ContractDescError::ScriptTooLarge { size, max: MAX_WITNESS_SCRIPT_SIZE });
+    }
+    Ok(())
+}
 
-    let (psbt, _) = tx_builder.finish().unwrap();
+// Checksum of the descriptor as written out once parsed, which both peers write alike
+pub fn desc_checksum(desc: &Descriptor<PublicKey>) -> String {
+    let desc = desc.to_string();
+    let (_, checksum) = desc.split_once('#').expect("Descriptors are written with their checksum");
 
-    psbt
+    checksum.to_string()
 }
 
-fn build_funding_tx(
-    receive_wallet: &Wallet<MemoryDatabase>,
-    utxos: Vec<WeightedUtxo>,
-) -> Psbt {
-    let mut tx_builder = receive_wallet.build_tx();
-    tx_builder.manually_selected_only();
+// Whether the peer built the same descriptor as us, telling it by the checksum it sent
+pub fn check_desc_checksum(desc: &Descriptor<PublicKey>, theirs: &str) -> Result<(), ContractDescError> {
+    let ours = desc_checksum(desc);
+    if ours != theirs {
+        return Err(ContractDescError::ChecksumMismatch { ours, theirs: theirs.to_string() });
+    }
+    Ok(())
+}
 
-    for utxo in utxos {
-        match utxo.utxo {
-            Utxo::Foreign { outpoint, psbt_input } => {
-                tx_builder.add_foreign_utxo(outpoint, *psbt_input, utxo.satisfaction_weight).unwrap();
+// Blocks a relative timelock takes at about 10 minutes each, in hours
+const BLOCKS_PER_HOUR: f64 = 6.0;
+
+// The spending paths of a contract in plain words, one per line. Our `own` keys are "you", the `maker`
+// keys "maker" and any other key "another user". Timelocks come in blocks and about the hours they take.
+pub fn describe_contract(
+    desc: &Descriptor<PublicKey>,
+    own: &[PublicKey],
+    maker: &[PublicKey],
+) -> Result<Vec<String>, ContractDescError> {
+    let policy = desc.lift().map_err(|e| ContractDescError::Invalid(e.to_string()))?;
+    let x_only = |key: &PublicKey| XOnlyPublicKey::from(key.inner);
+    let name = |key: &PublicKey| match key {
+        key if own.iter().any(|own| x_only(own) == x_only(key)) => "you",
+        key if maker.iter().any(|maker| x_only(maker) == x_only(key)) => "maker",
+        _ => "another user",
+    };
+
+    let mut paths = Vec::new();
+    any_of(&policy, &mut paths);
+    // The internal key of our taproot contracts can't spend
+    let unspendable = PublicKey::from_str(UNSPENDABLE_KEY).unwrap();
+    paths.retain(|path| !matches!(path, Semantic::Key(key) if x_only(key) == x_only(&unspendable)));
+
+    Ok(paths.into_iter().map(|path| describe_path(path, &name)).collect())
+}
+
+// The alternatives of `policy`, with those nested in one another brought up
+fn any_of<'a>(policy: &'a Semantic<PublicKey>, paths: &mut Vec<&'a Semantic<PublicKey>>) {
+    match policy {
+        Semantic::Threshold(1, subs) => subs.iter().for_each(|sub| any_of(sub, paths)),
+        _ => paths.push(policy),
+    }
+}
+
+// What `policy` needs all of, with those nested in one another brought up
+fn all_of<'a>(policy: &'a Semantic<PublicKey>, items: &mut Vec<&'a Semantic<PublicKey>>) {
+    match policy {
+        Semantic::Threshold(k, subs) if *k == subs.len() => subs.iter().for_each(|sub| all_of(sub, items)),
+        _ => items.push(policy),
+    }
+}
+
+// Like "you + maker", "maker alone after 69 blocks (~11.5 hours)" or "you + preimage"
+fn describe_path(path: &Semantic<PublicKey>, name: &impl Fn(&PublicKey) -> &'static str) -> String {
+    let mut items = Vec::new();
+    all_of(path, &mut items);
+
+    let (mut signers, mut preimages, mut timelock) = (Vec::new(), 0, None);
+    for item in items {
+        match item {
+            Semantic::Key(key) => signers.push(name(key).to_string()),
+            Semantic::Threshold(k, subs) => {
+                let subs: Vec<_> = subs.iter().map(|sub| describe_path(sub, name)).collect();
+                signers.push(format!("{k} of ({})", subs.join(", ")));
             },
-            Utxo::Local(_) => {
-                panic!("FUUUCK EL UTXO ES LOCAL");
+            Semantic::Sha256(_) | Semantic::Hash256(_) | Semantic::Ripemd160(_) | Semantic::Hash160(_) => {
+                preimages += 1;
             },
+            Semantic::Older(sequence) => timelock = Some(sequence.0),
+            Semantic::After(locktime) => signers.push(format!("after block {}", locktime.0)),
+            Semantic::Trivial => signers.push("anyone".to_string()),
+            Semantic::Unsatisfiable => signers.push("no one".to_string()),
         }
     }
-    let wallet_address = receive_wallet.get_address(AddressIndex::New).unwrap();
-    tx_builder.drain_to(wallet_address.script_pubkey());
 
-    // To build a tx from the wallet we need to specify the policy path although we are not
-    // spending from our own wallet UTXOs
-    let mut path = BTreeMap::new();
-    let wallet_policy = receive_wallet.policies(KeychainKind::External).unwrap().unwrap();
-    path.insert(wallet_policy.id, vec![0]);
-    tx_builder.policy_path(path, KeychainKind::External);
+    let mut path = signers.join(" + ");
+    match preimages {
+        0 if signers.len() == 1 && timelock.is_some() => path.push_str(" alone"),
+        0 => {},
+        1 => path.push_str(" + preimage"),
+        n => path.push_str(&format!(" + {n} preimages")),
+    }
+    if let Some(blocks) = timelock {
+        let hours = (blocks as f64 / BLOCKS_PER_HOUR * 10.0).round() / 10.0;
+        path.push_str(&format!(" after {blocks} blocks (~{hours} hours)"));
+    }
+    path
+}
+
+// Parses exactly `n` compressed pub keys
+pub fn parse_contract_keys(keys: &[String], n: u8) -> Result<Vec<PublicKey>, KeyParseError> {
+    if keys.len() != n as usize {
+        return Err(KeyParseError::WrongCount { expected: n as usize, got: keys.len() });
+    }
 
-    let (psbt, _) = tx_builder.finish().unwrap();
+    keys.iter().enumerate().map(|(index, key)| {
+        let key = PublicKey::from_str(key)
+            .map_err(|e| KeyParseError::Invalid { index, reason: e.to_string() })?;
 
-    psbt
+        if !key.compressed {
+            return Err(KeyParseError::Uncompressed { index });
+        }
+        Ok(key)
+    }).collect()
 }
 
-pub fn gen_key_pair() -> (PrivateKey, PublicKey) {
-    let secp = Secp256k1::new();
+// How long we wait for a peer at each phase of the swap, as a silent peer would otherwise hold us
+// forever
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadTimeouts {
+    // The handshake and hello of a new connection, so idle ones don't hold a pending slot for long
+    pub hello: Duration,
+    // Contract and user data
+    pub exchange: Duration,
+    // Signed and finalized PSBTs, which may take a while to check and sign
+    pub signing: Duration,
+    // Second leg connections and the hashlock key handover, which may wait for the funding txs
+    // to confirm
+    pub second_leg: Duration,
+    // A first leg user whose connection dropped while signing, to come back and resume the round
+    pub resume: Duration,
+}
 
-    let key: GeneratedKey<_, Segwitv0> =
-        PrivateKey::generate(PrivateKeyGenerateOptions::default()).unwrap();
+impl Default for ReadTimeouts {
+    fn default() -> Self {
+        ReadTimeouts {
+            hello: Duration::from_secs(10),
+            exchange: Duration::from_secs(60),
+            signing: Duration::from_secs(5 * 60),
+            second_leg: Duration::from_secs(2 * 60 * 60),
+            resume: Duration::from_secs(60),
+        }
+    }
+}
 
-    let pubk = key.public_key(&secp);
-    let privk = key.into_key();
+// Pings during the long waits of the protocol, so that idle connections aren't dropped by NATs
+// and a peer that's gone is noticed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    // Silence after which we ping the peer
+    pub interval: Duration,
+    // Pings without an answer before we take the connection as lost
+    pub max_missed: u32,
+}
 
-    (privk, pubk)
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive { interval: Duration::from_secs(30), max_missed: 4 }
+    }
 }
 
-pub fn get_descriptors() -> String {
-    let secp = Secp256k1::new();
+pub async fn with_timeout<T>(
+    limit: Duration,
+    read: impl Future<Output = Result<T, ProtocolError>>,
+) -> Result<T, ProtocolError> {
+    timeout(limit, read).await.unwrap_or(Err(ProtocolError::Timeout(limit)))
+}
 
-    let password = Some("watafak".to_string());
+// A 32 byte hash, txid or preimage must be exactly 64 hex characters
+pub fn check_hex32(line: &str, field: &'static str) -> Result<(), ProtocolError> {
+    if line.len() != 64 {
+        let reason = format!("Expected 64 hex characters, got {}", line.len());
+        return Err(ProtocolError::Malformed { field, reason });
+    }
+    if let Some(pos) = line.find(|c: char| !c.is_ascii_hexdigit()) {
+        let reason = format!("Non-hex character at position {pos}");
+        return Err(ProtocolError::Malformed { field, reason });
+    }
+    Ok(())
+}
 
-    let mnemonic: GeneratedKey<_, Segwitv0> =
-        Mnemonic::generate((WordCount::Words12, Language::English)).unwrap();
-    let mnemonic = mnemonic.into_key();
+// Signs a PSBT a peer built, one we can't sign (as with a sighash our wallet won't take) is the peer's fault
+pub fn sign_peer_psbt<D: BatchDatabase>(
+    psbt: &mut Psbt,
+    wallet: &Wallet<D>,
+    sign_ops: SignOptions,
+) -> Result<(), ProtocolError> {
+    wallet.sign(psbt, sign_ops).map_err(|e| PsbtReadError::Unsignable(e.to_string()))?;
 
-    let xkey: ExtendedKey = (mnemonic, password).into_extended_key().unwrap();
-    let xprv = xkey.into_xprv(Network::Regtest).unwrap();
+    Ok(())
+}
 
-    let mut keys = Vec::new();
+// Signs `psbt` and sends it to every peer. A PSBT we can't sign fails before any send, see
+// `sign_peer_psbt`. Each peer then gets its own result, in the order of `writers`.
+pub async fn sign_and_send_psbt<D: BatchDatabase, W: AsyncWrite + Unpin>(
+    psbt: &mut Psbt,
+    wallet: &Wallet<D>,
+    sign_ops: SignOptions,
+    writers: &mut [FramedWriter<W>],
+) -> Result<Vec<Result<(), ProtocolError>>, ProtocolError> {
+    sign_peer_psbt(psbt, wallet, sign_ops)?;
 
-    for path in ["m/84h/1h/0h/0", "m/84h/1h/0h/1"] {
-        let deriv_path = DerivationPath::from_str(path).unwrap();
-        let derived_xprv = &xprv.derive_priv(&secp, &deriv_path).unwrap();
-        let origin: KeySource = (xprv.fingerprint(&secp), deriv_path);
-        let derived_xprv_desc_key: DescriptorKey<Segwitv0> =
-            derived_xprv.into_descriptor_key(Some(origin), DerivationPath::default()).unwrap();
+    // All at once, so a slow peer doesn't hold back the others
+    Ok(join_all(writers.iter_mut().map(|writer| send_signed_psbt(psbt, writer))).await)
+}
 
-        // Wrap the derived key with the wpkh() string to produce a descriptor string
-        if let Secret(key, _, _) = derived_xprv_desc_key {
-            let mut desc = "wpkh(".to_string();
-            desc.push_str(&key.to_string());
-            desc.push_str(")");
-            keys.push(desc);
+// Least fee the refund tx pays for each user, who adds an output and a key to each contract path. It
+// pays the min relay feerate in rounds of any size, see `refund_fee_share`.
+pub const REFUND_FEE_PER_USER: u64 = 500;
+
+// Multiples of the refund fee the refund tx is also signed at, each participant paying that many times
+// its share. The refund is only valid once its timelock expires, when fees may be higher than when it
+// was signed, so users keep these to broadcast the cheapest that confirms.
+pub const REFUND_LADDER: [u64; 2] = [3, 10];
+
+// Users in a round, the contract paths are multisigs of every user plus the maker and a multisig
+// takes at most 20 keys
+pub const MIN_ROUND_USERS: usize = 2;
+pub const MAX_ROUND_USERS: usize = 19;
+
+// Smallest refund output standard nodes relay, as refund addresses can be p2wsh
+pub const DUST_LIMIT: u64 = 330;
+
+// Utxos each user may bring to a round. A full round of the heaviest inputs we take stays well under
+// the 400k WU standard nodes relay.
+pub const MAX_USER_UTXOS: usize = 5;
+
+// Values each user brings to a round, adding up its utxos. Smaller values cost more in fees than they
+// are worth, and larger ones need more liquidity than the maker has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UtxoValueRange {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl Default for UtxoValueRange {
+    fn default() -> Self {
+        UtxoValueRange { min: 10_000, max: 1_000_000 }
+    }
+}
+
+impl UtxoValueRange {
+    // Whatever the configured minimum, a user must pay its share of the refund fee and still get
+    // back more than dust
+    pub fn min_value(&self) -> u64 {
+        self.min.max(REFUND_FEE_PER_USER + DUST_LIMIT)
+    }
+
+    pub fn check(&self, value: u64) -> Result<(), UtxoError> {
+        let min = self.min_value();
+        if value < min || value > self.max {
+            return Err(UtxoError::ValueOutOfRange { value, min, max: self.max });
         }
+        Ok(())
     }
+}
 
-    keys[0].clone()
-}
\ No newline at end of file
+// Feerates a user accepts for the funding and refund txs built by the maker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeeRateRange {
+    pub min: FeeRate,
+    pub max: FeeRate,
+}
+
+impl Default for FeeRateRange {
+    fn default() -> Self {
+        FeeRateRange {
+            min: FeeRate::from_sat_per_vb(1.0),
+            max: FeeRate::from_sat_per_vb(50.0),
+        }
+    }
+}
+
+impl FeeRateRange {
+    pub fn contains(&self, fee: u64, vsize: usize) -> bool {
+        let rate = FeeRate::from_vb(fee, vsize);
+        self.min <= rate && rate <= self.max
+    }
+
+    // Also false for the values a `FeeRate` can't hold, like NaN
+    pub fn contains_rate(&self, sat_per_vb: f32) -> bool {
+        (self.min.as_sat_per_vb()..=self.max.as_sat_per_vb()).contains(&sat_per_vb)
+    }
+}
+
+// Feerates in sat/vB the maker builds the funding and refund txs with, as it tells users in the contract
+// data. The refund pays at least `REFUND_FEE_PER_USER` for each user, so at low feerates it pays more.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeRates {
+    pub funding: f32,
+    pub refund: f32,
+}
+
+impl FeeRates {
+    // Both within the feerates we accept, checked before we take them as a `FeeRate`
+    pub fn check(&self, accepted: &FeeRateRange) -> Result<(), String> {
+        let FeeRates { funding, refund } = *self;
+        if !accepted.contains_rate(funding) || !accepted.contains_rate(refund) {
+            let (min, max) = (accepted.min.as_sat_per_vb(), accepted.max.as_sat_per_vb());
+            return Err(format!("Feerates of {funding} and {refund} sat/vB, we accept {min} to {max}"));
+        }
+        Ok(())
+    }
+
+    pub fn funding_rate(&self) -> FeeRate {
+        FeeRate::from_sat_per_vb(self.funding)
+    }
+
+    pub fn refund_rate(&self) -> FeeRate {
+        FeeRate::from_sat_per_vb(self.refund)
+    }
+}
+
+// Share of the refund fee each of the `participants` pays, at least `REFUND_FEE_PER_USER`. It counts
+// the heaviest contract path and outputs as heavy as p2wsh ones, so the refund pays `rate` whatever
+// addresses the participants use.
+pub fn refund_fee_share(pub_desc: &Descriptor<PublicKey>, participants: usize, rate: FeeRate) -> u64 {
+    let txout = TxOut { value: 0, script_pubkey: Script::new_v0_p2wsh(&WScriptHash::all_zeros()) };
+    let refund = Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn::default()],
+        output: vec![txout; participants],
+    };
+    let contract_weight = pub_desc.max_satisfaction_weight().unwrap_or_default();
+    let fee = rate.fee_vb(estimate_vsize(&refund, &[contract_weight]));
+
+    fee.div_ceil(participants.max(1) as u64).max(REFUND_FEE_PER_USER)
+}
+
+// What the maker keeps out of what each user puts in the contract: `base` sats plus `ppm` millionths of
+// it. The payout of the round is what each user put in minus this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MakerFee {
+    pub base: u64,
+    pub ppm: u64,
+}
+
+impl MakerFee {
+    pub fn of(&self, amount: u64) -> u64 {
+        let proportional = amount as u128 * self.ppm as u128 / 1_000_000;
+
+        self.base.saturating_add(proportional.try_into().unwrap_or(u64::MAX))
+    }
+
+    // Whether the fee on `amount` is at most `max_ppm` millionths of it, the base included
+    pub fn within_ppm(&self, amount: u64, max_ppm: u64) -> bool {
+        self.of(amount) as u128 * 1_000_000 <= max_ppm as u128 * amount as u128
+    }
+}
+
+// `<base>,<ppm>`
+impl FromStr for MakerFee {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (base, ppm) = s.split_once(',').ok_or_else(|| "Expected <base>,<ppm>".to_string())?;
+        let base = base.parse().map_err(|e| format!("Invalid base fee '{base}': {e}"))?;
+        let ppm = ppm.parse().map_err(|e| format!("Invalid proportional fee '{ppm}': {e}"))?;
+        if ppm > 1_000_000 {
+            return Err(format!("A proportional fee of {ppm} ppm is over the whole amount"));
+        }
+        Ok(MakerFee { base, ppm })
+    }
+}
+
+impl fmt::Display for MakerFee {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} sats + {} ppm", self.base, self.ppm)
+    }
+}
+
+// Relative timelocks (in blocks) of the refund path of the users2maker contract and the timelocked path of
+// the maker2user ones. The maker picks them and tells users in the contract data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Timelocks {
+    pub refund: u16,
+    pub maker2user: u16,
+}
+
+impl Default for Timelocks {
+    fn default() -> Self {
+        Timelocks { refund: 48, maker2user: 69 }
+    }
+}
+
+// `<refund>,<maker2user>`
+impl FromStr for Timelocks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let timelocks = s.split(',')
+            .map(|blocks| match blocks.parse() {
+                Ok(0) => Err("Timelocks must be of at least 1 block".to_string()),
+                Ok(blocks) => Ok(blocks),
+                Err(e) => Err(format!("Invalid timelock '{blocks}': {e}")),
+            })
+            .collect::<Result<Vec<u16>, _>>()?;
+
+        match timelocks[..] {
+            // Users claim their maker2user contract after the refund gets valid at the latest, see
+            // `TimelockBounds`, so no user takes contracts the other way around
+            [refund, maker2user] if maker2user <= refund => {
+                Err(format!("The maker2user timelock ({maker2user}) must be longer than the refund one ({refund})"))
+            },
+            [refund, maker2user] => Ok(Timelocks { refund, maker2user }),
+            _ => Err(format!("Expected the refund and maker2user timelocks, got {} values", timelocks.len())),
+        }
+    }
+}
+
+impl fmt::Display for Timelocks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "refund {} blocks, maker2user {} blocks", self.refund, self.maker2user)
+    }
+}
+
+// Timelocks a user takes. The refund one must give the round time to finish before anyone can take the
+// refund, and not lock our coins for too long if it doesn't. Once the maker claims the users2maker
+// contract, revealing the preimage, we have until the maker2user timelock to claim ours. The maker may
+// claim up to when the refund gets valid, so the maker2user timelock must be `claim_window` blocks longer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimelockBounds {
+    pub min_refund: u16,
+    pub max_refund: u16,
+    pub claim_window: u16,
+}
+
+impl Default for TimelockBounds {
+    fn default() -> Self {
+        TimelockBounds { min_refund: 24, max_refund: 1008, claim_window: 12 }
+    }
+}
+
+impl TimelockBounds {
+    pub fn check(&self, timelocks: &Timelocks) -> Result<(), String> {
+        let Timelocks { refund, maker2user } = *timelocks;
+        if !(self.min_refund..=self.max_refund).contains(&refund) {
+            let (min, max) = (self.min_refund, self.max_refund);
+            return Err(format!("A refund timelock of {refund} blocks, we take {min} to {max}"));
+        }
+        validate_timelock_schedule(refund, maker2user, self.claim_window)
+    }
+}
+
+// The maker may claim the users2maker contract, revealing the preimage, until its refund gets valid after
+// `first_csv` blocks. We need `claim_window` blocks after that to claim the maker2user contract before the
+// maker can take it back, after `second_csv` blocks.
+pub fn validate_timelock_schedule(first_csv: u16, second_csv: u16, claim_window: u16) -> Result<(), String> {
+    if second_csv.saturating_sub(first_csv) < claim_window {
+        return Err(format!(
+            "A maker2user timelock of {second_csv} blocks leaves less than {claim_window} to claim after the refund \
+             timelock of {first_csv}"
+        ));
+    }
+    Ok(())
+}
+
+// Relative timelocks a maker builds its contracts with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimelockRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl TimelockRange {
+    pub fn only(blocks: u16) -> Self {
+        TimelockRange { min: blocks, max: blocks }
+    }
+
+    pub fn contains(&self, blocks: u16) -> bool {
+        self.min <= blocks && blocks <= self.max
+    }
+}
+
+// Same as the wpkh descriptor weight: script_sig length, signature with sighash byte and pubkey
+pub const P2WPKH_SATISFACTION_WEIGHT: usize = 4 + 1 + 73 + 1 + 33;
+
+// Most weight that satisfying a segwit v0 input can add, if we know how it's spent
+pub fn max_satisfaction_weight(input: &psbt::Input) -> Option<usize> {
+    let spk = &input.witness_utxo.as_ref()?.script_pubkey;
+    if spk.is_v0_p2wpkh() {
+        return Some(P2WPKH_SATISFACTION_WEIGHT);
+    }
+    let witness_script = input.witness_script.as_ref().filter(|script| script.to_v0_p2wsh() == *spk)?;
+    let ms = Miniscript::<PublicKey, Segwitv0>::parse(witness_script).ok()?;
+
+    Descriptor::new_wsh(ms).ok()?.max_satisfaction_weight().ok()
+}
+
+// Largest vsize of the tx once each input is satisfied, given the weight that adds to each one
+pub fn estimate_vsize(tx: &Transaction, satisfaction_weights: &[usize]) -> usize {
+    estimate_weight(tx, satisfaction_weights).div_ceil(4)
+}
+
+// What the outputs of `tx` add up to, `None` past `u64::MAX`
+pub fn output_value(tx: &Transaction) -> Option<u64> {
+    tx.output.iter().try_fold(0u64, |sum, txout| sum.checked_add(txout.value))
+}
+
+// The fee of `psbt`, what its inputs add up to minus its outputs. `None` if it doesn't tell the value of
+// some input, or if its outputs add up to more. Unlike bdk's `fee_amount` it never panics, as peers may
+// send us such PSBTs.
+pub fn psbt_fee(psbt: &Psbt) -> Option<u64> {
+    let tx = &psbt.unsigned_tx;
+    if psbt.inputs.len() != tx.input.len() {
+        return None;
+    }
+    let mut inputs = 0u64;
+    for (txin, input) in tx.input.iter().zip(&psbt.inputs) {
+        let value = match (&input.witness_utxo, &input.non_witness_utxo) {
+            (Some(txout), _) => txout.value,
+            (None, Some(prev_tx)) => prev_tx.output.get(txin.previous_output.vout as usize)?.value,
+            (None, None) => return None,
+        };
+        inputs = inputs.checked_add(value)?;
+    }
+
+    inputs.checked_sub(output_value(tx)?)
+}
+
+// Why nodes wouldn't take `replacement` in place of the funding tx `replaced` (BIP125): it must spend
+// every input of it and pay its fee plus 1 sat/vB of its own vsize. The fee is only checked when we
+// know every input weight.
+pub fn replacement_failures(replaced: &Psbt, replacement: &Psbt) -> Vec<PsbtCheckFailure> {
+    let mut failures: Vec<_> = replaced.unsigned_tx.input.iter()
+        .map(|txin| txin.previous_output)
+        .filter(|outpoint| !replacement.unsigned_tx.input.iter().any(|txin| txin.previous_output == *outpoint))
+        .map(PsbtCheckFailure::ReplacedInputMissing)
+        .collect();
+
+    let weights: Option<Vec<_>> = replacement.inputs.iter().map(max_satisfaction_weight).collect();
+    if let (Some(fee), Some(replaced_fee), Some(weights)) = (psbt_fee(replacement), psbt_fee(replaced), weights) {
+        let min = replaced_fee.saturating_add(estimate_vsize(&replacement.unsigned_tx, &weights) as u64);
+        if fee < min {
+            failures.push(PsbtCheckFailure::ReplacementFeeTooLow { fee, min });
+        }
+    }
+    failures
+}
+
+// Bitcoin Core default policy: the heaviest tx it relays (100k vbytes), the least feerate it relays
+// in sat/vB, and the most witness items a p2wsh spend may push besides its script
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+pub const MIN_RELAY_FEE_RATE: u64 = 1;
+pub const MAX_STANDARD_P2WSH_STACK_ITEMS: usize = 100;
+
+// Whether nodes with the default policy would relay the tx of `psbt` once each input is satisfied with
+// the weight given for it: not too heavy, no dust outputs, paying at least the min relay feerate, and
+// with a standard witness for the p2wsh inputs whose witness script we know
+pub fn check_standardness(psbt: &Psbt, satisfaction_weights: &[usize]) -> Result<(), StandardnessError> {
+    let tx = &psbt.unsigned_tx;
+    if satisfaction_weights.len() != tx.input.len() {
+        return Err(StandardnessError::UnknownInputWeights);
+    }
+    let weight = estimate_weight(tx, satisfaction_weights);
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        return Err(StandardnessError::TooHeavy { weight, max: MAX_STANDARD_TX_WEIGHT });
+    }
+    for (output, txout) in tx.output.iter().enumerate() {
+        let dust = txout.script_pubkey.dust_value().to_sat();
+        if txout.value < dust {
+            return Err(StandardnessError::DustOutput { output, value: txout.value, dust });
+        }
+    }
+    let fee = psbt_fee(psbt).ok_or(StandardnessError::UnknownFee)?;
+    let min = MIN_RELAY_FEE_RATE * weight.div_ceil(4) as u64;
+    if fee < min {
+        return Err(StandardnessError::BelowMinRelayFee { fee, min });
+    }
+
+    for (input, psbt_in) in psbt.inputs.iter().enumerate() {
+        let Some(spk) = psbt_in.witness_utxo.as_ref().map(|txout| &txout.script_pubkey) else { continue };
+        let Some(script) = psbt_in.witness_script.as_ref().filter(|script| script.to_v0_p2wsh() == *spk) else {
+            continue;
+        };
+        if script.len() > MAX_WITNESS_SCRIPT_SIZE {
+            return Err(StandardnessError::WitnessScriptTooLarge { input, size: script.len() });
+        }
+        // Miniscript counts the witness script among the items
+        let items = Miniscript::<PublicKey, Segwitv0>::parse(script).ok()
+            .and_then(|ms| ms.max_satisfaction_witness_elements().ok())
+            .map(|elements| elements.saturating_sub(1));
+        if let Some(items) = items.filter(|items| *items > MAX_STANDARD_P2WSH_STACK_ITEMS) {
+            return Err(StandardnessError::TooManyWitnessItems { input, items });
+        }
+    }
+    Ok(())
+}
+
+fn estimate_weight(tx: &Transaction, satisfaction_weights: &[usize]) -> usize {
+    // Satisfaction weights already count the script_sig length, which the unsigned tx has too, and
+    // the unsigned tx lacks the segwit marker and flag
+    tx.weight() - 4 * tx.input.len() + 2 + satisfaction_weights.iter().sum::<usize>()
+}
+
+// Input weight before it's satisfied: outpoint and sequence
+const TXIN_BASE_WEIGHT: usize = 4 * (32 + 4 + 4);
+
+// How users split the funding fee: each one pays for the weight its inputs and change add to the
+// funding tx, plus an even part of the weight they share (the tx fields and the contract output).
+// Shares are rounded down, and the refund fee gives up the few sats left over. Both the maker and
+// the users work it out of the funding psbt, so they agree to the sat.
+#[derive(Debug, Clone)]
+pub struct FundingFeeSplit<'a> {
+    psbt: &'a Psbt,
+    fee: u64,
+    users: usize,
+    // Weight of each input once satisfied
+    input_weights: Vec<usize>,
+    weight: usize,
+    shared_weight: usize,
+}
+
+impl<'a> FundingFeeSplit<'a> {
+    // For a funding tx paying to the contract of `contract_spk`. `None` if we can't tell how much some
+    // input weighs.
+    pub fn new(psbt: &'a Psbt, contract_spk: &Script, fee: u64, users: usize) -> Option<Self> {
+        let satisfaction_weights: Vec<_> = psbt.inputs.iter().map(max_satisfaction_weight).collect::<Option<_>>()?;
+        let weight = estimate_weight(&psbt.unsigned_tx, &satisfaction_weights);
+        let input_weights: Vec<_> = satisfaction_weights.iter().map(|weight| TXIN_BASE_WEIGHT + weight).collect();
+        // Every output but the contract is a change
+        let change_weight: usize = psbt.unsigned_tx.output.iter()
+            .filter(|txout| txout.script_pubkey != *contract_spk)
+            .map(txout_weight)
+            .sum();
+        let shared_weight = weight.checked_sub(input_weights.iter().sum::<usize>() + change_weight)?;
+
+        Some(FundingFeeSplit { psbt, fee, users: users.max(1), input_weights, weight, shared_weight })
+    }
+
+    // Weight the user spending `outpoints` adds to the funding tx, with the change it gets at `change`
+    pub fn user_weight(&self, outpoints: &[OutPoint], change: Option<&Script>) -> usize {
+        let tx = &self.psbt.unsigned_tx;
+        let inputs = tx.input.iter().zip(&self.input_weights)
+            .filter(|(txin, _)| outpoints.contains(&txin.previous_output))
+            .map(|(_, weight)| weight)
+            .sum::<usize>();
+        let change = tx.output.iter()
+            .filter(|txout| Some(&txout.script_pubkey) == change)
+            .map(txout_weight)
+            .sum::<usize>();
+
+        inputs + change
+    }
+
+    // Part of the funding fee paid by the user spending `outpoints`
+    pub fn share(&self, outpoints: &[OutPoint], change: Option<&Script>) -> u64 {
+        let users = self.users as u128;
+        let weight = users * self.user_weight(outpoints, change) as u128 + self.shared_weight as u128;
+        let share = self.fee as u128 * weight / (users * self.weight.max(1) as u128);
+
+        u64::try_from(share).unwrap_or(u64::MAX)
+    }
+}
+
+fn txout_weight(txout: &TxOut) -> usize {
+    4 * serialize(txout).len()
+}
+
+// Part of its utxos a user keeps, paid back to it by the funding tx
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    // What the user puts in the contract, the change pays its share of the funding fee
+    pub contribution: u64,
+    pub address: Address,
+    // Whether a change too small to relay may go to the contract instead, as if the user spent its
+    // utxos fully
+    pub fold_dust: bool,
+}
+
+impl Change {
+    // What the user gets back out of its utxos worth `value`, paying `fee_share` of the funding fee.
+    // Dust or less is returned as `None`.
+    pub fn value(&self, value: u64, fee_share: u64) -> Option<u64> {
+        let dust = self.address.script_pubkey().dust_value().to_sat();
+
+        value.checked_sub(self.contribution)?.checked_sub(fee_share).filter(|change| *change >= dust)
+    }
+}
+
+// What a user brings to the funding tx
+#[derive(Debug, Clone)]
+pub struct UserFunds {
+    pub utxos: Vec<WeightedUtxo>,
+    pub change: Option<Change>,
+}
+
+impl UserFunds {
+    pub fn value(&self) -> u64 {
+        self.utxos.iter().map(|utxo| utxo.utxo.txout().value).sum()
+    }
+
+    pub fn outpoints(&self) -> Vec<OutPoint> {
+        self.utxos.iter().map(|utxo| utxo.utxo.outpoint()).collect()
+    }
+
+    // Part of the funding fee this user pays, if it gets its change at `change`
+    fn fee_share(&self, split: &FundingFeeSplit, change: Option<&Change>) -> u64 {
+        split.share(&self.outpoints(), change.map(|change| change.address.script_pubkey()).as_ref())
+    }
+}
+
+fn fee_split<'a>(psbt: &'a Psbt, contract_spk: &Script, users: usize) -> Result<FundingFeeSplit<'a>, BuildError> {
+    let fee = psbt_fee(psbt).ok_or_else(|| BuildError::TxBuilder("Unknown funding fee".to_string()))?;
+
+    FundingFeeSplit::new(psbt, contract_spk, fee, users)
+        .ok_or_else(|| BuildError::TxBuilder("Unknown funding input weight".to_string()))
+}
+
+// Funding tx weight not tied to any one user's inputs or change: version, locktime, input and output
+// counts, segwit marker and flag, and the P2WSH contract output
+const FUNDING_BASE_WEIGHT: usize = 4 * (4 + 4 + 1 + 1 + 43) + 2;
+
+// How a user picks the utxos it brings for a contribution
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoinSelection {
+    #[default]
+    LargestFirst,
+    // Looks for a set that needs no change, else draws utxos at random. Sets over `MAX_USER_UTXOS`
+    // fall back to largest first.
+    BranchAndBound,
+}
+
+// Picks the utxos of `wallet` that put `contribution` in the contract, plus the fee each one adds to
+// the funding tx at `fee_rate` and that of its shared parts. Users split those shared parts evenly
+// in the round, so our share may still differ from this estimate. Unless `fold_dust`, the change paid
+// to `change_script` is left above dust.
+pub fn select_utxos<D: BatchDatabase>(
+    wallet: &Wallet<D>,
+    contribution: u64,
+    fee_rate: FeeRate,
+    change_script: &Script,
+    fold_dust: bool,
+    algorithm: CoinSelection,
+) -> Result<Vec<LocalUtxo>, UtxoError> {
+    let utxos = wallet.list_unspent().map_err(|e| UtxoError::Malformed(e.to_string()))?
+        .into_iter()
+        .map(|utxo| {
+            let desc = wallet.get_descriptor_for_keychain(utxo.keychain);
+            let satisfaction_weight =
+                desc.max_satisfaction_weight().map_err(|_| UtxoError::UnsupportedDescriptor(desc.desc_type()))?;
+            Ok(WeightedUtxo { satisfaction_weight, utxo: Utxo::Local(utxo) })
+        })
+        .collect::<Result<Vec<_>, UtxoError>>()?;
+    let select = |target: u64| {
+        let database = wallet.database();
+        let largest_first = || {
+            LargestFirstCoinSelection.coin_select(&*database, vec![], utxos.clone(), fee_rate, target, change_script)
+        };
+        match algorithm {
+            CoinSelection::LargestFirst => largest_first(),
+            CoinSelection::BranchAndBound => BranchAndBoundCoinSelection::default()
+                .coin_select(&*database, vec![], utxos.clone(), fee_rate, target, change_script)
+                .and_then(|result| if result.selected.len() > MAX_USER_UTXOS { largest_first() } else { Ok(result) }),
+        }
+    };
+
+    let target = contribution + fee_rate.fee_wu(FUNDING_BASE_WEIGHT);
+    let mut result = select(target);
+    // A change too small to relay would fail the round, so we bring enough to pay for a larger one
+    if let Ok(CoinSelectionResult { excess: Excess::NoChange { dust_threshold, change_fee, .. }, .. }) = result {
+        if !fold_dust {
+            result = select(target + dust_threshold + change_fee);
+        }
+    }
+    let selected = match result {
+        Ok(result) => result.selected,
+        Err(bdk::Error::InsufficientFunds { available, .. }) => {
+            return Err(UtxoError::ContributionTooLarge { contribution, value: available });
+        },
+        Err(e) => return Err(UtxoError::Malformed(e.to_string())),
+    };
+    if selected.len() > MAX_USER_UTXOS {
+        return Err(UtxoError::Count { count: selected.len(), max: MAX_USER_UTXOS });
+    }
+
+    Ok(selected.into_iter().filter_map(|utxo| match utxo {
+        Utxo::Local(local) => Some(local),
+        Utxo::Foreign { .. } => None,
+    }).collect())
+}
+
+// In a round of equal amounts every user puts the same denomination in the contract, a multiple of
+// `step`, and keeps the rest as change. It's the most the poorest user can put in while its change
+// still pays its share of the funding fee at `fee_rate` and stays above dust. Returns the denomination.
+pub fn denominate(
+    pub_desc: &Descriptor<PublicKey>,
+    users: &mut [UserFunds],
+    step: u64,
+    fee_rate: FeeRate,
+) -> Result<u64, BuildError> {
+    if users.is_empty() || users.iter().any(|user| user.utxos.is_empty()) {
+        return Err(BuildError::NoUtxos);
+    }
+    if let Some(index) = users.iter().position(|user| user.change.is_none()) {
+        return Err(BuildError::MissingChange { index });
+    }
+    let pub_wallet = contract_wallet(pub_desc)?;
+
+    // Every user keeps change, and any value above dust weighs the same
+    let changes: Vec<_> = users.iter().flat_map(|user| &user.change).collect();
+    let outputs = changes.iter()
+        .map(|change| {
+            let spk = change.address.script_pubkey();
+            let dust = spk.dust_value().to_sat();
+            (spk, dust)
+        })
+        .collect();
+    // Only its weight counts, which the locktime doesn't change
+    let draft = build_funding_psbt(&pub_wallet, users, outputs, None, fee_rate, LockTime::ZERO)?;
+    let split = fee_split(&draft, &pub_desc.script_pubkey(), users.len())?;
+
+    let mut most = u64::MAX;
+    for (index, (user, change)) in users.iter().zip(changes).enumerate() {
+        let fee_share = user.fee_share(&split, Some(change));
+        let dust = change.address.script_pubkey().dust_value().to_sat();
+        let value = user.value();
+        let affordable = value.checked_sub(fee_share + dust)
+            .ok_or(BuildError::InsufficientValue { index, value, fee_share })?;
+        most = most.min(affordable).min(change.contribution);
+    }
+    let denomination = most - most % step.max(1);
+
+    for change in users.iter_mut().flat_map(|user| &mut user.change) {
+        change.contribution = denomination;
+    }
+    Ok(denomination)
+}
+
+// A wallet of the contract that can't sign yet. We keep one per contract and register on it the
+// private keys we know as we learn them (see `add_contract_signers`).
+pub fn contract_wallet(pub_desc: &Descriptor<PublicKey>) -> Result<Wallet<MemoryDatabase>, BuildError> {
+    pub_desc.sanity_check().map_err(|e| BuildError::Descriptor(e.to_string()))?;
+
+    Wallet::new(
+        &pub_desc.to_string(),
+        None,
+        Network::Regtest,
+        MemoryDatabase::new(),
+    ).map_err(|e| BuildError::Descriptor(e.to_string()))
+}
+
+// Most blocks `anti_fee_sniping_locktime` puts the locktime behind the tip
+pub const MAX_LOCKTIME_OFFSET: u32 = 99;
+// Blocks the tip of the maker's backend may be apart from ours
+pub const TIP_TOLERANCE: u32 = 6;
+
+// Locktime of the txs we build. Common wallets set it to the tip, so a miner reorging the tip can't
+// include the tx in its own block and take the fee. Like Bitcoin Core, one tx in ten goes up to 99
+// blocks back, so the txs that took long to get signed don't stand out.
+pub fn anti_fee_sniping_locktime<R: Rng + ?Sized>(rng: &mut R, tip: u32) -> LockTime {
+    let mut height = tip;
+    if rng.gen_ratio(1, 10) {
+        height = height.saturating_sub(rng.gen_range(0..=MAX_LOCKTIME_OFFSET));
+    }
+    LockTime::from_height(height).expect("Block heights are below the locktime threshold")
+}
+
+// Whether `lock_time` could come from `anti_fee_sniping_locktime` with a tip close to ours
+pub fn locktime_near_tip(lock_time: PackedLockTime, tip: u32) -> bool {
+    let lowest = tip.saturating_sub(MAX_LOCKTIME_OFFSET + TIP_TOLERANCE);
+
+    LockTime::from(lock_time).is_block_height() && (lowest..=tip + TIP_TOLERANCE).contains(&lock_time.0)
+}
+
+// Every tx we build looks like those of common wallets: version 2 and the locktime at the tip, see
+// `anti_fee_sniping_locktime`
+pub fn like_wallet_txs<D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>(
+    tx_builder: &mut TxBuilder<'_, D, Cs, Ctx>,
+    lock_time: LockTime,
+) {
+    tx_builder.version(2).nlocktime(lock_time);
+}
+
+// The output of `tx` paying to the contract of `spk`. Txs we build shuffle their outputs like those of
+// common wallets, so the contract output is found by its script. `None` unless `tx` pays it once.
+pub fn contract_output(tx: &Transaction, spk: &Script) -> Option<(OutPoint, TxOut)> {
+    let mut paying = tx.output.iter().enumerate().filter(|(_, txout)| txout.script_pubkey == *spk);
+
+    match (paying.next(), paying.next()) {
+        (Some((vout, txout)), None) => Some((OutPoint { txid: tx.txid(), vout: vout as u32 }, txout.clone())),
+        _ => None,
+    }
+}
+
+// Whether the funding input asks to be signed with SIGHASH_ALL|ANYONECANPAY, see `set_anyone_can_pay`
+pub fn is_anyone_can_pay(input: &psbt::Input) -> bool {
+    input.sighash_type == Some(EcdsaSighashType::AllPlusAnyoneCanPay.into())
+}
+
+// Asks users to sign the funding inputs spending `outpoints` with SIGHASH_ALL|ANYONECANPAY, in rounds
+// where they agreed on it. Their signatures then commit to the outputs and their own input alone, so
+// another user can add an input (see `add_funding_input`) without making them sign again.
+pub fn set_anyone_can_pay(funding: &mut Psbt, outpoints: &[OutPoint]) {
+    for (txin, input) in funding.unsigned_tx.input.iter().zip(&mut funding.inputs) {
+        if outpoints.contains(&txin.previous_output) {
+            input.sighash_type = Some(EcdsaSighashType::AllPlusAnyoneCanPay.into());
+        }
+    }
+}
+
+// Appends the input spending `outpoint` to the funding tx, signed with SIGHASH_ALL|ANYONECANPAY like
+// the inputs of its user. Outputs are left as they are, so all of its value goes to the fee.
+pub fn add_funding_input(funding: &mut Psbt, outpoint: OutPoint, mut input: psbt::Input) -> Result<(), AddInputError> {
+    let tx = &mut funding.unsigned_tx;
+    if tx.input.iter().any(|txin| txin.previous_output == outpoint) {
+        return Err(AddInputError::DuplicateInput(outpoint));
+    }
+    let sequence = tx.input.first().map_or(Sequence::ENABLE_RBF_NO_LOCKTIME, |txin| txin.sequence);
+    tx.input.push(TxIn { previous_output: outpoint, sequence, ..Default::default() });
+
+    input.sighash_type = Some(EcdsaSighashType::AllPlusAnyoneCanPay.into());
+    input.partial_sigs.clear();
+    input.final_script_witness = None;
+    funding.inputs.push(input);
+    Ok(())
+}
+
+// Checks `updated` is the funding tx `original` with some inputs appended and nothing else changed,
+// returning the added outpoints and their values. As the outputs are the same, the contract still
+// holds the inputs minus the fee, which grows by all the added value.
+pub fn check_added_inputs(original: &Psbt, updated: &Psbt) -> Result<Vec<(OutPoint, u64)>, AddInputError> {
+    let (before, after) = (&original.unsigned_tx, &updated.unsigned_tx);
+    if after.output != before.output {
+        return Err(AddInputError::OutputsChanged);
+    }
+    let kept = before.input.len();
+    if after.version != before.version || after.lock_time != before.lock_time || after.input.len() < kept
+        || after.input[..kept] != before.input[..kept] || updated.inputs.len() != after.input.len()
+    {
+        return Err(AddInputError::InputsChanged);
+    }
+    let prevouts_kept = original.inputs.iter().zip(&updated.inputs)
+        .all(|(old, new)| old.witness_utxo == new.witness_utxo && old.non_witness_utxo == new.non_witness_utxo);
+    if !prevouts_kept {
+        return Err(AddInputError::InputsChanged);
+    }
+    if after.input.len() == kept {
+        return Err(AddInputError::NoAddedInput);
+    }
+
+    let mut added = Vec::new();
+    for (index, (txin, input)) in after.input.iter().zip(&updated.inputs).enumerate().skip(kept) {
+        let outpoint = txin.previous_output;
+        if after.input[..index].iter().any(|earlier| earlier.previous_output == outpoint) {
+            return Err(AddInputError::DuplicateInput(outpoint));
+        }
+        // The value is taken from the full previous tx, as a segwit v0 signature doesn't commit to the
+        // values of the other inputs
+        let value = input.non_witness_utxo.as_ref()
+            .filter(|prev_tx| prev_tx.txid() == outpoint.txid)
+            .and_then(|prev_tx| prev_tx.output.get(outpoint.vout as usize))
+            .filter(|prevout| input.witness_utxo.as_ref().is_none_or(|txout| txout == *prevout))
+            .map(|prevout| prevout.value)
+            .ok_or(AddInputError::UnknownValue(outpoint))?;
+        added.push((outpoint, value));
+    }
+    Ok(added)
+}
+
+// An unsigned copy of the refund PSBT spending the contract output of `funding` instead, once inputs
+// were added to the funding tx. The outputs are the same, so the contract output keeps its vout.
+pub fn rebase_refund(refund: &Psbt, funding: &Transaction) -> Psbt {
+    let mut rebased = refund.clone();
+    for txin in &mut rebased.unsigned_tx.input {
+        txin.previous_output.txid = funding.txid();
+    }
+    for input in &mut rebased.inputs {
+        if input.non_witness_utxo.is_some() {
+            input.non_witness_utxo = Some(funding.clone());
+        }
+        input.partial_sigs.clear();
+        input.tap_script_sigs.clear();
+        input.final_script_sig = None;
+        input.final_script_witness = None;
+    }
+    rebased
+}
+
+// A utxo of the maker that goes whole in the users2maker contract next to the users' ones, and
+// where the refund tx pays it back. It pays its share of the fees as a user without change would.
+#[derive(Debug, Clone)]
+pub struct MakerInput {
+    pub utxo: WeightedUtxo,
+    pub refund_to: Address,
+}
+
+// The funding tx spends the utxos of each user, paying back the change of those who keep some, and
+// the refund tx pays each one back what it put in the contract, minus its share of the fees. The
+// maker input, if any, goes last. Both txs pay the `fee_rates` and take the locktime of the `tip` of our
+// backend. The refund comes first, then its pricier variants (see `REFUND_LADDER`) but for those that
+// would leave a refund output under `DUST_LIMIT` or otherwise not be relayed (see `check_standardness`).
+pub fn build_funding_and_refund(
+    pub_desc: &Descriptor<PublicKey>,
+    users: Vec<UserFunds>,
+    refund_to: Vec<Address>,
+    maker: Option<MakerInput>,
+    fee_rates: FeeRates,
+    tip: u32,
+) -> Result<(Psbt, Vec<Psbt>), BuildError> {
+    build_funding_and_refund_with(&mut thread_rng(), pub_desc, users, refund_to, maker, fee_rates, tip)
+}
+
+// The same with the funding outputs and locktime drawn from `rng`
+pub(crate) fn build_funding_and_refund_with<R: Rng + ?Sized>(
+    rng: &mut R,
+    pub_desc: &Descriptor<PublicKey>,
+    mut users: Vec<UserFunds>,
+    mut refund_to: Vec<Address>,
+    maker: Option<MakerInput>,
+    fee_rates: FeeRates,
+    tip: u32,
+) -> Result<(Psbt, Vec<Psbt>), BuildError> {
+    if users.is_empty() || users.iter().any(|user| user.utxos.is_empty()) {
+        return Err(BuildError::NoUtxos);
+    }
+    if users.len() != refund_to.len() {
+        return Err(BuildError::CountMismatch { users: users.len(), addresses: refund_to.len() });
+    }
+    for (index, user) in users.iter().enumerate() {
+        if let Some(change) = user.change.as_ref().filter(|change| change.contribution > user.value()) {
+            let (contribution, value) = (change.contribution, user.value());
+            return Err(BuildError::ContributionTooLarge { index, contribution, value });
+        }
+    }
+    if let Some(MakerInput { utxo, refund_to: maker_refund }) = maker {
+        users.push(UserFunds { utxos: vec![utxo], change: None });
+        refund_to.push(maker_refund);
+    }
+    let pub_wallet = contract_wallet(pub_desc)?;
+    let lock_time = anti_fee_sniping_locktime(rng, tip);
+    let (mut funding_psbt, changes, fee_shares) =
+        build_funding_tx(&pub_wallet, &pub_desc.script_pubkey(), &users, fee_rates.funding_rate(), lock_time)?;
+    // The contract output is at no telling position, see `contract_output`
+    shuffle_outputs(&mut funding_psbt, rng);
+
+    // What each user puts in the contract. One keeping no change must pay its funding fee share out of
+    // its utxos.
+    let mut put_in = Vec::new();
+    for (index, ((user, change), fee_share)) in users.iter().zip(changes).zip(fee_shares).enumerate() {
+        let value = user.value();
+        let contribution = value.checked_sub(change + fee_share)
+            .ok_or(BuildError::InsufficientValue { index, value, fee_share })?;
+        put_in.push(contribution);
+    }
+    let refund_recipients: Vec<(Address, u64)> = refund_to
+        .into_iter()
+        .zip(put_in)
+        .collect();
+
+    // Create local utxo with the contract output of the funding tx and update the database
+    let (outpoint, contract_txout) = contract_output(&funding_psbt.unsigned_tx, &pub_desc.script_pubkey())
+        .ok_or_else(|| BuildError::TxBuilder("The funding tx doesn't pay to the contract".to_string()))?;
+    let local = LocalUtxo {
+        outpoint,
+        txout: contract_txout.clone(),
+        keychain: KeychainKind::External,
+        is_spent: false
+    };
+    let mut database = MemoryDatabase::new();
+    database.set_utxo(&local).map_err(|e| BuildError::TxBuilder(e.to_string()))?;
+
+    let updated_wallet = Wallet::new(
+        &pub_desc.to_string(),
+        None,
+        Network::Regtest,
+        database,
+    ).map_err(|e| BuildError::Descriptor(e.to_string()))?;
+
+    let fee_share = refund_fee_share(pub_desc, refund_recipients.len(), fee_rates.refund_rate());
+    let recipients = refund_recipients.clone();
+    let mut refund_psbt = build_refund_tx(&updated_wallet, recipients, &local, fee_share, lock_time)?;
+
+    // Witness utxo field doesn't include the whole tx data so we can spend from unsigned txs
+    refund_psbt.inputs[0].witness_utxo = Some(contract_txout.clone());
+
+    let funding_weights: Vec<_> = funding_psbt.inputs.iter().map(max_satisfaction_weight).collect::<Option<_>>()
+        .ok_or_else(|| BuildError::TxBuilder("Unknown funding input weight".to_string()))?;
+    check_standardness(&funding_psbt, &funding_weights)
+        .map_err(|error| BuildError::Nonstandard { tx: "funding", error })?;
+    let contract_weight = pub_desc.max_satisfaction_weight()
+        .map_err(|e| BuildError::Descriptor(e.to_string()))?;
+    check_standardness(&refund_psbt, &[contract_weight])
+        .map_err(|error| BuildError::Nonstandard { tx: "refund", error })?;
+
+    let mut refunds = vec![refund_psbt];
+    for multiple in REFUND_LADDER {
+        let recipients = refund_recipients.clone();
+        let variant_share = fee_share.saturating_mul(multiple);
+        let built = build_refund_tx(&updated_wallet, recipients, &local, variant_share, lock_time);
+        let Some(mut variant) = built.ok().filter(|psbt| {
+            psbt.unsigned_tx.output.iter().all(|txout| txout.value >= DUST_LIMIT)
+        }) else {
+            break;
+        };
+        variant.inputs[0].witness_utxo = Some(contract_txout.clone());
+        if check_standardness(&variant, &[contract_weight]).is_err() {
+            break;
+        }
+        refunds.push(variant);
+    }
+
+    Ok((funding_psbt, refunds))
+}
+
+fn build_refund_tx(
+    wallet: &Wallet<MemoryDatabase>,
+    recipients: Vec<(Address, u64)>,
+    contract: &LocalUtxo,
+    fee_share: u64,
+    lock_time: LockTime,
+) -> Result<Psbt, BuildError> {
+    let out_count = recipients.len() as u64;
+
+    // Computed from the same input values the funding tx was built with, as the psbt fields could
+    // disagree
+    let total_in: u64 = recipients.iter().map(|(_, value)| value).sum();
+    let total_out = contract.txout.value;
+    // The funding fee shares are rounded down, so users put in a few sats more than the contract holds
+    let left_over = total_in.checked_sub(total_out)
+        .filter(|left_over| *left_over < out_count)
+        .ok_or_else(|| BuildError::TxBuilder("The funding fee shares don't add up".to_string()))?;
+    // Users pay even shares of the refund fee, which gives up the funding fee left over. So the outputs
+    // and the fee add up to the contract value to the sat, and each user pays exactly its share.
+    let refund_fee = fee_share.checked_mul(out_count)
+        .and_then(|fee| fee.checked_sub(left_over))
+        .ok_or_else(|| BuildError::TxBuilder(format!("Refund fee shares of {fee_share} sats don't add up")))?;
+
+    let mut outputs = Vec::new();
+    for (index, (address, put_in)) in recipients.into_iter().enumerate() {
+        let final_value = put_in.checked_sub(fee_share)
+            .filter(|value| *value > 0)
+            .ok_or(BuildError::InsufficientValue { index, value: put_in, fee_share })?;
+
+        outputs.push((address.script_pubkey(), final_value));
+    }
+
+    // We have to spend from the relative timelocked path
+    let path = contract_policy_path(wallet, TIMELOCK_PATH)?;
+
+    let mut tx_builder = wallet.build_tx();
+    tx_builder
+        .manually_selected_only()
+        .add_utxo(contract.outpoint).map_err(|e| BuildError::TxBuilder(e.to_string()))?
+        .fee_absolute(refund_fee)
+        .set_recipients(outputs)
+        // Outputs in round order would tell on chain which refund is which user's
+        .ordering(TxOrdering::Shuffle)
+        .policy_path(path, KeychainKind::External);
+    like_wallet_txs(&mut tx_builder, lock_time);
+
+    let (psbt, _) = tx_builder.finish().map_err(|e| BuildError::TxBuilder(e.to_string()))?;
+
+    Ok(psbt)
+}
+
+// Users keeping change pay their share of the funding fee out of it. Outputs weigh the same whatever
+// their value, so a first build tells us the fee and a second one pays the change. A change too small
+// to relay is folded into the contract if its user lets us, and we build again without its output.
+// Returns the change paid to each user and its share of the funding fee.
+fn build_funding_tx(
+    receive_wallet: &Wallet<MemoryDatabase>,
+    contract_spk: &Script,
+    users: &[UserFunds],
+    fee_rate: FeeRate,
+    lock_time: LockTime,
+) -> Result<(Psbt, Vec<u64>, Vec<u64>), BuildError> {
+    let mut changes: Vec<_> = users.iter().map(|user| user.change.as_ref()).collect();
+
+    loop {
+        // Any value above dust weighs the same
+        let outputs = changes.iter().flatten()
+            .map(|change| {
+                let spk = change.address.script_pubkey();
+                let dust = spk.dust_value().to_sat();
+                (spk, dust)
+            })
+            .collect();
+        let draft = build_funding_psbt(receive_wallet, users, outputs, None, fee_rate, lock_time)?;
+        let split = fee_split(&draft, contract_spk, users.len())?;
+        // The final build pays the same fee for the same weight, so the shares hold
+        let fee_shares: Vec<_> = users.iter().zip(&changes)
+            .map(|(user, change)| user.fee_share(&split, *change))
+            .collect();
+
+        let mut values = Vec::new();
+        let mut folded = false;
+        for (index, ((user, change), &fee_share)) in users.iter().zip(changes.iter_mut()).zip(&fee_shares).enumerate() {
+            let Some(requested) = *change else {
+                values.push(0);
+                continue;
+            };
+            match requested.value(user.value(), fee_share) {
+                Some(value) => values.push(value),
+                None if requested.fold_dust => {
+                    *change = None;
+                    folded = true;
+                },
+                None => {
+                    let change = user.value().saturating_sub(requested.contribution).saturating_sub(fee_share);
+                    return Err(BuildError::DustChange { index, change });
+                },
+            }
+        }
+        if folded {
+            continue;
+        }
+
+        let outputs = changes.iter().zip(&values)
+            .filter_map(|(change, value)| Some((change.as_ref()?.address.script_pubkey(), *value)))
+            .collect();
+        let psbt = build_funding_psbt(receive_wallet, users, outputs, Some(split.fee), fee_rate, lock_time)?;
+
+        return Ok((psbt, values, fee_shares));
+    }
+}
+
+// Without a `fee` it pays `fee_rate`
+fn build_funding_psbt(
+    receive_wallet: &Wallet<MemoryDatabase>,
+    users: &[UserFunds],
+    change_outputs: Vec<(Script, u64)>,
+    fee: Option<u64>,
+    fee_rate: FeeRate,
+    lock_time: LockTime,
+) -> Result<Psbt, BuildError> {
+    let mut tx_builder = receive_wallet.build_tx();
+    // Signaling replaceability lets the maker bump the fee of a funding tx that doesn't confirm
+    tx_builder.manually_selected_only().enable_rbf();
+    like_wallet_txs(&mut tx_builder, lock_time);
+
+    for utxo in users.iter().flat_map(|user| user.utxos.iter().cloned()) {
+        match utxo.utxo {
+            Utxo::Foreign { outpoint, psbt_input } => {
+                tx_builder.add_foreign_utxo(outpoint, *psbt_input, utxo.satisfaction_weight)
+                    .map_err(|e| BuildError::TxBuilder(e.to_string()))?;
+            },
+            Utxo::Local(_) => {
+                return Err(BuildError::TxBuilder("User utxos can't be local".to_string()));
+            },
+        }
+    }
+    let wallet_address = receive_wallet.get_address(AddressIndex::New)
+        .map_err(|e| BuildError::Descriptor(e.to_string()))?;
+    tx_builder
+        .set_recipients(change_outputs)
+        .drain_to(wallet_address.script_pubkey());
+    match fee {
+        Some(fee) => tx_builder.fee_absolute(fee),
+        None => tx_builder.fee_rate(fee_rate),
+    };
+
+    // To build a tx from the wallet we need to specify the policy path although we are not
+    // spending from our own wallet UTXOs
+    tx_builder.policy_path(contract_policy_path(receive_wallet, MULTISIG_PATH)?, KeychainKind::External);
+
+    // The outputs go in the order of the caller's rng, see `shuffle_outputs`
+    tx_builder.ordering(TxOrdering::Untouched);
+    let (psbt, _) = tx_builder.finish().map_err(|e| BuildError::TxBuilder(e.to_string()))?;
+
+    Ok(psbt)
+}
+
+fn shuffle_outputs<R: Rng + ?Sized>(psbt: &mut Psbt, rng: &mut R) {
+    let mut outputs: Vec<_> = psbt.unsigned_tx.output.drain(..).zip(psbt.outputs.drain(..)).collect();
+    outputs.shuffle(rng);
+    (psbt.unsigned_tx.output, psbt.outputs) = outputs.into_iter().unzip();
+}
+
+// The policy path of a contract wallet that spends through `path`. The policy of a taproot contract
+// lists its key path first.
+fn contract_policy_path(
+    wallet: &Wallet<MemoryDatabase>,
+    path: usize,
+) -> Result<BTreeMap<String, Vec<usize>>, BuildError> {
+    let wallet_policy = wallet.policies(KeychainKind::External)
+        .map_err(|e| BuildError::Descriptor(e.to_string()))?
+        .ok_or_else(|| BuildError::Descriptor("The contract has no spending policy".to_string()))?;
+    let taproot = matches!(wallet.public_descriptor(KeychainKind::External), Ok(Some(Descriptor::Tr(_))));
+
+    Ok(BTreeMap::from([(wallet_policy.id, vec![path + taproot as usize])]))
+}
+
+// Lets the contract `wallet` sign with `prv_keys`, be they our own keys of the contract or keys a peer
+// handed over to us
+pub fn add_contract_signers<D: BatchDatabase>(
+    wallet: &mut Wallet<D>,
+    contract: &Descriptor<PublicKey>,
+    prv_keys: &[PrivateKey],
+) {
+    // None of our keys is the internal key of a taproot contract, which no one can sign for
+    let context = match contract {
+        Descriptor::Tr(_) => SignerContext::Tap { is_internal_key: false },
+        _ => SignerContext::Segwitv0,
+    };
+    for key in prv_keys {
+        let signer = Arc::new(SignerWrapper::new(*key, context));
+        wallet.add_signer(KeychainKind::External, SignerOrdering::default(), signer);
+    }
+}
+
+// Spends a contract through the multisig path, which needs the private keys of as many participants as
+// its threshold: the maker sweeps the users2maker contract with the users' keys, and a user its maker2user
+// contract with the maker's. Cheaper than the hashlock path and it doesn't reveal the preimage.
+// The `signer` is the contract wallet holding those keys, see `add_contract_signers`.
+pub fn build_cooperative_sweep(
+    signer: &Wallet<MemoryDatabase>,
+    outpoint: OutPoint,
+    prevout: TxOut,
+    destination: Script,
+    fee_rate: FeeRate,
+    tip: u32,
+) -> Result<Transaction, BuildError> {
+    let psbt = spend_contract(signer, (MULTISIG_PATH, None), &[], (outpoint, prevout), destination, fee_rate, tip)?;
+
+    Ok(psbt.extract_tx())
+}
+
+// Like `build_cooperative_sweep`, with a contract wallet of `desc` signing with `my_keys` and the keys the
+// other participants `handed_over`. Without enough of them it fails with a multisig path key we lack.
+pub fn build_multisig_sweep(
+    desc: &Descriptor<PublicKey>,
+    my_keys: &[PrivateKey],
+    handed_over: &[PrivateKey],
+    (outpoint, prevout): (OutPoint, TxOut),
+    destination: Script,
+    fee_rate: FeeRate,
+    tip: u32,
+) -> Result<Transaction, BuildError> {
+    let mut signer = contract_wallet(desc)?;
+    add_contract_signers(&mut signer, desc, my_keys);
+    add_contract_signers(&mut signer, desc, handed_over);
+
+    match build_cooperative_sweep(&signer, outpoint, prevout, destination, fee_rate, tip) {
+        Err(BuildError::Unsatisfied { path }) => {
+            let secp = Secp256k1::new();
+            let x_only = |key: &PublicKey| XOnlyPublicKey::from(key.inner);
+            let held: HashSet<_> =
+                my_keys.iter().chain(handed_over).map(|key| x_only(&key.public_key(&secp))).collect();
+            let missing = contract_path_keys(desc, path)?.into_iter().find(|key| !held.contains(&x_only(key)));
+
+            Err(missing.map_or(BuildError::Unsatisfied { path }, |key| BuildError::MissingKey { path, key }))
+        },
+        swept => swept,
+    }
+}
+
+// The keys that sign for `path` of a contract, as `describe_contract` lists its paths
+fn contract_path_keys(desc: &Descriptor<PublicKey>, path: usize) -> Result<Vec<PublicKey>, BuildError> {
+    let policy = desc.lift().map_err(|e| BuildError::Descriptor(e.to_string()))?;
+    let mut paths = Vec::new();
+    any_of(&policy, &mut paths);
+    // The internal key of our taproot contracts isn't a path
+    let unspendable = XOnlyPublicKey::from(PublicKey::from_str(UNSPENDABLE_KEY).unwrap().inner);
+    paths.retain(|path| !matches!(path, Semantic::Key(key) if XOnlyPublicKey::from(key.inner) == unspendable));
+
+    let path = paths.get(path).ok_or_else(|| BuildError::Descriptor(format!("The contract has no path {path}")))?;
+    let mut keys = Vec::new();
+    path.for_each_key(|key| {
+        keys.push(*key);
+        true
+    });
+    Ok(keys)
+}
+
+// Spends the users2maker contract through the hashlock path, with the `signer` holding the hashlock path
+// keys of as many participants as its threshold. The spend reveals the `preimage` of the contract hash,
+// with which users claim their maker2user contracts if we didn't hand over their payout preimages.
+// The PSBT comes back finalized, ready to extract.
+pub fn build_hashlock_spend(
+    signer: &Wallet<MemoryDatabase>,
+    preimage: [u8; 32],
+    outpoint: OutPoint,
+    prevout: TxOut,
+    destination: Script,
+    fee_rate: FeeRate,
+    tip: u32,
+) -> Result<Psbt, BuildError> {
+    spend_contract(signer, (HASHLOCK_PATH, None), &[preimage], (outpoint, prevout), destination, fee_rate, tip)
+}
+
+// Claims a maker2user contract through the timelock path, with the `signer` holding its timelock key.
+// The input sequence is `csv`, the contract timelock, so the tx is only valid once that many blocks
+// passed since the contract was funded. Below the contract timelock the path isn't satisfied.
+pub fn build_timelock_claim(
+    signer: &Wallet<MemoryDatabase>,
+    csv: u16,
+    outpoint: OutPoint,
+    prevout: TxOut,
+    destination: Script,
+    fee_rate: FeeRate,
+    tip: u32,
+) -> Result<Transaction, BuildError> {
+    let path = (TIMELOCK_PATH, Some(csv));
+    let psbt = spend_contract(signer, path, &[], (outpoint, prevout), destination, fee_rate, tip)?;
+
+    Ok(psbt.extract_tx())
+}
+
+// Sweeps the contract utxo, at its outpoint with its prevout, to `destination` through its `path`, the
+// policy of which sets the input sequence unless we give the `csv` it takes. The locktime comes from the
+// `tip` of our backend. The PSBT comes back finalized, and checked against the script interpreter.
+fn spend_contract(
+    signer: &Wallet<MemoryDatabase>,
+    (path, csv): (usize, Option<u16>),
+    preimages: &[[u8; 32]],
+    (outpoint, prevout): (OutPoint, TxOut),
+    destination: Script,
+    fee_rate: FeeRate,
+    tip: u32,
+) -> Result<Psbt, BuildError> {
+    let build_error = |e: bdk::Error| BuildError::TxBuilder(e.to_string());
+    let local = LocalUtxo {
+        outpoint,
+        txout: prevout.clone(),
+        keychain: KeychainKind::External,
+        is_spent: false
+    };
+    let mut database = MemoryDatabase::new();
+    database.set_utxo(&local).map_err(build_error)?;
+
+    // The tx is built without the private keys, as the wallet policy with a signer for each key of
+    // the path takes time exponential in the number of keys
+    let contract = signer.public_descriptor(KeychainKind::External).map_err(build_error)?
+        .ok_or_else(|| BuildError::Descriptor("The contract wallet has no descriptor".to_string()))?;
+    let mut wallet = Wallet::new(&contract.to_string(), None, Network::Regtest, database)
+        .map_err(|e| BuildError::Descriptor(e.to_string()))?;
+    // The wallet needs the contract spk cached to fill the witness script of the input
+    wallet.ensure_addresses_cached(1).map_err(build_error)?;
+
+    let policy_path = contract_policy_path(&wallet, path)?;
+
+    let mut tx_builder = wallet.build_tx();
+    tx_builder
+        .manually_selected_only()
+        .add_utxo(outpoint).map_err(build_error)?
+        .drain_to(destination)
+        .fee_rate(fee_rate)
+        .policy_path(policy_path, KeychainKind::External);
+    like_wallet_txs(&mut tx_builder, anti_fee_sniping_locktime(&mut thread_rng(), tip));
+
+    let (mut psbt, _) = tx_builder.finish().map_err(build_error)?;
+    if let Some(csv) = csv {
+        psbt.unsigned_tx.input[0].sequence = Sequence::from_height(csv);
+    }
+    psbt.inputs[0].witness_utxo = Some(prevout);
+    // The finalizer takes the preimages from the input, whichever hash each one is locked to
+    for preimage in preimages {
+        psbt.inputs[0].sha256_preimages.insert(sha256::Hash::hash(preimage), preimage.to_vec());
+        psbt.inputs[0].hash160_preimages.insert(hash160::Hash::hash(preimage), preimage.to_vec());
+    }
+
+    for key_signer in signer.get_signers(KeychainKind::External).signers() {
+        wallet.add_signer(KeychainKind::External, SignerOrdering::default(), Arc::clone(key_signer));
+    }
+    let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
+    if !wallet.sign(&mut psbt, sign_ops).map_err(build_error)? {
+        return Err(BuildError::Unsatisfied { path });
+    }
+    // Also with the preimages the path takes, as the interpreter runs the script
+    verify_finalized_input(&psbt, 0).map_err(BuildError::InvalidSpend)?;
+
+    Ok(psbt)
+}
+
+pub fn gen_key_pair(network: Network) -> (PrivateKey, PublicKey) {
+    let secp = Secp256k1::new();
+
+    let key: GeneratedKey<_, Segwitv0> =
+        PrivateKey::generate(PrivateKeyGenerateOptions::default()).unwrap();
+
+    let pubk = key.public_key(&secp);
+    let mut privk = key.into_key();
+    privk.network = network;
+
+    (privk, pubk)
+}
+
+// Random preimage and its hash, which must not be one of the `used` hashes
+pub fn gen_hash<R: Rng + ?Sized>(rng: &mut R, used: Option<&HashSet<sha256::Hash>>) -> ([u8; 32], sha256::Hash) {
+    loop {
+        let mut preimage = [0u8; 32];
+        rng.fill(&mut preimage[..]);
+
+        let hash = sha256::Hash::hash(&preimage);
+        if !used.is_some_and(|used| used.contains(&hash)) {
+            return (preimage, hash);
+        }
+    }
+}
+
+// Random preimage and its payout hash of `kind`
+pub fn gen_payout_hash<R: Rng + ?Sized>(rng: &mut R, kind: HashKind) -> ([u8; 32], PayoutHash) {
+    let mut preimage = [0u8; 32];
+    rng.fill(&mut preimage[..]);
+
+    (preimage, PayoutHash::of(kind, &preimage))
+}
+
+// Seed of a demo wallet, from a new mnemonic each time
+pub fn demo_seed() -> ExtendedPrivKey {
+    let password = Some("watafak".to_string());
+
+    let mnemonic: GeneratedKey<_, Segwitv0> =
+        Mnemonic::generate((WordCount::Words12, Language::English)).unwrap();
+    let mnemonic = mnemonic.into_key();
+
+    let xkey: ExtendedKey = (mnemonic, password).into_extended_key().unwrap();
+    xkey.into_xprv(Network::Regtest).unwrap()
+}
+
+// Receive descriptor of the wallet of `xprv`. Its contract keys come from another branch of it, see
+// `keys::CONTRACT_KEYS_PATH`.
+pub fn get_descriptors(xprv: &ExtendedPrivKey) -> String {
+    let secp = Secp256k1::new();
+
+    let mut keys = Vec::new();
+
+    for path in ["m/84h/1h/0h/0", "m/84h/1h/0h/1"] {
+        let deriv_path = DerivationPath::from_str(path).unwrap();
+        let derived_xprv = &xprv.derive_priv(&secp, &deriv_path).unwrap();
+        let origin: KeySource = (xprv.fingerprint(&secp), deriv_path);
+        let derived_xprv_desc_key: DescriptorKey<Segwitv0> =
+            derived_xprv.into_descriptor_key(Some(origin), DerivationPath::default()).unwrap();
+
+        // Wrap the derived key with the wpkh() string to produce a descriptor string
+        if let Secret(key, _, _) = derived_xprv_desc_key {
+            let mut desc = "wpkh(".to_string();
+            desc.push_str(&key.to_string());
+            desc.push(')');
+            keys.push(desc);
+        }
+    }
+
+    keys[0].clone()
+}
+
+// Noise static key of the demo maker. It's fixed so the demo users can reach the maker without being
+// told its address, a real maker keeps its own secret.
+pub fn demo_maker_key() -> SecretKey {
+    SecretKey::from_slice(&sha256::Hash::hash(b"joinswap demo maker").into_inner()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk::bitcoin::hashes::hex::ToHex;
+    use bdk::bitcoin::secp256k1::ecdsa;
+    use bdk::bitcoin::secp256k1::rand::rngs::StdRng;
+    use bdk::bitcoin::secp256k1::rand::SeedableRng;
+    use bdk::bitcoin::{EcdsaSig, Sequence, TxIn, Txid, WScriptHash, Witness};
+    use bdk::database::AnyDatabase;
+    use bdk::miniscript::interpreter::SatisfiedConstraint;
+    use tokio::io::{duplex, BufReader};
+
+    use super::*;
+    use crate::chain::{BroadcastError, ChainAccess, MemoryChain};
+    use crate::lightning::{HashSource, PayoutRequest};
+    use crate::message::{read_signed_psbt, SignedUpdate};
+    use crate::transport::{FramedReader, Framing};
+
+    fn key(byte: u8) -> PublicKey {
+        PrivateKey::new(SecretKey::from_slice(&[byte; 32]).unwrap(), Network::Regtest).public_key(&Secp256k1::new())
+    }
+
+    // Multisig, timelock and hashlock keys of each participant, the maker's last
+    fn triplets(participants: u8) -> Vec<[PublicKey; 3]> {
+        (0..participants).map(|i| [key(10 * i + 1), key(10 * i + 2), key(10 * i + 3)]).collect()
+    }
+
+    fn spk(desc: Result<Descriptor<PublicKey>, ContractDescError>) -> String {
+        desc.unwrap().script_pubkey().to_hex()
+    }
+
+    // The script_pubkeys peers on earlier versions build for the same keys, so rounds between them
+    // still agree on the contracts
+    #[test]
+    fn contract_scripts_are_pinned() {
+        let payout_sha256 = PayoutHash::Sha256(sha256::Hash::hash(&[7; 32]));
+        let payout_hash160 = PayoutHash::Hash160(hash160::Hash::hash(&[8; 32]));
+        let hash = sha256::Hash::hash(&[9; 32]);
+        let partial = PathThresholds { multisig: 2, timelock: 3, hashlock: 2 };
+        let multisig_keys = [key(1), key(2)];
+
+        assert_eq!(
+            spk(maker2users_contract_desc(&multisig_keys, &key(3), &[key(4), key(5)], payout_sha256, hash, 144)),
+            "002098903265fb3a2190bfe6e1be155e99aa41bdb2d702a6f25546432e981740ad4d",
+        );
+        assert_eq!(
+            spk(maker2users_contract_desc_tr(&multisig_keys, &key(3), &[key(4), key(5)], payout_hash160, hash, 144)),
+            "51200df1e5302defc22bfcd9e0f4395cf0f5d2a6223b50747719f1265785ef2b1776",
+        );
+
+        assert_eq!(
+            spk(users2maker_contract_desc(&triplets(3), hash, PathThresholds::all(3), 288)),
+            "0020d57bc3e9c736cddbe848a7ebc492f8ed7b7d9bf33147f9f7e0d6c53bb3fd168d",
+        );
+        assert_eq!(
+            spk(users2maker_contract_desc(&triplets(3), hash, partial, 288)),
+            "00202846d8de2a39ccdab742d4433bfc929e2a1ae20bc3a0d7d9098189a2a442abed",
+        );
+        assert_eq!(
+            spk(users2maker_contract_desc_tr(&triplets(3), hash, PathThresholds::all(3), 288)),
+            "5120c321a3176400b68126627d01415c619be71b60c067a5d87bfe893e05bfbfe674",
+        );
+        assert_eq!(
+            spk(users2maker_contract_desc_tr(&triplets(3), hash, partial, 288)),
+            "512061b56434167f66531be72c71fc383286f59c0a9b37416567dd24af2c49d395fd",
+        );
+    }
+
+    #[test]
+    fn bad_thresholds() {
+        let hash = sha256::Hash::hash(&[9; 32]);
+        for thresholds in [PathThresholds { multisig: 1, timelock: 3, hashlock: 3 }, PathThresholds::all(4)] {
+            assert!(matches!(
+                users2maker_contract_desc(&triplets(3), hash, thresholds, 288),
+                Err(ContractDescError::BadThreshold { .. }),
+            ));
+        }
+    }
+
+    // Each path of both contracts in plain words, naming as "you" whichever participant's keys we pass
+    #[test]
+    fn contracts_in_plain_words() {
+        let hash = sha256::Hash::hash(&[9; 32]);
+        let keys = triplets(3);
+        let users2maker = users2maker_contract_desc(&keys, hash, PathThresholds::all(3), 48).unwrap();
+        assert_eq!(describe_contract(&users2maker, &keys[0], &keys[2]).unwrap(), [
+            "you + another user + maker",
+            "you + another user + maker after 48 blocks (~8 hours)",
+            "you + another user + maker + preimage",
+        ]);
+        let partial = PathThresholds { multisig: 2, timelock: 3, hashlock: 2 };
+        let users2maker = users2maker_contract_desc_tr(&keys, hash, partial, 48).unwrap();
+        assert_eq!(describe_contract(&users2maker, &keys[1], &keys[2]).unwrap(), [
+            "maker + 1 of (another user, you)",
+            "another user + you + maker after 48 blocks (~8 hours)",
+            "maker + 1 of (another user, you) + preimage",
+        ]);
+
+        // Our multisig and hashlock keys against the maker's multisig and timelock keys
+        let payout_hash = PayoutHash::Sha256(sha256::Hash::hash(&[7; 32]));
+        let (own, maker) = ([key(1), key(4), key(5)], [key(2), key(3)]);
+        let (multisig_keys, hashlock_keys) = ([key(1), key(2)], [key(4), key(5)]);
+        let maker2user =
+            maker2users_contract_desc(&multisig_keys, &key(3), &hashlock_keys, payout_hash, hash, 69).unwrap();
+        let maker2user_tr =
+            maker2users_contract_desc_tr(&multisig_keys, &key(3), &hashlock_keys, payout_hash, hash, 69).unwrap();
+        let lines = ["you + maker", "maker alone after 69 blocks (~11.5 hours)", "you + preimage", "you + preimage"];
+        assert_eq!(describe_contract(&maker2user, &own, &maker).unwrap(), lines);
+        assert_eq!(describe_contract(&maker2user_tr, &own, &maker).unwrap(), lines);
+        // Seen by the maker, the keys swap names
+        assert_eq!(describe_contract(&maker2user, &maker, &own).unwrap(), [
+            "maker + you",
+            "you alone after 69 blocks (~11.5 hours)",
+            "maker + preimage",
+            "maker + preimage",
+        ]);
+    }
+
+    // Users take a maker2user timelock at least the claim window longer than the refund one, and makers
+    // don't start with one that isn't longer
+    #[test]
+    fn timelock_schedules() {
+        let bounds = TimelockBounds::default();
+        for (refund, maker2user) in [(48, 69), (48, 60), (24, 36)] {
+            bounds.check(&Timelocks { refund, maker2user }).unwrap();
+            assert_eq!(Timelocks::from_str(&format!("{refund},{maker2user}")), Ok(Timelocks { refund, maker2user }));
+        }
+
+        let inverted = Timelocks { refund: 69, maker2user: 48 };
+        assert!(bounds.check(&inverted).unwrap_err().contains("leaves less than 12 to claim"));
+        assert!(Timelocks::from_str("69,48").unwrap_err().contains("must be longer"));
+        assert!(Timelocks::from_str("48,48").is_err());
+        // Longer, but with too short a window to claim
+        let short = Timelocks { refund: 48, maker2user: 55 };
+        assert!(bounds.check(&short).unwrap_err().contains("leaves less than 12 to claim"));
+
+        // The second contract's timelock as it comes with its data
+        assert_eq!(validate_timelock_schedule(48, 69, 12), Ok(()));
+        assert_eq!(validate_timelock_schedule(48, 60, 12), Ok(()));
+        assert_eq!(
+            validate_timelock_schedule(69, 48, 12),
+            Err("A maker2user timelock of 48 blocks leaves less than 12 to claim after the refund timelock of 69"
+                .to_string()),
+        );
+        assert!(validate_timelock_schedule(48, 59, 12).is_err());
+        assert_eq!(validate_timelock_schedule(48, 48, 0), Ok(()));
+    }
+
+    // A maker whose template drifted from ours, by the order of the keys or the timelock, sends a checksum
+    // of another descriptor, which names both checksums
+    #[test]
+    fn perturbed_contracts_are_caught() {
+        let hash = sha256::Hash::hash(&[9; 32]);
+        let payout_hash = PayoutHash::Sha256(sha256::Hash::hash(&[7; 32]));
+        let ours = users2maker_contract_desc(&triplets(3), hash, PathThresholds::all(3), 48).unwrap();
+        check_desc_checksum(&ours, &desc_checksum(&ours)).unwrap();
+
+        let mut swapped = triplets(3);
+        swapped.swap(0, 1);
+        let maker2user = |multisig_keys: [PublicKey; 2], timelock| {
+            maker2users_contract_desc(&multisig_keys, &key(3), &[key(4), key(5)], payout_hash, hash, timelock).unwrap()
+        };
+        let perturbed = [
+            (ours.clone(), users2maker_contract_desc(&swapped, hash, PathThresholds::all(3), 48).unwrap()),
+            (ours, users2maker_contract_desc(&triplets(3), hash, PathThresholds::all(3), 49).unwrap()),
+            (maker2user([key(1), key(2)], 69), maker2user([key(2), key(1)], 69)),
+            (maker2user([key(1), key(2)], 69), maker2user([key(1), key(2)], 70)),
+        ];
+        for (ours, theirs) in perturbed {
+            let e = check_desc_checksum(&ours, &desc_checksum(&theirs)).unwrap_err();
+            let (ours, theirs) = (desc_checksum(&ours), desc_checksum(&theirs));
+            assert_ne!(ours, theirs);
+            let message = e.to_string();
+            assert!(message.contains(&ours) && message.contains(&theirs), "{message}");
+            let ContractDescError::ChecksumMismatch { ours: o, theirs: t } = e else { panic!("Unexpected {e}") };
+            assert_eq!((o, t), (ours, theirs));
+        }
+    }
+
+    fn p2wpkh(rng: &mut StdRng) -> Script {
+        let key = PrivateKey::new(SecretKey::new(rng), Network::Regtest).public_key(&Secp256k1::new());
+        Address::p2wpkh(&key, Network::Regtest).unwrap().script_pubkey()
+    }
+
+    // The outpoints a user spends in the funding tx, and its change script if any
+    type Funds = (Vec<OutPoint>, Option<Script>);
+
+    // A funding psbt of `users` users with a few p2wpkh inputs each and maybe a change, paying `fee`
+    fn funding_psbt(rng: &mut StdRng, users: usize, fee: u64) -> (Psbt, Script, Vec<Funds>) {
+        let contract_spk = Script::new_v0_p2wsh(&WScriptHash::from_inner(rng.gen()));
+        let mut tx = Transaction { version: 2, lock_time: PackedLockTime(0), input: vec![], output: vec![] };
+        let mut prevouts = Vec::new();
+        let mut funds = Vec::new();
+
+        for _ in 0..users {
+            let outpoints: Vec<_> = (0..rng.gen_range(1..=3))
+                .map(|_| OutPoint::new(Txid::from_inner(rng.gen()), rng.gen_range(0..4)))
+                .collect();
+            for outpoint in &outpoints {
+                tx.input.push(TxIn { previous_output: *outpoint, script_sig: Script::new(), sequence: Sequence::MAX,
+                                     witness: Witness::new() });
+                prevouts.push(TxOut { value: rng.gen_range(10_000..10_000_000), script_pubkey: p2wpkh(rng) });
+            }
+            let change = rng.gen_bool(0.5).then(|| p2wpkh(rng));
+            if let Some(change) = &change {
+                tx.output.push(TxOut { value: rng.gen_range(1_000..100_000), script_pubkey: change.clone() });
+            }
+            funds.push((outpoints, change));
+        }
+        let total_in: u64 = prevouts.iter().map(|txout| txout.value).sum();
+        let contract_value = total_in - output_value(&tx).unwrap() - fee;
+        tx.output.push(TxOut { value: contract_value, script_pubkey: contract_spk.clone() });
+
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        for (input, prevout) in psbt.inputs.iter_mut().zip(prevouts) {
+            input.witness_utxo = Some(prevout);
+        }
+        (psbt, contract_spk, funds)
+    }
+
+    // The shares add up to the whole funding fee, but for less than a sat per user lost rounding
+    // down, which the refund fee gives up. So what's left over is never negative.
+    #[test]
+    fn funding_fee_split_conserves_the_fee() {
+        let mut rng = StdRng::seed_from_u64(559);
+        for _ in 0..500 {
+            let users = rng.gen_range(1..=6);
+            let fee = match rng.gen_range(0..4) {
+                0 => rng.gen_range(0..users as u64),
+                _ => rng.gen_range(0..100_000),
+            };
+            let (psbt, contract_spk, funds) = funding_psbt(&mut rng, users, fee);
+            let split = FundingFeeSplit::new(&psbt, &contract_spk, psbt_fee(&psbt).unwrap(), users).unwrap();
+
+            let weights: usize = funds.iter().map(|(outpoints, change)| split.user_weight(outpoints, change.as_ref()))
+                .sum();
+            assert_eq!(weights + split.shared_weight, split.weight);
+
+            let shares: Vec<u64> = funds.iter().map(|(outpoints, change)| split.share(outpoints, change.as_ref()))
+                .collect();
+            let left_over = fee.checked_sub(shares.iter().sum()).expect("The shares add up to more than the fee");
+            assert!(left_over < users as u64, "{left_over} sats left over of {fee} for {users} users");
+        }
+    }
+
+    // A user that adds more weight never pays less
+    #[test]
+    fn funding_fee_split_follows_the_weight() {
+        let mut rng = StdRng::seed_from_u64(585);
+        for _ in 0..200 {
+            let fee = rng.gen_range(1_000..100_000);
+            let (psbt, contract_spk, funds) = funding_psbt(&mut rng, 3, fee);
+            let split = FundingFeeSplit::new(&psbt, &contract_spk, psbt_fee(&psbt).unwrap(), 3).unwrap();
+
+            let mut by_weight: Vec<_> = funds.iter()
+                .map(|(outpoints, change)| {
+                    let change = change.as_ref();
+                    (split.user_weight(outpoints, change), split.share(outpoints, change))
+                })
+                .collect();
+            by_weight.sort();
+            assert!(by_weight.windows(2).all(|pair| pair[0].1 <= pair[1].1), "{by_weight:?}");
+        }
+    }
+
+    // Huge fees neither overflow nor charge more than the fee
+    #[test]
+    fn funding_fee_split_of_huge_fees() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let (psbt, contract_spk, funds) = funding_psbt(&mut rng, 4, 0);
+        let split = FundingFeeSplit::new(&psbt, &contract_spk, u64::MAX, 4).unwrap();
+
+        let shares: u128 = funds.iter()
+            .map(|(outpoints, change)| split.share(outpoints, change.as_ref()) as u128)
+            .sum();
+        assert!(shares <= u64::MAX as u128 && u64::MAX as u128 - shares < 4);
+    }
+
+    // A change that can't pay the fee share, or that would be dust, is no change
+    #[test]
+    fn change_never_goes_negative() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let change = Change { contribution: 50_000, address: Address::p2wpkh(&key(1), Network::Regtest).unwrap(),
+                              fold_dust: false };
+        let dust = change.address.script_pubkey().dust_value().to_sat();
+        for _ in 0..1000 {
+            let (value, fee_share): (u64, u64) = (rng.gen_range(0..100_000), rng.gen_range(0..10_000));
+            let expected = value.checked_sub(50_000 + fee_share).filter(|change| *change >= dust);
+            assert_eq!(change.value(value, fee_share), expected);
+        }
+        assert_eq!(change.value(u64::MAX, u64::MAX), None);
+    }
+
+    fn signing_wallet() -> Wallet<MemoryDatabase> {
+        let prv_key = PrivateKey::new(SecretKey::from_slice(&[1; 32]).unwrap(), Network::Regtest);
+        Wallet::new(&format!("wpkh({prv_key})"), None, Network::Regtest, MemoryDatabase::new()).unwrap()
+    }
+
+    // A peer that doesn't read yet doesn't hold back the one after it, and a gone peer only fails its own send
+    #[tokio::test]
+    async fn psbt_sends_are_per_peer() {
+        let (mut psbt, _, _) = funding_psbt(&mut StdRng::seed_from_u64(547), 2, 1_000);
+        let (slow, slow_reader) = duplex(64);
+        let (fast, fast_reader) = duplex(64);
+        let (gone, _) = duplex(64);
+        let mut writers: Vec<_> =
+            [slow, fast, gone].into_iter().map(|stream| FramedWriter::new(stream, Framing::default())).collect();
+        let mut slow_reader = FramedReader::new(BufReader::new(slow_reader), Framing::default());
+        let mut fast_reader = FramedReader::new(BufReader::new(fast_reader), Framing::default());
+
+        let wallet = signing_wallet();
+        let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
+        let sending = sign_and_send_psbt(&mut psbt, &wallet, sign_ops, &mut writers);
+        let reading = async {
+            let fast = read_signed_psbt(&mut fast_reader).await.unwrap();
+            (fast, read_signed_psbt(&mut slow_reader).await.unwrap())
+        };
+        let (sent, read) = timeout(Duration::from_secs(5), async { tokio::join!(sending, reading) }).await.unwrap();
+
+        let sent = sent.unwrap();
+        assert!(sent[0].is_ok() && sent[1].is_ok());
+        assert!(matches!(sent[2], Err(ProtocolError::Io(_))));
+        for update in [read.0, read.1] {
+            assert!(matches!(update, SignedUpdate::Psbt(read) if read.unsigned_tx == psbt.unsigned_tx));
+        }
+    }
+
+    // A sighash the wallet won't sign with fails as an error, before anything is sent
+    #[tokio::test]
+    async fn unsignable_psbt_is_an_error() {
+        let (mut psbt, _, _) = funding_psbt(&mut StdRng::seed_from_u64(547), 2, 1_000);
+        psbt.inputs[0].sighash_type = Some(EcdsaSighashType::None.into());
+        let (writer, _reader) = duplex(64);
+        let mut writers = [FramedWriter::new(writer, Framing::default())];
+
+        let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
+        assert!(matches!(
+            sign_and_send_psbt(&mut psbt, &signing_wallet(), sign_ops, &mut writers).await,
+            Err(ProtocolError::Psbt(PsbtReadError::Unsignable(e))) if e.contains("sighash"),
+        ));
+    }
+
+    fn prv_key(byte: u8) -> PrivateKey {
+        PrivateKey::new(SecretKey::from_slice(&[byte; 32]).unwrap(), Network::Regtest)
+    }
+
+    // The private keys of `triplets`
+    fn prv_triplets(participants: u8) -> Vec<[PrivateKey; 3]> {
+        (0..participants).map(|i| [prv_key(10 * i + 1), prv_key(10 * i + 2), prv_key(10 * i + 3)]).collect()
+    }
+
+    // The funding output of a contract
+    fn contract_utxo(desc: &Descriptor<PublicKey>, value: u64) -> (OutPoint, TxOut) {
+        let outpoint = OutPoint { txid: Txid::from_inner([7; 32]), vout: 0 };
+        (outpoint, TxOut { value, script_pubkey: desc.script_pubkey() })
+    }
+
+    // The contract wallet of `desc` signing with `prv_keys`
+    fn signer(desc: &Descriptor<PublicKey>, prv_keys: &[PrivateKey]) -> Wallet<MemoryDatabase> {
+        let mut signer = contract_wallet(desc).unwrap();
+        add_contract_signers(&mut signer, desc, prv_keys);
+        signer
+    }
+
+    // What the witness of the only input of `tx`, spending `prevout`, satisfies
+    fn satisfied(tx: &Transaction, prevout: &TxOut) -> Vec<SatisfiedConstraint> {
+        let txin = &tx.input[0];
+        let interpreter = Interpreter::from_txdata(
+            &prevout.script_pubkey,
+            &txin.script_sig,
+            &txin.witness,
+            txin.sequence,
+            tx.lock_time.into(),
+        ).unwrap();
+        let prevouts = [prevout.clone()];
+        let secp = Secp256k1::verification_only();
+
+        interpreter.iter(&secp, tx, 0, &Prevouts::All(&prevouts)).collect::<Result<_, _>>().unwrap()
+    }
+
+    // The multisig path takes a signature of each of its keys, and neither a preimage nor the timelock
+    #[test]
+    fn cooperative_sweep_takes_the_multisig_path() {
+        let hash = sha256::Hash::hash(&[9; 32]);
+        let prv_keys = prv_triplets(3);
+        let multisig_keys: Vec<_> = prv_keys.iter().map(|[multisig, ..]| *multisig).collect();
+        let destination = Address::p2wpkh(&key(200), Network::Regtest).unwrap().script_pubkey();
+
+        for contract in [users2maker_contract_desc, users2maker_contract_desc_tr] {
+            let desc = contract(&triplets(3), hash, PathThresholds::all(3), 288).unwrap();
+            let (outpoint, prevout) = contract_utxo(&desc, 100_000);
+            let fee_rate = FeeRate::from_sat_per_vb(2.0);
+            let sweep = build_cooperative_sweep(
+                &signer(&desc, &multisig_keys), outpoint, prevout.clone(), destination.clone(), fee_rate, 1_000,
+            ).unwrap();
+
+            assert_eq!(sweep.output.len(), 1);
+            assert_eq!(sweep.output[0].script_pubkey, destination);
+            assert!(!sweep.input[0].sequence.is_height_locked());
+            let satisfied = satisfied(&sweep, &prevout);
+            assert_eq!(satisfied.len(), 3);
+            assert!(satisfied.iter().all(|constraint| matches!(constraint, SatisfiedConstraint::PublicKey { .. })));
+        }
+    }
+
+    // An unsigned spend of the contract `desc` funds with `prevout`
+    fn contract_spend(desc: &Descriptor<PublicKey>, (outpoint, prevout): (OutPoint, TxOut)) -> Psbt {
+        let destination = Address::p2wpkh(&key(200), Network::Regtest).unwrap().script_pubkey();
+        let tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn { previous_output: outpoint, ..Default::default() }],
+            output: vec![TxOut { value: prevout.value - 1_000, script_pubkey: destination }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(prevout);
+        psbt.inputs[0].witness_script = Some(desc.explicit_script().unwrap());
+        psbt
+    }
+
+    // The partial sigs of `wallet` on `psbt`
+    fn partial_sigs(wallet: &Wallet<MemoryDatabase>, mut psbt: Psbt) -> BTreeMap<PublicKey, EcdsaSig> {
+        let sign_ops = SignOptions { trust_witness_utxo: true, try_finalize: false, ..Default::default() };
+        wallet.sign(&mut psbt, sign_ops).unwrap();
+        psbt.inputs.remove(0).partial_sigs
+    }
+
+    // The wallet we used to sign with: `desc` written out with the private keys in place of their public
+    // ones
+    fn substituted(desc: &str, prv_keys: &[PrivateKey]) -> Wallet<MemoryDatabase> {
+        let secp = Secp256k1::new();
+        let (desc, _) = desc.split_once('#').unwrap_or((desc, ""));
+        let prv_desc = prv_keys.iter().fold(desc.to_string(), |desc, prv_key| {
+            desc.replace(&prv_key.public_key(&secp).to_string(), &prv_key.to_string())
+        });
+        Wallet::new(&prv_desc, None, Network::Regtest, MemoryDatabase::new()).unwrap()
+    }
+
+    // Registered signers sign as the private descriptor did, and still do when the descriptor writes its
+    // keys in another case, where substituting the keys silently left a wallet that can't sign
+    #[test]
+    fn registered_signers_sign_like_a_private_descriptor() {
+        let hash = sha256::Hash::hash(&[9; 32]);
+        let desc = users2maker_contract_desc(&triplets(3), hash, PathThresholds::all(3), 288).unwrap();
+        let multisig_keys: Vec<_> = prv_triplets(3).iter().map(|[multisig, ..]| *multisig).collect();
+        let psbt = contract_spend(&desc, contract_utxo(&desc, 100_000));
+
+        let registered = partial_sigs(&signer(&desc, &multisig_keys), psbt.clone());
+        assert_eq!(registered.len(), 3);
+        assert_eq!(registered, partial_sigs(&substituted(&desc.to_string(), &multisig_keys), psbt.clone()));
+
+        let written = triplets(3).concat().iter().fold(desc.to_string(), |desc, key| {
+            desc.replace(&key.to_string(), &key.to_string().to_uppercase())
+        });
+        assert_ne!(written, desc.to_string());
+        assert!(partial_sigs(&substituted(&written, &multisig_keys), psbt.clone()).is_empty());
+        let parsed = Descriptor::<PublicKey>::from_str(written.split_once('#').unwrap().0).unwrap();
+        assert_eq!(partial_sigs(&signer(&parsed, &multisig_keys), psbt), registered);
+    }
+
+    // The signature a contract wallet puts on a wsh multisig input verifies, and each way of spoiling it is caught
+    #[test]
+    fn partial_sigs_of_a_wsh_multisig_input() {
+        let hash = sha256::Hash::hash(&[9; 32]);
+        let desc = users2maker_contract_desc(&triplets(3), hash, PathThresholds::all(3), 288).unwrap();
+        let keys: Vec<_> = triplets(3).iter().map(|[multisig, ..]| *multisig).collect();
+        let [multisig, ..] = prv_triplets(3)[0];
+        let mut psbt = contract_spend(&desc, contract_utxo(&desc, 100_000));
+        psbt.inputs[0].partial_sigs = partial_sigs(&signer(&desc, &[multisig]), psbt.clone());
+        let status = |psbt: &Psbt, key: PublicKey| verify_partial_sigs(psbt, 0, &[key]).unwrap()[0].1;
+
+        let statuses = verify_partial_sigs(&psbt, 0, &keys).unwrap();
+        let expected = [SigStatus::Valid, SigStatus::Missing, SigStatus::Missing];
+        assert_eq!(statuses, keys.iter().copied().zip(expected).collect::<Vec<_>>());
+
+        // A byte of the signature flipped
+        let mut flipped = psbt.clone();
+        let sig = flipped.inputs[0].partial_sigs.get_mut(&keys[0]).unwrap();
+        let mut compact = sig.sig.serialize_compact();
+        compact[40] ^= 1;
+        sig.sig = ecdsa::Signature::from_compact(&compact).unwrap();
+        assert_eq!(status(&flipped, keys[0]), SigStatus::Invalid);
+
+        // Filed under another key
+        let mut misfiled = psbt.clone();
+        let sig = misfiled.inputs[0].partial_sigs.remove(&keys[0]).unwrap();
+        misfiled.inputs[0].partial_sigs.insert(keys[1], sig);
+        assert_eq!(status(&misfiled, keys[1]), SigStatus::Invalid);
+
+        // Signed for another tx
+        let mut other = psbt.clone();
+        other.unsigned_tx.output[0].value -= 1;
+        assert_eq!(status(&other, keys[0]), SigStatus::Invalid);
+
+        // Signed with a sighash other than ALL, by the same wallet. ALL|ANYONECANPAY is fine on an input that
+        // asks for it, and only there
+        for sighash in [EcdsaSighashType::AllPlusAnyoneCanPay, EcdsaSighashType::None, EcdsaSighashType::Single] {
+            let mut other_sighash = contract_spend(&desc, contract_utxo(&desc, 100_000));
+            other_sighash.inputs[0].sighash_type = Some(sighash.into());
+            let sign_ops = SignOptions { trust_witness_utxo: true, try_finalize: false, allow_all_sighashes: true,
+                                         ..Default::default() };
+            signer(&desc, &[multisig]).sign(&mut other_sighash, sign_ops).unwrap();
+            if sighash == EcdsaSighashType::AllPlusAnyoneCanPay {
+                assert_eq!(status(&other_sighash, keys[0]), SigStatus::Valid);
+            }
+            other_sighash.inputs[0].sighash_type = None;
+            assert_eq!(status(&other_sighash, keys[0]), SigStatus::NonStandardSighash(sighash.into()));
+        }
+
+        // Inputs we can't check at all
+        let mut no_script = psbt.clone();
+        no_script.inputs[0].witness_script = None;
+        assert_eq!(verify_partial_sigs(&no_script, 0, &keys), Err(SigVerifyError::MissingWitnessScript(0)));
+        let mut other_script = psbt.clone();
+        other_script.inputs[0].witness_script = Some(Script::new());
+        assert_eq!(verify_partial_sigs(&other_script, 0, &keys), Err(SigVerifyError::WitnessScriptMismatch(0)));
+        let mut no_prevout = psbt.clone();
+        no_prevout.inputs[0].witness_utxo = None;
+        assert_eq!(verify_partial_sigs(&no_prevout, 0, &keys), Err(SigVerifyError::MissingPrevout(0)));
+        assert_eq!(verify_partial_sigs(&psbt, 1, &keys), Err(SigVerifyError::NoSuchInput(1)));
+    }
+
+    #[test]
+    fn cooperative_sweep_needs_every_multisig_key() {
+        let hash = sha256::Hash::hash(&[9; 32]);
+        let desc = users2maker_contract_desc(&triplets(3), hash, PathThresholds::all(3), 288).unwrap();
+        let prv_keys = prv_triplets(3);
+        // A hashlock key where a multisig one should be
+        let keys = [prv_keys[0][0], prv_keys[1][0], prv_keys[2][2]];
+        let (outpoint, prevout) = contract_utxo(&desc, 100_000);
+        let destination = Address::p2wpkh(&key(200), Network::Regtest).unwrap().script_pubkey();
+        let fee_rate = FeeRate::default_min_relay_fee();
+
+        assert_eq!(
+            build_cooperative_sweep(&signer(&desc, &keys), outpoint, prevout, destination, fee_rate, 1_000),
+            Err(BuildError::Unsatisfied { path: MULTISIG_PATH }),
+        );
+    }
+
+    // The hashlock path takes the hashlock key of each participant and the preimage of the contract hash
+    #[test]
+    fn hashlock_spend_reveals_the_preimage() {
+        let preimage = [9; 32];
+        let hash = sha256::Hash::hash(&preimage);
+        let hashlock_keys: Vec<_> = prv_triplets(3).iter().map(|[.., hashlock]| *hashlock).collect();
+        let destination = Address::p2wpkh(&key(200), Network::Regtest).unwrap().script_pubkey();
+        let fee_rate = FeeRate::from_sat_per_vb(2.0);
+
+        for contract in [users2maker_contract_desc, users2maker_contract_desc_tr] {
+            let desc = contract(&triplets(3), hash, PathThresholds::all(3), 288).unwrap();
+            let (outpoint, prevout) = contract_utxo(&desc, 100_000);
+            let signer = signer(&desc, &hashlock_keys);
+            let psbt = build_hashlock_spend(
+                &signer, preimage, outpoint, prevout.clone(), destination.clone(), fee_rate, 1_000,
+            ).unwrap();
+            verify_finalized_input(&psbt, 0).unwrap();
+
+            let satisfied = satisfied(&psbt.extract_tx(), &prevout);
+            let signatures = satisfied.iter()
+                .filter(|constraint| matches!(constraint, SatisfiedConstraint::PublicKey { .. }));
+            assert_eq!(signatures.count(), 3);
+            assert!(satisfied.iter().any(|constraint| {
+                matches!(constraint, SatisfiedConstraint::HashLock { preimage: revealed, .. } if *revealed == preimage)
+            }));
+
+            // Without the preimage of the contract hash the path isn't satisfied
+            assert_eq!(
+                build_hashlock_spend(&signer, [8; 32], outpoint, prevout, destination.clone(), fee_rate, 1_000),
+                Err(BuildError::Unsatisfied { path: HASHLOCK_PATH }),
+            );
+        }
+    }
+
+    // A maker2user contract with keys 1 to 5, the timelock key being 3
+    fn maker2user(taproot: bool, timelock: u16) -> Descriptor<PublicKey> {
+        let contract = match taproot {
+            true => maker2users_contract_desc_tr,
+            false => maker2users_contract_desc,
+        };
+        let payout_hash = PayoutHash::of(HashKind::Sha256, &[7; 32]);
+        contract(&[key(1), key(2)], &key(3), &[key(4), key(5)], payout_hash, sha256::Hash::hash(&[9; 32]), timelock)
+            .unwrap()
+    }
+
+    // Below the contract timelock the claim can't be finalized, at it the witness satisfies the timelock path
+    #[test]
+    fn timelock_claim_needs_the_contract_timelock() {
+        let destination = Address::p2wpkh(&key(200), Network::Regtest).unwrap().script_pubkey();
+        let fee_rate = FeeRate::from_sat_per_vb(2.0);
+
+        for taproot in [false, true] {
+            let desc = maker2user(taproot, 144);
+            let (outpoint, prevout) = contract_utxo(&desc, 100_000);
+            let signer = signer(&desc, &[prv_key(3)]);
+
+            assert_eq!(
+                build_timelock_claim(&signer, 143, outpoint, prevout.clone(), destination.clone(), fee_rate, 1_000),
+                Err(BuildError::Unsatisfied { path: TIMELOCK_PATH }),
+            );
+            let claim =
+                build_timelock_claim(&signer, 144, outpoint, prevout.clone(), destination.clone(), fee_rate, 1_000)
+                    .unwrap();
+            assert_eq!(claim.input[0].sequence, Sequence::from_height(144));
+            let satisfied = satisfied(&claim, &prevout);
+            assert!(satisfied.contains(&SatisfiedConstraint::RelativeTimelock { n: Sequence::from_height(144) }));
+            assert!(!satisfied.iter().any(|constraint| matches!(constraint, SatisfiedConstraint::HashLock { .. })));
+        }
+    }
+
+    // The claim only gets in a block once the contract funding has as many confirmations as its timelock
+    #[test]
+    fn timelock_claim_is_valid_once_mined_past_the_timelock() {
+        let chain = MemoryChain::default();
+        let desc = maker2user(false, 10);
+        let funding = chain.fund(&desc.script_pubkey(), &[100_000]);
+        let outpoint = OutPoint { txid: funding, vout: 0 };
+        let prevout = TxOut { value: 100_000, script_pubkey: desc.script_pubkey() };
+        let destination = Address::p2wpkh(&key(200), Network::Regtest).unwrap().script_pubkey();
+        let tip = chain.tip_height().unwrap();
+        let claim = build_timelock_claim(
+            &signer(&desc, &[prv_key(3)]), 10, outpoint, prevout, destination, FeeRate::from_sat_per_vb(2.0), tip,
+        ).unwrap();
+
+        chain.mine(8);
+        assert_eq!(chain.confirmations(&funding, &desc.script_pubkey()).unwrap(), 9);
+        assert!(matches!(chain.broadcast(&claim), Err(BroadcastError::Rejected(_))));
+        assert_eq!(chain.get_tx(&claim.txid()).unwrap(), None);
+
+        chain.mine(1);
+        chain.broadcast(&claim).unwrap();
+        assert_eq!(chain.confirmations(&claim.txid(), &Script::new()).unwrap(), 1);
+    }
+
+    // A wallet of the seed made of `seed` bytes, holding `values` in confirmed utxos
+    fn funded_wallet(chain: &MemoryChain, seed: u8, values: &[u64]) -> Wallet<AnyDatabase> {
+        let xprv = ExtendedPrivKey::new_master(Network::Regtest, &[seed; 32]).unwrap();
+        let database = AnyDatabase::Memory(MemoryDatabase::new());
+        let wallet = Wallet::new(&get_descriptors(&xprv), None, Network::Regtest, database).unwrap();
+        chain.fund(&wallet.get_address(AddressIndex::New).unwrap().script_pubkey(), values);
+        chain.sync_wallet(&wallet).unwrap();
+
+        wallet
+    }
+
+    fn selected_values(utxos: &[LocalUtxo]) -> Vec<u64> {
+        let mut values: Vec<_> = utxos.iter().map(|utxo| utxo.txout.value).collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn largest_first_selection() {
+        let chain = MemoryChain::default();
+        let wallet = funded_wallet(&chain, 1, &[10_000, 40_000, 25_000, 70_000]);
+        let change = wallet.get_address(AddressIndex::New).unwrap().script_pubkey();
+        let fee_rate = FeeRate::from_sat_per_vb(1.0);
+        let select =
+            |contribution| select_utxos(&wallet, contribution, fee_rate, &change, false, CoinSelection::LargestFirst);
+
+        assert_eq!(selected_values(&select(50_000).unwrap()), [70_000]);
+        assert_eq!(selected_values(&select(90_000).unwrap()), [40_000, 70_000]);
+        // Paying the fees too takes the next largest one
+        assert_eq!(selected_values(&select(110_000).unwrap()), [25_000, 40_000, 70_000]);
+        assert_eq!(select(145_000), Err(UtxoError::ContributionTooLarge { contribution: 145_000, value: 145_000 }));
+    }
+
+    // Branch and bound finds the utxos that pay the contribution and their fees without change, where
+    // largest first would take the largest one and pay change. Only if we fold the dust, else we bring
+    // enough for a change.
+    #[test]
+    fn branch_and_bound_selection_for_a_target_amount() {
+        let chain = MemoryChain::default();
+        let wallet = funded_wallet(&chain, 1, &[10_000, 40_000, 25_000, 70_000]);
+        let change = wallet.get_address(AddressIndex::New).unwrap().script_pubkey();
+        let fee_rate = FeeRate::from_sat_per_vb(1.0);
+        let select = |contribution, algorithm| select_utxos(&wallet, contribution, fee_rate, &change, true, algorithm);
+
+        // What two utxos add to the funding tx, and its shared parts, at 1 sat/vB
+        let fees = fee_rate.fee_wu(FUNDING_BASE_WEIGHT + 2 * (TXIN_BASE_WEIGHT + P2WPKH_SATISFACTION_WEIGHT));
+        let contribution = 35_000 - fees;
+        assert_eq!(selected_values(&select(contribution, CoinSelection::BranchAndBound).unwrap()), [10_000, 25_000]);
+        assert_eq!(selected_values(&select(contribution, CoinSelection::LargestFirst).unwrap()), [70_000]);
+    }
+
+    // The user sweeps its maker2user contract with its multisig key and the one the maker handed over,
+    // without the timelock or a preimage
+    #[test]
+    fn multisig_sweep_with_handed_over_keys() {
+        let destination = Address::p2wpkh(&key(200), Network::Regtest).unwrap().script_pubkey();
+        let fee_rate = FeeRate::from_sat_per_vb(2.0);
+
+        for taproot in [false, true] {
+            let desc = maker2user(taproot, 144);
+            let (outpoint, prevout) = contract_utxo(&desc, 100_000);
+            let sweep = build_multisig_sweep(
+                &desc, &[prv_key(1)], &[prv_key(2)], (outpoint, prevout.clone()), destination.clone(), fee_rate, 1_000,
+            ).unwrap();
+
+            assert!(!sweep.input[0].sequence.is_height_locked());
+            let satisfied = satisfied(&sweep, &prevout);
+            assert_eq!(satisfied.len(), 2);
+            assert!(satisfied.iter().all(|constraint| matches!(constraint, SatisfiedConstraint::PublicKey { .. })));
+
+            // Without the maker's key we can't, however many other keys of the contract we hold
+            let (mine, others) = ([prv_key(1)], [prv_key(3), prv_key(4)]);
+            assert_eq!(
+                build_multisig_sweep(&desc, &mine, &others, (outpoint, prevout), destination.clone(), fee_rate, 1_000),
+                Err(BuildError::MissingKey { path: MULTISIG_PATH, key: key(2) }),
+            );
+        }
+    }
+
+    #[test]
+    fn handed_over_keys_must_match() {
+        let secp = Secp256k1::new();
+        let expected: Vec<_> = [1, 2, 3].map(|byte| prv_key(byte).public_key(&secp)).to_vec();
+        let mismatch = |index, byte, kind| Err(KeyMismatch {
+            index,
+            derived: prv_key(byte).public_key(&secp),
+            expected: expected.clone(),
+            kind,
+        });
+
+        assert_eq!(check_prv_keys(&[prv_key(3), prv_key(1)], expected.clone(), Network::Regtest), Ok(()));
+        assert_eq!(check_prv_keys(&[], expected.clone(), Network::Regtest), Ok(()));
+        // Any test network takes the same keys
+        assert_eq!(check_prv_keys(&[prv_key(2)], expected.clone(), Network::Testnet), Ok(()));
+
+        let wrong = [prv_key(1), prv_key(4)];
+        assert_eq!(check_prv_keys(&wrong, expected.clone(), Network::Regtest), mismatch(1, 4, MismatchKind::Unknown));
+        let twice = [prv_key(2), prv_key(1), prv_key(2)];
+        let duplicate = mismatch(2, 2, MismatchKind::Duplicate);
+        assert_eq!(check_prv_keys(&twice, expected.clone(), Network::Regtest), duplicate);
+
+        let mainnet = PrivateKey { network: Network::Bitcoin, ..prv_key(3) };
+        assert_eq!(
+            check_prv_keys(&[prv_key(1), mainnet], expected.clone(), Network::Regtest),
+            mismatch(1, 3, MismatchKind::WrongNetwork(Network::Bitcoin)),
+        );
+        assert_eq!(
+            check_prv_keys(&[prv_key(1)], expected.clone(), Network::Bitcoin),
+            mismatch(0, 1, MismatchKind::WrongNetwork(Network::Regtest)),
+        );
+        let message = check_prv_keys(&[prv_key(4)], expected.clone(), Network::Regtest).unwrap_err().to_string();
+        let derived = prv_key(4).public_key(&secp);
+        assert!(message.starts_with(&format!("Private key 0 (for {derived}) is not one of {}", expected[0])));
+    }
+
+    // What a user brings to the funding tx, a p2wpkh utxo of `value` with its previous tx
+    fn user_funds(byte: u8, value: u64) -> UserFunds {
+        let script_pubkey = Address::p2wpkh(&key(byte), Network::Regtest).unwrap().script_pubkey();
+        let txout = TxOut { value, script_pubkey };
+        let prev_tx = Transaction { version: 2, lock_time: PackedLockTime::ZERO, input: vec![TxIn::default()],
+                                    output: vec![txout.clone()] };
+        let outpoint = OutPoint { txid: prev_tx.txid(), vout: 0 };
+        let psbt_input =
+            psbt::Input { witness_utxo: Some(txout), non_witness_utxo: Some(prev_tx), ..Default::default() };
+        let utxo = Utxo::Foreign { outpoint, psbt_input: Box::new(psbt_input) };
+
+        UserFunds { utxos: vec![WeightedUtxo { satisfaction_weight: P2WPKH_SATISFACTION_WEIGHT, utxo }], change: None }
+    }
+
+    #[test]
+    fn funding_and_refund_inputs_are_checked() {
+        let desc = users2maker_contract_desc(&triplets(3), sha256::Hash::hash(&[9; 32]), PathThresholds::all(3), 288)
+            .unwrap();
+        let refund_to: Vec<_> = [200, 201].map(|byte| Address::p2wpkh(&key(byte), Network::Regtest).unwrap()).into();
+        let fee_rates = FeeRates { funding: 2.0, refund: 2.0 };
+        let build = |users, refund_to| build_funding_and_refund(&desc, users, refund_to, None, fee_rates, 1_000);
+
+        let (funding, refunds) = build(vec![user_funds(100, 50_000), user_funds(101, 60_000)], refund_to.clone())
+            .unwrap();
+        assert_eq!(funding.unsigned_tx.input.len(), 2);
+        assert_eq!(refunds[0].unsigned_tx.output.len(), 2);
+
+        assert_eq!(build(vec![], vec![]), Err(BuildError::NoUtxos));
+        let no_utxos = UserFunds { utxos: vec![], change: None };
+        assert_eq!(build(vec![user_funds(100, 50_000), no_utxos], refund_to.clone()), Err(BuildError::NoUtxos));
+
+        let one_address = refund_to[..1].to_vec();
+        assert_eq!(
+            build(vec![user_funds(100, 50_000), user_funds(101, 60_000)], one_address),
+            Err(BuildError::CountMismatch { users: 2, addresses: 1 }),
+        );
+
+        // Worth less than its share of the funding fee
+        let Err(BuildError::InsufficientValue { index: 1, value: 100, fee_share }) =
+            build(vec![user_funds(100, 50_000), user_funds(101, 100)], refund_to)
+        else {
+            panic!("Expected the second user to be short of its fee share");
+        };
+        assert!(fee_share > 100);
+    }
+
+    // An input added to a funding tx signed with ALL|ANYONECANPAY leaves the signatures valid, goes whole to
+    // the fee and moves the refund to the new txid, and any other change to the tx is caught
+    #[test]
+    fn added_funding_inputs_are_checked() {
+        let desc = users2maker_contract_desc(&triplets(3), sha256::Hash::hash(&[9; 32]), PathThresholds::all(3), 288)
+            .unwrap();
+        let refund_to: Vec<_> = [200, 201].map(|byte| Address::p2wpkh(&key(byte), Network::Regtest).unwrap()).into();
+        let fee_rates = FeeRates { funding: 2.0, refund: 2.0 };
+        let users = vec![user_funds(100, 50_000), user_funds(101, 60_000)];
+        let (mut funding, refunds) = build_funding_and_refund(&desc, users, refund_to, None, fee_rates, 1_000).unwrap();
+
+        let outpoints: Vec<_> = funding.unsigned_tx.input.iter().map(|txin| txin.previous_output).collect();
+        set_anyone_can_pay(&mut funding, &outpoints);
+        assert!(funding.inputs.iter().all(is_anyone_can_pay));
+        let signer = Wallet::new(&format!("wpkh({})", prv_key(100)), None, Network::Regtest, MemoryDatabase::new())
+            .unwrap();
+        let sign_ops = SignOptions { try_finalize: false, allow_all_sighashes: true, ..Default::default() };
+        let mut signed = funding.clone();
+        signer.sign(&mut signed, sign_ops).unwrap();
+        let signed_index = signed.inputs.iter().position(|input| !input.partial_sigs.is_empty()).unwrap();
+        let valid = vec![(key(100), SigStatus::Valid)];
+        assert_eq!(verify_partial_sigs(&signed, signed_index, &[key(100)]).unwrap(), valid);
+
+        let Utxo::Foreign { outpoint, psbt_input } = user_funds(102, 30_000).utxos.remove(0).utxo else { panic!() };
+        let mut updated = signed.clone();
+        add_funding_input(&mut updated, outpoint, *psbt_input.clone()).unwrap();
+        assert_eq!(check_added_inputs(&funding, &updated), Ok(vec![(outpoint, 30_000)]));
+        assert_eq!(verify_partial_sigs(&updated, signed_index, &[key(100)]).unwrap(), valid);
+        assert!(is_anyone_can_pay(&updated.inputs[2]));
+        assert_eq!(
+            add_funding_input(&mut updated.clone(), outpoint, *psbt_input.clone()),
+            Err(AddInputError::DuplicateInput(outpoint)),
+        );
+
+        let rebased = rebase_refund(&refunds[0], &updated.unsigned_tx);
+        let before = refunds[0].unsigned_tx.input[0].previous_output;
+        let after = rebased.unsigned_tx.input[0].previous_output;
+        assert_eq!((after.txid, after.vout), (updated.unsigned_tx.txid(), before.vout));
+        assert_eq!(rebased.unsigned_tx.output, refunds[0].unsigned_tx.output);
+
+        // The added value taken from the contract output, or any other change
+        let mut outputs_changed = updated.clone();
+        outputs_changed.unsigned_tx.output.iter_mut().for_each(|txout| txout.value += 10_000);
+        assert_eq!(check_added_inputs(&funding, &outputs_changed), Err(AddInputError::OutputsChanged));
+        assert_eq!(check_added_inputs(&funding, &funding), Err(AddInputError::NoAddedInput));
+        let mut inputs_changed = updated.clone();
+        inputs_changed.unsigned_tx.input.swap(0, 1);
+        assert_eq!(check_added_inputs(&funding, &inputs_changed), Err(AddInputError::InputsChanged));
+        let mut twice = updated.clone();
+        twice.unsigned_tx.input.push(twice.unsigned_tx.input[2].clone());
+        twice.inputs.push(twice.inputs[2].clone());
+        assert_eq!(check_added_inputs(&funding, &twice), Err(AddInputError::DuplicateInput(outpoint)));
+        let mut no_prev_tx = updated.clone();
+        no_prev_tx.inputs[2].non_witness_utxo = None;
+        assert_eq!(check_added_inputs(&funding, &no_prev_tx), Err(AddInputError::UnknownValue(outpoint)));
+        let mut other_value = updated;
+        other_value.inputs[2].witness_utxo.as_mut().unwrap().value += 1;
+        assert_eq!(check_added_inputs(&funding, &other_value), Err(AddInputError::UnknownValue(outpoint)));
+    }
+
+    // Txs we build at a tip are locked to it, or now and then to one of the `MAX_LOCKTIME_OFFSET` blocks
+    // before it, and peers whose tip is up to `TIP_TOLERANCE` blocks off ours take them
+    #[test]
+    fn locktimes_land_near_the_tip() {
+        const TIP: u32 = 800_000;
+        let window = TIP - MAX_LOCKTIME_OFFSET..=TIP;
+        let mut rng = StdRng::seed_from_u64(581);
+        let lock_times: Vec<_> = (0..1000).map(|_| PackedLockTime::from(anti_fee_sniping_locktime(&mut rng, TIP)))
+            .collect();
+        assert!(lock_times.iter().all(|lock_time| window.contains(&lock_time.0)));
+        let behind = lock_times.iter().filter(|lock_time| lock_time.0 < TIP).count();
+        assert!((50..150).contains(&behind), "{behind}");
+        for tip in [TIP - TIP_TOLERANCE, TIP, TIP + TIP_TOLERANCE] {
+            assert!(lock_times.iter().all(|lock_time| locktime_near_tip(*lock_time, tip)));
+        }
+        assert!(!locktime_near_tip(PackedLockTime(TIP + TIP_TOLERANCE + 1), TIP));
+        assert!(!locktime_near_tip(PackedLockTime(TIP - MAX_LOCKTIME_OFFSET - TIP_TOLERANCE - 1), TIP));
+
+        // The funding, refund and sweep txs we build at that tip
+        let desc = users2maker_contract_desc(&triplets(3), sha256::Hash::hash(&[9; 32]), PathThresholds::all(3), 288)
+            .unwrap();
+        let refund_to: Vec<_> = [200, 201].map(|byte| Address::p2wpkh(&key(byte), Network::Regtest).unwrap()).into();
+        let users = vec![user_funds(100, 50_000), user_funds(101, 60_000)];
+        let fee_rates = FeeRates { funding: 2.0, refund: 2.0 };
+        let (funding, refunds) = build_funding_and_refund(&desc, users, refund_to, None, fee_rates, TIP).unwrap();
+        let multisig_keys: Vec<_> = prv_triplets(3).iter().map(|[multisig, ..]| *multisig).collect();
+        let (outpoint, prevout) = contract_utxo(&desc, 100_000);
+        let destination = Address::p2wpkh(&key(200), Network::Regtest).unwrap().script_pubkey();
+        let fee_rate = FeeRate::from_sat_per_vb(2.0);
+        let sweep =
+            build_cooperative_sweep(&signer(&desc, &multisig_keys), outpoint, prevout, destination, fee_rate, TIP)
+                .unwrap();
+
+        let txs = [&funding.unsigned_tx, &sweep].into_iter().chain(refunds.iter().map(|refund| &refund.unsigned_tx));
+        for tx in txs {
+            assert_eq!(tx.version, 2);
+            assert!(window.contains(&tx.lock_time.0), "{}", tx.lock_time.0);
+        }
+    }
+
+    // The rng drawing a used hash again, we draw another one
+    #[test]
+    fn used_hashes_are_drawn_again() {
+        let (preimage, hash) = gen_hash(&mut StdRng::seed_from_u64(525), None);
+        assert_eq!(sha256::Hash::hash(&preimage), hash);
+
+        let used = HashSet::from([hash]);
+        let (fresh_preimage, fresh) = gen_hash(&mut StdRng::seed_from_u64(525), Some(&used));
+        assert_ne!(fresh, hash);
+        assert_eq!(sha256::Hash::hash(&fresh_preimage), fresh);
+        // The one after the collision, as the same rng draws it
+        let mut rng = StdRng::seed_from_u64(525);
+        gen_hash(&mut rng, None);
+        assert_eq!(gen_hash(&mut rng, None), (fresh_preimage, fresh));
+    }
+
+    #[test]
+    fn handed_over_wif_keys() {
+        // WIF keys of every test network read back as testnet ones
+        let key = PrivateKey { network: Network::Testnet, ..prv_key(1) };
+        assert_eq!(parse_prv_key(&prv_key(1).to_wif(), Network::Regtest), Ok(key));
+        assert_eq!(parse_prv_key(&key.to_wif(), Network::Signet), Ok(key));
+
+        let mainnet = PrivateKey { network: Network::Bitcoin, ..key };
+        let wrong_network = Err(PrvKeyError::WrongNetwork(Network::Bitcoin));
+        assert_eq!(parse_prv_key(&mainnet.to_wif(), Network::Regtest), wrong_network);
+        assert_eq!(parse_prv_key(&key.to_wif(), Network::Bitcoin), Err(PrvKeyError::WrongNetwork(Network::Testnet)));
+        assert_eq!(parse_prv_key(&mainnet.to_wif(), Network::Bitcoin), Ok(mainnet));
+
+        let uncompressed = PrivateKey { compressed: false, ..key };
+        assert_eq!(parse_prv_key(&uncompressed.to_wif(), Network::Regtest), Err(PrvKeyError::Uncompressed));
+
+        let mut wif = key.to_wif();
+        wif.pop();
+        assert!(matches!(parse_prv_key(&wif, Network::Regtest), Err(PrvKeyError::Invalid(_))));
+        let padded = format!("{} ", key.to_wif());
+        assert!(matches!(parse_prv_key(&padded, Network::Regtest), Err(PrvKeyError::Invalid(_))));
+    }
+
+    type Contracts = [Result<Descriptor<PublicKey>, ContractDescError>; 2];
+
+    // Builds the contracts of `keys` with each key put in every later place too, which is refused as
+    // the later one. Also the same x-only key with the other parity, as taproot takes it, and an
+    // uncompressed key.
+    fn check_duplicate_keys(keys: Vec<PublicKey>, build: impl Fn(&[PublicKey]) -> Contracts) {
+        assert!(build(&keys).iter().all(Result::is_ok));
+        for first in 0..keys.len() {
+            for second in first + 1..keys.len() {
+                let mut duplicated = keys.clone();
+                duplicated[second] = keys[first];
+                for desc in build(&duplicated) {
+                    assert_eq!(desc, Err(ContractDescError::DuplicateKey(keys[first])), "{first} and {second}");
+                }
+            }
+        }
+
+        let mut negated = keys.clone();
+        negated[1] = PublicKey::new(keys[0].inner.negate(&Secp256k1::new()));
+        for desc in build(&negated) {
+            assert_eq!(desc, Err(ContractDescError::DuplicateKey(negated[1])));
+        }
+        let mut uncompressed = keys;
+        uncompressed[2].compressed = false;
+        for desc in build(&uncompressed) {
+            assert_eq!(desc, Err(ContractDescError::Uncompressed(uncompressed[2])));
+        }
+    }
+
+    #[test]
+    fn every_duplicate_key_is_refused() {
+        let (payout_hash, hash) = (PayoutHash::Sha256(sha256::Hash::hash(&[7; 32])), sha256::Hash::hash(&[9; 32]));
+
+        check_duplicate_keys((1..=5).map(key).collect(), |keys| [
+            maker2users_contract_desc(&[keys[0], keys[1]], &keys[2], &[keys[3], keys[4]], payout_hash, hash, 144),
+            maker2users_contract_desc_tr(&[keys[0], keys[1]], &keys[2], &[keys[3], keys[4]], payout_hash, hash, 144),
+        ]);
+        check_duplicate_keys(triplets(3).concat(), |keys| {
+            let triplets: Vec<[PublicKey; 3]> = keys.chunks(3).map(|triplet| triplet.try_into().unwrap()).collect();
+            [
+                users2maker_contract_desc(&triplets, hash, PathThresholds::all(3), 288),
+                users2maker_contract_desc_tr(&triplets, hash, PathThresholds::all(3), 288),
+            ]
+        });
+    }
+
+    // Against txs we signed, whose signatures take 71 or 72 bytes where the estimate counts the most, 73
+    #[test]
+    fn vsize_estimates_of_signed_txs() {
+        let wallet = signing_wallet();
+        let spk = wallet.get_address(AddressIndex::New).unwrap().script_pubkey();
+        let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
+
+        for inputs in 1..=3u8 {
+            let input = (0..inputs)
+                .map(|i| TxIn { previous_output: OutPoint::new(Txid::from_inner([i; 32]), 0), ..Default::default() })
+                .collect();
+            let output = vec![TxOut { value: 10_000, script_pubkey: spk.clone() }; 2];
+            let tx = Transaction { version: 2, lock_time: PackedLockTime::ZERO, input, output };
+            let mut psbt = Psbt::from_unsigned_tx(tx.clone()).unwrap();
+            for input in &mut psbt.inputs {
+                input.witness_utxo = Some(TxOut { value: 20_000, script_pubkey: spk.clone() });
+            }
+            assert!(wallet.sign(&mut psbt, sign_ops.clone()).unwrap());
+            let signed = psbt.extract_tx();
+
+            let estimate = estimate_vsize(&tx, &vec![P2WPKH_SATISFACTION_WEIGHT; inputs.into()]);
+            assert!(signed.vsize() <= estimate && estimate <= signed.vsize() + usize::from(inputs), "{inputs} inputs");
+        }
+
+        // The usual 1 input 2 output p2wpkh tx, 140.5 vB with a 72 byte signature
+        let input = vec![TxIn::default()];
+        let output = vec![TxOut { value: 10_000, script_pubkey: spk }; 2];
+        let tx = Transaction { version: 2, lock_time: PackedLockTime::ZERO, input, output };
+        assert_eq!(estimate_vsize(&tx, &[P2WPKH_SATISFACTION_WEIGHT]), 141);
+
+        // A contract spend is at most what its heaviest path weighs
+        let desc = maker2user(false, 144);
+        let (outpoint, prevout) = contract_utxo(&desc, 100_000);
+        let destination = Address::p2wpkh(&key(200), Network::Regtest).unwrap().script_pubkey();
+        let fee_rate = FeeRate::from_sat_per_vb(2.0);
+        let utxo = (outpoint, prevout);
+        let sweep = build_multisig_sweep(&desc, &[prv_key(1)], &[prv_key(2)], utxo, destination, fee_rate, 1_000);
+        let sweep = sweep.unwrap();
+        let mut unsigned = sweep.clone();
+        unsigned.input[0].witness = Witness::new();
+        assert!(estimate_vsize(&unsigned, &[desc.max_satisfaction_weight().unwrap()]) >= sweep.vsize());
+    }
+
+    // The fields inside messages, with nothing around their canonical form
+    #[test]
+    fn malformed_fields_are_refused() {
+        let uncompressed = PublicKey::new_uncompressed(key(1).inner).to_string();
+        let (key, other) = (key(1).to_string(), key(2).to_string());
+        let parsed = parse_contract_keys(&[key.clone(), other.clone()], 2).unwrap();
+        assert_eq!(parsed.iter().map(ToString::to_string).collect::<Vec<_>>(), [key.clone(), other.clone()]);
+
+        let bad_keys = [
+            format!("{key} "),
+            format!(" {key}"),
+            format!("{key}\r"),
+            format!("{key},"),
+            format!("0x{key}"),
+            format!("{key}00"),
+            key[..64].to_string(),
+            format!("04{}", &key[2..]),
+            key.replace('0', "o"),
+            String::new(),
+        ];
+        for bad_key in bad_keys {
+            let parsed = parse_contract_keys(&[other.clone(), bad_key.clone()], 2);
+            assert!(matches!(parsed, Err(KeyParseError::Invalid { index: 1, .. })), "{bad_key:?}: {parsed:?}");
+        }
+        assert_eq!(
+            parse_contract_keys(&[other.clone(), uncompressed], 2),
+            Err(KeyParseError::Uncompressed { index: 1 }),
+        );
+        for count in [0, 1, 3] {
+            let keys = vec![key.clone(); count];
+            assert_eq!(parse_contract_keys(&keys, 2), Err(KeyParseError::WrongCount { expected: 2, got: count }));
+        }
+
+        let hash = sha256::Hash::hash(b"hash").to_hex();
+        assert!(check_hex32(&hash, "hash").is_ok());
+        assert!(check_hex32(&hash.to_uppercase(), "hash").is_ok());
+        let bad_hashes = [
+            format!("{hash} "),
+            format!("\t{hash}"),
+            format!("{hash}\r"),
+            format!("0x{}", &hash[2..]),
+            hash[1..].to_string(),
+            format!("{hash}0"),
+            format!("{}g", &hash[1..]),
+            hash.replacen('a', "-", 1),
+            String::new(),
+        ];
+        for bad_hash in bad_hashes {
+            let checked = check_hex32(&bad_hash, "hash");
+            let malformed = matches!(checked, Err(ProtocolError::Malformed { field: "hash", .. }));
+            assert!(malformed, "{bad_hash:?}: {checked:?}");
+        }
+
+        assert_eq!(PayoutRequest::from_str("onchain"), Ok(PayoutRequest::OnChain));
+        for request in ["onchain ", " onchain", "onchain\r", "OnChain", "lightning", "on chain", ""] {
+            assert!(PayoutRequest::from_str(request).is_err(), "{request:?}");
+        }
+
+        let invoice = HashSource::from_str(&format!("invoice:{hash}"));
+        assert_eq!(invoice, Ok(HashSource::Invoice(sha256::Hash::hash(b"hash"))));
+        let bad_sources = [
+            "maker ".to_string(),
+            "Maker".to_string(),
+            "invoice".to_string(),
+            format!("invoice: {hash}"),
+            format!("invoice:{hash} "),
+            format!("invoice:{}", &hash[1..]),
+            format!("invoice:{hash}:"),
+            format!("maker:{hash}"),
+        ];
+        for source in bad_sources {
+            assert!(HashSource::from_str(&source).is_err(), "{source:?}");
+        }
+    }
+}
@@ -0,0 +1,370 @@
+// Lightning support. With a submarine swap a user asks the maker to lock both contracts to the
+// payment hash of a Lightning invoice it wants paid, so the maker only learns the preimage (and can
+// claim the users2maker coins through the hashlock path) by paying that invoice. With a Lightning
+// payout the user takes the second leg as a payment instead of a maker2user contract.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use bdk::bitcoin::hashes::{Hash, sha256};
+
+// Who provides the hash used in the contracts of a round
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashSource {
+    // The maker generates a random preimage, the regular JoinSwap
+    Maker,
+    // Payment hash of an invoice the user wants paid
+    Invoice(sha256::Hash),
+}
+
+impl fmt::Display for HashSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashSource::Maker => write!(f, "maker"),
+            HashSource::Invoice(hash) => write!(f, "invoice:{hash}"),
+        }
+    }
+}
+
+impl FromStr for HashSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hash) = s.strip_prefix("invoice:") {
+            let hash = sha256::Hash::from_str(hash).map_err(|e| format!("Invalid payment hash: {e}"))?;
+            return Ok(HashSource::Invoice(hash));
+        }
+
+        match s {
+            "maker" => Ok(HashSource::Maker),
+            _ => Err(format!("Unknown hash source '{s}'")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PreimageError {
+    // The maker has no way to learn preimages, so it can't accept invoice hashes
+    NoSource,
+    // A round can only pay one invoice, as every contract uses the same hash
+    ConflictingHashes,
+    // The source came back with a preimage that doesn't match the payment hash
+    WrongPreimage,
+    // Reusing a hash links both swaps, and whoever learned the old preimage could claim the new contracts
+    ReusedHash(sha256::Hash),
+    // The maker built the contracts on another hash than that of the invoice we sent
+    NotOurHash(sha256::Hash),
+    Backend(String),
+}
+
+impl fmt::Display for PreimageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreimageError::NoSource => write!(f, "No preimage source to pay invoices with"),
+            PreimageError::ConflictingHashes => write!(f, "Users asked for different payment hashes"),
+            PreimageError::WrongPreimage => write!(f, "Preimage doesn't match the payment hash"),
+            PreimageError::ReusedHash(hash) => write!(f, "Hash {hash} is used by another swap"),
+            PreimageError::NotOurHash(hash) => write!(f, "Contracts use hash {hash} instead of our payment hash"),
+            PreimageError::Backend(e) => write!(f, "Preimage source error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PreimageError {}
+
+// How the maker learns the preimage of an invoice hash, typically paying it with a Lightning node
+pub trait PreimageSource: Send + Sync {
+    fn preimage(&self, hash: sha256::Hash) -> Result<[u8; 32], PreimageError>;
+}
+
+// Answers with preimages given beforehand. Stands in for a Lightning node in the demo.
+#[derive(Debug, Default)]
+pub struct MemoryPreimages {
+    preimages: Mutex<HashMap<sha256::Hash, [u8; 32]>>,
+}
+
+impl MemoryPreimages {
+    pub fn insert(&self, preimage: [u8; 32]) {
+        self.preimages.lock().unwrap().insert(sha256::Hash::hash(&preimage), preimage);
+    }
+}
+
+impl PreimageSource for MemoryPreimages {
+    fn preimage(&self, hash: sha256::Hash) -> Result<[u8; 32], PreimageError> {
+        self.preimages.lock().unwrap()
+            .get(&hash)
+            .copied()
+            .ok_or_else(|| PreimageError::Backend(format!("Unknown payment hash {hash}")))
+    }
+}
+
+// Fetches the preimage for `hash` and makes sure it's the right one
+pub fn obtain_preimage(
+    source: &dyn PreimageSource,
+    hash: sha256::Hash,
+) -> Result<[u8; 32], PreimageError> {
+    let preimage = source.preimage(hash)?;
+
+    if sha256::Hash::hash(&preimage) != hash {
+        return Err(PreimageError::WrongPreimage);
+    }
+    Ok(preimage)
+}
+
+// How a user wants to receive the second leg of the swap
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayoutRequest {
+    // A maker2user contract, the regular JoinSwap
+    OnChain,
+    // A BOLT11 invoice for the amount the user is entitled to, minus the Lightning fee allowance
+    Lightning(String),
+}
+
+impl fmt::Display for PayoutRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayoutRequest::OnChain => write!(f, "onchain"),
+            PayoutRequest::Lightning(bolt11) => write!(f, "lightning:{bolt11}"),
+        }
+    }
+}
+
+impl FromStr for PayoutRequest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(bolt11) = s.strip_prefix("lightning:") {
+            return Ok(PayoutRequest::Lightning(bolt11.to_string()));
+        }
+
+        match s {
+            "onchain" => Ok(PayoutRequest::OnChain),
+            _ => Err(format!("Unknown payout request '{s}'")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvoiceDetails {
+    pub payment_hash: sha256::Hash,
+    pub amount_sat: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LnPayment {
+    pub preimage: [u8; 32],
+    pub fee_sat: u64,
+}
+
+#[derive(Debug)]
+pub struct LnError(pub String);
+
+impl fmt::Display for LnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Lightning backend error: {}", self.0)
+    }
+}
+
+impl std::error::Error for LnError {}
+
+// Lightning node used by the maker to pay second leg payouts. Calls may block for a while, the
+// sessions run them on the blocking thread pool.
+pub trait LnBackend: Send + Sync {
+    fn decode(&self, bolt11: &str) -> Result<InvoiceDetails, LnError>;
+
+    fn pay(&self, bolt11: &str, max_fee_sat: u64) -> Result<LnPayment, LnError>;
+}
+
+// Lightning capabilities of a maker, each one is optional
+#[derive(Clone, Default)]
+pub struct MakerLightning {
+    // Learns the preimage of the users' invoice hashes (submarine swaps)
+    pub preimages: Option<Arc<dyn PreimageSource>>,
+    // Pays the second leg to users asking for a Lightning payout
+    pub backend: Option<Arc<dyn LnBackend>>,
+}
+
+// Pays the invoices it knows about. Stands in for a Lightning node in the demo.
+#[derive(Debug, Default)]
+pub struct MemoryLnBackend {
+    invoices: Mutex<HashMap<String, (u64, [u8; 32])>>,
+}
+
+impl MemoryLnBackend {
+    pub fn add_invoice(&self, bolt11: String, amount_sat: u64, preimage: [u8; 32]) {
+        self.invoices.lock().unwrap().insert(bolt11, (amount_sat, preimage));
+    }
+}
+
+impl LnBackend for MemoryLnBackend {
+    fn decode(&self, bolt11: &str) -> Result<InvoiceDetails, LnError> {
+        let invoices = self.invoices.lock().unwrap();
+        let (amount_sat, preimage) = invoices.get(bolt11)
+            .ok_or_else(|| LnError(format!("Unknown invoice {bolt11}")))?;
+
+        Ok(InvoiceDetails { payment_hash: sha256::Hash::hash(preimage), amount_sat: *amount_sat })
+    }
+
+    fn pay(&self, bolt11: &str, _max_fee_sat: u64) -> Result<LnPayment, LnError> {
+        let invoices = self.invoices.lock().unwrap();
+        let (_, preimage) = invoices.get(bolt11)
+            .ok_or_else(|| LnError(format!("Unknown invoice {bolt11}")))?;
+
+        Ok(LnPayment { preimage: *preimage, fee_sat: 0 })
+    }
+}
+
+// Core Lightning, driven through `lightning-cli`
+#[cfg(feature = "cln")]
+#[derive(Debug, Clone, Default)]
+pub struct ClnBackend {
+    // Extra arguments for lightning-cli, like `--lightning-dir`
+    pub cli_args: Vec<String>,
+}
+
+#[cfg(feature = "cln")]
+impl LnBackend for ClnBackend {
+    fn decode(&self, bolt11: &str) -> Result<InvoiceDetails, LnError> {
+        let decoded = run_cli("lightning-cli", &self.cli_args, &["decodepay", bolt11])?;
+        let amount_msat = decoded["amount_msat"].as_u64()
+            .ok_or_else(|| LnError("Invoice without amount".to_string()))?;
+
+        Ok(InvoiceDetails { payment_hash: json_hash(&decoded["payment_hash"])?, amount_sat: amount_msat / 1000 })
+    }
+
+    fn pay(&self, bolt11: &str, max_fee_sat: u64) -> Result<LnPayment, LnError> {
+        let maxfee = format!("maxfee={}", max_fee_sat * 1000);
+        let paid = run_cli("lightning-cli", &self.cli_args, &["pay", "-k", &format!("bolt11={bolt11}"), &maxfee])?;
+
+        let sent_msat = paid["amount_sent_msat"].as_u64().unwrap_or_default();
+        let amount_msat = paid["amount_msat"].as_u64().unwrap_or_default();
+
+        Ok(LnPayment {
+            preimage: json_preimage(&paid["payment_preimage"])?,
+            fee_sat: sent_msat.saturating_sub(amount_msat).div_ceil(1000),
+        })
+    }
+}
+
+// LND, driven through `lncli`
+#[cfg(feature = "lnd")]
+#[derive(Debug, Clone, Default)]
+pub struct LndBackend {
+    // Extra arguments for lncli, like `--network` or `--rpcserver`
+    pub cli_args: Vec<String>,
+}
+
+#[cfg(feature = "lnd")]
+impl LnBackend for LndBackend {
+    fn decode(&self, bolt11: &str) -> Result<InvoiceDetails, LnError> {
+        let decoded = run_cli("lncli", &self.cli_args, &["decodepayreq", bolt11])?;
+        let amount_sat = decoded["num_satoshis"].as_str()
+            .and_then(|amount| amount.parse().ok())
+            .ok_or_else(|| LnError("Invoice without amount".to_string()))?;
+
+        Ok(InvoiceDetails { payment_hash: json_hash(&decoded["payment_hash"])?, amount_sat })
+    }
+
+    fn pay(&self, bolt11: &str, max_fee_sat: u64) -> Result<LnPayment, LnError> {
+        let fee_limit = max_fee_sat.to_string();
+        let paid = run_cli(
+            "lncli",
+            &self.cli_args,
+            &["payinvoice", "--force", "--json", "--fee_limit", &fee_limit, bolt11],
+        )?;
+
+        if paid["status"].as_str() != Some("SUCCEEDED") {
+            return Err(LnError(format!("Payment not completed: {}", paid["failure_reason"])));
+        }
+        let fee_sat = paid["fee_sat"].as_str()
+            .and_then(|fee| fee.parse().ok())
+            .unwrap_or_default();
+
+        Ok(LnPayment { preimage: json_preimage(&paid["payment_preimage"])?, fee_sat })
+    }
+}
+
+#[cfg(any(feature = "cln", feature = "lnd"))]
+fn run_cli(program: &str, cli_args: &[String], args: &[&str]) -> Result<serde_json::Value, LnError> {
+    let output = std::process::Command::new(program)
+        .args(cli_args)
+        .args(args)
+        .output()
+        .map_err(|e| LnError(format!("Can't run {program}: {e}")))?;
+
+    if !output.status.success() {
+        return Err(LnError(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| LnError(format!("Unexpected {program} output: {e}")))
+}
+
+#[cfg(any(feature = "cln", feature = "lnd"))]
+fn json_hash(value: &serde_json::Value) -> Result<sha256::Hash, LnError> {
+    value.as_str()
+        .and_then(|hash| sha256::Hash::from_str(hash).ok())
+        .ok_or_else(|| LnError("Missing payment hash".to_string()))
+}
+
+#[cfg(any(feature = "cln", feature = "lnd"))]
+fn json_preimage(value: &serde_json::Value) -> Result<[u8; 32], LnError> {
+    use bdk::bitcoin::hashes::hex::FromHex;
+
+    value.as_str()
+        .and_then(|preimage| <[u8; 32]>::from_hex(preimage).ok())
+        .ok_or_else(|| LnError("Missing payment preimage".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_round_trip() {
+        let hash = sha256::Hash::hash(&[1; 32]);
+        for source in [HashSource::Maker, HashSource::Invoice(hash)] {
+            assert_eq!(source.to_string().parse::<HashSource>(), Ok(source));
+        }
+        for request in [PayoutRequest::OnChain, PayoutRequest::Lightning("lnbcrt1".to_string())] {
+            assert_eq!(request.to_string().parse::<PayoutRequest>(), Ok(request));
+        }
+
+        assert!("invoice:abcd".parse::<HashSource>().is_err());
+        assert!("taker".parse::<HashSource>().is_err());
+        assert!("offchain".parse::<PayoutRequest>().is_err());
+    }
+
+    // A source that answers with the preimage of another hash
+    struct WrongPreimages;
+
+    impl PreimageSource for WrongPreimages {
+        fn preimage(&self, _: sha256::Hash) -> Result<[u8; 32], PreimageError> {
+            Ok([0; 32])
+        }
+    }
+
+    #[test]
+    fn preimages_must_match_the_hash() {
+        let preimages = MemoryPreimages::default();
+        preimages.insert([1; 32]);
+        let hash = sha256::Hash::hash(&[1; 32]);
+
+        assert_eq!(obtain_preimage(&preimages, hash).unwrap(), [1; 32]);
+        assert!(matches!(obtain_preimage(&WrongPreimages, hash), Err(PreimageError::WrongPreimage)));
+        let unknown = sha256::Hash::hash(&[2; 32]);
+        assert!(matches!(obtain_preimage(&preimages, unknown), Err(PreimageError::Backend(_))));
+    }
+
+    #[test]
+    fn memory_backend_pays_its_invoices() {
+        let backend = MemoryLnBackend::default();
+        backend.add_invoice("lnbcrt1".to_string(), 10_000, [1; 32]);
+
+        let details = backend.decode("lnbcrt1").unwrap();
+        assert_eq!(details, InvoiceDetails { payment_hash: sha256::Hash::hash(&[1; 32]), amount_sat: 10_000 });
+        assert_eq!(backend.pay("lnbcrt1", 100).unwrap(), LnPayment { preimage: [1; 32], fee_sat: 0 });
+        assert!(backend.decode("lnbcrt2").is_err());
+        assert!(backend.pay("lnbcrt2", 100).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+// Limits on the connections the maker takes. Each connection costs a handshake and some state, so
+// without them a flood of connections could exhaust the maker or keep honest users from pairing.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// Addresses we keep connection times for before forgetting the ones that went quiet
+const MAX_TRACKED_IPS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    // Connections that didn't make it into a round yet (handshaking or waiting for the other users)
+    pub max_pending: usize,
+    // Connections each IP can open within `per_ip_window`. Behind an onion service every user comes
+    // from localhost, so there it must be raised or the maker only takes a few users per window.
+    pub per_ip: usize,
+    pub per_ip_window: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_pending: 32,
+            per_ip: 10,
+            per_ip_window: Duration::from_secs(60),
+        }
+    }
+}
+
+// Why a connection was turned away, sent to the peer before closing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    Busy,
+    RateLimited,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::Busy => write!(f, "Maker is busy, try again later"),
+            Refusal::RateLimited => write!(f, "Too many connections from your address, try again later"),
+        }
+    }
+}
+
+// Decides which connections we take. Shared by every clone, so all the accept loops of the maker
+// count against the same limits.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionGate {
+    limits: ConnectionLimits,
+    pending: Arc<AtomicUsize>,
+    // Recent connection times of each IP, oldest first
+    recent: Arc<Mutex<HashMap<IpAddr, VecDeque<Instant>>>>,
+}
+
+// Counts as a pending connection until dropped
+#[derive(Debug)]
+pub struct PendingSlot(Arc<AtomicUsize>);
+
+impl Drop for PendingSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConnectionGate {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        ConnectionGate { limits, ..Default::default() }
+    }
+
+    // Connections from an unknown address (like a Unix socket) only count against `max_pending`
+    pub fn admit(&self, ip: Option<IpAddr>) -> Result<PendingSlot, Refusal> {
+        if let Some(ip) = ip {
+            self.check_rate(ip)?;
+        }
+
+        let taken = self.pending.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+            (pending < self.limits.max_pending).then_some(pending + 1)
+        });
+        match taken {
+            Ok(_) => Ok(PendingSlot(self.pending.clone())),
+            Err(_) => Err(Refusal::Busy),
+        }
+    }
+
+    // Only taken connections count, so an IP can't hold more than `per_ip` entries
+    fn check_rate(&self, ip: IpAddr) -> Result<(), Refusal> {
+        let mut recent = self.recent.lock().unwrap();
+        let now = Instant::now();
+        let window = self.limits.per_ip_window;
+
+        if recent.len() >= MAX_TRACKED_IPS {
+            recent.retain(|_, times| times.back().is_some_and(|last| now.duration_since(*last) < window));
+        }
+        let times = recent.entry(ip).or_default();
+        while times.front().is_some_and(|first| now.duration_since(*first) >= window) {
+            times.pop_front();
+        }
+        if times.len() >= self.limits.per_ip {
+            return Err(Refusal::RateLimited);
+        }
+        times.push_back(now);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_connections_are_capped() {
+        let gate = ConnectionGate::new(ConnectionLimits { max_pending: 2, ..ConnectionLimits::default() });
+        let first = gate.admit(None).unwrap();
+        let _second = gate.admit(None).unwrap();
+        assert_eq!(gate.admit(None).unwrap_err(), Refusal::Busy);
+
+        // Every clone counts against the same cap, and a dropped slot is free again
+        let clone = gate.clone();
+        assert_eq!(clone.admit(None).unwrap_err(), Refusal::Busy);
+        drop(first);
+        let _third = clone.admit(None).unwrap();
+        assert_eq!(gate.admit(None).unwrap_err(), Refusal::Busy);
+    }
+
+    #[tokio::test]
+    async fn connections_are_rate_limited_per_ip() {
+        let window = Duration::from_millis(50);
+        let gate = ConnectionGate::new(ConnectionLimits { max_pending: 100, per_ip: 2, per_ip_window: window });
+        let (ip, other) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        let slots = [gate.admit(Some(ip)).unwrap(), gate.admit(Some(ip)).unwrap()];
+        assert_eq!(gate.admit(Some(ip)).unwrap_err(), Refusal::RateLimited);
+        // Closing the connections doesn't give the IP more of them within the window
+        drop(slots);
+        assert_eq!(gate.admit(Some(ip)).unwrap_err(), Refusal::RateLimited);
+        let _other = gate.admit(Some(other)).unwrap();
+        let _no_ip = gate.admit(None).unwrap();
+
+        tokio::time::sleep(window).await;
+        let _again = gate.admit(Some(ip)).unwrap();
+    }
+}
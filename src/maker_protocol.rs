@@ -1,358 +1,697 @@
+use std::net::SocketAddr;
+use std::path::Path;
 use std::str::FromStr;
-use bdk::bitcoin::{Address, Network, OutPoint, PrivateKey, psbt, PublicKey, Txid};
-use bdk::descriptor::Descriptor;
-use bdk::{SignOptions, Utxo, Wallet, WeightedUtxo};
-use bdk::bitcoin::hashes::{Hash, sha256};
-use bdk::bitcoin::psbt::Psbt;
-use bdk::bitcoin::secp256k1::rand::{thread_rng, Rng};
-use bdk::database::{AnyDatabase, MemoryDatabase};
-use bdk::psbt::PsbtUtils;
-use bdk::wallet::get_funded_wallet;
-
-use serde_json;
-use tokio::io::{BufReader, ReadHalf, split, WriteHalf};
-use tokio::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
 
-use joinswap::{build_funding_and_refund, check_prv_keys, users2maker_contract_desc, gen_key_pair, get_descriptors, read_contract_keys, read_message, read_psbt, maker2users_contract_desc, send_message, sign_and_send_psbt};
+use bdk::bitcoin::hashes::hex::FromHex;
+use bdk::bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bdk::bitcoin::util::bip32::ExtendedPrivKey;
+use bdk::bitcoin::{Network, Transaction};
+use bdk::database::{AnyDatabase, MemoryDatabase};
+use bdk::sled;
+use bdk::wallet::AddressIndex;
+use bdk::{FeeRate, Wallet};
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+
+use joinswap::chain::{
+    connect_backend, ChainAccess, ChainBackend, FeeEstimator, FixedFeeRate, MemoryChain, RpcAuth,
+};
+use joinswap::control::{serve_control, MakerRegistry};
+use joinswap::error::{JoinSwapError, ProtocolError};
+use joinswap::events::{AbortCode, ContractKind, EventSink, Leg, MessageKind, Phase, ProtocolEvent, TxRole};
+use joinswap::{demo_maker_key, demo_seed, get_descriptors, HashKind, MakerFee, PathThresholds, Timelocks,
+               MAX_ROUND_USERS, MIN_ROUND_USERS};
+use joinswap::message::{send_error, PsbtEncoding};
+use joinswap::keys::{ContractKeySource, KeyChain};
+use joinswap::lightning::{LnBackend, MakerLightning, MemoryLnBackend, MemoryPreimages, PreimageSource};
+use joinswap::limits::{ConnectionGate, ConnectionLimits, PendingSlot};
+use joinswap::protocol::maker::{accept_gated, answer_hello, run_maker_round, send_waiting, MakerConfig, MakerTreasury,
+                                DenominationMode, RoundStatus, RoundUser, NO_ROUND, ROUND_USERS};
+use joinswap::protocol::rounds::{Arrival, RoundInbox, RoundRouter, UsedInputs};
+use joinswap::recovery::read_recovery;
+use joinswap::shutdown::{exit_with, on_ctrl_c, OrExit, SHUTDOWN_EXIT_CODE, SHUTTING_DOWN};
+use joinswap::tls::TlsAcceptor;
+use joinswap::transport::{bind_unix, Acceptor, Connection, Framing, ListenAddr, MakerAddress};
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:8080").await.unwrap();
-
-    // Accept the connections from user A and B
-    println!("CONNECTIONS 👉👈\n");
-    let (mut reader_a, writer_a) = accept_connection(&listener).await;
-    println!("New connection <-----------------> User A");
-    let (mut reader_b, writer_b) = accept_connection(&listener).await;
-    println!("New connection <-----------------> User B");
-
-    let ((key1_a, key2_a, key3_a), weighted_a, addr_a) = read_user_data(&mut reader_a).await;
-    let ((key1_b, key2_b, key3_b), weighted_b, addr_b) = read_user_data(&mut reader_b).await;
-    println!("User data <----------------------- Users (A/B)\n");
-
-    let mut writers = vec![writer_a, writer_b];
-    let mut readers = vec![reader_a, reader_b];
-
-    // Maker keys used in the contract
-    let (prv_key1, pub_key1) = gen_key_pair();
-    let (prv_key2, pub_key2) = gen_key_pair();
-    let (prv_key3, pub_key3) = gen_key_pair();
-
-    // Each 3 keys are from a different multisig path in the contract
-    let keys = [key1_a, key1_b, pub_key1, key2_a, key2_b, pub_key2, key3_a, key3_b, pub_key3];
-    let (preimage, hash) = gen_hash();
-
-    let users2maker_desc_str = users2maker_contract_desc(&keys, hash);
-    let users2maker_desc = Descriptor::<PublicKey>::from_str(&users2maker_desc_str).unwrap();
-
-    println!("CONTRACT CREATION 🐸\n");
-    println!("Users-to-maker contract address:\n{}\n",
-             users2maker_desc.address(Network::Regtest).unwrap());
-
-    // Build funding and refund tx spending from user utxos and refunding to their addresses
-    let (funding_psbt, refund_psbt) = build_funding_and_refund(
-        &users2maker_desc,
-        vec![weighted_a, weighted_b],
-        vec![addr_a, addr_b],
-    );
-
-    send_contract_data(&keys, hash, &funding_psbt, &refund_psbt, &mut writers).await;
-    println!("Contract data -------------------> Users (A/B)");
-    println!("Funding and Refund Tx -----------> Users (A/B)\n");
-
-    // Combine the signed refund psbts received from the users
-    let mut refund_final = read_and_combine_psbt(
-        &mut readers, Some(refund_psbt.unsigned_tx.txid())).await;
-    println!("Signed Refund PSBTs <------------- Users (A/B)");
-
-    // We have to sign from the refund psbt too as our key is also in the contract
-    let users2maker_prv_desc = users2maker_desc_str
-        .replace(&pub_key1.to_string(), &prv_key1.to_string())
-        .replace(&pub_key2.to_string(), &prv_key2.to_string())
-        .replace(&pub_key3.to_string(), &prv_key3.to_string());
-
-    let prv_wallet = Wallet::new(
-        &users2maker_prv_desc,
-        None,
-        Network::Regtest,
-        MemoryDatabase::new(),
-    ).unwrap();
-
-    let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
-    sign_and_send_psbt(&mut refund_final, &prv_wallet, sign_ops, &mut writers).await;
-    println!("Finalized Refund Tx -------------> Users (A/B)\n");
-
-    // Now that users have the finalized refund tx they sign the funding tx
-    let funding_final = read_and_combine_psbt(&mut readers, Some(funding_psbt.unsigned_tx.txid())).await;
-    println!("Signed Funding PSBTs <------------ Users (A/B)");
-    send_psbt(&funding_final, &mut writers).await;
-    println!("Finalized Funding Tx ------------> Users (A/B)\n");
-
-    // Here we should broadcast the funding tx and wait
-    println!("Broadcast Funding Tx\n");
-
-    // Second leg of the JoinSwap: The new peers should give us a blinded certificate to ensure
-    // they are the same participants
-    println!("CONNECTIONS, SECOND PART 👉👈\n");
-    let (mut reader_x, writer_x) = accept_connection(&listener).await;
-    println!("New connection <-----------------> User X");
-    let (mut reader_y, writer_y) = accept_connection(&listener).await;
-    println!("New connection <-----------------> User Y");
-
-    let (key1_x, key2_x) = read_second_user_data(&mut reader_x).await;
-    let (key1_y, key2_y) = read_second_user_data(&mut reader_y).await;
-    println!("User data <----------------------- Users (X/Y)\n");
-
-    // We will use the old IDs to read the users2maker contract private keys (private key handover)
-    let mut old_readers = readers;
-    let mut new_writers = vec![writer_x, writer_y];
-
-    // Gen maker keys and build the descriptor for each maker2user contract
-    let (prv_key4, pub_key4) = gen_key_pair();
-    let (_prv_key5, pub_key5) = gen_key_pair();
-    let (prv_key6, pub_key6) = gen_key_pair();
-    let (_prv_key7, pub_key7) = gen_key_pair();
-
-    let maker2user_x_desc_str = maker2users_contract_desc(
-        &[key1_x, pub_key4],
-        &pub_key5,
-        &key2_x,
-        hash);
-    let maker2user_y_desc_str = maker2users_contract_desc(
-        &[key1_y, pub_key6],
-        &pub_key7,
-        &key2_y,
-        hash);
-    let maker2user_x_desc = Descriptor::<PublicKey>::from_str(&maker2user_x_desc_str).unwrap();
-    let maker2user_y_desc = Descriptor::<PublicKey>::from_str(&maker2user_y_desc_str).unwrap();
-
-    println!("SECOND CONTRACT CREATION 🐸\n");
-    println!("Maker-to-user X contract address:\n{}\n",
-             maker2user_x_desc.address(Network::Regtest).unwrap());
-    println!("Maker-to-user Y contract address:\n{}\n",
-             maker2user_y_desc.address(Network::Regtest).unwrap());
-
-    // Build and sign the funding tx for each maker2user contract
-    let mut total_spent = 0;
-    let maker2users_txs: Vec<_> = [maker2user_x_desc, maker2user_y_desc].iter().map(|desc| {
-        let (wallet, _, _) = get_funded_wallet(&get_descriptors());
-        let mut psbt = build_second_funding(&wallet, &desc);
-
-        psbt.unsigned_tx.output.iter()
-            .filter(|txout| txout.script_pubkey == desc.script_pubkey())
-            .for_each(|txout| total_spent += txout.value);
-        total_spent += psbt.fee_amount().unwrap();
-
-        let finalized = wallet.sign(&mut psbt, SignOptions::default()).unwrap();
-        assert!(finalized);
-
-        psbt.extract_tx()
-    }).collect();
-
-    // Here these txs should be broadcast and mined within a period of time
-    println!("Broadcast maker-to-user X transaction");
-    println!("Broadcast maker-to-user Y transaction");
-
-    // Send maker pub keys + tx id to each user
-    send_second_contract_data(
-        vec![&[pub_key4, pub_key5], &[pub_key6, pub_key7]],
-        vec![maker2users_txs[0].txid(), maker2users_txs[1].txid()],
-        &mut new_writers,
-    ).await;
-    println!("Maker2users contract + TxIDs ----> Users (X/Y)\n");
-
-    // Once that users verify the funding second contract txs, they send us their private keys from
-    // the hashlock path of the users2maker contract. We then can redeem the first contract coins by
-    // revealing the preimage.
-
-    let hashlock_prv_keys = read_prv_keys(&mut old_readers).await;
-    println!("PRIVATE KEYS HANDOVER 😎🤝😎\n");
-    println!("Users2maker hashlock PrvKeys <---- Users (A/B)");
-
-    // Check that read private keys indeed correspond to the hashlock public keys
-    check_prv_keys(&hashlock_prv_keys, vec![key3_a, key3_b]);
-
-    // Send preimage + multisig path prv keys from the maker2users contracts
-    send_preimage_and_prv_keys(preimage, vec![prv_key4, prv_key6], &mut new_writers).await;
-    println!("Maker2users contract PrvKeys ----> Users (X/Y)");
-
-    // Users can now redeem their funds from the respective maker2user contract
-
-    // Receive users2maker contract keys
-    let prv_keys = read_prv_keys(&mut old_readers).await;
-    check_prv_keys(&prv_keys, vec![key1_a, key1_b]);
-    println!("Users2maker contract PrvKeys <---- Users (A/B)");
-
-    // Maker can now spend from:
-    let _prv_desc = users2maker_prv_desc
-        .replace(&key1_a.to_string(), &prv_keys[0].to_string())
-        .replace(&key1_b.to_string(), &prv_keys[1].to_string());
-
-    let total_received = funding_final.unsigned_tx.output[0].value;
-    let profit = total_received - total_spent;
-
-    println!("\nSuccesful JoinSwap! Maker earned {profit} sats");
-}
-
-async fn send_preimage_and_prv_keys(
-    preimage: [u8; 32],
-    prv_keys: Vec<PrivateKey>,
-    writers: &mut Vec<WriteHalf<TcpStream>>,
-) {
-    assert_eq!(prv_keys.len(), writers.len());
-    let serialized_preimage = serde_json::to_string(&preimage).unwrap();
+    // With `--inspect-recovery <file>` we only tell what a recovery file lets us claim, and when
+    if let Some(path) = arg_values("--inspect-recovery").first() {
+        let recovery = read_recovery(Path::new(path)).or_exit("Can't read the recovery file");
+        recovery.claims().iter().for_each(|claim| println!("{claim}"));
+        return;
+    }
 
-    for (key, mut writer) in prv_keys.iter().zip(writers) {
-        send_message(serialized_preimage.clone(), &mut writer).await;
-        send_message(key.to_string(), &mut writer).await;
+    // With `--users <n>` each round waits for n users instead of two
+    let round_users = match arg_values("--users").first() {
+        Some(users) => users.parse().or_exit("--users must be a number"),
+        None => ROUND_USERS,
+    };
+    if !(MIN_ROUND_USERS..=MAX_ROUND_USERS).contains(&round_users) {
+        exit_with(format!("Rounds take from {MIN_ROUND_USERS} to {MAX_ROUND_USERS} users"));
     }
-}
 
-async fn read_prv_keys(
-    readers: &mut Vec<BufReader<ReadHalf<TcpStream>>>
-) -> Vec<PrivateKey> {
-    assert_eq!(readers.len(), 2);
+    let (events, receiver) = RoundEvents::channel();
+    let registry = MakerRegistry::new(round_users);
+    let printer = tokio::spawn(print_events(receiver, registry.clone(), round_users));
+
+    // With `--listen <host>:<port>` we listen there instead of 127.0.0.1:8080, and with `--listen unix:<path>`
+    // on that Unix domain socket, for users on the same host
+    let listen = arg_values("--listen").first().cloned().unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let listen = ListenAddr::from_str(&listen).or_exit("Invalid --listen");
+
+    // Submarine swap invoices can only be "paid" if we are given their preimages with
+    // `--preimage <hex>`, as there's no Lightning node behind them yet
+    let known_preimages = arg_values("--preimage");
+    let preimages = if known_preimages.is_empty() {
+        None
+    } else {
+        let source = MemoryPreimages::default();
+        known_preimages.iter().for_each(|preimage| source.insert(parse_preimage(preimage)));
+        Some(Arc::new(source) as Arc<dyn PreimageSource>)
+    };
+    let lightning = MakerLightning { preimages, backend: ln_backend() };
+
+    // With `--line-framing` we talk to users that still delimit messages with newlines
+    let framing = if std::env::args().any(|arg| arg == "--line-framing") { Framing::Line } else { Framing::default() };
+
+    // With `--base64-psbts` we offer standard base64 PSBTs, used with peers that offer them too
+    let psbt_encoding =
+        if std::env::args().any(|arg| arg == "--base64-psbts") { PsbtEncoding::Base64 } else { PsbtEncoding::Json };
+
+    // With `--max-pending <n>` we take at most n users that aren't in a round yet, and with `--per-ip <n>` at
+    // most n connections a minute from each IP. Behind an onion service every user comes from localhost,
+    // so there connections are only limited per IP if asked.
+    let mut limits = ConnectionLimits::default();
+    if let Some(max) = arg_values("--max-pending").first() {
+        limits.max_pending = max.parse().or_exit("--max-pending must be a number");
+    }
+    match arg_values("--per-ip").first() {
+        Some(max) => limits.per_ip = max.parse().or_exit("--per-ip must be a number"),
+        None if !arg_values("--onion-hostname").is_empty() => limits.per_ip = usize::MAX,
+        None => {},
+    }
 
-    let mut prv_keys = Vec::new();
-    for mut reader in readers {
-        let prv_key_str = read_message(&mut reader).await;
-        prv_keys.push(PrivateKey::from_str(prv_key_str.trim()).unwrap());
+    // The recovery file of each round we fund is saved to `--state-dir <dir>` (or here) before every step
+    // we can't undo, and once more if we stop on Ctrl-C
+    let state_dir = arg_values("--state-dir").first().cloned().unwrap_or_else(|| ".".to_string());
+    let mut config = MakerConfig {
+        round_users,
+        framing,
+        psbt_encoding,
+        shutdown: on_ctrl_c(),
+        state_dir: Some(state_dir.clone().into()),
+        // The hashes of our rounds are saved there too, so no restart locks a new round to an old one
+        used: UsedInputs::load(state_dir.clone().into()).or_exit("Can't read the used hashes"),
+        gate: ConnectionGate::new(limits),
+        ..Default::default()
+    };
+    // With `--matchmaking-timeout <secs>` users wait that long for their round to fill up, instead of 10 minutes
+    if let Some(secs) = arg_values("--matchmaking-timeout").first() {
+        let secs = secs.parse().or_exit("--matchmaking-timeout must be a number");
+        config.matchmaking_timeout = Duration::from_secs(secs);
+    }
+    // With `--thresholds <multisig>,<timelock>,<hashlock>` each users2maker contract path needs that many
+    // participants (us always among them) instead of all, users must accept it
+    if let Some(thresholds) = arg_values("--thresholds").first() {
+        config.thresholds = Some(PathThresholds::from_str(thresholds).or_exit("Invalid --thresholds"));
+    }
+    // With `--timelocks <refund>,<maker2user>` the refund path and the maker2user contracts are timelocked for
+    // that many blocks, instead of 48 and 69. Users must take them.
+    if let Some(timelocks) = arg_values("--timelocks").first() {
+        config.timelocks = Timelocks::from_str(timelocks).or_exit("Invalid --timelocks");
+    }
+    // With `--hash160` the maker2user contracts are locked to hash160 hashes instead of sha256 ones, which take
+    // less space in the scripts
+    if std::env::args().any(|arg| arg == "--hash160") {
+        config.payout_hash_kind = HashKind::Hash160;
+    }
+    // With `--fee <base>,<ppm>` we keep base sats plus ppm millionths of what each user puts in, instead of 1000
+    // sats plus 1%
+    if let Some(fee) = arg_values("--fee").first() {
+        config.maker_fee = MakerFee::from_str(fee).or_exit("Invalid --fee");
+    }
+    // With `--denominate <step>` we run rounds of equal amounts, a multiple of step sats
+    if let Some(step) = arg_values("--denominate").first() {
+        config.denomination = Some(DenominationMode { step: step.parse().or_exit("--denominate must be a number") });
+    }
+    // With `--own-input` we put a utxo of our own in each users2maker contract, next to the users' ones
+    config.own_input = std::env::args().any(|arg| arg == "--own-input");
+    // With `--taproot` the contracts are taproot ones, for users running with `--taproot` too
+    config.taproot = std::env::args().any(|arg| arg == "--taproot");
+
+    let (treasury, chain, fee_estimator, seed) = maker_wallet(config.network);
+    // The demo chain doesn't know the made up coins of the users
+    config.lookup_user_utxos = backend_arg().is_some();
+    // The funding and refund txs pay what the `--electrum` server estimates, 1 sat/vB in the demo. With
+    // `--feerate <sat/vB>` they pay that instead.
+    config.fee_estimator = match arg_values("--feerate").first() {
+        Some(rate) => {
+            let rate: f32 = rate.parse().or_exit("--feerate must be a number");
+            if rate < 1.0 {
+                exit_with("--feerate must be at least 1 sat/vB");
+            }
+            Arc::new(FixedFeeRate(FeeRate::from_sat_per_vb(rate)))
+        },
+        None => fee_estimator,
+    };
+    // With `--bump-funding-after <secs>` we replace a funding tx still unconfirmed after that long, paying more
+    if let Some(secs) = arg_values("--bump-funding-after").first() {
+        let secs = secs.parse().or_exit("--bump-funding-after must be a number of seconds");
+        config.funding_bump_after = Some(Duration::from_secs(secs));
+    }
+    // With `--anyone-can-pay` users who also run with it sign their funding inputs with SIGHASH_ALL|ANYONECANPAY,
+    // so they may add inputs to the funding tx
+    config.anyone_can_pay = std::env::args().any(|arg| arg == "--anyone-can-pay");
+    // Our contract keys derive from the wallet seed under a session index counted in the state dir, so the seed
+    // and the recovery file of a round are enough to find them again. With `--random-contract-keys` they are
+    // random, and only the recovery file keeps them.
+    if !std::env::args().any(|arg| arg == "--random-contract-keys") {
+        let seed = seed.or_exit("A backend needs --contract-xprv, or --random-contract-keys");
+        let keys = KeyChain::new(seed, state_dir.clone().into()).or_exit("Can't read the contract key sessions");
+        config.contract_keys = ContractKeySource::Derived(keys);
+    }
+    treasury.sync(&chain).await.or_exit("Can't sync the maker wallet");
+    println!("Maker wallet balance: {} sats", treasury.balance());
+
+    // With `--rpc-listen <host>:<port>` (or `unix:<path>`) we answer JSON-RPC requests of the operator
+    // there. Its `shutdown` drains us: the round in progress finishes and we stop. Anyone reaching it
+    // can do so, as it has no authentication, so over TCP it must be on localhost.
+    let drain = config.shutdown.child_token();
+    if let Some(rpc) = arg_values("--rpc-listen").first() {
+        match ListenAddr::from_str(rpc).or_exit("Invalid --rpc-listen") {
+            ListenAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(&addr).await.or_exit(&format!("Can't listen on {addr}"));
+                let local = listener.local_addr().unwrap();
+                if !local.ip().is_loopback() {
+                    exit_with(format!("The control interface must only listen on localhost, not {local}"));
+                }
+                tokio::spawn(serve_control(listener, registry, drain.clone()));
+            },
+            ListenAddr::Unix(path) => {
+                let listener = bind_unix(&path).or_exit(&format!("Can't listen on {rpc}"));
+                tokio::spawn(serve_control(listener, registry, drain.clone()));
+            },
+        }
+        println!("Control interface on {rpc}");
     }
 
-    prv_keys
-}
+    // With `--tls-cert <pem file> --tls-key <pem file>` users connect over TLS instead of Noise
+    if let (Some(cert), Some(key)) = (arg_values("--tls-cert").first(), arg_values("--tls-key").first()) {
+        let ListenAddr::Tcp(addr) = &listen else { exit_with("TLS is only served over TCP") };
+        let listener = TcpListener::bind(addr).await.or_exit(&format!("Can't listen on {addr}"));
+        let public_addr = public_addr(&listen, Some(listener.local_addr().unwrap()));
 
-async fn send_second_contract_data(
-    maker_keys: Vec<&[PublicKey; 2]>,
-    txids: Vec<Txid>,
-    writers: &mut Vec<WriteHalf<TcpStream>>,
-) {
-    assert_eq!(maker_keys.len(), txids.len());
-    assert_eq!(maker_keys.len(), writers.len());
+        let cert = std::fs::read_to_string(cert).or_exit("Can't read the TLS certificate");
+        let key = std::fs::read_to_string(key).or_exit("Can't read the TLS key");
+        let acceptor = TlsAcceptor::new(listener, &cert, &key).or_exit("Invalid TLS certificate or key");
+        println!("Listening on {public_addr} (TLS)\n");
 
-    for ((key_pair, txid), mut writer) in maker_keys.iter().zip(txids).zip(writers) {
-        let keys_str = format!("{},{}", key_pair[0], key_pair[1]);
+        let config = MakerConfig { noise_key: None, ..config };
+        serve(acceptor, config.clone(), treasury, chain, lightning, events, drain).await;
+        return shut_down(printer, &config).await;
+    }
 
-        send_message(keys_str, &mut writer).await;
-        send_message(txid.to_string(), &mut writer).await;
+    // With `--noise-key <hex secret>` we use our own static key instead of the demo one
+    let noise_key = match arg_values("--noise-key").first() {
+        Some(hex) => SecretKey::from_str(hex).or_exit("The noise key must be 32 bytes in hex"),
+        None => demo_maker_key(),
+    };
+    let key = PublicKey::from_secret_key(&Secp256k1::new(), &noise_key);
+    let config = MakerConfig { noise_key: Some(noise_key), ..config };
+
+    match &listen {
+        ListenAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await.or_exit(&format!("Can't listen on {addr}"));
+            let address = MakerAddress { key, addr: public_addr(&listen, Some(listener.local_addr().unwrap())) };
+            println!("Listening on {address}\n");
+
+            serve(listener, config.clone(), treasury, chain, lightning, events, drain).await;
+        },
+        ListenAddr::Unix(path) => {
+            let listener = bind_unix(path).or_exit(&format!("Can't listen on {listen}"));
+            let address = MakerAddress { key, addr: public_addr(&listen, None) };
+            println!("Listening on {address}\n");
+
+            serve(listener, config.clone(), treasury, chain, lightning, events, drain).await;
+        },
     }
+    shut_down(printer, &config).await;
 }
 
-// The amount sent is fixed for now.
-fn build_second_funding(wallet: &Wallet<AnyDatabase>, pub_desc: &Descriptor<PublicKey>) -> Psbt {
-    let mut tx_builder = wallet.build_tx();
-
-    tx_builder.add_recipient(pub_desc.script_pubkey(), 45000);
+// With `--onion-hostname <file>` we are reached through the Tor hidden service whose hostname file is
+// given (HiddenServicePort forwarding to our listen address), and we publish its onion address.
+// `--onion-port <port>` sets the virtual port of the service if it isn't our listen port, so it's
+// needed when we listen on a Unix socket.
+fn public_addr(listen: &ListenAddr, local: Option<SocketAddr>) -> String {
+    let Some(file) = arg_values("--onion-hostname").first().cloned() else {
+        return local.map_or_else(|| listen.to_string(), |local| local.to_string());
+    };
+    if local.is_some_and(|local| !local.ip().is_loopback()) {
+        exit_with("Behind an onion service we must only listen on localhost");
+    }
 
-    let (psbt, _) = tx_builder.finish().unwrap();
+    let hostname = std::fs::read_to_string(file).or_exit("Can't read the onion hostname file");
+    let hostname = hostname.trim();
+    if !hostname.ends_with(".onion") {
+        exit_with(format!("{hostname} is not an onion address"));
+    }
 
-    psbt
+    let port = match arg_values("--onion-port").first() {
+        Some(port) => port.parse().or_exit("--onion-port must be a number"),
+        None => local.or_exit("Missing --onion-port for the Unix socket").port(),
+    };
+    format!("{hostname}:{port}")
 }
 
-fn gen_hash() -> ([u8; 32], sha256::Hash) {
-    let mut rng = thread_rng();
-    let mut bytes = [0u8; 32];
-    rng.fill(&mut bytes[..]);
-
-    let hash = sha256::Hash::hash(&bytes);
+// `serve` only returns once we are shutting down or drained, we let the last events print and exit
+async fn shut_down(printer: JoinHandle<()>, config: &MakerConfig) {
+    printer.await.unwrap();
+    std::process::exit(if config.shutdown.is_cancelled() { SHUTDOWN_EXIT_CODE } else { 0 });
+}
 
-    (bytes, hash)
+// Rounds run concurrently, each one on its own task with the next users that connect, until the shutdown
+// token of `base_config` is cancelled. Once `drain` (a child of it) is cancelled we take no new users and
+// stop after the rounds in progress.
+async fn serve<A>(
+    acceptor: A,
+    base_config: MakerConfig,
+    treasury: MakerTreasury,
+    chain: Arc<dyn ChainAccess>,
+    lightning: MakerLightning,
+    events: RoundEvents,
+    drain: CancellationToken,
+) where
+    A: Acceptor,
+    A::Stream: 'static,
+{
+    // Users resuming their session or joining a second leg go to their round, the rest wait in the pool
+    // for the next one, as do the users of a round another user declined. Users handshake concurrently,
+    // so one that stays silent doesn't keep the others waiting.
+    let router = RoundRouter::new();
+    let mut rematches = router.rematches();
+    let mut handshakes = FuturesUnordered::new();
+    let mut rounds = JoinSet::new();
+    let mut round = 1;
+    let mut pool: Vec<PoolEntry<A::Stream>> = Vec::new();
+    events.emit(round, ProtocolEvent::PhaseEntered(Phase::Connect));
+    // Users in the pool are told every interval how many users their round lacks, which also keeps
+    // their connections alive
+    let interval = base_config.keepalive.interval;
+    let mut status_ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+
+    while !(drain.is_cancelled() && rounds.is_empty()) {
+        // The pool is in order of arrival, so the first user is the next one to run out of time
+        let timed_out = pool.first().map(|entry| entry.joined + base_config.matchmaking_timeout);
+        tokio::select! {
+            accepted = accept_gated(&acceptor, &base_config) => match accepted {
+                Ok((conn, slot)) => handshakes.push(handshake(conn, slot, &base_config, &router)),
+                Err(e) => events.emit(round, ProtocolEvent::Aborted { code: AbortCode::PeerGone, reason: e.to_string() }),
+            },
+            Some((conn, slot, answered)) = handshakes.next() => match answered {
+                Ok(arrival) => if let Some((conn, slot)) = router.route(arrival, (conn, slot)) {
+                    join_pool(&mut pool, conn.into(), slot, &base_config, &drain, &events, round).await;
+                },
+                Err(e) => events.emit(round, ProtocolEvent::Aborted { code: e.abort_code(), reason: e.to_string() }),
+            },
+            // Back from a declined round, they count as pending again
+            Some(mut user) = rematches.recv() => match base_config.gate.admit(None) {
+                Ok(slot) => join_pool(&mut pool, user, slot, &base_config, &drain, &events, round).await,
+                Err(refusal) => {
+                    let _ = send_error(AbortCode::Refused, &refusal, &mut user.conn.writer).await;
+                },
+            },
+            Some(_) = rounds.join_next() => {},
+            _ = status_ticks.tick() => report_waiting(&mut pool, &base_config, &events, round).await,
+            _ = sleep_until(timed_out.unwrap_or_else(Instant::now)), if timed_out.is_some() => {
+                report_waiting(&mut pool, &base_config, &events, round).await;
+            },
+            _ = drain.cancelled(), if !pool.is_empty() => {
+                for entry in &mut pool {
+                    let _ = send_error(AbortCode::Shutdown, &SHUTTING_DOWN, &mut entry.user.conn.writer).await;
+                }
+                pool.clear();
+                events.emit(round, ProtocolEvent::Aborted { code: AbortCode::Shutdown, reason: "Shutting down".to_string() });
+            },
+        }
+
+        if pool.len() == base_config.round_users {
+            let peers = pool.drain(..).map(|entry| entry.user).collect();
+            rounds.spawn(run_round(
+                base_config.clone(),
+                treasury.clone(),
+                chain.clone(),
+                lightning.clone(),
+                peers,
+                router.inbox(),
+                events.sink(round),
+            ));
+            round += 1;
+            events.emit(round, ProtocolEvent::PhaseEntered(Phase::Connect));
+        }
+    }
 }
 
-async fn read_second_user_data(reader: &mut BufReader<ReadHalf<TcpStream>>) -> (PublicKey, PublicKey) {
-    let keys = read_contract_keys(reader, 2).await;
+// A user waiting for its round to fill up. It counts as pending until the round starts.
+struct PoolEntry<S> {
+    user: RoundUser<S>,
+    _slot: PendingSlot,
+    joined: Instant,
+}
 
-    (keys[0], keys[1])
+// Once draining we take no new users
+async fn join_pool<S: AsyncRead + AsyncWrite>(
+    pool: &mut Vec<PoolEntry<S>>,
+    mut user: RoundUser<S>,
+    slot: PendingSlot,
+    config: &MakerConfig,
+    drain: &CancellationToken,
+    events: &RoundEvents,
+    round: u64,
+) {
+    if drain.is_cancelled() {
+        let _ = send_error(AbortCode::Shutdown, &SHUTTING_DOWN, &mut user.conn.writer).await;
+        return;
+    }
+    events.emit(round, ProtocolEvent::PeerConnected { leg: Leg::First, index: pool.len() });
+    pool.push(PoolEntry { user, _slot: slot, joined: Instant::now() });
+    if pool.len() < config.round_users {
+        report_waiting(pool, config, events, round).await;
+    }
 }
 
-async fn send_psbt(psbt: &Psbt, writers: &mut Vec<WriteHalf<TcpStream>>) {
-    let serialized_psbt = serde_json::to_string(&psbt).unwrap();
+// Sends away the users that waited longer than `matchmaking_timeout`, nothing is signed yet, and tells
+// the rest how many users their round lacks. Those we can't reach anymore are dropped, the others
+// learn the right count on the next report.
+async fn report_waiting<S: AsyncRead + AsyncWrite>(
+    pool: &mut Vec<PoolEntry<S>>,
+    config: &MakerConfig,
+    events: &RoundEvents,
+    round: u64,
+) {
+    let before = pool.len();
+    let mut waiting = Vec::new();
+    for mut entry in pool.drain(..) {
+        match entry.joined.elapsed() < config.matchmaking_timeout {
+            true => waiting.push(entry),
+            false => {
+                let _ = send_error(AbortCode::NoRound, &NO_ROUND, &mut entry.user.conn.writer).await;
+            },
+        }
+    }
+    let missing = config.round_users - waiting.len();
+    for mut entry in waiting {
+        if send_waiting(missing, &mut entry.user.conn.writer).await.is_ok() {
+            pool.push(entry);
+        }
+    }
 
-    for mut writer in writers {
-        send_message(serialized_psbt.to_string(), &mut writer).await;
+    if pool.len() < before {
+        events.emit(round, ProtocolEvent::Aborted {
+            code: AbortCode::NoRound,
+            reason: format!("{} users left or waited too long for the round", before - pool.len()),
+        });
+    }
+    if !pool.is_empty() {
+        events.emit(round, ProtocolEvent::WaitingForUsers { missing: config.round_users - pool.len() });
     }
 }
 
-async fn send_contract_data(
-    keys: &[PublicKey; 9],
-    hash: sha256::Hash,
-    funding: &Psbt,
-    refund: &Psbt,
-    writers: &mut Vec<WriteHalf<TcpStream>>,
+// A misbehaving or disconnected user only aborts its own round
+async fn run_round<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    config: MakerConfig,
+    treasury: MakerTreasury,
+    chain: Arc<dyn ChainAccess>,
+    lightning: MakerLightning,
+    peers: Vec<RoundUser<S>>,
+    inbox: RoundInbox<S>,
+    events: EventSink,
 ) {
-    let serialized_funding = serde_json::to_string(&funding).unwrap();
-    let serialized_refund = serde_json::to_string(&refund).unwrap();
-
-    let keys_str = format!(
-        "{},{},{},{},{},{},{},{},{}",
-        keys[0], keys[1], keys[2], keys[3], keys[4], keys[5], keys[6], keys[7], keys[8]);
+    let result = run_maker_round(config, &treasury, chain, lightning, peers, inbox, events.clone()).await;
+
+    match result {
+        Ok(report) => {
+            let claims = claims_note(&report.maker2user_claims);
+            match report.status {
+                RoundStatus::Completed => events.emit(ProtocolEvent::Completed { profit: report.profit }),
+                RoundStatus::HashlockKeysRejected(mismatch) => events.emit(ProtocolEvent::Aborted {
+                    code: AbortCode::KeyMismatch,
+                    reason: format!("{mismatch}{claims}"),
+                }),
+                RoundStatus::PeerAborted { leg, index, code, reason } => events.emit(ProtocolEvent::Aborted {
+                    code,
+                    reason: format!("User {} failed: {reason}{claims}", peer_name(leg, index)),
+                }),
+            }
+        },
+        Err(JoinSwapError::Peer { leg, index, error }) => events.emit(ProtocolEvent::Aborted {
+            code: error.abort_code(),
+            reason: format!("User {} failed: {error}", peer_name(leg, index)),
+        }),
+        Err(e) => events.emit(ProtocolEvent::Aborted { code: e.abort_code(), reason: e.to_string() }),
+    }
+}
 
-    for mut writer in writers {
-        send_message(keys_str.clone(), &mut writer).await;
-        send_message(hash.to_string(), &mut writer).await;
-        send_message(serialized_funding.clone(), &mut writer).await;
-        send_message(serialized_refund.clone(), &mut writer).await;
+// The maker2user claims of a failed round, which take our coins back once their timelocks expire
+fn claims_note(claims: &[Transaction]) -> String {
+    if claims.is_empty() {
+        return String::new();
     }
+    let txids: Vec<_> = claims.iter().map(|tx| tx.txid().to_string()).collect();
+
+    format!(", maker2user claims {} can be broadcast once their timelocks expire", txids.join(", "))
 }
 
-async fn read_user_data(
-    reader: &mut BufReader<ReadHalf<TcpStream>>
-) -> ((PublicKey, PublicKey, PublicKey), WeightedUtxo, Address) {
-    let keys = read_contract_keys(reader, 3).await;
-    let weighted = read_utxo_data(reader).await;
-    let addr = read_refund(reader).await;
+// Answers the hello of a new connection, telling us where it goes
+async fn handshake<S: AsyncRead + AsyncWrite>(
+    mut conn: Connection<S>,
+    slot: PendingSlot,
+    config: &MakerConfig,
+    router: &RoundRouter<S>,
+) -> (Connection<S>, PendingSlot, Result<Arrival, ProtocolError>) {
+    let answered = answer_hello(&mut conn, config, router).await;
 
-    ((keys[0], keys[1], keys[2]), weighted, addr)
+    (conn, slot, answered)
 }
 
-async fn read_and_combine_psbt(
-    readers: &mut Vec<BufReader<ReadHalf<TcpStream>>>,
-    txid: Option<Txid>,
-) -> Psbt {
-    assert_eq!(readers.len(), 2);
+// Where the events of every round go, tagged with the round they come from
+#[derive(Clone)]
+struct RoundEvents(UnboundedSender<(u64, ProtocolEvent)>);
 
-    let mut signed_psbts = Vec::new();
-    for mut reader in readers {
-        let signed_psbt = read_psbt(&mut reader, txid).await;
-        signed_psbts.push(signed_psbt);
+impl RoundEvents {
+    fn channel() -> (RoundEvents, UnboundedReceiver<(u64, ProtocolEvent)>) {
+        let (sender, receiver) = unbounded_channel();
+
+        (RoundEvents(sender), receiver)
     }
-    let mut final_psbt = signed_psbts[0].clone();
-    final_psbt.combine(signed_psbts[1].clone()).unwrap();
 
-    final_psbt
-}
+    fn emit(&self, round: u64, event: ProtocolEvent) {
+        let _ = self.0.send((round, event));
+    }
 
-async fn accept_connection(listener: &TcpListener) -> (BufReader<ReadHalf<TcpStream>>, WriteHalf<TcpStream>) {
-    let (socket, _) = listener.accept().await.unwrap();
-    let (reader, writer) = split(socket);
-    let reader = BufReader::new(reader);
+    // The sink a round emits its events to, which we tag and pass on until the round drops it
+    fn sink(&self, round: u64) -> EventSink {
+        let (sink, mut receiver) = EventSink::channel();
+        let events = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                events.emit(round, event);
+            }
+        });
+
+        sink
+    }
+}
 
-    (reader, writer)
+async fn print_events(
+    mut receiver: UnboundedReceiver<(u64, ProtocolEvent)>,
+    registry: MakerRegistry,
+    round_users: usize,
+) {
+    // Like A/B for the first leg users and X/Y for the second leg ones
+    let names = |leg| (0..round_users).map(|index| peer_name(leg, index)).collect::<Vec<_>>().join("/");
+    let (first, second) = (names(Leg::First), names(Leg::Second));
+
+    // Rounds run at once, so each line tells which one it comes from
+    while let Some((round, event)) = receiver.recv().await {
+        registry.apply(round, &event);
+        macro_rules! say {
+            ($($arg:tt)*) => { println!("#{round} {}", format_args!($($arg)*)) };
+        }
+        match event {
+            ProtocolEvent::PhaseEntered(phase) => match phase {
+                Phase::Connect => say!("CONNECTIONS 👉👈\n"),
+                Phase::ContractCreation => say!("CONTRACT CREATION 🐸\n"),
+                Phase::SecondConnect => say!("CONNECTIONS, SECOND PART 👉👈\n"),
+                Phase::SecondContractCreation => say!("SECOND CONTRACT CREATION 🐸\n"),
+                Phase::Handover => say!("PRIVATE KEYS HANDOVER 😎🤝😎\n"),
+            },
+            ProtocolEvent::WaitingForUsers { missing } => {
+                say!("Waiting for the round to fill up ({missing} missing) ⏳\n");
+            },
+            ProtocolEvent::PeerConnected { leg, index } => {
+                say!("New connection <-----------------> User {}", peer_name(leg, index));
+            },
+            ProtocolEvent::SessionResumed { index: Some(index) } => {
+                say!("Resumed session <----------------> User {}", peer_name(Leg::First, index));
+            },
+            ProtocolEvent::Rematched { index: Some(index) } => {
+                say!("Back to matchmaking <------------- User {}", peer_name(Leg::First, index));
+            },
+            ProtocolEvent::MessageSent(kind) => match kind {
+                MessageKind::ContractData => say!("Contract data -------------------> Users ({first})"),
+                MessageKind::FundingAndRefund => say!("Funding and Refund Tx -----------> Users ({first})\n"),
+                MessageKind::FinalizedRefund => say!("Finalized Refund Tx -------------> Users ({first})\n"),
+                MessageKind::FinalizedFunding => say!("Finalized Funding Tx ------------> Users ({first})\n"),
+                MessageKind::FundingBump => say!("Funding Tx Replacement ----------> Users ({first})\n"),
+                MessageKind::InputAdded => say!("Funding Tx with added inputs ----> Users ({first})\n"),
+                MessageKind::SecondContractData => say!("Maker2users contract + TxIDs ----> Users ({second})\n"),
+                MessageKind::PreimageAndKey => say!("Maker2users contract PrvKeys ----> Users ({second})"),
+                _ => {},
+            },
+            ProtocolEvent::MessageReceived(kind) => match kind {
+                MessageKind::UserData => say!("User data <----------------------- Users ({first})\n"),
+                MessageKind::SignedRefund => say!("Signed Refund PSBTs <------------- Users ({first})"),
+                MessageKind::SignedFunding => say!("Signed Funding PSBTs <------------ Users ({first})"),
+                MessageKind::SecondUserData => say!("User data <----------------------- Users ({second})\n"),
+                MessageKind::HashlockKey => say!("Users2maker hashlock PrvKeys <---- Users ({first})"),
+                MessageKind::ContractKey => say!("Users2maker contract PrvKeys <---- Users ({first})"),
+                MessageKind::AddInput => say!("Added funding inputs <------------ Users ({first})"),
+                _ => {},
+            },
+            ProtocolEvent::ContractCreated { contract, address } => match contract {
+                ContractKind::Users2Maker => say!("Users-to-maker contract address:\n{address}\n"),
+                ContractKind::Maker2User(index) => say!(
+                    "Maker-to-user {} contract address:\n{address}\n", peer_name(Leg::Second, index)),
+            },
+            ProtocolEvent::Broadcast { role, .. } => match role {
+                TxRole::Funding => say!("Broadcast Funding Tx\n"),
+                TxRole::Maker2UserFunding(index) => say!(
+                    "Broadcast maker-to-user {} transaction", peer_name(Leg::Second, index)),
+                TxRole::Users2MakerSweep => say!("Broadcast users-to-maker contract sweep"),
+                // Only users broadcast these
+                TxRole::Maker2UserSweep | TxRole::Refund => {},
+            },
+            ProtocolEvent::InvoicePaid { index, amount } => {
+                say!("Paid {amount} sats Lightning invoice of User {}", peer_name(Leg::Second, index));
+            },
+            ProtocolEvent::Completed { profit } => {
+                say!("Succesful JoinSwap! Maker earned {} sats", profit.unwrap_or_default());
+            },
+            ProtocolEvent::Aborted { reason, .. } => say!("JoinSwap aborted: {reason}"),
+            _ => {},
+        }
+    }
 }
 
-async fn read_utxo_data(reader: &mut BufReader<ReadHalf<TcpStream>>) -> WeightedUtxo {
-    let mut line = read_message(reader).await;
-    let desc = Descriptor::<PublicKey>::from_str(&line.trim()).unwrap();
+// First leg users are A, B... and second leg users X, Y, Z and then X4, X5...
+fn peer_name(leg: Leg, index: usize) -> String {
+    match leg {
+        Leg::First => ((b'A' + index as u8) as char).to_string(),
+        Leg::Second if index < 3 => ((b'X' + index as u8) as char).to_string(),
+        Leg::Second => format!("X{}", index + 1),
+    }
+}
 
-    line = read_message(reader).await;
-    let outpoint = OutPoint::from_str(&line.trim()).unwrap();
+// The backend picked with `--<name> <url>`, and its url
+fn backend_arg() -> Option<(&'static str, String)> {
+    ["electrum", "esplora", "bitcoind"].into_iter().find_map(|name| {
+        Some((name, arg_values(&format!("--{name}")).first()?.clone()))
+    })
+}
 
-    line = read_message(reader).await;
-    let psbt_in: psbt::Input = serde_json::from_str(&line.trim()).unwrap();
+// Lightning payouts go through the node picked with `--ln cln|lnd` (if compiled with the feature).
+// For the demo, `--ln-invoice <bolt11>,<amount>,<preimage>` makes up invoices we can "pay" instead.
+// With `--electrum <url>` (or `--esplora <url>`, or `--bitcoind <url>`) the wallet of `--descriptor <desc>` (and
+// `--change-descriptor <desc>`) funds the rounds, kept in the `--wallet-db <path>` sled database or in memory. Without
+// it a demo wallet with made up coins is used, and nothing leaves this process. Also the seed our contract keys derive
+// from, that of the demo wallet or the `--contract-xprv <xprv>` with a backend. The backend estimates our feerates too.
+// We log in to bitcoind with its `--bitcoind-cookie <path>`, or with `--bitcoind-auth <user>:<password>`.
+fn maker_wallet(
+    network: Network,
+) -> (MakerTreasury, Arc<dyn ChainAccess>, Arc<dyn FeeEstimator>, Option<ExtendedPrivKey>) {
+    let Some((backend, url)) = backend_arg() else {
+        let seed = demo_seed();
+        let database = AnyDatabase::Memory(MemoryDatabase::new());
+        let wallet = Wallet::new(&get_descriptors(&seed), None, network, database).unwrap();
+        let chain = MemoryChain::default();
+        chain.fund(&wallet.get_address(AddressIndex::New).unwrap().script_pubkey(), &[100_000; 10]);
+
+        let fee_estimator = FixedFeeRate(FeeRate::from_sat_per_vb(1.0));
+
+        return (MakerTreasury::new(wallet), Arc::new(chain), Arc::new(fee_estimator), Some(seed));
+    };
+    let seed = arg_values("--contract-xprv").first()
+        .map(|xprv| ExtendedPrivKey::from_str(xprv).or_exit("Invalid --contract-xprv"));
+    let descriptor = arg_values("--descriptor").first().cloned().or_exit("A backend needs --descriptor");
+    let change_descriptor = arg_values("--change-descriptor").first().cloned();
+    let database = match arg_values("--wallet-db").first() {
+        Some(path) => {
+            let tree = sled::open(path).and_then(|db| db.open_tree("maker")).or_exit("Can't open --wallet-db");
+            AnyDatabase::Sled(tree)
+        },
+        None => AnyDatabase::Memory(MemoryDatabase::new()),
+    };
+    let wallet = Wallet::new(&descriptor, change_descriptor.as_ref(), network, database);
+    let wallet = wallet.or_exit("Invalid --descriptor");
+    let rpc_auth = match (arg_values("--bitcoind-cookie").first(), arg_values("--bitcoind-auth").first()) {
+        (Some(path), _) => Some(RpcAuth::Cookie(path.into())),
+        (None, Some(auth)) => {
+            let (user, password) = auth.split_once(':').or_exit("--bitcoind-auth must be <user>:<password>");
+            Some(RpcAuth::UserPass { user: user.to_string(), password: password.to_string() })
+        },
+        (None, None) => None,
+    };
+    let connected = connect_backend(backend, &url, rpc_auth);
+    let ChainBackend { chain, fee_estimator } = connected.or_exit(&format!("Can't connect to --{backend}"));
+
+    (MakerTreasury::new(wallet), chain, fee_estimator, seed)
+}
 
-    assert_eq!(
-        psbt_in.witness_utxo.as_ref().unwrap().script_pubkey,
-        desc.script_pubkey(),
-        "The descriptor needs to match the utxo");
+fn ln_backend() -> Option<Arc<dyn LnBackend>> {
+    match arg_values("--ln").first().map(String::as_str) {
+        #[cfg(feature = "cln")]
+        Some("cln") => return Some(Arc::new(joinswap::lightning::ClnBackend::default())),
+        #[cfg(feature = "lnd")]
+        Some("lnd") => return Some(Arc::new(joinswap::lightning::LndBackend::default())),
+        Some(other) => exit_with(format!("Lightning backend '{other}' is not available")),
+        None => {},
+    }
 
-    WeightedUtxo {
-        satisfaction_weight: desc.max_satisfaction_weight().unwrap(),
-        utxo: Utxo::Foreign { outpoint, psbt_input: Box::new(psbt_in) },
+    let invoices = arg_values("--ln-invoice");
+    if invoices.is_empty() {
+        return None;
     }
+    let backend = MemoryLnBackend::default();
+    for invoice in invoices {
+        let parts: Vec<&str> = invoice.split(',').collect();
+        if parts.len() != 3 {
+            exit_with("--ln-invoice must be <bolt11>,<amount>,<preimage>");
+        }
+
+        let amount = parts[1].parse().or_exit("The --ln-invoice amount must be a number");
+        backend.add_invoice(parts[0].to_string(), amount, parse_preimage(parts[2]));
+    }
+
+    Some(Arc::new(backend))
 }
 
-async fn read_refund(reader: &mut BufReader<ReadHalf<TcpStream>>) -> Address {
-    let line = read_message(reader).await;
+fn parse_preimage(hex: &str) -> [u8; 32] {
+    <[u8; 32]>::from_hex(hex).or_exit("The preimage must be 32 bytes in hex")
+}
+
+fn arg_values(name: &str) -> Vec<String> {
+    let args: Vec<String> = std::env::args().collect();
 
-    Address::from_str(&line.trim()).unwrap()
-}
\ No newline at end of file
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
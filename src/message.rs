@@ -0,0 +1,1581 @@
+// The wire protocol. Every message is a single frame of JSON, an object whose `type` field names the
+// message (`{"type":"user_keys","keys":[...]}`). Each step of the protocol expects one message,
+// anything else (but an abort) drops the peer.
+
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bdk::bitcoin::{psbt, EcdsaSig, Network, SchnorrSig, Txid, Witness};
+use bdk::bitcoin::hashes::hex::{FromHex, ToHex};
+use bdk::bitcoin::hashes::sha256;
+use bdk::bitcoin::psbt::Psbt;
+use bdk::bitcoin::secp256k1::ecdsa::Signature;
+use bdk::bitcoin::secp256k1::{Parity, PublicKey, XOnlyPublicKey};
+use bdk::bitcoin::util::taproot::TapLeafHash;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use tokio::io::{AsyncBufRead, AsyncWrite};
+use tokio::time::{timeout, Instant};
+
+use crate::error::{ProtocolError, PsbtReadError};
+use crate::events::AbortCode;
+use crate::transport::{FramedReader, FramedWriter};
+use crate::{signing, verify_finalized_input, verify_partial_sigs, FeeRates, HashKind, Keepalive, MakerFee,
+            PathThresholds, SigStatus, TimelockRange, Timelocks, REFUND_LADDER};
+
+// Keys, addresses and hashes are sent as text in the usual formats (hex pub keys and hashes, WIF
+// private keys, addresses, descriptors and `<txid>:<vout>` outpoints) and checked after decoding
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    // First message of every connection, the user sends it and the maker answers with its own
+    Hello(Box<Hello>),
+    // Maker to a user joining a round, its terms right after the hellos. The user takes them or leaves.
+    Offer(Offer),
+    Accept(Accept),
+    // User to maker, first leg
+    UserKeys(UserKeys),
+    UtxoData(Box<UtxoData>),
+    RefundAddress(RefundAddress),
+    HashSource(HashSourceData),
+    // Maker to users, first leg
+    ContractData(Box<ContractData>),
+    // Either way: users sign, the maker sends them back combined and finalized
+    SignedPsbt(Box<SignedPsbt>),
+    // The same, as the signatures alone
+    SigBundle(SigBundle),
+    // User to maker, second leg
+    PayoutRequest(PayoutRequestData),
+    // Maker to user, second leg
+    SecondContractData(SecondContractData),
+    LightningPayout(LightningPayout),
+    PreimageHandover(PreimageHandover),
+    // Users hand over their contract keys with their first leg identity
+    PrivKeyHandover(PrivKeyHandover),
+    // Maker to the users waiting for their round to fill up, only if they announced `WAITING_STATUS`
+    Waiting(Waiting),
+    // Maker to users holding the finalized funding tx, only with `FUNDING_BUMPS`: it replaces the funding
+    // tx, or keeps it
+    FundingBump(Box<FundingBump>),
+    FundingKept(FundingKept),
+    // User to maker instead of its signed funding psbt, only with `ANYONE_CAN_PAY`: a utxo it adds to the
+    // funding tx. The maker answers every user with the funding tx holding the added inputs.
+    AddInput(Box<AddInput>),
+    InputAdded(Box<InputAdded>),
+    // User to maker, any time before it sends its signed funding psbt: it leaves the round
+    Decline(Decline),
+    // Maker to the users left in a round another user declined
+    Rematch(Rematch),
+    // Last message to a peer we drop, telling it why
+    Abort(Abort),
+    // Either way, to keep idle connections alive. A ping is answered with a pong.
+    Ping(Ping),
+    Pong(Pong),
+}
+
+// After the hellos every message is sent in this frame, with the session nonce and its number in
+// the session (from 0 on each side), so it can't be replayed in another session or out of order.
+// Once a peer has its contract keys it also signs the nonce, number and exact bytes of `message`,
+// which are checked before we parse them.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SessionFrame<'a> {
+    session: String,
+    seq: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sig: Option<String>,
+    #[serde(borrow)]
+    message: &'a RawValue,
+}
+
+// What a session frame adds to the message in it, at most
+const SESSION_FRAME_OVERHEAD: usize = 400;
+
+// Unknown fields and features are ignored, so newer peers can announce more
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    pub network: String,
+    // Users in each round, the contracts are built for exactly this many
+    pub round_users: usize,
+    pub features: Vec<String>,
+    // Random 32 bytes in hex, both nonces make the session nonce that signed messages commit to
+    #[serde(default)]
+    pub nonce: String,
+    // Sent by a user coming back after its connection dropped, see `signing::resume_token`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume: Option<String>,
+    // Sent by a user joining the second leg, the funding txid of its round. The maker may be running
+    // several rounds at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub round: Option<String>,
+}
+
+// The amounts a maker takes from each user, the fee it keeps out of them and the confirmations and timelocks
+// of its contracts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Offer {
+    pub network: String,
+    pub min_amount: u64,
+    pub max_amount: u64,
+    pub fee: MakerFee,
+    // Confirmations of the funding tx the maker waits for before the second leg
+    pub confirmations: u32,
+    pub refund_timelocks: TimelockRange,
+    pub maker2user_timelocks: TimelockRange,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Accept {}
+
+// Our contract keys: 3 compressed pub keys for the users2maker contract, 3 for the maker2user one (see
+// `maker2users_contract_desc`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserKeys {
+    pub keys: Vec<String>,
+}
+
+// The utxos the user brings to the round, from one to `MAX_USER_UTXOS`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UtxoData {
+    pub utxos: Vec<UserUtxo>,
+    // Only if we agreed on `PARTIAL_SPENDS`, without it the utxos are fully spent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change: Option<ChangeRequest>,
+}
+
+// The PSBT input carries the full previous tx of the utxo
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserUtxo {
+    pub descriptor: String,
+    pub outpoint: String,
+    pub psbt_input: psbt::Input,
+}
+
+// The sats the user puts in the contract out of its utxos. The rest, minus its share of the funding
+// fee, comes back to `address`, or goes to the contract too if it's dust and `fold_dust` is set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChangeRequest {
+    pub contribution: u64,
+    pub address: String,
+    pub fold_dust: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RefundAddress {
+    pub address: String,
+}
+
+// `maker` or `invoice:<payment hash>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HashSourceData {
+    pub source: String,
+}
+
+// The contract keys (see `contract_keys_by_path`), the contract hash, the refund address of each user
+// of the round, the funding and refund PSBTs to sign and what each user gets paid on the second leg.
+// Users are listed in a random order, the same for the keys and the addresses.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContractData {
+    pub keys: Vec<String>,
+    pub hash: String,
+    // Of the users2maker contract descriptor, which users must build alike (see `desc_checksum`)
+    pub checksum: String,
+    pub refund_addresses: Vec<String>,
+    pub funding: WirePsbt,
+    pub refund: WirePsbt,
+    // The refund at each of the `REFUND_LADDER` multiples of its fee, as far as the maker could build them
+    pub refund_ladder: Vec<WirePsbt>,
+    // What the user gets paid on the second leg: what it put in minus the maker fee. Only in rounds of
+    // equal amounts is it the same for every user, otherwise it tells the maker which second leg user is
+    // which.
+    pub payout: u64,
+    // Only in rounds of equal amounts, see `DENOMINATIONS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub denomination: Option<Denomination>,
+    // Participants each contract path needs, every one if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thresholds: Option<PathThresholds>,
+    // Of the refund path and of the maker2user contracts, within what the offer advertised
+    pub timelocks: Timelocks,
+    // The funding and refund txs pay these, see `FeeRates`
+    pub fee_rates: FeeRates,
+    // Where the refund tx pays the maker back, only if it puts a utxo of its own in the contract
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maker_refund_address: Option<String>,
+}
+
+// What each user puts in the contract in a round of equal amounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Denomination {
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignedPsbt {
+    pub psbt: WirePsbt,
+}
+
+// The signatures of a PSBT the receiver already holds, instead of the whole PSBT again. PSBTs go out
+// unsigned, so every signature in it is one the receiver lacks. Only sent to peers that offer it in
+// their hello, a `SignedPsbt` is taken in its place.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigBundle {
+    // Txid of the unsigned tx, which tells the PSBT the signatures go into
+    pub txid: String,
+    pub inputs: Vec<InputSigs>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputSigs {
+    pub index: usize,
+    // Pub keys and their signatures (DER plus the sighash byte), in hex
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub partial_sigs: Vec<(String, String)>,
+    // On taproot inputs, x-only pub keys, the leaf hash they sign for and their signatures, in hex
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tap_script_sigs: Vec<(String, String, String)>,
+    // Witness items in hex, for an input the signer finalized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_script_witness: Option<Vec<String>>,
+}
+
+impl SigBundle {
+    pub fn from_psbt(psbt: &Psbt) -> Self {
+        let inputs = psbt.inputs.iter().enumerate()
+            .filter(|(_, input)| {
+                let signed = !input.partial_sigs.is_empty() || !input.tap_script_sigs.is_empty();
+                signed || input.final_script_witness.is_some()
+            })
+            .map(|(index, input)| InputSigs {
+                index,
+                partial_sigs: input.partial_sigs.iter()
+                    .map(|(key, sig)| (key.to_string(), sig.to_vec().to_hex()))
+                    .collect(),
+                tap_script_sigs: input.tap_script_sigs.iter()
+                    .map(|((key, leaf_hash), sig)| (key.to_string(), leaf_hash.to_string(), sig.to_vec().to_hex()))
+                    .collect(),
+                final_script_witness: input.final_script_witness.as_ref()
+                    .map(|witness| witness.iter().map(|item| item.to_hex()).collect()),
+            })
+            .collect();
+
+        SigBundle { txid: psbt.unsigned_tx.txid().to_string(), inputs }
+    }
+
+    pub fn txid(&self) -> Result<Txid, PsbtReadError> {
+        Txid::from_str(&self.txid).map_err(|e| PsbtReadError::Decode(format!("Invalid bundle txid: {e}")))
+    }
+
+    // `held` with our signatures, each one checked as it goes in
+    pub fn apply(self, held: &Psbt) -> Result<Psbt, PsbtReadError> {
+        let (expected, actual) = (held.unsigned_tx.txid(), self.txid()?);
+        if actual != expected {
+            return Err(PsbtReadError::TxidMismatch { expected, actual });
+        }
+        let decode = |what: &str| PsbtReadError::Decode(format!("Invalid {what} in the signature bundle"));
+
+        let mut psbt = held.clone();
+        for InputSigs { index, partial_sigs, tap_script_sigs, final_script_witness } in self.inputs {
+            let input = psbt.inputs.get_mut(index).ok_or_else(|| decode("input index"))?;
+            let mut keys = Vec::new();
+            for (key, sig) in partial_sigs {
+                let key = bdk::bitcoin::PublicKey::from_str(&key).map_err(|_| decode("pub key"))?;
+                let sig = Vec::from_hex(&sig).ok().and_then(|sig| EcdsaSig::from_slice(&sig).ok())
+                    .ok_or_else(|| decode("signature"))?;
+                input.partial_sigs.insert(key, sig);
+                keys.push(key);
+            }
+            for (key, leaf_hash, sig) in tap_script_sigs {
+                let key = XOnlyPublicKey::from_str(&key).map_err(|_| decode("x-only pub key"))?;
+                let leaf_hash = TapLeafHash::from_str(&leaf_hash).map_err(|_| decode("leaf hash"))?;
+                let sig = Vec::from_hex(&sig).ok().and_then(|sig| SchnorrSig::from_slice(&sig).ok())
+                    .ok_or_else(|| decode("signature"))?;
+                input.tap_script_sigs.insert((key, leaf_hash), sig);
+                // Checked as the key with an even y, which tapscript takes the same
+                keys.push(bdk::bitcoin::PublicKey::new(key.public_key(Parity::Even)));
+            }
+            if let Some(witness) = final_script_witness {
+                let items = witness.iter().map(|item| Vec::from_hex(item)).collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| decode("witness"))?;
+                input.final_script_witness = Some(Witness::from_vec(items));
+                verify_finalized_input(&psbt, index)
+                    .map_err(|reason| PsbtReadError::InvalidInput { input: index, reason })?;
+            }
+
+            let statuses = verify_partial_sigs(&psbt, index, &keys).map_err(PsbtReadError::Unverifiable)?;
+            if let Some((key, status)) = statuses.into_iter().find(|(_, status)| *status != SigStatus::Valid) {
+                return Err(PsbtReadError::BadSignature { input: index, key, status });
+            }
+        }
+
+        Ok(psbt)
+    }
+}
+
+// A signed PSBT as it came from the peer, whole or as the signatures for the one we hold
+pub enum SignedUpdate {
+    Psbt(Psbt),
+    Sigs(SigBundle),
+}
+
+impl SignedUpdate {
+    pub fn txid(&self) -> Result<Txid, PsbtReadError> {
+        match self {
+            SignedUpdate::Psbt(psbt) => Ok(psbt.unsigned_tx.txid()),
+            SignedUpdate::Sigs(bundle) => bundle.txid(),
+        }
+    }
+
+    // The signed version of `held`, which must be for the same tx
+    pub fn into_psbt(self, held: &Psbt) -> Result<Psbt, PsbtReadError> {
+        let psbt = match self {
+            SignedUpdate::Psbt(psbt) => psbt,
+            SignedUpdate::Sigs(bundle) => return bundle.apply(held),
+        };
+        let (expected, actual) = (held.unsigned_tx.txid(), psbt.unsigned_tx.txid());
+        if actual != expected {
+            return Err(PsbtReadError::TxidMismatch { expected, actual });
+        }
+        Ok(psbt)
+    }
+}
+
+// How we send PSBTs. Either one is accepted from peers, base64 is only sent to peers that offer it
+// in their hello.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PsbtEncoding {
+    // The JSON of the BDK struct, which only this implementation understands
+    #[default]
+    Json,
+    // A standard BIP-174 base64 string, which other wallets can inspect and sign
+    Base64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum WirePsbt {
+    Base64(String),
+    Json(Box<Psbt>),
+}
+
+impl WirePsbt {
+    pub fn encode(psbt: &Psbt, encoding: PsbtEncoding) -> Self {
+        match encoding {
+            PsbtEncoding::Json => WirePsbt::Json(Box::new(psbt.clone())),
+            PsbtEncoding::Base64 => WirePsbt::Base64(psbt.to_string()),
+        }
+    }
+
+    pub fn decode(self) -> Result<Psbt, PsbtReadError> {
+        match self {
+            WirePsbt::Json(psbt) => Ok(*psbt),
+            WirePsbt::Base64(base64) => Psbt::from_str(&base64).map_err(|e| PsbtReadError::Decode(e.to_string())),
+        }
+    }
+}
+
+// `onchain` or `lightning:<bolt11>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PayoutRequestData {
+    pub request: String,
+    // The payout the user was promised in its contract data
+    pub amount: u64,
+}
+
+// The 2 maker keys of the maker2user contract, the payout hash locking it besides the contract hash, the
+// checksum of its descriptor, the txid of the tx funding it, the sats it pays to the contract and the
+// relative timelock (in blocks) after which the maker can take it back
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecondContractData {
+    pub keys: Vec<String>,
+    pub hash: String,
+    // Of the payout hash, sha256 if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_kind: Option<HashKind>,
+    pub checksum: String,
+    pub txid: String,
+    pub amount: u64,
+    pub timelock: u16,
+}
+
+// Preimage of the paid invoice in hex, replaces the maker2user contract data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LightningPayout {
+    pub preimage: String,
+}
+
+// The preimage of the maker2user contract payout hash in hex and the maker key of the contract
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreimageHandover {
+    pub preimage: String,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrivKeyHandover {
+    pub key: String,
+}
+
+// Users the round still lacks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Waiting {
+    pub missing: usize,
+}
+
+// A replacement of the funding tx that didn't confirm in time, paying `fee_rate` in sat/vB, and the refunds
+// spending it. The users sign them as they did the first ones, the refunds first.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FundingBump {
+    pub fee_rate: f32,
+    pub funding: WirePsbt,
+    pub refund: WirePsbt,
+    pub refund_ladder: Vec<WirePsbt>,
+}
+
+// All of the utxo goes to the funding fee, the outputs stay as they are
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddInput {
+    pub utxo: UserUtxo,
+}
+
+// The funding tx with the inputs users added after the last one (see `check_added_inputs`), and the
+// refunds spending it. The users sign the refunds again, only those who added an input sign the funding.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputAdded {
+    pub funding: WirePsbt,
+    pub refund: WirePsbt,
+    pub refund_ladder: Vec<WirePsbt>,
+}
+
+// The funding tx confirmed, or we don't replace it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FundingKept {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Decline {
+    pub reason: String,
+}
+
+// The users answer with an `Accept` to wait for another round with the data they already sent, or
+// leave
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rematch {
+    pub reason: String,
+}
+
+// The code is why the sender ends the session, from its side
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Abort {
+    pub code: AbortCode,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ping {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pong {}
+
+// A message the protocol may expect next, and the largest line we read for it. Messages carrying
+// PSBTs (and the hello, the largest of the rest) are boxed in `Message`.
+pub trait Expected: Into<Message> + Sized {
+    const NAME: &'static str;
+    const MAX_SIZE: usize;
+
+    fn from_message(message: Message) -> Result<Self, Message>;
+}
+
+macro_rules! expected {
+    ($variant:ident, $ty:ty, $name:literal) => {
+        expected!($variant, $ty, $name, MAX_MESSAGE_SIZE, |m| m, |m| m);
+    };
+    ($variant:ident, boxed $ty:ty, $name:literal, $max:expr) => {
+        expected!($variant, $ty, $name, $max, Box::new, |m: Box<$ty>| *m);
+    };
+    ($variant:ident, $ty:ty, $name:literal, $max:expr, $wrap:expr, $unwrap:expr) => {
+        impl From<$ty> for Message {
+            fn from(message: $ty) -> Self {
+                Message::$variant($wrap(message))
+            }
+        }
+
+        impl Expected for $ty {
+            const NAME: &'static str = $name;
+            const MAX_SIZE: usize = $max;
+
+            fn from_message(message: Message) -> Result<Self, Message> {
+                match message {
+                    Message::$variant(message) => Ok($unwrap(message)),
+                    other => Err(other),
+                }
+            }
+        }
+    };
+}
+
+expected!(Hello, boxed Hello, "hello", MAX_MESSAGE_SIZE);
+expected!(Offer, Offer, "offer");
+expected!(Accept, Accept, "offer acceptance");
+expected!(UserKeys, UserKeys, "user keys");
+expected!(UtxoData, boxed UtxoData, "utxo data", MAX_PSBT_SIZE + MAX_MESSAGE_SIZE);
+expected!(RefundAddress, RefundAddress, "refund address");
+expected!(HashSource, HashSourceData, "hash source");
+expected!(ContractData, boxed ContractData, "contract data", MAX_TXS_SIZE + MAX_MESSAGE_SIZE);
+expected!(SignedPsbt, boxed SignedPsbt, "signed psbt", MAX_PSBT_SIZE);
+expected!(SigBundle, SigBundle, "signature bundle", MAX_SIG_BUNDLE_SIZE, |m| m, |m| m);
+expected!(PayoutRequest, PayoutRequestData, "payout request");
+expected!(SecondContractData, SecondContractData, "second contract data");
+expected!(LightningPayout, LightningPayout, "lightning payout");
+expected!(PreimageHandover, PreimageHandover, "preimage handover");
+expected!(PrivKeyHandover, PrivKeyHandover, "private key handover");
+expected!(Waiting, Waiting, "waiting status");
+expected!(FundingBump, boxed FundingBump, "funding bump", MAX_TXS_SIZE + MAX_MESSAGE_SIZE);
+expected!(FundingKept, FundingKept, "funding kept");
+expected!(AddInput, boxed AddInput, "added input", MAX_PSBT_SIZE + MAX_MESSAGE_SIZE);
+expected!(InputAdded, boxed InputAdded, "funding with added inputs", MAX_TXS_SIZE + MAX_MESSAGE_SIZE);
+expected!(Decline, Decline, "decline");
+expected!(Rematch, Rematch, "rematch");
+expected!(Abort, Abort, "abort");
+expected!(Ping, Ping, "ping");
+expected!(Pong, Pong, "pong");
+
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello(_) => Hello::NAME,
+            Message::Offer(_) => Offer::NAME,
+            Message::Accept(_) => Accept::NAME,
+            Message::UserKeys(_) => UserKeys::NAME,
+            Message::UtxoData(_) => UtxoData::NAME,
+            Message::RefundAddress(_) => RefundAddress::NAME,
+            Message::HashSource(_) => HashSourceData::NAME,
+            Message::ContractData(_) => ContractData::NAME,
+            Message::SignedPsbt(_) => SignedPsbt::NAME,
+            Message::SigBundle(_) => SigBundle::NAME,
+            Message::PayoutRequest(_) => PayoutRequestData::NAME,
+            Message::SecondContractData(_) => SecondContractData::NAME,
+            Message::LightningPayout(_) => LightningPayout::NAME,
+            Message::PreimageHandover(_) => PreimageHandover::NAME,
+            Message::PrivKeyHandover(_) => PrivKeyHandover::NAME,
+            Message::Waiting(_) => Waiting::NAME,
+            Message::FundingBump(_) => FundingBump::NAME,
+            Message::FundingKept(_) => FundingKept::NAME,
+            Message::AddInput(_) => AddInput::NAME,
+            Message::InputAdded(_) => InputAdded::NAME,
+            Message::Decline(_) => Decline::NAME,
+            Message::Rematch(_) => Rematch::NAME,
+            Message::Abort(_) => Abort::NAME,
+            Message::Ping(_) => Ping::NAME,
+            Message::Pong(_) => Pong::NAME,
+        }
+    }
+}
+
+// Peers must speak the same version, features are negotiated
+pub const PROTOCOL_VERSION: u32 = 15;
+// Feature names a hello may carry
+pub const BASE64_PSBT: &str = "base64_psbt";
+pub const SIG_BUNDLES: &str = "sig_bundles";
+pub const WAITING_STATUS: &str = "waiting_status";
+pub const PARTIAL_SPENDS: &str = "partial_spends";
+// Offered by peers running rounds of equal amounts, where every user puts the same in the contract and
+// gets the same payout. Both peers must offer it or neither.
+pub const DENOMINATIONS: &str = "denominations";
+// Offered by peers whose contracts are taproot ones (see `users2maker_contract_desc_tr`), also both or
+// neither
+pub const TAPROOT: &str = "taproot";
+// Offered by makers that wait for the funding tx to confirm and replace it at a higher feerate if it
+// doesn't in time, and by users who let them (see `FundingBump`)
+pub const FUNDING_BUMPS: &str = "funding_bumps";
+// Offered by makers taking inputs users add to the funding tx before they sign it, and by users who sign
+// their funding inputs with SIGHASH_ALL|ANYONECANPAY for it (see `AddInput`). Only rounds where every user
+// agreed on it take them.
+pub const ANYONE_CAN_PAY: &str = "anyone_can_pay";
+
+// What two peers that can talk to each other agreed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    pub psbt_encoding: PsbtEncoding,
+    // Whether signed PSBTs go as `SigBundle`s
+    pub sig_bundles: bool,
+    // Whether the maker tells users waiting for their round how many users it still lacks
+    pub waiting_status: bool,
+    // Whether users may keep part of their utxos as change
+    pub partial_spends: bool,
+    // Whether the maker may replace the funding tx
+    pub funding_bumps: bool,
+    // Whether the user signs its funding inputs with SIGHASH_ALL|ANYONECANPAY
+    pub anyone_can_pay: bool,
+}
+
+impl Hello {
+    pub fn new(
+        network: Network,
+        round_users: usize,
+        encoding: PsbtEncoding,
+        denominated: bool,
+        taproot: bool,
+        funding_bumps: bool,
+        anyone_can_pay: bool,
+    ) -> Self {
+        let mut features = vec![SIG_BUNDLES.to_string(), WAITING_STATUS.to_string(), PARTIAL_SPENDS.to_string()];
+        // Taproot PSBTs don't survive JSON, which can't read back their leaf versions
+        if encoding == PsbtEncoding::Base64 || taproot {
+            features.push(BASE64_PSBT.to_string());
+        }
+        if denominated {
+            features.push(DENOMINATIONS.to_string());
+        }
+        if taproot {
+            features.push(TAPROOT.to_string());
+        }
+        if funding_bumps {
+            features.push(FUNDING_BUMPS.to_string());
+        }
+        if anyone_can_pay {
+            features.push(ANYONE_CAN_PAY.to_string());
+        }
+
+        let (network, nonce) = (network.to_string(), signing::fresh_nonce());
+
+        Hello { version: PROTOCOL_VERSION, network, round_users, features, nonce, resume: None, round: None }
+    }
+
+    // How we talk to a peer that sent us `theirs`, if we can talk to it at all
+    pub fn negotiate(&self, theirs: &Hello) -> Result<Negotiated, ProtocolError> {
+        let incompatible = |reason| Err(ProtocolError::Incompatible(reason));
+
+        if theirs.version != self.version {
+            return incompatible(format!("Peer speaks version {}, we speak {}", theirs.version, self.version));
+        }
+        if theirs.network != self.network {
+            return incompatible(format!("Peer is on {}, we are on {}", theirs.network, self.network));
+        }
+        if theirs.round_users != self.round_users {
+            let (peer, ours) = (theirs.round_users, self.round_users);
+            return incompatible(format!("Peer runs rounds of {peer} users, we run {ours}"));
+        }
+
+        let offers = |hello: &Hello, feature: &str| hello.features.iter().any(|f| f == feature);
+        match (offers(self, DENOMINATIONS), offers(theirs, DENOMINATIONS)) {
+            (true, false) => return incompatible("Peer doesn't run rounds of equal amounts".to_string()),
+            (false, true) => return incompatible("Peer runs rounds of equal amounts, we don't".to_string()),
+            _ => {},
+        }
+        match (offers(self, TAPROOT), offers(theirs, TAPROOT)) {
+            (true, false) => return incompatible("Peer doesn't build taproot contracts".to_string()),
+            (false, true) => return incompatible("Peer builds taproot contracts, we don't".to_string()),
+            _ => {},
+        }
+
+        let both = |feature: &str| offers(self, feature) && offers(theirs, feature);
+        let psbt_encoding = if both(BASE64_PSBT) { PsbtEncoding::Base64 } else { PsbtEncoding::Json };
+
+        Ok(Negotiated {
+            psbt_encoding,
+            sig_bundles: both(SIG_BUNDLES),
+            waiting_status: both(WAITING_STATUS),
+            partial_spends: both(PARTIAL_SPENDS),
+            funding_bumps: both(FUNDING_BUMPS),
+            anyone_can_pay: both(ANYONE_CAN_PAY),
+        })
+    }
+}
+
+// Largest message we read from a peer, other than those carrying PSBTs
+pub const MAX_MESSAGE_SIZE: usize = 1_000;
+// Largest serialized PSBT we read from a peer, or the PSBT inputs of a user, which carry their full
+// previous txs
+pub const MAX_PSBT_SIZE: usize = 1_000_000;
+// Of the funding tx and its refunds as sent together, see `REFUND_LADDER`
+const MAX_TXS_SIZE: usize = (2 + REFUND_LADDER.len()) * MAX_PSBT_SIZE;
+// Largest signature bundle, which holds witnesses with their scripts (one per user in the finalized
+// funding tx)
+pub const MAX_SIG_BUNDLE_SIZE: usize = 100_000;
+
+// Handed over keys and preimages are only sent over an encrypted connection, anyone reading them
+// could take the contract coins
+pub async fn send_msg<W: AsyncWrite + Unpin>(
+    message: &Message,
+    writer: &mut FramedWriter<W>,
+) -> Result<(), ProtocolError> {
+    if matches!(message, Message::PrivKeyHandover(_) | Message::PreimageHandover(_)) && !writer.is_encrypted() {
+        return Err(ProtocolError::Unencrypted(message.name()));
+    }
+    let mut frame = serde_json::to_string(message).expect("Messages always serialize");
+    if let Some(session) = writer.session() {
+        let seq = writer.next_seq();
+        let (key, sig) = match writer.signing_key() {
+            Some(key) => {
+                let (key, sig) = signing::sign(key, &session, seq, &frame);
+                (Some(key.to_string()), Some(sig.serialize_der().to_hex()))
+            },
+            None => (None, None),
+        };
+        let body = RawValue::from_string(frame).expect("Messages are valid JSON");
+        let framed = SessionFrame { session: session.to_hex(), seq, key, sig, message: &body };
+
+        frame = serde_json::to_string(&framed).expect("Session frames always serialize");
+    }
+    writer.write_frame(&frame).await?;
+
+    Ok(())
+}
+
+// Reads the next message, which must be a `T`. An abort, decline or rematch from the peer is returned
+// as an error.
+pub async fn expect_msg<T: Expected, R: AsyncBufRead + Unpin>(
+    reader: &mut FramedReader<R>,
+) -> Result<T, ProtocolError> {
+    let message = read_msg(reader, T::MAX_SIZE).await?;
+
+    T::from_message(message).map_err(|got| ProtocolError::Unexpected { expected: T::NAME, got: got.name() })
+}
+
+// Reads the next message of any type, for the steps where the peer may send one of several. Pings
+// and pongs are skipped, they only matter while we keep the connection alive.
+pub async fn read_msg<R: AsyncBufRead + Unpin>(
+    reader: &mut FramedReader<R>,
+    max: usize,
+) -> Result<Message, ProtocolError> {
+    loop {
+        match read_any_msg(reader, max).await? {
+            Message::Ping(_) | Message::Pong(_) => continue,
+            message => return Ok(message),
+        }
+    }
+}
+
+// Like `expect_msg`, for the long waits of the protocol. Once the connection is silent for an
+// interval we ping the peer, and we answer its pings, so that neither the peer nor a NAT in between
+// drops it. After `max_missed` silent intervals in a row the connection is taken as lost.
+pub async fn expect_msg_alive<T: Expected, R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut FramedReader<R>,
+    writer: &mut FramedWriter<W>,
+    keepalive: Keepalive,
+) -> Result<T, ProtocolError> {
+    let message = read_msg_alive(reader, writer, T::MAX_SIZE, keepalive).await?;
+
+    T::from_message(message).map_err(|got| ProtocolError::Unexpected { expected: T::NAME, got: got.name() })
+}
+
+// Like `read_msg`, keeping the connection alive as `expect_msg_alive` does
+pub async fn read_msg_alive<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut FramedReader<R>,
+    writer: &mut FramedWriter<W>,
+    max: usize,
+    keepalive: Keepalive,
+) -> Result<Message, ProtocolError> {
+    let mut missed = 0;
+
+    loop {
+        // Waiting for the first byte can be cut short, the frame is then read in one go
+        match timeout(keepalive.interval, reader.wait_readable()).await {
+            Err(_) if missed == keepalive.max_missed => return Err(ProtocolError::ConnectionLost { missed }),
+            Err(_) => {
+                send_msg(&Ping {}.into(), writer).await?;
+                missed += 1;
+            },
+            Ok(readable) => {
+                readable?;
+                missed = 0;
+
+                match read_any_msg(reader, max).await? {
+                    Message::Ping(_) => send_msg(&Pong {}.into(), writer).await?,
+                    Message::Pong(_) => {},
+                    message => return Ok(message),
+                }
+            },
+        }
+    }
+}
+
+// Runs `work` while pinging `writers` every interval, for the peers that wait on us meanwhile.
+// A failed ping is ignored, a peer that's gone fails the next real message.
+pub async fn with_pings<T, W: AsyncWrite + Unpin>(
+    work: impl Future<Output = T>,
+    writers: &mut [&mut FramedWriter<W>],
+    interval: Duration,
+) -> T {
+    tokio::pin!(work);
+    let mut pings = tokio::time::interval_at(Instant::now() + interval, interval);
+
+    loop {
+        tokio::select! {
+            output = &mut work => return output,
+            _ = pings.tick() => for writer in writers.iter_mut() {
+                let _ = send_msg(&Ping {}.into(), writer).await;
+            },
+        }
+    }
+}
+
+async fn read_any_msg<R: AsyncBufRead + Unpin>(
+    reader: &mut FramedReader<R>,
+    max: usize,
+) -> Result<Message, ProtocolError> {
+    let frame = reader.read_frame(max + SESSION_FRAME_OVERHEAD).await?;
+
+    // Before the hellos messages go on their own
+    let message = match reader.session() {
+        Some(session) => open_session_frame(reader, session, &frame)?,
+        None => serde_json::from_str(&frame)
+            .map_err(|e| ProtocolError::Malformed { field: "message", reason: e.to_string() })?,
+    };
+
+    match message {
+        Message::Abort(Abort { code, reason }) => Err(ProtocolError::Rejected { code, reason }),
+        Message::Decline(Decline { reason }) => Err(ProtocolError::Declined(reason)),
+        Message::Rematch(Rematch { reason }) => Err(ProtocolError::Rematch(reason)),
+        message => Ok(message),
+    }
+}
+
+fn open_session_frame<R: AsyncBufRead + Unpin>(
+    reader: &mut FramedReader<R>,
+    session: sha256::Hash,
+    frame: &str,
+) -> Result<Message, ProtocolError> {
+    let malformed = |field| move |e: serde_json::Error| ProtocolError::Malformed { field, reason: e.to_string() };
+
+    let framed: SessionFrame = serde_json::from_str(frame).map_err(malformed("session frame"))?;
+    if framed.session != session.to_hex() {
+        return Err(ProtocolError::WrongSession);
+    }
+    reader.check_seq(framed.seq)?;
+
+    let body = framed.message.get();
+    match (framed.key, framed.sig) {
+        (Some(key), Some(sig)) => {
+            let bad_signature = |what| ProtocolError::BadSignature(format!("Invalid signing {what}"));
+            let key = PublicKey::from_str(&key).map_err(|_| bad_signature("key"))?;
+            let sig = Vec::from_hex(&sig).ok().and_then(|sig| Signature::from_der(&sig).ok())
+                .ok_or_else(|| bad_signature("signature"))?;
+
+            signing::verify(&key, &session, framed.seq, body, &sig)?;
+            reader.pin_peer_key(key)?;
+            serde_json::from_str(body).map_err(malformed("message"))
+        },
+        // Only aborts, declines, keepalives, waiting statuses and the offer with its acceptance may go
+        // unsigned, as the peer may not have its contract keys yet. A forged abort or decline only ends the
+        // session, which whoever can forge it could do anyway, and a forged status only changes what the
+        // user is shown. The offer comes before anyone has keys, its fee is checked again against the signed
+        // contract data.
+        (None, None) => match serde_json::from_str(body).map_err(malformed("message"))? {
+            message @ (Message::Abort(_)
+            | Message::Decline(_)
+            | Message::Ping(_)
+            | Message::Pong(_)
+            | Message::Waiting(_)
+            | Message::Offer(_)
+            | Message::Accept(_)) => Ok(message),
+            message => Err(ProtocolError::Unsigned(message.name())),
+        },
+        _ => Err(ProtocolError::BadSignature("Signing key or signature missing".to_string())),
+    }
+}
+
+// Peers that hold the PSBT already only get its signatures, if they take them
+pub async fn send_signed_psbt<W: AsyncWrite + Unpin>(
+    psbt: &Psbt,
+    writer: &mut FramedWriter<W>,
+) -> Result<(), ProtocolError> {
+    let message = match writer.sig_bundles() {
+        true => SigBundle::from_psbt(psbt).into(),
+        false => SignedPsbt { psbt: WirePsbt::encode(psbt, writer.psbt_encoding()) }.into(),
+    };
+
+    send_msg(&message, writer).await
+}
+
+// The next signed PSBT, as either message
+pub async fn read_signed_psbt<R: AsyncBufRead + Unpin>(
+    reader: &mut FramedReader<R>,
+) -> Result<SignedUpdate, ProtocolError> {
+    match read_msg(reader, SignedPsbt::MAX_SIZE).await? {
+        Message::SignedPsbt(signed) => Ok(SignedUpdate::Psbt(signed.psbt.decode()?)),
+        Message::SigBundle(bundle) => Ok(SignedUpdate::Sigs(bundle)),
+        other => Err(ProtocolError::Unexpected { expected: SignedPsbt::NAME, got: other.name() }),
+    }
+}
+
+// What comes while the funding tx is signed in rounds with `ANYONE_CAN_PAY`: a signed PSBT, or a change to
+// the funding tx before it
+pub enum FundingUpdate {
+    Signed(SignedUpdate),
+    AddInput(Box<AddInput>),
+    InputAdded(Box<InputAdded>),
+}
+
+pub async fn read_funding_update<R: AsyncBufRead + Unpin>(
+    reader: &mut FramedReader<R>,
+) -> Result<FundingUpdate, ProtocolError> {
+    match read_msg(reader, InputAdded::MAX_SIZE).await? {
+        Message::SignedPsbt(signed) => Ok(FundingUpdate::Signed(SignedUpdate::Psbt(signed.psbt.decode()?))),
+        Message::SigBundle(bundle) => Ok(FundingUpdate::Signed(SignedUpdate::Sigs(bundle))),
+        Message::AddInput(add) => Ok(FundingUpdate::AddInput(add)),
+        Message::InputAdded(added) => Ok(FundingUpdate::InputAdded(added)),
+        other => Err(ProtocolError::Unexpected { expected: SignedPsbt::NAME, got: other.name() }),
+    }
+}
+
+// Last message to the maker when we leave a round before signing its funding tx, telling it why
+pub async fn send_decline<W: AsyncWrite + Unpin>(
+    reason: &impl fmt::Display,
+    writer: &mut FramedWriter<W>,
+) -> Result<(), ProtocolError> {
+    send_msg(&Decline { reason: reason.to_string() }.into(), writer).await
+}
+
+// Last message to a peer we are dropping, with the `code` of why
+pub async fn send_error<W: AsyncWrite + Unpin>(
+    code: AbortCode,
+    error: &impl fmt::Display,
+    writer: &mut FramedWriter<W>,
+) -> Result<(), ProtocolError> {
+    send_msg(&Abort { code, reason: error.to_string() }.into(), writer).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use bdk::bitcoin::hashes::Hash;
+    use bdk::bitcoin::secp256k1::rand::rngs::StdRng;
+    use bdk::bitcoin::secp256k1::rand::{Rng, SeedableRng};
+    use bdk::bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bdk::bitcoin::{Address, OutPoint, PackedLockTime, PrivateKey, Sequence, Transaction, TxIn, TxOut};
+    use bdk::database::MemoryDatabase;
+    use bdk::{SignOptions, Wallet};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+    use crate::transport::{Connection, Framing};
+    use crate::{check_hex32, lightning, parse_contract_keys};
+
+    type Reader = FramedReader<BufReader<ReadHalf<DuplexStream>>>;
+    type Writer = FramedWriter<WriteHalf<DuplexStream>>;
+
+    fn session_hash() -> sha256::Hash {
+        sha256::Hash::hash(b"session")
+    }
+
+    // A writer and the reader of what it sends, in the same session
+    fn session_pipe() -> (Writer, Reader) {
+        let (ours, theirs) = duplex(1 << 16);
+        let mut ours = Connection::new(ours, Framing::default());
+        let mut theirs = Connection::new(theirs, Framing::default());
+        ours.start_session(session_hash());
+        theirs.start_session(session_hash());
+
+        (ours.writer, theirs.reader)
+    }
+
+    // A session frame of `body` as `send_msg` writes it, signed with `key` if any
+    fn frame(session: &sha256::Hash, seq: u64, key: Option<&SecretKey>, body: &str) -> String {
+        let (key, sig) = match key {
+            Some(key) => {
+                let (key, sig) = signing::sign(key, session, seq, body);
+                (Some(key.to_string()), Some(sig.serialize_der().to_hex()))
+            },
+            None => (None, None),
+        };
+        let message = RawValue::from_string(body.to_string()).unwrap();
+        let framed = SessionFrame { session: session.to_hex(), seq, key, sig, message: &message };
+
+        serde_json::to_string(&framed).unwrap()
+    }
+
+    fn secret_key(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip_in_a_session() {
+        let hello = Hello::new(Network::Regtest, 2, PsbtEncoding::Base64, false, false, true, false);
+        let messages: Vec<Message> = vec![
+            hello.into(),
+            Accept {}.into(),
+            UserKeys { keys: vec!["02aa".to_string(), "03bb".to_string()] }.into(),
+            RefundAddress { address: "bcrt1qxyz".to_string() }.into(),
+            HashSourceData { source: "maker".to_string() }.into(),
+            PayoutRequestData { request: "onchain".to_string(), amount: 90_000 }.into(),
+            Waiting { missing: 1 }.into(),
+            FundingKept {}.into(),
+            SigBundle { txid: Txid::all_zeros().to_string(), inputs: vec![] }.into(),
+        ];
+
+        let (mut writer, mut reader) = session_pipe();
+        writer.sign_with(secret_key(1));
+        for message in &messages {
+            send_msg(message, &mut writer).await.unwrap();
+        }
+        // Keepalives go through the session too, but aren't returned
+        send_msg(&Ping {}.into(), &mut writer).await.unwrap();
+        send_msg(&Waiting { missing: 0 }.into(), &mut writer).await.unwrap();
+
+        for message in messages {
+            assert_eq!(read_msg(&mut reader, MAX_SIG_BUNDLE_SIZE).await.unwrap(), message);
+        }
+        assert_eq!(expect_msg::<Waiting, _>(&mut reader).await.unwrap(), Waiting { missing: 0 });
+        assert_eq!(reader.peer_key(), Some(PublicKey::from_secret_key(&Secp256k1::new(), &secret_key(1))));
+    }
+
+    // The peer's last words come back as errors, and a message we didn't expect names both
+    #[tokio::test]
+    async fn aborts_and_unexpected_messages() {
+        let (mut writer, mut reader) = session_pipe();
+        send_msg(&Abort { code: AbortCode::Refused, reason: "Full".to_string() }.into(), &mut writer).await.unwrap();
+        send_msg(&Decline { reason: "Too expensive".to_string() }.into(), &mut writer).await.unwrap();
+        writer.sign_with(secret_key(1));
+        send_msg(&RefundAddress { address: "bcrt1qxyz".to_string() }.into(), &mut writer).await.unwrap();
+
+        assert!(matches!(
+            expect_msg::<UserKeys, _>(&mut reader).await,
+            Err(ProtocolError::Rejected { code: AbortCode::Refused, reason }) if reason == "Full",
+        ));
+        assert!(matches!(
+            expect_msg::<UserKeys, _>(&mut reader).await,
+            Err(ProtocolError::Declined(reason)) if reason == "Too expensive",
+        ));
+        assert!(matches!(
+            expect_msg::<UserKeys, _>(&mut reader).await,
+            Err(ProtocolError::Unexpected { expected: "user keys", got }) if got == RefundAddress::NAME,
+        ));
+    }
+
+    // Messages numbered other than the next one are refused, a replayed one as well
+    #[tokio::test]
+    async fn out_of_order_messages_are_refused() {
+        let key = secret_key(1);
+        let body = serde_json::to_string(&Message::from(Waiting { missing: 1 })).unwrap();
+
+        let (mut writer, mut reader) = session_pipe();
+        writer.write_frame(&frame(&session_hash(), 1, Some(&key), &body)).await.unwrap();
+        assert!(matches!(
+            read_msg(&mut reader, MAX_MESSAGE_SIZE).await,
+            Err(ProtocolError::OutOfOrder { expected: 0, got: 1 }),
+        ));
+
+        let (mut writer, mut reader) = session_pipe();
+        let captured = frame(&session_hash(), 0, Some(&key), &body);
+        writer.write_frame(&captured).await.unwrap();
+        writer.write_frame(&captured).await.unwrap();
+        assert!(read_msg(&mut reader, MAX_MESSAGE_SIZE).await.is_ok());
+        assert!(matches!(
+            read_msg(&mut reader, MAX_MESSAGE_SIZE).await,
+            Err(ProtocolError::OutOfOrder { expected: 1, got: 0 }),
+        ));
+    }
+
+    #[tokio::test]
+    async fn forged_frames_are_refused() {
+        let key = secret_key(1);
+        let body = serde_json::to_string(&Message::from(UserKeys { keys: vec!["02aa".to_string()] })).unwrap();
+        let tampered = body.replace("02aa", "02bb");
+
+        // Signed for one body, sent with another
+        let (mut writer, mut reader) = session_pipe();
+        let signed = frame(&session_hash(), 0, Some(&key), &body);
+        writer.write_frame(&signed.replace("02aa", "02bb")).await.unwrap();
+        assert!(matches!(read_msg(&mut reader, MAX_MESSAGE_SIZE).await, Err(ProtocolError::BadSignature(_))));
+
+        // From another session
+        let (mut writer, mut reader) = session_pipe();
+        writer.write_frame(&frame(&sha256::Hash::hash(b"other"), 0, Some(&key), &body)).await.unwrap();
+        assert!(matches!(read_msg(&mut reader, MAX_MESSAGE_SIZE).await, Err(ProtocolError::WrongSession)));
+
+        // Unsigned, which only a few messages may be
+        let (mut writer, mut reader) = session_pipe();
+        writer.write_frame(&frame(&session_hash(), 0, None, &body)).await.unwrap();
+        assert!(matches!(
+            read_msg(&mut reader, MAX_MESSAGE_SIZE).await,
+            Err(ProtocolError::Unsigned(name)) if name == UserKeys::NAME,
+        ));
+
+        // A key without its signature
+        let (mut writer, mut reader) = session_pipe();
+        let keyed = frame(&session_hash(), 0, Some(&key), &body);
+        let mut keyed: serde_json::Value = serde_json::from_str(&keyed).unwrap();
+        keyed.as_object_mut().unwrap().remove("sig");
+        writer.write_frame(&keyed.to_string()).await.unwrap();
+        assert!(matches!(read_msg(&mut reader, MAX_MESSAGE_SIZE).await, Err(ProtocolError::BadSignature(_))));
+
+        // Signed by someone else than the earlier messages
+        let (mut writer, mut reader) = session_pipe();
+        writer.write_frame(&frame(&session_hash(), 0, Some(&key), &body)).await.unwrap();
+        writer.write_frame(&frame(&session_hash(), 1, Some(&secret_key(2)), &tampered)).await.unwrap();
+        assert!(read_msg(&mut reader, MAX_MESSAGE_SIZE).await.is_ok());
+        assert!(matches!(read_msg(&mut reader, MAX_MESSAGE_SIZE).await, Err(ProtocolError::BadSignature(_))));
+    }
+
+    // One line as a peer in line framing may send it, before the hellos
+    async fn read_line_msg(line: &[u8]) -> Result<Message, ProtocolError> {
+        let (mut raw, theirs) = duplex(1 << 16);
+        raw.write_all(line).await.unwrap();
+        drop(raw);
+        read_msg(&mut Connection::new(theirs, Framing::Line).reader, MAX_MESSAGE_SIZE).await
+    }
+
+    // Only the exact line `send_msg` writes is taken, with either line ending
+    #[tokio::test]
+    async fn malformed_lines_are_refused() {
+        let waiting = serde_json::to_string(&Message::from(Waiting { missing: 1 })).unwrap();
+        assert_eq!(waiting, r#"{"type":"waiting","missing":1}"#);
+        for line in [format!("{waiting}\n"), format!("{waiting}\r\n")] {
+            assert_eq!(read_line_msg(line.as_bytes()).await.unwrap(), Waiting { missing: 1 }.into());
+        }
+
+        let padded = [
+            " {w}\n", "{w} \n", "\t{w}\n", "{w}\t\n", "{w} \r\n", "{w}\r\r\n", "\r{w}\n", "\u{a0}{w}\n",
+        ];
+        for line in padded.map(|line| line.replace("{w}", &waiting)) {
+            assert!(matches!(read_line_msg(line.as_bytes()).await, Err(ProtocolError::Padded)), "{line:?}");
+        }
+        for line in ["\n", "\r\n", "  \n", "\t\r\n"] {
+            assert!(matches!(read_line_msg(line.as_bytes()).await, Err(ProtocolError::EmptyLine)), "{line:?}");
+        }
+
+        let malformed = [
+            "{w},",
+            "{w};",
+            "{w}x",
+            "{w}{w}",
+            "[{w}]",
+            "{w}\u{0}",
+            r#"{"type":"waiting","missing":1,}"#,
+            r#"{"type":"waiting","missing":1"#,
+            r#"{"type":"waiting","missing":"1"}"#,
+            r#"{"type":"waiting","missing":-1}"#,
+            r#"{"type":"waiting","missing":1.5}"#,
+            r#"{"type":"waiting","missing":18446744073709551616}"#,
+            r#"{"type":"waiting","missing":1,"missing":2}"#,
+            r#"{"type":"waiting"}"#,
+            r#"{"type":"Waiting","missing":1}"#,
+            r#"{"type":"teleport"}"#,
+            r#"{"missing":1}"#,
+            r#"{'type':'waiting','missing':1}"#,
+            r#"{"type":"user_keys","keys":"02aa,03bb"}"#,
+            "{}",
+            "null",
+            "42",
+            r#""waiting""#,
+        ];
+        for line in malformed.map(|line| format!("{}\n", line.replace("{w}", &waiting))) {
+            let read = read_line_msg(line.as_bytes()).await;
+            assert!(matches!(read, Err(ProtocolError::Malformed { field: "message", .. })), "{line:?}: {read:?}");
+        }
+
+        // Not text, or without an end within the size limit
+        assert!(matches!(read_line_msg(b"{\"type\":\"waiting\xff\"}\n").await, Err(ProtocolError::Io(_))));
+        let endless = "x".repeat(2 * MAX_MESSAGE_SIZE);
+        assert!(matches!(read_line_msg(endless.as_bytes()).await, Err(ProtocolError::TooLong { .. })));
+    }
+
+    // Random bytes in each free-form field, and as whole lines. Half the time they're drawn from the characters
+    // keys, hashes and addresses are made of, to get further into their parsers.
+    #[tokio::test]
+    async fn random_fields_are_refused() {
+        let mut rng = StdRng::seed_from_u64(529);
+        for _ in 0..500 {
+            let bytes: Vec<u8> = (0..rng.gen_range(0..=150)).map(|_| rng.gen()).collect();
+            let text = match rng.gen() {
+                true => String::from_utf8_lossy(&bytes).into_owned(),
+                false => bytes.iter().map(|byte| b"0123456789abcdef:,q"[*byte as usize % 19] as char).collect(),
+            };
+
+            assert!(parse_contract_keys(std::slice::from_ref(&text), 1).is_err(), "{text:?}");
+            assert!(check_hex32(&text, "hash").is_err(), "{text:?}");
+            assert!(lightning::HashSource::from_str(&text).is_err(), "{text:?}");
+            assert!(lightning::PayoutRequest::from_str(&text).is_err(), "{text:?}");
+            assert!(Address::from_str(&text).is_err(), "{text:?}");
+
+            // A string field takes any string, it's up to its parser to refuse it
+            let keys = UserKeys { keys: vec![text] };
+            let (mut writer, mut reader) = session_pipe();
+            writer.sign_with(secret_key(1));
+            send_msg(&keys.clone().into(), &mut writer).await.unwrap();
+            assert_eq!(expect_msg::<UserKeys, _>(&mut reader).await.unwrap(), keys);
+
+            let mut line = bytes;
+            line.push(b'\n');
+            assert!(read_line_msg(&line).await.is_err(), "{line:?}");
+        }
+
+        // More keys than a message of keys can hold
+        let key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key(1)).to_string();
+        let (mut writer, mut reader) = session_pipe();
+        writer.sign_with(secret_key(1));
+        send_msg(&UserKeys { keys: vec![key; 20] }.into(), &mut writer).await.unwrap();
+        assert!(matches!(expect_msg::<UserKeys, _>(&mut reader).await, Err(ProtocolError::TooLong { .. })));
+    }
+
+    // Every message, with its optional fields set and left out, decodes back to itself
+    #[test]
+    fn every_message_round_trips() {
+        let (psbt, signed) = signed_psbt(PrivateKey::new(secret_key(1), Network::Regtest), 100_000);
+        let json = WirePsbt::encode(&psbt, PsbtEncoding::Json);
+        let base64 = WirePsbt::encode(&signed, PsbtEncoding::Base64);
+        let wif = PrivateKey::new(secret_key(2), Network::Regtest).to_wif();
+        let hello = Hello::new(Network::Regtest, 2, PsbtEncoding::Base64, true, true, true, true);
+        let hash = sha256::Hash::hash(b"hash").to_hex();
+        let utxo = UserUtxo {
+            descriptor: "wpkh(02aa)".to_string(),
+            outpoint: OutPoint::null().to_string(),
+            psbt_input: signed.inputs[0].clone(),
+        };
+        let contract_data = ContractData {
+            keys: vec!["02aa".to_string(); 9],
+            hash: hash.clone(),
+            checksum: "qwertyui".to_string(),
+            refund_addresses: vec!["bcrt1qxyz".to_string(); 2],
+            funding: json.clone(),
+            refund: base64.clone(),
+            refund_ladder: vec![base64.clone(), json.clone()],
+            payout: 90_000,
+            denomination: None,
+            thresholds: None,
+            timelocks: Timelocks::default(),
+            fee_rates: FeeRates { funding: 2.0, refund: 1.5 },
+            maker_refund_address: None,
+        };
+        let second_contract = SecondContractData {
+            keys: vec!["02aa".to_string(), "03bb".to_string()],
+            hash: hash.clone(),
+            hash_kind: None,
+            checksum: "qwertyui".to_string(),
+            txid: Txid::all_zeros().to_string(),
+            amount: 90_000,
+            timelock: 69,
+        };
+
+        let messages: Vec<Message> = vec![
+            hello.clone().into(),
+            Hello { resume: Some(hash.clone()), round: Some(Txid::all_zeros().to_string()), ..hello }.into(),
+            Offer {
+                network: Network::Regtest.to_string(),
+                min_amount: 10_000,
+                max_amount: 1_000_000,
+                fee: MakerFee { base: 1_000, ppm: 5_000 },
+                confirmations: 1,
+                refund_timelocks: TimelockRange { min: 48, max: 144 },
+                maker2user_timelocks: TimelockRange::only(69),
+            }.into(),
+            Accept {}.into(),
+            UserKeys { keys: vec!["02aa".to_string(); 3] }.into(),
+            UtxoData { utxos: vec![utxo.clone()], change: None }.into(),
+            UtxoData {
+                utxos: vec![utxo.clone(), utxo.clone()],
+                change: Some(ChangeRequest { contribution: 50_000, address: "bcrt1qxyz".to_string(), fold_dust: true }),
+            }.into(),
+            RefundAddress { address: "bcrt1qxyz".to_string() }.into(),
+            HashSourceData { source: format!("invoice:{hash}") }.into(),
+            contract_data.clone().into(),
+            ContractData {
+                denomination: Some(Denomination { amount: 100_000 }),
+                thresholds: Some(PathThresholds { multisig: 3, timelock: 2, hashlock: 3 }),
+                maker_refund_address: Some("bcrt1qxyz".to_string()),
+                ..contract_data
+            }.into(),
+            SignedPsbt { psbt: json.clone() }.into(),
+            SignedPsbt { psbt: base64.clone() }.into(),
+            SigBundle::from_psbt(&signed).into(),
+            SigBundle {
+                txid: Txid::all_zeros().to_string(),
+                inputs: vec![InputSigs {
+                    index: 1,
+                    partial_sigs: vec![("02aa".to_string(), "3044".to_string())],
+                    tap_script_sigs: vec![("aa".to_string(), "bb".to_string(), "cc".to_string())],
+                    final_script_witness: Some(vec![String::new(), "dd".to_string()]),
+                }],
+            }.into(),
+            PayoutRequestData { request: "lightning:lnbcrt1".to_string(), amount: 90_000 }.into(),
+            second_contract.clone().into(),
+            SecondContractData { hash_kind: Some(HashKind::Hash160), ..second_contract }.into(),
+            LightningPayout { preimage: hash.clone() }.into(),
+            PreimageHandover { preimage: hash, key: wif.clone() }.into(),
+            PrivKeyHandover { key: wif }.into(),
+            Waiting { missing: 2 }.into(),
+            FundingBump { fee_rate: 4.5, funding: json.clone(), refund: base64.clone(), refund_ladder: vec![] }.into(),
+            FundingKept {}.into(),
+            AddInput { utxo }.into(),
+            InputAdded { funding: json, refund: base64.clone(), refund_ladder: vec![base64] }.into(),
+            Decline { reason: "Too expensive".to_string() }.into(),
+            Rematch { reason: "A user declined".to_string() }.into(),
+            Abort { code: AbortCode::Refused, reason: "Full".to_string() }.into(),
+            Ping {}.into(),
+            Pong {}.into(),
+        ];
+
+        for message in &messages {
+            let encoded = serde_json::to_string(message).unwrap();
+            assert_eq!(&serde_json::from_str::<Message>(&encoded).unwrap(), message, "{encoded}");
+        }
+        // One of each
+        let kinds: HashSet<_> = messages.iter().map(std::mem::discriminant).collect();
+        let names: HashSet<_> = messages.iter().map(Message::name).collect();
+        assert_eq!((kinds.len(), names.len()), (25, 25));
+    }
+
+    // The BIP-174 test vector with a P2PKH input, which Bitcoin Core decodes, and the same tx as JSON
+    #[test]
+    fn base64_psbts() {
+        let vector = "cHNidP8BAHUCAAAAASaBcTce3/KF6Tet7qSze3gADAVmy7OtZGQXE8pCFxv2AAAAAAD+////AtPf9QUA\
+            AAAAGXapFNDFmQPFusKGh2DpD9UhpGZap2UgiKwA4fUFAAAAABepFDVF5uM7gyxHBQ8k0+65PJwDlIvHh7MuEwAAAQD9pQEBAAAA\
+            AAECiaPHHqtNIOA3G7ukzGmPopXJRjr6Ljl/hTPMti+VZ+UBAAAAFxYAFL4Y0VKpsBIDna89p95PUzSe7LmF/////4b4qkOnHf8U\
+            SIk6UwpyN+9rRgi7st0tAXHmOuxqSJC0AQAAABcWABT+Pp7xp0XpdNkCxDVZQ6vLNL1TU/////8CAMLrCwAAAAAZdqkUhc/xCX/Z\
+            4Ai7NK9wnGIZeziXikiIrHL++E4sAAAAF6kUM5cluiHv1irHU6m80GfWx6ajnQWHAkcwRAIgJxK+IuAnDzlPVoMR3HyppolwuAJf\
+            3TskAinwf4pfOiQCIAGLONfc0xTnNMkna9b7QPZzMlvEuqFEyADS8vAtsnZcASED0uFWdJQbrUqZY3LLh+GFbTZSYG2YVi/jnF6e\
+            fkE/IQUCSDBFAiEA0SuFLYXc2WHS9fSrZgZU327tzHlMDDPOXMMJ/7X85Y0CIGczio4OFyXBl/saiK9Z9R5E5CVbIBZ8hoQDHAXR\
+            8lkqASECI7cr7vCWXRC+B3jv7NYfysb3mk6haTkzgHNEZPhPKrMAAAAAAAAA";
+
+        let psbt = WirePsbt::Base64(vector.to_string()).decode().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        let values: Vec<_> = psbt.unsigned_tx.output.iter().map(|txout| txout.value).collect();
+        assert_eq!(values, [99_999_699, 100_000_000]);
+        assert!(psbt.inputs[0].non_witness_utxo.is_some());
+        assert_eq!(WirePsbt::encode(&psbt, PsbtEncoding::Base64), WirePsbt::Base64(vector.to_string()));
+
+        // Through a message, either way
+        for encoding in [PsbtEncoding::Base64, PsbtEncoding::Json] {
+            let message = Message::from(SignedPsbt { psbt: WirePsbt::encode(&psbt, encoding) });
+            let Message::SignedPsbt(signed) = serde_json::from_str(&serde_json::to_string(&message).unwrap()).unwrap()
+            else {
+                panic!("Expected a signed psbt");
+            };
+            assert_eq!(signed.psbt.decode().unwrap(), psbt);
+        }
+
+        let mut corrupted = vector.to_string();
+        corrupted.truncate(vector.len() - 8);
+        assert!(matches!(WirePsbt::Base64(corrupted).decode(), Err(PsbtReadError::Decode(_))));
+        assert!(matches!(WirePsbt::Base64("cHNidP8".to_string()).decode(), Err(PsbtReadError::Decode(_))));
+    }
+
+    fn connection_pair() -> (Connection<DuplexStream>, Connection<DuplexStream>) {
+        let (ours, theirs) = duplex(1 << 16);
+        (Connection::new(ours, Framing::default()), Connection::new(theirs, Framing::default()))
+    }
+
+    // The peer's side of a long wait: it answers the first `pongs` pings, if any, then sends a waiting
+    // status. How many pings it got.
+    async fn slow_peer(mut conn: Connection<DuplexStream>, pongs: Option<usize>) -> usize {
+        let mut pings = 0;
+        while let Ok(frame) = conn.reader.read_frame(MAX_MESSAGE_SIZE).await {
+            assert_eq!(serde_json::from_str::<Message>(&frame).unwrap(), Ping {}.into());
+            pings += 1;
+            match pongs {
+                Some(pongs) if pings == pongs => break,
+                Some(_) => send_msg(&Pong {}.into(), &mut conn.writer).await.unwrap(),
+                None => {},
+            }
+        }
+        let _ = send_msg(&Waiting { missing: 0 }.into(), &mut conn.writer).await;
+        pings
+    }
+
+    #[tokio::test]
+    async fn keepalive_through_a_slow_phase() {
+        let keepalive = Keepalive { interval: Duration::from_millis(20), max_missed: 2 };
+
+        // Answered pings keep the connection alive for longer than `max_missed` intervals
+        let (mut ours, theirs) = connection_pair();
+        let waiting = expect_msg_alive::<Waiting, _, _>(&mut ours.reader, &mut ours.writer, keepalive);
+        let (waiting, pings) = tokio::join!(waiting, slow_peer(theirs, Some(6)));
+        assert_eq!(waiting.unwrap(), Waiting { missing: 0 });
+        assert_eq!(pings, 6);
+
+        // Unanswered, until we give up
+        let (mut ours, theirs) = connection_pair();
+        let lost = async {
+            let lost = expect_msg_alive::<Waiting, _, _>(&mut ours.reader, &mut ours.writer, keepalive).await;
+            drop(ours);
+            lost
+        };
+        let (lost, pings) = tokio::join!(lost, slow_peer(theirs, None));
+        assert!(matches!(lost, Err(ProtocolError::ConnectionLost { missed: 2 })), "{lost:?}");
+        assert!(lost.unwrap_err().is_disconnect());
+        assert_eq!(pings, 2);
+    }
+
+    #[test]
+    fn negotiation() {
+        let ours = Hello::new(Network::Regtest, 2, PsbtEncoding::Json, false, false, true, true);
+        let theirs = Hello::new(Network::Regtest, 2, PsbtEncoding::Base64, false, false, false, false);
+        assert_eq!(ours.negotiate(&theirs).unwrap(), Negotiated {
+            psbt_encoding: PsbtEncoding::Json,
+            sig_bundles: true,
+            waiting_status: true,
+            partial_spends: true,
+            funding_bumps: false,
+            anyone_can_pay: false,
+        });
+
+        let ours = Hello::new(Network::Regtest, 2, PsbtEncoding::Base64, false, false, true, true);
+        let features = [BASE64_PSBT, FUNDING_BUMPS, ANYONE_CAN_PAY].map(str::to_string).to_vec();
+        let theirs = Hello { features, ..ours.clone() };
+        assert_eq!(ours.negotiate(&theirs).unwrap(), Negotiated {
+            psbt_encoding: PsbtEncoding::Base64,
+            sig_bundles: false,
+            waiting_status: false,
+            partial_spends: false,
+            funding_bumps: true,
+            anyone_can_pay: true,
+        });
+
+        // Unknown features are left out
+        let theirs = Hello { features: vec!["teleport".to_string()], ..ours.clone() };
+        assert_eq!(ours.negotiate(&theirs).unwrap().psbt_encoding, PsbtEncoding::Json);
+
+        let incompatible = [
+            Hello { version: PROTOCOL_VERSION + 1, ..ours.clone() },
+            Hello { network: Network::Bitcoin.to_string(), ..ours.clone() },
+            Hello { round_users: 3, ..ours.clone() },
+            Hello::new(Network::Regtest, 2, PsbtEncoding::Base64, true, false, true, true),
+            Hello::new(Network::Regtest, 2, PsbtEncoding::Base64, false, true, true, true),
+        ];
+        for theirs in incompatible {
+            assert!(matches!(ours.negotiate(&theirs), Err(ProtocolError::Incompatible(_))), "{theirs:?}");
+            assert!(matches!(theirs.negotiate(&ours), Err(ProtocolError::Incompatible(_))), "{theirs:?}");
+        }
+    }
+
+    // A PSBT spending a coin of `prv_key`, and the same one signed
+    fn signed_psbt(prv_key: PrivateKey, value: u64) -> (Psbt, Psbt) {
+        let wallet = Wallet::new(&format!("wpkh({prv_key})"), None, Network::Regtest, MemoryDatabase::new()).unwrap();
+        let spk = Address::p2wpkh(&prv_key.public_key(&Secp256k1::new()), Network::Regtest).unwrap().script_pubkey();
+        let tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_inner([3; 32]), 0),
+                script_sig: Default::default(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut { value: value - 1_000, script_pubkey: spk.clone() }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut { value, script_pubkey: spk });
+
+        let mut signed = psbt.clone();
+        let sign_ops = SignOptions { trust_witness_utxo: true, try_finalize: false, ..Default::default() };
+        wallet.sign(&mut signed, sign_ops).unwrap();
+        (psbt, signed)
+    }
+
+    // A whole signed PSBT must be for the tx we hold, a peer can't swap in another one
+    #[test]
+    fn signed_psbt_with_a_tweaked_output() {
+        let (held, signed) = signed_psbt(PrivateKey::new(secret_key(1), Network::Regtest), 100_000);
+        assert_eq!(SignedUpdate::Psbt(signed.clone()).into_psbt(&held).unwrap(), signed);
+
+        let mut tweaked = signed;
+        tweaked.unsigned_tx.output[0].value -= 10_000;
+        let (expected, actual) = (held.unsigned_tx.txid(), tweaked.unsigned_tx.txid());
+        let mismatch = SignedUpdate::Psbt(tweaked).into_psbt(&held);
+        let Err(PsbtReadError::TxidMismatch { expected: held_txid, actual: got }) = mismatch else {
+            panic!("Expected a txid mismatch, got {mismatch:?}");
+        };
+        assert_eq!((held_txid, got), (expected, actual));
+    }
+
+    #[test]
+    fn sig_bundles_apply_onto_the_held_psbt() {
+        let prv_key = PrivateKey::new(secret_key(1), Network::Regtest);
+        let (held, signed) = signed_psbt(prv_key, 100_000);
+        let bundle = SigBundle::from_psbt(&signed);
+        assert_eq!(bundle.inputs.len(), 1);
+
+        // Through the wire format too
+        let bundle: SigBundle = serde_json::from_str(&serde_json::to_string(&bundle).unwrap()).unwrap();
+        assert_eq!(bundle.clone().apply(&held).unwrap(), signed);
+        assert!(SigBundle::from_psbt(&held).inputs.is_empty());
+
+        // For another tx
+        let (other, other_signed) = signed_psbt(prv_key, 200_000);
+        assert!(matches!(bundle.clone().apply(&other), Err(PsbtReadError::TxidMismatch { .. })));
+
+        // With the signature of another tx
+        let key = prv_key.public_key(&Secp256k1::new());
+        let other_sig = SigBundle::from_psbt(&other_signed).inputs[0].partial_sigs[0].1.clone();
+        let mut forged = bundle.clone();
+        forged.inputs[0].partial_sigs[0].1 = other_sig;
+        assert!(matches!(
+            forged.apply(&held),
+            Err(PsbtReadError::BadSignature { input: 0, key: bad, status: SigStatus::Invalid }) if bad == key,
+        ));
+
+        // With its sighash flipped
+        let mut flipped = bundle.clone();
+        let sig = &mut flipped.inputs[0].partial_sigs[0].1;
+        sig.replace_range(sig.len() - 2.., "83");
+        assert!(matches!(
+            flipped.apply(&held),
+            Err(PsbtReadError::BadSignature { input: 0, status: SigStatus::NonStandardSighash(_), .. }),
+        ));
+
+        // Past the inputs, or not a signature at all
+        let mut past = bundle.clone();
+        past.inputs[0].index = 1;
+        assert!(matches!(past.apply(&held), Err(PsbtReadError::Decode(_))));
+        let mut garbage = bundle;
+        garbage.inputs[0].partial_sigs[0].1 = "00".to_string();
+        assert!(matches!(garbage.apply(&held), Err(PsbtReadError::Decode(_))));
+    }
+
+    // The same signatures as a bundle take a fraction of the bytes of the whole PSBT
+    #[tokio::test]
+    async fn sig_bundles_on_the_wire() {
+        let (held, signed) = signed_psbt(PrivateKey::new(secret_key(1), Network::Regtest), 100_000);
+        let mut sizes = Vec::new();
+        for sig_bundles in [false, true] {
+            let (ours, mut theirs) = duplex(1 << 16);
+            let mut writer = FramedWriter::new(ours, Framing::default());
+            writer.set_sig_bundles(sig_bundles);
+            send_signed_psbt(&signed, &mut writer).await.unwrap();
+            drop(writer);
+
+            let mut wire = Vec::new();
+            theirs.read_to_end(&mut wire).await.unwrap();
+            sizes.push(wire.len());
+            let mut reader = FramedReader::new(wire.as_slice(), Framing::default());
+            assert_eq!(read_signed_psbt(&mut reader).await.unwrap().into_psbt(&held).unwrap(), signed);
+        }
+        let [whole, bundle] = sizes[..] else { unreachable!() };
+        assert!(bundle * 3 < whole, "{bundle} bytes as a bundle, {whole} as a PSBT");
+    }
+}
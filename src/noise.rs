@@ -0,0 +1,474 @@
+// Encryption of the maker-user connections with the Noise_XK handshake, as Lightning does it (BOLT 8):
+// secp256k1 keys, ChaCha20-Poly1305 and SHA256. Users learn the maker static key with its address,
+// so nobody else can read what they send, and use a fresh static key on every connection so their
+// two identities can't be linked by it.
+
+use std::io;
+
+use bdk::bitcoin::hashes::{sha256, Hash, HashEngine, Hmac, HmacEngine};
+use bdk::bitcoin::secp256k1::ecdh::SharedSecret;
+use bdk::bitcoin::secp256k1::rand::thread_rng;
+use bdk::bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::error::ProtocolError;
+use crate::transport::Connection;
+
+const PROTOCOL_NAME: &[u8] = b"Noise_XK_secp256k1_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"joinswap";
+// Leading byte of every handshake message
+const HANDSHAKE_VERSION: u8 = 0;
+
+// Bytes the encryption adds to each message
+pub const TAG_LEN: usize = 16;
+// Messages each key encrypts before both sides move to the next one, as in BOLT 8
+const KEY_ROTATION_INTERVAL: u64 = 1000;
+
+const ACT_ONE_LEN: usize = 1 + 33 + TAG_LEN;
+const ACT_TWO_LEN: usize = 1 + 33 + TAG_LEN;
+const ACT_THREE_LEN: usize = 1 + 33 + 2 * TAG_LEN;
+
+// Leading byte of the message a maker sends instead of act two when it turns the user away, followed
+// by the reason in UTF-8. It comes in the clear, as no keys were agreed yet.
+pub(crate) const REFUSAL: u8 = 0xff;
+// Longest refusal we send or read, the byte included
+pub(crate) const MAX_REFUSAL_LEN: usize = 200;
+
+// The key and message counter for one direction of an encrypted connection, and the chaining key
+// the next key derives from
+pub struct CipherState {
+    key: [u8; 32],
+    nonce: u64,
+    chaining_key: [u8; 32],
+}
+
+impl CipherState {
+    fn new(key: [u8; 32], chaining_key: [u8; 32]) -> Self {
+        CipherState { key, nonce: 0, chaining_key }
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = encrypt_with_ad(&self.key, self.nonce, &[], plaintext);
+        self.advance();
+
+        ciphertext
+    }
+
+    // A message that doesn't decrypt was forged, altered or sent out of order
+    pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let plaintext = decrypt_with_ad(&self.key, self.nonce, &[], ciphertext).ok_or(ProtocolError::Undecryptable)?;
+        self.advance();
+
+        Ok(plaintext)
+    }
+
+    // So a leaked key only reveals the messages it encrypted, not the earlier ones
+    fn advance(&mut self) {
+        self.nonce += 1;
+        if self.nonce == KEY_ROTATION_INTERVAL {
+            (self.chaining_key, self.key) = hkdf(&self.chaining_key, &self.key);
+            self.nonce = 0;
+        }
+    }
+}
+
+// Turns away a user that sent (or is about to send) act one
+pub async fn refuse<S: AsyncRead + AsyncWrite>(conn: &mut Connection<S>, reason: &str) -> io::Result<()> {
+    let reason = reason.as_bytes();
+    let reason = &reason[..reason.len().min(MAX_REFUSAL_LEN - 1)];
+
+    conn.writer.write_handshake(&[&[REFUSAL][..], reason].concat()).await
+}
+
+// Opens the connection as the initiator, to the maker with static key `maker`. Fails if the peer
+// doesn't hold the secret of that key.
+pub async fn initiate<S: AsyncRead + AsyncWrite>(
+    conn: &mut Connection<S>,
+    maker: &PublicKey,
+) -> Result<(), ProtocolError> {
+    let static_key = SecretKey::new(&mut thread_rng());
+    let mut initiator = Initiator::new(PROLOGUE, maker, static_key, SecretKey::new(&mut thread_rng()));
+
+    conn.writer.write_handshake(&initiator.act_one()).await?;
+    let act_two = conn.reader.read_handshake(ACT_TWO_LEN).await?;
+    let (act_three, send, receive) = initiator.act_three(&act_two)?;
+    conn.writer.write_handshake(&act_three).await?;
+
+    conn.writer.encrypt_with(send);
+    conn.reader.decrypt_with(receive);
+    Ok(())
+}
+
+// Answers the handshake of a user as the responder, with our static key
+pub async fn respond<S: AsyncRead + AsyncWrite>(
+    conn: &mut Connection<S>,
+    static_key: &SecretKey,
+) -> Result<(), ProtocolError> {
+    let mut responder = Responder::new(PROLOGUE, *static_key, SecretKey::new(&mut thread_rng()));
+
+    let act_one = conn.reader.read_handshake(ACT_ONE_LEN).await?;
+    conn.writer.write_handshake(&responder.act_two(&act_one)?).await?;
+    let act_three = conn.reader.read_handshake(ACT_THREE_LEN).await?;
+    let (send, receive) = responder.read_act_three(&act_three)?;
+
+    conn.writer.encrypt_with(send);
+    conn.reader.decrypt_with(receive);
+    Ok(())
+}
+
+// Our side of the handshake as the initiator, apart from the I/O
+struct Initiator {
+    state: HandshakeState,
+    remote_static: PublicKey,
+    static_key: SecretKey,
+    ephemeral: SecretKey,
+}
+
+impl Initiator {
+    fn new(prologue: &[u8], remote_static: &PublicKey, static_key: SecretKey, ephemeral: SecretKey) -> Self {
+        let state = HandshakeState::new(prologue, remote_static);
+
+        Initiator { state, remote_static: *remote_static, static_key, ephemeral }
+    }
+
+    // Act one: our ephemeral key, mixed with the remote static key
+    fn act_one(&mut self) -> Vec<u8> {
+        let ephemeral_pub = PublicKey::from_secret_key(&Secp256k1::new(), &self.ephemeral);
+        self.state.mix_hash(&ephemeral_pub.serialize());
+        let temp_k1 = self.state.mix_key(&self.remote_static, &self.ephemeral);
+        let tag = self.state.encrypt_and_hash(&temp_k1, 0, &[]);
+
+        [&[HANDSHAKE_VERSION][..], &ephemeral_pub.serialize(), &tag].concat()
+    }
+
+    // Reads act two, the remote ephemeral key, and answers with act three: our static key, encrypted.
+    // Along with the ciphers we send and receive with.
+    fn act_three(mut self, act_two: &[u8]) -> Result<(Vec<u8>, CipherState, CipherState), ProtocolError> {
+        let remote_ephemeral = handshake_key(act_two)?;
+        self.state.mix_hash(&remote_ephemeral.serialize());
+        let temp_k2 = self.state.mix_key(&remote_ephemeral, &self.ephemeral);
+        self.state.decrypt_and_hash(&temp_k2, 0, &act_two[34..])?;
+
+        let static_pub = PublicKey::from_secret_key(&Secp256k1::new(), &self.static_key);
+        let encrypted_key = self.state.encrypt_and_hash(&temp_k2, 1, &static_pub.serialize());
+        let temp_k3 = self.state.mix_key(&remote_ephemeral, &self.static_key);
+        let tag = self.state.encrypt_and_hash(&temp_k3, 0, &[]);
+
+        let (send, receive) = self.state.split();
+        Ok(([&[HANDSHAKE_VERSION][..], &encrypted_key, &tag].concat(), send, receive))
+    }
+}
+
+// Our side of the handshake as the responder, apart from the I/O
+struct Responder {
+    state: HandshakeState,
+    static_key: SecretKey,
+    ephemeral: SecretKey,
+    // The key act three is encrypted with, known once we sent act two
+    temp_k2: Option<[u8; 32]>,
+}
+
+impl Responder {
+    fn new(prologue: &[u8], static_key: SecretKey, ephemeral: SecretKey) -> Self {
+        let state = HandshakeState::new(prologue, &PublicKey::from_secret_key(&Secp256k1::new(), &static_key));
+
+        Responder { state, static_key, ephemeral, temp_k2: None }
+    }
+
+    // Reads act one, the remote ephemeral key, which only decrypts if the initiator knows our static
+    // key, and answers with act two: our ephemeral key
+    fn act_two(&mut self, act_one: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let remote_ephemeral = handshake_key(act_one)?;
+        self.state.mix_hash(&remote_ephemeral.serialize());
+        let temp_k1 = self.state.mix_key(&remote_ephemeral, &self.static_key);
+        self.state.decrypt_and_hash(&temp_k1, 0, &act_one[34..])?;
+
+        let ephemeral_pub = PublicKey::from_secret_key(&Secp256k1::new(), &self.ephemeral);
+        self.state.mix_hash(&ephemeral_pub.serialize());
+        let temp_k2 = self.state.mix_key(&remote_ephemeral, &self.ephemeral);
+        let tag = self.state.encrypt_and_hash(&temp_k2, 0, &[]);
+        self.temp_k2 = Some(temp_k2);
+
+        Ok([&[HANDSHAKE_VERSION][..], &ephemeral_pub.serialize(), &tag].concat())
+    }
+
+    // Reads act three, the remote static key. The ciphers we send and receive with follow.
+    fn read_act_three(mut self, act_three: &[u8]) -> Result<(CipherState, CipherState), ProtocolError> {
+        let temp_k2 = self.temp_k2.expect("Act two comes before act three");
+        if act_three[0] != HANDSHAKE_VERSION {
+            return Err(ProtocolError::Handshake(format!("Unknown handshake version {}", act_three[0])));
+        }
+        let remote_static = self.state.decrypt_and_hash(&temp_k2, 1, &act_three[1..50])?;
+        let remote_static = PublicKey::from_slice(&remote_static)
+            .map_err(|e| ProtocolError::Handshake(format!("Invalid static key: {e}")))?;
+        let temp_k3 = self.state.mix_key(&remote_static, &self.ephemeral);
+        self.state.decrypt_and_hash(&temp_k3, 0, &act_three[50..])?;
+
+        let (receive, send) = self.state.split();
+        Ok((send, receive))
+    }
+}
+
+// The chaining key and handshake hash, which both sides update in the same way
+struct HandshakeState {
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+}
+
+impl HandshakeState {
+    fn new(prologue: &[u8], responder: &PublicKey) -> Self {
+        let hash = sha256::Hash::hash(PROTOCOL_NAME).into_inner();
+        let mut state = HandshakeState { chaining_key: hash, hash };
+
+        state.mix_hash(prologue);
+        state.mix_hash(&responder.serialize());
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = sha256::Hash::hash(&[&self.hash[..], data].concat()).into_inner();
+    }
+
+    // Mixes the ECDH of the keys into the chaining key, returning a key for the next handshake message
+    fn mix_key(&mut self, point: &PublicKey, scalar: &SecretKey) -> [u8; 32] {
+        let shared = SharedSecret::new(point, scalar);
+        let (chaining_key, temp_key) = hkdf(&self.chaining_key, &shared.secret_bytes());
+        self.chaining_key = chaining_key;
+
+        temp_key
+    }
+
+    fn encrypt_and_hash(&mut self, key: &[u8; 32], nonce: u64, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = encrypt_with_ad(key, nonce, &self.hash, plaintext);
+        self.mix_hash(&ciphertext);
+
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, key: &[u8; 32], nonce: u64, ciphertext: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let plaintext = decrypt_with_ad(key, nonce, &self.hash, ciphertext)
+            .ok_or_else(|| ProtocolError::Handshake("Peer sent a handshake message that doesn't decrypt".to_string()))?;
+        self.mix_hash(ciphertext);
+
+        Ok(plaintext)
+    }
+
+    // The ciphers of each direction, the initiator sends with the first one
+    fn split(&self) -> (CipherState, CipherState) {
+        let (first, second) = hkdf(&self.chaining_key, &[]);
+
+        (CipherState::new(first, self.chaining_key), CipherState::new(second, self.chaining_key))
+    }
+}
+
+// The ephemeral key of act one or two
+fn handshake_key(act: &[u8]) -> Result<PublicKey, ProtocolError> {
+    if act[0] != HANDSHAKE_VERSION {
+        return Err(ProtocolError::Handshake(format!("Unknown handshake version {}", act[0])));
+    }
+    PublicKey::from_slice(&act[1..34]).map_err(|e| ProtocolError::Handshake(format!("Invalid ephemeral key: {e}")))
+}
+
+// HKDF with SHA256, taking the two 32 byte outputs Noise uses
+fn hkdf(salt: &[u8; 32], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let prk = hmac(salt, ikm);
+    let first = hmac(&prk, &[1]);
+    let second = hmac(&prk, &[&first[..], &[2]].concat());
+
+    (first, second)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key);
+    engine.input(data);
+
+    Hmac::from_engine(engine).into_inner()
+}
+
+// The 96 bit nonce is 32 zero bits followed by the counter in little endian
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn cipher(key: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).expect("Keys are 32 bytes"))
+}
+
+fn encrypt_with_ad(key: &[u8; 32], counter: u64, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut in_out = plaintext.to_vec();
+    cipher(key)
+        .seal_in_place_append_tag(nonce(counter), Aad::from(ad), &mut in_out)
+        .expect("Messages are far below the ChaCha20-Poly1305 limit");
+
+    in_out
+}
+
+fn decrypt_with_ad(key: &[u8; 32], counter: u64, ad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    let mut in_out = ciphertext.to_vec();
+    let plaintext = cipher(key).open_in_place(nonce(counter), Aad::from(ad), &mut in_out).ok()?;
+
+    Some(plaintext.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk::bitcoin::hashes::hex::{FromHex, ToHex};
+
+    use super::*;
+    use crate::transport::Framing;
+
+    // The handshake and message vectors of BOLT 8, Appendix A. Lightning uses another prologue.
+    const BOLT8_PROLOGUE: &[u8] = b"lightning";
+    const ACT_ONE: &str = "00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df608655115\
+                           1f58b8afe6c195782c6a";
+    const ACT_TWO: &str = "0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac\
+                           583c9ef6eafca3f730ae";
+    const ACT_THREE: &str = "00b9e3a702e93e3a9948c2ed6e5fd7590a6e1c3a0344cfc9d5b57357049aa22355361aa02e55a8fc\
+                             28fef5bd6d71ad0c38228dc68b1c466263b47fdf31e560e139ba";
+    const SEND_KEY: &str = "969ab31b4d288cedf6218839b27a3e2140827047f2c0f01bf5c04435d43511a9";
+    const RECEIVE_KEY: &str = "bb9020b8965f4df047e07f955f3c4b88418984aadc5cdb35096b9ea8fa5c3442";
+    const CHAINING_KEY: &str = "919219dbb2920afa8db80f9a51787a840bcf111ed8d588caf9ab4be716e42b01";
+
+    fn key(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    fn initiator() -> Initiator {
+        let responder_static = PublicKey::from_secret_key(&Secp256k1::new(), &key(0x21));
+        assert_eq!(responder_static.to_string(), "028d7500dd4c12685d1f568b4c2b5048e8534b873319f3a8daa612b469132ec7f7");
+
+        Initiator::new(BOLT8_PROLOGUE, &responder_static, key(0x11), key(0x12))
+    }
+
+    fn responder() -> Responder {
+        Responder::new(BOLT8_PROLOGUE, key(0x21), key(0x22))
+    }
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        Vec::from_hex(hex).unwrap()
+    }
+
+    // The ciphers of both sides once the handshake of the vectors is done
+    fn handshake() -> ((CipherState, CipherState), (CipherState, CipherState)) {
+        let mut initiator = initiator();
+        let mut responder = responder();
+        let act_two = responder.act_two(&initiator.act_one()).unwrap();
+        let (act_three, send, receive) = initiator.act_three(&act_two).unwrap();
+
+        ((send, receive), responder.read_act_three(&act_three).unwrap())
+    }
+
+    #[test]
+    fn handshake_vectors() {
+        let mut initiator = initiator();
+        assert_eq!(initiator.act_one().to_hex(), ACT_ONE);
+
+        let mut responder = responder();
+        assert_eq!(responder.act_two(&bytes(ACT_ONE)).unwrap().to_hex(), ACT_TWO);
+
+        let (act_three, send, receive) = initiator.act_three(&bytes(ACT_TWO)).unwrap();
+        assert_eq!(act_three.to_hex(), ACT_THREE);
+        assert_eq!((send.key.to_hex(), receive.key.to_hex()), (SEND_KEY.to_string(), RECEIVE_KEY.to_string()));
+        assert_eq!(send.chaining_key.to_hex(), CHAINING_KEY);
+
+        let (send, receive) = responder.read_act_three(&act_three).unwrap();
+        assert_eq!((send.key.to_hex(), receive.key.to_hex()), (RECEIVE_KEY.to_string(), SEND_KEY.to_string()));
+        assert_eq!(receive.chaining_key.to_hex(), CHAINING_KEY);
+    }
+
+    #[test]
+    fn bad_act_one() {
+        let mut bad_version = bytes(ACT_ONE);
+        bad_version[0] = 1;
+        let mut bad_key = bytes(ACT_ONE);
+        bad_key[1] = 4;
+        let mut bad_tag = bytes(ACT_ONE);
+        *bad_tag.last_mut().unwrap() ^= 1;
+
+        for act_one in [bad_version, bad_key, bad_tag] {
+            assert!(matches!(responder().act_two(&act_one), Err(ProtocolError::Handshake(_))));
+        }
+        // Sent to another static key than ours, it doesn't decrypt
+        let mut other = Responder::new(BOLT8_PROLOGUE, key(0x23), key(0x22));
+        assert!(matches!(other.act_two(&bytes(ACT_ONE)), Err(ProtocolError::Handshake(_))));
+    }
+
+    #[test]
+    fn bad_act_three() {
+        let mut bad_version = bytes(ACT_THREE);
+        bad_version[0] = 1;
+        let mut bad_key = bytes(ACT_THREE);
+        bad_key[2] ^= 1;
+        let mut bad_tag = bytes(ACT_THREE);
+        *bad_tag.last_mut().unwrap() ^= 1;
+
+        for act_three in [bad_version, bad_key, bad_tag] {
+            let mut responder = responder();
+            responder.act_two(&bytes(ACT_ONE)).unwrap();
+            assert!(matches!(responder.read_act_three(&act_three), Err(ProtocolError::Handshake(_))));
+        }
+    }
+
+    // Lightning messages are the encrypted length followed by the encrypted body, so each takes two
+    // nonces and the keys rotate every 500 messages
+    #[test]
+    fn message_vectors() {
+        let ((mut send, _), (_, mut receive)) = handshake();
+        let vectors = [
+            (0, "cf2b30ddf0cf3f80e7c35a6e6730b59fe802473180f396d88a8fb0db8cbcf25d2f214cf9ea1d95"),
+            (1, "72887022101f0b6753e0c7de21657d35a4cb2a1f5cde2650528bbc8f837d0f0d7ad833b1a256a1"),
+            (500, "178cb9d7387190fa34db9c2d50027d21793c9bc2d40b1e14dcf30ebeeeb220f48364f7a4c68bf8"),
+            (501, "1b186c57d44eb6de4c057c49940d79bb838a145cb528d6e8fd26dbe50a60ca2c104b56b60e45bd"),
+            (1000, "4a2f3cc3b5e78ddb83dcb426d9863d9d9a723b0337c89dd0b005d89f8d3c05c52b76b29b740f09"),
+            (1001, "2ecd8c8a5629d0d02ab457a0fdd0f7b90a192cd46be5ecb6ca570bfc5e268338b1a16cf4ef2d36"),
+        ];
+
+        for n in 0..=1001 {
+            let message = [send.encrypt(&5u16.to_be_bytes()), send.encrypt(b"hello")].concat();
+            if let Some((_, expected)) = vectors.iter().find(|(at, _)| *at == n) {
+                assert_eq!(message.to_hex(), *expected, "message {n}");
+            }
+            // The other side rotates its keys at the same time
+            let (length, body) = message.split_at(2 + TAG_LEN);
+            assert_eq!(receive.decrypt(length).unwrap(), 5u16.to_be_bytes());
+            assert_eq!(receive.decrypt(body).unwrap(), b"hello");
+        }
+    }
+
+    #[test]
+    fn messages_out_of_order_fail() {
+        let ((mut send, _), (_, mut receive)) = handshake();
+        let first = send.encrypt(b"first");
+        let second = send.encrypt(b"second");
+
+        assert!(matches!(receive.decrypt(&second), Err(ProtocolError::Undecryptable)));
+        assert_eq!(receive.decrypt(&first).unwrap(), b"first");
+        assert!(matches!(receive.decrypt(&first), Err(ProtocolError::Undecryptable)));
+        assert_eq!(receive.decrypt(&second).unwrap(), b"second");
+    }
+
+    #[tokio::test]
+    async fn handshake_over_a_connection() {
+        let maker_key = key(0x21);
+        let public = |key: &SecretKey| PublicKey::from_secret_key(&Secp256k1::new(), key);
+        // A user expecting another static key fails, and so does the maker
+        for (expected, works) in [(public(&maker_key), true), (public(&key(0x23)), false)] {
+            let (user, maker) = tokio::io::duplex(1024);
+            let mut user = Connection::new(user, Framing::default());
+            let mut maker = Connection::new(maker, Framing::default());
+
+            // The maker hangs up on a failed handshake
+            let responding = async move { respond(&mut maker, &maker_key).await.map(|()| maker) };
+            let (initiated, responded) = tokio::join!(initiate(&mut user, &expected), responding);
+            assert_eq!((initiated.is_ok(), responded.is_ok()), (works, works));
+            if let Ok(mut maker) = responded {
+                user.writer.write_frame("over noise").await.unwrap();
+                assert_eq!(maker.reader.read_frame(100).await.unwrap(), "over noise");
+            }
+        }
+    }
+}
@@ -0,0 +1,2740 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bdk::bitcoin::{Address, Network, OutPoint, PrivateKey, psbt, PublicKey, Script, Transaction, TxOut, Txid};
+use bdk::bitcoin::hashes::hex::ToHex;
+use bdk::bitcoin::hashes::{Hash, sha256};
+use bdk::bitcoin::consensus::encode::VarInt;
+use bdk::bitcoin::psbt::Psbt;
+use bdk::bitcoin::secp256k1::rand::seq::SliceRandom;
+use bdk::bitcoin::secp256k1::rand::thread_rng;
+use bdk::bitcoin::secp256k1::{Parity, SecretKey};
+use bdk::database::{AnyDatabase, MemoryDatabase};
+use bdk::descriptor::Descriptor;
+use bdk::miniscript::descriptor::DescriptorType;
+use bdk::wallet::AddressIndex;
+use bdk::{FeeRate, SignOptions, Utxo, Wallet, WeightedUtxo};
+use futures::future::join_all;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader, ReadHalf, WriteHalf};
+use tokio::time::{timeout, timeout_at, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::chain::{chain_call, estimate_fee_rate, ChainAccess, ChainError, FeeEstimator, FixedFeeRate};
+use crate::error::{BuildError, ContractDescError, JoinSwapError, KeyMismatch, ProtocolError, PsbtPart, PsbtReadError,
+                   RefundAddrError, UtxoError};
+use crate::lightning::{obtain_preimage, HashSource, LnBackend, LnError, MakerLightning, PayoutRequest,
+                       PreimageError};
+use crate::keys::{ContractKeySource, KeyRole, SessionKeys};
+use crate::limits::{ConnectionGate, PendingSlot, Refusal};
+use crate::protocol::rounds::{Arrival, RoundInbox, RoundRouter, UsedInputs};
+use crate::recovery::{MakerRecovery, Recovery};
+use crate::shutdown::{save_state, SHUTTING_DOWN};
+use crate::{noise, signing};
+use crate::events::{AbortCode, ContractKind, EventSink, Leg, MessageKind, Phase, ProtocolEvent, TxRole};
+use crate::transport::{Acceptor, Connection, FramedReader, FramedWriter, Framing};
+use crate::message::{expect_msg, expect_msg_alive, read_msg, read_signed_psbt, send_error, send_msg, send_signed_psbt,
+                     with_pings, Accept, ContractData, Expected, HashSourceData, LightningPayout, Message, Offer,
+                     PayoutRequestData, Hello, PreimageHandover, PrivKeyHandover, PsbtEncoding, RefundAddress, Rematch,
+                     SecondContractData, SignedPsbt, UserKeys, UserUtxo, UtxoData, ChangeRequest, Denomination,
+                     FundingBump, FundingKept, Waiting, WirePsbt, read_funding_update, FundingUpdate, InputAdded};
+use crate::{add_contract_signers, add_funding_input, anti_fee_sniping_locktime, build_cooperative_sweep,
+            build_funding_and_refund, build_hashlock_spend, build_timelock_claim, check_prv_keys, contract_keys_by_path,
+            contract_output, contract_wallet, denominate, desc_checksum, rebase_refund, refund_fee_share,
+            gen_payout_hash, like_wallet_txs, set_anyone_can_pay, maker2users_contract_desc,
+            maker2users_contract_desc_tr, parse_contract_keys, parse_prv_key, replacement_failures,
+            users2maker_contract_desc, users2maker_contract_desc_tr, verify_finalized_input, verify_partial_sigs,
+            with_timeout, Keepalive, ReadTimeouts, SigStatus, Change, FeeRates, HashKind, MakerFee, MakerInput,
+            PathThresholds, PayoutHash, TimelockRange, Timelocks, UserFunds, UtxoValueRange, DUST_LIMIT, HASHLOCK_PATH,
+            MAX_USER_UTXOS, MULTISIG_PATH, P2WPKH_SATISFACTION_WEIGHT, TIMELOCK_PATH};
+
+// Users taking part in each round unless configured otherwise
+pub const ROUND_USERS: usize = 2;
+
+// Blocks within which we want the funding txs to confirm. The refund feerate is fixed once signed and
+// the refund is only broadcast when the round went wrong, so it aims for the next ones.
+pub const FUNDING_TARGET: usize = 6;
+pub const REFUND_TARGET: usize = 2;
+
+// A funding tx replacement pays at least this many sat/vB more, see `MakerConfig::funding_bump_after`
+pub const MIN_FEE_BUMP: f32 = 1.0;
+// How often we look for the funding tx confirmation meanwhile
+const CONFIRMATION_POLL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct MakerConfig {
+    pub network: Network,
+    // Users we wait for before starting a round, from `MIN_ROUND_USERS` to `MAX_ROUND_USERS`
+    pub round_users: usize,
+    // What we keep out of what each user puts in the contract, as we advertise it. Each maker2user
+    // contract gets the rest.
+    pub maker_fee: MakerFee,
+    // Smallest profit for which we fund the maker2user contracts
+    pub min_margin: u64,
+    // Set to run rounds of equal amounts, where every user puts in the same
+    pub denomination: Option<DenominationMode>,
+    // Whether we put a utxo of our own in the users2maker contract, so its funding tx has one more
+    // owner behind its inputs. We get it back with the rest of the contract.
+    pub own_input: bool,
+    // Participants each users2maker contract path needs, every one if None. A lower multisig threshold
+    // lets us sweep cooperatively without the keys of some users.
+    pub thresholds: Option<PathThresholds>,
+    // Of the refund path and the maker2user contracts, as we advertise them
+    pub timelocks: Timelocks,
+    // Hash function of the hashes locking the maker2user contracts
+    pub payout_hash_kind: HashKind,
+    // Whether the contracts are taproot ones, users must want them too
+    pub taproot: bool,
+    // How long a user waits for its round to fill up before we send it away, nothing is signed by then
+    pub matchmaking_timeout: Duration,
+    // How long we wait for the users2maker contract keys before falling back to the hashlock path
+    pub handover_timeout: Duration,
+    pub timeouts: ReadTimeouts,
+    pub keepalive: Keepalive,
+    // Framing of the second leg connections, the first leg ones come framed by the caller
+    pub framing: Framing,
+    // PSBT encoding we offer in the handshake
+    pub psbt_encoding: PsbtEncoding,
+    // Static key of the Noise handshake, users must know its pub key to connect to us. None when the
+    // acceptor encrypts the connections on its own (like TLS).
+    pub noise_key: Option<SecretKey>,
+    // Feerates of the funding and refund txs we build, see `FUNDING_TARGET`
+    pub fee_estimator: Arc<dyn FeeEstimator>,
+    // How long we wait for the funding tx to confirm before we replace it at a higher feerate, never if
+    // None. Only with users that accept it, and once per round.
+    pub funding_bump_after: Option<Duration>,
+    // Whether we take a utxo users add to the funding tx before they sign it (see `AddInput`). Only rounds
+    // where every user signs its funding inputs with SIGHASH_ALL|ANYONECANPAY for it take them.
+    pub anyone_can_pay: bool,
+    pub sweep_fee_rate: FeeRate,
+    // Most we pay in routing fees per Lightning payout, users ask for this much less
+    pub ln_fee_allowance: u64,
+    // How long we try to pay an invoice before funding the maker2user contract instead
+    pub ln_payment_timeout: Duration,
+    // Utxos spent by the funding txs of our rounds, which users can't bring again, and their hashes,
+    // as a new round must use a fresh one. Shared by the clones of the config.
+    pub used: UsedInputs,
+    pub utxo_values: UtxoValueRange,
+    // Whether we look the users' utxos up on the chain, to take only confirmed and unspent ones. A demo
+    // chain only knows our own coins.
+    pub lookup_user_utxos: bool,
+    // Once cancelled the round stops wherever it is, see `run_maker_round`
+    pub shutdown: CancellationToken,
+    // Where each round saves its recovery file (see `MakerRecovery`), not saved if None
+    pub state_dir: Option<PathBuf>,
+    // Where the contract keys of our rounds come from, shared by the clones of the config
+    pub contract_keys: ContractKeySource,
+    // Which connections we take, shared by the clones of the config
+    pub gate: ConnectionGate,
+}
+
+impl Default for MakerConfig {
+    fn default() -> Self {
+        MakerConfig {
+            network: Network::Regtest,
+            round_users: ROUND_USERS,
+            maker_fee: MakerFee { base: 1000, ppm: 10_000 },
+            min_margin: 0,
+            denomination: None,
+            own_input: false,
+            thresholds: None,
+            timelocks: Timelocks::default(),
+            payout_hash_kind: HashKind::default(),
+            taproot: false,
+            matchmaking_timeout: Duration::from_secs(10 * 60),
+            handover_timeout: Duration::from_secs(60),
+            timeouts: ReadTimeouts::default(),
+            keepalive: Keepalive::default(),
+            framing: Framing::default(),
+            psbt_encoding: PsbtEncoding::default(),
+            noise_key: Some(SecretKey::new(&mut thread_rng())),
+            fee_estimator: Arc::new(FixedFeeRate(FeeRate::from_sat_per_vb(1.0))),
+            funding_bump_after: None,
+            anyone_can_pay: false,
+            sweep_fee_rate: FeeRate::from_sat_per_vb(1.0),
+            ln_fee_allowance: 100,
+            ln_payment_timeout: Duration::from_secs(30),
+            used: UsedInputs::default(),
+            utxo_values: UtxoValueRange::default(),
+            lookup_user_utxos: true,
+            shutdown: CancellationToken::new(),
+            state_dir: None,
+            contract_keys: ContractKeySource::default(),
+            gate: ConnectionGate::default(),
+        }
+    }
+}
+
+// How we set the amounts of a round of equal amounts, see `denominate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DenominationMode {
+    // Users put in a multiple of this
+    pub step: u64,
+}
+
+// Where the coins for the maker2user contracts and our own input come from, and where the
+// users2maker contract coins go. It's a single wallet for every round, synced with the chain before
+// we spend from it. Clones share it.
+#[derive(Clone)]
+pub struct MakerTreasury(Arc<Mutex<Treasury>>);
+
+struct Treasury {
+    wallet: Wallet<AnyDatabase>,
+    // Utxos spent by txs of our rounds that the wallet hasn't seen spent yet, no other tx can take them
+    locked: HashSet<OutPoint>,
+}
+
+impl MakerTreasury {
+    pub fn new(wallet: Wallet<AnyDatabase>) -> Self {
+        MakerTreasury(Arc::new(Mutex::new(Treasury { wallet, locked: HashSet::new() })))
+    }
+
+    // Once the wallet sees a locked utxo spent it needs no lock
+    pub async fn sync(&self, chain: &Arc<dyn ChainAccess>) -> Result<(), ChainError> {
+        let treasury = self.clone();
+
+        chain_call(chain, move |chain| treasury.sync_blocking(chain)).await
+    }
+
+    fn sync_blocking(&self, chain: &dyn ChainAccess) -> Result<(), ChainError> {
+        let mut treasury = self.0.lock().unwrap();
+        chain.sync_wallet(&treasury.wallet)?;
+
+        let unspent: HashSet<_> = treasury.wallet.list_unspent().map_err(|e| ChainError(e.to_string()))?
+            .into_iter()
+            .map(|utxo| utxo.outpoint)
+            .collect();
+        treasury.locked.retain(|outpoint| unspent.contains(outpoint));
+        Ok(())
+    }
+
+    // As of the last sync, unconfirmed coins included
+    pub fn balance(&self) -> u64 {
+        self.0.lock().unwrap().wallet.get_balance().map_or(0, |balance| balance.get_total())
+    }
+
+    fn lock(&self) -> TreasuryLock {
+        TreasuryLock { treasury: self.clone(), outpoints: Vec::new() }
+    }
+
+    // Where the users2maker contract coins are swept to
+    fn sweep_address(&self) -> Script {
+        self.0.lock().unwrap().wallet.get_address(AddressIndex::New).unwrap().script_pubkey()
+    }
+
+    // Our largest free utxo for the users2maker contract, handed to the funding tx as the users' are
+    fn contract_input(&self, lock: &mut TreasuryLock) -> Result<MakerInput, BuildError> {
+        let mut treasury = self.0.lock().unwrap();
+        let Treasury { wallet, locked } = &mut *treasury;
+
+        let utxo = wallet.list_unspent().map_err(treasury_error)?
+            .into_iter()
+            .filter(|utxo| !locked.contains(&utxo.outpoint))
+            .max_by_key(|utxo| utxo.txout.value)
+            .ok_or_else(|| BuildError::Treasury("No utxo left for our own input".to_string()))?;
+        // Users need the previous tx to check the input value
+        let psbt_input = wallet.get_psbt_input(utxo.clone(), None, false).map_err(treasury_error)?;
+        let satisfaction_weight = wallet.get_descriptor_for_keychain(utxo.keychain).max_satisfaction_weight()
+            .map_err(|e| BuildError::Treasury(e.to_string()))?;
+        let refund_to = wallet.get_address(AddressIndex::New).map_err(treasury_error)?.address;
+
+        locked.insert(utxo.outpoint);
+        lock.outpoints.push(utxo.outpoint);
+        Ok(MakerInput {
+            utxo: WeightedUtxo {
+                satisfaction_weight,
+                utxo: Utxo::Foreign { outpoint: utxo.outpoint, psbt_input: Box::new(psbt_input) },
+            },
+            refund_to,
+        })
+    }
+
+    // Signed tx paying `amount` to the contract of `pub_desc` at `fee_rate`, and its fee. Its locktime comes
+    // from the `tip` of our backend.
+    fn fund_contract(
+        &self,
+        pub_desc: &Descriptor<PublicKey>,
+        amount: u64,
+        fee_rate: FeeRate,
+        tip: u32,
+        lock: &mut TreasuryLock,
+    ) -> Result<(Transaction, u64), BuildError> {
+        let mut treasury = self.0.lock().unwrap();
+        let Treasury { wallet, locked } = &mut *treasury;
+
+        let mut tx_builder = wallet.build_tx();
+        tx_builder
+            .add_recipient(pub_desc.script_pubkey(), amount)
+            .unspendable(locked.iter().copied().collect())
+            .fee_rate(fee_rate);
+        like_wallet_txs(&mut tx_builder, anti_fee_sniping_locktime(&mut thread_rng(), tip));
+        let (mut psbt, details) = tx_builder.finish().map_err(|e| match e {
+            bdk::Error::InsufficientFunds { needed, available } => BuildError::Treasury(format!(
+                "A maker2user contract of {amount} sats takes {needed} with the fee, we have {available} left",
+            )),
+            e => treasury_error(e),
+        })?;
+
+        // We tell the user its contract pays its payout, so it must get exactly that in a single output
+        let spk = pub_desc.script_pubkey();
+        let funded: Vec<_> = psbt.unsigned_tx.output.iter().filter(|txout| txout.script_pubkey == spk).collect();
+        if !matches!(funded.as_slice(), [txout] if txout.value == amount) {
+            return Err(BuildError::Treasury(format!("The maker2user funding tx doesn't pay {amount} sats once")));
+        }
+
+        if !wallet.sign(&mut psbt, SignOptions::default()).map_err(treasury_error)? {
+            return Err(BuildError::Treasury("Can't sign every input of the maker2user funding tx".to_string()));
+        }
+        let tx = psbt.extract_tx();
+        for txin in &tx.input {
+            locked.insert(txin.previous_output);
+            lock.outpoints.push(txin.previous_output);
+        }
+        Ok((tx, details.fee.unwrap_or_default()))
+    }
+
+    // Signs our own input of the users2maker funding tx, returning whether every input is finalized
+    fn sign(&self, psbt: &mut Psbt) -> Result<bool, BuildError> {
+        let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
+
+        self.0.lock().unwrap().wallet.sign(psbt, sign_ops).map_err(treasury_error)
+    }
+
+    // What the wallet got out of `txids`, minus what it spent on them, as of the last sync. Txs it
+    // didn't take part in count for nothing.
+    fn net_received(&self, txids: &[Txid]) -> i64 {
+        let treasury = self.0.lock().unwrap();
+
+        txids.iter()
+            .filter_map(|txid| treasury.wallet.get_tx(txid, false).ok().flatten())
+            .map(|details| details.received as i64 - details.sent as i64)
+            .sum()
+    }
+}
+
+fn treasury_error(e: bdk::Error) -> BuildError {
+    BuildError::Treasury(e.to_string())
+}
+
+// Treasury utxos spent by the txs a round built, which other rounds can't spend too. Given back when
+// dropped, but for those of the txs we broadcast.
+struct TreasuryLock {
+    treasury: MakerTreasury,
+    outpoints: Vec<OutPoint>,
+}
+
+impl TreasuryLock {
+    // The inputs of `tx`, now broadcast, stay locked until the wallet sees them spent
+    fn keep(&mut self, tx: &Transaction) {
+        self.outpoints.retain(|outpoint| tx.input.iter().all(|txin| txin.previous_output != *outpoint));
+    }
+}
+
+impl Drop for TreasuryLock {
+    fn drop(&mut self) {
+        let mut treasury = self.treasury.0.lock().unwrap();
+
+        for outpoint in &self.outpoints {
+            treasury.locked.remove(outpoint);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoundStatus {
+    // The users gave us their hashlock keys, so the users2maker contract coins are ours
+    Completed,
+    // A hashlock key didn't match, so we kept the preimage and the funded maker2user contracts
+    // must be taken back after their timelock
+    HashlockKeysRejected(KeyMismatch),
+    // A user went away or misbehaved after we funded the maker2user contracts, which we must take
+    // back after their timelock
+    PeerAborted { leg: Leg, index: usize, code: AbortCode, reason: String },
+}
+
+// How the maker takes the users2maker contract coins
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SweepPath {
+    // Every user handed over their multisig key, so we swept the coins through the multisig path
+    Cooperative { txid: Txid, fee: u64 },
+    // Some multisig key didn't arrive in time, so we swept the coins through the hashlock path
+    // revealing the preimage
+    Hashlock { txid: Txid, fee: u64 },
+    // We couldn't claim the coins, the users get them back with the refund tx
+    Refund,
+}
+
+#[derive(Debug, Clone)]
+pub struct MakerRoundReport {
+    pub status: RoundStatus,
+    pub sweep: SweepPath,
+    pub users2maker_desc: Descriptor<PublicKey>,
+    // Hash of the users2maker contract, the fallback of every maker2user one
+    pub hash: sha256::Hash,
+    pub funding_txid: Txid,
+    pub refund_txid: Txid,
+    // The users' utxos spent by the funding tx
+    pub user_outpoints: Vec<OutPoint>,
+    pub maker2user_txids: Vec<Txid>,
+    // Timelock path key of each funded maker2user contract, to take the coins back if the users
+    // don't hand over their hashlock keys
+    pub maker2user_timelock_keys: Vec<(Descriptor<PublicKey>, PrivateKey)>,
+    // If the round failed, the timelock path claim of each maker2user contract (see `build_timelock_claim`),
+    // valid once its timelock expires
+    pub maker2user_claims: Vec<Transaction>,
+    // Payment hashes of the invoices paid instead of a maker2user contract
+    pub lightning_payouts: Vec<sha256::Hash>,
+    // Value the users locked in the users2maker contract, what we put in ourselves aside
+    pub total_received: u64,
+    // Value sent to the users in the second leg, on-chain or through Lightning
+    pub maker2user_amounts: u64,
+    // Fees paid by the maker2user funding txs and the Lightning payments
+    pub maker2user_fees: u64,
+    // What the wallet got out of the round txs minus what the Lightning payouts cost, known once we
+    // sweep the users2maker contract cooperatively
+    pub profit: Option<u64>,
+}
+
+// A first leg user joining a round, with the data it already sent if it comes from a round another
+// user declined
+pub struct RoundUser<S> {
+    pub conn: Connection<S>,
+    sent: Option<UserData>,
+}
+
+impl<S> From<Connection<S>> for RoundUser<S> {
+    fn from(conn: Connection<S>) -> Self {
+        RoundUser { conn, sent: None }
+    }
+}
+
+// The connections of a round and its record, which outlive the round if it stops midway
+struct RoundState<S> {
+    readers: Vec<FramedReader<BufReader<ReadHalf<S>>>>,
+    writers: Vec<FramedWriter<WriteHalf<S>>>,
+    // The data of each first leg user, once we have it
+    users: Vec<Option<UserData>>,
+    // The second leg peers, once all connected
+    second_writers: Vec<FramedWriter<WriteHalf<S>>>,
+    record: MakerRecovery,
+}
+
+// Runs one JoinSwap round with the first leg `peers`, already matched and greeted (see
+// `answer_hello`) by the caller. The users resuming their sessions and the second leg connections,
+// made with the users' new identities, are routed to `inbox` by the caller, so several rounds can
+// run at once. Without a preimage source, rounds where a user brings its own invoice hash are
+// refused, and without a Lightning backend every user is paid on-chain.
+//
+// If a user declines the round before signing the funding tx, the other users can go back to
+// matchmaking through `inbox` with the data they sent (see `rematch_users`).
+//
+// Once the funding tx is out, what we need to claim our coins is saved to `config.state_dir` before
+// each step we can't undo (see `MakerRecovery`). If `config.shutdown` is cancelled the round stops
+// where it is, the users are told and that record is saved once more.
+pub async fn run_maker_round<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    config: MakerConfig,
+    treasury: &MakerTreasury,
+    chain: Arc<dyn ChainAccess>,
+    lightning: MakerLightning,
+    peers: Vec<RoundUser<S>>,
+    mut inbox: RoundInbox<S>,
+    events: EventSink,
+) -> Result<MakerRoundReport, JoinSwapError> {
+    assert_eq!(peers.len(), config.round_users);
+
+    let (mut readers, mut writers, mut users) = (Vec::new(), Vec::new(), Vec::new());
+    for RoundUser { conn, sent } in peers {
+        readers.push(conn.reader);
+        writers.push(conn.writer);
+        users.push(sent);
+    }
+    let mut state =
+        RoundState { readers, writers, users, second_writers: Vec::new(), record: MakerRecovery::default() };
+    let (shutdown, state_dir) = (config.shutdown.clone(), config.state_dir.clone());
+    let rematch_timeout = config.timeouts.exchange;
+
+    let round = play_round(config, treasury, chain, lightning, &mut state, &mut inbox, &events);
+    let result = tokio::select! {
+        result = round => Some(result),
+        _ = shutdown.cancelled() => None,
+    };
+    if let Some(result) = result {
+        if let Err(JoinSwapError::Peer { leg: Leg::First, index, error: ProtocolError::Declined(reason) }) = &result {
+            rematch_users(state, *index, reason, rematch_timeout, &inbox, &events).await;
+        }
+        return result;
+    }
+
+    for writer in state.writers.iter_mut().chain(&mut state.second_writers) {
+        let _ = send_error(AbortCode::Shutdown, &SHUTTING_DOWN, writer).await;
+    }
+    let saved = checkpoint(state_dir.as_deref(), &state.record)?;
+    Err(JoinSwapError::Shutdown { saved })
+}
+
+async fn play_round<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    config: MakerConfig,
+    treasury: &MakerTreasury,
+    chain: Arc<dyn ChainAccess>,
+    lightning: MakerLightning,
+    state: &mut RoundState<S>,
+    inbox: &mut RoundInbox<S>,
+    events: &EventSink,
+) -> Result<MakerRoundReport, JoinSwapError> {
+    let RoundState { readers, writers, users, second_writers, record } = state;
+
+    let backends = FirstLegBackends { treasury, chain: &chain, lightning: &lightning };
+    let funded = fund_users2maker(&config, &backends, readers, writers, users, inbox, events).await;
+    let contract = match funded {
+        Ok(contract) => contract,
+        Err(e) => {
+            notify_abort(&e, writers).await;
+            return Err(e);
+        },
+    };
+    let FundedContract {
+        desc: users2maker_desc,
+        multisig_keys,
+        maker_multisig_key: prv_key1,
+        mut signer,
+        session_keys,
+        hashlock_keys,
+        preimage,
+        hash,
+        funding_tx,
+        contract_utxo: (contract_outpoint, contract_txout),
+        refund_txid,
+        user_outpoints,
+        payouts,
+        own_put_in,
+    } = contract;
+    record.users2maker_desc = Some(users2maker_desc.to_string());
+    record.hash = Some(hash);
+    record.preimage = preimage.map(|preimage| preimage.to_hex());
+    record.funding_txid = Some(funding_tx.txid());
+    record.refund_txid = Some(refund_txid);
+    record.timelocks = Some(config.timelocks);
+    record.session = session_keys.session();
+    record.multisig_key = Some(prv_key1);
+    checkpoint(config.state_dir.as_deref(), record)?;
+
+    // Second leg of the JoinSwap: The new peers should give us a blinded certificate to ensure
+    // they are the same participants. Until then we take one new identity per user we owe a payout.
+    events.emit(ProtocolEvent::PhaseEntered(Phase::SecondConnect));
+    let round_users = payouts.len();
+    // The users we already have are pinged while the others connect
+    let timeouts = config.timeouts;
+    let interval = config.keepalive.interval;
+    let mut second_readers = Vec::new();
+    for index in 0..round_users {
+        let second = accept_second(inbox, index, &config);
+        let mut pinged = writers.iter_mut().chain(second_writers.iter_mut()).collect::<Vec<_>>();
+        let Connection { reader, writer } = with_pings(second, &mut pinged, interval).await?;
+        events.emit(ProtocolEvent::PeerConnected { leg: Leg::Second, index });
+        second_readers.push(reader);
+        second_writers.push(writer);
+    }
+
+    // We don't know which first leg user each one was, so each must ask for a payout we owe and that no
+    // other user asked for. A user asking for what another is owed fails the round.
+    let mut owed = payouts;
+    let mut second_users = Vec::new();
+    for (index, (reader, writer)) in second_readers.iter_mut().zip(second_writers.iter_mut()).enumerate() {
+        let user = with_timeout(timeouts.exchange, read_second_user_data(reader)).await;
+        let user = user.and_then(|(key1, key2, request, amount)| match owed.iter().position(|owed| *owed == amount) {
+            Some(position) => {
+                owed.swap_remove(position);
+                Ok((key1, key2, request, amount))
+            },
+            None => {
+                let reason = format!("No user is owed {amount} sats, or another one asked for it");
+                Err(ProtocolError::Malformed { field: "payout amount", reason })
+            },
+        });
+        second_users.push(check_peer(user, Leg::Second, index, writer).await?);
+    }
+    events.emit(ProtocolEvent::MessageReceived(MessageKind::SecondUserData));
+
+    // We will use the old IDs to read the users2maker contract private keys (private key handover)
+    let old_readers = readers;
+    let old_writers = writers;
+    let new_writers = second_writers;
+
+    // Gen our multisig and timelock path keys and build the descriptor for each maker2user contract, each
+    // locked to a payout hash of its own besides the round hash, so the users' contracts don't share a hash
+    // a spend with the payout preimage shows (see `maker2users_contract_desc`)
+    let payout_preimages: Vec<_> =
+        (0..round_users).map(|_| gen_payout_hash(&mut thread_rng(), config.payout_hash_kind)).collect();
+    record.payout_preimages = payout_preimages.iter().map(|(preimage, _)| preimage.to_hex()).collect();
+    let maker2user_keys: Vec<_> = (0..round_users)
+        .map(|user| {
+            let multisig = session_keys.key(KeyRole::Maker2User { user, path: MULTISIG_PATH });
+            (multisig, session_keys.key(KeyRole::Maker2User { user, path: TIMELOCK_PATH }))
+        })
+        .collect();
+    let mut maker2user_descs = Vec::new();
+    for (index, (writer, (key1, hashlock_keys, _, _))) in new_writers.iter_mut().zip(&second_users).enumerate() {
+        let ((_, multisig_key), (timelock_prv_key, timelock_key)) = maker2user_keys[index];
+        // As on the first leg, we sign with the timelock path keys
+        writer.sign_with(timelock_prv_key.inner);
+
+        // Our keys are fresh, so a bad key was sent by the user
+        let (timelock, (_, payout_hash)) = (config.timelocks.maker2user, payout_preimages[index]);
+        let maker2user_desc = match config.taproot {
+            true => maker2users_contract_desc_tr,
+            false => maker2users_contract_desc,
+        };
+        let desc = maker2user_desc(&[*key1, multisig_key], &timelock_key, hashlock_keys, payout_hash, hash, timelock);
+        maker2user_descs.push(check_peer(desc.map_err(Into::into), Leg::Second, index, writer).await?);
+    }
+
+    events.emit(ProtocolEvent::PhaseEntered(Phase::SecondContractCreation));
+    for (index, desc) in maker2user_descs.iter().enumerate() {
+        events.emit(ProtocolEvent::ContractCreated {
+            contract: ContractKind::Maker2User(index),
+            address: desc.address(config.network).unwrap(),
+        });
+    }
+
+    // Build and sign the funding tx for each maker2user contract. Users asking for a Lightning
+    // payout only get it if we can't pay their invoice, the coins of their tx are then freed.
+    treasury.sync(&chain).await?;
+    let mut treasury_lock = treasury.lock();
+    let mut maker2users_txs = Vec::new();
+    let tip = chain_call(&chain, |chain| chain.tip_height()).await?;
+    let fee_rate = estimate_fee_rate(&config.fee_estimator, FUNDING_TARGET).await?;
+    for (desc, (_, _, _, payout)) in maker2user_descs.iter().zip(&second_users) {
+        let (tx, fee) = treasury.fund_contract(desc, *payout, fee_rate, tip, &mut treasury_lock)?;
+        maker2users_txs.push((tx, *payout, fee));
+    }
+
+    // Invoices that we can pay, each one for at most what the user would get on-chain
+    let mut invoices = Vec::new();
+    for (_, _, request, payout) in second_users {
+        let invoice = match (request, &lightning.backend) {
+            (PayoutRequest::Lightning(bolt11), Some(backend)) => {
+                let details = ln_call(backend, { let bolt11 = bolt11.clone(); move |ln| ln.decode(&bolt11) }).await;
+                let max_amount = payout.saturating_sub(config.ln_fee_allowance);
+
+                match details {
+                    Ok(details) if details.amount_sat > 0 && details.amount_sat <= max_amount => Some((bolt11, details)),
+                    _ => None,
+                }
+            },
+            _ => None,
+        };
+        invoices.push(invoice);
+    }
+
+    // Paying the users is the first irreversible step for the maker, so make sure the round pays
+    // off before that. If we stop here users can still get their refund. Lightning users may end up
+    // paid on-chain, so we count the most expensive of both.
+    let total_received = contract_txout.value - own_put_in;
+    let spent = maker2users_txs.iter().zip(&invoices)
+        .map(|((_, amount, fee), invoice)| match invoice {
+            Some((_, details)) => (amount + fee).max(details.amount_sat + config.ln_fee_allowance),
+            None => amount + fee,
+        })
+        .sum();
+    if !matches!(total_received.checked_sub(spent), Some(margin) if margin >= config.min_margin) {
+        return Err(JoinSwapError::MarginTooLow {
+            received: total_received,
+            spent,
+            min_margin: config.min_margin,
+        });
+    }
+
+    // An invoice not paid in time falls back to the maker2user contract. Note a payment still in
+    // flight after the timeout could go through later, so the node should be checked afterwards.
+    let mut ln_payments = Vec::new();
+    for invoice in invoices {
+        let payment = match (invoice, &lightning.backend) {
+            (Some((bolt11, details)), Some(backend)) => {
+                let allowance = config.ln_fee_allowance;
+                let payment = ln_call(backend, move |ln| ln.pay(&bolt11, allowance));
+
+                match timeout(config.ln_payment_timeout, payment).await {
+                    Ok(Ok(payment)) if sha256::Hash::hash(&payment.preimage) == details.payment_hash => {
+                        Some((details, payment))
+                    },
+                    _ => None,
+                }
+            },
+            _ => None,
+        };
+        ln_payments.push(payment);
+    }
+
+    let mut maker2user_amounts = 0;
+    let mut maker2user_fees = 0;
+    let mut maker2user_txids = Vec::new();
+    let mut maker2user_timelock_keys = Vec::new();
+    let mut maker2user_outputs = Vec::new();
+    let mut lightning_payouts = Vec::new();
+    for (index, ((tx, amount, fee), payment)) in maker2users_txs.iter().zip(&ln_payments).enumerate() {
+        match payment {
+            Some((details, payment)) => {
+                maker2user_amounts += details.amount_sat;
+                maker2user_fees += payment.fee_sat;
+                lightning_payouts.push(details.payment_hash);
+                events.emit(ProtocolEvent::InvoicePaid { index, amount: details.amount_sat });
+            },
+            // Here these txs should be mined within a period of time
+            None => {
+                let (_, (timelock_key, _)) = maker2user_keys[index];
+                record.maker2user.push((maker2user_descs[index].to_string(), timelock_key, tx.txid()));
+                checkpoint(config.state_dir.as_deref(), record)?;
+                let broadcast = tx.clone();
+                chain_call(&chain, move |chain| chain.broadcast(&broadcast)).await?;
+                treasury_lock.keep(tx);
+                maker2user_amounts += amount;
+                maker2user_fees += fee;
+                maker2user_txids.push(tx.txid());
+                maker2user_timelock_keys.push((maker2user_descs[index].clone(), timelock_key));
+                let contract_utxo = contract_output(tx, &maker2user_descs[index].script_pubkey());
+                maker2user_outputs.push(contract_utxo.expect("Funds the contract"));
+                events.emit(ProtocolEvent::Broadcast { role: TxRole::Maker2UserFunding(index), txid: tx.txid() });
+                events.emit(ProtocolEvent::ContractFunded {
+                    contract: ContractKind::Maker2User(index),
+                    amount: *amount,
+                });
+            },
+        }
+    }
+    let ln_costs: u64 = ln_payments.iter().flatten()
+        .map(|(details, payment)| details.amount_sat + payment.fee_sat)
+        .sum();
+
+    let handover = async {
+        // Send maker pub keys + descriptor checksum + tx id to each on-chain user, and the payment proof to the rest
+        let maker_keys: Vec<_> = maker2user_keys.iter().map(|((_, key1), (_, key2))| [*key1, *key2]).collect();
+        let sent = join_all(new_writers.iter_mut().enumerate().map(|(index, writer)| {
+            let (keys, (_, hash), payment) = (maker_keys[index], payout_preimages[index], &ln_payments[index]);
+            let (txid, amount) = (maker2users_txs[index].0.txid(), maker2users_txs[index].1);
+            let (desc, timelock) = (&maker2user_descs[index], config.timelocks.maker2user);
+            async move {
+                match payment {
+                    Some((_, payment)) => send_ln_payout(payment.preimage, writer).await,
+                    None => send_second_contract_data(&keys, hash, desc, txid, (amount, timelock), writer).await,
+                }
+            }
+        })).await;
+        first_failure(sent, Leg::Second)?;
+        events.emit(ProtocolEvent::MessageSent(MessageKind::SecondContractData));
+
+        // Once that users verify the funding second contract txs, they send us their private keys
+        // from the hashlock path of the users2maker contract. We then can redeem the first contract
+        // coins by revealing the preimage.
+
+        // The second leg users wait for the preimage meanwhile
+        events.emit(ProtocolEvent::PhaseEntered(Phase::Handover));
+        let prv_keys = read_prv_keys(old_readers, old_writers, timeouts.second_leg, &config);
+        let hashlock_prv_keys = with_pings(prv_keys, &mut new_writers.iter_mut().collect::<Vec<_>>(), interval).await?;
+        events.emit(ProtocolEvent::MessageReceived(MessageKind::HashlockKey));
+
+        Ok::<_, JoinSwapError>(hashlock_prv_keys)
+    };
+
+    // Check that read private keys indeed correspond to the hashlock public keys. Otherwise we can't
+    // claim the users2maker coins, so the preimage isn't released: users can only take their refund
+    // and we take back the maker2user coins after the timelock. The same goes for a user that goes
+    // away, as the maker2user contracts are already funded we can't just drop the round.
+    let hashlock_keys = match handover.await {
+        Ok(prv_keys) => {
+            record.hashlock_keys = prv_keys.clone();
+            checkpoint(config.state_dir.as_deref(), record)?;
+            check_prv_keys(&prv_keys, hashlock_keys.clone(), config.network).map_err(RoundStatus::HashlockKeysRejected)
+        },
+        Err(JoinSwapError::Peer { leg, index, error }) => {
+            // The other users can go straight to their refund
+            for writer in new_writers.iter_mut() {
+                let _ = send_error(error.abort_code(), &PEER_ABORTED, writer).await;
+            }
+            Err(RoundStatus::PeerAborted { leg, index, code: error.abort_code(), reason: error.to_string() })
+        },
+        Err(e) => return Err(e),
+    };
+    let (status, sweep) = match hashlock_keys {
+        Err(status) => (status, SweepPath::Refund),
+        Ok(()) => {
+            // With an invoice hash, we can only claim the users2maker coins (and send the payout preimages)
+            // once the invoice is paid
+            let preimage = match (preimage, &lightning.preimages) {
+                (Some(preimage), _) => preimage,
+                (None, Some(source)) => obtain_preimage(source.as_ref(), hash)?,
+                (None, None) => unreachable!("Invoice hashes are refused without a preimage source"),
+            };
+            record.preimage = Some(preimage.to_hex());
+            checkpoint(config.state_dir.as_deref(), record)?;
+
+            // Send the payout preimage + multisig path prv key of each maker2users contract, users paid
+            // through Lightning have no contract. A user we can't reach claims its contract with the round
+            // preimage our hashlock claim reveals.
+            let prv_keys = maker2user_keys.iter().map(|((key, _), _)| key);
+            let peers = prv_keys.zip(&payout_preimages).zip(new_writers.iter_mut()).zip(&ln_payments);
+            let onchain = peers.filter(|(_, payment)| payment.is_none());
+            join_all(onchain.map(|(((key, (preimage, _)), writer), _)| {
+                send_preimage_and_prv_key(*preimage, key, writer)
+            })).await;
+            events.emit(ProtocolEvent::MessageSent(MessageKind::PreimageAndKey));
+
+            // Users can now redeem their funds from the respective maker2user contract
+
+            // Receive users2maker contract keys. If not enough arrive in time (users may hang up or
+            // send a wrong key) we still hold every key from the hashlock path, which we can spend
+            // revealing the preimage.
+            let prv_keys = read_contract_keys(old_readers, old_writers, &multisig_keys, &config).await;
+            // Besides ours, the multisig path needs the keys of as many users as its threshold asks
+            let needed = config.thresholds.map_or(multisig_keys.len(), |thresholds| thresholds.multisig - 1);
+            let tip = chain_call(&chain, |chain| chain.tip_height()).await?;
+            let (sweep, sweep_tx) = sweep_users2maker(
+                &mut signer,
+                &users2maker_desc,
+                (&prv_keys, needed),
+                (&record.hashlock_keys, preimage),
+                (contract_outpoint, contract_txout.clone()),
+                (treasury.sweep_address(), config.sweep_fee_rate),
+                tip,
+            )?;
+            if let SweepPath::Cooperative { .. } = sweep {
+                events.emit(ProtocolEvent::MessageReceived(MessageKind::ContractKey));
+                events.emit(ProtocolEvent::HandoverComplete);
+            }
+            let broadcast = sweep_tx.clone();
+            chain_call(&chain, move |chain| chain.broadcast(&broadcast)).await?;
+            events.emit(ProtocolEvent::Broadcast { role: TxRole::Users2MakerSweep, txid: sweep_tx.txid() });
+
+            (RoundStatus::Completed, sweep)
+        },
+    };
+    // If the round failed no user learns its preimage, so the maker2user coins stay ours. We claim them
+    // through the timelock path once it expires.
+    let maker2user_claims = match sweep {
+        SweepPath::Refund => {
+            let tip = chain_call(&chain, |chain| chain.tip_height()).await?;
+            maker2user_timelock_keys.iter().zip(maker2user_outputs)
+                .map(|((desc, timelock_key), (outpoint, prevout))| {
+                    let mut signer = contract_wallet(desc)?;
+                    add_contract_signers(&mut signer, desc, &[*timelock_key]);
+                    let destination = treasury.sweep_address();
+
+                    let csv = config.timelocks.maker2user;
+                    build_timelock_claim(&signer, csv, outpoint, prevout, destination, config.sweep_fee_rate, tip)
+                })
+                .collect::<Result<_, BuildError>>()?
+        },
+        _ => Vec::new(),
+    };
+    // Only a sweep brings the users2maker coins to the wallet. The round is over by then, so a failed
+    // sync only leaves the profit unknown.
+    let profit = match &sweep {
+        SweepPath::Cooperative { txid, .. } | SweepPath::Hashlock { txid, .. } => {
+            treasury.sync(&chain).await.ok().map(|()| {
+                let round_txids: Vec<_> = [funding_tx.txid(), *txid].into_iter()
+                    .chain(maker2user_txids.iter().copied())
+                    .collect();
+
+                (treasury.net_received(&round_txids) - ln_costs as i64).max(0) as u64
+            })
+        },
+        SweepPath::Refund => None,
+    };
+
+    Ok(MakerRoundReport {
+        status,
+        sweep,
+        users2maker_desc,
+        hash,
+        funding_txid: funding_tx.txid(),
+        refund_txid,
+        user_outpoints,
+        maker2user_txids,
+        maker2user_timelock_keys,
+        maker2user_claims,
+        lightning_payouts,
+        total_received,
+        maker2user_amounts,
+        maker2user_fees,
+        profit,
+    })
+}
+
+// Saves `record` to `state_dir`, over the one saved before in the round. Nothing is saved before the
+// funding tx is out.
+fn checkpoint(state_dir: Option<&Path>, record: &MakerRecovery) -> Result<Option<PathBuf>, JoinSwapError> {
+    let (Some(dir), Some(txid)) = (state_dir, record.funding_txid) else {
+        return Ok(None);
+    };
+
+    save_state(dir, &format!("maker-round-{txid}"), &Recovery::Maker(Box::new(record.clone())))
+        .map(Some)
+        .map_err(JoinSwapError::Recovery)
+}
+
+// State of the round once the users2maker contract is funded
+struct FundedContract {
+    desc: Descriptor<PublicKey>,
+    // Multisig path keys of the users in round order, and ours
+    multisig_keys: Vec<PublicKey>,
+    maker_multisig_key: PrivateKey,
+    // Signs for the contract with our keys, and with the users' multisig path keys once they hand them over
+    signer: Wallet<MemoryDatabase>,
+    // Where our keys of the round come from
+    session_keys: SessionKeys,
+    hashlock_keys: Vec<PublicKey>,
+    // Unknown until we pay the invoice the hash comes from
+    preimage: Option<[u8; 32]>,
+    hash: sha256::Hash,
+    funding_tx: Transaction,
+    // The output of the funding tx paying to the contract
+    contract_utxo: (OutPoint, TxOut),
+    refund_txid: Txid,
+    user_outpoints: Vec<OutPoint>,
+    // What each user is owed on the second leg, in no particular order
+    payouts: Vec<u64>,
+    // What our own input put in the contract, 0 without one
+    own_put_in: u64,
+}
+
+// What the first leg draws on besides the users
+struct FirstLegBackends<'a> {
+    // Our own input comes from it, if we put one in
+    treasury: &'a MakerTreasury,
+    chain: &'a Arc<dyn ChainAccess>,
+    lightning: &'a MakerLightning,
+}
+
+// What a first leg user brings to the round
+#[derive(Clone)]
+struct UserData {
+    // Multisig, timelock and hashlock path keys of the users2maker contract
+    keys: [PublicKey; 3],
+    funds: UserFunds,
+    refund_addr: Address,
+    hash_source: HashSource,
+}
+
+// First leg of the round: build the users2maker contract with the users and broadcast its funding tx
+async fn fund_users2maker<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    config: &MakerConfig,
+    backends: &FirstLegBackends<'_>,
+    readers: &mut [FramedReader<BufReader<ReadHalf<S>>>],
+    writers: &mut [FramedWriter<WriteHalf<S>>],
+    sent: &mut [Option<UserData>],
+    inbox: &mut RoundInbox<S>,
+    events: &EventSink,
+) -> Result<FundedContract, JoinSwapError> {
+    // Users coming from a declined round sent their data already
+    let mut users = Vec::new();
+    for (index, (reader, writer)) in readers.iter_mut().zip(writers.iter_mut()).enumerate() {
+        let user = match &sent[index] {
+            Some(user) => user.clone(),
+            None => {
+                let user = read_user_data(reader, config.network, config.utxo_values, config.denomination.is_some());
+                let user = with_timeout(config.timeouts.exchange, user).await;
+                check_peer(user, Leg::First, index, writer).await?
+            },
+        };
+        sent[index] = Some(user.clone());
+        users.push(user);
+    }
+    events.emit(ProtocolEvent::MessageReceived(MessageKind::UserData));
+
+    // All utxos go in the same funding tx, so they must be different and unspent. Other rounds can't
+    // take them meanwhile, nor our hash, unless we give them back by failing before the funding tx.
+    let user_outpoints: Vec<Vec<_>> = users.iter()
+        .map(|user| user.funds.utxos.iter().map(|utxo| utxo.utxo.outpoint()).collect())
+        .collect();
+    let declared_weights: Vec<Vec<_>> = users.iter()
+        .map(|user| user.funds.utxos.iter().map(|utxo| utxo.satisfaction_weight).collect())
+        .collect();
+    let mut reservation = config.used.reserve();
+    if let Err((index, e)) = reservation.take_outpoints(&user_outpoints) {
+        check_peer(Err(e.into()), Leg::First, index, &mut writers[index]).await?;
+    }
+    if config.lookup_user_utxos {
+        let utxos: Vec<Vec<_>> = users.iter()
+            .map(|user| user.funds.utxos.iter().map(|utxo| (utxo.utxo.outpoint(), utxo.utxo.txout().clone())).collect())
+            .collect();
+        if let Err((index, e)) = chain_call(backends.chain, move |chain| check_user_utxos(chain, &utxos)).await? {
+            check_peer(Err(e.into()), Leg::First, index, &mut writers[index]).await?;
+        }
+    }
+
+    // Maker keys used in the contract, and later in the maker2user ones
+    let session_keys = config.contract_keys.new_session(config.network).map_err(JoinSwapError::KeySession)?;
+    let (prv_key1, pub_key1) = session_keys.key(KeyRole::Users2Maker(MULTISIG_PATH));
+    let (prv_key2, pub_key2) = session_keys.key(KeyRole::Users2Maker(TIMELOCK_PATH));
+    let (prv_key3, pub_key3) = session_keys.key(KeyRole::Users2Maker(HASHLOCK_PATH));
+    // We sign with the timelock path key, which we never hand over
+    for writer in writers.iter_mut() {
+        writer.sign_with(prv_key2.inner);
+    }
+
+    // The triplet of keys of each user, and ours last. Users go in the contract in a random order, so
+    // the keys and refund addresses we hand out don't tell which connection is which.
+    let mut order: Vec<_> = (0..users.len()).collect();
+    order.shuffle(&mut thread_rng());
+    let keys: Vec<_> = order.iter().map(|&index| users[index].keys).chain([[pub_key1, pub_key2, pub_key3]]).collect();
+
+    // If a user brought an invoice we don't know the preimage until we pay it. Users bringing one
+    // must all bring the same.
+    let mut invoice_hashes = users.iter().filter_map(|user| match user.hash_source {
+        HashSource::Invoice(hash) => Some(hash),
+        HashSource::Maker => None,
+    });
+    let external_hash = invoice_hashes.next();
+    if invoice_hashes.any(|hash| Some(hash) != external_hash) {
+        return Err(PreimageError::ConflictingHashes.into());
+    }
+    if external_hash.is_some() && backends.lightning.preimages.is_none() {
+        return Err(PreimageError::NoSource.into());
+    }
+    let (preimage, hash) = reservation.take_hash(external_hash)?;
+
+    // A bad key can only come from a user, as ours are fresh
+    let thresholds = config.thresholds.unwrap_or(PathThresholds::all(keys.len()));
+    let refund_timelock = config.timelocks.refund;
+    let users2maker_desc = match config.taproot {
+        true => users2maker_contract_desc_tr(&keys, hash, thresholds, refund_timelock),
+        false => users2maker_contract_desc(&keys, hash, thresholds, refund_timelock),
+    };
+    let users2maker_desc = match users2maker_desc {
+        Ok(desc) => desc,
+        Err(e) => match contract_key_owner(&keys, &e).map(|position| order[position]) {
+            Some(index) => return check_peer(Err(e.into()), Leg::First, index, &mut writers[index]).await,
+            None => return Err(ProtocolError::from(e).into()),
+        },
+    };
+
+    let users2maker_address = users2maker_desc.address(config.network).unwrap();
+
+    // Each user needs its own refund address, which can't be the contract being refunded
+    let refund_addrs: Vec<_> = users.iter().map(|user| user.refund_addr.clone()).collect();
+    if let Err((index, e)) = check_refund_addresses(&refund_addrs, &users2maker_address) {
+        check_peer(Err(e.into()), Leg::First, index, &mut writers[index]).await?;
+    }
+    // Nor can the change go back to it
+    let change_addrs = users.iter().map(|user| user.funds.change.as_ref().map(|change| &change.address));
+    if let Some(index) = change_addrs.clone().position(|addr| addr == Some(&users2maker_address)) {
+        let e = UtxoError::BadChangeAddress(users2maker_address.to_string());
+        check_peer(Err(e.into()), Leg::First, index, &mut writers[index]).await?;
+    }
+
+    events.emit(ProtocolEvent::PhaseEntered(Phase::ContractCreation));
+    events.emit(ProtocolEvent::ContractCreated {
+        contract: ContractKind::Users2Maker,
+        address: users2maker_address,
+    });
+
+    // The contract keys of each user by path, in round order rather than the contract's
+    let multisig_keys: Vec<_> = users.iter().map(|user| user.keys[0]).collect();
+    let timelock_keys: Vec<_> = users.iter().map(|user| user.keys[1]).collect();
+    let hashlock_keys: Vec<_> = users.iter().map(|user| user.keys[2]).collect();
+    let mut funds: Vec<_> = users.into_iter().map(|user| user.funds).collect();
+
+    // Users are told the feerates, see `FeeRates`
+    let fee_rates = FeeRates {
+        funding: estimate_fee_rate(&config.fee_estimator, FUNDING_TARGET).await?.as_sat_per_vb(),
+        refund: estimate_fee_rate(&config.fee_estimator, REFUND_TARGET).await?.as_sat_per_vb(),
+    };
+
+    // In rounds of equal amounts users put in the same, which must still pay more than dust after our fee
+    let denomination = match config.denomination {
+        Some(mode) => {
+            let amount = denominate(&users2maker_desc, &mut funds, mode.step, fee_rates.funding_rate())?;
+            let min = config.utxo_values.min_value().max(config.maker_fee.of(amount) + DUST_LIMIT);
+            if amount < min {
+                return Err(BuildError::DenominationTooSmall { denomination: amount, min }.into());
+            }
+            Some(Denomination { amount })
+        },
+        None => None,
+    };
+
+    // Our own input goes in whole next to the users', and the refund tx pays it back to us too
+    let mut treasury_lock = backends.treasury.lock();
+    let maker_input = if config.own_input {
+        backends.treasury.sync(backends.chain).await?;
+        Some(backends.treasury.contract_input(&mut treasury_lock)?)
+    } else {
+        None
+    };
+    let maker_refund_addr = maker_input.as_ref().map(|input| input.refund_to.clone());
+
+    // Build funding and refund tx spending from user utxos and refunding to their addresses
+    let tip = chain_call(backends.chain, |chain| chain.tip_height()).await?;
+    let (mut funding_psbt, refund_psbts) = build_funding_and_refund(
+        &users2maker_desc,
+        funds.clone(),
+        refund_addrs.clone(),
+        maker_input.clone(),
+        fee_rates,
+        tip,
+    )?;
+    // Only if every user of the round signs its inputs for others to be added, see `AddInput`
+    let anyone_can_pay = writers.iter().all(|writer| writer.anyone_can_pay());
+    if anyone_can_pay {
+        set_anyone_can_pay(&mut funding_psbt, &user_outpoints.concat());
+    }
+    let refund_psbt = &refund_psbts[0];
+
+    // Each user gets paid what it puts in minus our fee. What a user puts in is what its refund pays plus
+    // its share of the refund fee.
+    let refund_share =
+        refund_fee_share(&users2maker_desc, refund_psbt.unsigned_tx.output.len(), fee_rates.refund_rate());
+    let payouts: Vec<_> = refund_addrs.iter().map(|addr| {
+        let put_in = refunded(refund_psbt, addr) + refund_share;
+        put_in.saturating_sub(config.maker_fee.of(put_in))
+    }).collect();
+    if let Some(&payout) = payouts.iter().find(|payout| **payout < DUST_LIMIT) {
+        return Err(BuildError::PayoutTooSmall { payout }.into());
+    }
+    // What we put in ourselves isn't earned, the refund tx tells it as it does for the users
+    let put_in_by_us = |refund_psbt: &Psbt| {
+        maker_refund_addr.as_ref().map_or(0, |addr| refunded(refund_psbt, addr) + refund_share)
+    };
+    // Nothing is signed yet, so a round that can't pay off ends here. The maker2user fees come on top.
+    let mut own_put_in = put_in_by_us(refund_psbt);
+    check_margin(&funding_psbt, &users2maker_desc, own_put_in, &payouts, config.min_margin)?;
+
+    let contract = ContractSummary {
+        keys: contract_keys_by_path(&keys),
+        hash,
+        checksum: desc_checksum(&users2maker_desc),
+        refund_addrs: order.iter().map(|&index| refund_addrs[index].clone()).collect(),
+        payouts: payouts.clone(),
+        denomination,
+        thresholds: config.thresholds,
+        timelocks: config.timelocks,
+        fee_rates,
+        maker_refund_addr: maker_refund_addr.clone(),
+    };
+    send_contract_data(&contract, &funding_psbt, &refund_psbts, writers).await?;
+    events.emit(ProtocolEvent::MessageSent(MessageKind::ContractData));
+    events.emit(ProtocolEvent::MessageSent(MessageKind::FundingAndRefund));
+
+    // From here until we have the signed funding tx a user that drops can come back and resume
+    let mut signing = SigningLeg::new(readers, writers, inbox, config, prv_key2.inner, events);
+
+    // We have to sign from the refund psbts too as our key is also in the contract
+    let mut signer = contract_wallet(&users2maker_desc)?;
+    add_contract_signers(&mut signer, &users2maker_desc, &[prv_key1, prv_key2, prv_key3]);
+
+    // The refund tx spends through the timelock path, so we can't sign it until every user did
+    let check_refund = |index: usize, psbt: &Psbt| check_required_sig(psbt, 0, timelock_keys[index]);
+    let check_funding = |index: usize, psbt: &Psbt| {
+        user_outpoints[index].iter().zip(&declared_weights[index])
+            .try_for_each(|(outpoint, declared)| check_own_input(psbt, *outpoint, *declared))
+    };
+    let own_input = config.own_input.then_some(backends.treasury);
+    let mut added_inputs = Vec::new();
+    let (funding_final, refunds_final) = if anyone_can_pay {
+        let refunds_final = signing.sign_refunds(&refund_psbts, &mut signer, &check_refund).await?;
+        let answers = signing.read_funding_answers(&funding_psbt, &check_funding).await?;
+
+        // Added utxos are checked like those the users brought, and all of their value goes to the fee
+        let mut funding_psbt = funding_psbt.clone();
+        for (index, answer) in answers.iter().enumerate() {
+            let FundingAnswer::AddInput(utxo) = answer else { continue };
+            let count = user_outpoints[index].len() + 1;
+            let utxo = match count > MAX_USER_UTXOS {
+                true => Err(UtxoError::Count { count, max: MAX_USER_UTXOS }.into()),
+                false => check_user_utxo(*utxo.clone()).map_err(ProtocolError::from),
+            };
+            let utxo = check_peer(utxo, Leg::First, index, &mut signing.writers[index]).await?;
+            let (outpoint, txout) = (utxo.utxo.outpoint(), utxo.utxo.txout().clone());
+
+            if let Err((_, e)) = reservation.take_outpoints(&[vec![outpoint]]) {
+                check_peer(Err(e.into()), Leg::First, index, &mut signing.writers[index]).await?;
+            }
+            if config.lookup_user_utxos {
+                let utxos = vec![vec![(outpoint, txout)]];
+                if let Err((_, e)) = chain_call(backends.chain, move |chain| check_user_utxos(chain, &utxos)).await? {
+                    check_peer(Err(e.into()), Leg::First, index, &mut signing.writers[index]).await?;
+                }
+            }
+            let Utxo::Foreign { psbt_input, .. } = utxo.utxo else { unreachable!("Users' utxos are foreign") };
+            let added = add_funding_input(&mut funding_psbt, outpoint, *psbt_input).map_err(ProtocolError::from);
+            check_peer(added, Leg::First, index, &mut signing.writers[index]).await?;
+            added_inputs.push((index, outpoint, utxo.satisfaction_weight));
+        }
+
+        let signed_psbts = answers.into_iter().filter_map(|answer| match answer {
+            FundingAnswer::Signed(psbt) => Some(psbt),
+            FundingAnswer::AddInput(_) => None,
+        });
+        let mut funding_final = funding_psbt.clone();
+        let refunds_final = if added_inputs.is_empty() {
+            for signed_psbt in signed_psbts {
+                funding_final.combine(signed_psbt).expect("Every PSBT was checked against the one we sent");
+            }
+            refunds_final
+        } else {
+            // Everyone signs the refunds spending the new funding tx. Inputs signed already keep their
+            // signatures, which only commit to the outputs and their own input, and a user who signed them
+            // with SIGHASH_ALL instead is to blame for it.
+            let refund_psbts: Vec<_> = refund_psbts.iter()
+                .map(|refund| rebase_refund(refund, &funding_psbt.unsigned_tx))
+                .collect();
+            signing.send_input_added(&funding_psbt, &refund_psbts).await;
+            events.emit(ProtocolEvent::MessageSent(MessageKind::InputAdded));
+            let refunds_final = signing.sign_refunds(&refund_psbts, &mut signer, &check_refund).await?;
+
+            for signed_psbt in signed_psbts {
+                for (input, signed) in funding_final.inputs.iter_mut().zip(signed_psbt.inputs) {
+                    if signed.final_script_witness.is_some() {
+                        *input = signed;
+                    }
+                }
+            }
+            let adders: Vec<_> = added_inputs.iter().map(|(index, _, _)| *index).collect();
+            for index in (0..signing.writers.len()).filter(|index| !adders.contains(index)) {
+                let kept = check_funding(index, &funding_final).map_err(ProtocolError::from);
+                check_peer(kept, Leg::First, index, &mut signing.writers[index]).await?;
+            }
+            let check_adder = |index: usize, psbt: &Psbt| {
+                let (_, outpoint, declared) = added_inputs.iter().find(|(adder, _, _)| *adder == index)
+                    .expect("Only users adding an input sign again");
+                check_funding(index, psbt).and_then(|()| check_own_input(psbt, *outpoint, *declared))
+            };
+            funding_final = signing.combine_signed(&funding_psbt, funding_final, &adders, check_adder).await?;
+            refunds_final
+        };
+        events.emit(ProtocolEvent::MessageReceived(MessageKind::SignedFunding));
+        sign_own_input(own_input, &mut funding_final)?;
+        (funding_final, refunds_final)
+    } else {
+        let signed = signing.sign_contract_txs(
+            &funding_psbt,
+            &refund_psbts,
+            &mut signer,
+            &check_refund,
+            &check_funding,
+            own_input,
+        );
+        signed.await?
+    };
+    node_accepts_funding(backends.chain, &funding_final).await?;
+
+    // Users that let us replace the funding tx learn whether we do once it's out. A funding tx with added
+    // inputs isn't replaced, as its rebuild would put their value in the contract instead of the fee.
+    let bump_after = config.funding_bump_after
+        .filter(|_| added_inputs.is_empty() && signing.writers.iter().all(|writer| writer.funding_bumps()));
+    let (funding_tx, refunds_final) = match bump_after {
+        None => {
+            // The users connect for the second leg as soon as they have it
+            signing.finish(funding_final.unsigned_tx.txid());
+            send_psbt(&funding_final, writers).await?;
+            for writer in writers.iter_mut().filter(|writer| writer.funding_bumps()) {
+                let _ = send_msg(&FundingKept {}.into(), writer).await;
+            }
+            events.emit(ProtocolEvent::MessageSent(MessageKind::FinalizedFunding));
+
+            // Here we should wait the funding tx to be mined
+            let funding_tx = funding_final.extract_tx();
+            let broadcast = funding_tx.clone();
+            chain_call(backends.chain, move |chain| chain.broadcast(&broadcast)).await?;
+            reservation.keep();
+            treasury_lock.keep(&funding_tx);
+            events.emit(ProtocolEvent::Broadcast { role: TxRole::Funding, txid: funding_tx.txid() });
+            (funding_tx, refunds_final)
+        },
+        Some(wait) => {
+            signing.send_psbt(&funding_final).await;
+            events.emit(ProtocolEvent::MessageSent(MessageKind::FinalizedFunding));
+            let replaced_tx = funding_final.clone().extract_tx();
+            let broadcast = replaced_tx.clone();
+            chain_call(backends.chain, move |chain| chain.broadcast(&broadcast)).await?;
+            // A replacement spends the same inputs, so they stay taken whichever tx confirms
+            reservation.keep();
+            treasury_lock.keep(&replaced_tx);
+            events.emit(ProtocolEvent::Broadcast { role: TxRole::Funding, txid: replaced_tx.txid() });
+
+            let contract_spk = users2maker_desc.script_pubkey();
+            let confirmed = confirms_within(backends.chain, &replaced_tx, &contract_spk, wait);
+            let mut pinged: Vec<_> = signing.writers.iter_mut().collect();
+            let confirmed = with_pings(confirmed, &mut pinged, config.keepalive.interval).await?;
+
+            // Past the wait we replace it at the feerate it now takes, and at least `MIN_FEE_BUMP` more.
+            // The payouts stay those we told the users, so our margin must still cover them.
+            let mut bump = None;
+            if !confirmed {
+                let estimate = estimate_fee_rate(&config.fee_estimator, FUNDING_TARGET).await?.as_sat_per_vb();
+                let fee_rates = FeeRates { funding: estimate.max(fee_rates.funding + MIN_FEE_BUMP), ..fee_rates };
+                let tip = chain_call(backends.chain, |chain| chain.tip_height()).await?;
+                let built = build_funding_and_refund(
+                    &users2maker_desc,
+                    funds,
+                    refund_addrs.clone(),
+                    maker_input,
+                    fee_rates,
+                    tip,
+                );
+                bump = built.ok().filter(|(funding_psbt, refund_psbts)| {
+                    let own_put_in = put_in_by_us(&refund_psbts[0]);
+                    let margin = check_margin(funding_psbt, &users2maker_desc, own_put_in, &payouts, config.min_margin);
+                    margin.is_ok() && replacement_failures(&funding_final, funding_psbt).is_empty()
+                }).map(|(funding_psbt, refund_psbts)| (fee_rates.funding, funding_psbt, refund_psbts));
+            }
+            let sent = bump.as_ref().map(|(fee_rate, funding, refunds)| (*fee_rate, funding, refunds.as_slice()));
+            signing.send_bump(sent).await;
+
+            match bump {
+                None => {
+                    signing.finish(replaced_tx.txid());
+                    (replaced_tx, refunds_final)
+                },
+                // The same as the first funding tx, but the users already have the contract data
+                Some((_, funding_psbt, refund_psbts)) => {
+                    events.emit(ProtocolEvent::MessageSent(MessageKind::FundingBump));
+                    let signed = signing.sign_contract_txs(
+                        &funding_psbt,
+                        &refund_psbts,
+                        &mut signer,
+                        &check_refund,
+                        &check_funding,
+                        own_input,
+                    );
+                    let (funding_final, refunds_final) = signed.await?;
+                    node_accepts_funding(backends.chain, &funding_final).await?;
+                    own_put_in = put_in_by_us(&refund_psbts[0]);
+
+                    signing.finish(funding_final.unsigned_tx.txid());
+                    send_psbt(&funding_final, writers).await?;
+                    events.emit(ProtocolEvent::MessageSent(MessageKind::FinalizedFunding));
+
+                    let funding_tx = funding_final.extract_tx();
+                    let broadcast = funding_tx.clone();
+                    chain_call(backends.chain, move |chain| chain.broadcast(&broadcast)).await?;
+                    events.emit(ProtocolEvent::Broadcast { role: TxRole::Funding, txid: funding_tx.txid() });
+                    (funding_tx, refunds_final)
+                },
+            }
+        },
+    };
+    let contract_utxo = contract_output(&funding_tx, &users2maker_desc.script_pubkey())
+        .expect("Our funding tx pays the contract");
+    events.emit(ProtocolEvent::ContractFunded {
+        contract: ContractKind::Users2Maker,
+        amount: contract_utxo.1.value,
+    });
+
+    Ok(FundedContract {
+        desc: users2maker_desc,
+        multisig_keys,
+        maker_multisig_key: prv_key1,
+        signer,
+        session_keys,
+        hashlock_keys,
+        preimage,
+        hash,
+        funding_tx,
+        contract_utxo,
+        refund_txid: refunds_final[0].unsigned_tx.txid(),
+        user_outpoints: user_outpoints.into_iter().flatten()
+            .chain(added_inputs.into_iter().map(|(_, outpoint, _)| outpoint))
+            .collect(),
+        payouts,
+        own_put_in,
+    })
+}
+
+// The first leg while the users sign the refund and funding txs. A user whose connection drops
+// meanwhile can connect again with the resume token of its session (see `signing::resume_token`)
+// and send its last PSBT again, and we pick the round up where it left it.
+struct SigningLeg<'a, S> {
+    readers: &'a mut [FramedReader<BufReader<ReadHalf<S>>>],
+    writers: &'a mut [FramedWriter<WriteHalf<S>>],
+    sessions: Vec<SessionProgress>,
+    inbox: &'a mut RoundInbox<S>,
+    config: &'a MakerConfig,
+    // Our timelock path key, which signs on the resumed connections too
+    signing_key: SecretKey,
+    events: &'a EventSink,
+}
+
+// A user's answer to the funding tx, see `SigningLeg::read_funding_answers`
+enum FundingAnswer {
+    Signed(Psbt),
+    AddInput(Box<UserUtxo>),
+}
+
+// Where a user is in the signing, looked up by the resume token of its session
+struct SessionProgress {
+    token: String,
+    // The key the user signs its messages with, a resumed connection must be signed with it too
+    signer: PublicKey,
+    // Txids of the PSBTs the user already signed
+    signed: Vec<Txid>,
+    // What we last sent the user, sent again if it missed it
+    last_sent: Option<Psbt>,
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> SigningLeg<'a, S> {
+    // Called once the users signed their data, so we know their sessions and signing keys
+    fn new(
+        readers: &'a mut [FramedReader<BufReader<ReadHalf<S>>>],
+        writers: &'a mut [FramedWriter<WriteHalf<S>>],
+        inbox: &'a mut RoundInbox<S>,
+        config: &'a MakerConfig,
+        signing_key: SecretKey,
+        events: &'a EventSink,
+    ) -> Self {
+        let sessions: Vec<_> = readers.iter().map(|reader| SessionProgress {
+            token: signing::resume_token(&reader.session().expect("Users greeted us")),
+            signer: PublicKey::new(reader.peer_key().expect("Users signed their data")),
+            signed: Vec::new(),
+            last_sent: None,
+        }).collect();
+        inbox.expect_resumes(&sessions.iter().map(|session| session.token.clone()).collect::<Vec<_>>());
+
+        SigningLeg { readers, writers, sessions, inbox, config, signing_key, events }
+    }
+
+    // Once we have the signed funding tx sessions are no longer resumed, and the users come back
+    // with their new identities for the round of `funding_txid`
+    fn finish(self, funding_txid: Txid) {
+        self.inbox.stop_resumes();
+        self.inbox.expect_second_leg(funding_txid);
+    }
+
+    // The users sign the refund txs first, one at a time as we finalize each, and once they have them
+    // all the funding tx, where we sign our own input last if we put one in
+    async fn sign_contract_txs(
+        &mut self,
+        funding_psbt: &Psbt,
+        refund_psbts: &[Psbt],
+        // Borrowed mutably so the round future stays Send
+        signer: &mut Wallet<MemoryDatabase>,
+        check_refund: &impl Fn(usize, &Psbt) -> Result<(), PsbtReadError>,
+        check_funding: &impl Fn(usize, &Psbt) -> Result<(), PsbtReadError>,
+        own_input: Option<&MakerTreasury>,
+    ) -> Result<(Psbt, Vec<Psbt>), JoinSwapError> {
+        let refunds_final = self.sign_refunds(refund_psbts, signer, check_refund).await?;
+
+        let mut funding_final = self.read_and_combine(funding_psbt, check_funding).await?;
+        self.events.emit(ProtocolEvent::MessageReceived(MessageKind::SignedFunding));
+        sign_own_input(own_input, &mut funding_final)?;
+
+        Ok((funding_final, refunds_final))
+    }
+
+    async fn sign_refunds(
+        &mut self,
+        refund_psbts: &[Psbt],
+        signer: &mut Wallet<MemoryDatabase>,
+        check_refund: &impl Fn(usize, &Psbt) -> Result<(), PsbtReadError>,
+    ) -> Result<Vec<Psbt>, JoinSwapError> {
+        let mut refunds_final = Vec::new();
+        for refund_psbt in refund_psbts {
+            let mut refund_final = self.read_and_combine(refund_psbt, check_refund).await?;
+            self.events.emit(ProtocolEvent::MessageReceived(MessageKind::SignedRefund));
+
+            let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
+            signer.sign(&mut refund_final, sign_ops).unwrap();
+            self.send_psbt(&refund_final).await;
+            self.events.emit(ProtocolEvent::MessageSent(MessageKind::FinalizedRefund));
+            refunds_final.push(refund_final);
+        }
+        Ok(refunds_final)
+    }
+
+    // Tells the users whether we replace the funding tx, see `MakerConfig::funding_bump_after`. A user
+    // we can't reach fails the next read.
+    async fn send_bump(&mut self, bump: Option<(f32, &Psbt, &[Psbt])>) {
+        join_all(self.writers.iter_mut().map(|writer| {
+            let message = match bump {
+                Some((fee_rate, funding, refunds)) => {
+                    let encoding = writer.psbt_encoding();
+                    FundingBump {
+                        fee_rate,
+                        funding: WirePsbt::encode(funding, encoding),
+                        refund: WirePsbt::encode(&refunds[0], encoding),
+                        refund_ladder: refunds[1..].iter().map(|refund| WirePsbt::encode(refund, encoding)).collect(),
+                    }.into()
+                },
+                None => FundingKept {}.into(),
+            };
+            async move {
+                let _ = send_msg(&message, writer).await;
+            }
+        })).await;
+    }
+
+    async fn read_and_combine(
+        &mut self,
+        sent: &Psbt,
+        check_user_sigs: impl Fn(usize, &Psbt) -> Result<(), PsbtReadError>,
+    ) -> Result<Psbt, JoinSwapError> {
+        let users: Vec<_> = (0..self.readers.len()).collect();
+        self.combine_signed(sent, sent.clone(), &users, check_user_sigs).await
+    }
+
+    // Combines into `combined` the `sent` PSBT as each of `users` signs it
+    async fn combine_signed(
+        &mut self,
+        sent: &Psbt,
+        mut combined: Psbt,
+        users: &[usize],
+        check_user_sigs: impl Fn(usize, &Psbt) -> Result<(), PsbtReadError>,
+    ) -> Result<Psbt, JoinSwapError> {
+        for &index in users {
+            let signed_psbt = self.read_signed(index, sent).await
+                .and_then(|psbt| check_signed(sent, psbt, |psbt| check_user_sigs(index, psbt)));
+            let signed_psbt = check_peer(signed_psbt, Leg::First, index, &mut self.writers[index]).await?;
+            self.sessions[index].signed.push(sent.unsigned_tx.txid());
+            combined.combine(signed_psbt).expect("Every PSBT was checked against the one we sent");
+        }
+        Ok(combined)
+    }
+
+    // What each user answers to the funding tx in rounds with `ANYONE_CAN_PAY`: signed, or with a utxo
+    // it adds to it (see `AddInput`)
+    async fn read_funding_answers(
+        &mut self,
+        sent: &Psbt,
+        check_user_sigs: impl Fn(usize, &Psbt) -> Result<(), PsbtReadError>,
+    ) -> Result<Vec<FundingAnswer>, JoinSwapError> {
+        let mut answers = Vec::new();
+        for index in 0..self.readers.len() {
+            let answer = self.read_answer(index, sent, true).await.and_then(|answer| match answer {
+                FundingAnswer::Signed(psbt) => {
+                    check_signed(sent, psbt, |psbt| check_user_sigs(index, psbt)).map(FundingAnswer::Signed)
+                },
+                add_input => Ok(add_input),
+            });
+            let answer = check_peer(answer, Leg::First, index, &mut self.writers[index]).await?;
+            match answer {
+                FundingAnswer::Signed(_) => self.sessions[index].signed.push(sent.unsigned_tx.txid()),
+                FundingAnswer::AddInput(_) => self.events.emit(ProtocolEvent::MessageReceived(MessageKind::AddInput)),
+            }
+            answers.push(answer);
+        }
+        Ok(answers)
+    }
+
+    // Sends every user the funding tx with the inputs users added, and the refunds spending it. A user we
+    // can't reach fails the next read.
+    async fn send_input_added(&mut self, funding: &Psbt, refunds: &[Psbt]) {
+        join_all(self.writers.iter_mut().map(|writer| {
+            let encoding = writer.psbt_encoding();
+            let message = InputAdded {
+                funding: WirePsbt::encode(funding, encoding),
+                refund: WirePsbt::encode(&refunds[0], encoding),
+                refund_ladder: refunds[1..].iter().map(|refund| WirePsbt::encode(refund, encoding)).collect(),
+            }.into();
+            async move {
+                let _ = send_msg(&message, writer).await;
+            }
+        })).await;
+    }
+
+    // Sends `psbt` to every user. A user we can't reach gets it again once it resumes.
+    async fn send_psbt(&mut self, psbt: &Psbt) {
+        for session in self.sessions.iter_mut() {
+            session.last_sent = Some(psbt.clone());
+        }
+        join_all(self.writers.iter_mut().map(|writer| send_signed_psbt(psbt, writer))).await;
+    }
+
+    // The `sent` PSBT signed by user `index`, who may resume meanwhile
+    async fn read_signed(&mut self, index: usize, sent: &Psbt) -> Result<Psbt, ProtocolError> {
+        match self.read_answer(index, sent, false).await? {
+            FundingAnswer::Signed(psbt) => Ok(psbt),
+            FundingAnswer::AddInput(_) => unreachable!("Added inputs are only read with `ANYONE_CAN_PAY`"),
+        }
+    }
+
+    // Like `read_signed`, but the user may add an input instead if `add_inputs`
+    async fn read_answer(
+        &mut self,
+        index: usize,
+        sent: &Psbt,
+        add_inputs: bool,
+    ) -> Result<FundingAnswer, ProtocolError> {
+        let limit = self.config.timeouts.signing;
+
+        loop {
+            let reader = &mut self.readers[index];
+            let update = match add_inputs {
+                true => with_timeout(limit, read_funding_update(reader)).await,
+                false => with_timeout(limit, read_signed_psbt(reader)).await.map(FundingUpdate::Signed),
+            };
+            let update = match update {
+                Err(e) if e.is_disconnect() => match self.wait_resume(index).await {
+                    true => continue,
+                    false => return Err(e),
+                },
+                update => update?,
+            };
+            let session = &self.sessions[index];
+            signing::check_signer(&self.readers[index], &[session.signer])?;
+
+            let signed = match update {
+                FundingUpdate::Signed(signed) => signed,
+                FundingUpdate::AddInput(add) => return Ok(FundingAnswer::AddInput(Box::new(add.utxo))),
+                FundingUpdate::InputAdded(_) => {
+                    return Err(ProtocolError::Unexpected { expected: SignedPsbt::NAME, got: InputAdded::NAME });
+                },
+            };
+            // A resumed user sends its last PSBT again, if we already had it the user missed our answer
+            if session.signed.contains(&signed.txid()?) {
+                if let Some(last_sent) = &session.last_sent {
+                    send_signed_psbt(last_sent, &mut self.writers[index]).await?;
+                }
+                continue;
+            }
+            return Ok(FundingAnswer::Signed(signed.into_psbt(sent)?));
+        }
+    }
+
+    // Whether user `index` came back within `timeouts.resume`. The other users are pinged
+    // meanwhile, and if one of them resumes too it takes its new connection.
+    async fn wait_resume(&mut self, index: usize) -> bool {
+        let deadline = Instant::now() + self.config.timeouts.resume;
+
+        loop {
+            let resume = timeout_at(deadline, self.inbox.next_resumed());
+            let mut others: Vec<_> = self.writers.iter_mut().enumerate()
+                .filter(|(other, _)| *other != index)
+                .map(|(_, writer)| writer)
+                .collect();
+            let Ok((resumed, mut conn)) = with_pings(resume, &mut others, self.config.keepalive.interval).await else {
+                return false;
+            };
+            conn.writer.sign_with(self.signing_key);
+            self.readers[resumed] = conn.reader;
+            self.writers[resumed] = conn.writer;
+            self.events.emit(ProtocolEvent::SessionResumed { index: Some(resumed) });
+
+            if resumed == index {
+                return true;
+            }
+        }
+    }
+}
+
+// Sent to the users left in a round that another user aborted
+const PEER_ABORTED: &str = "Another user left the round";
+
+// Before the funding tx is broadcast nothing is at stake, so the users still in the round are told
+// it's over instead of waiting for messages that won't come, along with the reason a user gave for
+// aborting. A peer that failed was already told why if it was its fault.
+async fn notify_abort<W: AsyncWrite + Unpin>(error: &JoinSwapError, writers: &mut [FramedWriter<W>]) {
+    join_all(writers.iter_mut().enumerate().map(|(index, writer)| async move {
+        let _ = match error {
+            JoinSwapError::Peer { leg: Leg::First, index: failed, .. } if *failed == index => return,
+            // The others are offered another round, see `rematch_users`
+            JoinSwapError::Peer { error: ProtocolError::Declined(_), .. } => return,
+            JoinSwapError::Peer { error: error @ ProtocolError::Rejected { reason, .. }, .. } => {
+                send_error(error.abort_code(), &format!("{PEER_ABORTED}: {reason}"), writer).await
+            },
+            JoinSwapError::Peer { error, .. } => send_error(error.abort_code(), &PEER_ABORTED, writer).await,
+            e => send_error(e.abort_code(), e, writer).await,
+        };
+    })).await;
+}
+
+// A user declined the round before signing the funding tx, so nothing is at stake yet. The users we
+// have the data of are offered to wait for another round with it, and those that take it (see
+// `RoundInbox::rematch`) go back to matchmaking. The rest are told the round is over.
+async fn rematch_users<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    state: RoundState<S>,
+    declined: usize,
+    reason: &str,
+    limit: Duration,
+    inbox: &RoundInbox<S>,
+    events: &EventSink,
+) {
+    let RoundState { readers, writers, users, .. } = state;
+
+    let users = readers.into_iter().zip(writers).zip(users).enumerate().filter(|(index, _)| *index != declined);
+    let offers = users.map(|(index, ((reader, writer), sent))| async move {
+        let mut conn = Connection { reader, writer };
+        let Some(sent) = sent else {
+            let _ = send_error(AbortCode::Declined, &format!("{PEER_ABORTED}: {reason}"), &mut conn.writer).await;
+            return None;
+        };
+        let taken = async {
+            send_msg(&Rematch { reason: reason.to_string() }.into(), &mut conn.writer).await?;
+            read_rematch_answer(&mut conn.reader).await
+        };
+        with_timeout(limit, taken).await.ok().map(|()| (index, RoundUser { conn, sent: Some(sent) }))
+    });
+    for (index, mut user) in join_all(offers).await.into_iter().flatten() {
+        user.conn.writer.stop_signing();
+        events.emit(ProtocolEvent::Rematched { index: Some(index) });
+        inbox.rematch(user);
+    }
+}
+
+// Whether the user takes another round. It may have sent us its signed PSBTs before it read our offer.
+async fn read_rematch_answer<R: AsyncBufRead + Unpin>(reader: &mut FramedReader<R>) -> Result<(), ProtocolError> {
+    loop {
+        match read_msg(reader, SignedPsbt::MAX_SIZE).await? {
+            Message::SignedPsbt(_) | Message::SigBundle(_) => continue,
+            Message::Accept(_) => return Ok(()),
+            other => return Err(ProtocolError::Unexpected { expected: Accept::NAME, got: other.name() }),
+        }
+    }
+}
+
+async fn send_preimage_and_prv_key<W: AsyncWrite + Unpin>(
+    preimage: [u8; 32],
+    prv_key: &PrivateKey,
+    writer: &mut FramedWriter<W>,
+) -> Result<(), ProtocolError> {
+    let message = PreimageHandover { preimage: preimage.to_hex(), key: prv_key.to_string() };
+
+    send_msg(&message.into(), writer).await
+}
+
+// Users may take a while to check the maker2user contracts, so we keep their connections alive
+async fn read_prv_keys<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    readers: &mut [FramedReader<R>],
+    writers: &mut [FramedWriter<W>],
+    limit: Duration,
+    config: &MakerConfig,
+) -> Result<Vec<PrivateKey>, JoinSwapError> {
+    let mut prv_keys = Vec::new();
+    for (index, (reader, writer)) in readers.iter_mut().zip(writers).enumerate() {
+        let prv_key = with_timeout(limit, expect_msg_alive(reader, writer, config.keepalive)).await
+            .and_then(|PrivKeyHandover { key }| Ok(parse_prv_key(&key, config.network)?));
+        prv_keys.push(check_peer(prv_key, Leg::First, index, writer).await?);
+    }
+
+    Ok(prv_keys)
+}
+
+// Sweeps the users2maker contract to `destination` at `fee_rate`, with the `signer` holding our keys of it. With the
+// multisig path keys of as many users as it `needs` we take the cooperative path, else (or if those keys
+// don't satisfy it after all) the hashlock path with the users' hashlock keys, which reveals the `preimage`.
+fn sweep_users2maker(
+    signer: &mut Wallet<MemoryDatabase>,
+    desc: &Descriptor<PublicKey>,
+    (multisig_keys, needs): (&[PrivateKey], usize),
+    (hashlock_keys, preimage): (&[PrivateKey], [u8; 32]),
+    (outpoint, prevout): (OutPoint, TxOut),
+    (destination, fee_rate): (Script, FeeRate),
+    tip: u32,
+) -> Result<(SweepPath, Transaction), BuildError> {
+    let swept = |tx: &Transaction| prevout.value - tx.output.iter().map(|txout| txout.value).sum::<u64>();
+
+    if multisig_keys.len() >= needs {
+        add_contract_signers(signer, desc, multisig_keys);
+        match build_cooperative_sweep(signer, outpoint, prevout.clone(), destination.clone(), fee_rate, tip) {
+            Ok(tx) => return Ok((SweepPath::Cooperative { txid: tx.txid(), fee: swept(&tx) }, tx)),
+            Err(BuildError::Unsatisfied { .. }) => {},
+            Err(e) => return Err(e),
+        }
+    }
+    add_contract_signers(signer, desc, hashlock_keys);
+    let spend = build_hashlock_spend(signer, preimage, outpoint, prevout.clone(), destination, fee_rate, tip)?;
+    let tx = spend.extract_tx();
+
+    Ok((SweepPath::Hashlock { txid: tx.txid(), fee: swept(&tx) }, tx))
+}
+
+// The users2maker contract keys of the users that hand over theirs in time, each one checked against
+// its key of `multisig_keys`
+async fn read_contract_keys<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    readers: &mut [FramedReader<R>],
+    writers: &mut [FramedWriter<W>],
+    multisig_keys: &[PublicKey],
+    config: &MakerConfig,
+) -> Vec<PrivateKey> {
+    let mut prv_keys = Vec::new();
+    for (index, (reader, writer)) in readers.iter_mut().zip(writers).enumerate() {
+        let prv_key = with_timeout(config.handover_timeout, expect_msg_alive(reader, writer, config.keepalive)).await
+            .and_then(|PrivKeyHandover { key }| Ok(parse_prv_key(&key, config.network)?));
+        let prv_key = match prv_key {
+            Ok(key) => check_prv_keys(&[key], vec![multisig_keys[index]], config.network)
+                .map(|()| key)
+                .map_err(|e| ProtocolError::Malformed { field: "contract key", reason: e.to_string() }),
+            Err(e) => Err(e),
+        };
+        if let Ok(prv_key) = check_peer(prv_key, Leg::First, index, writer).await {
+            prv_keys.push(prv_key);
+        }
+    }
+
+    prv_keys
+}
+
+// The user checks it builds `desc` alike by its checksum, and that the `timelock` leaves it time to claim
+async fn send_second_contract_data<W: AsyncWrite + Unpin>(
+    key_pair: &[PublicKey; 2],
+    hash: PayoutHash,
+    desc: &Descriptor<PublicKey>,
+    txid: Txid,
+    (amount, timelock): (u64, u16),
+    writer: &mut FramedWriter<W>,
+) -> Result<(), ProtocolError> {
+    let keys = key_pair.iter().map(PublicKey::to_string).collect();
+    // Sent only if not sha256
+    let hash_kind = (hash.kind() != HashKind::Sha256).then_some(hash.kind());
+    let (hash, checksum, txid) = (hash.to_string(), desc_checksum(desc), txid.to_string());
+    let message = SecondContractData { keys, hash, hash_kind, checksum, txid, amount, timelock };
+
+    send_msg(&message.into(), writer).await
+}
+
+// The preimage of the paid invoice replaces the maker2user contract data
+async fn send_ln_payout<W: AsyncWrite + Unpin>(preimage: [u8; 32], writer: &mut FramedWriter<W>) -> Result<(), ProtocolError> {
+    send_msg(&LightningPayout { preimage: preimage.to_hex() }.into(), writer).await
+}
+
+// A second leg peer that never connects fails like one that goes silent
+async fn accept_second<S>(
+    inbox: &mut RoundInbox<S>,
+    index: usize,
+    config: &MakerConfig,
+) -> Result<Connection<S>, JoinSwapError> {
+    let second = with_timeout(config.timeouts.second_leg, async { Ok(inbox.next_second().await) }).await;
+
+    second.map_err(peer_error(Leg::Second, index))
+}
+
+// The next connection our gate lets in, which counts as pending until the slot is dropped. Those
+// turned away are told why, so they can tell a busy maker from a network failure.
+pub async fn accept_gated<A: Acceptor>(
+    acceptor: &A,
+    config: &MakerConfig,
+) -> io::Result<(Connection<A::Stream>, PendingSlot)> {
+    loop {
+        let socket = acceptor.accept().await?;
+        let ip = A::peer_ip(&socket);
+        let mut conn = Connection::new(socket, config.framing);
+
+        match config.gate.admit(ip) {
+            Ok(slot) => return Ok((conn, slot)),
+            Err(refusal) => refuse(&mut conn, config, &refusal).await,
+        }
+    }
+}
+
+// Turns away a user before or during its hello. Without Noise (or once it's done) this is an error
+// message, otherwise it replaces the handshake reply.
+pub async fn refuse<S: AsyncRead + AsyncWrite>(conn: &mut Connection<S>, config: &MakerConfig, reason: &Refusal) {
+    let reason = reason.to_string();
+    let refused = async {
+        match config.noise_key.is_none() || conn.writer.is_encrypted() {
+            true => send_error(AbortCode::Refused, &reason, &mut conn.writer).await,
+            false => Ok(noise::refuse(conn, &reason).await?),
+        }
+    };
+    // A peer that doesn't read can't hold us up
+    let _ = timeout(REFUSE_TIMEOUT, refused).await;
+}
+
+// How long we try to tell a refused peer why
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Users open every connection with the Noise handshake and then a hello, which we answer with ours.
+// A user we can't talk to is told why, the caller drops it. Users resuming a session or joining a
+// second leg must be expected by a round of `router`, the caller routes them there.
+pub async fn answer_hello<S: AsyncRead + AsyncWrite>(
+    conn: &mut Connection<S>,
+    config: &MakerConfig,
+    router: &RoundRouter<S>,
+) -> Result<Arrival, ProtocolError> {
+    match &config.noise_key {
+        Some(key) => with_timeout(config.timeouts.hello, noise::respond(conn, key)).await?,
+        None => conn.writer.assume_encrypted_stream(),
+    }
+
+    let denominated = config.denomination.is_some();
+    let (bumps, anyone_can_pay) = (config.funding_bump_after.is_some(), config.anyone_can_pay);
+    let (network, encoding, taproot) = (config.network, config.psbt_encoding, config.taproot);
+    let hello = Hello::new(network, config.round_users, encoding, denominated, taproot, bumps, anyone_can_pay);
+    let theirs = with_timeout(config.timeouts.hello, expect_msg(&mut conn.reader)).await;
+
+    let session = theirs.and_then(|theirs: Hello| {
+        let arrival = match (&theirs.resume, &theirs.round) {
+            (Some(token), _) => Arrival::Resume(token.clone()),
+            (None, Some(txid)) => {
+                let txid = Txid::from_str(txid)
+                    .map_err(|e| ProtocolError::Malformed { field: "round", reason: e.to_string() })?;
+                Arrival::SecondLeg(txid)
+            },
+            (None, None) => Arrival::Join,
+        };
+        router.check(&arrival)?;
+        Ok((hello.negotiate(&theirs)?, signing::session_nonce(&theirs, &hello)?, arrival))
+    });
+    let (negotiated, session, arrival) = match session {
+        Ok(session) => session,
+        Err(e) => {
+            if e.is_peer_fault() {
+                let _ = send_error(e.abort_code(), &e, &mut conn.writer).await;
+            }
+            return Err(e);
+        },
+    };
+    send_msg(&hello.into(), &mut conn.writer).await?;
+    conn.writer.set_psbt_encoding(negotiated.psbt_encoding);
+    conn.writer.set_sig_bundles(negotiated.sig_bundles);
+    conn.writer.set_waiting_status(negotiated.waiting_status);
+    conn.writer.set_partial_spends(negotiated.partial_spends);
+    conn.writer.set_funding_bumps(negotiated.funding_bumps);
+    conn.writer.set_anyone_can_pay(negotiated.anyone_can_pay);
+    conn.start_session(session);
+
+    // A user joining a round gets our terms, and only waits for a round once it takes them
+    if matches!(arrival, Arrival::Join) {
+        send_msg(&offer(config).into(), &mut conn.writer).await?;
+        let Accept {} = with_timeout(config.timeouts.hello, expect_msg(&mut conn.reader)).await?;
+    }
+    Ok(arrival)
+}
+
+// We don't wait for the funding tx to confirm yet, see `fund_users2maker`
+const FUNDING_CONFIRMATIONS: u32 = 0;
+
+pub fn offer(config: &MakerConfig) -> Offer {
+    Offer {
+        network: config.network.to_string(),
+        min_amount: config.utxo_values.min_value(),
+        max_amount: config.utxo_values.max,
+        fee: config.maker_fee,
+        confirmations: FUNDING_CONFIRMATIONS,
+        refund_timelocks: TimelockRange::only(config.timelocks.refund),
+        maker2user_timelocks: TimelockRange::only(config.timelocks.maker2user),
+    }
+}
+
+// Sent to a user whose round didn't fill up within `matchmaking_timeout`
+pub const NO_ROUND: &str = "No round filled up in time, try again later";
+
+// Tells a user waiting for its round how many users it still lacks, if the user takes it
+pub async fn send_waiting<W: AsyncWrite + Unpin>(
+    missing: usize,
+    writer: &mut FramedWriter<W>,
+) -> Result<(), ProtocolError> {
+    if !writer.waiting_status() {
+        return Ok(());
+    }
+    send_msg(&Waiting { missing }.into(), writer).await
+}
+
+// Tags an error with the peer that caused it
+fn peer_error(leg: Leg, index: usize) -> impl FnOnce(ProtocolError) -> JoinSwapError {
+    move |error| JoinSwapError::Peer { leg, index, error }
+}
+
+// The user that sent the key a contract descriptor was rejected for, if any
+fn contract_key_owner(keys: &[[PublicKey; 3]], error: &ContractDescError) -> Option<usize> {
+    let key = match error {
+        ContractDescError::DuplicateKey(key) | ContractDescError::Uncompressed(key) => key,
+        _ => return None,
+    };
+    // The users' triplets come first and the maker's last
+    keys.iter().rposition(|triplet| triplet.contains(key)).filter(|&index| index < keys.len() - 1)
+}
+
+// Like `peer_error`, but a peer that sent us something invalid is told why we drop it
+
+async fn check_peer<T, W: AsyncWrite + Unpin>(
+    result: Result<T, ProtocolError>,
+    leg: Leg,
+    index: usize,
+    writer: &mut FramedWriter<W>,
+) -> Result<T, JoinSwapError> {
+    if let Err(error) = &result {
+        if error.is_peer_fault() {
+            // The round is over anyway, so it doesn't matter if the peer is gone
+            let _ = send_error(error.abort_code(), error, writer).await;
+        }
+    }
+    result.map_err(peer_error(leg, index))
+}
+
+// Runs a Lightning backend call on the blocking thread pool
+async fn ln_call<T: Send + 'static>(
+    backend: &Arc<dyn LnBackend>,
+    call: impl FnOnce(&dyn LnBackend) -> Result<T, LnError> + Send + 'static,
+) -> Result<T, LnError> {
+    let backend = backend.clone();
+
+    tokio::task::spawn_blocking(move || call(backend.as_ref())).await.unwrap()
+}
+
+// The maker2user contract keys and the payout request
+// The maker2user contract keys of the user, how it takes its payout and the payout it asks for
+async fn read_second_user_data<R: AsyncBufRead + Unpin>(
+    reader: &mut FramedReader<R>
+) -> Result<(PublicKey, [PublicKey; 2], PayoutRequest, u64), ProtocolError> {
+    let UserKeys { keys } = expect_msg(reader).await?;
+    let keys = parse_contract_keys(&keys, 3)?;
+    signing::check_signer(reader, &keys)?;
+
+    let PayoutRequestData { request, amount } = expect_msg(reader).await?;
+    let payout = PayoutRequest::from_str(&request)
+        .map_err(|reason| ProtocolError::Malformed { field: "payout request", reason })?;
+
+    Ok((keys[0], [keys[1], keys[2]], payout, amount))
+}
+
+async fn send_psbt<W: AsyncWrite + Unpin>(
+    psbt: &Psbt,
+    writers: &mut [FramedWriter<W>],
+) -> Result<(), JoinSwapError> {
+    let sent = join_all(writers.iter_mut().map(|writer| send_signed_psbt(psbt, writer))).await;
+
+    first_failure(sent, Leg::First)
+}
+
+// What the refund tx pays to `addr`
+fn refunded(refund_psbt: &Psbt, addr: &Address) -> u64 {
+    refund_psbt.unsigned_tx.output.iter()
+        .filter(|txout| txout.script_pubkey == addr.script_pubkey())
+        .map(|txout| txout.value)
+        .sum()
+}
+
+// The contract must leave us `min_margin` over the payouts, not counting what we put in ourselves
+fn check_margin(
+    funding_psbt: &Psbt,
+    desc: &Descriptor<PublicKey>,
+    own_put_in: u64,
+    payouts: &[u64],
+    min_margin: u64,
+) -> Result<(), JoinSwapError> {
+    let (_, contract_txout) = contract_output(&funding_psbt.unsigned_tx, &desc.script_pubkey())
+        .expect("Our funding tx pays the contract");
+    let received = contract_txout.value - own_put_in;
+    let spent = payouts.iter().sum();
+    if !matches!(received.checked_sub(spent), Some(margin) if margin >= min_margin) {
+        return Err(JoinSwapError::MarginTooLow { received, spent, min_margin });
+    }
+    Ok(())
+}
+
+// Our own input in the funding tx, if we put one in, is signed once the users finalized theirs
+fn sign_own_input(own_input: Option<&MakerTreasury>, funding: &mut Psbt) -> Result<(), JoinSwapError> {
+    if let Some(treasury) = own_input {
+        let finalized = treasury.sign(funding)?;
+        assert!(finalized, "The users' inputs were checked to be finalized");
+    }
+    Ok(())
+}
+
+// Checks our node would relay the finalized funding tx before anyone gets it, as then the users can't
+// broadcast it either. The refund txs can't be checked this way until their timelock expires.
+async fn node_accepts_funding(chain: &Arc<dyn ChainAccess>, funding: &Psbt) -> Result<(), ChainError> {
+    let tx = funding.clone().extract_tx();
+    match chain_call(chain, move |chain| chain.test_accept(&tx)).await? {
+        None => Ok(()),
+        Some(reason) => Err(ChainError(format!("Our node rejects the funding tx: {reason}"))),
+    }
+}
+
+// Whether the funding `tx` paying to the contract of `contract_spk` confirms within `wait`
+async fn confirms_within(
+    chain: &Arc<dyn ChainAccess>,
+    tx: &Transaction,
+    contract_spk: &Script,
+    wait: Duration,
+) -> Result<bool, ChainError> {
+    let deadline = Instant::now() + wait;
+    let (txid, contract_spk) = (tx.txid(), contract_spk.clone());
+    loop {
+        let contract_spk = contract_spk.clone();
+        if chain_call(chain, move |chain| chain.confirmations(&txid, &contract_spk)).await? > 0 {
+            return Ok(true);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        tokio::time::sleep(CONFIRMATION_POLL.min(deadline - now)).await;
+    }
+}
+
+// What users learn of the users2maker contract besides its txs
+struct ContractSummary {
+    // See `contract_keys_by_path`
+    keys: Vec<PublicKey>,
+    hash: sha256::Hash,
+    // Of the users2maker contract descriptor, see `desc_checksum`
+    checksum: String,
+    // Users check the refund tx only pays to these
+    refund_addrs: Vec<Address>,
+    // What each user gets paid, each one is only told its own
+    payouts: Vec<u64>,
+    denomination: Option<Denomination>,
+    // Sent only if not every path needs every participant
+    thresholds: Option<PathThresholds>,
+    timelocks: Timelocks,
+    fee_rates: FeeRates,
+    // Only if we put a utxo of our own in the contract
+    maker_refund_addr: Option<Address>,
+}
+
+async fn send_contract_data<W: AsyncWrite + Unpin>(
+    contract: &ContractSummary,
+    funding: &Psbt,
+    refunds: &[Psbt],
+    writers: &mut [FramedWriter<W>],
+) -> Result<(), JoinSwapError> {
+    let sent = join_all(writers.iter_mut().enumerate().map(|(index, writer)| async move {
+        let encoding = writer.psbt_encoding();
+        let message = Message::from(ContractData {
+            keys: contract.keys.iter().map(PublicKey::to_string).collect(),
+            hash: contract.hash.to_string(),
+            checksum: contract.checksum.clone(),
+            refund_addresses: contract.refund_addrs.iter().map(Address::to_string).collect(),
+            funding: WirePsbt::encode(funding, encoding),
+            refund: WirePsbt::encode(&refunds[0], encoding),
+            refund_ladder: refunds[1..].iter().map(|refund| WirePsbt::encode(refund, encoding)).collect(),
+            payout: contract.payouts[index],
+            denomination: contract.denomination,
+            thresholds: contract.thresholds,
+            timelocks: contract.timelocks,
+            fee_rates: contract.fee_rates,
+            maker_refund_address: contract.maker_refund_addr.as_ref().map(Address::to_string),
+        });
+        send_msg(&message, writer).await
+    })).await;
+
+    first_failure(sent, Leg::First)
+}
+
+// Sends to several peers go out concurrently, so one that is slow or gone doesn't hold back the
+// others. The first peer whose send failed fails the round.
+fn first_failure(sent: Vec<Result<(), ProtocolError>>, leg: Leg) -> Result<(), JoinSwapError> {
+    for (index, result) in sent.into_iter().enumerate() {
+        result.map_err(peer_error(leg, index))?;
+    }
+    Ok(())
+}
+
+async fn read_user_data<R: AsyncBufRead + Unpin>(
+    reader: &mut FramedReader<R>,
+    network: Network,
+    utxo_values: UtxoValueRange,
+    denominated: bool,
+) -> Result<UserData, ProtocolError> {
+    let UserKeys { keys } = expect_msg(reader).await?;
+    let keys = parse_contract_keys(&keys, 3)?;
+    signing::check_signer(reader, &keys)?;
+    let funds = read_utxo_data(reader, network, utxo_values, denominated).await?;
+    let refund_addr = read_refund(reader, network).await?;
+
+    let HashSourceData { source } = expect_msg(reader).await?;
+    let hash_source = HashSource::from_str(&source)
+        .map_err(|reason| ProtocolError::Malformed { field: "hash source", reason })?;
+
+    Ok(UserData { keys: [keys[0], keys[1], keys[2]], funds, refund_addr, hash_source })
+}
+
+// The PSBT a user signed, if it only added its signatures to the one we `sent` and they verify
+fn check_signed(
+    sent: &Psbt,
+    psbt: Psbt,
+    check_user_sigs: impl Fn(&Psbt) -> Result<(), PsbtReadError>,
+) -> Result<Psbt, ProtocolError> {
+    check_unaltered(sent, &psbt)?;
+    check_partial_sigs(&psbt)?;
+    check_user_sigs(&psbt)?;
+    Ok(psbt)
+}
+
+// Users may only add signatures to the PSBT we sent, or finalize their own inputs. Any other
+// change could alter what we sign, like a new sighash type, or break the finalization.
+fn check_unaltered(sent: &Psbt, received: &Psbt) -> Result<(), PsbtReadError> {
+    if received.version != sent.version
+        || received.xpub != sent.xpub
+        || received.proprietary != sent.proprietary
+        || received.unknown != sent.unknown
+        || received.inputs.len() != sent.inputs.len()
+        || received.outputs.len() != sent.outputs.len()
+    {
+        return Err(PsbtReadError::Altered(PsbtPart::Global));
+    }
+
+    for (input, (sent_in, received_in)) in sent.inputs.iter().zip(&received.inputs).enumerate() {
+        let mut expected = psbt::Input {
+            partial_sigs: received_in.partial_sigs.clone(),
+            tap_script_sigs: received_in.tap_script_sigs.clone(),
+            ..sent_in.clone()
+        };
+
+        // Finalizing may clear the fields only needed to sign
+        if received_in.final_script_sig.is_some() || received_in.final_script_witness.is_some() {
+            expected.final_script_sig = received_in.final_script_sig.clone();
+            expected.final_script_witness = received_in.final_script_witness.clone();
+
+            if received_in.sighash_type.is_none() {
+                expected.sighash_type = None;
+            }
+            if received_in.redeem_script.is_none() {
+                expected.redeem_script = None;
+            }
+            if received_in.witness_script.is_none() {
+                expected.witness_script = None;
+            }
+            if received_in.bip32_derivation.is_empty() {
+                expected.bip32_derivation.clear();
+            }
+        }
+
+        if received_in.sighash_type != expected.sighash_type {
+            return Err(PsbtReadError::SighashChanged { input, sighash: received_in.sighash_type });
+        }
+        if *received_in != expected {
+            return Err(PsbtReadError::Altered(PsbtPart::Input(input)));
+        }
+    }
+
+    match sent.outputs.iter().zip(&received.outputs).position(|(sent_out, received_out)| sent_out != received_out) {
+        Some(output) => Err(PsbtReadError::Altered(PsbtPart::Output(output))),
+        None => Ok(()),
+    }
+}
+
+// Every signature added by a user must be valid and commit to the whole tx (SIGHASH_ALL)
+fn check_partial_sigs(psbt: &Psbt) -> Result<(), PsbtReadError> {
+    for index in 0..psbt.inputs.len() {
+        let input = &psbt.inputs[index];
+        // Taproot keys are x-only, and checked as the key with an even y
+        let tap_keys = input.tap_script_sigs.keys().map(|(key, _)| PublicKey::new(key.public_key(Parity::Even)));
+        let keys: Vec<_> = input.partial_sigs.keys().copied().chain(tap_keys).collect();
+        check_sigs(psbt, index, &keys)?;
+    }
+    Ok(())
+}
+
+// The user must have signed the input with `key`, not just left it untouched
+fn check_required_sig(psbt: &Psbt, input: usize, key: PublicKey) -> Result<(), PsbtReadError> {
+    check_sigs(psbt, input, &[key])
+}
+
+fn check_sigs(psbt: &Psbt, input: usize, keys: &[PublicKey]) -> Result<(), PsbtReadError> {
+    let statuses = verify_partial_sigs(psbt, input, keys).map_err(PsbtReadError::Unverifiable)?;
+
+    match statuses.into_iter().find(|(_, status)| *status != SigStatus::Valid) {
+        Some((key, status)) => Err(PsbtReadError::BadSignature { input, key, status }),
+        None => Ok(()),
+    }
+}
+
+// Users sign and finalize their own funding inputs, so each witness must already satisfy its utxo
+// and weigh no more than the `declared` weight the fees were computed with
+fn check_own_input(psbt: &Psbt, outpoint: OutPoint, declared: usize) -> Result<(), PsbtReadError> {
+    let input = psbt.unsigned_tx.input.iter().position(|txin| txin.previous_output == outpoint)
+        .ok_or_else(|| PsbtReadError::Decode(format!("Missing input {outpoint}")))?;
+
+    verify_finalized_input(psbt, input).map_err(|reason| PsbtReadError::InvalidInput { input, reason })?;
+
+    // Counted like the declared weight, which includes the script_sig length
+    let finalized = &psbt.inputs[input];
+    let script_sig_len = finalized.final_script_sig.as_ref().map_or(0, |script| script.len());
+    let witness_len = finalized.final_script_witness.as_ref().map_or(0, |witness| witness.serialized_len());
+    let weight = 4 * (VarInt(script_sig_len as u64).len() + script_sig_len) + witness_len;
+
+    if weight > declared {
+        return Err(PsbtReadError::WitnessTooHeavy { input, weight, declared });
+    }
+    Ok(())
+}
+
+// The utxos of the user and the change it keeps, if any. What it puts in the contract must be in the
+// accepted range. In rounds of equal amounts that's the most it puts in, and it must keep change.
+async fn read_utxo_data<R: AsyncBufRead + Unpin>(
+    reader: &mut FramedReader<R>,
+    network: Network,
+    values: UtxoValueRange,
+    denominated: bool,
+) -> Result<UserFunds, ProtocolError> {
+    let UtxoData { utxos, change } = expect_msg(reader).await?;
+    if denominated && change.is_none() {
+        return Err(UtxoError::MissingChange.into());
+    }
+    if utxos.is_empty() || utxos.len() > MAX_USER_UTXOS {
+        return Err(UtxoError::Count { count: utxos.len(), max: MAX_USER_UTXOS }.into());
+    }
+    let utxos = utxos.into_iter().map(check_user_utxo).collect::<Result<Vec<_>, _>>()?;
+
+    let mut outpoints = HashSet::new();
+    if let Some(utxo) = utxos.iter().find(|utxo| !outpoints.insert(utxo.utxo.outpoint())) {
+        return Err(UtxoError::Duplicate(utxo.utxo.outpoint()).into());
+    }
+    let change = change.map(|change| check_change(change, network)).transpose()?;
+    let funds = UserFunds { utxos, change };
+
+    // Checked against the previous txs, so the values can be trusted
+    let value = funds.value();
+    match &funds.change {
+        Some(change) if change.contribution > value => {
+            return Err(UtxoError::ContributionTooLarge { contribution: change.contribution, value }.into());
+        },
+        Some(change) => values.check(change.contribution)?,
+        None => values.check(value)?,
+    }
+    Ok(funds)
+}
+
+fn check_change(change: ChangeRequest, network: Network) -> Result<Change, UtxoError> {
+    let ChangeRequest { contribution, address, fold_dust } = change;
+
+    // Unknown witness versions or programs could be unspendable or non-standard
+    let address = Address::from_str(&address).ok()
+        .filter(|addr| addr.is_valid_for_network(network) && addr.address_type().is_some())
+        .ok_or(UtxoError::BadChangeAddress(address))?;
+
+    Ok(Change { contribution, address, fold_dust })
+}
+
+fn check_user_utxo(utxo: UserUtxo) -> Result<WeightedUtxo, UtxoError> {
+    let UserUtxo { descriptor, outpoint, psbt_input: psbt_in } = utxo;
+    let desc = Descriptor::<PublicKey>::from_str(&descriptor)
+        .map_err(|e| UtxoError::Malformed(format!("Invalid descriptor: {e}")))?;
+    check_utxo_desc_type(&desc)?;
+    let satisfaction_weight = check_satisfaction_weight(&desc)?;
+
+    let outpoint = OutPoint::from_str(&outpoint)
+        .map_err(|e| UtxoError::Malformed(format!("Invalid outpoint: {e}")))?;
+
+    check_utxo(&desc, outpoint, &psbt_in)?;
+
+    Ok(WeightedUtxo {
+        satisfaction_weight,
+        utxo: Utxo::Foreign { outpoint, psbt_input: Box::new(psbt_in) },
+    })
+}
+
+// Most weight a wsh user input may add to the funding tx, enough for a multisig of a dozen keys
+const MAX_WSH_SATISFACTION_WEIGHT: usize = 2_000;
+
+// The funding fee is paid for the weight the user descriptor declares, so it must be an upper bound
+// we can trust (insane scripts may be satisfied in unexpected ways) and not unreasonably large
+fn check_satisfaction_weight(desc: &Descriptor<PublicKey>) -> Result<usize, UtxoError> {
+    desc.sanity_check().map_err(|e| UtxoError::Malformed(format!("Insane descriptor: {e}")))?;
+    let weight = desc.max_satisfaction_weight()
+        .map_err(|e| UtxoError::Malformed(format!("Unsatisfiable descriptor: {e}")))?;
+
+    let max = match desc.desc_type() {
+        DescriptorType::Wpkh => P2WPKH_SATISFACTION_WEIGHT,
+        _ => MAX_WSH_SATISFACTION_WEIGHT,
+    };
+    if weight > max {
+        return Err(UtxoError::HeavySatisfaction { weight, max });
+    }
+    Ok(weight)
+}
+
+fn check_utxo_desc_type(desc: &Descriptor<PublicKey>) -> Result<(), UtxoError> {
+    match desc.desc_type() {
+        DescriptorType::Wpkh | DescriptorType::Wsh | DescriptorType::WshSortedMulti => Ok(()),
+        desc_type => Err(UtxoError::UnsupportedDescriptor(desc_type)),
+    }
+}
+
+// The witness_utxo alone could claim any value, so it must match the output of the full previous
+// tx, which must also be the one the outpoint points to
+fn check_utxo(desc: &Descriptor<PublicKey>, outpoint: OutPoint, psbt_in: &psbt::Input) -> Result<(), UtxoError> {
+    let witness_utxo = psbt_in.witness_utxo.as_ref().ok_or(UtxoError::MissingWitnessUtxo)?;
+    let prev_tx = psbt_in.non_witness_utxo.as_ref().ok_or(UtxoError::MissingPrevTx)?;
+
+    if prev_tx.txid() != outpoint.txid {
+        return Err(UtxoError::TxidMismatch { expected: outpoint.txid, actual: prev_tx.txid() });
+    }
+    let prevout = prev_tx.output.get(outpoint.vout as usize)
+        .ok_or(UtxoError::VoutMissing { vout: outpoint.vout })?;
+
+    if witness_utxo.value != prevout.value {
+        return Err(UtxoError::ValueMismatch { claimed: witness_utxo.value, actual: prevout.value });
+    }
+    if witness_utxo.script_pubkey != prevout.script_pubkey {
+        return Err(UtxoError::ScriptMismatch);
+    }
+    if prevout.script_pubkey != desc.script_pubkey() {
+        return Err(UtxoError::DescriptorMismatch);
+    }
+    Ok(())
+}
+
+async fn read_refund<R: AsyncBufRead + Unpin>(reader: &mut FramedReader<R>, network: Network) -> Result<Address, ProtocolError> {
+    let RefundAddress { address } = expect_msg(reader).await?;
+    let addr = Address::from_str(&address).map_err(|e| RefundAddrError::Invalid(e.to_string()))?;
+
+    if !addr.is_valid_for_network(network) {
+        return Err(RefundAddrError::WrongNetwork { address: addr.to_string(), network }.into());
+    }
+    // Unknown witness versions or programs could be unspendable or non-standard
+    if addr.address_type().is_none() {
+        return Err(RefundAddrError::NonStandard(addr.to_string()).into());
+    }
+    Ok(addr)
+}
+
+// Fails with the index of the first user with a utxo we can't spend
+type UtxoCheck = Result<(), (usize, UtxoError)>;
+
+// Returns the index of the first user with a utxo that the chain shows unconfirmed or spent by a tx
+// we didn't make, either of which could leave the funding tx invalid
+fn check_user_utxos(chain: &dyn ChainAccess, utxos: &[Vec<(OutPoint, TxOut)>]) -> Result<UtxoCheck, ChainError> {
+    for (index, user_utxos) in utxos.iter().enumerate() {
+        for (outpoint, txout) in user_utxos {
+            if chain.confirmations(&outpoint.txid, &txout.script_pubkey)? == 0 {
+                return Ok(Err((index, UtxoError::Unconfirmed(*outpoint))));
+            }
+            if chain.spent(outpoint, &txout.script_pubkey)? {
+                return Ok(Err((index, UtxoError::Spent(*outpoint))));
+            }
+        }
+    }
+    Ok(Ok(()))
+}
+
+// Returns the index of the first user with an address we can't refund to
+fn check_refund_addresses(
+    addrs: &[Address],
+    contract: &Address,
+) -> Result<(), (usize, RefundAddrError)> {
+    for (index, addr) in addrs.iter().enumerate() {
+        if addr == contract {
+            return Err((index, RefundAddrError::ContractAddress));
+        }
+        if addrs[..index].contains(addr) {
+            return Err((index, RefundAddrError::Duplicate(addr.to_string())));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk::bitcoin::secp256k1::Secp256k1;
+    use bdk::bitcoin::psbt::PsbtSighashType;
+    use bdk::bitcoin::util::bip32::ExtendedPrivKey;
+    use bdk::bitcoin::{EcdsaSighashType, PackedLockTime, TxIn};
+    use bdk::miniscript::psbt::PsbtExt;
+
+    use super::*;
+    use crate::chain::MemoryChain;
+    use crate::message::SignedUpdate;
+    use crate::{get_descriptors, users2maker_contract_desc, PathThresholds};
+
+    fn prv_key(byte: u8) -> PrivateKey {
+        PrivateKey::new(SecretKey::from_slice(&[byte; 32]).unwrap(), Network::Regtest)
+    }
+
+    // The users2maker contract of two users and us, with the multisig, timelock and hashlock keys of each
+    struct Contract {
+        desc: Descriptor<PublicKey>,
+        keys: Vec<[PrivateKey; 3]>,
+        preimage: [u8; 32],
+    }
+
+    impl Contract {
+        fn new() -> Self {
+            let keys: Vec<_> = (0..3).map(|i| [1, 2, 3].map(|path| prv_key(10 * i + path))).collect();
+            let secp = Secp256k1::new();
+            let pub_keys: Vec<_> = keys.iter().map(|triplet| triplet.map(|key| key.public_key(&secp))).collect();
+            let preimage = [9; 32];
+            let hash = sha256::Hash::hash(&preimage);
+            let desc = users2maker_contract_desc(&pub_keys, hash, PathThresholds::all(3), 288).unwrap();
+
+            Contract { desc, keys, preimage }
+        }
+
+        // How we sweep it with the multisig path keys `handed_over` by the users
+        fn sweep(&self, handed_over: &[PrivateKey]) -> SweepPath {
+            let mut signer = contract_wallet(&self.desc).unwrap();
+            add_contract_signers(&mut signer, &self.desc, &self.keys[2]);
+            let hashlock_keys = [self.keys[0][2], self.keys[1][2]];
+            let outpoint = OutPoint { txid: Txid::from_inner([7; 32]), vout: 0 };
+            let prevout = TxOut { value: 100_000, script_pubkey: self.desc.script_pubkey() };
+            let destination = Address::p2wpkh(&self.keys[2][0].public_key(&Secp256k1::new()), Network::Regtest)
+                .unwrap()
+                .script_pubkey();
+
+            let (sweep, tx) = sweep_users2maker(
+                &mut signer,
+                &self.desc,
+                (handed_over, 2),
+                (&hashlock_keys, self.preimage),
+                (outpoint, prevout),
+                (destination, FeeRate::from_sat_per_vb(2.0)),
+                1_000,
+            ).unwrap();
+            match sweep {
+                SweepPath::Cooperative { txid, .. } | SweepPath::Hashlock { txid, .. } => assert_eq!(txid, tx.txid()),
+                SweepPath::Refund => unreachable!("We always sweep"),
+            }
+            sweep
+        }
+    }
+
+    #[test]
+    fn handover_in_time_sweeps_cooperatively() {
+        let contract = Contract::new();
+        let handed_over = [contract.keys[0][0], contract.keys[1][0]];
+
+        assert!(matches!(contract.sweep(&handed_over), SweepPath::Cooperative { .. }));
+    }
+
+    #[test]
+    fn missing_handover_falls_back_to_the_hashlock() {
+        let contract = Contract::new();
+
+        assert!(matches!(contract.sweep(&[contract.keys[0][0]]), SweepPath::Hashlock { .. }));
+        // As many keys as needed, but one of them isn't a multisig key
+        let wrong = [contract.keys[0][0], contract.keys[1][1]];
+        assert!(matches!(contract.sweep(&wrong), SweepPath::Hashlock { .. }));
+    }
+
+    // A treasury holding `values`
+    fn treasury(values: &[u64]) -> MakerTreasury {
+        let chain = MemoryChain::default();
+        let xprv = ExtendedPrivKey::new_master(Network::Regtest, &[0xaa; 32]).unwrap();
+        let database = AnyDatabase::Memory(MemoryDatabase::new());
+        let wallet = Wallet::new(&get_descriptors(&xprv), None, Network::Regtest, database).unwrap();
+        chain.fund(&wallet.get_address(AddressIndex::New).unwrap().script_pubkey(), values);
+        chain.sync_wallet(&wallet).unwrap();
+
+        MakerTreasury::new(wallet)
+    }
+
+    // A maker2user contract gets exactly the amount, with our change back to us, unless we can't afford it
+    #[test]
+    fn maker2user_funding_pays_the_amount() {
+        let desc = Contract::new().desc;
+        let treasury = treasury(&[30_000, 40_000]);
+        let fee_rate = FeeRate::from_sat_per_vb(2.0);
+
+        let Err(BuildError::Treasury(reason)) =
+            treasury.fund_contract(&desc, 70_000, fee_rate, 1_000, &mut treasury.lock())
+        else {
+            panic!("Expected the treasury to fall short");
+        };
+        assert!(reason.starts_with("A maker2user contract of 70000 sats takes"), "{reason}");
+        assert!(reason.ends_with("we have 70000 left"), "{reason}");
+
+        let (tx, fee) = treasury.fund_contract(&desc, 45_000, fee_rate, 1_000, &mut treasury.lock()).unwrap();
+        let paying: Vec<_> = tx.output.iter().filter(|txout| txout.script_pubkey == desc.script_pubkey()).collect();
+        assert!(matches!(paying.as_slice(), [txout] if txout.value == 45_000));
+        let wallet = &treasury.0.lock().unwrap().wallet;
+        let change: u64 = tx.output.iter()
+            .filter(|txout| wallet.is_mine(&txout.script_pubkey).unwrap())
+            .map(|txout| txout.value)
+            .sum();
+        assert_eq!(45_000 + change + fee, 70_000);
+        assert!(fee >= 2 * tx.vsize() as u64, "{fee}");
+    }
+
+    // A wpkh utxo of `value` and the psbt input a user sends for it
+    fn user_utxo(value: u64) -> (Descriptor<PublicKey>, OutPoint, psbt::Input) {
+        let desc = Descriptor::new_wpkh(prv_key(50).public_key(&Secp256k1::new())).unwrap();
+        let txout = TxOut { value, script_pubkey: desc.script_pubkey() };
+        let input = vec![TxIn::default()];
+        let prev_tx = Transaction { version: 2, lock_time: PackedLockTime::ZERO, input, output: vec![txout.clone()] };
+        let outpoint = OutPoint { txid: prev_tx.txid(), vout: 0 };
+        let psbt_in = psbt::Input { witness_utxo: Some(txout), non_witness_utxo: Some(prev_tx), ..Default::default() };
+
+        (desc, outpoint, psbt_in)
+    }
+
+    #[test]
+    fn forged_utxo_value_and_script() {
+        let (desc, outpoint, psbt_in) = user_utxo(50_000);
+        assert_eq!(check_utxo(&desc, outpoint, &psbt_in), Ok(()));
+
+        let mut inflated = psbt_in.clone();
+        inflated.witness_utxo.as_mut().unwrap().value = 150_000;
+        assert_eq!(
+            check_utxo(&desc, outpoint, &inflated),
+            Err(UtxoError::ValueMismatch { claimed: 150_000, actual: 50_000 }),
+        );
+
+        let other_desc = Descriptor::new_wpkh(prv_key(51).public_key(&Secp256k1::new())).unwrap();
+        let mut forged = psbt_in.clone();
+        forged.witness_utxo.as_mut().unwrap().script_pubkey = other_desc.script_pubkey();
+        assert_eq!(check_utxo(&other_desc, outpoint, &forged), Err(UtxoError::ScriptMismatch));
+        // Both agree, but not with the descriptor the user declared
+        assert_eq!(check_utxo(&other_desc, outpoint, &psbt_in), Err(UtxoError::DescriptorMismatch));
+    }
+
+    // Only segwit v0 utxos are taken, the funding tx weighs what their descriptors tell
+    #[test]
+    fn user_utxo_descriptors() {
+        let (desc, outpoint, psbt_input) = user_utxo(50_000);
+        let utxo = |descriptor: String| UserUtxo {
+            descriptor,
+            outpoint: outpoint.to_string(),
+            psbt_input: psbt_input.clone(),
+        };
+        let weighted = check_user_utxo(utxo(desc.to_string())).unwrap();
+        assert_eq!(weighted.satisfaction_weight, P2WPKH_SATISFACTION_WEIGHT);
+        assert_eq!(weighted.utxo.outpoint(), outpoint);
+
+        let key = prv_key(50).public_key(&Secp256k1::new());
+        let other = prv_key(51).public_key(&Secp256k1::new());
+        let unsupported = [
+            (format!("pkh({key})"), DescriptorType::Pkh),
+            (format!("multi(1,{key},{other})"), DescriptorType::Bare),
+            (format!("sh(wpkh({key}))"), DescriptorType::ShWpkh),
+            (format!("tr({key})"), DescriptorType::Tr),
+        ];
+        for (descriptor, desc_type) in unsupported {
+            let Err(error) = check_user_utxo(utxo(descriptor)) else {
+                panic!("Expected {desc_type:?} descriptors to be refused");
+            };
+            assert_eq!(error, UtxoError::UnsupportedDescriptor(desc_type));
+            let message = format!("Unsupported {desc_type:?} descriptor, only wpkh and wsh are accepted");
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    // Inflating the prev tx along with the witness_utxo makes it another tx than the outpoint's
+    #[test]
+    fn tweaked_prev_tx_is_a_txid_mismatch() {
+        let (desc, outpoint, psbt_in) = user_utxo(50_000);
+
+        let mut tweaked = psbt_in.clone();
+        tweaked.witness_utxo.as_mut().unwrap().value = 150_000;
+        let prev_tx = tweaked.non_witness_utxo.as_mut().unwrap();
+        prev_tx.output[0].value = 150_000;
+        let actual = prev_tx.txid();
+        assert_eq!(
+            check_utxo(&desc, outpoint, &tweaked),
+            Err(UtxoError::TxidMismatch { expected: outpoint.txid, actual }),
+        );
+
+        let past_the_outputs = OutPoint { vout: 1, ..outpoint };
+        assert_eq!(check_utxo(&desc, past_the_outputs, &psbt_in), Err(UtxoError::VoutMissing { vout: 1 }));
+    }
+
+    // The first leg gate: what the contract pays us, but for what we put in ourselves, against the payouts
+    #[test]
+    fn margin_gate() {
+        let desc = Contract::new().desc;
+        let output = vec![TxOut { value: 100_000, script_pubkey: desc.script_pubkey() }];
+        let tx = Transaction { version: 2, lock_time: PackedLockTime::ZERO, input: vec![TxIn::default()], output };
+        let funding = Psbt::from_unsigned_tx(tx).unwrap();
+
+        assert!(check_margin(&funding, &desc, 0, &[49_000, 49_000], 2_000).is_ok());
+        assert!(matches!(
+            check_margin(&funding, &desc, 0, &[49_000, 49_000], 2_001),
+            Err(JoinSwapError::MarginTooLow { received: 100_000, spent: 98_000, min_margin: 2_001 }),
+        ));
+
+        // A loss, which no minimum allows
+        let loss = check_margin(&funding, &desc, 10_000, &[49_000, 49_000], 0).unwrap_err();
+        assert!(matches!(loss, JoinSwapError::MarginTooLow { received: 90_000, spent: 98_000, min_margin: 0 }));
+        assert_eq!(loss.abort_code(), AbortCode::MarginTooLow);
+        assert_eq!(
+            loss.to_string(),
+            "Maker margin too low: receiving 90000 sats and spending 98000 sats, but the minimum margin is 0 sats",
+        );
+    }
+
+    // A user PSBT of `user_utxo`, as we send it and as the user signs it, with `sighash` if any
+    fn user_psbt(sighash: Option<PsbtSighashType>) -> (Psbt, Psbt) {
+        let prv_key = prv_key(50);
+        let wallet = Wallet::new(&format!("wpkh({prv_key})"), None, Network::Regtest, MemoryDatabase::new()).unwrap();
+        let (desc, outpoint, psbt_in) = user_utxo(50_000);
+        let txin = TxIn { previous_output: outpoint, ..Default::default() };
+        let output = vec![TxOut { value: 49_000, script_pubkey: desc.script_pubkey() }];
+        let tx = Transaction { version: 2, lock_time: PackedLockTime::ZERO, input: vec![txin], output };
+        let mut sent = Psbt::from_unsigned_tx(tx).unwrap();
+        sent.inputs[0] = psbt_in;
+
+        let mut signed = sent.clone();
+        signed.inputs[0].sighash_type = sighash;
+        let sign_ops = SignOptions { try_finalize: false, allow_all_sighashes: true, ..Default::default() };
+        assert!(!wallet.sign(&mut signed, sign_ops).unwrap());
+        assert_eq!(signed.inputs[0].partial_sigs.len(), 1);
+        (sent, signed)
+    }
+
+    // What read_and_combine checks of every PSBT a user sends back
+    fn check_received(sent: &Psbt, received: Psbt) -> Result<(), PsbtReadError> {
+        let received = SignedUpdate::Psbt(received).into_psbt(sent)?;
+        check_unaltered(sent, &received)?;
+        check_partial_sigs(&received)
+    }
+
+    #[test]
+    fn tampered_user_psbts() {
+        let (sent, signed) = user_psbt(None);
+        assert_eq!(check_received(&sent, signed.clone()), Ok(()));
+
+        // Another output value makes it another tx
+        let mut changed_value = signed.clone();
+        changed_value.unsigned_tx.output[0].value = 48_000;
+        let actual = changed_value.unsigned_tx.txid();
+        assert_eq!(
+            check_received(&sent, changed_value),
+            Err(PsbtReadError::TxidMismatch { expected: sent.unsigned_tx.txid(), actual }),
+        );
+
+        let mut changed_output = signed.clone();
+        changed_output.outputs[0].witness_script = Some(Script::new());
+        assert_eq!(check_received(&sent, changed_output), Err(PsbtReadError::Altered(PsbtPart::Output(0))));
+
+        // Flipping the sighash of the input, with the signature or without it
+        let single_acp = PsbtSighashType::from(EcdsaSighashType::SinglePlusAnyoneCanPay);
+        let mut flipped = signed;
+        flipped.inputs[0].sighash_type = Some(single_acp);
+        assert_eq!(
+            check_received(&sent, flipped),
+            Err(PsbtReadError::SighashChanged { input: 0, sighash: Some(single_acp) }),
+        );
+
+        let (_, mut signed_single) = user_psbt(Some(single_acp));
+        signed_single.inputs[0].sighash_type = None;
+        let key = prv_key(50).public_key(&Secp256k1::new());
+        assert_eq!(
+            check_received(&sent, signed_single),
+            Err(PsbtReadError::BadSignature { input: 0, key, status: SigStatus::NonStandardSighash(single_acp) }),
+        );
+    }
+
+    #[test]
+    fn satisfaction_weights() {
+        let keys: Vec<_> = (60..80).map(|byte| prv_key(byte).public_key(&Secp256k1::new()).to_string()).collect();
+        let desc = |desc: String| Descriptor::<PublicKey>::from_str(&desc).unwrap();
+
+        let wpkh = desc(format!("wpkh({})", keys[0]));
+        assert_eq!(check_satisfaction_weight(&wpkh), Ok(P2WPKH_SATISFACTION_WEIGHT));
+        let multisig = desc(format!("wsh(multi(2,{},{},{}))", keys[0], keys[1], keys[2]));
+        assert_eq!(check_satisfaction_weight(&multisig), Ok(multisig.max_satisfaction_weight().unwrap()));
+
+        // A wsh the funding fee couldn't pay for at the agreed feerate
+        let inflated = desc(format!("wsh(multi(20,{}))", keys.join(",")));
+        let weight = inflated.max_satisfaction_weight().unwrap();
+        assert!(weight > MAX_WSH_SATISFACTION_WEIGHT);
+        assert_eq!(
+            check_satisfaction_weight(&inflated),
+            Err(UtxoError::HeavySatisfaction { weight, max: MAX_WSH_SATISFACTION_WEIGHT }),
+        );
+
+        // Satisfiable without a signature, so no upper bound to trust
+        let insane = desc(format!("wsh(or_d(pk({}),older(1)))", keys[0]));
+        assert!(matches!(check_satisfaction_weight(&insane), Err(UtxoError::Malformed(_))));
+    }
+
+    // After signing, the witness of the user can't weigh more than it declared
+    #[test]
+    fn finalized_witness_against_the_declared_weight() {
+        let (_, mut signed) = user_psbt(None);
+        signed.finalize_mut(&Secp256k1::verification_only()).unwrap();
+        let outpoint = signed.unsigned_tx.input[0].previous_output;
+        assert_eq!(check_own_input(&signed, outpoint, P2WPKH_SATISFACTION_WEIGHT), Ok(()));
+
+        let witness = signed.inputs[0].final_script_witness.as_ref().unwrap();
+        let weight = 4 + witness.serialized_len();
+        assert!(weight <= P2WPKH_SATISFACTION_WEIGHT);
+        assert_eq!(
+            check_own_input(&signed, outpoint, weight - 1),
+            Err(PsbtReadError::WitnessTooHeavy { input: 0, weight, declared: weight - 1 }),
+        );
+    }
+}
@@ -0,0 +1,5 @@
+// The session logic of each protocol side, independent of how peers are reached
+
+pub mod maker;
+pub mod rounds;
+pub mod user;
@@ -0,0 +1,365 @@
+// What the rounds a maker runs at once share: the connections coming back to a running round are
+// routed to it, and the utxos and hashes one round takes can't be taken by another.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use bdk::bitcoin::{OutPoint, Txid};
+use bdk::bitcoin::hashes::sha256;
+use bdk::bitcoin::secp256k1::rand::thread_rng;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::error::{JoinSwapError, ProtocolError, UtxoError};
+use crate::gen_hash;
+use crate::lightning::PreimageError;
+use crate::limits::PendingSlot;
+use crate::protocol::maker::RoundUser;
+use crate::shutdown::save_state;
+use crate::transport::Connection;
+
+// Why a user connects, as told by its hello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Arrival {
+    // A first leg user looking for a round
+    Join,
+    // A user coming back to its session after the connection dropped, see `signing::resume_token`
+    Resume(String),
+    // A second leg user of the round with this funding txid
+    SecondLeg(Txid),
+}
+
+// A connection let in by our gate, pending until its round takes it
+pub type Arrived<S> = (Connection<S>, PendingSlot);
+
+// A resumed connection along with the index of its user in the round
+type Resumed<S> = (usize, Arrived<S>);
+
+struct Routes<S> {
+    // Resume token of each session still signing, with the index of its user in the round
+    resumes: HashMap<String, (usize, UnboundedSender<Resumed<S>>)>,
+    // Rounds waiting for their second leg users, by funding txid
+    second_legs: HashMap<Txid, UnboundedSender<Arrived<S>>>,
+    // Where the users of declined rounds go back to matchmaking, if anyone takes them
+    rematches: Option<UnboundedSender<RoundUser<S>>>,
+}
+
+// Hands each connection to the round it belongs to. Clones share the routes: the accept loop routes
+// with one and each round registers what it waits for through its `RoundInbox`.
+pub struct RoundRouter<S>(Arc<Mutex<Routes<S>>>);
+
+impl<S> Clone for RoundRouter<S> {
+    fn clone(&self) -> Self {
+        RoundRouter(self.0.clone())
+    }
+}
+
+impl<S> Default for RoundRouter<S> {
+    fn default() -> Self {
+        RoundRouter(Arc::new(Mutex::new(Routes {
+            resumes: HashMap::new(),
+            second_legs: HashMap::new(),
+            rematches: None,
+        })))
+    }
+}
+
+impl<S> RoundRouter<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Whether a running round takes the user, first leg users can always join a new one
+    pub fn check(&self, arrival: &Arrival) -> Result<(), ProtocolError> {
+        let routes = self.0.lock().unwrap();
+
+        match arrival {
+            Arrival::Join => Ok(()),
+            Arrival::Resume(token) if !routes.resumes.contains_key(token) => Err(ProtocolError::UnknownSession),
+            Arrival::SecondLeg(txid) if !routes.second_legs.contains_key(txid) => Err(ProtocolError::UnknownRound),
+            _ => Ok(()),
+        }
+    }
+
+    // Hands a connection over to its round. Users joining a new round are given back, and those of
+    // a round that ended meanwhile dropped.
+    pub fn route(&self, arrival: Arrival, arrived: Arrived<S>) -> Option<Arrived<S>> {
+        let routes = self.0.lock().unwrap();
+
+        match arrival {
+            Arrival::Join => return Some(arrived),
+            Arrival::Resume(token) => if let Some((index, round)) = routes.resumes.get(&token) {
+                let _ = round.send((*index, arrived));
+            },
+            Arrival::SecondLeg(txid) => if let Some(round) = routes.second_legs.get(&txid) {
+                let _ = round.send(arrived);
+            },
+        }
+        None
+    }
+
+    // The users of declined rounds that wait for another one, see `RoundInbox::rematch`
+    pub fn rematches(&self) -> UnboundedReceiver<RoundUser<S>> {
+        let (sender, receiver) = unbounded_channel();
+        self.0.lock().unwrap().rematches = Some(sender);
+
+        receiver
+    }
+
+    // Where a new round gets its connections
+    pub fn inbox(&self) -> RoundInbox<S> {
+        let (resumed_sender, resumed) = unbounded_channel();
+        let (second_sender, second) = unbounded_channel();
+
+        RoundInbox { router: self.clone(), resumed_sender, resumed, second_sender, second }
+    }
+}
+
+// The connections routed to one round. Those it no longer waits for are dropped along with it, and
+// each one keeps its pending slot until taken, so the gate still bounds them.
+pub struct RoundInbox<S> {
+    router: RoundRouter<S>,
+    resumed_sender: UnboundedSender<Resumed<S>>,
+    resumed: UnboundedReceiver<Resumed<S>>,
+    second_sender: UnboundedSender<Arrived<S>>,
+    second: UnboundedReceiver<Arrived<S>>,
+}
+
+impl<S> RoundInbox<S> {
+    // The users of the round can resume the sessions of `tokens`, given in the order of the users
+    pub fn expect_resumes(&self, tokens: &[String]) {
+        let mut routes = self.router.0.lock().unwrap();
+
+        for (index, token) in tokens.iter().enumerate() {
+            routes.resumes.insert(token.clone(), (index, self.resumed_sender.clone()));
+        }
+    }
+
+    pub fn stop_resumes(&self) {
+        let mut routes = self.router.0.lock().unwrap();
+
+        routes.resumes.retain(|_, (_, round)| !round.same_channel(&self.resumed_sender));
+    }
+
+    // Hands a user of a declined round back to matchmaking. Without anyone taking it, it's dropped.
+    pub fn rematch(&self, user: RoundUser<S>) {
+        if let Some(rematches) = &self.router.0.lock().unwrap().rematches {
+            let _ = rematches.send(user);
+        }
+    }
+
+    // Second leg users tell us the funding txid of their round
+    pub fn expect_second_leg(&self, funding_txid: Txid) {
+        let mut routes = self.router.0.lock().unwrap();
+
+        routes.second_legs.insert(funding_txid, self.second_sender.clone());
+    }
+
+    // The next user resuming its session, and its index. We hold a sender, so one always may come.
+    pub async fn next_resumed(&mut self) -> (usize, Connection<S>) {
+        let (index, (conn, _slot)) = self.resumed.recv().await.expect("The inbox holds a sender");
+
+        (index, conn)
+    }
+
+    pub async fn next_second(&mut self) -> Connection<S> {
+        let (conn, _slot) = self.second.recv().await.expect("The inbox holds a sender");
+
+        conn
+    }
+}
+
+impl<S> Drop for RoundInbox<S> {
+    fn drop(&mut self) {
+        self.stop_resumes();
+        let mut routes = self.router.0.lock().unwrap();
+
+        routes.second_legs.retain(|_, round| !round.same_channel(&self.second_sender));
+    }
+}
+
+#[derive(Debug, Default)]
+struct Used {
+    outpoints: HashSet<OutPoint>,
+    hashes: HashSet<sha256::Hash>,
+    // Where the hashes are saved, see `UsedInputs::load`
+    dir: Option<PathBuf>,
+}
+
+// Name of the file in the state dir with the hashes of our rounds
+const USED_HASHES: &str = "used-hashes";
+
+impl Used {
+    fn save_hashes(&self) -> io::Result<()> {
+        match &self.dir {
+            Some(dir) => save_state(dir, USED_HASHES, &self.hashes).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+// Utxos and hashes taken by our rounds, running or finished, which no other round can take. Clones
+// share them.
+#[derive(Debug, Clone, Default)]
+pub struct UsedInputs(Arc<Mutex<Used>>);
+
+impl UsedInputs {
+    // Takes the hashes of our earlier runs from `<dir>/used-hashes.json`, where we save each hash a round
+    // takes before its users see it. Without them a restart could lock a new round to an old hash.
+    pub fn load(dir: PathBuf) -> io::Result<Self> {
+        let hashes = match fs::read_to_string(dir.join(format!("{USED_HASHES}.json"))) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(UsedInputs(Arc::new(Mutex::new(Used { outpoints: HashSet::new(), hashes, dir: Some(dir) }))))
+    }
+
+    // Marks as used the utxos spent by the funding txs of our previous runs, and their hashes
+    pub fn extend(
+        &self,
+        outpoints: impl IntoIterator<Item = OutPoint>,
+        hashes: impl IntoIterator<Item = sha256::Hash>,
+    ) {
+        let mut used = self.0.lock().unwrap();
+
+        used.outpoints.extend(outpoints);
+        used.hashes.extend(hashes);
+    }
+
+    pub fn reserve(&self) -> Reservation {
+        Reservation { used: self.clone(), outpoints: Vec::new(), hash: None, kept: false }
+    }
+}
+
+// What a round takes, given back when dropped unless kept (once the funding tx is out)
+#[derive(Debug)]
+pub struct Reservation {
+    used: UsedInputs,
+    outpoints: Vec<OutPoint>,
+    hash: Option<sha256::Hash>,
+    kept: bool,
+}
+
+impl Reservation {
+    // Takes the utxos of each user, or returns the index of the first user with one we can't spend.
+    // We can only tell a utxo was spent if it was by one of our funding txs.
+    pub fn take_outpoints(&mut self, outpoints: &[Vec<OutPoint>]) -> Result<(), (usize, UtxoError)> {
+        let mut used = self.used.0.lock().unwrap();
+
+        for (index, user_outpoints) in outpoints.iter().enumerate() {
+            for outpoint in user_outpoints {
+                if outpoints[..index].iter().flatten().any(|taken| taken == outpoint) {
+                    return Err((index, UtxoError::AlreadyInRound(*outpoint)));
+                }
+                if used.outpoints.contains(outpoint) {
+                    return Err((index, UtxoError::PreviouslyUsed(*outpoint)));
+                }
+            }
+        }
+        used.outpoints.extend(outpoints.iter().flatten());
+        self.outpoints.extend(outpoints.iter().flatten());
+        Ok(())
+    }
+
+    // Takes the hash of the invoice the users brought, or a fresh one along with its preimage
+    pub fn take_hash(
+        &mut self,
+        external: Option<sha256::Hash>,
+    ) -> Result<(Option<[u8; 32]>, sha256::Hash), JoinSwapError> {
+        let mut used = self.used.0.lock().unwrap();
+
+        let (preimage, hash) = match external {
+            Some(hash) if used.hashes.contains(&hash) => return Err(PreimageError::ReusedHash(hash).into()),
+            Some(hash) => (None, hash),
+            None => {
+                let (preimage, hash) = gen_hash(&mut thread_rng(), Some(&used.hashes));
+                (Some(preimage), hash)
+            },
+        };
+        used.hashes.insert(hash);
+        if let Err(e) = used.save_hashes() {
+            used.hashes.remove(&hash);
+            return Err(JoinSwapError::UsedHashes(e));
+        }
+        self.hash = Some(hash);
+        Ok((preimage, hash))
+    }
+
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        let mut used = self.used.0.lock().unwrap();
+
+        for outpoint in &self.outpoints {
+            used.outpoints.remove(outpoint);
+        }
+        if let Some(hash) = &self.hash {
+            used.hashes.remove(hash);
+            // Left in the file, it would only keep the users from bringing the same invoice again
+            let _ = used.save_hashes();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    // A fresh state dir, as a process would find it on its first start
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("joinswap-rounds-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // After a restart the hash of a funded round can't be taken again, one given back can
+    #[test]
+    fn hashes_survive_a_restart() {
+        let dir = state_dir("restart");
+        let used = UsedInputs::load(dir.clone()).unwrap();
+        let mut funded = used.reserve();
+        let (_, hash) = funded.take_hash(None).unwrap();
+        funded.keep();
+        let mut failed = used.reserve();
+        let (_, given_back) = failed.take_hash(None).unwrap();
+        drop(failed);
+
+        let restarted = UsedInputs::load(dir.clone()).unwrap();
+        let reused = restarted.reserve().take_hash(Some(hash));
+        assert!(matches!(reused, Err(JoinSwapError::Preimage(PreimageError::ReusedHash(reused))) if reused == hash));
+        assert_eq!(restarted.reserve().take_hash(Some(given_back)).unwrap(), (None, given_back));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A hash we couldn't save isn't handed out, and a file we can't read stops the start
+    #[test]
+    fn unsaved_hashes() {
+        let dir = state_dir("unsaved");
+        let used = UsedInputs::load(dir.clone()).unwrap();
+        // With a file in its place, the dir can't be created again
+        fs::remove_dir_all(&dir).unwrap();
+        fs::write(&dir, "").unwrap();
+        assert!(matches!(used.reserve().take_hash(None), Err(JoinSwapError::UsedHashes(_))));
+        assert!(used.0.lock().unwrap().hashes.is_empty());
+        fs::remove_file(&dir).unwrap();
+
+        let dir = state_dir("unreadable");
+        fs::write(dir.join(format!("{USED_HASHES}.json")), "[\"not a hash\"]").unwrap();
+        assert_eq!(UsedInputs::load(dir.clone()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
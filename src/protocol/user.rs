@@ -0,0 +1,2727 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bdk::bitcoin::hashes::hex::{FromHex, ToHex};
+use bdk::bitcoin::hashes::{Hash, sha256};
+use bdk::bitcoin::psbt::Psbt;
+use bdk::bitcoin::{Address, EcdsaSighashType, Network, OutPoint, PrivateKey, PublicKey, Script, Sequence, Transaction,
+                   Txid};
+use bdk::bitcoin::secp256k1::{self, Secp256k1, XOnlyPublicKey};
+use bdk::database::{BatchDatabase, MemoryDatabase};
+use bdk::descriptor::Descriptor;
+use bdk::miniscript::psbt::PsbtExt;
+use bdk::wallet::AddressIndex;
+use bdk::{FeeRate, KeychainKind, LocalUtxo, SignOptions, Wallet};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+use tokio::time::{timeout, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::chain::{chain_call, ChainAccess, ChainError};
+use crate::error::{AddInputError, ContractKeyError, JoinSwapError, ProtocolError, PsbtCheckFailure, PsbtReadError};
+use crate::events::{AbortCode, ContractKind, EventSink, MessageKind, Phase, ProtocolEvent, PsbtRole, TxRole};
+use crate::{noise, signing};
+use crate::keys::{ContractKeySource, KeyRole};
+use crate::lightning::{HashSource, PayoutRequest, PreimageError};
+use crate::protocol::maker::ROUND_USERS;
+use crate::recovery::{LadderRefund, Maker2UserRecovery, Recovery, ReplacedFunding, UserRecovery};
+use crate::shutdown::{save_state, SHUTTING_DOWN};
+use crate::transport::{Connection, FramedReader, FramedWriter, Framing, Transport};
+use crate::message::{expect_msg, expect_msg_alive, read_msg_alive, read_signed_psbt, send_decline, send_error, send_msg,
+                     send_signed_psbt, Accept, ContractData, HashSourceData, LightningPayout, Message, Offer,
+                     PayoutRequestData, Hello, PreimageHandover, PrivKeyHandover, PsbtEncoding, RefundAddress,
+                     SecondContractData, UserKeys, UserUtxo, UtxoData, ChangeRequest, Denomination, Waiting, Expected,
+                     FundingBump, WirePsbt, with_pings, read_funding_update, AddInput, FundingUpdate, InputAdded,
+                     SignedPsbt, MAX_MESSAGE_SIZE};
+use crate::{add_contract_signers, add_funding_input, build_multisig_sweep, check_desc_checksum, check_hex32,
+            check_prv_keys, check_added_inputs, check_standardness, contract_keys_by_participant, contract_output,
+            contract_wallet, describe_contract, maker2users_contract_desc, maker2users_contract_desc_tr,
+            max_satisfaction_weight, output_value, parse_contract_keys, parse_prv_key, psbt_fee, rebase_refund,
+            sign_and_send_psbt, sign_peer_psbt, users2maker_contract_desc, users2maker_contract_desc_tr, estimate_vsize,
+            is_anyone_can_pay, locktime_near_tip, refund_fee_share, replacement_failures, validate_timelock_schedule,
+            verify_finalized_input, with_timeout, FeeRateRange, FeeRates, Keepalive, ReadTimeouts, select_utxos, Change,
+            CoinSelection, FundingFeeSplit, MakerFee, PathThresholds, PayoutHash, TimelockBounds, Timelocks,
+            UtxoValueRange, FALLBACK_PATH, HASHLOCK_PATH, MAX_ROUND_USERS, MAX_USER_UTXOS, MIN_ROUND_USERS,
+            MULTISIG_PATH, REFUND_LADDER, TIMELOCK_PATH};
+
+#[cfg(feature = "adversarial")]
+use crate::adversarial::{self, Scenario};
+
+#[derive(Debug, Clone)]
+pub struct UserConfig {
+    pub network: Network,
+    // Users in the rounds we join, which must match the maker's
+    pub round_users: usize,
+    // Set to the payment hash of an invoice to have the maker pay it as part of the swap
+    pub hash_source: HashSource,
+    // BOLT11 invoice (and its payment hash) to take the second leg through Lightning instead of a
+    // maker2user contract
+    pub lightning_payout: Option<(String, sha256::Hash)>,
+    // Maker keys from our previous swaps, which a new contract must not reuse
+    pub maker_key_history: Option<HashSet<PublicKey>>,
+    // Contract hashes from our previous swaps, also not to be reused
+    pub hash_history: Option<HashSet<sha256::Hash>>,
+    // How long we wait for the maker to fill our round up before leaving, nothing is signed by then
+    pub max_wait: Duration,
+    pub timeouts: ReadTimeouts,
+    pub keepalive: Keepalive,
+    // Must match the framing the maker uses
+    pub framing: Framing,
+    // PSBT encoding we offer in the handshake
+    pub psbt_encoding: PsbtEncoding,
+    // Feerates we accept for the funding and refund txs of the users2maker contract
+    pub fee_rates: FeeRateRange,
+    // Utxo values the maker takes, so we don't join a round it would reject us from
+    pub utxo_values: UtxoValueRange,
+    // Sats we put in the contract out of our utxos, the rest comes back to us as change. Without it
+    // we spend them fully.
+    pub contribution: Option<u64>,
+    // Whether a change too small to relay may go to the contract instead
+    pub fold_dust_change: bool,
+    // Join rounds of equal amounts, the maker must run them too. What we contribute is then the most we
+    // put in, and we keep the rest as change.
+    pub denominated: bool,
+    // Lowest participants we accept each users2maker contract path to need, every one if None
+    pub lowest_thresholds: Option<PathThresholds>,
+    // Most the maker may keep out of what we put in, in millionths of it, whatever it offers if None
+    pub max_fee_ppm: Option<u64>,
+    // Contract timelocks we take from the maker
+    pub timelock_bounds: TimelockBounds,
+    // Join rounds with taproot contracts, the maker must build them too
+    pub taproot: bool,
+    // Let the maker replace the funding tx at a higher feerate if it doesn't confirm in time
+    pub funding_bumps: bool,
+    // Sign our funding inputs with SIGHASH_ALL|ANYONECANPAY if the maker and every user of the round do,
+    // so any of us may add a utxo to the funding tx before signing it (see `AddInput`). The outputs stay
+    // committed, but whoever holds our signature can add inputs and change the txid our refund spends, so
+    // we only give it once the refund is signed.
+    pub anyone_can_pay: bool,
+    // In such rounds, a funding tx paying less than this feerate gets one of our spare utxos added, the
+    // smallest reaching it within `fee_rates`. All of its value goes to the fee.
+    pub top_up_rate: Option<FeeRate>,
+    // How we pick the utxos for our contribution
+    pub coin_selection: CoinSelection,
+    // Once cancelled the session stops wherever it is, see `run_user_session`
+    pub shutdown: CancellationToken,
+    // Where the session saves its recovery file (see `UserRecovery`), not saved if None
+    pub state_dir: Option<PathBuf>,
+    // Where our contract keys come from
+    pub contract_keys: ContractKeySource,
+    // Times we connect again to resume the session if the connection drops while signing
+    pub resume_attempts: u32,
+    // Whether we wait for another round with the data we sent when another user declines ours
+    pub rematch: bool,
+    // Asked whether to sign the refund of the users2maker contract, else we sign it once our checks pass
+    pub review_contract: Option<ContractReview>,
+    // Feerate of the tx taking our maker2user contract coins to the wallet
+    pub sweep_fee_rate: FeeRate,
+    // How long we wait for our backend to see the maker2user tx, leaving the maker if it doesn't. `None`
+    // looks it up once and goes on without it if not found, as the demo backend never sees the maker's txs.
+    pub maker2user_wait: Option<Duration>,
+    // Confirmations of the maker2user tx we wait for, within `maker2user_wait`, before we hand over our
+    // hashlock key
+    pub maker2user_confirmations: u32,
+    // How we misbehave on purpose, to see how the maker handles it
+    #[cfg(feature = "adversarial")]
+    pub adversarial: Option<Scenario>,
+}
+
+// Given the spending paths of the users2maker contract in plain words (see `describe_contract`),
+// whether we go on and sign its refund. It runs on a blocking thread, so it may wait on a prompt.
+#[derive(Clone)]
+pub struct ContractReview(Arc<ReviewFn>);
+
+type ReviewFn = dyn Fn(&[String]) -> bool + Send + Sync;
+
+impl ContractReview {
+    pub fn new(review: impl Fn(&[String]) -> bool + Send + Sync + 'static) -> Self {
+        ContractReview(Arc::new(review))
+    }
+}
+
+impl fmt::Debug for ContractReview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ContractReview")
+    }
+}
+
+impl Default for UserConfig {
+    fn default() -> Self {
+        UserConfig {
+            network: Network::Regtest,
+            round_users: ROUND_USERS,
+            hash_source: HashSource::Maker,
+            lightning_payout: None,
+            maker_key_history: None,
+            hash_history: None,
+            max_wait: Duration::from_secs(10 * 60),
+            timeouts: ReadTimeouts::default(),
+            keepalive: Keepalive::default(),
+            framing: Framing::default(),
+            psbt_encoding: PsbtEncoding::default(),
+            fee_rates: FeeRateRange::default(),
+            utxo_values: UtxoValueRange::default(),
+            contribution: None,
+            fold_dust_change: false,
+            denominated: false,
+            lowest_thresholds: None,
+            max_fee_ppm: None,
+            timelock_bounds: TimelockBounds::default(),
+            taproot: false,
+            funding_bumps: true,
+            anyone_can_pay: false,
+            top_up_rate: None,
+            coin_selection: CoinSelection::default(),
+            shutdown: CancellationToken::new(),
+            state_dir: None,
+            contract_keys: ContractKeySource::default(),
+            resume_attempts: 3,
+            rematch: true,
+            review_contract: None,
+            sweep_fee_rate: FeeRate::from_sat_per_vb(1.0),
+            maker2user_wait: None,
+            maker2user_confirmations: 1,
+            #[cfg(feature = "adversarial")]
+            adversarial: None,
+        }
+    }
+}
+
+// What we bring to the round
+#[derive(Debug, Clone)]
+pub struct MyFunds {
+    pub utxos: Vec<LocalUtxo>,
+    pub change: Option<Change>,
+}
+
+impl MyFunds {
+    pub fn value(&self) -> u64 {
+        self.utxos.iter().map(|utxo| utxo.txout.value).sum()
+    }
+
+    // What we put in the contract, before paying our share of the fees
+    pub fn contribution(&self) -> u64 {
+        self.change.as_ref().map_or(self.value(), |change| change.contribution)
+    }
+}
+
+// How we got the second leg of the swap
+#[derive(Debug, Clone)]
+pub enum UserPayout {
+    // With the txid of our sweep of the contract, if we could already build it
+    OnChain { desc: Box<Descriptor<PublicKey>>, txid: Txid, sweep: Option<Txid> },
+    Lightning { payment_hash: sha256::Hash },
+    // The swap failed after the funding tx was broadcast, the finalized refund tx gives us our
+    // coins back once its timelock expires. So do its pricier variants, if fees went up by then (see
+    // `REFUND_LADDER`).
+    Refund { tx: Box<Transaction>, ladder: Vec<Transaction>, reason: String },
+}
+
+// What a completed swap looks like from the user side
+#[derive(Debug, Clone)]
+pub struct UserSwapReport {
+    pub users2maker_desc: Descriptor<PublicKey>,
+    pub hash: sha256::Hash,
+    pub payout: UserPayout,
+    pub funding_txid: Txid,
+    pub refund_txid: Txid,
+    // Value of the utxo we put in the users2maker contract
+    pub contributed: u64,
+    // What the refund tx would have paid us back
+    pub refund_amount: u64,
+    pub funding_fee: u64,
+    pub refund_fee: u64,
+}
+
+// The connections of a session and its recovery record, which outlive the session if it stops midway
+struct SessionState<S> {
+    old_id: Option<Connection<S>>,
+    new_id: Option<Connection<S>>,
+    recovery: Option<UserRecovery>,
+}
+
+// Runs both legs of a JoinSwap as a user, spending from the first utxo of `wallet`. The transport
+// is used twice: once for the original identity and once for the new one of the second leg.
+//
+// Before signing the funding tx and each later step we can't undo, what we need to claim our coins is
+// saved to `config.state_dir` (see `UserRecovery`). If `config.shutdown` is cancelled the session
+// stops where it is, the maker is told and that record is saved once more.
+pub async fn run_user_session<D: BatchDatabase, T: Transport>(
+    config: UserConfig,
+    wallet: Wallet<D>,
+    chain: Arc<dyn ChainAccess>,
+    transport: T,
+    events: EventSink,
+) -> Result<UserSwapReport, JoinSwapError> {
+    let mut state = SessionState { old_id: None, new_id: None, recovery: None };
+    let (shutdown, state_dir) = (config.shutdown.clone(), config.state_dir.clone());
+
+    let session = play_session(config, wallet, chain, &transport, events, &mut state);
+    let result = tokio::select! {
+        result = session => Some(result),
+        _ = shutdown.cancelled() => None,
+    };
+    if let Some(result) = result {
+        return result;
+    }
+
+    for conn in [&mut state.old_id, &mut state.new_id].into_iter().flatten() {
+        let _ = send_error(AbortCode::Shutdown, &SHUTTING_DOWN, &mut conn.writer).await;
+    }
+    let saved = match &state.recovery {
+        Some(record) => checkpoint(state_dir.as_deref(), record)?,
+        None => None,
+    };
+    Err(JoinSwapError::Shutdown { saved })
+}
+
+async fn play_session<D: BatchDatabase, T: Transport>(
+    config: UserConfig,
+    wallet: Wallet<D>,
+    chain: Arc<dyn ChainAccess>,
+    transport: &T,
+    events: EventSink,
+    state: &mut SessionState<T::Stream>,
+) -> Result<UserSwapReport, JoinSwapError> {
+    let SessionState { old_id, new_id: new_slot, recovery } = state;
+    let state_dir = config.state_dir.clone();
+
+    // Without a contribution we spend the first `MAX_USER_UTXOS` utxos of the wallet, fully unless the
+    // round is of equal amounts. Else we select enough for it and keep the change. The maker builds the
+    // funding tx at the feerate it tells us in the contract data.
+    let my_funds = match config.contribution {
+        None => {
+            let utxos: Vec<_> = wallet.list_unspent().unwrap().into_iter().take(MAX_USER_UTXOS).collect();
+            let change = config.denominated.then(|| Change {
+                contribution: utxos.iter().map(|utxo| utxo.txout.value).sum(),
+                address: wallet.get_address(AddressIndex::New).unwrap().address,
+                fold_dust: config.fold_dust_change,
+            });
+            MyFunds { utxos, change }
+        },
+        Some(contribution) => {
+            let address = wallet.get_address(AddressIndex::New).unwrap().address;
+            let utxos = select_utxos(
+                &wallet,
+                contribution,
+                FeeRate::default_min_relay_fee(),
+                &address.script_pubkey(),
+                config.fold_dust_change,
+                config.coin_selection,
+            )?;
+            MyFunds { utxos, change: Some(Change { contribution, address, fold_dust: config.fold_dust_change }) }
+        },
+    };
+    config.utxo_values.check(my_funds.contribution())?;
+    let hello = Hello::new(
+        config.network,
+        config.round_users,
+        config.psbt_encoding,
+        config.denominated,
+        config.taproot,
+        config.funding_bumps,
+        config.anyone_can_pay,
+    );
+    let old_id = old_id.insert(Connection::new(transport.connect().await?, config.framing));
+    events.emit(ProtocolEvent::PhaseEntered(Phase::Connect));
+    say_hello(&hello, old_id, transport.maker_key(), config.timeouts.exchange).await?;
+    if my_funds.change.is_some() && !old_id.writer.partial_spends() {
+        return Err(ProtocolError::Incompatible("The maker doesn't take partial spends".to_string()).into());
+    }
+    // We only join the round if we take the maker's terms, else we leave telling it why
+    let offer = with_timeout(config.timeouts.exchange, expect_msg(&mut old_id.reader)).await;
+    let offer: Offer = check_maker(offer, &mut old_id.writer).await?;
+    events.emit(ProtocolEvent::OfferReceived(offer.clone()));
+    if let Err(e) = check_offer(&offer, &config, my_funds.contribution()) {
+        let _ = send_decline(&e, &mut old_id.writer).await;
+        return Err(e.into());
+    }
+    send_msg(&Accept {}.into(), &mut old_id.writer).await?;
+
+    let session_keys = match config.contract_keys.new_session(config.network) {
+        Ok(session_keys) => session_keys,
+        Err(e) => {
+            let error = JoinSwapError::KeySession(e);
+            let _ = send_error(error.abort_code(), &error, &mut old_id.writer).await;
+            return Err(error);
+        },
+    };
+    let (prv_key1, pub_key1) = session_keys.key(KeyRole::Users2Maker(MULTISIG_PATH));
+    let (prv_key2, pub_key2) = session_keys.key(KeyRole::Users2Maker(TIMELOCK_PATH));
+    let (prv_key3, pub_key3) = session_keys.key(KeyRole::Users2Maker(HASHLOCK_PATH));
+    // We sign with the timelock path key, the only one we never hand over
+    old_id.writer.sign_with(prv_key2.inner);
+
+    let keys = [pub_key1, pub_key2, pub_key3];
+    #[cfg(feature = "adversarial")]
+    let keys = adversarial::contract_keys(config.adversarial, keys);
+    #[cfg_attr(not(feature = "adversarial"), allow(unused_mut))]
+    let mut utxo_data = utxo_data(&wallet, &my_funds);
+    #[cfg(feature = "adversarial")]
+    utxo_data.utxos.iter_mut().for_each(|utxo| adversarial::tamper_utxo(config.adversarial, &mut utxo.psbt_input));
+    let refund = send_user_data(&wallet, &keys, utxo_data, config.hash_source, &mut old_id.writer).await?;
+
+    events.emit(ProtocolEvent::MessageSent(MessageKind::UserData));
+    events.emit(ProtocolEvent::PhaseEntered(Phase::ContractCreation));
+
+    let timeouts = config.timeouts;
+    // Until we hold the finalized refund of a round, another user may decline it. The maker then offers
+    // us another one with the data we already sent.
+    let sent_funds = my_funds;
+    let joined = loop {
+        let mut my_funds = sent_funds.clone();
+        let joining = async {
+            // The maker answers once the other users joined the round, telling us meanwhile how many it still
+            // lacks. Nothing is signed yet, so if it takes too long we just leave.
+            let contract_data = wait_for_round(&mut old_id.reader, &mut old_id.writer, config.keepalive, &events);
+            let contract_data = match timeout(config.max_wait, contract_data).await {
+                Ok(contract_data) => contract_data.and_then(|data| {
+                    check_contract_data(data, &old_id.reader, &config, &offer, my_funds.contribution())
+                }),
+                Err(_) => {
+                    let _ = send_error(AbortCode::NoRound, &GAVE_UP_WAITING, &mut old_id.writer).await;
+                    return Err(JoinSwapError::NoRound { waited: config.max_wait });
+                },
+            };
+            let Contract {
+                keys,
+                hash,
+                checksum,
+                refund_addrs,
+                maker_refund_addr,
+                funding: funding_psbt,
+                refunds: mut refund_psbts,
+                payout: round_payout,
+                denomination,
+                thresholds,
+                timelocks,
+                fee_rates: contract_rates,
+            } = check_round(contract_data, &mut old_id.writer).await?;
+            // In rounds of equal amounts we put in the denomination, and the rest comes back as change
+            if let (Some(denomination), Some(change)) = (denomination, my_funds.change.as_mut()) {
+                change.contribution = denomination.amount;
+            }
+
+            events.emit(ProtocolEvent::MessageReceived(MessageKind::ContractData));
+            events.emit(ProtocolEvent::MessageReceived(MessageKind::FundingAndRefund));
+
+            #[cfg(feature = "adversarial")]
+            if config.adversarial == Some(Scenario::SilentAfterContract) {
+                // We only read until the maker gives up on us and hangs up
+                while old_id.reader.read_frame(MAX_MESSAGE_SIZE).await.is_ok() {}
+                return Err(ProtocolError::Eof.into());
+            }
+
+            // There should be no duplicate keys and my keys should appear once in each policy path
+            let key_history = config.maker_key_history.as_ref();
+            if let Err(e) = check_contract_keys(&keys, &pub_key1, &pub_key2, &pub_key3, key_history) {
+                let _ = send_decline(&e, &mut old_id.writer).await;
+                return Err(e.into());
+            }
+            if config.hash_history.as_ref().is_some_and(|history| history.contains(&hash)) {
+                let e = PreimageError::ReusedHash(hash);
+                let _ = send_decline(&e, &mut old_id.writer).await;
+                return Err(e.into());
+            }
+
+            // The maker doesn't know the preimage of our invoice hash yet, it learns it paying the invoice
+            if let HashSource::Invoice(payment_hash) = config.hash_source {
+                if hash != payment_hash {
+                    let e = PreimageError::NotOurHash(hash);
+                    let _ = send_decline(&e, &mut old_id.writer).await;
+                    return Err(e.into());
+                }
+            }
+
+            let users2maker_desc = match config.taproot {
+                true => users2maker_contract_desc_tr(&keys, hash, thresholds, timelocks.refund),
+                false => users2maker_contract_desc(&keys, hash, thresholds, timelocks.refund),
+            };
+            let users2maker_desc = check_round(users2maker_desc.map_err(Into::into), &mut old_id.writer).await?;
+            // The maker must have built the same contract, or the funding tx would pay to another one
+            let same_desc = check_desc_checksum(&users2maker_desc, &checksum).map_err(Into::into);
+            check_round(same_desc, &mut old_id.writer).await?;
+            events.emit(ProtocolEvent::ContractCreated {
+                contract: ContractKind::Users2Maker,
+                address: users2maker_desc.address(config.network).unwrap(),
+            });
+
+            // Ensure the funding and refund psbts are correctly formed. A maker putting in a utxo of its own
+            // is one more participant to them.
+            let terms = PsbtTerms {
+                fee_rates: config.fee_rates,
+                contract_rates,
+                maker_fee: offer.fee,
+                payout: round_payout,
+                refund_timelock: timelocks.refund,
+                tip: chain_call(&chain, |chain| chain.tip_height()).await?,
+                anyone_can_pay: old_id.writer.anyone_can_pay(),
+            };
+            let participant_addrs: Vec<_> = refund_addrs.iter().chain(&maker_refund_addr).cloned().collect();
+            let checked = check_psbts(
+                &funding_psbt,
+                &refund_psbts,
+                &users2maker_desc,
+                &my_funds,
+                &refund,
+                &participant_addrs,
+                terms,
+            );
+            if let Err(failures) = checked {
+                let error = JoinSwapError::PsbtChecks(failures);
+                let _ = send_decline(&error, &mut old_id.writer).await;
+                return Err(error);
+            }
+            // Their fees are known once checked
+            events.emit(ProtocolEvent::PsbtReceived {
+                role: PsbtRole::Funding,
+                fee: psbt_fee(&funding_psbt).unwrap_or_default(),
+                psbt: Box::new(funding_psbt.clone()),
+            });
+            for refund_psbt in &refund_psbts {
+                events.emit(ProtocolEvent::PsbtReceived {
+                    role: PsbtRole::Refund,
+                    fee: psbt_fee(refund_psbt).unwrap_or_default(),
+                    psbt: Box::new(refund_psbt.clone()),
+                });
+            }
+
+            // Last, whoever reviews the contract for us must approve it
+            if let Some(ContractReview(review)) = config.review_contract.clone() {
+                let maker_keys = keys.last().expect("Contracts have the maker keys");
+                let paths = describe_contract(&users2maker_desc, &[pub_key1, pub_key2, pub_key3], maker_keys);
+                let paths = check_round(paths.map_err(Into::into), &mut old_id.writer).await?;
+                if !tokio::task::spawn_blocking(move || review(&paths)).await.unwrap_or(false) {
+                    let error = JoinSwapError::NotApproved;
+                    let _ = send_decline(&error, &mut old_id.writer).await;
+                    return Err(error);
+                }
+            }
+
+            // The refund tx spends from the contract, so to sign it we use our contract private keys
+            let prv_keys = vec![prv_key1, prv_key2, prv_key3];
+            #[cfg(feature = "adversarial")]
+            let prv_keys = adversarial::refund_signing_keys(config.adversarial, prv_keys);
+            let mut prv_wallet = contract_wallet(&users2maker_desc)?;
+            add_contract_signers(&mut prv_wallet, &users2maker_desc, &prv_keys);
+
+            // We sign the pricier refunds too, but send each once the maker finalized the one before
+            let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
+            for refund_psbt in &mut refund_psbts[1..] {
+                check_round(sign_peer_psbt(refund_psbt, &prv_wallet, sign_ops.clone()), &mut old_id.writer).await?;
+            }
+            #[cfg(feature = "adversarial")]
+            let sign_ops = adversarial::refund_sign_options(config.adversarial, &mut refund_psbts[0], sign_ops);
+            let writers = std::slice::from_mut(&mut old_id.writer);
+            let sent = sign_and_send_psbt(&mut refund_psbts[0], &prv_wallet, sign_ops, writers).await;
+            // Our only peer is the maker
+            let sent = match sent.and_then(|sent| sent.into_iter().collect::<Result<(), _>>()) {
+                // The maker may have ended the round and told us why before we wrote, see `send_signed`
+                Err(e) if e.is_disconnect() => {
+                    match with_timeout(RESUME_DELAY, read_signed_psbt(&mut old_id.reader)).await {
+                        Err(rejected @ ProtocolError::Rejected { .. }) => Err(rejected),
+                        _ => Err(e),
+                    }
+                },
+                sent => sent,
+            };
+            check_round(sent, &mut old_id.writer).await?;
+            events.emit(ProtocolEvent::MessageSent(MessageKind::SignedRefund));
+
+            // From here until the funding tx is finalized we resume the session if the connection drops
+            let resume = old_id.reader.session().map(|session| signing::resume_token(&session));
+            let mut signing = SigningSession {
+                transport,
+                hello: Hello { resume, ..hello.clone() },
+                framing: config.framing,
+                timeouts,
+                signing_key: prv_key2.inner,
+                maker_keys: *keys.last().expect("Contracts have the maker keys"),
+                last_sent: refund_psbts[0].clone(),
+                received: Vec::new(),
+                resumes_left: config.resume_attempts,
+                events: &events,
+            };
+
+            let refunds_final = signing.finalize_refunds(old_id, &refund_psbts, &funding_psbt).await;
+            let refunds_final = check_round(refunds_final, &mut old_id.writer).await?;
+
+            Ok::<_, JoinSwapError>(JoinedRound {
+                my_funds,
+                hash,
+                timelocks,
+                round_payout,
+                users2maker_desc,
+                funding_psbt,
+                refund_psbts,
+                refunds_final,
+                terms,
+                participant_addrs,
+                signing,
+            })
+        };
+        match joining.await {
+            Err(JoinSwapError::Protocol(ProtocolError::Rematch(_))) if config.rematch => {
+                send_msg(&Accept {}.into(), &mut old_id.writer).await?;
+                // The next round comes with new maker keys
+                old_id.reader.forget_peer_key();
+                events.emit(ProtocolEvent::Rematched { index: None });
+            },
+            joined => break joined?,
+        }
+    };
+    let JoinedRound {
+        mut my_funds,
+        hash,
+        timelocks,
+        round_payout,
+        users2maker_desc,
+        mut funding_psbt,
+        mut refund_psbts,
+        mut refunds_final,
+        terms,
+        participant_addrs,
+        mut signing,
+    } = joined;
+    let mut refund_txid = refund_psbts[0].unsigned_tx.txid();
+
+    #[cfg(feature = "adversarial")]
+    if config.adversarial == Some(Scenario::ReconnectBeforeFunding) {
+        signing.resume(old_id, ProtocolError::Eof).await?;
+    }
+
+    // Now that we have the finalized refund tx that is valid after a relative timelock we can sign
+    // the funding tx without risk of losing the funds, once it's saved
+    let record = recovery.insert(UserRecovery {
+        users2maker_desc: users2maker_desc.to_string(),
+        contract_keys: [prv_key1, prv_key2, prv_key3],
+        session: session_keys.session(),
+        funding_txid: funding_psbt.unsigned_tx.txid(),
+        refund_txid,
+        refund_psbt: refunds_final[0].to_string(),
+        refund_ladder: ladder_refunds(&refunds_final),
+        timelocks,
+        maker2user: None,
+        preimage: None,
+        handed_over: Vec::new(),
+        replaced: Vec::new(),
+    });
+    if let Err(e) = checkpoint(state_dir.as_deref(), record) {
+        let _ = send_error(e.abort_code(), &e, &mut old_id.writer).await;
+        return Err(e);
+    }
+    // In rounds with `ANYONE_CAN_PAY` we sign our inputs so others can be added, and may add one first
+    let anyone_can_pay = terms.anyone_can_pay && funding_psbt.inputs.iter().any(is_anyone_can_pay);
+    let mut prv_wallet = contract_wallet(&users2maker_desc)?;
+    add_contract_signers(&mut prv_wallet, &users2maker_desc, &[prv_key1, prv_key2, prv_key3]);
+    let top_up = config.top_up_rate.filter(|_| anyone_can_pay)
+        .and_then(|rate| top_up_utxo(&wallet, &funding_psbt, &my_funds, rate, config.fee_rates));
+    if let Some(utxo) = top_up {
+        let sent = send_msg(&AddInput { utxo: user_utxo(&wallet, &utxo) }.into(), &mut old_id.writer).await;
+        check_round(sent, &mut old_id.writer).await?;
+        events.emit(ProtocolEvent::MessageSent(MessageKind::AddInput));
+
+        let proposed = Some(utxo.outpoint);
+        let added = signing.read_update(old_id, &funding_psbt, true).await.and_then(|update| match update {
+            MakerUpdate::InputAdded(added) => check_input_added(*added, &funding_psbt, &refund_psbts, proposed),
+            MakerUpdate::Finalized(_) => {
+                Err(ProtocolError::Unexpected { expected: InputAdded::NAME, got: SignedPsbt::NAME })
+            },
+        });
+        let (funding, mut refunds) = check_round(added, &mut old_id.writer).await?;
+        events.emit(ProtocolEvent::MessageReceived(MessageKind::InputAdded));
+        let finals = signing.sign_refunds_again(old_id, &prv_wallet, &funding, &mut refunds).await;
+        refunds_final = check_round(finals, &mut old_id.writer).await?;
+        record_inputs_added(record, &funding, &refunds_final);
+        checkpoint(state_dir.as_deref(), record)?;
+
+        my_funds.utxos.push(utxo);
+        refund_txid = record.refund_txid;
+        (funding_psbt, refund_psbts) = (funding, refunds);
+    }
+    let sign_ops = SignOptions { allow_all_sighashes: anyone_can_pay, ..Default::default() };
+    check_round(sign_peer_psbt(&mut funding_psbt, &wallet, sign_ops), &mut old_id.writer).await?;
+    signing.send_signed(old_id, &funding_psbt).await?;
+    #[cfg(feature = "adversarial")]
+    if config.adversarial == Some(Scenario::DoubleFundingSig) {
+        let psbt = crate::message::WirePsbt::encode(&funding_psbt, old_id.writer.psbt_encoding());
+        let message = crate::message::SignedPsbt { psbt }.into();
+        send_msg(&message, &mut old_id.writer).await?;
+    }
+    events.emit(ProtocolEvent::MessageSent(MessageKind::SignedFunding));
+
+    // From here on the maker can broadcast the funding tx, so if the swap fails we can only take our
+    // coins back with the refund tx once its timelock expires
+    let mut funding_txid = funding_psbt.unsigned_tx.txid();
+    let payout = async {
+        let update = signing.read_update(old_id, &funding_psbt, anyone_can_pay).await;
+        let funding_final = match check_maker(update, &mut old_id.writer).await? {
+            MakerUpdate::Finalized(funding_final) => funding_final,
+            // Other users added inputs to the funding tx. Our signature holds, but the refunds must spend it.
+            MakerUpdate::InputAdded(added) => {
+                events.emit(ProtocolEvent::MessageReceived(MessageKind::InputAdded));
+                let added = check_input_added(*added, &funding_psbt, &refund_psbts, None);
+                let (funding, mut refunds) = check_maker(added, &mut old_id.writer).await?;
+                let finals = signing.sign_refunds_again(old_id, &prv_wallet, &funding, &mut refunds).await;
+                refunds_final = check_maker(finals, &mut old_id.writer).await?;
+                record_inputs_added(record, &funding, &refunds_final);
+                checkpoint(state_dir.as_deref(), record)?;
+
+                (funding_txid, refund_txid) = (record.funding_txid, record.refund_txid);
+                (funding_psbt, refund_psbts) = (funding, refunds);
+                let funding_final = signing.read_finalized(old_id, &funding_psbt).await;
+                check_maker(funding_final, &mut old_id.writer).await?
+            },
+        };
+        events.emit(ProtocolEvent::MessageReceived(MessageKind::FinalizedFunding));
+
+        // Here we should wait the funding tx to be mined, for now we just broadcast it ourselves
+        let funding_tx = funding_final.extract_tx();
+        chain_call(&chain, move |chain| chain.broadcast(&funding_tx)).await?;
+        events.emit(ProtocolEvent::Broadcast { role: TxRole::Funding, txid: funding_txid });
+
+        // With funding bumps the maker holds the second leg until the funding tx confirms, and replaces it
+        // if it doesn't in time
+        if old_id.writer.funding_bumps() {
+            let next = read_msg_alive(&mut old_id.reader, &mut old_id.writer, FundingBump::MAX_SIZE, config.keepalive)
+                .await
+                .and_then(|message| {
+                    signing::check_signer(&old_id.reader, &signing.maker_keys)?;
+                    match message {
+                        Message::FundingKept(_) => Ok(None),
+                        Message::FundingBump(bump) => Ok(Some(*bump)),
+                        other => Err(ProtocolError::Unexpected { expected: FundingBump::NAME, got: other.name() }),
+                    }
+                });
+            if let Some(bump) = check_maker(next, &mut old_id.writer).await? {
+                events.emit(ProtocolEvent::MessageReceived(MessageKind::FundingBump));
+                let terms = PsbtTerms { tip: chain_call(&chain, |chain| chain.tip_height()).await?, ..terms };
+                let checked = check_funding_bump(
+                    bump,
+                    &funding_psbt,
+                    &users2maker_desc,
+                    &my_funds,
+                    &refund,
+                    &participant_addrs,
+                    terms,
+                );
+                let (mut bumped, mut bumped_refunds) = match checked {
+                    Ok(psbts) => psbts,
+                    Err(e) => {
+                        let _ = send_error(e.abort_code(), &e, &mut old_id.writer).await;
+                        return Err(e);
+                    },
+                };
+
+                // As the first time, we sign the refunds first and save them before we sign the funding tx
+                let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
+                for refund_psbt in &mut bumped_refunds {
+                    let signed = sign_peer_psbt(refund_psbt, &prv_wallet, sign_ops.clone());
+                    check_round(signed, &mut old_id.writer).await?;
+                }
+                signing.send_signed(old_id, &bumped_refunds[0]).await?;
+                events.emit(ProtocolEvent::MessageSent(MessageKind::SignedRefund));
+
+                let bumped_finals = signing.finalize_refunds(old_id, &bumped_refunds, &bumped).await;
+                let bumped_finals = check_maker(bumped_finals, &mut old_id.writer).await?;
+
+                // Either funding tx may confirm, so we keep the refunds of the replaced one too
+                record.replaced.push(ReplacedFunding {
+                    funding_txid,
+                    refund_txid,
+                    refund_psbt: record.refund_psbt.clone(),
+                    refund_ladder: std::mem::take(&mut record.refund_ladder),
+                });
+                record.funding_txid = bumped.unsigned_tx.txid();
+                record.refund_txid = bumped_refunds[0].unsigned_tx.txid();
+                record.refund_psbt = bumped_finals[0].to_string();
+                record.refund_ladder = ladder_refunds(&bumped_finals);
+                checkpoint(state_dir.as_deref(), record)?;
+
+                check_round(sign_peer_psbt(&mut bumped, &wallet, SignOptions::default()), &mut old_id.writer).await?;
+                signing.send_signed(old_id, &bumped).await?;
+                events.emit(ProtocolEvent::MessageSent(MessageKind::SignedFunding));
+
+                let funding_final = signing.read_finalized(old_id, &bumped).await;
+                let funding_final = check_maker(funding_final, &mut old_id.writer).await?;
+                events.emit(ProtocolEvent::MessageReceived(MessageKind::FinalizedFunding));
+                let funding_tx = funding_final.extract_tx();
+                chain_call(&chain, move |chain| chain.broadcast(&funding_tx)).await?;
+
+                (funding_txid, refund_txid) = (record.funding_txid, record.refund_txid);
+                (funding_psbt, refund_psbts, refunds_final) = (bumped, bumped_refunds, bumped_finals);
+                events.emit(ProtocolEvent::Broadcast { role: TxRole::Funding, txid: funding_txid });
+            }
+        }
+
+        // Connect to the maker with a different ID for the second leg of the JoinSwap, telling it which
+        // round we join
+        let new_id = new_slot.insert(Connection::new(transport.connect().await?, config.framing));
+        events.emit(ProtocolEvent::PhaseEntered(Phase::SecondConnect));
+        let hello = Hello { round: Some(funding_txid.to_string()), ..hello.clone() };
+        say_hello(&hello, new_id, transport.maker_key(), timeouts.exchange).await?;
+
+        // We don't know our index in the round, so our maker2user keys are those of the first user
+        let (prv_key4, pub_key4) = session_keys.key(KeyRole::Maker2User { user: 0, path: MULTISIG_PATH });
+        let (prv_key5, pub_key5) = session_keys.key(KeyRole::Maker2User { user: 0, path: HASHLOCK_PATH });
+        let (prv_key6, pub_key6) = session_keys.key(KeyRole::Maker2User { user: 0, path: FALLBACK_PATH });
+        // As on the first leg, we sign with a key we never hand over
+        new_id.writer.sign_with(prv_key5.inner);
+
+        let payout_request = match &config.lightning_payout {
+            Some((bolt11, _)) => PayoutRequest::Lightning(bolt11.clone()),
+            None => PayoutRequest::OnChain,
+        };
+        let keys = [pub_key4, pub_key5, pub_key6];
+        send_second_user_data(&keys, &payout_request, round_payout, &mut new_id.writer).await?;
+        events.emit(ProtocolEvent::MessageSent(MessageKind::SecondUserData));
+
+        events.emit(ProtocolEvent::PhaseEntered(Phase::SecondContractCreation));
+        let payment_hash = config.lightning_payout.as_ref().map(|(_, payment_hash)| *payment_hash);
+        let hash_history = config.hash_history.as_ref();
+        // The maker2user timelock comes with the contract data, and we take none that leaves us no time to claim
+        let schedule = (round_payout, timelocks.refund, config.timelock_bounds.claim_window);
+        let second_leg = read_second_contract_data(
+            &mut new_id.reader, &mut new_id.writer, config.keepalive, schedule, (hash, hash_history), payment_hash);
+        let second_leg = with_timeout(timeouts.second_leg, second_leg).await;
+        let payout = match check_maker(second_leg, &mut new_id.writer).await? {
+            SecondLeg::Lightning(preimage) => {
+                events.emit(ProtocolEvent::MessageReceived(MessageKind::LightningPayout));
+
+                // The preimage proves the maker paid our invoice, there's no maker2user contract
+                let payment_hash = sha256::Hash::hash(&preimage);
+
+                // This private key must be sent with the old ID (such that the two IDs remain unlinked)
+                events.emit(ProtocolEvent::PhaseEntered(Phase::Handover));
+                record.handed_over.push(pub_key3);
+                checkpoint(state_dir.as_deref(), record)?;
+                send_prv_key(&prv_key3, &mut old_id.writer).await?;
+                events.emit(ProtocolEvent::MessageSent(MessageKind::HashlockKey));
+
+                UserPayout::Lightning { payment_hash }
+            },
+            // Also what we get if our invoice couldn't be paid
+            SecondLeg::OnChain {
+                maker_keys: (maker_key1, maker_key2),
+                hash: payout_hash,
+                timelock,
+                checksum,
+                txid: maker2user_txid,
+            } => {
+                events.emit(ProtocolEvent::MessageReceived(MessageKind::SecondContractData));
+
+                // Derive the maker2user contract descriptor
+                let maker2user_desc = match config.taproot {
+                    true => maker2users_contract_desc_tr,
+                    false => maker2users_contract_desc,
+                };
+                let multisig_keys = [pub_key4, maker_key1];
+                let hashlock_keys = [pub_key5, pub_key6];
+                let maker2user_desc =
+                    maker2user_desc(&multisig_keys, &maker_key2, &hashlock_keys, payout_hash, hash, timelock);
+                let maker2user_desc = check_maker(maker2user_desc.map_err(Into::into), &mut new_id.writer).await?;
+                let same_desc = check_desc_checksum(&maker2user_desc, &checksum).map_err(Into::into);
+                check_maker(same_desc, &mut new_id.writer).await?;
+                events.emit(ProtocolEvent::ContractCreated {
+                    contract: ContractKind::Maker2User(0),
+                    address: maker2user_desc.address(config.network).unwrap(),
+                });
+
+                // The maker2user tx must pay our contract the payout the maker promised for the round, and
+                // have `maker2user_confirmations` before we hand over our hashlock key. Else we leave the
+                // maker and get our coins back with the refund.
+                let need = config.maker2user_confirmations;
+                events.emit(ProtocolEvent::AwaitingConfirmation { txid: maker2user_txid, have: 0, need });
+                let maker2user_tx = match config.maker2user_wait {
+                    Some(wait) => {
+                        // The maker waits on both connections meanwhile
+                        let deadline = Instant::now() + wait;
+                        let interval = config.keepalive.interval;
+                        let found = tx_within(&chain, maker2user_txid, deadline);
+                        let mut pinged = [&mut old_id.writer, &mut new_id.writer];
+                        let found = with_pings(found, &mut pinged, interval).await?;
+                        let seen = found.ok_or_else(|| ProtocolError::Malformed {
+                            field: "maker2user tx",
+                            reason: format!("Our backend didn't see it within {wait:?}"),
+                        });
+                        let tx = check_maker(seen, &mut new_id.writer).await?;
+                        check_maker(check_payout(&tx, &maker2user_desc, round_payout), &mut new_id.writer).await?;
+
+                        let spk = maker2user_desc.script_pubkey();
+                        let confirmed = confirmations_within(&chain, &tx, &spk, need, deadline, &events);
+                        let mut pinged = [&mut old_id.writer, &mut new_id.writer];
+                        let have = with_pings(confirmed, &mut pinged, interval).await?;
+                        if have < need {
+                            let reason = format!("It has {have} of the {need} confirmations we wait for in {wait:?}");
+                            let unconfirmed = Err(ProtocolError::Malformed { field: "maker2user tx", reason });
+                            check_maker(unconfirmed, &mut new_id.writer).await?;
+                        }
+                        Some(tx)
+                    },
+                    // The demo backend never sees the maker's txs, so we go on without it
+                    None => {
+                        let tx = chain_call(&chain, move |chain| chain.get_tx(&maker2user_txid)).await?;
+                        if let Some(tx) = &tx {
+                            check_maker(check_payout(tx, &maker2user_desc, round_payout), &mut new_id.writer).await?;
+                        }
+                        tx
+                    },
+                };
+
+                // If the previous step was successful, send the hashlock path private key from the
+                // users2maker contract to the maker. If all users agree that maker funded correctly the
+                // maker2users contracts then maker will have all the hashlock path keys, and so will be
+                // able to spend the first contract coins by revealing the preimage.
+
+                // This private key must be sent with the old ID (such that the two IDs remain unlinked)
+                events.emit(ProtocolEvent::PhaseEntered(Phase::Handover));
+                record.maker2user = Some(Maker2UserRecovery {
+                    desc: maker2user_desc.to_string(),
+                    txid: maker2user_txid,
+                    keys: [prv_key4, prv_key5],
+                    fallback_key: Some(prv_key6),
+                    maker_key: None,
+                });
+                record.handed_over.push(pub_key3);
+                checkpoint(state_dir.as_deref(), record)?;
+                send_prv_key(&prv_key3, &mut old_id.writer).await?;
+                events.emit(ProtocolEvent::MessageSent(MessageKind::HashlockKey));
+
+                // Read preimage + maker2user contract prv key and check them
+                // If correct, users can now redeem the maker2user contract coins
+                let preimage_and_key =
+                    read_preimage_and_prv_key(&mut new_id.reader, &mut new_id.writer, config.network, config.keepalive);
+                let preimage_and_key = with_timeout(timeouts.second_leg, preimage_and_key).await;
+                let (preimage, maker_prv_key) = check_maker(preimage_and_key, &mut new_id.writer).await?;
+                events.emit(ProtocolEvent::MessageReceived(MessageKind::PreimageAndKey));
+
+                // A wrong preimage leaves us the refund, the record we saved before the handover says so
+                if !payout_hash.matches(&preimage) {
+                    let e = PreimageError::WrongPreimage;
+                    let _ = send_error(AbortCode::PeerMisbehaved, &e, &mut new_id.writer).await;
+                    return Err(e.into());
+                }
+                // With a wrong key we can still claim through the hashlock path, but we don't hand over
+                // our users2maker contract key
+                record.preimage = Some(preimage.to_hex());
+                checkpoint(state_dir.as_deref(), record)?;
+                check_prv_keys(&[maker_prv_key], vec![maker_key1], config.network)?;
+
+                // We can now spend the maker2user contract through its multisig path with `prv_key4` and the
+                // maker's key (see `build_multisig_sweep`), which the recovery record keeps
+                if let Some(maker2user) = record.maker2user.as_mut() {
+                    maker2user.maker_key = Some(maker_prv_key);
+                }
+                checkpoint(state_dir.as_deref(), record)?;
+
+                // If our backend saw the maker2user tx we take its coins to our wallet right away
+                let sweep = match &maker2user_tx {
+                    Some(tx) => {
+                        let tip = chain_call(&chain, |chain| chain.tip_height()).await?;
+                        let contract_utxo = contract_output(tx, &maker2user_desc.script_pubkey())
+                            .expect("We checked the payout");
+                        let sweep_tx = build_multisig_sweep(
+                            &maker2user_desc,
+                            &[prv_key4],
+                            &[maker_prv_key],
+                            contract_utxo,
+                            wallet.get_address(AddressIndex::New).unwrap().script_pubkey(),
+                            config.sweep_fee_rate,
+                            tip,
+                        )?;
+                        let broadcast = sweep_tx.clone();
+                        chain_call(&chain, move |chain| chain.broadcast(&broadcast)).await?;
+                        events.emit(ProtocolEvent::Broadcast { role: TxRole::Maker2UserSweep, txid: sweep_tx.txid() });
+
+                        Some(sweep_tx.txid())
+                    },
+                    None => None,
+                };
+
+                UserPayout::OnChain { desc: Box::new(maker2user_desc), txid: maker2user_txid, sweep }
+            },
+        };
+
+        Ok::<_, JoinSwapError>(payout)
+    };
+    let payout = match payout.await {
+        Ok(payout) => payout,
+        Err(e) => {
+            let mut txs = refunds_final.into_iter().map(Psbt::extract_tx);
+            let tx = Box::new(txs.next().expect("There is always the refund at the base fee"));
+
+            UserPayout::Refund { tx, ladder: txs.collect(), reason: e.to_string() }
+        },
+    };
+
+    // Send users2maker contract key (with old ID), only if we got paid
+    let hand_over = !matches!(payout, UserPayout::Refund { .. });
+    #[cfg(feature = "adversarial")]
+    let hand_over = hand_over && config.adversarial != Some(Scenario::WithheldContractKey);
+    if hand_over {
+        record.handed_over.push(pub_key1);
+        checkpoint(state_dir.as_deref(), record)?;
+        send_prv_key(&prv_key1, &mut old_id.writer).await?;
+        events.emit(ProtocolEvent::MessageSent(MessageKind::ContractKey));
+        events.emit(ProtocolEvent::HandoverComplete);
+    }
+
+    let refund_amount = refund_psbts[0].unsigned_tx.output.iter()
+        .filter(|txout| txout.script_pubkey == refund.script_pubkey())
+        .map(|txout| txout.value)
+        .sum();
+
+    Ok(UserSwapReport {
+        users2maker_desc,
+        hash,
+        payout,
+        funding_txid,
+        refund_txid,
+        contributed: my_funds.contribution(),
+        refund_amount,
+        funding_fee: psbt_fee(&funding_psbt).unwrap_or_default(),
+        refund_fee: psbt_fee(&refund_psbts[0]).unwrap_or_default(),
+    })
+}
+
+// The maker waits for the hashlock keys of every user first, so this can take a while
+async fn read_preimage_and_prv_key<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut FramedReader<R>,
+    writer: &mut FramedWriter<W>,
+    network: Network,
+    keepalive: Keepalive,
+) -> Result<([u8; 32], PrivateKey), ProtocolError> {
+    let PreimageHandover { preimage, key } = expect_msg_alive(reader, writer, keepalive).await?;
+    let preimage = parse_hex32(&preimage, "preimage")?;
+    let prv_key = parse_prv_key(&key, network)?;
+
+    Ok((preimage, prv_key))
+}
+
+// Time we give the maker to notice our connection dropped, before we resume on a new one
+const RESUME_DELAY: Duration = Duration::from_secs(1);
+
+// The first leg connection from the moment we signed the refund until the funding tx is finalized.
+// If it drops meanwhile we connect again with the resume token of the session and send our last
+// PSBT again, and the maker answers where we left it.
+// What the maker sends us once we signed the funding tx, see `SigningSession::read_update`
+enum MakerUpdate {
+    Finalized(Psbt),
+    InputAdded(Box<InputAdded>),
+}
+
+struct SigningSession<'a, T: Transport> {
+    transport: &'a T,
+    // Our hello with the resume token
+    hello: Hello,
+    framing: Framing,
+    timeouts: ReadTimeouts,
+    // Our timelock path key, which signs on the resumed connections too
+    signing_key: secp256k1::SecretKey,
+    // The keys the maker may sign with, as on the first connection
+    maker_keys: [PublicKey; 3],
+    last_sent: Psbt,
+    // Txids of the finalized PSBTs we got, which the maker sends again if it thinks we missed them
+    received: Vec<Txid>,
+    resumes_left: u32,
+    events: &'a EventSink,
+}
+
+impl<T: Transport> SigningSession<'_, T> {
+    async fn send_signed(&mut self, conn: &mut Connection<T::Stream>, psbt: &Psbt) -> Result<(), ProtocolError> {
+        self.last_sent = psbt.clone();
+
+        match send_signed_psbt(psbt, &mut conn.writer).await {
+            Err(e) if e.is_disconnect() => {
+                // The maker may have told us why it hung up before we wrote
+                if let Err(e @ ProtocolError::Rejected { .. }) =
+                    with_timeout(RESUME_DELAY, read_signed_psbt(&mut conn.reader)).await
+                {
+                    return Err(e);
+                }
+                self.resume(conn, e).await
+            },
+            sent => sent,
+        }
+    }
+
+    // The `held` PSBT finalized by the maker
+    async fn read_finalized(&mut self, conn: &mut Connection<T::Stream>, held: &Psbt) -> Result<Psbt, ProtocolError> {
+        match self.read_update(conn, held, false).await? {
+            MakerUpdate::Finalized(psbt) => Ok(psbt),
+            MakerUpdate::InputAdded(_) => unreachable!("Added inputs are only read with `ANYONE_CAN_PAY`"),
+        }
+    }
+
+    // Like `read_finalized`, but the maker may send the funding tx with the inputs users added instead if
+    // `inputs_added`
+    async fn read_update(
+        &mut self,
+        conn: &mut Connection<T::Stream>,
+        held: &Psbt,
+        inputs_added: bool,
+    ) -> Result<MakerUpdate, ProtocolError> {
+        loop {
+            let reader = &mut conn.reader;
+            let update = match inputs_added {
+                true => with_timeout(self.timeouts.signing, read_funding_update(reader)).await,
+                false => with_timeout(self.timeouts.signing, read_signed_psbt(reader)).await.map(FundingUpdate::Signed),
+            };
+            let update = match update {
+                Err(e) if e.is_disconnect() => {
+                    self.resume(conn, e).await?;
+                    continue;
+                },
+                update => update?,
+            };
+            signing::check_signer(&conn.reader, &self.maker_keys)?;
+
+            let signed = match update {
+                FundingUpdate::Signed(signed) => signed,
+                FundingUpdate::InputAdded(added) => return Ok(MakerUpdate::InputAdded(added)),
+                FundingUpdate::AddInput(_) => {
+                    return Err(ProtocolError::Unexpected { expected: SignedPsbt::NAME, got: AddInput::NAME });
+                },
+            };
+            let txid = signed.txid()?;
+            if self.received.contains(&txid) {
+                continue;
+            }
+            let psbt = signed.into_psbt(held)?;
+            self.received.push(txid);
+            return Ok(MakerUpdate::Finalized(psbt));
+        }
+    }
+
+    // Signs the refunds spending the funding tx with the inputs users added, see `check_input_added`. The
+    // maker finalizes them as it did the first ones.
+    async fn sign_refunds_again(
+        &mut self,
+        conn: &mut Connection<T::Stream>,
+        prv_wallet: &Wallet<MemoryDatabase>,
+        funding: &Psbt,
+        refunds: &mut [Psbt],
+    ) -> Result<Vec<Psbt>, ProtocolError> {
+        let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
+        for refund in refunds.iter_mut() {
+            sign_peer_psbt(refund, prv_wallet, sign_ops.clone())?;
+        }
+        self.send_signed(conn, &refunds[0]).await?;
+        self.events.emit(ProtocolEvent::MessageSent(MessageKind::SignedRefund));
+
+        self.finalize_refunds(conn, refunds, funding).await
+    }
+
+    // The `refunds` finalized by the maker, who finalizes each before it takes our signature of the next.
+    // We already sent the first.
+    async fn finalize_refunds(
+        &mut self,
+        conn: &mut Connection<T::Stream>,
+        refunds: &[Psbt],
+        funding: &Psbt,
+    ) -> Result<Vec<Psbt>, ProtocolError> {
+        let mut finalized = Vec::new();
+        for (index, refund) in refunds.iter().enumerate() {
+            if index > 0 {
+                self.send_signed(conn, refund).await?;
+                self.events.emit(ProtocolEvent::MessageSent(MessageKind::SignedRefund));
+            }
+            let refund_final = self.read_finalized(conn, refund).await?;
+            finalized.push(check_refund_final(refund_final, funding)?);
+            self.events.emit(ProtocolEvent::MessageReceived(MessageKind::FinalizedRefund));
+        }
+
+        Ok(finalized)
+    }
+
+    // Replaces `conn` with a new connection resuming its session, where we send our last PSBT again.
+    // Once out of attempts we fail with `dropped`, what made us resume.
+    async fn resume(&mut self, conn: &mut Connection<T::Stream>, dropped: ProtocolError) -> Result<(), ProtocolError> {
+        // The maker must see the old connection closed before it takes the new one
+        let _ = conn.writer.shutdown().await;
+
+        let mut failure = dropped;
+        while self.resumes_left > 0 {
+            self.resumes_left -= 1;
+            tokio::time::sleep(RESUME_DELAY).await;
+
+            match self.reconnect().await {
+                Ok(resumed) => {
+                    *conn = resumed;
+                    self.events.emit(ProtocolEvent::SessionResumed { index: None });
+                    return Ok(());
+                },
+                // The maker won't take us back
+                Err(e @ ProtocolError::Rejected { .. }) => return Err(e),
+                Err(e) => failure = e,
+            }
+        }
+        Err(failure)
+    }
+
+    async fn reconnect(&self) -> Result<Connection<T::Stream>, ProtocolError> {
+        let mut conn = Connection::new(self.transport.connect().await?, self.framing);
+        say_hello(&self.hello, &mut conn, self.transport.maker_key(), self.timeouts.exchange).await?;
+        conn.writer.sign_with(self.signing_key);
+        send_signed_psbt(&self.last_sent, &mut conn.writer).await?;
+
+        Ok(conn)
+    }
+}
+
+// A funding bump must replace the funding tx we signed: spend every input of it and pay at least 1 sat/vB
+// of its own size more, as nodes only relay such replacements (BIP125). Past that its PSBTs must pass the
+// checks the first ones did, at the new funding feerate.
+fn check_funding_bump(
+    bump: FundingBump,
+    replaced: &Psbt,
+    desc: &Descriptor<PublicKey>,
+    my_funds: &MyFunds,
+    refund_addr: &Address,
+    refund_addrs: &[Address],
+    terms: PsbtTerms,
+) -> Result<(Psbt, Vec<Psbt>), JoinSwapError> {
+    let FundingBump { fee_rate, funding, refund, refund_ladder } = bump;
+    let contract_rates = FeeRates { funding: fee_rate, ..terms.contract_rates };
+    contract_rates.check(&terms.fee_rates).map_err(ProtocolError::Incompatible)?;
+    let funding = funding.decode().map_err(ProtocolError::Psbt)?;
+    let refunds = decode_refunds(refund, refund_ladder)?;
+
+    let mut failures = replacement_failures(replaced, &funding);
+    let terms = PsbtTerms { contract_rates, ..terms };
+    if let Err(more) = check_psbts(&funding, &refunds, desc, my_funds, refund_addr, refund_addrs, terms) {
+        failures.extend(more);
+    }
+
+    match failures.is_empty() {
+        true => Ok((funding, refunds)),
+        false => Err(JoinSwapError::PsbtChecks(failures)),
+    }
+}
+
+// The funding tx with the inputs users added must be the one we `held` with inputs appended (see
+// `check_added_inputs`), ours among them if we `proposed` one, and the refunds our `refunds` spending it
+fn check_input_added(
+    added: InputAdded,
+    held: &Psbt,
+    refunds: &[Psbt],
+    proposed: Option<OutPoint>,
+) -> Result<(Psbt, Vec<Psbt>), ProtocolError> {
+    let InputAdded { funding, refund, refund_ladder } = added;
+    let funding = funding.decode()?;
+    let received = decode_refunds(refund, refund_ladder)?;
+
+    let added = check_added_inputs(held, &funding)?;
+    if let Some(outpoint) = proposed.filter(|outpoint| !added.iter().any(|(added, _)| added == outpoint)) {
+        return Err(AddInputError::NotProposed(outpoint).into());
+    }
+    let refunds: Vec<_> = refunds.iter().map(|refund| rebase_refund(refund, &funding.unsigned_tx)).collect();
+    let same = received.len() == refunds.len()
+        && received.iter().zip(&refunds).all(|(received, rebased)| received.unsigned_tx == rebased.unsigned_tx);
+    if !same {
+        return Err(AddInputError::RefundsChanged.into());
+    }
+    Ok((funding, refunds))
+}
+
+// Once users added inputs to the funding tx, the one we had can't be completed without their signatures,
+// so the refunds spending it are replaced rather than kept
+fn record_inputs_added(record: &mut UserRecovery, funding: &Psbt, refunds_final: &[Psbt]) {
+    record.funding_txid = funding.unsigned_tx.txid();
+    record.refund_txid = refunds_final[0].unsigned_tx.txid();
+    record.refund_psbt = refunds_final[0].to_string();
+    record.refund_ladder = ladder_refunds(refunds_final);
+}
+
+// The pricier refunds as we keep them in our recovery file
+fn ladder_refunds(refunds_final: &[Psbt]) -> Vec<LadderRefund> {
+    refunds_final[1..].iter()
+        .map(|psbt| LadderRefund {
+            txid: psbt.unsigned_tx.txid(),
+            fee: psbt_fee(psbt).unwrap_or_default(),
+            psbt: psbt.to_string(),
+        })
+        .collect()
+}
+
+// The refund tx is all we have if the swap fails, so it must be fully signed and spend the actual
+// funding output. We finalize it if the maker didn't.
+fn check_refund_final(mut refund: Psbt, funding: &Psbt) -> Result<Psbt, PsbtReadError> {
+    let invalid = |reason: String| PsbtReadError::InvalidInput { input: 0, reason };
+
+    let input = refund.inputs.get_mut(0).ok_or_else(|| invalid("Missing PSBT input".to_string()))?;
+    // Don't trust the maker with the spent value and script
+    let prevout = refund.unsigned_tx.input.first().map(|txin| txin.previous_output);
+    input.witness_utxo = prevout
+        .filter(|prevout| prevout.txid == funding.unsigned_tx.txid())
+        .and_then(|prevout| funding.unsigned_tx.output.get(prevout.vout as usize))
+        .cloned();
+
+    if input.final_script_witness.is_none() {
+        refund.finalize_inp_mut(&Secp256k1::verification_only(), 0).map_err(|e| invalid(e.to_string()))?;
+    }
+    verify_finalized_input(&refund, 0).map_err(invalid)?;
+
+    Ok(refund)
+}
+
+// We speak first: the Noise handshake, which fails unless the peer holds `maker_key`, and then our
+// hello. The maker answers with its own hello or drops us if it can't talk to us. Each connection
+// gets a new nonce, so our two identities don't share one.
+async fn say_hello<S: AsyncRead + AsyncWrite>(
+    hello: &Hello,
+    conn: &mut Connection<S>,
+    maker_key: Option<secp256k1::PublicKey>,
+    limit: Duration,
+) -> Result<(), ProtocolError> {
+    match maker_key {
+        Some(key) => with_timeout(limit, noise::initiate(conn, &key)).await?,
+        // The transport already encrypts the stream
+        None => conn.writer.assume_encrypted_stream(),
+    }
+    let hello = Hello { nonce: signing::fresh_nonce(), ..hello.clone() };
+    send_msg(&hello.clone().into(), &mut conn.writer).await?;
+
+    let theirs = with_timeout(limit, expect_msg(&mut conn.reader)).await;
+    let session = theirs.and_then(|theirs| Ok((hello.negotiate(&theirs)?, signing::session_nonce(&hello, &theirs)?)));
+    let (negotiated, session) = check_maker(session, &mut conn.writer).await?;
+    conn.writer.set_psbt_encoding(negotiated.psbt_encoding);
+    conn.writer.set_sig_bundles(negotiated.sig_bundles);
+    conn.writer.set_partial_spends(negotiated.partial_spends);
+    conn.writer.set_funding_bumps(negotiated.funding_bumps);
+    conn.writer.set_anyone_can_pay(negotiated.anyone_can_pay);
+    conn.start_session(session);
+    Ok(())
+}
+
+// A maker that sent us something invalid is told why we leave the swap
+async fn check_maker<T, W: AsyncWrite + Unpin>(
+    result: Result<T, ProtocolError>,
+    writer: &mut FramedWriter<W>,
+) -> Result<T, ProtocolError> {
+    if let Err(error) = &result {
+        if error.is_peer_fault() {
+            let _ = send_error(error.abort_code(), error, writer).await;
+        }
+    }
+    result
+}
+
+// Like `check_maker`, until we sign the funding tx: we decline the round, so the other users can go on
+async fn check_round<T, W: AsyncWrite + Unpin>(
+    result: Result<T, ProtocolError>,
+    writer: &mut FramedWriter<W>,
+) -> Result<T, ProtocolError> {
+    if let Err(error) = &result {
+        if error.is_peer_fault() {
+            let _ = send_decline(error, writer).await;
+        }
+    }
+    result
+}
+
+// Saves `record` to `state_dir`, over the one saved before in the session. The other users of the
+// round share its txids, so the file is named after our timelock path key.
+fn checkpoint(state_dir: Option<&Path>, record: &UserRecovery) -> Result<Option<PathBuf>, JoinSwapError> {
+    let Some(dir) = state_dir else {
+        return Ok(None);
+    };
+    let name = format!("user-session-{}", record.contract_keys[1].public_key(&Secp256k1::new()));
+
+    save_state(dir, &name, &Recovery::User(Box::new(record.clone()))).map(Some).map_err(JoinSwapError::Recovery)
+}
+
+async fn send_prv_key<W: AsyncWrite + Unpin>(key: &PrivateKey, writer: &mut FramedWriter<W>) -> Result<(), ProtocolError> {
+    send_msg(&PrivKeyHandover { key: key.to_string() }.into(), writer).await
+}
+
+enum SecondLeg {
+    // The checksum of the contract descriptor as the maker built it, and the timelock it built it with
+    OnChain { maker_keys: (PublicKey, PublicKey), hash: PayoutHash, timelock: u16, checksum: String, txid: Txid },
+    // Preimage of our paid invoice
+    Lightning([u8; 32]),
+}
+
+// Either the preimage of our paid invoice or the maker2user contract data, which must pay our `payout`.
+// Its payout hash must be new to us, neither the `round_hash` nor one in our `hash_history`, and its
+// timelock must leave us the `claim_window` after our `refund_timelock`.
+// The maker waits for the other users to reconnect first, so this can take a while
+async fn read_second_contract_data<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut FramedReader<R>,
+    writer: &mut FramedWriter<W>,
+    keepalive: Keepalive,
+    (payout, refund_timelock, claim_window): (u64, u16, u16),
+    (round_hash, hash_history): (sha256::Hash, Option<&HashSet<sha256::Hash>>),
+    payment_hash: Option<sha256::Hash>,
+) -> Result<SecondLeg, ProtocolError> {
+    let second_leg = match read_msg_alive(reader, writer, MAX_MESSAGE_SIZE, keepalive).await? {
+        // Only if we sent an invoice, and the preimage must prove it was paid
+        Message::LightningPayout(LightningPayout { preimage }) => {
+            let Some(payment_hash) = payment_hash else {
+                let (expected, got) = (SecondContractData::NAME, LightningPayout::NAME);
+                return Err(ProtocolError::Unexpected { expected, got });
+            };
+            let preimage = parse_hex32(&preimage, "invoice preimage")?;
+            if sha256::Hash::hash(&preimage) != payment_hash {
+                let reason = format!("It isn't that of our payment hash {payment_hash}");
+                return Err(ProtocolError::Malformed { field: "invoice preimage", reason });
+            }
+            SecondLeg::Lightning(preimage)
+        },
+        // Repeated keys are caught when building the maker2user contract descriptor
+        Message::SecondContractData(contract) => {
+            let SecondContractData { keys, hash, hash_kind, checksum, txid, amount, timelock } = contract;
+            let maker_keys = parse_contract_keys(&keys, 2)?;
+            signing::check_signer(reader, &maker_keys)?;
+
+            if amount != payout {
+                let reason = format!("Pays {amount} sats, we were promised {payout}");
+                return Err(ProtocolError::Malformed { field: "maker2user amount", reason });
+            }
+
+            // A spend with the payout preimage would show a hash other contracts have
+            let malformed = |reason| ProtocolError::Malformed { field: "maker2user hash", reason };
+            let payout_hash = PayoutHash::parse(hash_kind.unwrap_or_default(), &hash).map_err(malformed)?;
+            if let PayoutHash::Sha256(payout_hash) = payout_hash {
+                if payout_hash == round_hash || hash_history.is_some_and(|history| history.contains(&payout_hash)) {
+                    return Err(malformed(format!("{payout_hash} is the round hash or one we used before")));
+                }
+            }
+
+            validate_timelock_schedule(refund_timelock, timelock, claim_window)
+                .map_err(|reason| ProtocolError::Malformed { field: "maker2user timelock", reason })?;
+
+            check_hex32(&txid, "maker2user txid")?;
+            let txid = Txid::from_str(&txid)
+                .map_err(|e| ProtocolError::Malformed { field: "maker2user txid", reason: e.to_string() })?;
+
+            let maker_keys = (maker_keys[0], maker_keys[1]);
+            SecondLeg::OnChain { maker_keys, hash: payout_hash, timelock, checksum, txid }
+        },
+        other => {
+            let expected = "second contract data or lightning payout";
+            return Err(ProtocolError::Unexpected { expected, got: other.name() });
+        },
+    };
+    Ok(second_leg)
+}
+
+fn parse_hash(hex: &str, field: &'static str) -> Result<sha256::Hash, ProtocolError> {
+    check_hex32(hex, field)?;
+    sha256::Hash::from_str(hex).map_err(|e| ProtocolError::Malformed { field, reason: e.to_string() })
+}
+
+fn parse_hex32(hex: &str, field: &'static str) -> Result<[u8; 32], ProtocolError> {
+    check_hex32(hex, field)?;
+
+    Ok(<[u8; 32]>::from_hex(hex).expect("Checked to be 64 hex characters"))
+}
+
+// Our maker2user contract keys, and the payout we were promised on the first leg and how we take it
+async fn send_second_user_data<W: AsyncWrite + Unpin>(
+    keys: &[PublicKey; 3],
+    payout: &PayoutRequest,
+    amount: u64,
+    writer: &mut FramedWriter<W>,
+) -> Result<(), ProtocolError> {
+    let keys = keys.iter().map(PublicKey::to_string).collect();
+
+    send_msg(&UserKeys { keys }.into(), writer).await?;
+    send_msg(&PayoutRequestData { request: payout.to_string(), amount }.into(), writer).await
+}
+
+async fn send_user_data<D: BatchDatabase, W: AsyncWrite + Unpin>(
+    wallet: &Wallet<D>,
+    keys: &[PublicKey; 3],
+    utxo_data: UtxoData,
+    hash_source: HashSource,
+    writer: &mut FramedWriter<W>,
+) -> Result<Address, ProtocolError> {
+    let keys = keys.iter().map(PublicKey::to_string).collect();
+    send_msg(&UserKeys { keys }.into(), writer).await?;
+    send_msg(&utxo_data.into(), writer).await?;
+    let refund = wallet.get_address(AddressIndex::New).unwrap().address;
+    send_msg(&RefundAddress { address: refund.to_string() }.into(), writer).await?;
+    send_msg(&HashSourceData { source: hash_source.to_string() }.into(), writer).await?;
+
+    Ok(refund)
+}
+
+// Sent to the maker when we leave because our round didn't fill up within `max_wait`
+const GAVE_UP_WAITING: &str = "Waited too long for the round to fill up";
+
+// The contract data the maker sends once the round has all its users, reporting how many it lacks
+// until then
+async fn wait_for_round<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut FramedReader<R>,
+    writer: &mut FramedWriter<W>,
+    keepalive: Keepalive,
+    events: &EventSink,
+) -> Result<ContractData, ProtocolError> {
+    loop {
+        match read_msg_alive(reader, writer, ContractData::MAX_SIZE, keepalive).await? {
+            Message::Waiting(Waiting { missing }) => events.emit(ProtocolEvent::WaitingForUsers { missing }),
+            message => {
+                return ContractData::from_message(message)
+                    .map_err(|got| ProtocolError::Unexpected { expected: ContractData::NAME, got: got.name() });
+            },
+        }
+    }
+}
+
+// The round we take part in once we hold its finalized refund tx
+struct JoinedRound<'a, T: Transport> {
+    // In rounds of equal amounts our contribution is the denomination
+    my_funds: MyFunds,
+    hash: sha256::Hash,
+    timelocks: Timelocks,
+    round_payout: u64,
+    users2maker_desc: Descriptor<PublicKey>,
+    funding_psbt: Psbt,
+    // The refund at the base fee first, then its pricier variants
+    refund_psbts: Vec<Psbt>,
+    refunds_final: Vec<Psbt>,
+    // What the PSBTs were checked against, a funding bump is checked the same way
+    terms: PsbtTerms,
+    participant_addrs: Vec<Address>,
+    signing: SigningSession<'a, T>,
+}
+
+// The users2maker contract the maker proposes
+struct Contract {
+    // The contract keys of each user and the maker's last
+    keys: Vec<[PublicKey; 3]>,
+    hash: sha256::Hash,
+    // Of the contract descriptor as the maker built it
+    checksum: String,
+    // The refund address of each user
+    refund_addrs: Vec<Address>,
+    // Only if the maker puts a utxo of its own in the contract
+    maker_refund_addr: Option<Address>,
+    funding: Psbt,
+    // The refund at the base fee first, then its pricier variants
+    refunds: Vec<Psbt>,
+    payout: u64,
+    // Only in rounds of equal amounts
+    denomination: Option<Denomination>,
+    thresholds: PathThresholds,
+    timelocks: Timelocks,
+    // What the maker says the funding and refund txs pay
+    fee_rates: FeeRates,
+}
+
+// Checks the contract data of the maker. The round size comes from the keys, which must be for the `round_users`
+// we agreed on in the hello, and the timelocks must be ones the maker `offer`ed.
+fn check_contract_data<R: AsyncBufRead + Unpin>(
+    data: ContractData,
+    reader: &FramedReader<R>,
+    config: &UserConfig,
+    offer: &Offer,
+    offered: u64,
+) -> Result<Contract, ProtocolError> {
+    let ContractData {
+        keys, hash, checksum, refund_addresses, funding, refund, refund_ladder,
+        payout, denomination, thresholds, timelocks, fee_rates, maker_refund_address,
+    } = data;
+    let (network, round_users) = (config.network, config.round_users);
+    let users = (keys.len() / 3).saturating_sub(1);
+    if keys.len() % 3 != 0 || !(MIN_ROUND_USERS..=MAX_ROUND_USERS).contains(&users) {
+        let reason = format!("Got {} keys, not a triplet for each user and the maker", keys.len());
+        return Err(ProtocolError::Malformed { field: "contract keys", reason });
+    }
+    if users != round_users {
+        let reason = format!("The contract is for {users} users, the round has {round_users}");
+        return Err(ProtocolError::Malformed { field: "contract keys", reason });
+    }
+    let keys = contract_keys_by_participant(&parse_contract_keys(&keys, keys.len() as u8)?);
+    // The last triplet of keys is the maker's
+    signing::check_signer(reader, keys.last().expect("Checked to have the maker keys"))?;
+
+    let hash = parse_hash(&hash, "contract hash")?;
+
+    let malformed = |reason| ProtocolError::Malformed { field: "refund addresses", reason };
+    if refund_addresses.len() != users {
+        return Err(malformed(format!("Got {} addresses instead of {users}", refund_addresses.len())));
+    }
+    let parse_addr = |addr: &String| {
+        let addr = Address::from_str(addr).map_err(|e| malformed(e.to_string()))?;
+
+        if !addr.is_valid_for_network(network) {
+            return Err(malformed(format!("{addr} is not for {network}")));
+        }
+        Ok(addr)
+    };
+    let refund_addrs = refund_addresses.iter().map(parse_addr).collect::<Result<_, _>>()?;
+    let maker_refund_addr = maker_refund_address.as_ref().map(parse_addr).transpose()?;
+
+    // We put in at most what we offered, and not less than we would on our own
+    let malformed = |reason| ProtocolError::Malformed { field: "denomination", reason };
+    match denomination {
+        Some(_) if !config.denominated => return Err(malformed("Our round isn't of equal amounts".to_string())),
+        None if config.denominated => return Err(malformed("Missing in a round of equal amounts".to_string())),
+        Some(Denomination { amount, .. }) if amount > offered => {
+            return Err(malformed(format!("{amount} sats, we offered to put in {offered}")));
+        },
+        Some(Denomination { amount, .. }) if amount < config.utxo_values.min_value() => {
+            return Err(malformed(format!("{amount} sats, the least we put in is {}", config.utxo_values.min_value())));
+        },
+        _ => {},
+    }
+
+    // Paths that don't need every participant let some spend without the rest, so we only take those we
+    // accept
+    let participants = keys.len();
+    let thresholds = thresholds.unwrap_or(PathThresholds::all(participants));
+    let lowest = config.lowest_thresholds.unwrap_or(PathThresholds::all(participants));
+    if !thresholds.at_least(&lowest) {
+        let reason = format!("The contract paths need {thresholds} participants, we accept at least {lowest}");
+        return Err(ProtocolError::Incompatible(reason));
+    }
+
+    let advertised = offer.refund_timelocks.contains(timelocks.refund)
+        && offer.maker2user_timelocks.contains(timelocks.maker2user);
+    if !advertised {
+        let reason = format!("The contracts have timelocks ({timelocks}) the maker didn't offer");
+        return Err(ProtocolError::Malformed { field: "timelocks", reason });
+    }
+    config.timelock_bounds.check(&timelocks).map_err(ProtocolError::Incompatible)?;
+    fee_rates.check(&config.fee_rates).map_err(ProtocolError::Incompatible)?;
+    let refunds = decode_refunds(refund, refund_ladder)?;
+
+    Ok(Contract {
+        keys,
+        hash,
+        checksum,
+        refund_addrs,
+        maker_refund_addr,
+        funding: funding.decode()?,
+        refunds,
+        payout,
+        denomination,
+        thresholds,
+        timelocks,
+        fee_rates,
+    })
+}
+
+// The refund and its pricier variants, at most one per `REFUND_LADDER` multiple
+fn decode_refunds(refund: WirePsbt, ladder: Vec<WirePsbt>) -> Result<Vec<Psbt>, ProtocolError> {
+    if ladder.len() > REFUND_LADDER.len() {
+        let reason = format!("Got {} variants, there are at most {}", ladder.len(), REFUND_LADDER.len());
+        return Err(ProtocolError::Malformed { field: "refund ladder", reason });
+    }
+    let refunds = std::iter::once(refund).chain(ladder).map(WirePsbt::decode).collect::<Result<_, _>>()?;
+
+    Ok(refunds)
+}
+
+// The maker's terms must fit what we put in and the fee we pay for it, and its contracts must be able to
+// have timelocks we take
+fn check_offer(offer: &Offer, config: &UserConfig, amount: u64) -> Result<(), ProtocolError> {
+    let rejected = |reason| Err(ProtocolError::TermsRejected(reason));
+    let network = config.network.to_string();
+    if offer.network != network {
+        return rejected(format!("The maker is on {}, we are on {network}", offer.network));
+    }
+    if amount < offer.min_amount || amount > offer.max_amount {
+        let (min, max) = (offer.min_amount, offer.max_amount);
+        return rejected(format!("We put in {amount} sats, the maker takes {min} to {max}"));
+    }
+    if let Some(max_ppm) = config.max_fee_ppm.filter(|max_ppm| !offer.fee.within_ppm(amount, *max_ppm)) {
+        let fee = offer.fee.of(amount);
+        return rejected(format!("A fee of {fee} sats on our {amount} is over the {max_ppm} ppm we pay"));
+    }
+    // The shortest refund and longest maker2user timelocks we could get are the best for us
+    let refund = offer.refund_timelocks.min.max(config.timelock_bounds.min_refund);
+    let timelocks = Timelocks { refund, maker2user: offer.maker2user_timelocks.max };
+    if !offer.refund_timelocks.contains(refund) {
+        let (max, min_refund) = (offer.refund_timelocks.max, config.timelock_bounds.min_refund);
+        return rejected(format!("Refund timelocks of at most {max} blocks, we take {min_refund} or more"));
+    }
+    if let Err(reason) = config.timelock_bounds.check(&timelocks) {
+        return rejected(format!("The maker doesn't build contracts with timelocks we take: {reason}"));
+    }
+    Ok(())
+}
+
+// The tx `txid` once our backend sees it, if it does before `deadline`
+async fn tx_within(
+    chain: &Arc<dyn ChainAccess>,
+    txid: Txid,
+    deadline: Instant,
+) -> Result<Option<Transaction>, ChainError> {
+    loop {
+        if let Some(tx) = chain_call(chain, move |chain| chain.get_tx(&txid)).await? {
+            return Ok(Some(tx));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        tokio::time::sleep(TX_LOOKUP_POLL.min(deadline - now)).await;
+    }
+}
+
+// The confirmations of `tx`, paying `script_pubkey`, once it has `need` or at `deadline`. Each new one is
+// told to `events`.
+async fn confirmations_within(
+    chain: &Arc<dyn ChainAccess>,
+    tx: &Transaction,
+    script_pubkey: &Script,
+    need: u32,
+    deadline: Instant,
+    events: &EventSink,
+) -> Result<u32, ChainError> {
+    let mut told = 0;
+    loop {
+        let (txid, script_pubkey) = (tx.txid(), script_pubkey.clone());
+        let have = chain_call(chain, move |chain| chain.confirmations(&txid, &script_pubkey)).await?;
+        if have > told {
+            events.emit(ProtocolEvent::AwaitingConfirmation { txid: tx.txid(), have, need });
+            told = have;
+        }
+        let now = Instant::now();
+        if have >= need || now >= deadline {
+            return Ok(have);
+        }
+        tokio::time::sleep(TX_LOOKUP_POLL.min(deadline - now)).await;
+    }
+}
+
+// How often we look the maker2user tx and its confirmations up while waiting for them
+const TX_LOOKUP_POLL: Duration = Duration::from_secs(5);
+
+// The maker2user tx must pay our contract the payout of the round
+fn check_payout(tx: &Transaction, desc: &Descriptor<PublicKey>, payout: u64) -> Result<(), ProtocolError> {
+    // A single output pays it, the one we sweep
+    let reason = match contract_output(tx, &desc.script_pubkey()) {
+        Some((_, txout)) if txout.value == payout => return Ok(()),
+        Some((_, txout)) => format!("Pays {} sats to our contract, the round pays {payout}", txout.value),
+        None => "Doesn't pay to our contract in a single output".to_string(),
+    };
+    Err(ProtocolError::Malformed { field: "maker2user tx", reason })
+}
+
+// The utxos we bring and the change we want back
+fn utxo_data<D: BatchDatabase>(wallet: &Wallet<D>, funds: &MyFunds) -> UtxoData {
+    let utxos = funds.utxos.iter().map(|utxo| user_utxo(wallet, utxo)).collect();
+
+    let change = funds.change.as_ref().map(|change| ChangeRequest {
+        contribution: change.contribution,
+        address: change.address.to_string(),
+        fold_dust: change.fold_dust,
+    });
+
+    UtxoData { utxos, change }
+}
+
+fn user_utxo<D: BatchDatabase>(wallet: &Wallet<D>, utxo: &LocalUtxo) -> UserUtxo {
+    let psbt_in = wallet
+        .get_psbt_input(utxo.clone(), None, false)
+        .unwrap();
+
+    // Find the concrete descriptor of our utxo
+    let pub_desc = wallet.public_descriptor(KeychainKind::External).unwrap().unwrap();
+    let (_, index) = wallet.database().get_path_from_script_pubkey(&utxo.txout.script_pubkey).unwrap().unwrap();
+    let desc = pub_desc.derived_descriptor(&Secp256k1::new(), index).unwrap();
+
+    UserUtxo { descriptor: desc.to_string(), outpoint: utxo.outpoint.to_string(), psbt_input: psbt_in }
+}
+
+// The smallest of our spare utxos that lifts the funding tx to `rate` (see `UserConfig::top_up_rate`),
+// if it pays less, as long as the funding feerate stays within those we `accept`
+fn top_up_utxo<D: BatchDatabase>(
+    wallet: &Wallet<D>,
+    funding: &Psbt,
+    my_funds: &MyFunds,
+    rate: FeeRate,
+    accept: FeeRateRange,
+) -> Option<LocalUtxo> {
+    let fee_and_vsize = |psbt: &Psbt| {
+        let weights: Option<Vec<_>> = psbt.inputs.iter().map(max_satisfaction_weight).collect();
+        Some((psbt_fee(psbt)?, estimate_vsize(&psbt.unsigned_tx, &weights?)))
+    };
+    let (fee, vsize) = fee_and_vsize(funding)?;
+    if FeeRate::from_vb(fee, vsize) >= rate {
+        return None;
+    }
+
+    let mut spare: Vec<_> = wallet.list_unspent().ok()?.into_iter()
+        .filter(|utxo| !my_funds.utxos.iter().any(|mine| mine.outpoint == utxo.outpoint))
+        .collect();
+    spare.sort_by_key(|utxo| utxo.txout.value);
+    // A larger utxo would only pay more
+    let (utxo, fee, vsize) = spare.into_iter().find_map(|utxo| {
+        let mut topped_up = funding.clone();
+        let input = wallet.get_psbt_input(utxo.clone(), None, false).ok()?;
+        add_funding_input(&mut topped_up, utxo.outpoint, input).ok()?;
+        let (fee, vsize) = fee_and_vsize(&topped_up)?;
+        (FeeRate::from_vb(fee, vsize) >= rate).then_some((utxo, fee, vsize))
+    })?;
+    accept.contains(fee, vsize).then_some(utxo)
+}
+
+// Check that all keys are compressed and different, that my respective key appears once in its
+// policy path (and nowhere else) and that the maker isn't reusing keys from the `history` swaps
+fn check_contract_keys(
+    keys: &[[PublicKey; 3]],
+    my_key1: &PublicKey,
+    my_key2: &PublicKey,
+    my_key3: &PublicKey,
+    history: Option<&HashSet<PublicKey>>,
+) -> Result<(), ContractKeyError> {
+    let all_keys = keys.concat();
+    if let Some(key) = all_keys.iter().find(|key| !key.compressed) {
+        return Err(ContractKeyError::Uncompressed(*key));
+    }
+
+    // Taproot contracts take keys as x-only, so a key with the other y counts as the same one
+    let x_only = |key: &PublicKey| XOnlyPublicKey::from(key.inner);
+
+    // Each participant has a key in each path: multisig, timelock and hashlock
+    let my_keys = [
+        (my_key1, ContractKeyError::MultisigKeyMissing),
+        (my_key2, ContractKeyError::TimelockKeyMissing),
+        (my_key3, ContractKeyError::HashlockKeyMissing),
+    ];
+    for (path, (my_key, missing)) in my_keys.into_iter().enumerate() {
+        match keys.iter().filter(|triplet| triplet[path] == *my_key).count() {
+            0 => return Err(missing),
+            1 => {},
+            _ => return Err(ContractKeyError::DuplicateKey(*my_key)),
+        }
+        if all_keys.iter().filter(|&key| x_only(key) == x_only(my_key)).count() > 1 {
+            return Err(ContractKeyError::MultipleRoles(*my_key));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    if let Some(key) = all_keys.iter().find(|&key| !seen.insert(x_only(key))) {
+        return Err(ContractKeyError::DuplicateKey(*key));
+    }
+
+    if let Some(history) = history {
+        let history: HashSet<_> = history.iter().map(x_only).collect();
+        if let Some(key) = all_keys.iter().find(|&key| history.contains(&x_only(key))) {
+            return Err(ContractKeyError::ReusedKey(*key));
+        }
+    }
+    Ok(())
+}
+
+// What the PSBTs must keep to besides their own rules: the feerates we accept and those the maker told
+// us, the maker fee it advertised at the payout it promised for the round, the refund timelock of the
+// contract and the height of the best block our backend knows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PsbtTerms {
+    pub fee_rates: FeeRateRange,
+    pub contract_rates: FeeRates,
+    pub maker_fee: MakerFee,
+    pub payout: u64,
+    pub refund_timelock: u16,
+    pub tip: u32,
+    // Whether we agreed on `ANYONE_CAN_PAY` with the maker
+    pub anyone_can_pay: bool,
+}
+
+// How far the funding fee may be from what the feerate the maker told us pays for our vsize estimate,
+// which counts the heaviest spending path of each input
+pub const FEE_RATE_TOLERANCE: f32 = 0.25;
+
+// Check that funding and refund transactions are properly constructed, collecting every rule they
+// break:
+
+// 1. The funding tx must pay once to the spk of the contract descriptor, at any position
+// 2. The funding fee must be known, its outputs adding up to no more than its inputs. Its feerate must be
+// within our accepted range, estimated from the input satisfaction weights, and within `FEE_RATE_TOLERANCE`
+// of the one the maker told us
+// 3. Each of my utxos must be included in the inputs once
+// 4. Total input value minus funding tx fee must match the output values
+// 5. Refund tx input must only be the funding utxo
+// 6. Refund tx must spend from the relative timelocked path (actually I don't know how to do that,
+// but we can enforce the relative timelock anyway)
+// 7. Refund tx must include my address once
+// 8. Refund feerate must be within our accepted range too, and my address must receive what my
+// utxos add up to - my change - my funding fee share - my refund fee share at the feerate the maker
+// told us (see `refund_fee_share`)
+// 9. Funding tx must spend from one to `MAX_USER_UTXOS` utxos per user, and none twice
+// 10. Refund tx must have exactly one output per user
+// 11. The maker must tell us one distinct refund address per user (the number of users), mine
+// among them, and the refund tx must only pay to them
+// 12. Refund outputs plus the refund fee must add up to the contract value
+// 13. If I keep change, the funding tx must pay it once to my change address: what my utxos add up to
+// - my contribution - my funding fee share. Only if I let it, a dust change may go to the contract.
+// 14. Funding tx must only pay the contract and at most one change per user
+// 15. The payout of the round must leave the maker at most its advertised fee out of what I put in the
+// contract: what my utxos add up to - my change - my funding fee share
+// 16. Funding and refund locktimes must be block heights close to our tip (see `locktime_near_tip`).
+// A later one would keep the refund from being valid when its timelock expires.
+// 17. Each pricier refund (see `REFUND_LADDER`) must be the refund tx with every participant paying that
+// many times its refund fee share: the same inputs, version and locktime, and the same outputs for less.
+// 18. Nodes with the default policy must relay the funding tx and every refund (see `check_standardness`)
+// 19. My funding inputs must ask to be signed with SIGHASH_ALL, or SIGHASH_ALL|ANYONECANPAY if we agreed
+// on `ANYONE_CAN_PAY`
+
+// My funding fee share pays for the weight my inputs and change add to the funding tx, plus an even
+// part of the rest, as the maker splits it (see `FundingFeeSplit`). A maker putting a utxo of its own
+// in the contract counts as one more user, with its refund address among the users'. The `refunds` come
+// base first, then the pricier ones.
+pub fn check_psbts(
+    funding: &Psbt,
+    refunds: &[Psbt],
+    desc: &Descriptor<PublicKey>,
+    my_funds: &MyFunds,
+    refund_addr: &Address,
+    refund_addrs: &[Address],
+    terms: PsbtTerms,
+) -> Result<(), Vec<PsbtCheckFailure>> {
+    let PsbtTerms { fee_rates, contract_rates, maker_fee, payout, refund_timelock, tip, anyone_can_pay } = terms;
+    let (refund, ladder) = refunds.split_first().expect("There is always the refund at the base fee");
+    let mut failures = Vec::new();
+    let users = refund_addrs.len();
+
+    // 1)
+    let contract = contract_output(&funding.unsigned_tx, &desc.script_pubkey());
+    if contract.is_none() {
+        failures.push(PsbtCheckFailure::WrongContractSpk);
+    }
+
+    // 2)
+    let funding_fee = psbt_fee(funding);
+    if funding_fee.is_none() {
+        failures.push(PsbtCheckFailure::UnknownFundingFee);
+    }
+    let mut input_weights = Vec::new();
+    for (input, psbt_in) in funding.inputs.iter().enumerate() {
+        match max_satisfaction_weight(psbt_in) {
+            Some(weight) => input_weights.push(weight),
+            None => failures.push(PsbtCheckFailure::UnknownInputWeight { input }),
+        }
+    }
+    if let (Some(fee), true) = (funding_fee, input_weights.len() == funding.inputs.len()) {
+        let vsize = estimate_vsize(&funding.unsigned_tx, &input_weights);
+        if !fee_rates.contains(fee, vsize) {
+            failures.push(PsbtCheckFailure::FundingFeeRate { fee, vsize });
+        }
+        let stated = contract_rates.funding_rate().fee_vb(vsize);
+        let tolerance = (stated as f32 * FEE_RATE_TOLERANCE) as u64;
+        if fee.abs_diff(stated) > tolerance {
+            failures.push(PsbtCheckFailure::FundingFeeMismatch { fee, stated });
+        }
+    }
+
+    // for each input of the funding tx, get the prev output (OutPoint)
+    let prevouts = funding.unsigned_tx.input
+        .iter()
+        .map(|txin| txin.previous_output);
+
+    // 3)
+    for outpoint in my_funds.utxos.iter().map(|utxo| utxo.outpoint) {
+        match prevouts.clone().filter(|prevout| *prevout == outpoint).count() {
+            0 => failures.push(PsbtCheckFailure::MyUtxoMissing(outpoint)),
+            1 => {},
+            count => failures.push(PsbtCheckFailure::MyUtxoDuplicated { outpoint, count }),
+        }
+    }
+
+    // for each input, index the output of the specific tx to get the utxo value
+    let input_values: Vec<_> = funding.inputs
+        .iter()
+        .zip(prevouts)
+        .map(|(input, prevout)| {
+            let prev_tx = input.non_witness_utxo.as_ref()?;
+            prev_tx.output.get(prevout.vout as usize).map(|txout| txout.value)
+        })
+        .collect();
+    for (input, value) in input_values.iter().enumerate() {
+        if value.is_none() {
+            failures.push(PsbtCheckFailure::MissingPrevTx { input });
+        }
+    }
+
+    // 4)
+    // Outputs above the inputs were reported by 2)
+    let inputs = input_values.iter().try_fold(0u64, |sum, value| sum.checked_add((*value)?));
+    if let (Some(inputs), Some(fee), Some(output)) = (inputs, funding_fee, output_value(&funding.unsigned_tx)) {
+        if inputs.checked_sub(fee) != Some(output) {
+            failures.push(PsbtCheckFailure::BalanceMismatch { inputs, fee, output });
+        }
+    }
+
+    // 5)
+    let refund_inputs: Vec<_> = refund.unsigned_tx.input.iter().map(|txin| txin.previous_output).collect();
+    if contract.as_ref().is_some_and(|(outpoint, _)| refund_inputs != [*outpoint]) {
+        failures.push(PsbtCheckFailure::WrongRefundInputs { inputs: refund_inputs });
+    }
+
+    // 6)
+    let sequence = refund.unsigned_tx.input.first().map(|txin| txin.sequence);
+    if refund.unsigned_tx.version != 2 || sequence != Some(Sequence::from_height(refund_timelock)) {
+        failures.push(PsbtCheckFailure::WrongRefundTimelock { version: refund.unsigned_tx.version, sequence });
+    }
+
+    // 7)
+    let my_txout: Vec<_> = refund.unsigned_tx.output.iter().filter(|txout| {
+        txout.script_pubkey == refund_addr.script_pubkey()
+    }).collect();
+    if my_txout.len() != 1 {
+        failures.push(PsbtCheckFailure::RefundOutputCount { count: my_txout.len() });
+    }
+
+    let my_outpoints: Vec<_> = my_funds.utxos.iter().map(|utxo| utxo.outpoint).collect();
+    let my_change_spk = my_funds.change.as_ref().map(|change| change.address.script_pubkey());
+    // Unknown input weights were reported by 2)
+    let fee_share = funding_fee
+        .and_then(|fee| FundingFeeSplit::new(funding, &desc.script_pubkey(), fee, users))
+        .map(|split| split.share(&my_outpoints, my_change_spk.as_ref()));
+
+    // 8)
+    let refund_fee = psbt_fee(refund);
+    // The refund spends the timelocked path, but the heaviest one is a safe bound
+    let contract_weight = desc.max_satisfaction_weight().unwrap_or_default();
+    let refund_vsize = estimate_vsize(&refund.unsigned_tx, &[contract_weight]);
+    if !refund_fee.is_some_and(|fee| fee_rates.contains(fee, refund_vsize)) {
+        failures.push(PsbtCheckFailure::RefundFeeRate { fee: refund_fee, vsize: refund_vsize });
+    }
+    let my_value = my_funds.value();
+    let change_outputs: Vec<_> = funding.unsigned_tx.output.iter().filter(|txout| {
+        Some(&txout.script_pubkey) == my_change_spk.as_ref()
+    }).collect();
+    if let (Some(fee_share), Some(txout)) = (fee_share, my_txout.first()) {
+        // The refund fee is split evenly, but for the funding fee left over by rounding the shares down
+        let my_change = match change_outputs.as_slice() {
+            [change] => change.value,
+            _ => 0,
+        };
+        let refund_share = refund_fee_share(desc, refund.unsigned_tx.output.len(), contract_rates.refund_rate());
+        let expected = my_value.saturating_sub(my_change).saturating_sub(fee_share.saturating_add(refund_share));
+
+        if txout.value != expected {
+            failures.push(PsbtCheckFailure::WrongRefundAmount { expected, actual: txout.value });
+        }
+    }
+
+    // 9)
+    let funding_inputs = &funding.unsigned_tx.input;
+    if !(users..=users * MAX_USER_UTXOS).contains(&funding_inputs.len()) {
+        failures.push(PsbtCheckFailure::WrongInputCount { users, actual: funding_inputs.len() });
+    }
+    let mut seen = HashSet::new();
+    for txin in funding_inputs {
+        // Ours were already reported by 3)
+        let mine = my_funds.utxos.iter().any(|utxo| utxo.outpoint == txin.previous_output);
+        if !seen.insert(txin.previous_output) && !mine {
+            failures.push(PsbtCheckFailure::DuplicateInput(txin.previous_output));
+        }
+    }
+
+    // 10)
+    let refund_outputs = refund.unsigned_tx.output.len();
+    if refund_outputs != users {
+        failures.push(PsbtCheckFailure::WrongRefundOutputCount { expected: users, actual: refund_outputs });
+    }
+
+    // 11)
+    let refund_spks: HashSet<_> = refund_addrs.iter().map(Address::script_pubkey).collect();
+    if refund_spks.len() != users {
+        failures.push(PsbtCheckFailure::WrongRefundAddrCount { expected: users, actual: refund_spks.len() });
+    }
+    if !refund_spks.contains(&refund_addr.script_pubkey()) {
+        failures.push(PsbtCheckFailure::MyRefundAddrMissing);
+    }
+    for (output, txout) in refund.unsigned_tx.output.iter().enumerate() {
+        if !refund_spks.contains(&txout.script_pubkey) {
+            failures.push(PsbtCheckFailure::UnknownRefundOutput { output });
+        }
+    }
+
+    // 12)
+    // An unknown refund fee was reported by 8)
+    let paid = output_value(&refund.unsigned_tx).zip(refund_fee).and_then(|(outputs, fee)| outputs.checked_add(fee));
+    if let (Some(funded), Some(paid)) = (contract.as_ref().map(|(_, txout)| txout.value), paid) {
+        if paid != funded {
+            failures.push(PsbtCheckFailure::RefundValueMismatch { funded, paid });
+        }
+    }
+
+    // 13)
+    if let (Some(change), Some(fee_share)) = (&my_funds.change, fee_share) {
+        let expected = change.value(my_value, fee_share);
+
+        match (expected, change_outputs.as_slice()) {
+            (Some(expected), [txout]) if txout.value == expected => {},
+            (None, []) if change.fold_dust => {},
+            (expected, [txout]) => failures.push(PsbtCheckFailure::WrongChangeAmount {
+                expected: expected.unwrap_or_default(),
+                actual: txout.value,
+            }),
+            (_, outputs) => failures.push(PsbtCheckFailure::ChangeOutputCount { count: outputs.len() }),
+        }
+    }
+
+    // 14)
+    let funding_outputs = funding.unsigned_tx.output.len();
+    if funding_outputs > users + 1 {
+        failures.push(PsbtCheckFailure::TooManyFundingOutputs { users, actual: funding_outputs });
+    }
+
+    // 15)
+    if let Some(fee_share) = fee_share {
+        let my_change = change_outputs.iter().fold(0u64, |sum, txout| sum.saturating_add(txout.value));
+        let put_in = my_value.saturating_sub(my_change).saturating_sub(fee_share);
+        let advertised = maker_fee.of(put_in);
+
+        if put_in.saturating_sub(payout) > advertised {
+            failures.push(PsbtCheckFailure::FeeAboveOffer { put_in, payout, advertised });
+        }
+    }
+
+    // 16)
+    if !locktime_near_tip(funding.unsigned_tx.lock_time, tip) {
+        failures.push(PsbtCheckFailure::FundingLocktime { lock_time: funding.unsigned_tx.lock_time.0, tip });
+    }
+    if !locktime_near_tip(refund.unsigned_tx.lock_time, tip) {
+        failures.push(PsbtCheckFailure::RefundLocktime { lock_time: refund.unsigned_tx.lock_time.0, tip });
+    }
+
+    // 17)
+    let base_share = refund_fee_share(desc, refund.unsigned_tx.output.len(), contract_rates.refund_rate());
+    let sorted_outputs = |psbt: &Psbt, fee_increase: u64| {
+        let mut outputs: Vec<_> = psbt.unsigned_tx.output.iter()
+            .map(|txout| (txout.script_pubkey.clone(), txout.value.checked_add(fee_increase)))
+            .collect();
+        outputs.sort();
+        outputs
+    };
+    for (variant, multiple) in ladder.iter().zip(REFUND_LADDER) {
+        let (base_tx, tx) = (&refund.unsigned_tx, &variant.unsigned_tx);
+        let same_tx = (tx.version, tx.lock_time, &tx.input) == (base_tx.version, base_tx.lock_time, &base_tx.input);
+        if !same_tx || sorted_outputs(variant, base_share.saturating_mul(multiple - 1)) != sorted_outputs(refund, 0) {
+            failures.push(PsbtCheckFailure::LadderRefundMismatch { multiple });
+        }
+    }
+
+    // 18) Unknown funding input weights were reported by 2)
+    if input_weights.len() == funding.inputs.len() {
+        if let Err(e) = check_standardness(funding, &input_weights) {
+            failures.push(PsbtCheckFailure::NonstandardFunding(e));
+        }
+    }
+    for (variant, multiple) in refunds.iter().zip([1].into_iter().chain(REFUND_LADDER)) {
+        if let Err(error) = check_standardness(variant, &[contract_weight]) {
+            failures.push(PsbtCheckFailure::NonstandardRefund { multiple, error });
+        }
+    }
+
+    // 19)
+    for (input, (txin, psbt_in)) in funding.unsigned_tx.input.iter().zip(&funding.inputs).enumerate() {
+        let mine = my_funds.utxos.iter().any(|utxo| utxo.outpoint == txin.previous_output);
+        let Some(sighash) = psbt_in.sighash_type.filter(|_| mine) else { continue };
+        if sighash != EcdsaSighashType::All.into() && !(anyone_can_pay && is_anyone_can_pay(psbt_in)) {
+            failures.push(PsbtCheckFailure::MySighash { input, sighash });
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk::bitcoin::psbt;
+    use bdk::bitcoin::secp256k1::rand::rngs::StdRng;
+    use bdk::bitcoin::secp256k1::rand::{thread_rng, Rng, SeedableRng};
+    use bdk::bitcoin::secp256k1::SecretKey;
+    use bdk::bitcoin::{EcdsaSig, OutPoint, PackedLockTime, TxIn, TxOut, Witness};
+    use bdk::{Utxo, WeightedUtxo};
+
+    use super::*;
+    use crate::chain::MemoryChain;
+    use crate::error::StandardnessError;
+    use crate::{build_funding_and_refund_with, UserFunds, P2WPKH_SATISFACTION_WEIGHT};
+
+    const TIP: u32 = 1_000;
+    const REFUND_TIMELOCK: u16 = 48;
+    const RATES: FeeRates = FeeRates { funding: 2.0, refund: 2.0 };
+
+    fn key(seed: u8) -> PublicKey {
+        let secret = SecretKey::from_slice(&[seed; 32]).unwrap();
+        PrivateKey::new(secret, Network::Regtest).public_key(&Secp256k1::new())
+    }
+
+    fn address(seed: u8) -> Address {
+        Address::p2wpkh(&key(seed), Network::Regtest).unwrap()
+    }
+
+    // A p2wpkh utxo of `value`, as we and the maker see it
+    fn utxo(seed: u8, value: u64) -> (LocalUtxo, WeightedUtxo) {
+        let txout = TxOut { value, script_pubkey: address(seed).script_pubkey() };
+        let prev_tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![txout.clone()],
+        };
+        let outpoint = OutPoint { txid: prev_tx.txid(), vout: 0 };
+        let psbt_input = psbt::Input {
+            witness_utxo: Some(txout.clone()),
+            non_witness_utxo: Some(prev_tx),
+            ..Default::default()
+        };
+        let local = LocalUtxo { outpoint, txout, keychain: KeychainKind::External, is_spent: false };
+        let weighted = WeightedUtxo {
+            satisfaction_weight: P2WPKH_SATISFACTION_WEIGHT,
+            utxo: Utxo::Foreign { outpoint, psbt_input: Box::new(psbt_input) },
+        };
+        (local, weighted)
+    }
+
+    // A round of three users, where we keep change and the others put their utxos in whole
+    struct Round {
+        funding: Psbt,
+        refunds: Vec<Psbt>,
+        desc: Descriptor<PublicKey>,
+        my_funds: MyFunds,
+        refund_addr: Address,
+        refund_addrs: Vec<Address>,
+        terms: PsbtTerms,
+    }
+
+    impl Round {
+        fn new() -> Self {
+            Round::shuffled_by(&mut thread_rng())
+        }
+
+        // With the funding outputs and locktime drawn from `rng`
+        fn shuffled_by(rng: &mut impl Rng) -> Self {
+            let keys: Vec<_> = (0..4).map(|participant| [1, 2, 3].map(|path| key(10 * participant + path))).collect();
+            let hash = sha256::Hash::hash(&[0; 32]);
+            let thresholds = PathThresholds::all(keys.len());
+            let desc = users2maker_contract_desc(&keys, hash, thresholds, REFUND_TIMELOCK).unwrap();
+
+            let change = Change { contribution: 50_000, address: address(100), fold_dust: false };
+            let (my_utxo, my_weighted) = utxo(101, 80_000);
+            let mut users = vec![UserFunds { utxos: vec![my_weighted], change: Some(change.clone()) }];
+            for seed in [102, 103] {
+                users.push(UserFunds { utxos: vec![utxo(seed, 50_000).1], change: None });
+            }
+            let refund_addrs: Vec<_> = [104, 105, 106].map(address).into();
+            let (funding, refunds) =
+                build_funding_and_refund_with(rng, &desc, users, refund_addrs.clone(), None, RATES, TIP).unwrap();
+
+            Round {
+                funding,
+                refunds,
+                desc,
+                my_funds: MyFunds { utxos: vec![my_utxo], change: Some(change) },
+                refund_addr: refund_addrs[0].clone(),
+                refund_addrs,
+                terms: PsbtTerms {
+                    fee_rates: FeeRateRange::default(),
+                    contract_rates: RATES,
+                    maker_fee: MakerFee { base: 5_000, ppm: 0 },
+                    payout: 47_000,
+                    refund_timelock: REFUND_TIMELOCK,
+                    tip: TIP,
+                    anyone_can_pay: false,
+                },
+            }
+        }
+
+        fn failures(&self) -> Vec<PsbtCheckFailure> {
+            let Round { funding, refunds, desc, my_funds, refund_addr, refund_addrs, terms } = self;
+            check_psbts(funding, refunds, desc, my_funds, refund_addr, refund_addrs, *terms).err().unwrap_or_default()
+        }
+
+        fn fails(&self, rule: impl Fn(&PsbtCheckFailure) -> bool) -> bool {
+            self.failures().iter().any(rule)
+        }
+
+        fn contract_index(&self) -> usize {
+            let spk = self.desc.script_pubkey();
+            self.funding.unsigned_tx.output.iter().position(|txout| txout.script_pubkey == spk).unwrap()
+        }
+
+        fn my_refund_index(&self) -> usize {
+            let spk = self.refund_addr.script_pubkey();
+            self.refunds[0].unsigned_tx.output.iter().position(|txout| txout.script_pubkey == spk).unwrap()
+        }
+
+        fn my_change_index(&self) -> usize {
+            let spk = self.my_funds.change.as_ref().unwrap().address.script_pubkey();
+            self.funding.unsigned_tx.output.iter().position(|txout| txout.script_pubkey == spk).unwrap()
+        }
+
+        // Funding input spending `outpoint`
+        fn input_of(&self, outpoint: OutPoint) -> usize {
+            self.funding.unsigned_tx.input.iter().position(|txin| txin.previous_output == outpoint).unwrap()
+        }
+
+        fn duplicate_input(&mut self, input: usize) {
+            let (txin, psbt_in) = (self.funding.unsigned_tx.input[input].clone(), self.funding.inputs[input].clone());
+            self.funding.unsigned_tx.input.push(txin);
+            self.funding.inputs.push(psbt_in);
+        }
+    }
+
+    #[test]
+    fn honest_round_passes() {
+        assert_eq!(Round::new().failures(), vec![]);
+    }
+
+    #[test]
+    fn rule_1_contract_output() {
+        let mut round = Round::new();
+        let contract = round.contract_index();
+        round.funding.unsigned_tx.output[contract].script_pubkey = address(200).script_pubkey();
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::WrongContractSpk));
+    }
+
+    // The funding pays a contract of the same shape and hash, but with a key of the maker's in place of ours
+    #[test]
+    fn rule_1_forged_contract() {
+        let mut round = Round::new();
+        let contract = round.contract_index();
+        let mut keys: Vec<_> = (0..4).map(|participant| [1, 2, 3].map(|path| key(10 * participant + path))).collect();
+        keys[0][1] = key(200);
+        let hash = sha256::Hash::hash(&[0; 32]);
+        let forged = users2maker_contract_desc(&keys, hash, PathThresholds::all(4), REFUND_TIMELOCK).unwrap();
+        round.funding.unsigned_tx.output[contract].script_pubkey = forged.script_pubkey();
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::WrongContractSpk));
+    }
+
+    #[test]
+    fn rule_2_funding_fee() {
+        let mut round = Round::new();
+        round.terms.contract_rates.funding = 20.0;
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::FundingFeeMismatch { .. })));
+
+        let mut round = Round::new();
+        round.terms.fee_rates.min = FeeRate::from_sat_per_vb(10.0);
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::FundingFeeRate { .. })));
+
+        let mut round = Round::new();
+        round.funding.inputs[1].non_witness_utxo = None;
+        round.funding.inputs[1].witness_utxo = None;
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::UnknownFundingFee));
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::UnknownInputWeight { input: 1 }));
+    }
+
+    #[test]
+    fn rule_2_outputs_above_inputs() {
+        let mut round = Round::new();
+        let contract = round.contract_index();
+        round.funding.unsigned_tx.output[contract].value = 10_000_000;
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::UnknownFundingFee));
+
+        let mut round = Round::new();
+        round.funding.unsigned_tx.output[contract].value = u64::MAX;
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::UnknownFundingFee));
+    }
+
+    #[test]
+    fn rule_3_my_utxo() {
+        let mut round = Round::new();
+        let mine = round.my_funds.utxos[0].outpoint;
+        round.my_funds.utxos[0].outpoint.vout = 1;
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::MyUtxoMissing(_))));
+
+        let mut round = Round::new();
+        let input = round.input_of(mine);
+        round.duplicate_input(input);
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::MyUtxoDuplicated { outpoint: mine, count: 2 }));
+    }
+
+    #[test]
+    fn rule_3_missing_prev_tx() {
+        let mut round = Round::new();
+        round.funding.inputs[2].non_witness_utxo = None;
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::MissingPrevTx { input: 2 }));
+    }
+
+    #[test]
+    fn rule_4_balance() {
+        // The prev tx tells another value than the witness utxo the fee is computed with
+        let mut round = Round::new();
+        let prev_tx = round.funding.inputs[0].non_witness_utxo.as_mut().unwrap();
+        prev_tx.output[0].value += 1_000;
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::BalanceMismatch { .. })));
+    }
+
+    #[test]
+    fn rule_5_refund_inputs() {
+        let mut round = Round::new();
+        round.refunds[0].unsigned_tx.input[0].previous_output.vout += 1;
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::WrongRefundInputs { .. })));
+    }
+
+    #[test]
+    fn rule_6_refund_timelock() {
+        let mut round = Round::new();
+        round.refunds[0].unsigned_tx.input[0].sequence = Sequence::from_height(REFUND_TIMELOCK - 1);
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::WrongRefundTimelock { .. })));
+
+        let mut round = Round::new();
+        round.refunds[0].unsigned_tx.version = 1;
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::WrongRefundTimelock { .. })));
+    }
+
+    #[test]
+    fn rule_7_my_refund_output() {
+        let mut round = Round::new();
+        let mine = round.my_refund_index();
+        round.refunds[0].unsigned_tx.output[mine].script_pubkey = round.refund_addrs[1].script_pubkey();
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::RefundOutputCount { count: 0 }));
+    }
+
+    #[test]
+    fn rule_8_refund_fee_and_amount() {
+        let mut round = Round::new();
+        let mine = round.my_refund_index();
+        round.refunds[0].unsigned_tx.output[mine].value -= 1;
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::WrongRefundAmount { .. })));
+
+        let mut round = Round::new();
+        round.refunds[0].inputs[0].witness_utxo = None;
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::RefundFeeRate { fee: None, .. })));
+
+        let mut round = Round::new();
+        round.terms.fee_rates.max = FeeRate::from_sat_per_vb(1.5);
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::RefundFeeRate { fee: Some(_), .. })));
+    }
+
+    #[test]
+    fn rule_9_funding_inputs() {
+        let mut round = Round::new();
+        let other = round.funding.unsigned_tx.input.iter()
+            .position(|txin| txin.previous_output != round.my_funds.utxos[0].outpoint)
+            .unwrap();
+        let outpoint = round.funding.unsigned_tx.input[other].previous_output;
+        round.duplicate_input(other);
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::DuplicateInput(outpoint)));
+
+        let mut round = Round::new();
+        // Two inputs are too few for eight users
+        round.refund_addrs.extend((107..112).map(address));
+        let users = round.refund_addrs.len();
+        round.funding.unsigned_tx.input.truncate(2);
+        round.funding.inputs.truncate(2);
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::WrongInputCount { users, actual: 2 }));
+    }
+
+    #[test]
+    fn rule_10_refund_output_count() {
+        let mut round = Round::new();
+        let extra = round.refunds[0].unsigned_tx.output[0].clone();
+        round.refunds[0].unsigned_tx.output.push(extra);
+        round.refunds[0].outputs.push(Default::default());
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::WrongRefundOutputCount { expected: 3, actual: 4 }));
+    }
+
+    #[test]
+    fn rule_11_refund_addresses() {
+        let mut round = Round::new();
+        round.refund_addrs[2] = round.refund_addrs[1].clone();
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::WrongRefundAddrCount { expected: 3, actual: 2 }));
+
+        let mut round = Round::new();
+        round.refund_addrs[0] = address(200);
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::MyRefundAddrMissing));
+
+        let mut round = Round::new();
+        let other = (round.my_refund_index() + 1) % 3;
+        round.refunds[0].unsigned_tx.output[other].script_pubkey = address(200).script_pubkey();
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::UnknownRefundOutput { output: other }));
+    }
+
+    #[test]
+    fn rule_12_refund_value() {
+        let mut round = Round::new();
+        round.refunds[0].inputs[0].witness_utxo.as_mut().unwrap().value += 1_000;
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::RefundValueMismatch { .. })));
+    }
+
+    #[test]
+    fn rule_13_change() {
+        let mut round = Round::new();
+        let change = round.my_change_index();
+        round.funding.unsigned_tx.output[change].value -= 1_000;
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::WrongChangeAmount { .. })));
+
+        let mut round = Round::new();
+        let change = round.my_change_index();
+        round.funding.unsigned_tx.output[change].script_pubkey = address(200).script_pubkey();
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::ChangeOutputCount { count: 0 }));
+    }
+
+    #[test]
+    fn rule_14_funding_outputs() {
+        let mut round = Round::new();
+        for seed in 200..203 {
+            round.funding.unsigned_tx.output.push(TxOut { value: 1_000, script_pubkey: address(seed).script_pubkey() });
+            round.funding.outputs.push(Default::default());
+        }
+        let actual = round.funding.unsigned_tx.output.len();
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::TooManyFundingOutputs { users: 3, actual }));
+    }
+
+    #[test]
+    fn rule_15_maker_fee() {
+        let mut round = Round::new();
+        round.terms.payout = 40_000;
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::FeeAboveOffer { .. })));
+    }
+
+    #[test]
+    fn rule_16_locktimes() {
+        let mut round = Round::new();
+        round.funding.unsigned_tx.lock_time = PackedLockTime(TIP + 10);
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::FundingLocktime { .. })));
+
+        let mut round = Round::new();
+        round.refunds[0].unsigned_tx.lock_time = PackedLockTime(500_000_001);
+        assert!(round.fails(|failure| matches!(failure, PsbtCheckFailure::RefundLocktime { .. })));
+    }
+
+    #[test]
+    fn rule_17_ladder() {
+        let mut round = Round::new();
+        assert!(round.refunds.len() > 1, "The round is large enough for pricier refunds");
+        round.refunds[1].unsigned_tx.output[0].value -= 1;
+        let multiple = REFUND_LADDER[0];
+        assert!(round.fails(|failure| *failure == PsbtCheckFailure::LadderRefundMismatch { multiple }));
+    }
+
+    #[test]
+    fn rule_18_standardness() {
+        let mut round = Round::new();
+        let contract = round.contract_index();
+        round.funding.unsigned_tx.output[contract].value -= 1;
+        round.funding.unsigned_tx.output.push(TxOut { value: 1, script_pubkey: address(104).script_pubkey() });
+        round.funding.outputs.push(Default::default());
+        let dust = |failure: &PsbtCheckFailure| {
+            matches!(failure, PsbtCheckFailure::NonstandardFunding(StandardnessError::DustOutput { .. }))
+        };
+        assert!(round.fails(dust));
+    }
+
+    #[test]
+    fn rule_19_my_sighash() {
+        let mut round = Round::new();
+        let mine = round.input_of(round.my_funds.utxos[0].outpoint);
+        round.funding.inputs[mine].sighash_type = Some(EcdsaSighashType::AllPlusAnyoneCanPay.into());
+        let my_sighash = |failure: &PsbtCheckFailure| matches!(failure, PsbtCheckFailure::MySighash { input, .. }
+            if *input == mine);
+        assert!(round.fails(my_sighash));
+
+        // Unless we agreed on it, and only that one
+        round.terms.anyone_can_pay = true;
+        assert_eq!(round.failures(), vec![]);
+        round.funding.inputs[mine].sighash_type = Some(EcdsaSighashType::Single.into());
+        assert!(round.fails(my_sighash));
+    }
+
+    // The funding tx a user added an input to, with the refunds moved to its txid
+    fn input_added(round: &Round, seed: u8) -> (OutPoint, Psbt, Vec<Psbt>) {
+        let Utxo::Foreign { outpoint, psbt_input } = utxo(seed, 30_000).1.utxo else { unreachable!() };
+        let mut funding = round.funding.clone();
+        add_funding_input(&mut funding, outpoint, *psbt_input).unwrap();
+        let refunds = round.refunds.iter().map(|refund| rebase_refund(refund, &funding.unsigned_tx)).collect();
+        (outpoint, funding, refunds)
+    }
+
+    fn wire(funding: &Psbt, refunds: &[Psbt]) -> InputAdded {
+        InputAdded {
+            funding: WirePsbt::encode(funding, PsbtEncoding::Base64),
+            refund: WirePsbt::encode(&refunds[0], PsbtEncoding::Base64),
+            refund_ladder: refunds[1..].iter().map(|refund| WirePsbt::encode(refund, PsbtEncoding::Base64)).collect(),
+        }
+    }
+
+    // The funding tx with another user's input, or ours, is taken as long as nothing else changed
+    #[test]
+    fn added_input_must_leave_the_rest() {
+        let round = Round::new();
+        let (outpoint, funding, refunds) = input_added(&round, 107);
+        let check = |added, proposed| check_input_added(added, &round.funding, &round.refunds, proposed);
+
+        let (checked, rebased) = check(wire(&funding, &refunds), None).unwrap();
+        assert_eq!((checked.unsigned_tx, rebased.len()), (funding.unsigned_tx.clone(), round.refunds.len()));
+        assert!(check(wire(&funding, &refunds), Some(outpoint)).is_ok());
+        let (other, ..) = input_added(&round, 108);
+        assert!(matches!(
+            check(wire(&funding, &refunds), Some(other)),
+            Err(ProtocolError::AddedInput(AddInputError::NotProposed(proposed))) if proposed == other,
+        ));
+
+        // Paid for out of the contract rather than by the added input
+        let mut outputs_changed = funding.clone();
+        let contract = round.contract_index();
+        outputs_changed.unsigned_tx.output[contract].value += 30_000;
+        assert!(matches!(
+            check(wire(&outputs_changed, &refunds), None),
+            Err(ProtocolError::AddedInput(AddInputError::OutputsChanged)),
+        ));
+
+        let mut refunds_changed = refunds.clone();
+        refunds_changed[0].unsigned_tx.output[0].value -= 1;
+        assert!(matches!(
+            check(wire(&funding, &refunds_changed), None),
+            Err(ProtocolError::AddedInput(AddInputError::RefundsChanged)),
+        ));
+        assert!(matches!(
+            check(wire(&funding, &round.refunds), None),
+            Err(ProtocolError::AddedInput(AddInputError::RefundsChanged)),
+        ));
+    }
+
+    // The refund of `round` signed through the timelock path by every participant, the maker being the last
+    fn signed_refund(round: &Round) -> Psbt {
+        let mut signer = contract_wallet(&round.desc).unwrap();
+        signer.get_address(AddressIndex::New).unwrap();
+        let prv_key = |seed| PrivateKey::new(SecretKey::from_slice(&[seed; 32]).unwrap(), Network::Regtest);
+        let timelock_keys: Vec<_> = (0..4).map(|participant| prv_key(10 * participant + 2)).collect();
+        add_contract_signers(&mut signer, &round.desc, &timelock_keys);
+
+        let mut refund = round.refunds[0].clone();
+        let sign_ops = SignOptions { trust_witness_utxo: true, try_finalize: false, ..Default::default() };
+        signer.sign(&mut refund, sign_ops).unwrap();
+        assert_eq!(refund.inputs[0].partial_sigs.len(), 4);
+        refund
+    }
+
+    #[test]
+    fn corrupted_refund_signature() {
+        let round = Round::new();
+        let refund = signed_refund(&round);
+        assert!(check_refund_final(refund.clone(), &round.funding).is_ok());
+
+        // Whether we finalize it or the maker did, one byte off in its signature
+        let maker_key = key(32);
+        let mut corrupted = refund.clone();
+        let sig = corrupted.inputs[0].partial_sigs.get_mut(&maker_key).unwrap();
+        let mut bytes = sig.to_vec();
+        bytes[10] ^= 1;
+        *sig = EcdsaSig::from_slice(&bytes).unwrap();
+        let refused = check_refund_final(corrupted, &round.funding);
+        assert!(matches!(refused, Err(PsbtReadError::InvalidInput { input: 0, .. })), "{refused:?}");
+
+        let maker_sig = refund.inputs[0].partial_sigs[&maker_key].to_vec();
+        let mut finalized = refund;
+        finalized.finalize_inp_mut(&Secp256k1::verification_only(), 0).unwrap();
+        let mut elements = finalized.inputs[0].final_script_witness.take().unwrap().to_vec();
+        let maker_element = elements.iter().position(|element| *element == maker_sig).unwrap();
+        elements[maker_element][10] ^= 1;
+        finalized.inputs[0].final_script_witness = Some(Witness::from_vec(elements));
+        let refused = check_refund_final(finalized, &round.funding);
+        assert!(matches!(refused, Err(PsbtReadError::InvalidInput { input: 0, .. })), "{refused:?}");
+    }
+
+    // Funding outputs shuffled so the contract goes second pass every check, and the refund spending it signs
+    #[test]
+    fn contract_output_at_vout_1() {
+        let round = Round::shuffled_by(&mut StdRng::seed_from_u64(1));
+        assert_eq!(round.contract_index(), 1);
+        let contract = OutPoint { txid: round.funding.unsigned_tx.txid(), vout: 1 };
+        assert_eq!(round.refunds[0].unsigned_tx.input[0].previous_output, contract);
+        assert_eq!(round.failures(), vec![]);
+        assert!(check_refund_final(signed_refund(&round), &round.funding).is_ok());
+    }
+
+    // A tx paying `value` to `script_pubkey`, next to a change output
+    fn payout_tx(script_pubkey: Script, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![
+                TxOut { value: 10_000, script_pubkey: address(99).script_pubkey() },
+                TxOut { value, script_pubkey },
+            ],
+        }
+    }
+
+    #[test]
+    fn maker2user_payout() {
+        let (desc, payout) = (Round::new().desc, 50_000);
+        assert!(check_payout(&payout_tx(desc.script_pubkey(), payout), &desc, payout).is_ok());
+
+        let reason = |tx: &Transaction| match check_payout(tx, &desc, payout) {
+            Err(ProtocolError::Malformed { field: "maker2user tx", reason }) => reason,
+            other => panic!("Unexpected {other:?}"),
+        };
+        let short = payout_tx(desc.script_pubkey(), payout - 1);
+        assert_eq!(reason(&short), "Pays 49999 sats to our contract, the round pays 50000");
+        let elsewhere = payout_tx(address(98).script_pubkey(), payout);
+        assert_eq!(reason(&elsewhere), "Doesn't pay to our contract in a single output");
+        let mut twice = payout_tx(desc.script_pubkey(), payout);
+        twice.output.push(twice.output[1].clone());
+        assert_eq!(reason(&twice), "Doesn't pay to our contract in a single output");
+    }
+
+    #[tokio::test]
+    async fn maker2user_missing_or_unconfirmed() {
+        let memory = Arc::new(MemoryChain::default());
+        let chain: Arc<dyn ChainAccess> = memory.clone();
+        let spk = address(97).script_pubkey();
+        let tx = memory.get_tx(&memory.fund(&spk, &[50_000])).unwrap().unwrap();
+
+        // A tx our backend never sees isn't there at the deadline
+        let missing = payout_tx(spk.clone(), 50_000).txid();
+        assert_eq!(tx_within(&chain, missing, Instant::now()).await.unwrap(), None);
+        assert_eq!(tx_within(&chain, tx.txid(), Instant::now()).await.unwrap(), Some(tx.clone()));
+
+        // One held in the mempool has no confirmations, and one mined gets them as blocks come
+        let held = memory.get_tx(&memory.fund(&spk, &[60_000])).unwrap().unwrap();
+        memory.hold_in_mempool(held.txid());
+        let (events, mut received) = EventSink::channel();
+        assert_eq!(confirmations_within(&chain, &held, &spk, 3, Instant::now(), &events).await.unwrap(), 0);
+        assert!(received.try_recv().is_err());
+        memory.mine(2);
+        assert_eq!(confirmations_within(&chain, &held, &spk, 3, Instant::now(), &events).await.unwrap(), 0);
+        assert_eq!(confirmations_within(&chain, &tx, &spk, 3, Instant::now(), &events).await.unwrap(), 3);
+        let told = ProtocolEvent::AwaitingConfirmation { txid: tx.txid(), have: 3, need: 3 };
+        assert_eq!(received.try_recv().unwrap(), told);
+    }
+
+    // The maker's second contract data, read by us with our refund timelock of 48 blocks
+    async fn second_leg_with_timelock(timelock: u16) -> Result<SecondLeg, ProtocolError> {
+        let (maker, user) = tokio::io::duplex(1 << 16);
+        let mut maker = Connection::new(maker, Framing::default());
+        let mut user = Connection::new(user, Framing::default());
+        let session = sha256::Hash::hash(b"second leg");
+        maker.start_session(session);
+        user.start_session(session);
+        maker.writer.sign_with(SecretKey::from_slice(&[31; 32]).unwrap());
+
+        let contract = SecondContractData {
+            keys: vec![key(31).to_string(), key(32).to_string()],
+            hash: sha256::Hash::hash(b"payout").to_string(),
+            hash_kind: None,
+            checksum: "qwertyui".to_string(),
+            txid: Txid::all_zeros().to_string(),
+            amount: 50_000,
+            timelock,
+        };
+        send_msg(&contract.into(), &mut maker.writer).await.unwrap();
+        let round_hash = sha256::Hash::hash(b"round");
+        let (reader, writer, keepalive) = (&mut user.reader, &mut user.writer, Keepalive::default());
+        read_second_contract_data(reader, writer, keepalive, (50_000, 48, 12), (round_hash, None), None).await
+    }
+
+    // We take a maker2user timelock leaving us the claim window after the refund one, not one before it
+    #[tokio::test]
+    async fn maker2user_timelock_schedule() {
+        assert!(matches!(second_leg_with_timelock(69).await, Ok(SecondLeg::OnChain { timelock: 69, .. })));
+        assert!(matches!(
+            second_leg_with_timelock(36).await,
+            Err(ProtocolError::Malformed { field: "maker2user timelock", reason }) if reason.contains("less than 12"),
+        ));
+    }
+}
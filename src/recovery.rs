@@ -0,0 +1,192 @@
+// Recovery files. Before every step it can't undo, a session saves what it needs to claim its coins
+// without its peers (see `shutdown::save_state`), and `claims` reads back what a file lets us claim.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use bdk::bitcoin::{PrivateKey, PublicKey, Txid};
+use bdk::bitcoin::hashes::sha256;
+use bdk::bitcoin::secp256k1::Secp256k1;
+use serde::{Deserialize, Serialize};
+
+use crate::Timelocks;
+
+// A recovery file, of a user session or of a maker round
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "role", rename_all = "snake_case")]
+pub enum Recovery {
+    User(Box<UserRecovery>),
+    Maker(Box<MakerRecovery>),
+}
+
+// Saved by the user once it holds the finalized refund, before it signs the funding tx
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRecovery {
+    pub users2maker_desc: String,
+    // Our multisig, timelock and hashlock path keys of the users2maker contract
+    pub contract_keys: [PrivateKey; 3],
+    // Session our contract keys derive from (see `keys`), None if they are random
+    #[serde(default)]
+    pub session: Option<u32>,
+    pub funding_txid: Txid,
+    pub refund_txid: Txid,
+    // The finalized refund PSBT in base64, its tx can be broadcast once its timelock expires
+    pub refund_psbt: String,
+    // The same refund paying higher fees (see `REFUND_LADDER`), the cheapest that confirms will do
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub refund_ladder: Vec<LadderRefund>,
+    pub timelocks: Timelocks,
+    // Our maker2user contract, once the maker funded it
+    pub maker2user: Option<Maker2UserRecovery>,
+    // Preimage of the payout hash locking our maker2user contract
+    pub preimage: Option<String>,
+    // Our contract keys the maker has, each saved before we hand it over
+    pub handed_over: Vec<PublicKey>,
+    // Funding txs the maker replaced (see `FundingBump`), any of which may still confirm instead
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replaced: Vec<ReplacedFunding>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplacedFunding {
+    pub funding_txid: Txid,
+    pub refund_txid: Txid,
+    // Its finalized refund PSBT in base64
+    pub refund_psbt: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub refund_ladder: Vec<LadderRefund>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LadderRefund {
+    pub txid: Txid,
+    pub fee: u64,
+    // The finalized PSBT in base64
+    pub psbt: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Maker2UserRecovery {
+    pub desc: String,
+    pub txid: Txid,
+    // Our multisig and hashlock path keys
+    pub keys: [PrivateKey; 2],
+    // Our key of the hashlock path with the round hash, see `maker2users_contract_desc`
+    #[serde(default)]
+    pub fallback_key: Option<PrivateKey>,
+    // The maker's multisig path key, once it handed it over
+    pub maker_key: Option<PrivateKey>,
+}
+
+// Saved by the maker once the funding tx of the round is out
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MakerRecovery {
+    pub users2maker_desc: Option<String>,
+    pub hash: Option<sha256::Hash>,
+    pub preimage: Option<String>,
+    // Of the payout hash of each maker2user contract, see `maker2users_contract_desc`
+    pub payout_preimages: Vec<String>,
+    pub funding_txid: Option<Txid>,
+    pub refund_txid: Option<Txid>,
+    pub timelocks: Option<Timelocks>,
+    // Session our contract keys derive from (see `keys`), None if they are random
+    #[serde(default)]
+    pub session: Option<u32>,
+    // Our multisig path key of the users2maker contract
+    pub multisig_key: Option<PrivateKey>,
+    // The users' hashlock path keys, once they handed them over
+    pub hashlock_keys: Vec<PrivateKey>,
+    // Descriptor, our timelock path key and funding txid of each maker2user contract, each saved
+    // before we broadcast it
+    pub maker2user: Vec<(String, PrivateKey, Txid)>,
+}
+
+// Reads the recovery file at `path`
+pub fn read_recovery(path: &Path) -> io::Result<Recovery> {
+    let json = fs::read_to_string(path)?;
+
+    serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl Recovery {
+    // What the file lets us claim and when, one line per contract. Relative timelocks count the
+    // blocks since the tx funding the contract confirmed.
+    pub fn claims(&self) -> Vec<String> {
+        match self {
+            Recovery::User(record) => user_claims(record),
+            Recovery::Maker(record) => maker_claims(record),
+        }
+    }
+}
+
+fn user_claims(record: &UserRecovery) -> Vec<String> {
+    let secp = Secp256k1::new();
+    let handed_over = |key: &PrivateKey| record.handed_over.contains(&key.public_key(&secp));
+    let [multisig_key, _, hashlock_key] = &record.contract_keys;
+    let (funding, refund) = (record.funding_txid, record.refund_txid);
+    let mut claims = Vec::new();
+
+    if let Some(Maker2UserRecovery { txid, maker_key, .. }) = &record.maker2user {
+        let deadline = format!("before the maker's timelock of {} blocks after {txid}", record.timelocks.maker2user);
+        claims.push(match (maker_key, &record.preimage) {
+            (Some(_), _) => format!("Maker2user contract of {txid}: now, through the multisig path ({deadline})"),
+            (None, Some(_)) => format!("Maker2user contract of {txid}: now, through the hashlock path ({deadline})"),
+            (None, None) => format!(
+                "Maker2user contract of {txid}: through the fallback of the hashlock path once the maker claims \
+                the users2maker contract and reveals the round preimage ({deadline})",
+            ),
+        });
+    }
+    let timelock = record.timelocks.refund;
+    claims.push(match (handed_over(multisig_key), handed_over(hashlock_key)) {
+        (true, _) => format!("Refund tx {refund}: none, the maker can sweep the users2maker contract of {funding}"),
+        (false, true) => format!(
+            "Refund tx {refund}: {timelock} blocks after {funding}, unless the maker claims the users2maker \
+            contract through the hashlock path first",
+        ),
+        (false, false) => format!("Refund tx {refund}: {timelock} blocks after {funding}"),
+    });
+    claims.extend(ladder_claims(&record.refund_ladder));
+    for ReplacedFunding { funding_txid, refund_txid, refund_ladder, .. } in &record.replaced {
+        claims.push(format!("Refund tx {refund_txid}: {timelock} blocks after {funding_txid}, if that one confirmed"));
+        claims.extend(ladder_claims(refund_ladder));
+    }
+
+    claims
+}
+
+// The pricier refunds are claims on the same coins as the refund before them
+fn ladder_claims(ladder: &[LadderRefund]) -> impl Iterator<Item = String> + '_ {
+    ladder.iter().map(|LadderRefund { txid, fee, .. }| {
+        format!("Refund tx {txid}: the same refund paying a fee of {fee} sats, if the cheaper ones don't confirm")
+    })
+}
+
+fn maker_claims(record: &MakerRecovery) -> Vec<String> {
+    let (Some(funding), Some(refund), Some(timelocks)) = (record.funding_txid, record.refund_txid, record.timelocks)
+    else {
+        return vec!["Nothing to claim, the round didn't fund the users2maker contract".to_string()];
+    };
+    let mut claims = Vec::new();
+
+    let deadline = format!("before the users' refund tx {refund} is valid {} blocks after {funding}", timelocks.refund);
+    claims.push(match (record.hashlock_keys.is_empty(), &record.preimage) {
+        (true, _) => format!("Users2maker contract of {funding}: none, the users take it back with refund tx {refund}"),
+        (false, Some(_)) => format!("Users2maker contract of {funding}: now, through the hashlock path ({deadline})"),
+        (false, None) => format!(
+            "Users2maker contract of {funding}: through the hashlock path once we learn the preimage of {} \
+            ({deadline})",
+            record.hash.map_or("the contract hash".to_string(), |hash| hash.to_string()),
+        ),
+    });
+    for (_, _, txid) in &record.maker2user {
+        claims.push(format!(
+            "Maker2user contract of {txid}: through the timelock path {} blocks after {txid}, unless the user \
+            claimed it first",
+            timelocks.maker2user,
+        ));
+    }
+
+    claims
+}
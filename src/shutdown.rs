@@ -0,0 +1,72 @@
+// Stopping a session midway. Both sessions watch a cancellation token, and once it fires they tell
+// their peers, save what they need to take their coins back and return `JoinSwapError::Shutdown`.
+// Also how the binaries stop when they can't even start.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+// Exit code of the binaries when they stop on Ctrl-C, as shells report a process killed by SIGINT
+pub const SHUTDOWN_EXIT_CODE: i32 = 130;
+
+// Exit code of the binaries when a flag is wrong, or what it points to (a backend, a file) can't be used
+pub const FAILURE_EXIT_CODE: i32 = 1;
+
+// Ends the binary telling what went wrong, instead of panicking with a backtrace
+pub fn exit_with(message: impl fmt::Display) -> ! {
+    eprintln!("{message}");
+    std::process::exit(FAILURE_EXIT_CODE)
+}
+
+// In place of `expect` on what the binaries are given
+pub trait OrExit<T> {
+    // The value, or we `exit_with` the message along with the error if there's one
+    fn or_exit(self, message: &str) -> T;
+}
+
+impl<T, E: fmt::Display> OrExit<T> for Result<T, E> {
+    fn or_exit(self, message: &str) -> T {
+        self.unwrap_or_else(|e| exit_with(format!("{message}: {e}")))
+    }
+}
+
+impl<T> OrExit<T> for Option<T> {
+    fn or_exit(self, message: &str) -> T {
+        self.unwrap_or_else(|| exit_with(message))
+    }
+}
+
+// Abort reason our peers get when we stop
+pub const SHUTTING_DOWN: &str = "Peer is shutting down";
+
+// A token cancelled on the first Ctrl-C
+pub fn on_ctrl_c() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel.cancel();
+        }
+    });
+
+    token
+}
+
+// Writes `state` as JSON to `<dir>/<name>.json`, creating the dir if needed. The JSON goes to a temp
+// file first that then replaces the old one, so a crash midway leaves the last state we saved.
+pub fn save_state(dir: &Path, name: &str, state: &impl Serialize) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{name}.json"));
+    let temp = dir.join(format!(".{name}.json.tmp"));
+
+    let mut file = File::create(&temp)?;
+    file.write_all(serde_json::to_string_pretty(state).expect("States always serialize").as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, &path)?;
+
+    Ok(path)
+}
@@ -0,0 +1,87 @@
+// Signatures binding the protocol messages to the peer we started the session with. Both hellos
+// carry a random nonce, and once a peer has its contract keys it signs every message along with
+// the nonces using one of them. A message can't be altered nor replayed on another connection, and
+// the receiver checks the signing key is one of the sender's contract keys.
+
+use bdk::bitcoin::hashes::hex::{FromHex, ToHex};
+use bdk::bitcoin::hashes::{sha256, Hash, HashEngine};
+use bdk::bitcoin::secp256k1::ecdsa::Signature;
+use bdk::bitcoin::secp256k1::rand::{thread_rng, Rng};
+use bdk::bitcoin::secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use tokio::io::AsyncBufRead;
+
+use crate::check_hex32;
+use crate::error::ProtocolError;
+use crate::message::Hello;
+use crate::transport::FramedReader;
+
+// Prefixed to what we sign, so a message signature can't be taken for one over a transaction
+const SIGNING_TAG: &[u8] = b"joinswap signed message";
+const RESUME_TAG: &[u8] = b"joinswap resume token";
+
+// A random nonce for our hello, new on every connection so it can't link the user identities
+pub fn fresh_nonce() -> String {
+    thread_rng().gen::<[u8; 32]>().to_hex()
+}
+
+// The nonce of a session, from the hellos of the user (which speaks first) and the maker
+pub fn session_nonce(user: &Hello, maker: &Hello) -> Result<sha256::Hash, ProtocolError> {
+    let mut engine = sha256::Hash::engine();
+    for hello in [user, maker] {
+        check_hex32(&hello.nonce, "hello nonce")?;
+        engine.input(&<[u8; 32]>::from_hex(&hello.nonce).expect("Checked to be 64 hex characters"));
+    }
+
+    Ok(sha256::Hash::from_engine(engine))
+}
+
+// What a user presents in the hello of a new connection to resume the session of the one that
+// dropped. Only both peers know the session nonce, as the hellos go encrypted.
+pub fn resume_token(session: &sha256::Hash) -> String {
+    let mut engine = sha256::Hash::engine();
+    engine.input(RESUME_TAG);
+    engine.input(&session[..]);
+
+    sha256::Hash::from_engine(engine).to_hex()
+}
+
+// Signs the message numbered `seq` in the session
+pub fn sign(key: &SecretKey, session: &sha256::Hash, seq: u64, body: &str) -> (PublicKey, Signature) {
+    let secp = Secp256k1::signing_only();
+
+    (PublicKey::from_secret_key(&secp, key), secp.sign_ecdsa(&digest(session, seq, body), key))
+}
+
+pub fn verify(
+    key: &PublicKey,
+    session: &sha256::Hash,
+    seq: u64,
+    body: &str,
+    sig: &Signature,
+) -> Result<(), ProtocolError> {
+    Secp256k1::verification_only()
+        .verify_ecdsa(&digest(session, seq, body), sig, key)
+        .map_err(|_| ProtocolError::BadSignature("Signature doesn't match the message".to_string()))
+}
+
+// The peer signs with one of the contract `keys` it sent us. Call it once those keys arrived.
+pub fn check_signer<R: AsyncBufRead + Unpin>(
+    reader: &FramedReader<R>,
+    keys: &[bdk::bitcoin::PublicKey],
+) -> Result<(), ProtocolError> {
+    match reader.peer_key() {
+        Some(signer) if keys.iter().any(|key| key.inner == signer) => Ok(()),
+        Some(signer) => Err(ProtocolError::BadSignature(format!("Signed with {signer}, not a contract key"))),
+        None => Err(ProtocolError::BadSignature("The contract keys weren't signed".to_string())),
+    }
+}
+
+fn digest(session: &sha256::Hash, seq: u64, body: &str) -> Message {
+    let mut engine = sha256::Hash::engine();
+    engine.input(SIGNING_TAG);
+    engine.input(&session[..]);
+    engine.input(&seq.to_be_bytes());
+    engine.input(body.as_bytes());
+
+    Message::from_slice(&sha256::Hash::from_engine(engine)).expect("Hashes are 32 bytes")
+}
@@ -0,0 +1,264 @@
+// Outbound connections through a SOCKS5 proxy (RFC 1928), like the one Tor runs. Each connection
+// authenticates with fresh random credentials (RFC 1929), which Tor takes as a request for a
+// separate circuit, so the two identities of a user don't share one.
+
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use bdk::bitcoin::hashes::hex::ToHex;
+use bdk::bitcoin::secp256k1::rand::{thread_rng, Rng};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const SOCKS_VERSION: u8 = 5;
+const USER_PASS_AUTH: u8 = 2;
+const CONNECT: u8 = 1;
+
+// Reaching the proxy is local and quick, but Tor may take a while to build the circuit
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const SOCKS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Connects to `target` (`<host>:<port>`, onion addresses included) through the proxy at `proxy`.
+// The host is resolved by the proxy, so no DNS query leaves from our IP.
+pub async fn connect(proxy: &str, target: &str) -> io::Result<TcpStream> {
+    let (host, port) = split_host_port(target)?;
+
+    let timed_out = |what: &str| io::Error::new(io::ErrorKind::TimedOut, format!("SOCKS5 proxy at {proxy} {what}"));
+
+    let mut stream = timeout(PROXY_CONNECT_TIMEOUT, TcpStream::connect(proxy))
+        .await
+        .map_err(|_| timed_out("didn't answer"))?
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't reach the SOCKS5 proxy at {proxy}: {e}")))?;
+
+    timeout(SOCKS_HANDSHAKE_TIMEOUT, handshake(&mut stream, host, port))
+        .await
+        .map_err(|_| timed_out("didn't connect us in time"))?
+        .map_err(|e| io::Error::new(e.kind(), format!("SOCKS5 proxy at {proxy}: {e}")))?;
+    Ok(stream)
+}
+
+async fn handshake(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    // We only offer username/password auth, without it the proxy can't isolate our connections
+    stream.write_all(&[SOCKS_VERSION, 1, USER_PASS_AUTH]).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(invalid("Not a SOCKS5 proxy"));
+    }
+    if choice[1] != USER_PASS_AUTH {
+        return Err(invalid("The proxy doesn't take username/password auth, needed to isolate connections"));
+    }
+
+    let user = thread_rng().gen::<[u8; 16]>().to_hex();
+    let password = thread_rng().gen::<[u8; 16]>().to_hex();
+    let auth = [&[1, user.len() as u8], user.as_bytes(), &[password.len() as u8], password.as_bytes()].concat();
+    stream.write_all(&auth).await?;
+    let mut status = [0; 2];
+    stream.read_exact(&mut status).await?;
+    if status[1] != 0 {
+        return Err(invalid("The proxy refused our credentials"));
+    }
+
+    let address = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => [&[1][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[4][..], &ip.octets()].concat(),
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| invalid("Host name too long"))?;
+            [&[3, len][..], host.as_bytes()].concat()
+        },
+    };
+    stream.write_all(&[&[SOCKS_VERSION, CONNECT, 0][..], &address, &port.to_be_bytes()].concat()).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, reply_error(reply[1])));
+    }
+    // Skip the address the proxy bound, and its port
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(invalid("Unknown address type in the proxy reply")),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(())
+}
+
+fn split_host_port(target: &str) -> io::Result<(&str, u16)> {
+    let (host, port) = target.rsplit_once(':').ok_or_else(|| invalid("Expected <host>:<port>"))?;
+    let port = port.parse().map_err(|_| invalid("Invalid port"))?;
+
+    // IPv6 addresses come in brackets
+    Ok((host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "General proxy failure",
+        2 => "Connection not allowed by the proxy",
+        3 => "Network unreachable",
+        4 => "Host unreachable",
+        5 => "Connection refused by the target",
+        6 => "TTL expired",
+        7 => "Command not supported by the proxy",
+        8 => "Address type not supported by the proxy",
+        _ => "Unknown proxy failure",
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    // What a proxy stub answers: the auth method it picks, the auth status and the connect reply code
+    struct Script {
+        method: u8,
+        auth_status: u8,
+        reply: u8,
+    }
+
+    const SUCCESS: Script = Script { method: USER_PASS_AUTH, auth_status: 0, reply: 0 };
+
+    // What the stub got: the credentials, and the address and port to connect to as sent
+    #[derive(Debug, Default)]
+    struct Request {
+        credentials: Vec<u8>,
+        address: Vec<u8>,
+    }
+
+    // A SOCKS5 proxy for one connection, which greets us with "hi" once connected
+    async fn stub(script: Script) -> (String, JoinHandle<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let serving = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Request::default();
+
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [SOCKS_VERSION, 1, USER_PASS_AUTH]);
+            stream.write_all(&[SOCKS_VERSION, script.method]).await.unwrap();
+            if script.method != USER_PASS_AUTH {
+                return request;
+            }
+
+            // Version, then the user and password with their lengths
+            let mut auth = vec![0; 2];
+            stream.read_exact(&mut auth).await.unwrap();
+            auth.resize(auth[1] as usize + 3, 0);
+            stream.read_exact(&mut auth[2..]).await.unwrap();
+            let password_len = *auth.last().unwrap() as usize;
+            auth.resize(auth.len() + password_len, 0);
+            let start = auth.len() - password_len;
+            stream.read_exact(&mut auth[start..]).await.unwrap();
+            request.credentials = auth;
+            stream.write_all(&[1, script.auth_status]).await.unwrap();
+            if script.auth_status != 0 {
+                return request;
+            }
+
+            // Users give up before asking for names too long to send
+            let mut header = [0; 4];
+            if stream.read_exact(&mut header).await.is_err() {
+                return request;
+            }
+            assert_eq!(header[..3], [SOCKS_VERSION, CONNECT, 0]);
+            let len = match header[3] {
+                1 => 4,
+                4 => 16,
+                _ => stream.read_u8().await.unwrap() as usize,
+            };
+            request.address = vec![0; len + 2];
+            stream.read_exact(&mut request.address).await.unwrap();
+
+            // We say we bound a domain name, which the user skips over
+            stream.write_all(&[SOCKS_VERSION, script.reply, 0, 3, 5]).await.unwrap();
+            stream.write_all(b"proxy\x1f\x90").await.unwrap();
+            if script.reply == 0 {
+                stream.write_all(b"hi").await.unwrap();
+            }
+            request
+        });
+        (addr, serving)
+    }
+
+    // Connects through a stub following `script`, and reads the greeting of the target
+    async fn connect_with(script: Script, target: &str) -> (io::Result<[u8; 2]>, Request) {
+        let (proxy, serving) = stub(script).await;
+        let greeting = async {
+            let mut stream = connect(&proxy, target).await?;
+            let mut greeting = [0; 2];
+            stream.read_exact(&mut greeting).await?;
+            Ok(greeting)
+        };
+
+        (greeting.await, serving.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn connects() {
+        let (greeting, request) = connect_with(SUCCESS, "10.0.0.1:8333").await;
+        assert_eq!(&greeting.unwrap(), b"hi");
+        assert_eq!(request.address, [10, 0, 0, 1, 0x20, 0x8d]);
+
+        let (greeting, request) = connect_with(SUCCESS, "[::1]:80").await;
+        assert_eq!(&greeting.unwrap(), b"hi");
+        assert_eq!(request.address, [&[0; 15][..], &[1, 0, 80]].concat());
+    }
+
+    // Host names go to the proxy to resolve, onion addresses included
+    #[tokio::test]
+    async fn connects_to_domain_names() {
+        let onion = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+        let (greeting, request) = connect_with(SUCCESS, &format!("{onion}:9735")).await;
+        assert_eq!(&greeting.unwrap(), b"hi");
+        assert_eq!(request.address, [onion.as_bytes(), &[0x26, 0x07]].concat());
+
+        let (greeting, request) = connect_with(SUCCESS, &format!("{}:80", "a".repeat(256))).await;
+        assert!(greeting.unwrap_err().to_string().ends_with("Host name too long"));
+        assert!(request.address.is_empty());
+    }
+
+    // Each connection authenticates with its own random credentials, so Tor isolates them
+    #[tokio::test]
+    async fn fresh_credentials() {
+        let (_, first) = connect_with(SUCCESS, "10.0.0.1:8333").await;
+        let (_, second) = connect_with(SUCCESS, "10.0.0.1:8333").await;
+
+        assert_eq!((first.credentials[0], first.credentials.len()), (1, 3 + 32 + 32));
+        assert_ne!(first.credentials, second.credentials);
+    }
+
+    #[tokio::test]
+    async fn auth_failures() {
+        let no_auth = Script { method: 0, ..SUCCESS };
+        let error = connect_with(no_auth, "10.0.0.1:8333").await.0.unwrap_err();
+        assert!(error.to_string().contains("doesn't take username/password auth"), "{error}");
+
+        let refused = Script { auth_status: 1, ..SUCCESS };
+        let error = connect_with(refused, "10.0.0.1:8333").await.0.unwrap_err();
+        assert!(error.to_string().contains("refused our credentials"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn error_replies() {
+        for (code, reason) in [(1, "General proxy failure"), (4, "Host unreachable"), (9, "Unknown proxy failure")] {
+            let error = connect_with(Script { reply: code, ..SUCCESS }, "10.0.0.1:8333").await.0.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(error.to_string().ends_with(reason), "{error}");
+        }
+    }
+}
@@ -0,0 +1,419 @@
+// TLS for deployments that don't want the Noise handshake. The maker serves a certificate, and users
+// either pin its SHA256 fingerprint or validate it for a DNS name against the webpki roots. A TLS
+// connection is already encrypted, so the protocol skips Noise on it.
+
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
+
+use bdk::bitcoin::hashes::{sha256, Hash};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+             ServerConnection, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::transport::{connect_tcp, Acceptor, Transport};
+
+// A stream encrypted by a rustls connection. The handshake runs before the first read or write.
+pub struct TlsStream<S> {
+    io: S,
+    conn: rustls::Connection,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TlsStream<S> {
+    fn new(io: S, conn: rustls::Connection) -> Self {
+        TlsStream { io, conn }
+    }
+
+    pub async fn handshake(&mut self) -> io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_handshake(cx)).await
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.conn.is_handshaking() {
+            while self.conn.wants_write() {
+                ready!(self.poll_write_tls(cx))?;
+            }
+            if self.conn.is_handshaking() && ready!(self.poll_read_tls(cx))? == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        // The last flight of the handshake
+        while self.conn.wants_write() {
+            ready!(self.poll_write_tls(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Feeds the records the peer sent to rustls, returns 0 on EOF
+    fn poll_read_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let read = match self.conn.read_tls(&mut SyncIo { io: &mut self.io, cx }) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+            result => result?,
+        };
+
+        if let Err(e) = self.conn.process_new_packets() {
+            // Try to tell the peer with an alert
+            let _ = self.poll_write_tls(cx);
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+        Poll::Ready(Ok(read))
+    }
+
+    fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        match self.conn.write_tls(&mut SyncIo { io: &mut self.io, cx }) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+
+        loop {
+            match this.conn.reader().read(buf.initialize_unfilled()) {
+                Ok(read) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            // Whatever rustls must answer (like key updates) goes out before we wait for more
+            while this.conn.wants_write() {
+                if this.poll_write_tls(cx)?.is_pending() {
+                    break;
+                }
+            }
+            // On EOF the reader tells apart a clean close from a cut connection
+            ready!(this.poll_read_tls(cx))?;
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+
+        loop {
+            let written = this.conn.writer().write(buf)?;
+            while this.conn.wants_write() {
+                if this.poll_write_tls(cx)?.is_pending() {
+                    // The records wait in rustls, unless it had no room for any of `buf`
+                    return if written > 0 { Poll::Ready(Ok(written)) } else { Poll::Pending };
+                }
+            }
+            if written > 0 || buf.is_empty() {
+                return Poll::Ready(Ok(written));
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        this.conn.writer().flush()?;
+        while this.conn.wants_write() {
+            ready!(this.poll_write_tls(cx))?;
+        }
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.conn.send_close_notify();
+        ready!(self.as_mut().poll_flush(cx))?;
+
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+// The blocking Read and Write rustls wants, with a pending async stream reported as WouldBlock
+struct SyncIo<'a, 'b, S> {
+    io: &'a mut S,
+    cx: &'a mut Context<'b>,
+}
+
+impl<S: AsyncRead + Unpin> Read for SyncIo<'_, '_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+
+        match Pin::new(&mut *self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(result) => result.map(|_| buf.filled().len()),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for SyncIo<'_, '_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+// How users check the maker certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertCheck {
+    // The SHA256 of the DER certificate, for self signed certificates handed out with the address
+    Pinned(sha256::Hash),
+    // A certificate for this DNS name, issued by one of the webpki roots
+    WebPki(String),
+}
+
+// Connects to the maker over TLS, finishing the handshake (and so the certificate check) before any
+// message is sent
+pub struct TlsTransport {
+    addr: String,
+    name: ServerName,
+    config: Arc<ClientConfig>,
+    // SOCKS5 proxy (`<host>:<port>`) the connections go through
+    proxy: Option<String>,
+}
+
+impl TlsTransport {
+    pub fn new(addr: impl Into<String>, check: CertCheck) -> io::Result<Self> {
+        let addr = addr.into();
+        let builder = ClientConfig::builder().with_safe_defaults();
+
+        let (name, config) = match check {
+            CertCheck::Pinned(fingerprint) => {
+                // The name is only sent in the SNI, the pin is what we check
+                let host = addr.rsplit_once(':').map_or(addr.as_str(), |(host, _)| host);
+                let name = match host.parse::<IpAddr>() {
+                    Ok(ip) => ServerName::IpAddress(ip),
+                    Err(_) => server_name(host)?,
+                };
+                let verifier = Arc::new(PinnedCert(fingerprint));
+
+                (name, builder.with_custom_certificate_verifier(verifier).with_no_client_auth())
+            },
+            CertCheck::WebPki(dns_name) => {
+                (server_name(&dns_name)?, builder.with_root_certificates(webpki_roots()).with_no_client_auth())
+            },
+        };
+
+        Ok(TlsTransport { addr, name, config: Arc::new(config), proxy: None })
+    }
+
+    pub fn with_proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+}
+
+impl Transport for TlsTransport {
+    type Stream = TlsStream<TcpStream>;
+
+    async fn connect(&self) -> io::Result<TlsStream<TcpStream>> {
+        let socket = connect_tcp(&self.addr, self.proxy.as_deref()).await?;
+        let conn = ClientConnection::new(self.config.clone(), self.name.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let mut stream = TlsStream::new(socket, conn.into());
+        stream.handshake().await?;
+        Ok(stream)
+    }
+
+    fn maker_key(&self) -> Option<bdk::bitcoin::secp256k1::PublicKey> {
+        None
+    }
+}
+
+// The certificate authorities browsers trust
+pub(crate) fn webpki_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    roots
+}
+
+fn server_name(name: &str) -> io::Result<ServerName> {
+    ServerName::try_from(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+// Accepts only the certificate with our pinned fingerprint. The handshake signature is still checked
+// against it, so the peer must hold its key.
+struct PinnedCert(sha256::Hash);
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = sha256::Hash::hash(&end_entity.0);
+        if fingerprint != self.0 {
+            return Err(rustls::Error::General(format!("Certificate fingerprint {fingerprint} isn't the pinned one")));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// Accepts users over TLS. The handshake runs on the first read, so within the timeout of the hello.
+pub struct TlsAcceptor {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    // `cert_pem` holds the certificate chain and `key_pem` its private key (PKCS#8, PKCS#1 or SEC1)
+    pub fn new(listener: TcpListener, cert_pem: &str, key_pem: &str) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+
+        let certs = pem_blocks(cert_pem, &["CERTIFICATE"]).map_err(invalid)?;
+        if certs.is_empty() {
+            return Err(invalid("No certificate found".to_string()));
+        }
+        let key = pem_blocks(key_pem, &["PRIVATE KEY", "RSA PRIVATE KEY", "EC PRIVATE KEY"])
+            .map_err(invalid)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| invalid("No private key found".to_string()))?;
+
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(key))
+            .map_err(|e| invalid(e.to_string()))?;
+
+        Ok(TlsAcceptor { listener, config: Arc::new(config) })
+    }
+}
+
+impl Acceptor for TlsAcceptor {
+    type Stream = TlsStream<TcpStream>;
+
+    async fn accept(&self) -> io::Result<TlsStream<TcpStream>> {
+        let (socket, _) = self.listener.accept().await?;
+        let conn = ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+
+        Ok(TlsStream::new(socket, conn.into()))
+    }
+
+    fn peer_ip(stream: &TlsStream<TcpStream>) -> Option<IpAddr> {
+        stream.io.peer_addr().ok().map(|addr| addr.ip())
+    }
+}
+
+// The DER content of the PEM blocks with one of the `labels`
+pub(crate) fn pem_blocks(pem: &str, labels: &[&str]) -> Result<Vec<Vec<u8>>, String> {
+    let mut blocks = Vec::new();
+    let mut lines = pem.lines().map(str::trim);
+
+    while let Some(line) = lines.next() {
+        let Some(label) = line.strip_prefix("-----BEGIN ").and_then(|rest| rest.strip_suffix("-----")) else {
+            continue;
+        };
+        let end = format!("-----END {label}-----");
+        let body: String = lines.by_ref().take_while(|line| *line != end).collect();
+
+        if labels.contains(&label) {
+            blocks.push(base64::decode(&body).map_err(|e| format!("Invalid PEM {label}: {e}"))?);
+        }
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const CERT: &str = include_str!("../testdata/localhost.pem");
+    const KEY: &str = include_str!("../testdata/localhost.key");
+
+    async fn acceptor() -> (TlsAcceptor, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        (TlsAcceptor::new(listener, CERT, KEY).unwrap(), addr)
+    }
+
+    fn fingerprint() -> sha256::Hash {
+        sha256::Hash::hash(&pem_blocks(CERT, &["CERTIFICATE"]).unwrap()[0])
+    }
+
+    // Bytes go through both ways, more than a TLS record holds, and a close ends the stream
+    #[tokio::test]
+    async fn round_trip() {
+        let (acceptor, addr) = acceptor().await;
+        let sent: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+
+        let echo = async {
+            let mut stream = acceptor.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+            stream.shutdown().await.unwrap();
+        };
+        let user = async {
+            let transport = TlsTransport::new(addr, CertCheck::Pinned(fingerprint())).unwrap();
+            let mut stream = transport.connect().await.unwrap();
+            stream.write_all(&sent).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed).await.unwrap();
+            echoed
+        };
+
+        let ((), echoed) = tokio::join!(echo, user);
+        assert_eq!(echoed, sent);
+    }
+
+    // A maker with another certificate fails the handshake, before anything is sent
+    #[tokio::test]
+    async fn wrong_pin() {
+        let (acceptor, addr) = acceptor().await;
+        let wrong = sha256::Hash::hash(b"another certificate");
+
+        let maker = async {
+            let mut stream = acceptor.accept().await.unwrap();
+            stream.read(&mut [0; 16]).await
+        };
+        let user = async {
+            let transport = TlsTransport::new(addr, CertCheck::Pinned(wrong)).unwrap();
+            transport.connect().await
+        };
+
+        let (read, connected) = tokio::join!(maker, user);
+        let error = connected.err().unwrap();
+        assert!(error.to_string().contains("isn't the pinned one"), "{error}");
+        assert!(read.is_err());
+    }
+
+    // Nor is a self signed certificate valid for the webpki roots
+    #[tokio::test]
+    async fn untrusted_certificate() {
+        let (acceptor, addr) = acceptor().await;
+
+        let maker = async { acceptor.accept().await.unwrap().read(&mut [0; 16]).await };
+        let user = async { TlsTransport::new(addr, CertCheck::WebPki("localhost".into())).unwrap().connect().await };
+
+        let (read, connected) = tokio::join!(maker, user);
+        assert!(connected.is_err() && read.is_err());
+    }
+}
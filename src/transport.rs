@@ -0,0 +1,725 @@
+// How the protocol reaches its peers. All the message helpers work on any AsyncRead/AsyncWrite
+// stream, so a session can run over TCP or over in-memory streams alike.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bdk::bitcoin::hashes::hex::{FromHex, ToHex};
+use bdk::bitcoin::hashes::sha256;
+use bdk::bitcoin::secp256k1::{PublicKey, SecretKey};
+use tokio::io::{split, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+                BufReader, ReadHalf, WriteHalf};
+use tokio::io::{duplex, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::error::ProtocolError;
+use crate::events::AbortCode;
+use crate::message::PsbtEncoding;
+use crate::noise::{CipherState, MAX_REFUSAL_LEN, REFUSAL, TAG_LEN};
+use crate::socks;
+
+// How messages are delimited on the wire. Both peers must use the same framing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    // A 4 byte big endian length followed by that many bytes
+    #[default]
+    LengthPrefixed,
+    // One line ended by \n (or \r\n), kept for peers that don't use length prefixes yet
+    Line,
+}
+
+// A peer connection split into a framed reader and writer
+pub struct Connection<S> {
+    pub reader: FramedReader<BufReader<ReadHalf<S>>>,
+    pub writer: FramedWriter<WriteHalf<S>>,
+}
+
+impl<S: AsyncRead + AsyncWrite> Connection<S> {
+    pub fn new(stream: S, framing: Framing) -> Self {
+        let (reader, writer) = split(stream);
+
+        Connection {
+            reader: FramedReader::new(BufReader::new(reader), framing),
+            writer: FramedWriter::new(writer, framing),
+        }
+    }
+
+    // Called once the hellos are exchanged, from then on messages are signed
+    pub fn start_session(&mut self, session: sha256::Hash) {
+        self.reader.session = Some(session);
+        self.writer.session = Some(session);
+    }
+}
+
+pub struct FramedReader<R> {
+    inner: R,
+    framing: Framing,
+    // Set once the Noise handshake is done, from then on every frame is encrypted
+    cipher: Option<CipherState>,
+    // Set once the hellos are exchanged, from then on messages must be signed
+    session: Option<sha256::Hash>,
+    // The key the peer signed its first message with, which must sign every other
+    peer_key: Option<PublicKey>,
+    // Number of the next message the peer sends in the session
+    next_seq: u64,
+}
+
+impl<R: AsyncBufRead + Unpin> FramedReader<R> {
+    pub fn new(inner: R, framing: Framing) -> Self {
+        FramedReader { inner, framing, cipher: None, session: None, peer_key: None, next_seq: 0 }
+    }
+
+    pub fn decrypt_with(&mut self, cipher: CipherState) {
+        self.cipher = Some(cipher);
+    }
+
+    pub(crate) fn session(&self) -> Option<sha256::Hash> {
+        self.session
+    }
+
+    pub fn peer_key(&self) -> Option<PublicKey> {
+        self.peer_key
+    }
+
+    // Messages of a session must come in order, so a repeated or dropped one is caught
+    pub(crate) fn check_seq(&mut self, seq: u64) -> Result<(), ProtocolError> {
+        if seq != self.next_seq {
+            return Err(ProtocolError::OutOfOrder { expected: self.next_seq, got: seq });
+        }
+        self.next_seq += 1;
+
+        Ok(())
+    }
+
+    // Takes the key of the first signed message, later ones must come with the same key
+    pub(crate) fn pin_peer_key(&mut self, key: PublicKey) -> Result<(), ProtocolError> {
+        match self.peer_key {
+            Some(pinned) if pinned != key => {
+                Err(ProtocolError::BadSignature(format!("Signed with {key}, but earlier messages with {pinned}")))
+            },
+            _ => {
+                self.peer_key = Some(key);
+                Ok(())
+            },
+        }
+    }
+
+    // A user rejoining matchmaking takes the key the maker signs its next round with
+    pub(crate) fn forget_peer_key(&mut self) {
+        self.peer_key = None;
+    }
+
+    // The content of the next frame, which can't have surrounding whitespace. Frames longer than
+    // `max` are refused before reading them, and lines once `max` bytes came without a newline, so
+    // a peer can't make us buffer more than that.
+    pub async fn read_frame(&mut self, max: usize) -> Result<String, ProtocolError> {
+        let frame = match (&self.cipher, self.framing) {
+            (Some(_), _) => self.read_binary(max + TAG_LEN).await?,
+            (None, Framing::LengthPrefixed) => self.read_prefixed(max).await?,
+            (None, Framing::Line) => self.read_line(max).await?.into_bytes(),
+        };
+        let frame = match &mut self.cipher {
+            Some(cipher) => cipher.decrypt(&frame)?,
+            None => frame,
+        };
+        let frame = String::from_utf8(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let content = frame.trim();
+        if content.is_empty() {
+            return Err(ProtocolError::EmptyLine);
+        }
+        if content != frame {
+            return Err(ProtocolError::Padded);
+        }
+        Ok(frame)
+    }
+
+    // Waits until the peer sent something (or hung up), without reading it. Unlike reading a frame,
+    // this can be cancelled without losing data.
+    pub async fn wait_readable(&mut self) -> io::Result<()> {
+        self.inner.fill_buf().await?;
+
+        Ok(())
+    }
+
+    // A handshake message, which must be exactly `len` bytes
+    pub(crate) async fn read_handshake(&mut self, len: usize) -> Result<Vec<u8>, ProtocolError> {
+        let act = self.read_binary(len.max(MAX_REFUSAL_LEN)).await?;
+        // The maker may turn us away instead of answering
+        if let Some((&REFUSAL, reason)) = act.split_first() {
+            let reason = String::from_utf8_lossy(reason).into_owned();
+            return Err(ProtocolError::Rejected { code: AbortCode::Refused, reason });
+        }
+        if act.len() != len {
+            return Err(ProtocolError::Handshake(format!("Expected a {len} byte handshake message")));
+        }
+        Ok(act)
+    }
+
+    // Binary frames (the handshake and every encrypted message) are sent as hex in line framing
+    async fn read_binary(&mut self, max: usize) -> Result<Vec<u8>, ProtocolError> {
+        match self.framing {
+            Framing::LengthPrefixed => self.read_prefixed(max).await,
+            Framing::Line => {
+                let line = self.read_line(max * 2).await.map_err(|e| match e {
+                    ProtocolError::TooLong { .. } => ProtocolError::TooLong { max },
+                    e => e,
+                })?;
+                Vec::from_hex(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+            },
+        }
+    }
+
+    async fn read_prefixed(&mut self, max: usize) -> Result<Vec<u8>, ProtocolError> {
+        let mut prefix = [0; 4];
+        match self.inner.read_exact(&mut prefix).await {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(ProtocolError::Eof),
+            result => result?,
+        };
+
+        let len = u32::from_be_bytes(prefix) as usize;
+        if len > max {
+            return Err(ProtocolError::TooLong { max });
+        }
+        let mut buf = vec![0; len];
+        self.inner.read_exact(&mut buf).await?;
+
+        Ok(buf)
+    }
+
+    async fn read_line(&mut self, max: usize) -> Result<String, ProtocolError> {
+        let mut buf = String::new();
+
+        // Room for a \r\n after the max, so a cut line is longer than the max
+        if (&mut self.inner).take(max as u64 + 2).read_line(&mut buf).await? == 0 {
+            return Err(ProtocolError::Eof);
+        }
+        let line = buf.strip_suffix('\n').unwrap_or(&buf);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        if line.len() > max {
+            return Err(ProtocolError::TooLong { max });
+        }
+        Ok(line.to_string())
+    }
+}
+
+pub struct FramedWriter<W> {
+    inner: W,
+    framing: Framing,
+    // How we send PSBTs to this peer, JSON until the handshake agrees on something else
+    psbt_encoding: PsbtEncoding,
+    // Whether we send signed PSBTs as their signatures alone, if the handshake agrees on it
+    sig_bundles: bool,
+    // Whether the peer takes `Waiting` messages, if the handshake agrees on it
+    waiting_status: bool,
+    // Whether we may send a `ChangeRequest`, if the handshake agrees on it
+    partial_spends: bool,
+    // Whether the funding tx may be replaced (see `FundingBump`), if the handshake agrees on it
+    funding_bumps: bool,
+    // Whether the funding inputs of the user are signed with SIGHASH_ALL|ANYONECANPAY, so inputs may be
+    // added (see `AddInput`), if the handshake agrees on it
+    anyone_can_pay: bool,
+    // Set once the Noise handshake is done, from then on every frame is encrypted
+    cipher: Option<CipherState>,
+    // The stream encrypts on its own (TLS), so there's no Noise handshake
+    encrypted_stream: bool,
+    session: Option<sha256::Hash>,
+    // Number of the next message we send in the session
+    next_seq: u64,
+    // One of our contract keys, once we have them we sign every message with it
+    signing_key: Option<SecretKey>,
+}
+
+impl<W: AsyncWrite + Unpin> FramedWriter<W> {
+    pub fn new(inner: W, framing: Framing) -> Self {
+        FramedWriter {
+            inner,
+            framing,
+            psbt_encoding: PsbtEncoding::Json,
+            sig_bundles: false,
+            waiting_status: false,
+            partial_spends: false,
+            funding_bumps: false,
+            anyone_can_pay: false,
+            cipher: None,
+            encrypted_stream: false,
+            session: None,
+            next_seq: 0,
+            signing_key: None,
+        }
+    }
+
+    pub fn psbt_encoding(&self) -> PsbtEncoding {
+        self.psbt_encoding
+    }
+
+    pub fn set_psbt_encoding(&mut self, encoding: PsbtEncoding) {
+        self.psbt_encoding = encoding;
+    }
+
+    pub fn sig_bundles(&self) -> bool {
+        self.sig_bundles
+    }
+
+    pub fn set_sig_bundles(&mut self, sig_bundles: bool) {
+        self.sig_bundles = sig_bundles;
+    }
+
+    pub fn waiting_status(&self) -> bool {
+        self.waiting_status
+    }
+
+    pub fn set_waiting_status(&mut self, waiting_status: bool) {
+        self.waiting_status = waiting_status;
+    }
+
+    pub fn partial_spends(&self) -> bool {
+        self.partial_spends
+    }
+
+    pub fn set_partial_spends(&mut self, partial_spends: bool) {
+        self.partial_spends = partial_spends;
+    }
+
+    pub fn funding_bumps(&self) -> bool {
+        self.funding_bumps
+    }
+
+    pub fn set_funding_bumps(&mut self, funding_bumps: bool) {
+        self.funding_bumps = funding_bumps;
+    }
+
+    pub fn anyone_can_pay(&self) -> bool {
+        self.anyone_can_pay
+    }
+
+    pub fn set_anyone_can_pay(&mut self, anyone_can_pay: bool) {
+        self.anyone_can_pay = anyone_can_pay;
+    }
+
+    pub fn encrypt_with(&mut self, cipher: CipherState) {
+        self.cipher = Some(cipher);
+    }
+
+    // Only for streams that encrypt on their own, secrets are sent over them in the clear
+    pub fn assume_encrypted_stream(&mut self) {
+        self.encrypted_stream = true;
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some() || self.encrypted_stream
+    }
+
+    // Use a contract key we never hand over, or the peer could sign in our name afterwards
+    pub fn sign_with(&mut self, key: SecretKey) {
+        self.signing_key = Some(key);
+    }
+
+    // Until the next round of a rematched user signs with its new key, as `FramedReader::forget_peer_key`
+    // expects
+    pub(crate) fn stop_signing(&mut self) {
+        self.signing_key = None;
+    }
+
+    pub(crate) fn session(&self) -> Option<sha256::Hash> {
+        self.session
+    }
+
+    pub(crate) fn signing_key(&self) -> Option<&SecretKey> {
+        self.signing_key.as_ref()
+    }
+
+    // Numbers our messages of the session, in the order they are sent
+    pub(crate) fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+
+        self.next_seq - 1
+    }
+
+    // Frames can't be larger than what the 4 byte prefix holds, and lines can't hold a newline
+    pub async fn write_frame(&mut self, frame: &str) -> io::Result<()> {
+        if let Some(cipher) = &mut self.cipher {
+            let sealed = cipher.encrypt(frame.as_bytes());
+            return self.write_binary(&sealed).await;
+        }
+
+        match self.framing {
+            Framing::LengthPrefixed => self.write_prefixed(frame.as_bytes()).await,
+            Framing::Line => {
+                if frame.contains('\n') {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Newline inside a line frame"));
+                }
+                self.inner.write_all(&[frame.as_bytes(), b"\n"].concat()).await
+            },
+        }
+    }
+
+    // Closes our side of the connection, the peer reads its end
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown().await
+    }
+
+    pub(crate) async fn write_handshake(&mut self, act: &[u8]) -> io::Result<()> {
+        self.write_binary(act).await
+    }
+
+    async fn write_binary(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self.framing {
+            Framing::LengthPrefixed => self.write_prefixed(frame).await,
+            Framing::Line => self.inner.write_all(format!("{}\n", frame.to_hex()).as_bytes()).await,
+        }
+    }
+
+    async fn write_prefixed(&mut self, frame: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(frame.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Frame too large"))?;
+
+        self.inner.write_all(&[&len.to_be_bytes(), frame].concat()).await
+    }
+}
+
+// Where users find a maker: `<static pub key>@<host>:<port>`. The key is the one the maker answers the
+// Noise handshake with, so users must learn it along with the address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MakerAddress {
+    pub key: PublicKey,
+    pub addr: String,
+}
+
+impl FromStr for MakerAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, addr) = s.split_once('@').ok_or("Expected <pub key>@<host>:<port>")?;
+        let key = PublicKey::from_str(key).map_err(|e| format!("Invalid maker key: {e}"))?;
+
+        Ok(MakerAddress { key, addr: addr.to_string() })
+    }
+}
+
+impl fmt::Display for MakerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.key, self.addr)
+    }
+}
+
+// Where the maker listens, `<host>:<port>` or `unix:<path>` for a Unix domain socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("Missing socket path after unix:".to_string()),
+            Some(path) => Ok(ListenAddr::Unix(path.into())),
+            None if s.contains(':') => Ok(ListenAddr::Tcp(s.to_string())),
+            None => Err(format!("Expected <host>:<port> or unix:<path>, got '{s}'")),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Opens connections to the maker. A user connects once per identity, so this is called twice.
+pub trait Transport {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    fn connect(&self) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+
+    // Static key the maker must prove it holds in the Noise handshake, none for transports that
+    // encrypt on their own (like TLS)
+    fn maker_key(&self) -> Option<PublicKey>;
+}
+
+#[derive(Clone)]
+pub struct TcpTransport {
+    maker: MakerAddress,
+    // SOCKS5 proxy (`<host>:<port>`) the connections go through
+    proxy: Option<String>,
+}
+
+impl TcpTransport {
+    pub fn new(maker: MakerAddress) -> Self {
+        TcpTransport { maker, proxy: None }
+    }
+
+    pub fn with_proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+}
+
+impl Transport for TcpTransport {
+    type Stream = TcpStream;
+
+    fn connect(&self) -> impl Future<Output = io::Result<TcpStream>> + Send {
+        let addr = self.maker.addr.clone();
+        let proxy = self.proxy.clone();
+
+        async move { connect_tcp(&addr, proxy.as_deref()).await }
+    }
+
+    fn maker_key(&self) -> Option<PublicKey> {
+        Some(self.maker.key)
+    }
+}
+
+// Connects directly or through a SOCKS5 proxy, which isolates each connection from the others.
+// Onion addresses (`<name>.onion:<port>`) can only be reached through a Tor proxy.
+pub async fn connect_tcp(addr: &str, proxy: Option<&str>) -> io::Result<TcpStream> {
+    match proxy {
+        Some(proxy) => socks::connect(proxy, addr).await,
+        None if is_onion(addr) => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{addr} can only be reached through a Tor proxy")))
+        },
+        None => TcpStream::connect(addr).await,
+    }
+}
+
+fn is_onion(addr: &str) -> bool {
+    addr.rsplit_once(':').is_some_and(|(host, _)| host.ends_with(".onion"))
+}
+
+// Accepts connections from users. The maker needs it mid round, when the users come back with
+// their new identities for the second leg.
+pub trait Acceptor {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    fn accept(&self) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+
+    // Address the connection comes from, for rate limiting. None if the stream has no IP.
+    fn peer_ip(_stream: &Self::Stream) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl Acceptor for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<TcpStream> {
+        let (socket, _) = TcpListener::accept(self).await?;
+
+        Ok(socket)
+    }
+
+    fn peer_ip(stream: &TcpStream) -> Option<IpAddr> {
+        stream.peer_addr().ok().map(|addr| addr.ip())
+    }
+}
+
+impl Acceptor for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept(&self) -> io::Result<UnixStream> {
+        let (socket, _) = UnixListener::accept(self).await?;
+
+        Ok(socket)
+    }
+}
+
+// Listens on a Unix domain socket, replacing the one a previous run may have left behind. Any other
+// file at `path` is left alone and the bind fails.
+pub fn bind_unix(path: &std::path::Path) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+// Connects users to a maker on the same host through its Unix domain socket
+#[derive(Clone)]
+pub struct UnixTransport {
+    path: PathBuf,
+    maker_key: PublicKey,
+}
+
+impl UnixTransport {
+    pub fn new(path: impl Into<PathBuf>, maker_key: PublicKey) -> Self {
+        UnixTransport { path: path.into(), maker_key }
+    }
+}
+
+impl Transport for UnixTransport {
+    type Stream = UnixStream;
+
+    fn connect(&self) -> impl Future<Output = io::Result<UnixStream>> + Send {
+        UnixStream::connect(self.path.clone())
+    }
+
+    fn maker_key(&self) -> Option<PublicKey> {
+        Some(self.maker_key)
+    }
+}
+
+// Bytes each direction of an in-memory connection buffers before writes wait for the reader
+const MEMORY_BUFFER: usize = 64 * 1024;
+
+// Connects users to a maker in the same process, for running whole rounds without sockets. The
+// maker still proves `maker_key` in the Noise handshake, like over TCP.
+pub fn memory_transport(maker_key: PublicKey) -> (MemoryTransport, MemoryAcceptor) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    (MemoryTransport { sender, maker_key }, MemoryAcceptor { receiver: Mutex::new(receiver) })
+}
+
+#[derive(Clone)]
+pub struct MemoryTransport {
+    sender: mpsc::UnboundedSender<DuplexStream>,
+    maker_key: PublicKey,
+}
+
+impl Transport for MemoryTransport {
+    type Stream = DuplexStream;
+
+    fn connect(&self) -> impl Future<Output = io::Result<DuplexStream>> + Send {
+        let (ours, theirs) = duplex(MEMORY_BUFFER);
+        let sent = self.sender.send(theirs);
+
+        async move {
+            sent.map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, "The maker is gone"))?;
+            Ok(ours)
+        }
+    }
+
+    fn maker_key(&self) -> Option<PublicKey> {
+        Some(self.maker_key)
+    }
+}
+
+pub struct MemoryAcceptor {
+    receiver: Mutex<mpsc::UnboundedReceiver<DuplexStream>>,
+}
+
+impl Acceptor for MemoryAcceptor {
+    type Stream = DuplexStream;
+
+    async fn accept(&self) -> io::Result<DuplexStream> {
+        let stream = self.receiver.lock().await.recv().await;
+
+        stream.ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Every user transport was dropped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk::bitcoin::secp256k1::Secp256k1;
+
+    use super::*;
+
+    // A reader of what `writer` sends, through a pipe that takes one byte at a time
+    fn pipe(framing: Framing) -> (FramedWriter<DuplexStream>, FramedReader<BufReader<DuplexStream>>) {
+        let (writer, reader) = duplex(1);
+
+        (FramedWriter::new(writer, framing), FramedReader::new(BufReader::new(reader), framing))
+    }
+
+    #[tokio::test]
+    async fn partial_reads() {
+        for framing in [Framing::LengthPrefixed, Framing::Line] {
+            let (mut writer, mut reader) = pipe(framing);
+            let writing = async {
+                writer.write_frame("first frame").await.unwrap();
+                writer.write_frame("second").await.unwrap();
+            };
+            let reading = async { (reader.read_frame(100).await.unwrap(), reader.read_frame(100).await.unwrap()) };
+
+            let ((), frames) = tokio::join!(writing, reading);
+            assert_eq!(frames, ("first frame".to_string(), "second".to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_of_the_max_size() {
+        let frame = "x".repeat(64);
+        for framing in [Framing::LengthPrefixed, Framing::Line] {
+            let (mut writer, mut reader) = pipe(framing);
+            let writing = async {
+                writer.write_frame(&frame).await.unwrap();
+                writer.write_frame("next").await.unwrap();
+            };
+            let reading = async { (reader.read_frame(64).await.unwrap(), reader.read_frame(64).await.unwrap()) };
+
+            let ((), frames) = tokio::join!(writing, reading);
+            assert_eq!(frames, (frame.clone(), "next".to_string()));
+        }
+
+        // Lines may end with \r\n
+        let (mut writer, reader) = duplex(1024);
+        let mut reader = FramedReader::new(BufReader::new(reader), Framing::Line);
+        writer.write_all(format!("{frame}\r\nnext\r\n").as_bytes()).await.unwrap();
+        assert_eq!(reader.read_frame(64).await.unwrap(), frame);
+        assert_eq!(reader.read_frame(64).await.unwrap(), "next");
+    }
+
+    // A frame one byte over the max is refused, a prefixed one before its body comes
+    #[tokio::test]
+    async fn oversize_frames() {
+        let (mut writer, mut reader) = pipe(Framing::LengthPrefixed);
+        let prefix = 65u32.to_be_bytes();
+        let (_, read) = tokio::join!(writer.inner.write_all(&prefix), reader.read_frame(64));
+        assert!(matches!(read, Err(ProtocolError::TooLong { max: 64 })));
+
+        let (mut writer, mut reader) = pipe(Framing::Line);
+        let frame = "x".repeat(65);
+        let (_, read) = tokio::join!(writer.write_frame(&frame), reader.read_frame(64));
+        assert!(matches!(read, Err(ProtocolError::TooLong { max: 64 })));
+    }
+
+    // A maker listening on whatever port the OS gave it, reached at the address it prints
+    #[tokio::test]
+    async fn connect_to_an_arbitrary_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let key = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let printed = MakerAddress { key, addr: listener.local_addr().unwrap().to_string() }.to_string();
+        let maker = MakerAddress::from_str(&printed).unwrap();
+        assert_eq!(maker.key, key);
+
+        let (accepted, connected) = tokio::join!(listener.accept(), connect_tcp(&maker.addr, None));
+        let mut ours = Connection::new(connected.unwrap(), Framing::default());
+        let mut theirs = Connection::new(accepted.unwrap().0, Framing::default());
+        ours.writer.write_frame("hello").await.unwrap();
+        assert_eq!(theirs.reader.read_frame(100).await.unwrap(), "hello");
+
+        let unreachable = connect_tcp("abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuvwx.onion:80", None).await;
+        assert_eq!(unreachable.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn listen_addrs() {
+        let parsed = [
+            ("127.0.0.1:8000", ListenAddr::Tcp("127.0.0.1:8000".to_string())),
+            ("[::1]:0", ListenAddr::Tcp("[::1]:0".to_string())),
+            ("maker.example:443", ListenAddr::Tcp("maker.example:443".to_string())),
+            ("unix:/tmp/maker.sock", ListenAddr::Unix("/tmp/maker.sock".into())),
+            ("unix:relative/maker.sock", ListenAddr::Unix("relative/maker.sock".into())),
+        ];
+        for (printed, addr) in parsed {
+            assert_eq!(ListenAddr::from_str(printed).unwrap(), addr);
+            assert_eq!(addr.to_string(), printed);
+        }
+
+        for invalid in ["", "unix:", "localhost", "/tmp/maker.sock"] {
+            assert!(ListenAddr::from_str(invalid).is_err(), "{invalid}");
+        }
+    }
+}
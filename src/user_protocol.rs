@@ -1,332 +1,475 @@
-use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
 use std::str::FromStr;
-use bdk::bitcoin::hashes::{Hash, sha256};
-use bdk::bitcoin::psbt::Psbt;
-use bdk::bitcoin::{Address, Network, OutPoint, PrivateKey, PublicKey, Sequence, Txid};
-use bdk::bitcoin::secp256k1::Secp256k1;
-use bdk::descriptor::Descriptor;
-use bdk::wallet::{AddressIndex, get_funded_wallet};
-use bdk::{KeychainKind, LocalUtxo, SignOptions, Wallet};
-use bdk::database::{AnyDatabase, MemoryDatabase};
-use bdk::psbt::PsbtUtils;
-use joinswap::{check_prv_keys, users2maker_contract_desc, gen_key_pair, get_descriptors, read_contract_keys, read_message, read_psbt, maker2users_contract_desc, send_message, sign_and_send_psbt};
+use std::sync::Arc;
+use std::time::Duration;
 
-use serde_json;
-use tokio::io::{BufReader, ReadHalf, split, WriteHalf};
-use tokio::net::TcpStream;
+use bdk::bitcoin::hashes::sha256;
+use bdk::bitcoin::secp256k1::{PublicKey, Secp256k1};
+use bdk::bitcoin::util::bip32::ExtendedPrivKey;
+use bdk::bitcoin::{Network, Txid};
+use bdk::database::{AnyDatabase, MemoryDatabase};
+use bdk::sled;
+use bdk::wallet::AddressIndex;
+use bdk::{FeeRate, Wallet};
+use joinswap::chain::{connect_backend, ChainAccess, MemoryChain, RpcAuth};
+use joinswap::error::JoinSwapError;
+use joinswap::events::{AbortCode, ContractKind, EventSink, MessageKind, Phase, ProtocolEvent, PsbtRole, TxRole};
+use joinswap::{demo_maker_key, demo_seed, get_descriptors, CoinSelection, PathThresholds};
+use joinswap::keys::{ContractKeySource, KeyChain};
+use joinswap::message::PsbtEncoding;
+use joinswap::lightning::HashSource;
+use joinswap::protocol::user::{run_user_session, ContractReview, UserConfig, UserPayout, UserSwapReport};
+use joinswap::recovery::{read_recovery, Recovery, UserRecovery};
+use joinswap::shutdown::{exit_with, on_ctrl_c, OrExit, SHUTDOWN_EXIT_CODE};
+use joinswap::watchtower::{RefundWatch, WatchOutcome, WATCH_POLL};
+use joinswap::tls::{CertCheck, TlsTransport};
+use joinswap::transport::{Framing, ListenAddr, MakerAddress, TcpTransport, UnixTransport};
+
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "adversarial")]
+use joinswap::adversarial;
 
 #[tokio::main]
 async fn main() {
-    let socket = TcpStream::connect("127.0.0.1:8080").await.unwrap();
-    let (reader, writer) = split(socket);
-    let reader = BufReader::new(reader);
-    println!("CONNECT TO MAKER 👉👈\n");
-
-    // Later, a new pair of writer/reader will be pushed into these vectors to communicate with the
-    // maker using different identities (second part of a regular CoinJoin)
-    let mut writer = vec![writer];
-    let mut reader = vec![reader];
-
-    let (prv_key1, pub_key1) = gen_key_pair();
-    let (prv_key2, pub_key2) = gen_key_pair();
-    let (prv_key3, pub_key3) = gen_key_pair();
-
-    let (user_wallet, _, _) = get_funded_wallet(&get_descriptors());
-    let (my_utxo, refund) = send_user_data(
-        &user_wallet, &pub_key1, &pub_key2, &pub_key3,
-        &mut writer[0]).await;
-
-    println!("User data ----------------------------> Maker\n");
-    println!("CONTRACT CREATION 🐸\n");
-
-    let (keys, hash) = read_contract_data(&mut reader[0]).await;
-    let mut funding_psbt = read_psbt(&mut reader[0], None).await;
-    let mut refund_psbt = read_psbt(&mut reader[0], None).await;
-
-    println!("Contract data <------------------------ Maker");
-    println!("Funding and Refund Tx <---------------- Maker\n");
-
-    // There should be no duplicate keys and my keys should appear once in each policy path
-    check_contract_keys(&keys, &pub_key1, &pub_key2, &pub_key3);
-
-    let users2maker_desc_str = users2maker_contract_desc(&keys, hash);
-    let users2maker_desc = Descriptor::<PublicKey>::from_str(&users2maker_desc_str).unwrap();
-    println!("Users-to-maker contract address:\n{}\n",
-             users2maker_desc.address(Network::Regtest).unwrap());
-
-    // Ensure the funding and refund psbts are correctly formed
-    check_psbts(&funding_psbt, &refund_psbt, &users2maker_desc, my_utxo, &refund);
-
-    // The refund tx spends from the contract, so to sign it we use our contract private keys
-    let users2maker_prv_desc = users2maker_desc_str
-        .replace(&pub_key1.to_string(), &prv_key1.to_string())
-        .replace(&pub_key2.to_string(), &prv_key2.to_string())
-        .replace(&pub_key3.to_string(), &prv_key3.to_string());
-
-    let prv_wallet = Wallet::new(
-        &users2maker_prv_desc,
-        None,
-        Network::Regtest,
-        MemoryDatabase::new(),
-    ).unwrap();
-
-    let sign_ops = SignOptions { trust_witness_utxo: true, ..Default::default() };
-    sign_and_send_psbt(&mut refund_psbt, &prv_wallet, sign_ops, &mut writer).await;
-    println!("Signed Refund PSBTs ------------------> Maker");
-
-    let _refund_final = read_psbt(&mut reader[0], Some(refund_psbt.unsigned_tx.txid())).await;
-    // Here we should verify the refund tx is valid and can be mined
-    println!("Finalized Refund Tx <------------------ Maker\n");
-
-    // Now that we have the finalized refund tx that is valid after a relative timelock we can sign
-    // the funding tx without risk of losing the funds
-    sign_and_send_psbt(&mut funding_psbt, &user_wallet, SignOptions::default(), &mut writer).await;
-    println!("Signed Funding PSBTs -----------------> Maker");
-
-    let _funding_final = read_psbt(&mut reader[0], Some(funding_psbt.unsigned_tx.txid())).await;
-    println!("Finalized Funding Tx <----------------- Maker\n");
-
-    // Here we should wait the funding tx to be mined, or broadcast it ourselves
-    println!("Broadcast Funding Tx\n");
-
-    // Connect to the maker with a different ID for the second leg of the JoinSwap
-    let socket = TcpStream::connect("127.0.0.1:8080").await.unwrap();
-    let (reader_new, writer_new) = split(socket);
-    let reader_new = BufReader::new(reader_new);
-    println!("CONNECT TO MAKER (NEW ID) 👉👈\n");
-
-    writer.push(writer_new);
-    reader.push(reader_new);
-
-    let (prv_key4, pub_key4) = gen_key_pair();
-    let (_prv_key5, pub_key5) = gen_key_pair();
-
-    // Note that we use writer[1] to write to the maker with the new ID
-    send_second_user_data(&pub_key4, &pub_key5, &mut writer[1]).await;
-    println!("User data ------------NEW-ID----------> Maker\n");
-
-    println!("SECOND CONTRACT CREATION 🐸\n");
-    // Read maker pub keys and txid and derive the maker2user contract descriptor
-    let ((maker_key1, maker_key2), _txid) = read_second_contract_data(&mut reader[1]).await;
-    println!("Maker2user contract + TxID <---NEW-ID-- Maker\n");
-
-    let maker2user_desc_str = maker2users_contract_desc(
-        &[pub_key4, maker_key1],
-        &maker_key2,
-        &pub_key5,
-        hash,
-    );
-    let maker2user_desc = Descriptor::<PublicKey>::from_str(&maker2user_desc_str).unwrap();
-    println!("Maker-to-user contract address:\n{}\n",
-             maker2user_desc.address(Network::Regtest).unwrap());
-
-    // Fetch the maker2user tx from the blockchain using the txid and check it has an output that
-    // matches the descriptor spk with the correct balance
-    println!("Fetch maker-to-user transaction\n");
-
-    // If the previous step was successful, send the hashlock path private key from the users2maker
-    // contract to the maker. If all users agree that maker funded correctly the maker2users
-    // contracts then maker will have all the hashlock path keys, and so will be able to spend the
-    // first contract coins by revealing the preimage.
-
-    // This private key must be sent with the old ID (such that the two IDs remain unlinked)
-    send_prv_key(&prv_key3, &mut writer[0]).await;
-    println!("PRIVATE KEYS HANDOVER 😎🤝😎\n");
-    println!("Users2maker hashlock path PrvKey -----> Maker");
-
-    // Read preimage + maker2user contract prv key and check them
-    // If correct, users can now redeem the maker2user contract coins
-    let (preimage, maker_prv_key) = read_preimage_and_prv_key(&mut reader[1]).await;
-    println!("Maker2user contract PrvKey <---NEW-ID-- Maker");
-
-    assert_eq!(sha256::Hash::hash(&preimage), hash);
-    check_prv_keys(&vec![maker_prv_key], vec![maker_key1]);
-
-    // User can now spend from:
-    let _maker2user_prv_desc = maker2user_desc_str
-        .replace(&pub_key4.to_string(), &prv_key4.to_string())
-        .replace(&maker_key1.to_string(), &maker_prv_key.to_string());
-
-    // Send users2maker contract key (with old ID)
-    send_prv_key(&prv_key1, &mut writer[0]).await;
-    println!("Users2maker contract PrvKey ----------> Maker");
-
-    println!("\nSuccesful JoinSwap! 🙈");
+    #[cfg(feature = "adversarial")]
+    let scenario = adversarial::from_args().or_exit("Invalid --adversarial");
+    #[cfg(feature = "adversarial")]
+    if let Some(scenario) = scenario {
+        println!("ADVERSARIAL MODE: {scenario} 😈\n");
+    }
+
+    // With `--inspect-recovery <file>` we only tell what a recovery file lets us claim, and when
+    if let Some(path) = arg_value("--inspect-recovery") {
+        let recovery = read_recovery(Path::new(&path)).or_exit("Can't read the recovery file");
+        recovery.claims().iter().for_each(|claim| println!("{claim}"));
+        return;
+    }
+    // With `--watch-refund <file>` we only look after the users2maker contract of that recovery file, through
+    // our backend, and broadcast its refund once the timelock expires unless the swap completed
+    if let Some(path) = arg_value("--watch-refund") {
+        let Recovery::User(record) = read_recovery(Path::new(&path)).or_exit("Can't read the recovery file") else {
+            exit_with("--watch-refund takes the recovery file of a user session");
+        };
+        let chain = chain_backend().or_exit("--watch-refund needs --electrum, --esplora or --bitcoind");
+        watch_refund(&record, &chain, on_ctrl_c()).await;
+        return;
+    }
+
+    // With `--dump-psbts <dir>` the funding and refund PSBTs we get are saved in base64 (BIP-174) to
+    // `funding.psbt` and `refund.psbt`, or printed if the dir is `-`
+    let (events, receiver) = EventSink::channel();
+    let printer = tokio::spawn(print_events(receiver, arg_value("--dump-psbts")));
+
+    // With `--invoice-hash <hash>` the maker pays our invoice as part of the swap
+    let mut config = UserConfig {
+        #[cfg(feature = "adversarial")]
+        adversarial: scenario,
+        ..Default::default()
+    };
+    if let Some(hash) = arg_value("--invoice-hash") {
+        config.hash_source = HashSource::Invoice(sha256::Hash::from_str(&hash).or_exit("Invalid --invoice-hash"));
+    }
+    // With `--ln-payout <bolt11>,<payment hash>` we take the second leg through Lightning
+    if let Some(payout) = arg_value("--ln-payout") {
+        let (bolt11, hash) = payout.split_once(',').or_exit("--ln-payout must be <bolt11>,<payment hash>");
+        let hash = sha256::Hash::from_str(hash).or_exit("Invalid --ln-payout hash");
+        config.lightning_payout = Some((bolt11.to_string(), hash));
+    }
+    // With `--users <n>` we join rounds of n users, the maker must run rounds of that size too
+    if let Some(users) = arg_value("--users") {
+        config.round_users = users.parse().or_exit("--users must be a number");
+    }
+    // With `--max-wait <secs>` we leave if our round doesn't fill up in that time, instead of 10 minutes
+    if let Some(secs) = arg_value("--max-wait") {
+        config.max_wait = Duration::from_secs(secs.parse().or_exit("--max-wait must be a number"));
+    }
+    // With `--contribute <sats>` we only put that much in the contract and get the rest back as change,
+    // which with `--fold-dust` may go to the contract too if it's dust
+    if let Some(sats) = arg_value("--contribute") {
+        config.contribution = Some(sats.parse().or_exit("--contribute must be a number"));
+    }
+    config.fold_dust_change = std::env::args().any(|arg| arg == "--fold-dust");
+    // With `--max-feerate <sat/vB>` we leave makers whose funding or refund tx would pay more, instead of 50
+    if let Some(rate) = arg_value("--max-feerate") {
+        let rate: f32 = rate.parse().or_exit("--max-feerate must be a number");
+        if rate < config.fee_rates.min.as_sat_per_vb() {
+            exit_with("--max-feerate must be at least 1 sat/vB");
+        }
+        config.fee_rates.max = FeeRate::from_sat_per_vb(rate);
+    }
+    // With `--max-fee-ppm <ppm>` we leave makers that would keep more than ppm millionths of what we put in
+    if let Some(ppm) = arg_value("--max-fee-ppm") {
+        config.max_fee_ppm = Some(ppm.parse().or_exit("--max-fee-ppm must be a number"));
+    }
+    // With `--lowest-thresholds <multisig>,<timelock>,<hashlock>` we accept users2maker contract paths that
+    // need that many participants instead of all
+    if let Some(thresholds) = arg_value("--lowest-thresholds") {
+        config.lowest_thresholds = Some(PathThresholds::from_str(&thresholds).or_exit("Invalid --lowest-thresholds"));
+    }
+    // With `--min-refund-timelock <blocks>`, `--max-refund-timelock <blocks>` and `--claim-window <blocks>` we
+    // take other contract timelocks than 24 to 1008 blocks for the refund, and 12 more for the maker2user contract
+    if let Some(blocks) = arg_value("--min-refund-timelock") {
+        config.timelock_bounds.min_refund = blocks.parse().or_exit("--min-refund-timelock must be a number");
+    }
+    if let Some(blocks) = arg_value("--max-refund-timelock") {
+        config.timelock_bounds.max_refund = blocks.parse().or_exit("--max-refund-timelock must be a number");
+    }
+    if let Some(blocks) = arg_value("--claim-window") {
+        config.timelock_bounds.claim_window = blocks.parse().or_exit("--claim-window must be a number");
+    }
+    // With `--denominated` we join rounds where every user puts in and gets the same, keeping the rest of what
+    // we bring (or `--contribute`) as change
+    config.denominated = std::env::args().any(|arg| arg == "--denominated");
+    // With `--taproot` we join rounds with taproot contracts, the maker must run with `--taproot` too
+    config.taproot = std::env::args().any(|arg| arg == "--taproot");
+    // With `--no-funding-bumps` we don't let the maker replace a funding tx that doesn't confirm
+    config.funding_bumps = !std::env::args().any(|arg| arg == "--no-funding-bumps");
+    // With `--anyone-can-pay` we sign our funding inputs with SIGHASH_ALL|ANYONECANPAY in rounds where everyone
+    // does, and with `--top-up-feerate <sat/vB>` we then add a spare utxo to a funding tx paying less
+    config.anyone_can_pay = std::env::args().any(|arg| arg == "--anyone-can-pay");
+    if let Some(rate) = arg_value("--top-up-feerate") {
+        let rate: f32 = rate.parse().or_exit("--top-up-feerate must be a number");
+        config.top_up_rate = Some(FeeRate::from_sat_per_vb(rate));
+    }
+    // With `--coin-selection bnb` we look for utxos that need no change first, instead of taking the
+    // largest ones
+    if let Some(algorithm) = arg_value("--coin-selection") {
+        config.coin_selection = match algorithm.as_str() {
+            "largest-first" => CoinSelection::LargestFirst,
+            "bnb" => CoinSelection::BranchAndBound,
+            _ => exit_with("--coin-selection must be largest-first or bnb"),
+        };
+    }
+    // With `--line-framing` we talk to a maker that still delimits messages with newlines
+    if std::env::args().any(|arg| arg == "--line-framing") {
+        config.framing = Framing::Line;
+    }
+    // With `--base64-psbts` we offer standard base64 PSBTs, used with peers that offer them too
+    if std::env::args().any(|arg| arg == "--base64-psbts") {
+        config.psbt_encoding = PsbtEncoding::Base64;
+    }
+    // The users2maker contract is shown before we sign its refund, and with `--confirm` we only sign it
+    // once we say so
+    let confirm = std::env::args().any(|arg| arg == "--confirm");
+    config.review_contract = Some(ContractReview::new(move |paths| review_contract(paths, confirm)));
+    // With `--no-rematch` we leave when another user declines our round, instead of waiting for another one
+    config.rematch = !std::env::args().any(|arg| arg == "--no-rematch");
+    // Our recovery file is saved to `--state-dir <dir>` (or here) before every step we can't undo, and
+    // once more if we stop on Ctrl-C
+    config.shutdown = on_ctrl_c();
+    let state_dir = arg_value("--state-dir").unwrap_or_else(|| ".".to_string());
+    config.state_dir = Some(state_dir.clone().into());
+    // Our contract keys derive from the seed of our wallet, under a session index counted in the state dir, so
+    // the seed and our recovery file are enough to find them again. With `--random-contract-keys` they are
+    // random, and only the recovery file keeps them.
+    let (user_wallet, chain, seed) = user_wallet(config.network);
+    let has_backend = ["--electrum", "--esplora", "--bitcoind"].into_iter().any(|name| arg_value(name).is_some());
+    // With a backend we wait `--maker2user-wait <secs>` (an hour by default) for it to see the maker2user tx with
+    // `--maker2user-confirmations <n>` (1 by default), and leave the maker if it doesn't
+    if has_backend {
+        let secs = arg_value("--maker2user-wait")
+            .map_or(60 * 60, |secs| secs.parse().or_exit("--maker2user-wait must be a number"));
+        config.maker2user_wait = Some(Duration::from_secs(secs));
+        if let Some(confirmations) = arg_value("--maker2user-confirmations") {
+            config.maker2user_confirmations =
+                confirmations.parse().or_exit("--maker2user-confirmations must be a number");
+        }
+    }
+    if !std::env::args().any(|arg| arg == "--random-contract-keys") {
+        let seed = seed.or_exit("A backend needs --contract-xprv, or --random-contract-keys");
+        let keys = KeyChain::new(seed, state_dir.clone().into()).or_exit("Can't read the contract key sessions");
+        config.contract_keys = ContractKeySource::Derived(keys);
+    }
+
+    // With `--maker <pub key>@<host>:<port>` we swap with that maker instead of the demo one. Over TLS
+    // (`--tls-pin <cert sha256>` or `--tls-name <dns name>`) the address is just `<host>:<port>`. The
+    // host can be an onion address, reached through the `--proxy` of Tor. A maker on this host can also
+    // be reached through its Unix domain socket, with `<pub key>@unix:<path>`.
+    let maker = arg_value("--maker");
+    // With `--proxy <host>:<port>` we connect through that SOCKS5 proxy (like Tor at 127.0.0.1:9050), each
+    // identity on its own circuit
+    let proxy = arg_value("--proxy");
+    let tls_check = match (arg_value("--tls-pin"), arg_value("--tls-name")) {
+        (Some(pin), _) => Some(CertCheck::Pinned(sha256::Hash::from_str(&pin).or_exit("Invalid --tls-pin"))),
+        (None, Some(name)) => Some(CertCheck::WebPki(name)),
+        (None, None) => None,
+    };
+    // With a backend, unless `--no-refund-watch`, we stay after a failed swap to broadcast the refund once its
+    // timelock expires, as `--watch-refund` does
+    let watch_chain = (has_backend && !std::env::args().any(|arg| arg == "--no-refund-watch")).then(|| chain.clone());
+    let shutdown = config.shutdown.clone();
+    let result = match tls_check {
+        Some(check) => {
+            let addr = maker.unwrap_or_else(|| "127.0.0.1:8080".to_string());
+            let mut transport = TlsTransport::new(addr, check).or_exit("Can't set up TLS");
+            if let Some(proxy) = proxy {
+                transport = transport.with_proxy(proxy);
+            }
+
+            run_user_session(config, user_wallet, chain, transport, events.clone()).await
+        },
+        None => {
+            let maker = match maker {
+                Some(address) => MakerAddress::from_str(&address).or_exit("Invalid --maker"),
+                None => MakerAddress {
+                    key: PublicKey::from_secret_key(&Secp256k1::new(), &demo_maker_key()),
+                    addr: "127.0.0.1:8080".to_string(),
+                },
+            };
+
+            match ListenAddr::from_str(&maker.addr).or_exit("Invalid --maker") {
+                ListenAddr::Unix(path) => {
+                    if proxy.is_some() {
+                        exit_with("Unix sockets can't be reached through a proxy");
+                    }
+                    let transport = UnixTransport::new(path, maker.key);
+
+                    run_user_session(config, user_wallet, chain, transport, events.clone()).await
+                },
+                ListenAddr::Tcp(_) => {
+                    let mut transport = TcpTransport::new(maker);
+                    if let Some(proxy) = proxy {
+                        transport = transport.with_proxy(proxy);
+                    }
+
+                    run_user_session(config, user_wallet, chain, transport, events.clone()).await
+                },
+            }
+        },
+    };
+
+    let shut_down = matches!(result, Err(JoinSwapError::Shutdown { .. }));
+    let refunded = match &result {
+        Ok(UserSwapReport { payout: UserPayout::Refund { .. }, funding_txid, .. }) => Some(*funding_txid),
+        _ => None,
+    };
+    match result {
+        Ok(UserSwapReport { payout: UserPayout::Refund { tx, ladder, reason }, .. }) => {
+            let mut reason = format!("{reason}, refund tx {} can be broadcast once its timelock expires", tx.txid());
+            if !ladder.is_empty() {
+                let txids: Vec<_> = ladder.iter().map(|tx| tx.txid().to_string()).collect();
+                reason += &format!(" (or {}, paying higher fees)", txids.join(", "));
+            }
+            events.emit(ProtocolEvent::Aborted { code: AbortCode::Refunded, reason })
+        },
+        Ok(_) => events.emit(ProtocolEvent::Completed { profit: None }),
+        Err(JoinSwapError::PsbtChecks(failures)) => {
+            let failures: Vec<_> = failures.iter().map(|failure| format!("\n - {failure}")).collect();
+            events.emit(ProtocolEvent::Aborted {
+                code: AbortCode::InvalidPsbts,
+                reason: format!("Invalid PSBTs from the maker:{}", failures.concat()),
+            });
+        },
+        Err(e) => events.emit(ProtocolEvent::Aborted { code: e.abort_code(), reason: e.to_string() }),
+    }
+    drop(events);
+    printer.await.unwrap();
+
+    if let (Some(funding_txid), Some(chain)) = (refunded, watch_chain) {
+        match find_user_recovery(Path::new(&state_dir), funding_txid) {
+            Some(record) => watch_refund(&record, &chain, shutdown).await,
+            None => println!("\nNo recovery file of {funding_txid} in {state_dir} to watch its refund"),
+        }
+    }
+    if shut_down {
+        std::process::exit(SHUTDOWN_EXIT_CODE);
+    }
 }
 
-async fn read_preimage_and_prv_key(
-    reader: &mut BufReader<ReadHalf<TcpStream>>
-) -> ([u8; 32], PrivateKey) {
-    let preimage_str = read_message(reader).await;
-    let preimage: [u8; 32] = serde_json::from_str(preimage_str.trim()).unwrap();
-
-    let prv_key_str = read_message(reader).await;
-    let prv_key = PrivateKey::from_str(prv_key_str.trim()).unwrap();
-
-    (preimage, prv_key)
+// Looks after the users2maker contract of `record` every `--watch-poll <secs>` (a minute by default) until its
+// refund confirms, the maker takes the coins or `shutdown` is cancelled
+async fn watch_refund(record: &UserRecovery, chain: &Arc<dyn ChainAccess>, shutdown: CancellationToken) {
+    let poll = arg_value("--watch-poll")
+        .map_or(WATCH_POLL, |secs| Duration::from_secs(secs.parse().or_exit("--watch-poll must be a number")));
+    let watch = RefundWatch::new(record).or_exit("Invalid recovery file");
+    println!("\nWatching the users-to-maker contract of {} to broadcast its refund\n", record.funding_txid);
+
+    let (events, receiver) = EventSink::channel();
+    let printer = tokio::spawn(print_events(receiver, None));
+    let outcome = tokio::select! {
+        outcome = watch.run(chain, poll, &events) => Some(outcome),
+        _ = shutdown.cancelled() => None,
+    };
+    drop(events);
+    printer.await.unwrap();
+
+    match outcome {
+        Some(Ok(WatchOutcome::Refunded { txid })) => println!("Refund tx {txid} confirmed 🐸"),
+        Some(Ok(WatchOutcome::StoodDown)) => println!("Nothing to refund, the swap completed or the maker took it"),
+        Some(Err(e)) => println!("Stopped watching the refund: {e}"),
+        None => println!("Stopped watching the refund, `--watch-refund <recovery file>` goes on"),
+    }
 }
 
-async fn send_prv_key(key: &PrivateKey, writer: &mut WriteHalf<TcpStream>) {
-    send_message(format!("{}", key), writer).await;
+// The recovery file in `dir` of the session whose funding tx is `funding_txid`, or was before a replacement
+fn find_user_recovery(dir: &Path, funding_txid: Txid) -> Option<UserRecovery> {
+    let entries = std::fs::read_dir(dir).ok()?;
+    let recoveries = entries.flatten().filter_map(|entry| read_recovery(&entry.path()).ok());
+
+    recoveries
+        .filter_map(|recovery| match recovery {
+            Recovery::User(record) => Some(*record),
+            Recovery::Maker(_) => None,
+        })
+        .find(|record| {
+            record.funding_txid == funding_txid || record.replaced.iter().any(|old| old.funding_txid == funding_txid)
+        })
 }
 
-async fn read_second_contract_data(
-    reader: &mut BufReader<ReadHalf<TcpStream>>
-) -> ((PublicKey, PublicKey), Txid) {
-    let maker_keys = read_contract_keys(reader, 2).await;
-
-    let txid_str = read_message(reader).await;
-    let txid = Txid::from_str(txid_str.trim()).unwrap();
-    assert_ne!(maker_keys[0], maker_keys[1]);
-
-    ((maker_keys[0], maker_keys[1]), txid)
+async fn print_events(mut receiver: UnboundedReceiver<ProtocolEvent>, psbt_dump: Option<String>) {
+    while let Some(event) = receiver.recv().await {
+        match event {
+            ProtocolEvent::PhaseEntered(phase) => match phase {
+                Phase::Connect => println!("CONNECT TO MAKER 👉👈\n"),
+                Phase::ContractCreation => println!("CONTRACT CREATION 🐸\n"),
+                Phase::SecondConnect => println!("CONNECT TO MAKER (NEW ID) 👉👈\n"),
+                Phase::SecondContractCreation => println!("SECOND CONTRACT CREATION 🐸\n"),
+                Phase::Handover => println!("PRIVATE KEYS HANDOVER 😎🤝😎\n"),
+            },
+            ProtocolEvent::OfferReceived(offer) => println!(
+                "Maker offer: {} to {} sats, fee {}, {} confirmations\n",
+                offer.min_amount, offer.max_amount, offer.fee, offer.confirmations,
+            ),
+            ProtocolEvent::WaitingForUsers { missing } => {
+                println!("Waiting for the round to fill up ({missing} missing) ⏳\n");
+            },
+            ProtocolEvent::MessageSent(kind) => match kind {
+                MessageKind::UserData => println!("User data ----------------------------> Maker\n"),
+                MessageKind::SignedRefund => println!("Signed Refund PSBTs ------------------> Maker"),
+                MessageKind::SignedFunding => println!("Signed Funding PSBTs -----------------> Maker"),
+                MessageKind::SecondUserData => println!("User data ------------NEW-ID----------> Maker\n"),
+                MessageKind::HashlockKey => println!("Users2maker hashlock path PrvKey -----> Maker"),
+                MessageKind::ContractKey => println!("Users2maker contract PrvKey ----------> Maker"),
+                MessageKind::AddInput => println!("Added funding input -----------------> Maker"),
+                _ => {},
+            },
+            ProtocolEvent::MessageReceived(kind) => match kind {
+                MessageKind::ContractData => println!("Contract data <------------------------ Maker"),
+                MessageKind::FundingAndRefund => println!("Funding and Refund Tx <---------------- Maker\n"),
+                MessageKind::FinalizedRefund => println!("Finalized Refund Tx <------------------ Maker\n"),
+                MessageKind::FinalizedFunding => println!("Finalized Funding Tx <----------------- Maker\n"),
+                MessageKind::FundingBump => println!("Funding Tx Replacement <--------------- Maker\n"),
+                MessageKind::InputAdded => println!("Funding Tx with added inputs <--------- Maker\n"),
+                MessageKind::SecondContractData => println!("Maker2user contract + TxID <---NEW-ID-- Maker\n"),
+                MessageKind::LightningPayout => println!("Lightning payment proof <-----NEW-ID-- Maker\n"),
+                MessageKind::PreimageAndKey => println!("Maker2user contract PrvKey <---NEW-ID-- Maker"),
+                _ => {},
+            },
+            ProtocolEvent::SessionResumed { .. } => println!("Resumed session after disconnect <---- Maker"),
+            ProtocolEvent::Rematched { .. } => println!("Another user declined, waiting for a new round <---- Maker"),
+            ProtocolEvent::ContractCreated { contract, address } => match contract {
+                ContractKind::Users2Maker => println!("Users-to-maker contract address:\n{address}\n"),
+                ContractKind::Maker2User(_) => println!("Maker-to-user contract address:\n{address}\n"),
+            },
+            ProtocolEvent::PsbtReceived { role, psbt, .. } => if let Some(dir) = &psbt_dump {
+                let name = match role {
+                    PsbtRole::Funding => "funding",
+                    PsbtRole::Refund => "refund",
+                };
+                if dir == "-" {
+                    println!("{name} PSBT: {psbt}\n");
+                } else {
+                    let path = std::path::Path::new(dir).join(format!("{name}.psbt"));
+                    // A bad dir loses the dump, not the swap
+                    if let Err(e) = std::fs::write(&path, psbt.to_string()) {
+                        eprintln!("Can't write the PSBT dump to {}: {e}\n", path.display());
+                    }
+                }
+            },
+            ProtocolEvent::Broadcast { role: TxRole::Funding, .. } => println!("Broadcast Funding Tx\n"),
+            ProtocolEvent::Broadcast { role: TxRole::Maker2UserSweep, txid } => {
+                println!("Broadcast maker-to-user contract sweep {txid}\n");
+            },
+            ProtocolEvent::AwaitingConfirmation { txid, have, need } => {
+                println!("Transaction {txid}: {have} of {need} confirmations\n");
+            },
+            ProtocolEvent::Broadcast { role: TxRole::Refund, txid } => println!("Broadcast refund tx {txid}\n"),
+            ProtocolEvent::Completed { .. } => println!("\nSuccesful JoinSwap! 🙈"),
+            ProtocolEvent::Aborted { reason, .. } => println!("\nJoinSwap aborted: {reason}"),
+            _ => {},
+        }
+    }
 }
 
-// This fn should also take the contract value in the future
-async fn send_second_user_data(
-    key1: &PublicKey,
-    key2: &PublicKey,
-    writer: &mut WriteHalf<TcpStream>,
-) {
-    send_message(format!("{},{}", key1, key2), writer).await;
-}
+fn review_contract(paths: &[String], confirm: bool) -> bool {
+    println!("Users-to-maker contract, spendable by:");
+    paths.iter().for_each(|path| println!(" - {path}"));
+    println!();
+    if !confirm {
+        return true;
+    }
 
-async fn send_user_data(
-    wallet: &Wallet<AnyDatabase>,
-    key1: &PublicKey,
-    key2: &PublicKey,
-    key3: &PublicKey,
-    writer: &mut WriteHalf<TcpStream>,
-) -> (LocalUtxo, Address) {
-    send_message(format!("{},{},{}", key1, key2, key3), writer).await;
-    // We only use the first utxo from the wallet and spent fully for now
-    let my_utxo = send_utxo_data(&wallet, writer).await;
-    let refund = wallet.get_address(AddressIndex::New).unwrap().address;
-    send_message(refund.to_string(), writer).await;
+    print!("Sign its refund and lock our coins in it? [y/N] ");
+    std::io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).unwrap();
 
-    (my_utxo, refund)
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
-async fn read_contract_data(
-    reader: &mut BufReader<ReadHalf<TcpStream>>
-) -> ([PublicKey; 9], sha256::Hash) {
-    let keys = read_contract_keys(reader, 9).await;
-    let keys_array = [keys[0], keys[1], keys[2], keys[3], keys[4], keys[5], keys[6], keys[7], keys[8]];
-
-    let hash_str = read_message(reader).await;
-    let hash = sha256::Hash::from_str(&hash_str.trim()).unwrap();
-
-    (keys_array, hash)
+// With `--electrum <url>` (or `--esplora <url>`, or `--bitcoind <url>`) our wallet is that of `--descriptor <desc>`
+// (and `--change-descriptor <desc>`), kept in the `--wallet-db <path>` sled database or in memory and synced from the
+// backend, which also broadcasts our txs and finds the maker's. Our contract keys then derive from
+// `--contract-xprv <xprv>`. Without it the demo wallet holds made up coins, from the seed it returns, and nothing
+// leaves this process.
+fn user_wallet(network: Network) -> (Wallet<AnyDatabase>, Arc<dyn ChainAccess>, Option<ExtendedPrivKey>) {
+    let Some(chain) = chain_backend() else {
+        // With `--utxos <n>` the 50k sats of our demo wallet are split in n utxos, or with `--utxo-values
+        // <sats>,...` it holds those. We bring them all unless we `--contribute` less.
+        let utxos: u64 = arg_value("--utxos").map_or(1, |utxos| utxos.parse().or_exit("--utxos must be a number"));
+        let values = arg_value("--utxo-values").map_or(vec![50_000 / utxos; utxos as usize], |values| {
+            values.split(',').map(|value| value.parse().or_exit("--utxo-values must be numbers")).collect()
+        });
+        let seed = demo_seed();
+        let database = AnyDatabase::Memory(MemoryDatabase::new());
+        let wallet = Wallet::new(&get_descriptors(&seed), None, network, database).unwrap();
+        let chain = MemoryChain::default();
+        chain.fund(&wallet.get_address(AddressIndex::New).unwrap().script_pubkey(), &values);
+        chain.sync_wallet(&wallet).unwrap();
+
+        return (wallet, Arc::new(chain), Some(seed));
+    };
+    let seed = arg_value("--contract-xprv")
+        .map(|xprv| ExtendedPrivKey::from_str(&xprv).or_exit("Invalid --contract-xprv"));
+    let descriptor = arg_value("--descriptor").or_exit("A backend needs --descriptor");
+    let change_descriptor = arg_value("--change-descriptor");
+    let database = match arg_value("--wallet-db") {
+        Some(path) => {
+            let tree = sled::open(path).and_then(|db| db.open_tree("user")).or_exit("Can't open --wallet-db");
+            AnyDatabase::Sled(tree)
+        },
+        None => AnyDatabase::Memory(MemoryDatabase::new()),
+    };
+    let wallet = Wallet::new(&descriptor, change_descriptor.as_ref(), network, database);
+    let wallet = wallet.or_exit("Invalid --descriptor");
+    chain.sync_wallet(&wallet).or_exit("Can't sync the wallet");
+
+    (wallet, chain, seed)
 }
 
-async fn send_utxo_data(wallet: &Wallet<AnyDatabase>, writer: &mut WriteHalf<TcpStream>) -> LocalUtxo {
-    let utxos = wallet.list_unspent().unwrap();
-
-    // We fully spend one utxo for now
-    let outpoint = utxos[0].outpoint;
-
-    let psbt_in = wallet
-        .get_psbt_input(utxos[0].clone(), None, false)
-        .unwrap();
-    let psbt_in_serialized = serde_json::to_string(&psbt_in).unwrap();
-
-    // Find the concrete descriptor of our utxo
-    let pub_desc = wallet.public_descriptor(KeychainKind::External).unwrap().unwrap();
-    let (_, desc) = pub_desc.find_derivation_index_for_spk(
-        &Secp256k1::new(),
-        &utxos[0].txout.script_pubkey,
-        0..1,
-    ).unwrap().unwrap();
-
-    send_message(desc.to_string(), writer).await;
-    send_message(outpoint.to_string(), writer).await;
-    send_message(psbt_in_serialized, writer).await;
-
-    utxos[0].clone()
+// The backend of `--electrum <url>`, `--esplora <url>` or `--bitcoind <url>`, if any. We log in to bitcoind with its
+// `--bitcoind-cookie <path>`, or `--bitcoind-auth <user>:<pass>`.
+fn chain_backend() -> Option<Arc<dyn ChainAccess>> {
+    let (backend, url) = ["electrum", "esplora", "bitcoind"].into_iter()
+        .find_map(|name| Some((name, arg_value(&format!("--{name}"))?)))?;
+    let rpc_auth = match (arg_value("--bitcoind-cookie"), arg_value("--bitcoind-auth")) {
+        (Some(path), _) => Some(RpcAuth::Cookie(path.into())),
+        (None, Some(auth)) => {
+            let (user, password) = auth.split_once(':').or_exit("--bitcoind-auth must be <user>:<password>");
+            Some(RpcAuth::UserPass { user: user.to_string(), password: password.to_string() })
+        },
+        (None, None) => None,
+    };
+
+    Some(connect_backend(backend, &url, rpc_auth).or_exit(&format!("Can't connect to --{backend}")).chain)
 }
 
-// Check that all keys are different and that my respective key appears only once per policy path
-fn check_contract_keys(
-    keys: &[PublicKey; 9],
-    my_key1: &PublicKey,
-    my_key2: &PublicKey,
-    my_key3: &PublicKey,
-) {
-    assert_eq!(keys.len(), keys.iter().collect::<HashSet<_>>().len());
+fn arg_value(name: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    let i = args.iter().position(|arg| arg == name)?;
 
-    assert_eq!(keys[0..3].iter().filter(|&key| key == my_key1).count(), 1);
-    assert_eq!(keys[3..6].iter().filter(|&key| key == my_key2).count(), 1);
-    assert_eq!(keys[6..9].iter().filter(|&key| key == my_key3).count(), 1);
+    Some(args.get(i + 1).or_exit(&format!("{name} needs a value")).clone())
 }
-
-// Check that funding and refund transactions are properly constructed
-// (As of now funding tx must have only one output):
-
-// 1. The spk of the funding utxo must match the contract descriptor's
-// 2. Fee must be lower than 420 (to be changed in the future with RBF or something)
-// 3. My utxo must be included in the inputs once
-// 4. Total input value minus funding tx fee must match the output value
-// 5. Refund tx input must only be the funding utxo
-// 6. Refund tx must spend from the relative timelocked path (actually I don't know how to do that,
-// but we can enforce the relative timelock anyway)
-// 7. Refund tx must include my address once
-// 8. Finally my address must receive initial_amount - (funding_fee + refund_fee)/users
-fn check_psbts(
-    funding: &Psbt,
-    refund: &Psbt,
-    desc: &Descriptor<PublicKey>,
-    my_utxo: LocalUtxo,
-    refund_addr: &Address,
-) {
-    // 1)
-    assert_eq!(funding.unsigned_tx.output[0].script_pubkey, desc.script_pubkey());
-
-    // 2)
-    let funding_fee = funding.fee_amount().unwrap();
-    assert!(funding_fee < 420);
-
-    // for each input of the funding tx, get the prev output (OutPoint)
-    let prevouts = funding.unsigned_tx.input
-        .iter()
-        .map(|txin| txin.previous_output);
-
-    // 3)
-    let my_utxo_outpoint: Vec<_> = prevouts.clone()
-        .filter(|prevout| *prevout == my_utxo.outpoint)
-        .collect();
-    assert_eq!(my_utxo_outpoint.len(), 1);
-
-    // for each input, index the output of the specific tx to get the utxo value
-    let input_values = funding.inputs
-        .iter()
-        .zip(prevouts)
-        .map(|(input, prevout)| {
-            let vout = prevout.vout as usize;
-            input.non_witness_utxo.as_ref().unwrap().output[vout].value
-        });
-
-    // 4)
-    let total_input_value: u64 = input_values.sum();
-    assert_eq!(total_input_value - funding_fee, funding.unsigned_tx.output[0].value);
-
-    // 5)
-    let funding_outpoint = OutPoint { txid: funding.unsigned_tx.txid(), vout: 0 };
-    assert_eq!(refund.inputs.len(), 1);
-    assert_eq!(refund.unsigned_tx.input[0].previous_output, funding_outpoint);
-
-    // 6)
-    assert_eq!(refund.unsigned_tx.version, 2);
-    assert_eq!(refund.unsigned_tx.input[0].sequence, Sequence::from_height(48));
-
-    // 7)
-    let my_txout: Vec<_> = refund.unsigned_tx.output.iter().filter(|txout| {
-        txout.script_pubkey == refund_addr.script_pubkey()
-    }).collect();
-    assert_eq!(my_txout.len(), 1);
-
-    // 8)
-    let users = refund.outputs.iter().count() as u64;
-    assert_eq!(refund.fee_amount().unwrap(), 1000);
-    let refund_amount = my_utxo.txout.value - (&funding_fee + 1000)/users;
-    assert_eq!(my_txout[0].value, refund_amount);
-}
\ No newline at end of file
@@ -0,0 +1,310 @@
+// Broadcasting the refund of a user session on its own. A user holds a finalized refund tx from before
+// it signs the funding tx, but it's only valid once its timelock expires, long after a failed swap ended.
+// A `RefundWatch` looks after the users2maker contract until then, from the session's recovery file.
+
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bdk::bitcoin::psbt::Psbt;
+use bdk::bitcoin::secp256k1::Secp256k1;
+use bdk::bitcoin::{OutPoint, PublicKey, Script, Transaction, Txid};
+use bdk::descriptor::Descriptor;
+
+use crate::chain::{chain_call, BroadcastError, ChainAccess, ChainError};
+use crate::events::{EventSink, ProtocolEvent, TxRole};
+use crate::recovery::{LadderRefund, ReplacedFunding, UserRecovery};
+
+// How often the binaries look at the chain while watching a contract
+pub const WATCH_POLL: Duration = Duration::from_secs(60);
+
+// Blocks a refund may wait unconfirmed before we broadcast the next pricier one
+pub const REFUND_BUMP_BLOCKS: u32 = 6;
+
+// The contract of a user session, and the refunds to broadcast once its timelock expires
+#[derive(Debug)]
+pub struct RefundWatch {
+    contract_spk: Script,
+    timelock: u32,
+    // The funding tx of the session and those the maker replaced, as any of them may confirm
+    fundings: Vec<WatchedFunding>,
+    // We handed over our multisig key, so the maker can take the coins and there's nothing to refund
+    completed: bool,
+}
+
+#[derive(Debug)]
+struct WatchedFunding {
+    txid: Txid,
+    // The contract output, which all its refunds spend
+    outpoint: OutPoint,
+    // The cheapest first
+    refunds: Vec<Transaction>,
+}
+
+// How watching a contract ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchOutcome {
+    // One of our refunds confirmed
+    Refunded { txid: Txid },
+    // The swap completed or the maker took the coins, so we stood down
+    StoodDown,
+}
+
+impl RefundWatch {
+    // From the recovery file of a user session, see `recovery::read_recovery`
+    pub fn new(record: &UserRecovery) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let desc = Descriptor::<PublicKey>::from_str(&record.users2maker_desc).map_err(|e| invalid(e.to_string()))?;
+        let multisig_key = record.contract_keys[0].public_key(&Secp256k1::new());
+
+        let current = (record.funding_txid, &record.refund_psbt, &record.refund_ladder);
+        let replaced = record.replaced.iter().map(|ReplacedFunding { funding_txid, refund_psbt, refund_ladder, .. }| {
+            (*funding_txid, refund_psbt, refund_ladder)
+        });
+        let mut fundings = Vec::new();
+        for (txid, refund_psbt, ladder) in [current].into_iter().chain(replaced) {
+            let psbts = [refund_psbt].into_iter().chain(ladder.iter().map(|LadderRefund { psbt, .. }| psbt));
+            // Saved finalized, so their txs are ready to broadcast
+            let refunds = psbts.map(|psbt| Psbt::from_str(psbt).map(Psbt::extract_tx))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| invalid(format!("Invalid refund PSBT: {e}")))?;
+            let outpoint = refunds[0].input.first().map(|txin| txin.previous_output)
+                .ok_or_else(|| invalid("A refund tx without inputs".to_string()))?;
+            fundings.push(WatchedFunding { txid, outpoint, refunds });
+        }
+
+        Ok(RefundWatch {
+            contract_spk: desc.script_pubkey(),
+            timelock: record.timelocks.refund.into(),
+            fundings,
+            completed: record.handed_over.contains(&multisig_key),
+        })
+    }
+
+    // Watches the contract every `poll` until one of our refunds confirms or the coins go elsewhere. Once
+    // the funding tx has the confirmations of the timelock we broadcast the cheapest refund, and the next
+    // one if the mempool rejects it or it doesn't confirm within `REFUND_BUMP_BLOCKS`.
+    pub async fn run(
+        &self,
+        chain: &Arc<dyn ChainAccess>,
+        poll: Duration,
+        events: &EventSink,
+    ) -> Result<WatchOutcome, ChainError> {
+        if self.completed {
+            return Ok(WatchOutcome::StoodDown);
+        }
+        let mut told = None;
+        // Index of the last refund we broadcast, and the tip height then
+        let mut broadcast: Option<(usize, u32)> = None;
+
+        loop {
+            if let Some((funding, have)) = self.confirmed_funding(chain).await? {
+                // Told up to the timelock, after that they no longer matter
+                let shown = have.min(self.timelock);
+                if told != Some(shown) {
+                    let txid = funding.txid;
+                    events.emit(ProtocolEvent::AwaitingConfirmation { txid, have: shown, need: self.timelock });
+                    told = Some(shown);
+                }
+                for refund in &funding.refunds {
+                    let (txid, script_pubkey) = (refund.txid(), refund.output[0].script_pubkey.clone());
+                    if chain_call(chain, move |chain| chain.confirmations(&txid, &script_pubkey)).await? > 0 {
+                        return Ok(WatchOutcome::Refunded { txid });
+                    }
+                }
+                // Spent by anything but our refunds, a cooperative spend or the maker's hashlock path
+                let (outpoint, contract_spk) = (funding.outpoint, self.contract_spk.clone());
+                if chain_call(chain, move |chain| chain.spent(&outpoint, &contract_spk)).await? {
+                    let mut ours = false;
+                    for refund in &funding.refunds {
+                        let txid = refund.txid();
+                        ours |= chain_call(chain, move |chain| chain.get_tx(&txid)).await?.is_some();
+                    }
+                    if !ours {
+                        return Ok(WatchOutcome::StoodDown);
+                    }
+                }
+
+                if have >= self.timelock {
+                    let tip = chain_call(chain, |chain| chain.tip_height()).await?;
+                    let next = match broadcast {
+                        None => Some(0),
+                        Some((last, at)) if tip >= at + REFUND_BUMP_BLOCKS => Some(last + 1),
+                        Some(_) => None,
+                    };
+                    // A refund the mempool rejects, as fees went up, makes way for the next one. If we can't
+                    // reach the backend we try the same one again at the next poll.
+                    for (index, refund) in funding.refunds.iter().enumerate().skip(next.unwrap_or(usize::MAX)) {
+                        let tx = refund.clone();
+                        match chain_call(chain, move |chain| chain.broadcast(&tx)).await {
+                            Ok(()) => {
+                                events.emit(ProtocolEvent::Broadcast { role: TxRole::Refund, txid: refund.txid() });
+                                broadcast = Some((index, tip));
+                                break;
+                            },
+                            Err(BroadcastError::Rejected(_)) => {},
+                            Err(BroadcastError::Chain(_)) => break,
+                        }
+                    }
+                }
+            }
+            tokio::time::sleep(poll).await;
+        }
+    }
+
+    // The funding tx that confirmed, if any, with its confirmations
+    async fn confirmed_funding(
+        &self,
+        chain: &Arc<dyn ChainAccess>,
+    ) -> Result<Option<(&WatchedFunding, u32)>, ChainError> {
+        for funding in &self.fundings {
+            let (txid, contract_spk) = (funding.txid, self.contract_spk.clone());
+            let have = chain_call(chain, move |chain| chain.confirmations(&txid, &contract_spk)).await?;
+            if have > 0 {
+                return Ok(Some((funding, have)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use bdk::bitcoin::{PackedLockTime, TxIn, TxOut};
+    use bdk::database::AnyDatabase;
+    use bdk::Wallet;
+
+    use super::*;
+
+    const TIMELOCK: u32 = 10;
+
+    // A chain where the funding tx has its timelock of confirmations, and broadcasts answer in turn what
+    // `answers` says: `None` for a backend we can't reach, `Some(accepted)` otherwise. The contract output
+    // is `spent` by something else than our refunds, or not at all.
+    struct ScriptedChain {
+        answers: Mutex<VecDeque<Option<bool>>>,
+        attempts: Mutex<Vec<Txid>>,
+        accepted: Mutex<Option<Txid>>,
+        spent: bool,
+    }
+
+    impl ScriptedChain {
+        fn new(answers: &[Option<bool>], spent: bool) -> Self {
+            ScriptedChain {
+                answers: Mutex::new(answers.iter().copied().collect()),
+                attempts: Mutex::new(Vec::new()),
+                accepted: Mutex::new(None),
+                spent,
+            }
+        }
+    }
+
+    impl ChainAccess for ScriptedChain {
+        fn broadcast(&self, tx: &Transaction) -> Result<(), BroadcastError> {
+            self.attempts.lock().unwrap().push(tx.txid());
+            match self.answers.lock().unwrap().pop_front().expect("An unexpected broadcast") {
+                Some(true) => {
+                    *self.accepted.lock().unwrap() = Some(tx.txid());
+                    Ok(())
+                },
+                Some(false) => Err(BroadcastError::Rejected("min relay fee not met".to_string())),
+                None => Err(BroadcastError::Chain(ChainError("Connection refused".to_string()))),
+            }
+        }
+
+        fn get_tx(&self, _txid: &Txid) -> Result<Option<Transaction>, ChainError> {
+            Ok(None)
+        }
+
+        fn tip_height(&self) -> Result<u32, ChainError> {
+            Ok(1000)
+        }
+
+        fn confirmations(&self, txid: &Txid, _script_pubkey: &Script) -> Result<u32, ChainError> {
+            match *self.accepted.lock().unwrap() == Some(*txid) {
+                true => Ok(1),
+                false => Ok(if *txid == funding_txid() { TIMELOCK } else { 0 }),
+            }
+        }
+
+        fn spent(&self, _outpoint: &OutPoint, _script_pubkey: &Script) -> Result<bool, ChainError> {
+            Ok(self.spent)
+        }
+
+        fn sync_wallet(&self, _wallet: &Wallet<AnyDatabase>) -> Result<(), ChainError> {
+            Ok(())
+        }
+    }
+
+    fn funding_txid() -> Txid {
+        Txid::from_str("0101010101010101010101010101010101010101010101010101010101010101").unwrap()
+    }
+
+    // The cheapest refund first, paying less as the fee goes up
+    fn watch() -> RefundWatch {
+        let outpoint = OutPoint { txid: funding_txid(), vout: 0 };
+        let refunds = [99_000, 98_000, 97_000].map(|value| Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn { previous_output: outpoint, ..Default::default() }],
+            output: vec![TxOut { value, script_pubkey: Script::new_op_return(&[]) }],
+        });
+        let fundings = vec![WatchedFunding { txid: funding_txid(), outpoint, refunds: refunds.to_vec() }];
+
+        RefundWatch { contract_spk: Script::new(), timelock: TIMELOCK, fundings, completed: false }
+    }
+
+    // The refund that confirmed, and the refunds each broadcast tried
+    async fn run(answers: &[Option<bool>]) -> (Txid, Vec<Txid>) {
+        let scripted = Arc::new(ScriptedChain::new(answers, false));
+        let chain: Arc<dyn ChainAccess> = scripted.clone();
+        let outcome = watch().run(&chain, Duration::from_millis(1), &EventSink::none()).await.unwrap();
+        let WatchOutcome::Refunded { txid } = outcome else { panic!("We didn't refund") };
+
+        let attempts = scripted.attempts.lock().unwrap().clone();
+        (txid, attempts)
+    }
+
+    #[tokio::test]
+    async fn backend_errors_retry_the_same_refund() {
+        let refunds: Vec<_> = watch().fundings[0].refunds.iter().map(Transaction::txid).collect();
+
+        let (refunded, attempts) = run(&[None, None, Some(true)]).await;
+        assert_eq!(refunded, refunds[0]);
+        assert_eq!(attempts, [refunds[0]; 3]);
+    }
+
+    #[tokio::test]
+    async fn rejections_move_up_the_ladder() {
+        let refunds: Vec<_> = watch().fundings[0].refunds.iter().map(Transaction::txid).collect();
+
+        let (refunded, attempts) = run(&[Some(false), Some(false), Some(true)]).await;
+        assert_eq!(refunded, refunds[2]);
+        assert_eq!(attempts, refunds);
+
+        // A backend error halfway up the ladder retries where it stopped, after the refunds rejected again
+        let (refunded, attempts) = run(&[Some(false), None, Some(false), Some(true)]).await;
+        assert_eq!(refunded, refunds[1]);
+        assert_eq!(attempts, [refunds[0], refunds[1], refunds[0], refunds[1]]);
+    }
+
+    // A contract spent otherwise, as by the cooperative sweep, leaves nothing to refund, even past the timelock
+    #[tokio::test]
+    async fn cooperative_spends_stand_down() {
+        let scripted = Arc::new(ScriptedChain::new(&[], true));
+        let chain: Arc<dyn ChainAccess> = scripted.clone();
+        let outcome = watch().run(&chain, Duration::from_millis(1), &EventSink::none()).await.unwrap();
+        assert_eq!(outcome, WatchOutcome::StoodDown);
+        assert!(scripted.attempts.lock().unwrap().is_empty());
+
+        // A session that handed over its multisig key doesn't look at the chain at all
+        let completed = RefundWatch { completed: true, ..watch() };
+        let chain: Arc<dyn ChainAccess> = Arc::new(ScriptedChain::new(&[], false));
+        let outcome = completed.run(&chain, Duration::from_millis(1), &EventSink::none()).await.unwrap();
+        assert_eq!(outcome, WatchOutcome::StoodDown);
+    }
+}
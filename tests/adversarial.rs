@@ -0,0 +1,161 @@
+// Rounds where one user misbehaves on purpose (see `joinswap::adversarial`). The maker must catch each
+// scenario with the check it maps to, tell the honest user per the abort rules, and keep serving rounds.
+#![cfg(feature = "adversarial")]
+
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bdk::bitcoin::secp256k1::{PublicKey, Secp256k1};
+use bdk::bitcoin::util::bip32::ExtendedPrivKey;
+use bdk::bitcoin::Network;
+use joinswap::adversarial::Scenario;
+use joinswap::chain::MemoryChain;
+use joinswap::error::{JoinSwapError, ProtocolError};
+use joinswap::events::{AbortCode, Leg};
+use joinswap::keys::{derive_contract_keys, ContractKeySource, KeyChain, KeyRole};
+use joinswap::protocol::maker::{MakerConfig, RoundStatus, SweepPath};
+use joinswap::protocol::user::{UserConfig, UserPayout, UserSwapReport};
+use joinswap::transport::memory_transport;
+
+use common::{funded_wallet, run_round_on, treasury};
+
+// The honest user's contract keys derive from this seed, so the adversary can copy one
+fn honest_seed() -> ExtendedPrivKey {
+    ExtendedPrivKey::new_master(Network::Regtest, &[5; 32]).unwrap()
+}
+
+// The abort code of the maker dropping `user`
+fn rejection(user: &Result<UserSwapReport, JoinSwapError>) -> Option<AbortCode> {
+    match user {
+        Err(JoinSwapError::Protocol(ProtocolError::Rejected { code, .. })) => Some(*code),
+        _ => None,
+    }
+}
+
+fn paid_on_chain(user: &Result<UserSwapReport, JoinSwapError>) -> bool {
+    matches!(user, Ok(UserSwapReport { payout: UserPayout::OnChain { .. }, .. }))
+}
+
+// Plays a round with an honest user and one playing `scenario`, then another with two honest users
+async fn against(scenario: Scenario) {
+    let state_dir = std::env::temp_dir().join(format!("joinswap-adversarial-{scenario}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&state_dir);
+    let chain = Arc::new(MemoryChain::default());
+    let mut maker_config = MakerConfig::default();
+    maker_config.timeouts.exchange = Duration::from_secs(3);
+    maker_config.timeouts.signing = Duration::from_secs(3);
+    maker_config.timeouts.second_leg = Duration::from_secs(3);
+    let maker_key = PublicKey::from_secret_key(&Secp256k1::new(), maker_config.noise_key.as_ref().unwrap());
+    let (transport, acceptor) = memory_transport(maker_key);
+    let treasury = treasury(&chain);
+
+    let adversary = UserConfig { adversarial: Some(scenario), ..Default::default() };
+    let honest = UserConfig {
+        contract_keys: ContractKeySource::Derived(KeyChain::new(honest_seed(), state_dir.clone()).unwrap()),
+        ..Default::default()
+    };
+    let users = vec![(adversary, funded_wallet(&chain, 1, &[50_000])), (honest, funded_wallet(&chain, 2, &[60_000]))];
+    let round = run_round_on(&transport, &acceptor, &treasury, maker_config.clone(), users, chain.clone());
+    let (maker, users) = round.await;
+    let [adversary, honest] = <[_; 2]>::try_from(users).unwrap();
+
+    match (scenario.caught_by(), maker) {
+        (None, Ok(report)) => {
+            assert_eq!(report.status, RoundStatus::Completed);
+            let hashlock = matches!(report.sweep, SweepPath::Hashlock { .. });
+            assert_eq!(hashlock, scenario == Scenario::WithheldContractKey, "{:?}", report.sweep);
+            assert!(paid_on_chain(&adversary) && paid_on_chain(&honest));
+        },
+        // Once the funding tx is out every user takes its refund
+        (Some(_), Ok(report)) if scenario.caught_after_funding() => {
+            let aborted = matches!(
+                report.status,
+                RoundStatus::PeerAborted { leg: Leg::First, code: AbortCode::PeerMisbehaved, .. }
+            );
+            assert!(aborted, "{:?}", report.status);
+            assert_eq!(report.sweep, SweepPath::Refund);
+            for user in [adversary, honest] {
+                assert!(matches!(user, Ok(UserSwapReport { payout: UserPayout::Refund { .. }, .. })));
+            }
+        },
+        // Before it nothing is at stake: the user at fault is told why, the other that the round is over
+        (Some(check), Err(JoinSwapError::Peer { leg: Leg::First, error, .. })) if !scenario.caught_after_funding() => {
+            assert!(check.matches(&error), "{scenario} should fail the {check:?} check, not: {error}");
+            // The user at fault and the others are told the code of what the maker caught. The maker can't
+            // tell which user copied the key of the other, so it may be the honest one it drops.
+            let code = error.abort_code();
+            match error {
+                // The silent user never reads why
+                ProtocolError::Timeout(_) => {
+                    assert_eq!(code, AbortCode::PeerGone);
+                    assert!(matches!(adversary, Err(JoinSwapError::Protocol(ProtocolError::Eof))));
+                    assert_eq!(rejection(&honest), Some(code));
+                },
+                _ => {
+                    assert_eq!(code, AbortCode::PeerMisbehaved);
+                    assert_eq!((rejection(&adversary), rejection(&honest)), (Some(code), Some(code)), "{honest:?}");
+                },
+            }
+        },
+        (check, maker) => panic!("{scenario} should fail the {check:?} check, the maker got: {maker:?}"),
+    }
+
+    // The maker goes on to serve the next round out of the same wallet
+    let users = vec![
+        (UserConfig::default(), funded_wallet(&chain, 3, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 4, &[60_000])),
+    ];
+    let (maker, users) = run_round_on(&transport, &acceptor, &treasury, maker_config, users, chain).await;
+    assert_eq!(maker.unwrap().status, RoundStatus::Completed);
+    assert!(users.iter().all(paid_on_chain));
+    let _ = fs::remove_dir_all(state_dir);
+}
+
+#[tokio::test]
+async fn uncompressed_key() {
+    against(Scenario::UncompressedKey).await;
+}
+
+#[tokio::test]
+async fn duplicate_key() {
+    let honest_key = derive_contract_keys(&honest_seed(), 0, KeyRole::Users2Maker(0)).1;
+    against(Scenario::DuplicateKey(honest_key)).await;
+}
+
+#[tokio::test]
+async fn unsigned_refund() {
+    against(Scenario::UnsignedRefund).await;
+}
+
+#[tokio::test]
+async fn sighash_none_refund() {
+    against(Scenario::SighashNoneRefund).await;
+}
+
+#[tokio::test]
+async fn inflated_utxo() {
+    against(Scenario::InflatedUtxo).await;
+}
+
+#[tokio::test]
+async fn silent_after_contract() {
+    against(Scenario::SilentAfterContract).await;
+}
+
+#[tokio::test]
+async fn double_funding_sig() {
+    against(Scenario::DoubleFundingSig).await;
+}
+
+#[tokio::test]
+async fn reconnect_before_funding() {
+    against(Scenario::ReconnectBeforeFunding).await;
+}
+
+#[tokio::test]
+async fn withheld_contract_key() {
+    against(Scenario::WithheldContractKey).await;
+}
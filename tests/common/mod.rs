@@ -0,0 +1,180 @@
+// Whole rounds in one process: a maker and its users over in-memory transports, sharing a chain where
+// every broadcast tx is there at once
+// Each test crate only uses some of these
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bdk::bitcoin::util::bip32::ExtendedPrivKey;
+use bdk::bitcoin::Network;
+use bdk::database::{AnyDatabase, MemoryDatabase};
+use bdk::wallet::AddressIndex;
+use bdk::Wallet;
+use futures::future::join_all;
+use joinswap::chain::{ChainAccess, MemoryChain};
+use joinswap::error::JoinSwapError;
+use joinswap::events::{EventSink, ProtocolEvent};
+use joinswap::get_descriptors;
+use joinswap::lightning::MakerLightning;
+use joinswap::protocol::maker::{accept_gated, answer_hello, run_maker_round, MakerConfig, MakerRoundReport,
+                                MakerTreasury, RoundUser};
+use joinswap::protocol::rounds::{Arrival, RoundRouter};
+use joinswap::protocol::user::{run_user_session, UserConfig, UserSwapReport};
+use joinswap::transport::{Acceptor, Transport};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+// Longest a whole round may take, so a round that hangs fails the test instead
+pub const ROUND_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A demo wallet of the seed made of `seed` bytes, holding `values`
+pub fn funded_wallet(chain: &MemoryChain, seed: u8, values: &[u64]) -> Wallet<AnyDatabase> {
+    let xprv = ExtendedPrivKey::new_master(Network::Regtest, &[seed; 32]).unwrap();
+    let database = AnyDatabase::Memory(MemoryDatabase::new());
+    let wallet = Wallet::new(&get_descriptors(&xprv), None, Network::Regtest, database).unwrap();
+    chain.fund(&wallet.get_address(AddressIndex::New).unwrap().script_pubkey(), values);
+    chain.sync_wallet(&wallet).unwrap();
+
+    wallet
+}
+
+pub fn treasury(chain: &MemoryChain) -> MakerTreasury {
+    MakerTreasury::new(funded_wallet(chain, 0xaa, &[100_000; 10]))
+}
+
+// Runs one round as the maker with the first users that join through `acceptor`, routing the second
+// leg and resumed connections to it meanwhile, as the maker binary does
+pub async fn serve_round<A: Acceptor>(
+    acceptor: &A,
+    config: MakerConfig,
+    treasury: &MakerTreasury,
+    chain: Arc<dyn ChainAccess>,
+    (lightning, events): (MakerLightning, EventSink),
+) -> Result<MakerRoundReport, JoinSwapError>
+where
+    A::Stream: 'static,
+{
+    let router = RoundRouter::new();
+    let mut peers = Vec::new();
+    let mut slots = Vec::new();
+    while peers.len() < config.round_users {
+        let (mut conn, slot) = accept_gated(acceptor, &config).await.unwrap();
+        assert_eq!(answer_hello(&mut conn, &config, &router).await.unwrap(), Arrival::Join);
+        peers.push(RoundUser::from(conn));
+        slots.push(slot);
+    }
+
+    let inbox = router.inbox();
+    let round = run_maker_round(config.clone(), treasury, chain, lightning, peers, inbox, events);
+    let routing = async {
+        loop {
+            let (mut conn, slot) = accept_gated(acceptor, &config).await.unwrap();
+            if let Ok(arrival) = answer_hello(&mut conn, &config, &router).await {
+                router.route(arrival, (conn, slot));
+            }
+        }
+    };
+    tokio::select! {
+        report = round => report,
+        _ = routing => unreachable!(),
+    }
+}
+
+// Runs a round of the maker with a user of each wallet, and what each side reported
+pub async fn run_round<T: Transport + Clone, A: Acceptor>(
+    (transport, acceptor): (T, A),
+    maker_config: MakerConfig,
+    users: Vec<(UserConfig, Wallet<AnyDatabase>)>,
+    chain: Arc<MemoryChain>,
+) -> (Result<MakerRoundReport, JoinSwapError>, Vec<Result<UserSwapReport, JoinSwapError>>)
+where
+    A::Stream: 'static,
+{
+    run_round_on(&transport, &acceptor, &treasury(&chain), maker_config, users, chain).await
+}
+
+// The same with a maker that serves more rounds on `acceptor`, out of `treasury`
+pub async fn run_round_on<T: Transport + Clone, A: Acceptor>(
+    transport: &T,
+    acceptor: &A,
+    treasury: &MakerTreasury,
+    maker_config: MakerConfig,
+    users: Vec<(UserConfig, Wallet<AnyDatabase>)>,
+    chain: Arc<MemoryChain>,
+) -> (Result<MakerRoundReport, JoinSwapError>, Vec<Result<UserSwapReport, JoinSwapError>>)
+where
+    A::Stream: 'static,
+{
+    let users = users.into_iter().map(|(config, wallet)| (config, wallet, EventSink::none())).collect();
+    let maker = (maker_config, MakerLightning::default(), EventSink::none());
+    play_round((transport, acceptor), treasury, maker, users, chain).await
+}
+
+// `run_round` with a maker that pays Lightning payouts and learns invoice preimages through `lightning`
+pub async fn run_round_with_lightning<T: Transport + Clone, A: Acceptor>(
+    (transport, acceptor): (T, A),
+    (maker_config, lightning): (MakerConfig, MakerLightning),
+    users: Vec<(UserConfig, Wallet<AnyDatabase>)>,
+    chain: Arc<MemoryChain>,
+) -> (Result<MakerRoundReport, JoinSwapError>, Vec<Result<UserSwapReport, JoinSwapError>>)
+where
+    A::Stream: 'static,
+{
+    let users = users.into_iter().map(|(config, wallet)| (config, wallet, EventSink::none())).collect();
+    let maker = (maker_config, lightning, EventSink::none());
+    play_round((&transport, &acceptor), &treasury(&chain), maker, users, chain).await
+}
+
+// Events the maker and each user emitted during a round, in order
+pub struct RoundEvents {
+    pub maker: Vec<ProtocolEvent>,
+    pub users: Vec<Vec<ProtocolEvent>>,
+}
+
+// `run_round` collecting the events of every side
+pub async fn run_round_with_events<T: Transport + Clone, A: Acceptor>(
+    (transport, acceptor): (T, A),
+    maker_config: MakerConfig,
+    users: Vec<(UserConfig, Wallet<AnyDatabase>)>,
+    chain: Arc<MemoryChain>,
+) -> (Result<MakerRoundReport, JoinSwapError>, Vec<Result<UserSwapReport, JoinSwapError>>, RoundEvents)
+where
+    A::Stream: 'static,
+{
+    let (maker_events, mut maker_receiver) = EventSink::channel();
+    let mut user_receivers = Vec::new();
+    let users = users.into_iter().map(|(config, wallet)| {
+        let (events, receiver) = EventSink::channel();
+        user_receivers.push(receiver);
+        (config, wallet, events)
+    }).collect();
+
+    let treasury = treasury(&chain);
+    let maker = (maker_config, MakerLightning::default(), maker_events);
+    let (maker, users) = play_round((&transport, &acceptor), &treasury, maker, users, chain).await;
+    // The sinks are gone with the round, so the receivers hold every event
+    let drain = |receiver: &mut UnboundedReceiver<ProtocolEvent>| std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+    let events = RoundEvents {
+        maker: drain(&mut maker_receiver),
+        users: user_receivers.iter_mut().map(drain).collect(),
+    };
+    (maker, users, events)
+}
+
+async fn play_round<T: Transport + Clone, A: Acceptor>(
+    (transport, acceptor): (&T, &A),
+    treasury: &MakerTreasury,
+    (maker_config, lightning, maker_events): (MakerConfig, MakerLightning, EventSink),
+    users: Vec<(UserConfig, Wallet<AnyDatabase>, EventSink)>,
+    chain: Arc<MemoryChain>,
+) -> (Result<MakerRoundReport, JoinSwapError>, Vec<Result<UserSwapReport, JoinSwapError>>)
+where
+    A::Stream: 'static,
+{
+    let maker = serve_round(acceptor, maker_config, treasury, chain.clone(), (lightning, maker_events));
+    let users = join_all(users.into_iter().map(|(config, wallet, events)| {
+        run_user_session(config, wallet, chain.clone(), transport.clone(), events)
+    }));
+
+    tokio::time::timeout(ROUND_TIMEOUT, async { tokio::join!(maker, users) }).await.expect("The round hung")
+}
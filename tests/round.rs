@@ -0,0 +1,1953 @@
+// A whole round run through the library API, with the maker and two users in one process
+
+mod common;
+
+use std::collections::HashSet;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bdk::bitcoin::hashes::hex::FromHex;
+use bdk::bitcoin::hashes::{hash160, sha256, Hash};
+use bdk::bitcoin::psbt::Psbt;
+use bdk::bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bdk::bitcoin::util::sighash::Prevouts;
+use bdk::bitcoin::{Network, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Txid};
+use bdk::database::AnyDatabase;
+use bdk::descriptor::Descriptor;
+use bdk::miniscript::interpreter::{HashLockType, Interpreter, SatisfiedConstraint};
+use bdk::miniscript::ForEachKey;
+use bdk::wallet::AddressIndex;
+use bdk::{FeeRate, Wallet};
+use futures::future::join_all;
+use joinswap::chain::{BroadcastError, ChainAccess, ChainError, FeeEstimator, MemoryChain};
+use joinswap::error::{JoinSwapError, ProtocolError, UtxoError};
+use joinswap::events::{AbortCode, ContractKind, EventSink, Leg, MessageKind, Phase, ProtocolEvent, PsbtRole, TxRole};
+use joinswap::lightning::{HashSource, MakerLightning, MemoryLnBackend, PreimageError, PreimageSource};
+use joinswap::limits::{ConnectionGate, ConnectionLimits, Refusal};
+use joinswap::message::{expect_msg, read_msg, send_error, send_msg, Hello, PsbtEncoding, MAX_MESSAGE_SIZE,
+                        MAX_PSBT_SIZE, PROTOCOL_VERSION};
+use joinswap::noise;
+use joinswap::protocol::maker::{accept_gated, answer_hello, run_maker_round, send_waiting, DenominationMode,
+                                MakerConfig, MakerRoundReport, RoundStatus, RoundUser, SweepPath, NO_ROUND};
+use joinswap::protocol::rounds::{Arrival, RoundRouter};
+use joinswap::protocol::user::{run_user_session, ContractReview, UserConfig, UserPayout, UserSwapReport};
+use joinswap::recovery::{read_recovery, Recovery, UserRecovery};
+use joinswap::shutdown::save_state;
+use joinswap::transport::{bind_unix, memory_transport, Acceptor, Connection, Framing, MakerAddress,
+                          MemoryAcceptor, MemoryTransport, TcpTransport, Transport, UnixTransport};
+use joinswap::watchtower::{RefundWatch, WatchOutcome};
+use joinswap::{add_contract_signers, build_hashlock_spend, build_multisig_sweep, contract_wallet, HashKind, MakerFee,
+                PathThresholds, TimelockBounds, Timelocks};
+use tokio::io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+use common::{funded_wallet, run_round, run_round_on, run_round_with_events, run_round_with_lightning, serve_round,
+             treasury, ROUND_TIMEOUT};
+
+fn transports(config: &MakerConfig) -> (MemoryTransport, MemoryAcceptor) {
+    memory_transport(PublicKey::from_secret_key(&Secp256k1::new(), config.noise_key.as_ref().unwrap()))
+}
+
+#[tokio::test]
+async fn round_over_memory_transport() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let users = vec![
+        (UserConfig::default(), funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    assert!(matches!(maker.sweep, SweepPath::Cooperative { .. }));
+
+    for user in users {
+        let user = user.unwrap();
+        assert_eq!((user.funding_txid, user.hash), (maker.funding_txid, maker.hash));
+        let UserPayout::OnChain { txid, sweep: Some(sweep), .. } = user.payout else {
+            panic!("Expected an on-chain payout we swept, got {:?}", user.payout);
+        };
+        assert!(maker.maker2user_txids.contains(&txid));
+        assert!(chain.get_tx(&sweep).unwrap().is_some());
+    }
+}
+
+// What `tx` pays to `script_pubkey`
+fn paying(tx: &Transaction, script_pubkey: &Script) -> u64 {
+    tx.output.iter().filter(|txout| &txout.script_pubkey == script_pubkey).map(|txout| txout.value).sum()
+}
+
+// The maker report tells where every sat of the round went, as the txs on the chain show
+#[tokio::test]
+async fn round_report_accounts_for_every_sat() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let users = vec![
+        (UserConfig::default(), funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+    let user_outpoints: HashSet<_> = users.iter()
+        .flat_map(|(_, wallet)| wallet.list_unspent().unwrap())
+        .map(|utxo| utxo.outpoint)
+        .collect();
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    let maker = maker.unwrap();
+    let users: Vec<_> = users.into_iter().map(Result::unwrap).collect();
+    let tx = |txid: &Txid| chain.get_tx(txid).unwrap().expect("The round broadcast it");
+
+    assert_eq!(maker.user_outpoints.iter().copied().collect::<HashSet<_>>(), user_outpoints);
+    assert!(users.iter().all(|user| user.refund_txid == maker.refund_txid));
+
+    let locked = paying(&tx(&maker.funding_txid), &maker.users2maker_desc.script_pubkey());
+    assert_eq!(maker.total_received, locked);
+
+    // Each maker2user tx pays the contract of one user, the rest is our change and its fee
+    assert_eq!(maker.maker2user_txids.len(), users.len());
+    let paid: u64 = users.iter().map(|user| {
+        let UserPayout::OnChain { desc, txid, .. } = &user.payout else { panic!("Expected an on-chain payout") };
+        paying(&tx(txid), &desc.script_pubkey())
+    }).sum();
+    assert_eq!(maker.maker2user_amounts, paid);
+
+    let SweepPath::Cooperative { txid: sweep, fee: sweep_fee } = maker.sweep else { panic!("Expected a sweep") };
+    assert_eq!(tx(&sweep).output.iter().map(|txout| txout.value).sum::<u64>(), locked - sweep_fee);
+    assert_eq!(maker.profit, Some(locked - sweep_fee - maker.maker2user_amounts - maker.maker2user_fees));
+    assert!(maker.maker2user_claims.is_empty() && maker.lightning_payouts.is_empty());
+}
+
+// Plays a round of `wallets` that the maker must end before building the contract, and the utxo error
+// of the user it dropped along with its index. The other user is told the round is over.
+async fn rejected_utxo(chain: Arc<MemoryChain>, wallets: [Wallet<AnyDatabase>; 2]) -> (usize, UtxoError) {
+    let maker_config = MakerConfig::default();
+    let users = wallets.into_iter().map(|wallet| (UserConfig::default(), wallet)).collect();
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    let Err(JoinSwapError::Peer { leg: Leg::First, index, error: ProtocolError::Utxo(error) }) = maker else {
+        panic!("Expected a utxo error, got {maker:?}");
+    };
+    let Err(JoinSwapError::Protocol(ProtocolError::Rejected { code, reason })) = &users[1 - index] else {
+        panic!("Expected a rejection, got {:?}", users[1 - index]);
+    };
+    assert_eq!((*code, reason.as_str()), (AbortCode::PeerMisbehaved, "Another user left the round"));
+    (index, error)
+}
+
+#[tokio::test]
+async fn users_bring_the_same_utxo() {
+    let chain = Arc::new(MemoryChain::default());
+    // Two wallets of one seed, with its only utxo
+    let wallets = [funded_wallet(&chain, 1, &[50_000]), funded_wallet(&chain, 1, &[])];
+    let outpoint = wallets[0].list_unspent().unwrap()[0].outpoint;
+
+    assert_eq!(rejected_utxo(chain, wallets).await, (1, UtxoError::AlreadyInRound(outpoint)));
+}
+
+#[tokio::test]
+async fn user_brings_a_spent_utxo() {
+    let chain = Arc::new(MemoryChain::default());
+    let wallets = [funded_wallet(&chain, 1, &[50_000]), funded_wallet(&chain, 2, &[60_000])];
+    // Spent behind the back of the wallet, which still has it
+    let outpoint = wallets[1].list_unspent().unwrap()[0].outpoint;
+    let input = vec![TxIn { previous_output: outpoint, ..Default::default() }];
+    chain.broadcast(&Transaction { version: 2, lock_time: PackedLockTime(0), input, output: Vec::new() }).unwrap();
+
+    assert_eq!(rejected_utxo(chain, wallets).await, (1, UtxoError::Spent(outpoint)));
+}
+
+#[tokio::test]
+async fn users_share_an_unconfirmed_parent() {
+    let chain = Arc::new(MemoryChain::default());
+    let wallets = [funded_wallet(&chain, 1, &[]), funded_wallet(&chain, 2, &[])];
+    let output = wallets.iter()
+        .map(|wallet| wallet.get_address(AddressIndex::Peek(0)).unwrap().script_pubkey())
+        .map(|script_pubkey| TxOut { value: 50_000, script_pubkey })
+        .collect();
+    let parent = Transaction { version: 2, lock_time: PackedLockTime(0), input: Vec::new(), output };
+    chain.broadcast(&parent).unwrap();
+    chain.hold_in_mempool(parent.txid());
+    wallets.iter().for_each(|wallet| chain.sync_wallet(wallet).unwrap());
+
+    let (index, error) = rejected_utxo(chain, wallets).await;
+    assert_eq!(error, UtxoError::Unconfirmed(OutPoint { txid: parent.txid(), vout: index as u32 }));
+}
+
+// Copies `from` to `to`, keeping what went through in `seen`
+async fn relay(mut from: impl AsyncRead + Unpin, mut to: impl AsyncWrite + Unpin, seen: Arc<Mutex<Vec<u8>>>) {
+    let mut buf = [0; 4096];
+    while let Ok(n @ 1..) = from.read(&mut buf).await {
+        seen.lock().unwrap().extend_from_slice(&buf[..n]);
+        if to.write_all(&buf[..n]).await.is_err() {
+            break;
+        }
+    }
+    let _ = to.shutdown().await;
+}
+
+// The memory transport with every byte on the wire, both ways, kept for looking at
+#[derive(Clone)]
+struct TappedTransport {
+    inner: MemoryTransport,
+    seen: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Transport for TappedTransport {
+    type Stream = DuplexStream;
+
+    fn connect(&self) -> impl Future<Output = io::Result<DuplexStream>> + Send {
+        let (inner, seen) = (self.inner.clone(), self.seen.clone());
+
+        async move {
+            let (maker_read, maker_write) = split(inner.connect().await?);
+            let (ours, theirs) = duplex(64 * 1024);
+            let (user_read, user_write) = split(theirs);
+            tokio::spawn(relay(user_read, maker_write, seen.clone()));
+            tokio::spawn(relay(maker_read, user_write, seen));
+            Ok(ours)
+        }
+    }
+
+    fn maker_key(&self) -> Option<PublicKey> {
+        self.inner.maker_key()
+    }
+}
+
+// Over Noise nothing of the round shows on the wire: no message fields, hashes or txids
+#[tokio::test]
+async fn round_over_noise_shows_no_plaintext() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let users = vec![
+        (UserConfig::default(), funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+    let (inner, acceptor) = transports(&maker_config);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let transport = TappedTransport { inner, seen: seen.clone() };
+
+    let (maker, users) = run_round((transport, acceptor), maker_config, users, chain).await;
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    assert!(users.iter().all(Result::is_ok));
+
+    let seen = seen.lock().unwrap();
+    assert!(seen.len() > 10_000, "Only {} bytes went through the tap", seen.len());
+    let plaintext = [
+        "\"type\"".to_string(),
+        "\"session\"".to_string(),
+        maker.hash.to_string(),
+        maker.funding_txid.to_string(),
+        maker.refund_txid.to_string(),
+    ];
+    for text in plaintext {
+        assert!(!seen.windows(text.len()).any(|window| window == text.as_bytes()), "{text} is on the wire");
+    }
+}
+
+// The hashes a contract descriptor is locked to, in hex
+fn hashes_of(desc: &Descriptor<bdk::bitcoin::PublicKey>) -> HashSet<String> {
+    let desc = desc.to_string();
+    ["sha256(", "hash160("].iter()
+        .flat_map(|fragment| desc.split(fragment).skip(1))
+        .map(|rest| rest.split(')').next().unwrap().to_string())
+        .collect()
+}
+
+// Each maker2user contract is locked to a payout hash of its own, and to the round hash only as the fallback
+// of its hashlock path. No other contract of the round has the payout hash, so a spend showing it links
+// the contract to none.
+#[tokio::test]
+async fn maker2user_contracts_have_hashes_of_their_own() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let users = vec![
+        (UserConfig::default(), funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain).await;
+    let maker = maker.unwrap();
+    let round_hash = maker.hash.to_string();
+    assert_eq!(hashes_of(&maker.users2maker_desc), HashSet::from([round_hash.clone()]));
+
+    let mut payout_hashes = HashSet::new();
+    for user in users {
+        let user = user.unwrap();
+        assert_eq!(hashes_of(&user.users2maker_desc), HashSet::from([round_hash.clone()]));
+        let UserPayout::OnChain { desc, .. } = user.payout else { panic!("Expected an on-chain payout") };
+
+        let mut hashes = hashes_of(&desc);
+        assert!(hashes.remove(&round_hash), "The fallback is missing from {desc}");
+        assert_eq!(hashes.len(), 1, "Expected a single payout hash in {desc}");
+        payout_hashes.extend(hashes);
+    }
+    assert_eq!(payout_hashes.len(), 2);
+}
+
+// A user that doesn't approve the contract declines the round before anything is signed. The maker
+// ends the round and tells the other user, who doesn't wait for another one, and nothing is broadcast.
+#[tokio::test]
+async fn user_rejects_the_contract() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let rejecting = UserConfig { review_contract: Some(ContractReview::new(|_| false)), ..UserConfig::default() };
+    let other = UserConfig { rematch: false, ..UserConfig::default() };
+    let wallets = [funded_wallet(&chain, 1, &[50_000]), funded_wallet(&chain, 2, &[60_000])];
+    let utxos: Vec<_> = wallets.iter().flat_map(|wallet| wallet.list_unspent().unwrap()).collect();
+    let users = [rejecting, other].into_iter().zip(wallets).collect();
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    let Err(JoinSwapError::Peer { leg: Leg::First, index, error: error @ ProtocolError::Declined(_) }) = &maker else {
+        panic!("Expected the maker to see a declined round, got {maker:?}");
+    };
+    assert_eq!(error.abort_code(), AbortCode::Declined);
+    let Err(rejected) = &users[*index] else {
+        panic!("Expected the user to reject the contract");
+    };
+    assert_eq!(rejected.abort_code(), AbortCode::NotApproved);
+    assert!(matches!(&users[1 - index], Err(JoinSwapError::Protocol(ProtocolError::Rematch(_)))));
+
+    for utxo in utxos {
+        assert!(!chain.spent(&utxo.outpoint, &utxo.txout.script_pubkey).unwrap());
+    }
+}
+
+// What an event says of the progress of a round, leaving out the data that changes with every run
+#[derive(Debug, PartialEq)]
+enum Step {
+    Entered(Phase),
+    Connected(Leg),
+    Sent(MessageKind),
+    Received(MessageKind),
+    Created(ContractKind),
+    Funded(ContractKind),
+    Checked(PsbtRole),
+    Broadcast(TxRole),
+    Handover,
+}
+
+fn steps(events: &[ProtocolEvent]) -> Vec<Step> {
+    events.iter().filter_map(|event| match event {
+        ProtocolEvent::PhaseEntered(phase) => Some(Step::Entered(*phase)),
+        // Second leg peers connect in any order
+        ProtocolEvent::PeerConnected { leg, .. } => Some(Step::Connected(*leg)),
+        ProtocolEvent::MessageSent(kind) => Some(Step::Sent(*kind)),
+        ProtocolEvent::MessageReceived(kind) => Some(Step::Received(*kind)),
+        ProtocolEvent::ContractCreated { contract, .. } => Some(Step::Created(*contract)),
+        ProtocolEvent::ContractFunded { contract, .. } => Some(Step::Funded(*contract)),
+        ProtocolEvent::PsbtReceived { role, .. } => Some(Step::Checked(*role)),
+        ProtocolEvent::Broadcast { role, .. } => Some(Step::Broadcast(*role)),
+        ProtocolEvent::HandoverComplete => Some(Step::Handover),
+        // How many times a user polls for a confirmation varies
+        ProtocolEvent::AwaitingConfirmation { .. } => None,
+        event => panic!("Unexpected event in a round: {event:?}"),
+    }).collect()
+}
+
+// Users start with the offer of the maker
+fn user_steps(events: &[ProtocolEvent]) -> Vec<Step> {
+    assert!(matches!(events[..2], [ProtocolEvent::PhaseEntered(Phase::Connect), ProtocolEvent::OfferReceived(_)]));
+    steps(&events[2..])
+}
+
+#[tokio::test]
+async fn round_events_in_order() {
+    use {MessageKind::*, Step::*};
+
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let users = vec![
+        (UserConfig::default(), funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+
+    let (maker, users, events) = run_round_with_events(transports(&maker_config), maker_config, users, chain).await;
+    assert_eq!(maker.unwrap().status, RoundStatus::Completed);
+    // A refund PSBT for each rung of the fee ladder
+    let rungs = 3;
+    let refunds = (0..rungs).flat_map(|_| [Received(SignedRefund), Sent(FinalizedRefund)]);
+    let mut expected = vec![
+        Received(UserData),
+        Entered(Phase::ContractCreation),
+        Created(ContractKind::Users2Maker),
+        Sent(ContractData),
+        Sent(FundingAndRefund),
+    ];
+    expected.extend(refunds);
+    expected.extend([
+        Received(SignedFunding),
+        Sent(FinalizedFunding),
+        Broadcast(TxRole::Funding),
+        Funded(ContractKind::Users2Maker),
+        Entered(Phase::SecondConnect),
+        Connected(Leg::Second),
+        Connected(Leg::Second),
+        Received(SecondUserData),
+        Entered(Phase::SecondContractCreation),
+        Created(ContractKind::Maker2User(0)),
+        Created(ContractKind::Maker2User(1)),
+        Broadcast(TxRole::Maker2UserFunding(0)),
+        Funded(ContractKind::Maker2User(0)),
+        Broadcast(TxRole::Maker2UserFunding(1)),
+        Funded(ContractKind::Maker2User(1)),
+        Sent(SecondContractData),
+        Entered(Phase::Handover),
+        Received(HashlockKey),
+        Sent(PreimageAndKey),
+        Received(ContractKey),
+        Handover,
+        Broadcast(TxRole::Users2MakerSweep),
+    ]);
+    assert_eq!(steps(&events.maker), expected);
+
+    for (user, events) in users.iter().zip(&events.users) {
+        assert!(matches!(user, Ok(UserSwapReport { payout: UserPayout::OnChain { .. }, .. })));
+        let mut expected = vec![
+            Sent(UserData),
+            Entered(Phase::ContractCreation),
+            Received(ContractData),
+            Received(FundingAndRefund),
+            Created(ContractKind::Users2Maker),
+            Checked(PsbtRole::Funding),
+        ];
+        expected.extend((0..rungs).map(|_| Checked(PsbtRole::Refund)));
+        expected.extend((0..rungs).flat_map(|_| [Sent(SignedRefund), Received(FinalizedRefund)]));
+        expected.extend([
+            Sent(SignedFunding),
+            Received(FinalizedFunding),
+            Broadcast(TxRole::Funding),
+            Entered(Phase::SecondConnect),
+            Sent(SecondUserData),
+            Entered(Phase::SecondContractCreation),
+            Received(SecondContractData),
+            // Users only know of their own maker2user contract
+            Created(ContractKind::Maker2User(0)),
+            Entered(Phase::Handover),
+            Sent(HashlockKey),
+            Received(PreimageAndKey),
+            Broadcast(TxRole::Maker2UserSweep),
+            Sent(ContractKey),
+            Handover,
+        ]);
+        assert_eq!(user_steps(events), expected);
+    }
+}
+
+// A user rejecting the contract stops right after checking the PSBTs, and the round ends before
+// anything is broadcast
+#[tokio::test]
+async fn rejected_round_events_in_order() {
+    use {MessageKind::*, Step::*};
+
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let rejecting = UserConfig { review_contract: Some(ContractReview::new(|_| false)), ..UserConfig::default() };
+    let other = UserConfig { rematch: false, ..UserConfig::default() };
+    let wallets = [funded_wallet(&chain, 1, &[50_000]), funded_wallet(&chain, 2, &[60_000])];
+    let users = [rejecting, other].into_iter().zip(wallets).collect();
+
+    let (maker, _, events) = run_round_with_events(transports(&maker_config), maker_config, users, chain).await;
+    assert!(matches!(maker, Err(JoinSwapError::Peer { error: ProtocolError::Declined(_), .. })), "{maker:?}");
+    let contract = [
+        Entered(Phase::ContractCreation),
+        Received(ContractData),
+        Received(FundingAndRefund),
+        Created(ContractKind::Users2Maker),
+        Checked(PsbtRole::Funding),
+    ];
+    let checked = contract.into_iter().chain((0..3).map(|_| Checked(PsbtRole::Refund)));
+    let mut expected = vec![Sent(UserData)];
+    expected.extend(checked);
+    assert_eq!(user_steps(&events.users[0]), expected);
+    // The other user signed the first refund PSBT before the maker ended the round
+    expected.push(Sent(SignedRefund));
+    assert_eq!(user_steps(&events.users[1]), expected);
+
+    assert_eq!(steps(&events.maker), [
+        Received(UserData),
+        Entered(Phase::ContractCreation),
+        Created(ContractKind::Users2Maker),
+        Sent(ContractData),
+        Sent(FundingAndRefund),
+    ]);
+}
+
+// Checks the witness of each input of `tx` against the output it spends, as found on `chain`, and
+// returns what each one satisfies
+fn verified_spend(chain: &MemoryChain, tx: &Transaction) -> Vec<Vec<SatisfiedConstraint>> {
+    let prevouts: Vec<_> = tx.input.iter().map(|txin| {
+        let prev_tx = chain.get_tx(&txin.previous_output.txid).unwrap().expect("The round broadcast it");
+        prev_tx.output[txin.previous_output.vout as usize].clone()
+    }).collect();
+    let secp = Secp256k1::verification_only();
+
+    tx.input.iter().enumerate().map(|(index, txin)| {
+        let (script_pubkey, lock_time) = (&prevouts[index].script_pubkey, tx.lock_time.into());
+        let interpreter =
+            Interpreter::from_txdata(script_pubkey, &txin.script_sig, &txin.witness, txin.sequence, lock_time).unwrap();
+        interpreter.iter(&secp, tx, index, &Prevouts::All(&prevouts)).collect::<Result<_, _>>().unwrap()
+    }).collect()
+}
+
+fn taproot_round(chain: &MemoryChain) -> (MakerConfig, Vec<(UserConfig, Wallet<AnyDatabase>)>) {
+    let maker_config = MakerConfig { taproot: true, ..MakerConfig::default() };
+    let users = vec![
+        (UserConfig { taproot: true, ..UserConfig::default() }, funded_wallet(chain, 1, &[50_000])),
+        (UserConfig { taproot: true, ..UserConfig::default() }, funded_wallet(chain, 2, &[60_000])),
+    ];
+    (maker_config, users)
+}
+
+// Every contract of a taproot round pays to a taproot output, and the maker and the users sweep them
+// through the multisig paths
+#[tokio::test]
+async fn taproot_round_sweeps() {
+    let chain = Arc::new(MemoryChain::default());
+    let (maker_config, users) = taproot_round(&chain);
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    assert!(maker.users2maker_desc.script_pubkey().is_v1_p2tr());
+    let tx = |txid: &Txid| chain.get_tx(txid).unwrap().expect("The round broadcast it");
+    let multisig = |satisfied: &[SatisfiedConstraint]| {
+        satisfied.iter().all(|constraint| matches!(constraint, SatisfiedConstraint::PublicKey { .. }))
+    };
+
+    let SweepPath::Cooperative { txid, .. } = maker.sweep else { panic!("Expected a sweep, got {:?}", maker.sweep) };
+    assert!(verified_spend(&chain, &tx(&txid)).iter().all(|satisfied| multisig(satisfied)));
+    for user in users {
+        let UserPayout::OnChain { desc, sweep: Some(sweep), .. } = user.unwrap().payout else {
+            panic!("Expected an on-chain payout we swept");
+        };
+        assert!(desc.script_pubkey().is_v1_p2tr());
+        let satisfied = verified_spend(&chain, &tx(&sweep));
+        assert!(multisig(&satisfied[0]), "{satisfied:?}");
+    }
+}
+
+// A user leaving the second leg of a taproot round makes every user take the refund, which spends
+// the taproot users2maker contract through its timelock path once the timelock is over
+#[tokio::test]
+async fn taproot_round_refunds() {
+    let chain = Arc::new(MemoryChain::default());
+    let (maker_config, mut users) = taproot_round(&chain);
+    // The maker2user tx gets its first confirmation at once, but nothing else gets mined
+    users[0].0.maker2user_wait = Some(Duration::from_millis(100));
+    users[0].0.maker2user_confirmations = 2;
+    let refund_timelock = maker_config.timelocks.refund;
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    let maker = maker.unwrap();
+    assert!(matches!(maker.status, RoundStatus::PeerAborted { .. }), "{:?}", maker.status);
+    assert_eq!(maker.sweep, SweepPath::Refund);
+
+    let mut refunds = users.into_iter().map(|user| match user.unwrap().payout {
+        UserPayout::Refund { tx, .. } => *tx,
+        payout => panic!("Expected a refund, got {payout:?}"),
+    });
+    let refund = refunds.next().unwrap();
+    assert!(refunds.all(|tx| tx == refund));
+    assert_eq!(refund.txid(), maker.refund_txid);
+
+    // The interpreter checks the timelock against the sequence, and takes a signature of every participant
+    assert_eq!(refund.input[0].sequence, Sequence::from_height(refund_timelock));
+    let satisfied = verified_spend(&chain, &refund);
+    assert_eq!(satisfied[0].len(), 3, "{satisfied:?}");
+    assert!(matches!(chain.broadcast(&refund), Err(BroadcastError::Rejected(_))));
+    chain.mine(u32::from(refund_timelock));
+    chain.broadcast(&refund).unwrap();
+}
+
+// With hash160 payout hashes each maker2user contract locks its hashlock path with a hash160, and the
+// preimage the maker hands over claims it through that path
+#[tokio::test]
+async fn round_with_hash160_payout_hashes() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig { payout_hash_kind: HashKind::Hash160, ..MakerConfig::default() };
+    let state_dir = std::env::temp_dir().join(format!("joinswap-round-hash160-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&state_dir);
+    let user_dirs = [state_dir.join("user-1"), state_dir.join("user-2")];
+    let users = user_dirs.iter().zip([(1, 50_000), (2, 60_000)]).map(|(dir, (seed, value))| {
+        let config = UserConfig { state_dir: Some(dir.clone()), ..UserConfig::default() };
+        (config, funded_wallet(&chain, seed, &[value]))
+    }).collect();
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    assert_eq!(maker.unwrap().status, RoundStatus::Completed);
+
+    for (user, dir) in users.into_iter().zip(&user_dirs) {
+        let UserPayout::OnChain { desc, txid, .. } = user.unwrap().payout else {
+            panic!("Expected an on-chain payout");
+        };
+        let file = fs::read_dir(dir).unwrap().next().unwrap().unwrap().path();
+        let Recovery::User(record) = read_recovery(&file).unwrap() else { panic!("Expected a user recovery file") };
+        let preimage: [u8; 32] = Vec::from_hex(record.preimage.as_deref().unwrap()).unwrap().try_into().unwrap();
+        let [_, hashlock_key] = record.maker2user.unwrap().keys;
+
+        let funding = chain.get_tx(&txid).unwrap().unwrap();
+        let vout = funding.output.iter().position(|txout| txout.script_pubkey == desc.script_pubkey()).unwrap();
+        let (outpoint, prevout) = (OutPoint { txid, vout: vout as u32 }, funding.output[vout].clone());
+        let mut signer = contract_wallet(&desc).unwrap();
+        add_contract_signers(&mut signer, &desc, &[hashlock_key]);
+        let destination = prevout.script_pubkey.clone();
+        let fee_rate = FeeRate::from_sat_per_vb(1.0);
+        let claim = build_hashlock_spend(&signer, preimage, outpoint, prevout, destination, fee_rate, 1_000).unwrap();
+
+        // The user swept the contract already, so the claim is only checked against the script
+        let hash = hash160::Hash::hash(&preimage);
+        let revealed = SatisfiedConstraint::HashLock { hash: HashLockType::Hash160(hash), preimage };
+        assert!(verified_spend(&chain, &claim.extract_tx())[0].contains(&revealed));
+    }
+    let _ = fs::remove_dir_all(state_dir);
+}
+
+// Where user 1's multisig key comes in the users2maker contract of the round saved under `dirs`,
+// relative to user 2's
+fn user1_goes_first(dirs: &[PathBuf; 2]) -> bool {
+    let secp = Secp256k1::new();
+    let positions: Vec<_> = dirs.iter().map(|dir| {
+        let file = fs::read_dir(dir).unwrap().next().unwrap().unwrap().path();
+        let Recovery::User(record) = read_recovery(&file).unwrap() else { panic!("Expected a user recovery file") };
+        let key = record.contract_keys[0].public_key(&secp).to_string();
+        record.users2maker_desc.find(&key).unwrap()
+    }).collect();
+    positions[0] < positions[1]
+}
+
+// The maker puts the users in the contract in a random order, and the same two users get the same
+// outcome whichever of them goes first
+#[tokio::test]
+async fn round_outcome_is_the_same_in_either_order() {
+    let state_dir = std::env::temp_dir().join(format!("joinswap-round-order-{}", std::process::id()));
+    let mut outcomes = [None, None];
+    for _ in 0..32 {
+        let _ = fs::remove_dir_all(&state_dir);
+        let chain = Arc::new(MemoryChain::default());
+        let maker_config = MakerConfig::default();
+        let user_dirs = [state_dir.join("user-1"), state_dir.join("user-2")];
+        let users = user_dirs.iter().zip([(1, 50_000), (2, 60_000)]).map(|(dir, (seed, value))| {
+            let config = UserConfig { state_dir: Some(dir.clone()), ..UserConfig::default() };
+            (config, funded_wallet(&chain, seed, &[value]))
+        }).collect();
+
+        let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain).await;
+        let maker = maker.unwrap();
+        assert_eq!(maker.status, RoundStatus::Completed);
+        let outcome: Vec<_> = users.into_iter().map(|user| {
+            let user = user.unwrap();
+            assert!(matches!(user.payout, UserPayout::OnChain { .. }));
+            (user.contributed, user.refund_amount, user.funding_fee, user.refund_fee)
+        }).collect();
+
+        let slot = &mut outcomes[usize::from(!user1_goes_first(&user_dirs))];
+        assert_eq!(*slot.get_or_insert_with(|| outcome.clone()), outcome);
+        if outcomes.iter().all(Option::is_some) {
+            break;
+        }
+    }
+    let _ = fs::remove_dir_all(state_dir);
+
+    let [Some(first), Some(second)] = outcomes else { panic!("The users went in the same order every round") };
+    assert_eq!(first, second);
+}
+
+// A user recovery file written out again reads back the same, and its keys alone sign the refund and a
+// sweep of the maker2user contract through the multisig path
+#[tokio::test]
+async fn recovery_file_rebuilds_signable_contracts() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let state_dir = std::env::temp_dir().join(format!("joinswap-round-recovery-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&state_dir);
+    let config = UserConfig { state_dir: Some(state_dir.join("user-1")), ..UserConfig::default() };
+    let users = vec![
+        (config, funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+
+    let (maker, _) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    assert_eq!(maker.unwrap().status, RoundStatus::Completed);
+    let file = fs::read_dir(state_dir.join("user-1")).unwrap().next().unwrap().unwrap().path();
+    let saved = read_recovery(&file).unwrap();
+    let copy = save_state(&state_dir.join("copy"), "recovery", &saved).unwrap();
+    let Recovery::User(record) = read_recovery(&copy).unwrap() else { panic!("Expected a user recovery file") };
+    assert_eq!(fs::read_to_string(&copy).unwrap(), serde_json::to_string_pretty(&saved).unwrap());
+    let _ = fs::remove_dir_all(state_dir);
+
+    // The refund spends the funding tx with valid signatures
+    let refund = record.refund_psbt.parse::<Psbt>().unwrap().extract_tx();
+    assert_eq!(refund.txid(), record.refund_txid);
+    assert_eq!(refund.input[0].previous_output.txid, record.funding_txid);
+    assert!(!verified_spend(&chain, &refund)[0].is_empty());
+
+    // Our multisig key and the one the maker handed over sweep the maker2user contract
+    let maker2user = record.maker2user.unwrap();
+    let desc = Descriptor::<bdk::bitcoin::PublicKey>::from_str(&maker2user.desc).unwrap();
+    let funding = chain.get_tx(&maker2user.txid).unwrap().unwrap();
+    let vout = funding.output.iter().position(|txout| txout.script_pubkey == desc.script_pubkey()).unwrap();
+    let prevout = (OutPoint { txid: maker2user.txid, vout: vout as u32 }, funding.output[vout].clone());
+    let (my_keys, handed_over) = ([maker2user.keys[0]], [maker2user.maker_key.unwrap()]);
+    let fee_rate = FeeRate::from_sat_per_vb(1.0);
+    let destination = desc.script_pubkey();
+    let sweep = build_multisig_sweep(&desc, &my_keys, &handed_over, prevout, destination, fee_rate, 1_000).unwrap();
+    assert_eq!(verified_spend(&chain, &sweep)[0].len(), 2);
+}
+
+// A chain where the first tx broadcast, the funding tx of the round, gets stuck in the mempool as fees
+// go up from 1 to 5 sat/vB. Whatever replaces it confirms.
+#[derive(Debug, Default)]
+struct CongestedChain {
+    chain: MemoryChain,
+    stuck: Mutex<Option<Transaction>>,
+}
+
+impl ChainAccess for CongestedChain {
+    fn broadcast(&self, tx: &Transaction) -> Result<(), BroadcastError> {
+        self.chain.broadcast(tx)?;
+        let mut stuck = self.stuck.lock().unwrap();
+        if stuck.is_none() {
+            self.chain.hold_in_mempool(tx.txid());
+            *stuck = Some(tx.clone());
+        }
+        Ok(())
+    }
+
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, ChainError> {
+        self.chain.get_tx(txid)
+    }
+
+    fn tip_height(&self) -> Result<u32, ChainError> {
+        self.chain.tip_height()
+    }
+
+    fn confirmations(&self, txid: &Txid, script_pubkey: &Script) -> Result<u32, ChainError> {
+        self.chain.confirmations(txid, script_pubkey)
+    }
+
+    fn spent(&self, outpoint: &OutPoint, script_pubkey: &Script) -> Result<bool, ChainError> {
+        self.chain.spent(outpoint, script_pubkey)
+    }
+
+    fn sync_wallet(&self, wallet: &Wallet<AnyDatabase>) -> Result<(), ChainError> {
+        self.chain.sync_wallet(wallet)
+    }
+}
+
+impl FeeEstimator for CongestedChain {
+    fn fee_rate(&self, _target: usize) -> Result<FeeRate, ChainError> {
+        let congested = self.stuck.lock().unwrap().is_some();
+        Ok(FeeRate::from_sat_per_vb(if congested { 5.0 } else { 1.0 }))
+    }
+}
+
+// The sat/vB `tx` pays, spending outputs of `chain`
+fn fee_rate_of(chain: &MemoryChain, tx: &Transaction) -> f32 {
+    let spent: u64 = tx.input.iter().map(|txin| {
+        let prev_tx = chain.get_tx(&txin.previous_output.txid).unwrap().unwrap();
+        prev_tx.output[txin.previous_output.vout as usize].value
+    }).sum();
+    let fee = spent - tx.output.iter().map(|txout| txout.value).sum::<u64>();
+    fee as f32 / tx.vsize() as f32
+}
+
+// A funding tx built at 1 sat/vB that doesn't confirm is replaced at 5. The users sign the replacement as
+// they did the first one, and the round goes on with it.
+#[tokio::test]
+async fn stuck_funding_is_replaced() {
+    let chain = Arc::new(CongestedChain::default());
+    let maker_config = MakerConfig {
+        fee_estimator: chain.clone(),
+        funding_bump_after: Some(Duration::from_millis(300)),
+        ..MakerConfig::default()
+    };
+    let (transport, acceptor) = transports(&maker_config);
+    let treasury = treasury(&chain.chain);
+    let (maker_events, mut maker_received) = EventSink::channel();
+    let lightning = (MakerLightning::default(), maker_events);
+    let maker = serve_round(&acceptor, maker_config, &treasury, chain.clone(), lightning);
+    let mut user_received = Vec::new();
+    let users = join_all([(1, 50_000), (2, 60_000)].map(|(seed, value)| {
+        let (events, received) = EventSink::channel();
+        user_received.push(received);
+        let wallet = funded_wallet(&chain.chain, seed, &[value]);
+        run_user_session(UserConfig::default(), wallet, chain.clone(), transport.clone(), events)
+    }));
+    let (maker, users) = tokio::time::timeout(ROUND_TIMEOUT, async { tokio::join!(maker, users) }).await.unwrap();
+
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    let maker_events: Vec<_> = std::iter::from_fn(|| maker_received.try_recv().ok()).collect();
+    assert!(maker_events.contains(&ProtocolEvent::MessageSent(MessageKind::FundingBump)));
+    for (user, received) in users.into_iter().zip(&mut user_received) {
+        let user = user.unwrap();
+        assert!(matches!(user.payout, UserPayout::OnChain { .. }));
+        assert_eq!(user.funding_txid, maker.funding_txid);
+        let events: Vec<_> = std::iter::from_fn(|| received.try_recv().ok()).collect();
+        assert!(events.contains(&ProtocolEvent::MessageReceived(MessageKind::FundingBump)));
+    }
+
+    // The replacement spends every input of the stuck tx, which is gone from the chain
+    let stuck = chain.stuck.lock().unwrap().clone().unwrap();
+    let funding = chain.get_tx(&maker.funding_txid).unwrap().unwrap();
+    assert_ne!(stuck.txid(), funding.txid());
+    assert!(chain.get_tx(&stuck.txid()).unwrap().is_none());
+    let spent: HashSet<_> = funding.input.iter().map(|txin| txin.previous_output).collect();
+    assert!(stuck.input.iter().all(|txin| spent.contains(&txin.previous_output)));
+    let (stuck_rate, bumped_rate) = (fee_rate_of(&chain.chain, &stuck), fee_rate_of(&chain.chain, &funding));
+    assert!((1.0..1.1).contains(&stuck_rate), "{stuck_rate}");
+    assert!((5.0..5.1).contains(&bumped_rate), "{bumped_rate}");
+}
+
+// In a round where everyone signs its funding inputs with SIGHASH_ALL|ANYONECANPAY, a user finding the
+// funding tx too cheap adds a spare utxo to it. The other user's signature still holds, and both refunds
+// spend the funding tx that confirms.
+#[tokio::test]
+async fn user_adds_a_funding_input() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig { anyone_can_pay: true, ..MakerConfig::default() };
+    let state_dir = std::env::temp_dir().join(format!("joinswap-round-added-input-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&state_dir);
+    let config = |user: &str| UserConfig {
+        anyone_can_pay: true,
+        state_dir: Some(state_dir.join(user)),
+        ..UserConfig::default()
+    };
+    let topping_up = UserConfig {
+        contribution: Some(40_000),
+        top_up_rate: Some(FeeRate::from_sat_per_vb(5.0)),
+        ..config("user-1")
+    };
+    let wallet = funded_wallet(&chain, 1, &[50_000, 12_000]);
+    let spare = wallet.list_unspent().unwrap().into_iter().find(|utxo| utxo.txout.value == 12_000).unwrap();
+    let users = vec![(topping_up, wallet), (config("user-2"), funded_wallet(&chain, 2, &[60_000]))];
+
+    let (maker, users, events) = run_round_with_events(transports(&maker_config), maker_config, users, chain.clone())
+        .await;
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    assert!(maker.user_outpoints.contains(&spare.outpoint));
+    assert!(events.maker.contains(&ProtocolEvent::MessageReceived(MessageKind::AddInput)));
+    assert!(events.users[0].contains(&ProtocolEvent::MessageSent(MessageKind::AddInput)));
+    for (user, events) in users.into_iter().zip(&events.users) {
+        let user = user.unwrap();
+        assert_eq!((user.funding_txid, user.refund_txid), (maker.funding_txid, maker.refund_txid));
+        assert!(events.contains(&ProtocolEvent::MessageReceived(MessageKind::InputAdded)));
+    }
+
+    let funding = chain.get_tx(&maker.funding_txid).unwrap().unwrap();
+    assert_eq!(funding.input.len(), 3);
+    assert_eq!(funding.input[2].previous_output, spare.outpoint);
+    assert!(fee_rate_of(&chain, &funding) >= 5.0);
+
+    // Each user keeps a refund that spends it with valid signatures
+    for user in ["user-1", "user-2"] {
+        let file = fs::read_dir(state_dir.join(user)).unwrap().next().unwrap().unwrap().path();
+        let Recovery::User(record) = read_recovery(&file).unwrap() else { panic!("Expected a user recovery file") };
+        assert_eq!(record.funding_txid, maker.funding_txid);
+        let refund = record.refund_psbt.parse::<Psbt>().unwrap().extract_tx();
+        assert_eq!((refund.txid(), refund.input[0].previous_output.txid), (maker.refund_txid, maker.funding_txid));
+        assert!(!verified_spend(&chain, &refund)[0].is_empty());
+    }
+    let _ = fs::remove_dir_all(state_dir);
+}
+
+// Three users across both legs: each one gets a maker2user contract of its own, funded and swept, and
+// the maker sweeps the users2maker contract
+#[tokio::test]
+async fn round_of_three_users_on_both_legs() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig { round_users: 3, ..MakerConfig::default() };
+    let user_config = UserConfig { round_users: 3, ..UserConfig::default() };
+    let users = [(1, 50_000), (2, 60_000), (3, 70_000)].into_iter()
+        .map(|(seed, value)| (user_config.clone(), funded_wallet(&chain, seed, &[value])))
+        .collect();
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    assert!(matches!(maker.sweep, SweepPath::Cooperative { .. }), "{:?}", maker.sweep);
+    assert_eq!(maker.maker2user_txids.len(), 3);
+
+    let mut scripts = HashSet::new();
+    for user in users {
+        let UserPayout::OnChain { desc, txid, sweep } = user.unwrap().payout else {
+            panic!("Expected an on-chain payout");
+        };
+        assert!(maker.maker2user_txids.contains(&txid));
+        assert!(scripts.insert(desc.script_pubkey()));
+        assert!(paying(&chain.get_tx(&txid).unwrap().unwrap(), &desc.script_pubkey()) > 0);
+        let sweep = chain.get_tx(&sweep.expect("Expected the user to sweep")).unwrap().unwrap();
+        assert!(sweep.input.iter().any(|input| input.previous_output.txid == txid));
+    }
+}
+
+// Three users in a round whose contract paths need fewer than every participant: the fees are split
+// evenly among them, and the maker sweeps with its key and those of two of them
+#[tokio::test]
+async fn round_of_three_users_with_thresholds() {
+    let chain = Arc::new(MemoryChain::default());
+    let thresholds = PathThresholds { multisig: 3, timelock: 4, hashlock: 3 };
+    let maker_config = MakerConfig { round_users: 3, thresholds: Some(thresholds), ..MakerConfig::default() };
+    let user_config = UserConfig { round_users: 3, lowest_thresholds: Some(thresholds), ..UserConfig::default() };
+    let users = [(1, 50_000), (2, 60_000), (3, 70_000)].into_iter()
+        .map(|(seed, value)| (user_config.clone(), funded_wallet(&chain, seed, &[value])))
+        .collect();
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    let users: Vec<_> = users.into_iter().map(Result::unwrap).collect();
+    assert_eq!(maker.maker2user_txids.len(), 3);
+
+    // Every user pays the same share of the funding and refund fees, which add up to both
+    let (funding_fee, refund_fee) = (users[0].funding_fee, users[0].refund_fee);
+    assert!(users.iter().all(|user| (user.funding_fee, user.refund_fee) == (funding_fee, refund_fee)));
+    let shares: Vec<_> = users.iter().map(|user| user.contributed - user.refund_amount).collect();
+    assert_eq!(shares.iter().sum::<u64>(), funding_fee + refund_fee);
+    assert!(shares.iter().max().unwrap() - shares.iter().min().unwrap() <= 1, "{shares:?}");
+
+    let SweepPath::Cooperative { txid, .. } = maker.sweep else { panic!("Expected a sweep, got {:?}", maker.sweep) };
+    let sweep = chain.get_tx(&txid).unwrap().unwrap();
+    let satisfied = verified_spend(&chain, &sweep);
+    assert_eq!(satisfied[0].len(), thresholds.multisig, "{satisfied:?}");
+}
+
+// One user takes its payout through Lightning and the other on-chain: the maker pays the invoice
+// instead of funding a maker2user contract for it, and still sweeps the users2maker contract
+#[tokio::test]
+async fn round_with_a_lightning_and_an_on_chain_payout() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let (bolt11, preimage, amount) = ("lnbcrt100u1joinswap".to_string(), [9; 32], 10_000);
+    let payment_hash = sha256::Hash::hash(&preimage);
+    let backend = MemoryLnBackend::default();
+    backend.add_invoice(bolt11.clone(), amount, preimage);
+    let lightning = MakerLightning { preimages: None, backend: Some(Arc::new(backend)) };
+    let lightning_user = UserConfig { lightning_payout: Some((bolt11, payment_hash)), ..UserConfig::default() };
+    let users = vec![
+        (lightning_user, funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+
+    let (maker, users) =
+        run_round_with_lightning(transports(&maker_config), (maker_config, lightning), users, chain.clone()).await;
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    assert!(matches!(maker.sweep, SweepPath::Cooperative { .. }));
+    assert_eq!(maker.lightning_payouts, [payment_hash]);
+    assert_eq!(maker.maker2user_txids.len(), 1);
+    let users: Vec<_> = users.into_iter().map(Result::unwrap).collect();
+    assert!(matches!(users[0].payout, UserPayout::Lightning { payment_hash: paid } if paid == payment_hash));
+
+    let UserPayout::OnChain { desc, txid, sweep: Some(_) } = &users[1].payout else {
+        panic!("Expected an on-chain payout we swept, got {:?}", users[1].payout);
+    };
+    assert_eq!(maker.maker2user_txids, [*txid]);
+    let paid_on_chain = paying(&chain.get_tx(txid).unwrap().unwrap(), &desc.script_pubkey());
+    assert_eq!(maker.maker2user_amounts, paid_on_chain + amount);
+}
+
+// A user whose TCP connection drops mid round only fails that round, the maker serving on the same listener
+// goes on to the next one
+#[tokio::test]
+async fn maker_outlives_a_dropped_connection() {
+    let chain = Arc::new(MemoryChain::default());
+    let mut maker_config = MakerConfig::default();
+    maker_config.timeouts.resume = Duration::from_millis(200);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let key = PublicKey::from_secret_key(&Secp256k1::new(), maker_config.noise_key.as_ref().unwrap());
+    let transport = TcpTransport::new(MakerAddress { key, addr: listener.local_addr().unwrap().to_string() });
+    let treasury = treasury(&chain);
+
+    let serving = (MakerLightning::default(), EventSink::none());
+    // Boxed, as the futures of whole sessions are too large for the test thread's stack
+    let maker = Box::pin(serve_round(&listener, maker_config.clone(), &treasury, chain.clone(), serving));
+    // The session, and its stream with it, is dropped once it has the contract data
+    let (events, mut received) = EventSink::channel();
+    let wallet = funded_wallet(&chain, 1, &[50_000]);
+    let dropped = Box::pin(run_user_session(UserConfig::default(), wallet, chain.clone(), transport.clone(), events));
+    let dropping = async {
+        let contract_data = async {
+            while let Some(event) = received.recv().await {
+                if event == ProtocolEvent::MessageReceived(MessageKind::ContractData) {
+                    return;
+                }
+            }
+        };
+        tokio::select! {
+            user = dropped => panic!("The user should have been dropped, it got: {user:?}"),
+            _ = contract_data => {},
+        }
+    };
+    let wallet = funded_wallet(&chain, 2, &[60_000]);
+    let other = run_user_session(UserConfig::default(), wallet, chain.clone(), transport.clone(), EventSink::none());
+    let other = Box::pin(other);
+    let round = tokio::time::timeout(ROUND_TIMEOUT, async { tokio::join!(maker, dropping, other) });
+    let (maker, (), other) = round.await.expect("The round hung");
+
+    let Err(JoinSwapError::Peer { leg: Leg::First, error, .. }) = maker else {
+        panic!("Expected the dropped user to fail the round, got: {maker:?}");
+    };
+    assert!(error.is_disconnect(), "{error}");
+    assert!(matches!(other, Err(JoinSwapError::Protocol(ProtocolError::Rejected { code: AbortCode::PeerGone, .. }))));
+
+    let users = vec![
+        (UserConfig::default(), funded_wallet(&chain, 3, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 4, &[60_000])),
+    ];
+    let (maker, users) = run_round_on(&transport, &listener, &treasury, maker_config, users, chain).await;
+    assert_eq!(maker.unwrap().status, RoundStatus::Completed);
+    assert!(users.iter().all(|user| matches!(user, Ok(UserSwapReport { payout: UserPayout::OnChain { .. }, .. }))));
+}
+
+// A whole round with the maker listening on a Unix domain socket
+#[tokio::test]
+async fn round_over_a_unix_socket() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let dir = std::env::temp_dir().join(format!("joinswap-round-unix-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("maker.sock");
+    let listener = bind_unix(&path).unwrap();
+    let key = PublicKey::from_secret_key(&Secp256k1::new(), maker_config.noise_key.as_ref().unwrap());
+    let users = vec![
+        (UserConfig::default(), funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+
+    let (maker, users) = run_round((UnixTransport::new(&path, key), listener), maker_config, users, chain).await;
+    assert_eq!(maker.unwrap().status, RoundStatus::Completed);
+    assert!(users.iter().all(|user| matches!(user, Ok(UserSwapReport { payout: UserPayout::OnChain { .. }, .. }))));
+    let _ = fs::remove_dir_all(dir);
+}
+
+// Opens a connection from `ip` to the maker at `addr` and runs the handshake, then hangs up. Why the
+// maker turned us away, if it did.
+async fn knock(addr: SocketAddr, ip: &str, key: &PublicKey) -> Option<String> {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(format!("{ip}:0").parse().unwrap()).unwrap();
+    let mut conn = Connection::new(socket.connect(addr).await.unwrap(), Framing::default());
+
+    match noise::initiate(&mut conn, key).await {
+        Ok(()) => None,
+        Err(ProtocolError::Rejected { code: AbortCode::Refused, reason }) => Some(reason),
+        Err(e) => panic!("Expected the handshake or a refusal, got {e}"),
+    }
+}
+
+// Connections past the cap on pending ones are told the maker is busy, until a slot frees up
+#[tokio::test]
+async fn pending_connections_are_capped() {
+    let limits = ConnectionLimits { max_pending: 2, ..ConnectionLimits::default() };
+    let config = MakerConfig { gate: ConnectionGate::new(limits), ..MakerConfig::default() };
+    let key = PublicKey::from_secret_key(&Secp256k1::new(), config.noise_key.as_ref().unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut pending = Vec::new();
+    for _ in 0..2 {
+        let (accepted, _) = tokio::join!(accept_gated(&listener, &config), TcpStream::connect(addr));
+        pending.push(accepted.unwrap());
+    }
+    let turned_away = tokio::select! {
+        refused = knock(addr, "127.0.0.1", &key) => refused,
+        _ = accept_gated(&listener, &config) => unreachable!("Every pending slot is taken"),
+    };
+    assert_eq!(turned_away.unwrap(), Refusal::Busy.to_string());
+
+    pending.pop();
+    let admitted = async {
+        let (mut conn, _slot) = accept_gated(&listener, &config).await.unwrap();
+        noise::respond(&mut conn, config.noise_key.as_ref().unwrap()).await
+    };
+    let (admitted, refused) = tokio::join!(admitted, knock(addr, "127.0.0.1", &key));
+    assert!(admitted.is_ok());
+    assert_eq!(refused, None);
+}
+
+// A flood of connections while a round is in progress is rate limited per IP, and the round goes on
+#[tokio::test]
+async fn round_outlasts_a_flood_of_connections() {
+    let chain = Arc::new(MemoryChain::default());
+    // The users of the round connect twice each from 127.0.0.1, the flood comes from 127.0.0.2
+    let limits = ConnectionLimits { per_ip: 4, ..ConnectionLimits::default() };
+    let maker_config = MakerConfig { gate: ConnectionGate::new(limits), ..MakerConfig::default() };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let key = PublicKey::from_secret_key(&Secp256k1::new(), maker_config.noise_key.as_ref().unwrap());
+    let transport = TcpTransport::new(MakerAddress { key, addr: addr.to_string() });
+    let treasury = treasury(&chain);
+
+    let (events, mut received) = EventSink::channel();
+    let serving = (MakerLightning::default(), events);
+    let maker = Box::pin(serve_round(&listener, maker_config, &treasury, chain.clone(), serving));
+    let users = join_all([(1, 50_000), (2, 60_000)].map(|(seed, value)| {
+        let wallet = funded_wallet(&chain, seed, &[value]);
+        Box::pin(run_user_session(UserConfig::default(), wallet, chain.clone(), transport.clone(), EventSink::none()))
+    }));
+    let flood = async {
+        while let Some(event) = received.recv().await {
+            if event == ProtocolEvent::PhaseEntered(Phase::ContractCreation) {
+                break;
+            }
+        }
+        let mut refusals = Vec::new();
+        for _ in 0..10 {
+            refusals.push(knock(addr, "127.0.0.2", &key).await);
+        }
+        refusals
+    };
+    let round = tokio::time::timeout(ROUND_TIMEOUT, async { tokio::join!(maker, users, flood) });
+    let (maker, users, refusals) = round.await.expect("The round hung");
+
+    assert!(refusals[..4].iter().all(Option::is_none), "{refusals:?}");
+    assert!(refusals[4..].iter().all(|refused| refused.as_deref() == Some(&Refusal::RateLimited.to_string())));
+    assert_eq!(maker.unwrap().status, RoundStatus::Completed);
+    assert!(users.iter().all(|user| matches!(user, Ok(UserSwapReport { payout: UserPayout::OnChain { .. }, .. }))));
+}
+
+fn keys_of(desc: &Descriptor<bdk::bitcoin::PublicKey>) -> HashSet<bdk::bitcoin::PublicKey> {
+    let mut keys = HashSet::new();
+    desc.for_each_key(|key| {
+        keys.insert(*key);
+        true
+    });
+    keys
+}
+
+// Two rounds of one maker at once, out of the same treasury: each one keeps to its own users, keys and txs
+#[tokio::test]
+async fn concurrent_rounds_stay_apart() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let treasury = treasury(&chain);
+    let wallets = [(1, 50_000), (2, 60_000), (3, 70_000), (4, 80_000)].map(|(seed, value)| {
+        (UserConfig::default(), funded_wallet(&chain, seed, &[value]))
+    });
+    let outpoints: Vec<_> = wallets.iter().map(|(_, wallet)| wallet.list_unspent().unwrap()[0].outpoint).collect();
+    let [first, second, third, fourth] = wallets;
+    let ((transport_a, acceptor_a), (transport_b, acceptor_b)) = (transports(&maker_config), transports(&maker_config));
+
+    let round_a = Box::pin(run_round_on(
+        &transport_a, &acceptor_a, &treasury, maker_config.clone(), vec![first, second], chain.clone(),
+    ));
+    let round_b = Box::pin(run_round_on(
+        &transport_b, &acceptor_b, &treasury, maker_config, vec![third, fourth], chain.clone(),
+    ));
+    let (round_a, round_b) = tokio::join!(round_a, round_b);
+
+    let (mut descs, mut spent_by_maker) = (Vec::new(), HashSet::new());
+    for ((maker, users), own) in [round_a, round_b].into_iter().zip([&outpoints[..2], &outpoints[2..]]) {
+        let maker = maker.unwrap();
+        assert_eq!(maker.status, RoundStatus::Completed);
+        assert_eq!(HashSet::<&OutPoint>::from_iter(&maker.user_outpoints), HashSet::from_iter(own));
+        for user in users {
+            let user = user.unwrap();
+            assert_eq!((&user.users2maker_desc, user.hash), (&maker.users2maker_desc, maker.hash));
+            assert_eq!(user.funding_txid, maker.funding_txid);
+        }
+        // The treasury never hands the same utxo to both rounds
+        for txid in &maker.maker2user_txids {
+            for input in chain.get_tx(txid).unwrap().unwrap().input {
+                assert!(spent_by_maker.insert(input.previous_output), "{} spent twice", input.previous_output);
+            }
+        }
+        descs.push(maker.users2maker_desc);
+    }
+    assert!(keys_of(&descs[0]).is_disjoint(&keys_of(&descs[1])));
+    assert!(hashes_of(&descs[0]).is_disjoint(&hashes_of(&descs[1])));
+}
+
+// A user alone in the pool of a maker whose round never fills up. The maker tells it every 50ms that
+// the round lacks a user, and sends it away once `sends_away` elapses, if given. What the user got, its
+// events and why the maker read no more from it, unless it sent the user away.
+async fn lone_user(
+    config: UserConfig,
+    sends_away: Option<Duration>,
+) -> (Result<UserSwapReport, JoinSwapError>, Vec<ProtocolEvent>, Option<ProtocolError>) {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let (transport, acceptor) = transports(&maker_config);
+    let maker = async {
+        let router = RoundRouter::new();
+        let (mut conn, _slot) = accept_gated(&acceptor, &maker_config).await.unwrap();
+        assert_eq!(answer_hello(&mut conn, &maker_config, &router).await.unwrap(), Arrival::Join);
+
+        let (reader, writer) = (&mut conn.reader, &mut conn.writer);
+        // The data of the user, its utxo with its PSBT input among them, until it leaves
+        let leaves = async {
+            loop {
+                if let Err(e) = read_msg(reader, MAX_PSBT_SIZE).await {
+                    return e;
+                }
+            }
+        };
+        let waits = async {
+            let mut ticks = tokio::time::interval(Duration::from_millis(50));
+            let sent_away = tokio::time::sleep(sends_away.unwrap_or(ROUND_TIMEOUT));
+            tokio::pin!(sent_away);
+            loop {
+                tokio::select! {
+                    // A user that left may close the connection before we read why
+                    _ = ticks.tick() => if send_waiting(1, writer).await.is_err() {
+                        std::future::pending::<()>().await;
+                    },
+                    _ = &mut sent_away => {
+                        send_error(AbortCode::NoRound, &NO_ROUND, writer).await.unwrap();
+                        return;
+                    },
+                }
+            }
+        };
+        tokio::select! {
+            e = leaves => Some(e),
+            _ = waits => None,
+        }
+    };
+    let (events, mut received) = EventSink::channel();
+    let wallet = funded_wallet(&chain, 1, &[50_000]);
+    let user = Box::pin(run_user_session(config, wallet, chain.clone(), transport, events));
+
+    let round = tokio::time::timeout(ROUND_TIMEOUT, async { tokio::join!(maker, user) });
+    let (last_read, user) = round.await.expect("The round hung");
+    (user, std::iter::from_fn(|| received.try_recv().ok()).collect(), last_read)
+}
+
+// Nothing is signed while a user waits for its round, so past `max_wait` it leaves telling the maker why
+#[tokio::test]
+async fn lone_user_gives_up_waiting() {
+    let max_wait = Duration::from_millis(300);
+    let (user, events, last_read) = lone_user(UserConfig { max_wait, ..UserConfig::default() }, None).await;
+
+    assert!(matches!(user, Err(JoinSwapError::NoRound { waited }) if waited == max_wait), "{user:?}");
+    assert!(events.contains(&ProtocolEvent::WaitingForUsers { missing: 1 }));
+    let Some(ProtocolError::Rejected { code, .. }) = last_read else {
+        panic!("Expected the user to tell why it left, got {last_read:?}");
+    };
+    assert_eq!(code, AbortCode::NoRound);
+}
+
+// The maker may also give up on the round first, the user is told and leaves
+#[tokio::test]
+async fn lone_user_is_sent_away() {
+    let (user, events, _) = lone_user(UserConfig::default(), Some(Duration::from_millis(300))).await;
+
+    let Err(JoinSwapError::Protocol(ProtocolError::Rejected { code, reason })) = user else {
+        panic!("Expected the maker to send the user away, got {user:?}");
+    };
+    assert_eq!((code, reason.as_str()), (AbortCode::NoRound, NO_ROUND));
+    assert!(events.contains(&ProtocolEvent::WaitingForUsers { missing: 1 }));
+}
+
+// In a round of equal amounts users of different utxos put in the same, and get the same back in the
+// second leg, so no amount tells them apart
+#[tokio::test]
+async fn denominated_round_pays_equal_outputs() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig { denomination: Some(DenominationMode { step: 10_000 }), ..MakerConfig::default() };
+    let config = UserConfig { denominated: true, ..UserConfig::default() };
+    let users = vec![
+        (config.clone(), funded_wallet(&chain, 1, &[53_000])),
+        (config, funded_wallet(&chain, 2, &[68_000])),
+    ];
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    assert_eq!(maker.unwrap().status, RoundStatus::Completed);
+    let (mut contributions, mut payouts) = (HashSet::new(), HashSet::new());
+    for user in users {
+        let user = user.unwrap();
+        let UserPayout::OnChain { desc, txid, .. } = &user.payout else {
+            panic!("Expected an on-chain payout");
+        };
+        contributions.insert(user.contributed);
+        payouts.insert(paying(&chain.get_tx(txid).unwrap().unwrap(), &desc.script_pubkey()));
+    }
+    assert_eq!(contributions, HashSet::from([50_000]));
+    assert_eq!(payouts.len(), 1, "{payouts:?}");
+}
+
+// What a user of `config` got from the offer of a maker of `maker_config`, once the maker hung up, and
+// how the maker took its answer
+async fn offer_between(
+    maker_config: MakerConfig,
+    config: UserConfig,
+) -> (Result<UserSwapReport, JoinSwapError>, Result<Arrival, ProtocolError>) {
+    let chain = Arc::new(MemoryChain::default());
+    let (transport, acceptor) = transports(&maker_config);
+    let maker = async {
+        let (mut conn, _slot) = accept_gated(&acceptor, &maker_config).await.unwrap();
+        answer_hello(&mut conn, &maker_config, &RoundRouter::new()).await
+    };
+    let wallet = funded_wallet(&chain, 1, &[50_000]);
+    let user = Box::pin(run_user_session(config, wallet, chain.clone(), transport, EventSink::none()));
+
+    let (arrival, user) = tokio::time::timeout(ROUND_TIMEOUT, async { tokio::join!(maker, user) }).await.unwrap();
+    (user, arrival)
+}
+
+// What a user paying at most `max_fee_ppm` makes of the offer of a maker keeping 1% of each contribution
+async fn offer_of_one_percent(
+    max_fee_ppm: u64,
+) -> (Result<UserSwapReport, JoinSwapError>, Result<Arrival, ProtocolError>) {
+    let maker_config = MakerConfig { maker_fee: MakerFee { base: 0, ppm: 10_000 }, ..MakerConfig::default() };
+    let config = UserConfig { max_fee_ppm: Some(max_fee_ppm), ..UserConfig::default() };
+    offer_between(maker_config, config).await
+}
+
+// A user that pays at most 0.5% leaves a maker asking for 1% before sending any data, and tells it why
+#[tokio::test]
+async fn offer_over_the_max_fee_is_rejected() {
+    let (user, arrival) = offer_of_one_percent(5_000).await;
+    let Err(JoinSwapError::Protocol(ProtocolError::TermsRejected(reason))) = user else {
+        panic!("Expected the user to reject the terms, got {user:?}");
+    };
+    assert!(reason.contains("5000 ppm"), "{reason}");
+    let Err(ProtocolError::Declined(declined)) = arrival else {
+        panic!("Expected the user to decline, got {arrival:?}");
+    };
+    assert!(declined.contains(&reason), "{declined}");
+
+    // One that pays up to 1.5% joins
+    let (_, arrival) = offer_of_one_percent(15_000).await;
+    assert_eq!(arrival.unwrap(), Arrival::Join);
+}
+
+// A maker whose refund timelock is a single block would leave a user no time to react before the maker
+// could take the coins back, so the user leaves it before sending any data
+#[tokio::test]
+async fn refund_timelock_of_one_block_is_rejected() {
+    let maker_config = MakerConfig { timelocks: Timelocks { refund: 1, maker2user: 69 }, ..MakerConfig::default() };
+    let (user, arrival) = offer_between(maker_config.clone(), UserConfig::default()).await;
+    let Err(JoinSwapError::Protocol(ProtocolError::TermsRejected(reason))) = user else {
+        panic!("Expected the user to reject the terms, got {user:?}");
+    };
+    assert!(reason.contains("we take 24 or more"), "{reason}");
+    let Err(ProtocolError::Declined(declined)) = arrival else {
+        panic!("Expected the user to decline, got {arrival:?}");
+    };
+    assert!(declined.contains(&reason), "{declined}");
+
+    // One that takes such timelocks joins
+    let bounds = TimelockBounds { min_refund: 1, ..TimelockBounds::default() };
+    let config = UserConfig { timelock_bounds: bounds, ..UserConfig::default() };
+    let (_, arrival) = offer_between(maker_config, config).await;
+    assert_eq!(arrival.unwrap(), Arrival::Join);
+}
+
+// A maker that puts a utxo of its own in the users2maker contract: the users take a funding tx with one
+// more input than theirs, and the round goes on as usual
+#[tokio::test]
+async fn round_with_a_maker_input() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig { own_input: true, ..MakerConfig::default() };
+    let users = vec![
+        (UserConfig::default(), funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    let funding = chain.get_tx(&maker.funding_txid).unwrap().unwrap();
+    let maker_inputs: Vec<_> = funding.input.iter()
+        .filter(|txin| !maker.user_outpoints.contains(&txin.previous_output))
+        .collect();
+    assert_eq!((funding.input.len(), maker_inputs.len()), (3, 1));
+    // The contract holds the maker input along with what the users put in
+    let contract = paying(&funding, &maker.users2maker_desc.script_pubkey());
+    assert!(contract > maker.total_received, "{contract} <= {}", maker.total_received);
+
+    for user in users {
+        let user = user.unwrap();
+        assert_eq!(user.funding_txid, maker.funding_txid);
+        assert!(matches!(user.payout, UserPayout::OnChain { .. }));
+    }
+}
+
+// Runs `round` while routing the connections coming back to it, as `serve_round` does
+async fn routing<A: Acceptor, T>(
+    acceptor: &A,
+    config: &MakerConfig,
+    router: &RoundRouter<A::Stream>,
+    round: impl Future<Output = T>,
+) -> T {
+    let routing = async {
+        loop {
+            let (mut conn, slot) = accept_gated(acceptor, config).await.unwrap();
+            if let Ok(arrival) = answer_hello(&mut conn, config, router).await {
+                router.route(arrival, (conn, slot));
+            }
+        }
+    };
+    tokio::select! {
+        output = round => output,
+        _ = routing => unreachable!(),
+    }
+}
+
+// A user that declines the round before signing leaves it, and the other one goes back to matchmaking
+// with the data it sent. It's matched with a new user and their round completes.
+#[tokio::test]
+async fn remaining_user_is_rematched() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let (transport, acceptor) = transports(&maker_config);
+    let treasury = treasury(&chain);
+    let router = RoundRouter::new();
+    let mut rematches = router.rematches();
+    let (start_newcomer, newcomer_starts) = tokio::sync::oneshot::channel();
+
+    let maker = async {
+        let mut peers = Vec::new();
+        for _ in 0..2 {
+            let (mut conn, _slot) = accept_gated(&acceptor, &maker_config).await.unwrap();
+            assert_eq!(answer_hello(&mut conn, &maker_config, &router).await.unwrap(), Arrival::Join);
+            peers.push(RoundUser::from(conn));
+        }
+        let lightning = MakerLightning::default();
+        let round = run_maker_round(
+            maker_config.clone(), &treasury, chain.clone(), lightning.clone(), peers, router.inbox(), EventSink::none(),
+        );
+        let declined = routing(&acceptor, &maker_config, &router, Box::pin(round)).await;
+
+        // The user left waits in matchmaking for the newcomer
+        let rematched = rematches.recv().await.unwrap();
+        start_newcomer.send(()).unwrap();
+        let (mut conn, _slot) = accept_gated(&acceptor, &maker_config).await.unwrap();
+        assert_eq!(answer_hello(&mut conn, &maker_config, &router).await.unwrap(), Arrival::Join);
+        let peers = vec![rematched, RoundUser::from(conn)];
+        let round = run_maker_round(
+            maker_config.clone(), &treasury, chain.clone(), lightning, peers, router.inbox(), EventSink::none(),
+        );
+        (declined, routing(&acceptor, &maker_config, &router, Box::pin(round)).await)
+    };
+    let declining = UserConfig { review_contract: Some(ContractReview::new(|_| false)), ..UserConfig::default() };
+    let declining = run_user_session(
+        declining, funded_wallet(&chain, 1, &[50_000]), chain.clone(), transport.clone(), EventSink::none(),
+    );
+    let (events, mut remaining_events) = EventSink::channel();
+    let remaining = funded_wallet(&chain, 2, &[60_000]);
+    let remaining_utxo = remaining.list_unspent().unwrap()[0].outpoint;
+    let remaining = run_user_session(UserConfig::default(), remaining, chain.clone(), transport.clone(), events);
+    let newcomer = async {
+        newcomer_starts.await.unwrap();
+        let wallet = funded_wallet(&chain, 3, &[70_000]);
+        run_user_session(UserConfig::default(), wallet, chain.clone(), transport.clone(), EventSink::none()).await
+    };
+
+    let all = async { tokio::join!(Box::pin(maker), Box::pin(declining), Box::pin(remaining), Box::pin(newcomer)) };
+    let ((declined, rematched), declining, remaining, newcomer) =
+        tokio::time::timeout(ROUND_TIMEOUT, all).await.expect("The rounds hung");
+
+    assert!(matches!(declined, Err(JoinSwapError::Peer { error: ProtocolError::Declined(_), .. })), "{declined:?}");
+    assert_eq!(declining.unwrap_err().abort_code(), AbortCode::NotApproved);
+    let rematched = rematched.unwrap();
+    assert_eq!(rematched.status, RoundStatus::Completed);
+    assert!(rematched.user_outpoints.contains(&remaining_utxo));
+    for user in [remaining, newcomer] {
+        let user = user.unwrap();
+        assert_eq!(user.funding_txid, rematched.funding_txid);
+        assert!(matches!(user.payout, UserPayout::OnChain { .. }));
+    }
+    let remaining_events: Vec<_> = std::iter::from_fn(|| remaining_events.try_recv().ok()).collect();
+    assert!(remaining_events.contains(&ProtocolEvent::Rematched { index: None }));
+}
+
+// A maker whose round can't pay its minimum margin stops before anything is signed and tells the users why,
+// and one that can goes on
+#[tokio::test]
+async fn round_halts_at_the_margin_gate() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig { min_margin: 1_000_000, ..MakerConfig::default() };
+    let wallets = [funded_wallet(&chain, 1, &[50_000]), funded_wallet(&chain, 2, &[60_000])];
+    let utxos: Vec<_> = wallets.iter().flat_map(|wallet| wallet.list_unspent().unwrap()).collect();
+    let users = wallets.into_iter().map(|wallet| (UserConfig { rematch: false, ..UserConfig::default() }, wallet));
+
+    let (maker, users) = run_round(transports(&maker_config), maker_config, users.collect(), chain.clone()).await;
+    assert!(matches!(maker, Err(JoinSwapError::MarginTooLow { min_margin: 1_000_000, .. })), "{maker:?}");
+    for user in users {
+        let Err(JoinSwapError::Protocol(ProtocolError::Rejected { code: AbortCode::MarginTooLow, reason })) = user
+        else {
+            panic!("Expected the maker to stop at its margin, got {user:?}");
+        };
+        assert!(reason.contains("margin too low"), "{reason}");
+    }
+    for utxo in utxos {
+        assert!(!chain.spent(&utxo.outpoint, &utxo.txout.script_pubkey).unwrap());
+    }
+
+    let maker_config = MakerConfig { min_margin: 500, ..MakerConfig::default() };
+    let users = vec![
+        (UserConfig::default(), funded_wallet(&chain, 3, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 4, &[60_000])),
+    ];
+    let (maker, _) = run_round(transports(&maker_config), maker_config, users, chain).await;
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    assert!(maker.total_received - maker.maker2user_amounts - maker.maker2user_fees >= 500);
+}
+
+// Stands in for a Lightning node paying the invoice of a submarine swap, and tells which hashes it was asked for
+#[derive(Default)]
+struct PayingNode {
+    preimage: [u8; 32],
+    paid: Mutex<Vec<sha256::Hash>>,
+}
+
+impl PreimageSource for PayingNode {
+    fn preimage(&self, hash: sha256::Hash) -> Result<[u8; 32], PreimageError> {
+        self.paid.lock().unwrap().push(hash);
+        Ok(self.preimage)
+    }
+}
+
+// A user asks for its invoice to be paid: both contracts lock to its payment hash, and the maker only learns
+// the preimage, which its sweep needs, from the node once it pays the invoice
+#[tokio::test]
+async fn submarine_swap_round() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let node = Arc::new(PayingNode { preimage: [4; 32], ..PayingNode::default() });
+    let payment_hash = sha256::Hash::hash(&node.preimage);
+    let lightning = MakerLightning { preimages: Some(node.clone()), backend: None };
+    let submarine = UserConfig { hash_source: HashSource::Invoice(payment_hash), ..UserConfig::default() };
+    let users = vec![
+        (submarine, funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+
+    let (maker, users) =
+        run_round_with_lightning(transports(&maker_config), (maker_config, lightning), users, chain.clone()).await;
+    let maker = maker.unwrap();
+    assert_eq!(maker.status, RoundStatus::Completed);
+    assert!(matches!(maker.sweep, SweepPath::Cooperative { .. }));
+    assert_eq!(maker.hash, payment_hash);
+    assert_eq!(*node.paid.lock().unwrap(), [payment_hash]);
+    for user in users {
+        assert_eq!(user.unwrap().hash, payment_hash);
+    }
+
+    // Without a node the maker can't learn the preimage, so it takes no invoice hashes
+    let maker_config = MakerConfig::default();
+    let submarine = UserConfig { hash_source: HashSource::Invoice(payment_hash), ..UserConfig::default() };
+    let users = vec![
+        (submarine, funded_wallet(&chain, 3, &[50_000])),
+        (UserConfig { rematch: false, ..UserConfig::default() }, funded_wallet(&chain, 4, &[60_000])),
+    ];
+    let (maker, _) = run_round(transports(&maker_config), maker_config, users, chain).await;
+    assert!(matches!(maker, Err(JoinSwapError::Preimage(PreimageError::NoSource))), "{maker:?}");
+}
+
+// Peers of another protocol version part at the hellos, with the reason on both sides, before anything else
+// is sent
+#[tokio::test]
+async fn mismatched_versions_are_refused() {
+    let chain = Arc::new(MemoryChain::default());
+    let config = MakerConfig::default();
+    let noise_key = *config.noise_key.as_ref().unwrap();
+    let (transport, acceptor) = transports(&config);
+    let hello = Hello::new(Network::Regtest, config.round_users, PsbtEncoding::Json, false, false, false, false);
+
+    // An older user, against our maker
+    let router = RoundRouter::new();
+    let maker = async {
+        let (mut conn, _slot) = accept_gated(&acceptor, &config).await.unwrap();
+        answer_hello(&mut conn, &config, &router).await
+    };
+    let user = async {
+        let mut conn = Connection::new(transport.connect().await.unwrap(), Framing::default());
+        noise::initiate(&mut conn, &transport.maker_key().unwrap()).await.unwrap();
+        let old = Hello { version: PROTOCOL_VERSION - 1, ..hello.clone() };
+        send_msg(&old.into(), &mut conn.writer).await.unwrap();
+        read_msg(&mut conn.reader, MAX_MESSAGE_SIZE).await
+    };
+    let (maker, user) = tokio::join!(maker, user);
+    assert!(matches!(maker, Err(ProtocolError::Incompatible(reason)) if reason.contains("version")));
+    let Err(ProtocolError::Rejected { code: AbortCode::PeerMisbehaved, reason }) = user else {
+        panic!("Expected the maker to refuse us, got {user:?}");
+    };
+    assert!(reason.contains(&format!("speaks version {}", PROTOCOL_VERSION - 1)), "{reason}");
+
+    // A newer maker, against our user
+    let maker = async {
+        let mut conn = Connection::new(acceptor.accept().await.unwrap(), Framing::default());
+        noise::respond(&mut conn, &noise_key).await.unwrap();
+        let theirs: Hello = expect_msg(&mut conn.reader).await.unwrap();
+        assert_eq!(theirs.version, PROTOCOL_VERSION);
+        let newer = Hello { version: PROTOCOL_VERSION + 1, ..hello };
+        send_msg(&newer.into(), &mut conn.writer).await.unwrap();
+        read_msg(&mut conn.reader, MAX_MESSAGE_SIZE).await
+    };
+    let wallet = funded_wallet(&chain, 1, &[50_000]);
+    let user = Box::pin(run_user_session(UserConfig::default(), wallet, chain, transport, EventSink::none()));
+    let (maker, user) = tokio::time::timeout(ROUND_TIMEOUT, async { tokio::join!(maker, user) }).await.unwrap();
+    assert!(matches!(maker, Err(ProtocolError::Rejected { code: AbortCode::PeerMisbehaved, .. })), "{maker:?}");
+    let Err(JoinSwapError::Protocol(ProtocolError::Incompatible(reason))) = user else {
+        panic!("Expected an incompatible maker, got {user:?}");
+    };
+    assert!(reason.contains(&format!("speaks version {}", PROTOCOL_VERSION + 1)), "{reason}");
+}
+
+// Edits the frames going from the maker to the user, which may drop, change or add frames
+type FrameEdit = Arc<Mutex<dyn FnMut(Vec<u8>) -> Vec<Vec<u8>> + Send>>;
+
+// Copies the length prefixed frames of `from` to `to` through `edit`
+async fn edit_frames(mut from: impl AsyncRead + Unpin, mut to: impl AsyncWrite + Unpin, edit: FrameEdit) {
+    loop {
+        let mut len = [0; 4];
+        if from.read_exact(&mut len).await.is_err() {
+            break;
+        }
+        let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+        if from.read_exact(&mut frame).await.is_err() {
+            break;
+        }
+        let frames = (edit.lock().unwrap())(frame);
+        for frame in frames {
+            let framed = [&(frame.len() as u32).to_be_bytes()[..], &frame].concat();
+            if to.write_all(&framed).await.is_err() {
+                return;
+            }
+        }
+    }
+    let _ = to.shutdown().await;
+}
+
+// The memory transport as a man in the middle that broke the encryption would have it: a plaintext stream
+// (the maker takes it as encrypted, like TLS) and the frames from the maker at its mercy
+#[derive(Clone)]
+struct MitmTransport {
+    inner: MemoryTransport,
+    edit: FrameEdit,
+}
+
+impl Transport for MitmTransport {
+    type Stream = DuplexStream;
+
+    fn connect(&self) -> impl Future<Output = io::Result<DuplexStream>> + Send {
+        let (inner, edit) = (self.inner.clone(), self.edit.clone());
+
+        async move {
+            let (maker_read, mut maker_write) = split(inner.connect().await?);
+            let (ours, theirs) = duplex(64 * 1024);
+            let (mut user_read, user_write) = split(theirs);
+            tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut user_read, &mut maker_write).await;
+                let _ = maker_write.shutdown().await;
+            });
+            tokio::spawn(edit_frames(maker_read, user_write, edit));
+            Ok(ours)
+        }
+    }
+
+    fn maker_key(&self) -> Option<PublicKey> {
+        None
+    }
+}
+
+// Runs a first leg where the frames the maker sends the first user go through `edit`, and what the maker
+// and that user got. Nothing gets funded.
+async fn round_through_mitm(
+    edit: impl FnMut(Vec<u8>) -> Vec<Vec<u8>> + Send + 'static,
+) -> (Result<MakerRoundReport, JoinSwapError>, Result<UserSwapReport, JoinSwapError>) {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig { noise_key: None, ..MakerConfig::default() };
+    let key = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+    let (inner, acceptor) = memory_transport(key);
+    let mitm = MitmTransport { inner: inner.clone(), edit: Arc::new(Mutex::new(edit)) };
+    let plain = MitmTransport { inner: inner.clone(), edit: Arc::new(Mutex::new(|frame| vec![frame])) };
+    let config = UserConfig { rematch: false, ..UserConfig::default() };
+    let wallets = [funded_wallet(&chain, 1, &[50_000]), funded_wallet(&chain, 2, &[60_000])];
+    let utxos: Vec<_> = wallets.iter().flat_map(|wallet| wallet.list_unspent().unwrap()).collect();
+    let [first, second] = wallets;
+
+    let treasury = treasury(&chain);
+    let serving = (MakerLightning::default(), EventSink::none());
+    let maker = Box::pin(serve_round(&acceptor, maker_config, &treasury, chain.clone(), serving));
+    let first = Box::pin(run_user_session(config.clone(), first, chain.clone(), mitm, EventSink::none()));
+    let second = Box::pin(run_user_session(config, second, chain.clone(), plain, EventSink::none()));
+    let round = tokio::time::timeout(ROUND_TIMEOUT, async { tokio::join!(maker, first, second) });
+    let (maker, first, _) = round.await.expect("The round hung");
+    // Kept until the round is over, the maker accepts connections for as long as users can connect
+    drop(inner);
+
+    for utxo in utxos {
+        assert!(!chain.spent(&utxo.outpoint, &utxo.txout.script_pubkey).unwrap());
+    }
+    (maker, first)
+}
+
+// The contract data is signed by the maker, so a payout changed on its way to the user doesn't get past it
+#[tokio::test]
+async fn tampered_contract_data_is_refused() {
+    let tamper = |mut frame: Vec<u8>| {
+        let pattern = b"\"payout\":";
+        if let Some(at) = frame.windows(pattern.len()).position(|window| window == pattern) {
+            let digit = &mut frame[at + pattern.len()];
+            *digit = if *digit == b'9' { b'1' } else { *digit + 1 };
+        }
+        vec![frame]
+    };
+
+    let (maker, user) = round_through_mitm(tamper).await;
+    let Err(JoinSwapError::Protocol(ProtocolError::BadSignature(reason))) = user else {
+        panic!("Expected the tampered message to be refused, got {user:?}");
+    };
+    assert!(!reason.is_empty());
+    // The user hadn't signed anything yet, so it leaves telling why
+    let Err(JoinSwapError::Peer { leg: Leg::First, error: ProtocolError::Declined(declined), .. }) = maker else {
+        panic!("Expected the user to leave the round, got {maker:?}");
+    };
+    assert!(declined.contains(&reason), "{declined}");
+}
+
+// A message captured earlier in the session and sent again is out of order, even with its valid signature
+#[tokio::test]
+async fn replayed_message_is_refused() {
+    let contains = |frame: &[u8], text: &str| frame.windows(text.len()).any(|window| window == text.as_bytes());
+    let mut captured = None;
+    let replay = move |frame: Vec<u8>| {
+        if contains(&frame, "\"seq\":0,") {
+            captured = Some(frame.clone());
+        }
+        match (contains(&frame, "\"seq\":1,"), &captured) {
+            (true, Some(first)) => vec![frame, first.clone()],
+            _ => vec![frame],
+        }
+    };
+
+    let (maker, user) = round_through_mitm(replay).await;
+    assert!(
+        matches!(user, Err(JoinSwapError::Protocol(ProtocolError::OutOfOrder { expected: 2, got: 0 }))),
+        "{user:?}",
+    );
+    assert!(matches!(maker, Err(JoinSwapError::Peer { leg: Leg::First, .. })), "{maker:?}");
+}
+
+// Cancels `token` once the session emits an event `at` matches
+fn shut_down_at(token: CancellationToken, at: fn(&ProtocolEvent) -> bool) -> EventSink {
+    let (events, mut received) = EventSink::channel();
+    tokio::spawn(async move {
+        while let Some(event) = received.recv().await {
+            if at(&event) {
+                token.cancel();
+            }
+        }
+    });
+
+    events
+}
+
+// Runs a round where the maker, or else the first user, shuts down at the first event `at` matches, saving
+// their state under `state_dir`. What each side got.
+async fn round_shut_down(
+    maker_shuts: bool,
+    at: fn(&ProtocolEvent) -> bool,
+    state_dir: &Path,
+) -> (Result<MakerRoundReport, JoinSwapError>, Vec<Result<UserSwapReport, JoinSwapError>>) {
+    round_shut_down_on(Arc::new(MemoryChain::default()), maker_shuts, at, state_dir).await
+}
+
+async fn round_shut_down_on(
+    chain: Arc<MemoryChain>,
+    maker_shuts: bool,
+    at: fn(&ProtocolEvent) -> bool,
+    state_dir: &Path,
+) -> (Result<MakerRoundReport, JoinSwapError>, Vec<Result<UserSwapReport, JoinSwapError>>) {
+    let mut maker_config = MakerConfig { state_dir: Some(state_dir.join("maker")), ..MakerConfig::default() };
+    // Those left in the round soon give up on the one that stopped
+    maker_config.timeouts.second_leg = Duration::from_millis(500);
+    maker_config.timeouts.resume = Duration::from_millis(500);
+    let maker_events = match maker_shuts {
+        true => shut_down_at(maker_config.shutdown.clone(), at),
+        false => EventSink::none(),
+    };
+    let (transport, acceptor) = transports(&maker_config);
+    let users = [(1, 50_000), (2, 60_000)].into_iter().map(|(seed, value)| {
+        let dir = state_dir.join(format!("user-{seed}"));
+        let mut config = UserConfig { state_dir: Some(dir), rematch: false, ..UserConfig::default() };
+        config.timeouts.hello = Duration::from_millis(500);
+        config.timeouts.second_leg = Duration::from_millis(500);
+        let events = match !maker_shuts && seed == 1 {
+            true => shut_down_at(config.shutdown.clone(), at),
+            false => EventSink::none(),
+        };
+        let wallet = funded_wallet(&chain, seed, &[value]);
+        Box::pin(run_user_session(config, wallet, chain.clone(), transport.clone(), events))
+    });
+
+    let treasury = treasury(&chain);
+    let serving = (MakerLightning::default(), maker_events);
+    // Connections to a maker that stopped are refused, as they would be over TCP
+    let maker = Box::pin(async {
+        let report = serve_round(&acceptor, maker_config, &treasury, chain.clone(), serving).await;
+        drop(acceptor);
+        report
+    });
+    let round = tokio::time::timeout(ROUND_TIMEOUT, async { tokio::join!(maker, join_all(users)) });
+    round.await.expect("The round hung")
+}
+
+fn shutdown_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("joinswap-round-shutdown-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn told_shutdown(result: &Result<UserSwapReport, JoinSwapError>) -> bool {
+    matches!(result, Err(JoinSwapError::Protocol(ProtocolError::Rejected { code: AbortCode::Shutdown, .. })))
+}
+
+fn refunded(result: &Result<UserSwapReport, JoinSwapError>) -> bool {
+    matches!(result, Ok(UserSwapReport { payout: UserPayout::Refund { .. }, .. }))
+}
+
+// A user that stops before signing anything has nothing to save, and the round ends for the others
+#[tokio::test]
+async fn user_shuts_down_before_signing() {
+    let state_dir = shutdown_dir("user-unsigned");
+    let at = |event: &ProtocolEvent| *event == ProtocolEvent::PhaseEntered(Phase::ContractCreation);
+
+    let (maker, users) = round_shut_down(false, at, &state_dir).await;
+    assert!(matches!(users[0], Err(JoinSwapError::Shutdown { saved: None })), "{:?}", users[0]);
+    let Err(JoinSwapError::Peer { leg: Leg::First, index: 0, error }) = &maker else {
+        panic!("Expected the user to leave the round, got {maker:?}");
+    };
+    // It may learn why, or only find the connection gone when it sends the contract data
+    let told = matches!(error, ProtocolError::Rejected { code: AbortCode::Shutdown, .. });
+    assert!(told || error.is_disconnect(), "{error}");
+    let Err(JoinSwapError::Protocol(ProtocolError::Rejected { code, .. })) = &users[1] else {
+        panic!("Expected the other user to be told, got {:?}", users[1]);
+    };
+    assert_eq!(*code, AbortCode::PeerGone);
+    assert!(!state_dir.exists());
+}
+
+// Once it signed the funding tx, a user that stops saves what it needs to take its coins back
+#[tokio::test]
+async fn user_shuts_down_after_signing_the_funding() {
+    let state_dir = shutdown_dir("user-signed");
+    let at = |event: &ProtocolEvent| *event == ProtocolEvent::MessageSent(MessageKind::SignedFunding);
+
+    let (maker, users) = round_shut_down(false, at, &state_dir).await;
+    let Err(JoinSwapError::Shutdown { saved: Some(path) }) = &users[0] else {
+        panic!("Expected the user to save its state, got {:?}", users[0]);
+    };
+    assert!(path.starts_with(state_dir.join("user-1")));
+    assert!(matches!(read_recovery(path).unwrap(), Recovery::User(_)));
+    assert!(matches!(maker, Err(JoinSwapError::Peer { leg: Leg::First, index: 0, .. })), "{maker:?}");
+    assert!(refunded(&users[1]), "{:?}", users[1]);
+    let _ = fs::remove_dir_all(state_dir);
+}
+
+// A maker that stops before the funding tx is out has nothing to save, and every user is told
+#[tokio::test]
+async fn maker_shuts_down_before_funding() {
+    let state_dir = shutdown_dir("maker-unfunded");
+    let at = |event: &ProtocolEvent| *event == ProtocolEvent::MessageReceived(MessageKind::UserData);
+
+    let (maker, users) = round_shut_down(true, at, &state_dir).await;
+    assert!(matches!(maker, Err(JoinSwapError::Shutdown { saved: None })), "{maker:?}");
+    assert!(users.iter().all(told_shutdown), "{users:?}");
+    assert!(!state_dir.exists());
+}
+
+// Once it broadcast the funding tx, a maker that stops saves the round so it can sweep or refund it later
+#[tokio::test]
+async fn maker_shuts_down_after_funding() {
+    let state_dir = shutdown_dir("maker-funded");
+    let at = |event: &ProtocolEvent| matches!(event, ProtocolEvent::Broadcast { role: TxRole::Funding, .. });
+
+    let (maker, users) = round_shut_down(true, at, &state_dir).await;
+    let Err(JoinSwapError::Shutdown { saved: Some(path) }) = &maker else {
+        panic!("Expected the maker to save the round, got {maker:?}");
+    };
+    assert!(path.starts_with(state_dir.join("maker")));
+    assert!(matches!(read_recovery(path).unwrap(), Recovery::Maker(_)));
+    // The funding tx is out, so the users keep their refunds
+    assert!(users.iter().all(refunded), "{users:?}");
+    let _ = fs::remove_dir_all(state_dir);
+}
+
+// The recovery file a user saved in `dir`
+fn user_record(dir: &Path) -> UserRecovery {
+    let file = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path())
+        .find(|path| path.file_name().unwrap().to_string_lossy().starts_with("user-session"))
+        .expect("A user recovery file");
+    let Recovery::User(record) = read_recovery(&file).unwrap() else { panic!("Expected a user recovery file") };
+
+    *record
+}
+
+// A user the maker left after funding gets its refund from the watch, once the funding tx has the
+// confirmations of the refund timelock and not before
+#[tokio::test]
+async fn watch_refunds_once_the_timelock_expires() {
+    let state_dir = shutdown_dir("watch-refund");
+    let chain = Arc::new(MemoryChain::default());
+    let at = |event: &ProtocolEvent| matches!(event, ProtocolEvent::Broadcast { role: TxRole::Funding, .. });
+    let (maker, users) = round_shut_down_on(chain.clone(), true, at, &state_dir).await;
+    assert!(matches!(maker, Err(JoinSwapError::Shutdown { .. })), "{maker:?}");
+    assert!(refunded(&users[0]), "{:?}", users[0]);
+    let record = user_record(&state_dir.join("user-1"));
+    let _ = fs::remove_dir_all(state_dir);
+
+    let watch = RefundWatch::new(&record).unwrap();
+    let (events, mut received) = EventSink::channel();
+    let watched: Arc<dyn ChainAccess> = chain.clone();
+    let watching = tokio::spawn(async move { watch.run(&watched, Duration::from_millis(10), &events).await });
+
+    // A block short of the timelock, the refund isn't final yet
+    let timelock = u32::from(record.timelocks.refund);
+    chain.mine(timelock - 2);
+    let short = ProtocolEvent::AwaitingConfirmation { txid: record.funding_txid, have: timelock - 1, need: timelock };
+    while received.recv().await.unwrap() != short {}
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!watching.is_finished());
+    assert_eq!(chain.get_tx(&record.refund_txid).unwrap(), None);
+
+    chain.mine(1);
+    let outcome = tokio::time::timeout(ROUND_TIMEOUT, watching).await.expect("The watch hung").unwrap().unwrap();
+    assert_eq!(outcome, WatchOutcome::Refunded { txid: record.refund_txid });
+    assert_eq!(chain.confirmations(&record.refund_txid, &Script::new()).unwrap(), 1);
+    let refund = ProtocolEvent::Broadcast { role: TxRole::Refund, txid: record.refund_txid };
+    assert!(std::iter::from_fn(|| received.try_recv().ok()).any(|event| event == refund));
+}
+
+// A watch that missed the handover of our multisig key stands down once the maker sweeps the contract,
+// without broadcasting the refund
+#[tokio::test]
+async fn watch_stands_down_after_the_cooperative_sweep() {
+    let chain = Arc::new(MemoryChain::default());
+    let maker_config = MakerConfig::default();
+    let state_dir = std::env::temp_dir().join(format!("joinswap-round-watch-sweep-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&state_dir);
+    let config = UserConfig { state_dir: Some(state_dir.clone()), ..UserConfig::default() };
+    let users = vec![
+        (config, funded_wallet(&chain, 1, &[50_000])),
+        (UserConfig::default(), funded_wallet(&chain, 2, &[60_000])),
+    ];
+    let (maker, _) = run_round(transports(&maker_config), maker_config, users, chain.clone()).await;
+    assert!(matches!(maker.unwrap().sweep, SweepPath::Cooperative { .. }));
+    let mut record = user_record(&state_dir);
+    let _ = fs::remove_dir_all(state_dir);
+
+    record.handed_over.clear();
+    let (watch, watched): (_, Arc<dyn ChainAccess>) = (RefundWatch::new(&record).unwrap(), chain.clone());
+    let events = EventSink::none();
+    let outcome = tokio::time::timeout(ROUND_TIMEOUT, watch.run(&watched, Duration::from_millis(10), &events))
+        .await
+        .expect("The watch hung")
+        .unwrap();
+    assert_eq!(outcome, WatchOutcome::StoodDown);
+    assert_eq!(chain.get_tx(&record.refund_txid).unwrap(), None);
+}